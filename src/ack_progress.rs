@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! In-progress acknowledgements of long analyses
+//!
+//! JetStream redelivers a message that is not acknowledged within the
+//! consumer's ack wait, even while a replica is still working on it: a
+//! large image, a slow graph query or a queue in front of the rules can
+//! outlast the wait, and the redelivery runs the same analysis twice.
+//! While a message is processed, an in-progress acknowledgement every half
+//! ack wait restarts the server's timer, so only work that was truly
+//! abandoned is redelivered.
+
+use async_nats::jetstream::{message::Message as JetStreamMessage, AckKind};
+use std::{future::Future, time::Duration};
+use tokio::time::{interval_at, Instant};
+use tracing::warn;
+
+use crate::metrics::Metrics;
+
+/// Run `work`, calling `progress` every `every` until it completes
+pub async fn keep_alive<F, P, Fut>(work: F, every: Duration, mut progress: P) -> F::Output
+where
+    F: Future,
+    P: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    tokio::pin!(work);
+    let mut ticker = interval_at(Instant::now() + every, every);
+    loop {
+        tokio::select! {
+            output = &mut work => return output,
+            _ = ticker.tick() => progress().await,
+        }
+    }
+}
+
+/// Run `work` on `msg`, keeping the message from being redelivered while
+/// it runs past half of `ack_wait`
+pub async fn with_progress<F: Future>(
+    msg: &JetStreamMessage,
+    ack_wait: Duration,
+    metrics: &Metrics,
+    work: F,
+) -> F::Output {
+    keep_alive(work, ack_wait / 2, || async {
+        match msg.ack_with(AckKind::Progress).await {
+            Ok(()) => metrics.ack_progress.inc(),
+            Err(e) => {
+                // The next tick tries again; only a whole ack wait of
+                // failures leads to a redelivery
+                warn!("In-progress acknowledgement failed: {}", e);
+                metrics.errors.inc();
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_progress_is_sent_while_work_runs() {
+        let sent = AtomicUsize::new(0);
+        let output = keep_alive(
+            async {
+                tokio::time::sleep(Duration::from_millis(110)).await;
+                "done"
+            },
+            Duration::from_millis(20),
+            || async {
+                sent.fetch_add(1, Ordering::SeqCst);
+            },
+        )
+        .await;
+        assert_eq!(output, "done");
+        assert!(sent.load(Ordering::SeqCst) >= 3);
+    }
+
+    #[tokio::test]
+    async fn test_quick_work_sends_no_progress() {
+        let sent = AtomicUsize::new(0);
+        keep_alive(async {}, Duration::from_secs(15), || async {
+            sent.fetch_add(1, Ordering::SeqCst);
+        })
+        .await;
+        assert_eq!(sent.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! HTTP API for external tools
+//!
+//! `POST /similar` with `{"text": "..."}` or `{"content_hash": "..."}`
+//! returns the nearest previously seen items and their verdicts.
+
+use anyhow::Result;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{body::Bytes, server::conn::http1, service::service_fn, Method, Request, Response};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde_json::json;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::similarity::SimilarityIndex;
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+/// Body of a `POST /similar` request
+#[derive(Debug, Deserialize)]
+pub struct SimilarityQuery {
+    pub text: Option<String>,
+    pub content_hash: Option<String>,
+    pub limit: Option<usize>,
+}
+
+pub async fn run_api_server(addr: SocketAddr, index: Arc<SimilarityIndex>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+
+    info!("Similarity API running on {}", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let io = TokioIo::new(stream);
+        let index = Arc::clone(&index);
+
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                let index = Arc::clone(&index);
+                async move { handle_api_request(req, index).await }
+            });
+
+            if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
+                error!("API connection error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_api_request(
+    req: Request<hyper::body::Incoming>,
+    index: Arc<SimilarityIndex>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    if req.uri().path() != "/similar" {
+        return Ok(json_response(404, json!({ "error": "not found" })));
+    }
+    if req.method() != Method::POST {
+        return Ok(json_response(405, json!({ "error": "use POST" })));
+    }
+
+    let body = match Limited::new(req.into_body(), MAX_BODY_BYTES)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(json_response(413, json!({ "error": "body too large" }))),
+    };
+    let (status, body) = match serde_json::from_slice::<SimilarityQuery>(&body) {
+        Ok(query) => answer(&index, &query),
+        Err(e) => (400, json!({ "error": e.to_string() })),
+    };
+    Ok(json_response(status, body))
+}
+
+/// Resolve a similarity query to a status code and JSON body
+fn answer(index: &SimilarityIndex, query: &SimilarityQuery) -> (u16, serde_json::Value) {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    match (&query.text, &query.content_hash) {
+        (Some(text), None) => (
+            200,
+            json!({ "neighbors": index.similar_to_text(text, limit) }),
+        ),
+        (None, Some(hash)) => match index.similar_to_hash(hash, limit) {
+            Some(neighbors) => (200, json!({ "neighbors": neighbors })),
+            None => (404, json!({ "error": "unknown content_hash" })),
+        },
+        _ => (
+            400,
+            json!({ "error": "provide exactly one of text or content_hash" }),
+        ),
+    }
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(text: Option<&str>, content_hash: Option<&str>) -> SimilarityQuery {
+        SimilarityQuery {
+            text: text.map(str::to_string),
+            content_hash: content_hash.map(str::to_string),
+            limit: None,
+        }
+    }
+
+    #[test]
+    fn test_answer_by_text_and_hash() {
+        let index = SimilarityIndex::new(10, 0.5);
+        index.insert(
+            "known",
+            "the moon landing was filmed in a studio",
+            "DISINFO",
+            &[],
+        );
+
+        let (status, body) = answer(
+            &index,
+            &query(Some("the moon landing was filmed in a studio"), None),
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body["neighbors"][0]["verdict"], "DISINFO");
+
+        let (status, _) = answer(&index, &query(None, Some("unknown")));
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn test_answer_requires_exactly_one_key() {
+        let index = SimilarityIndex::new(10, 0.5);
+        assert_eq!(answer(&index, &query(None, None)).0, 400);
+        assert_eq!(answer(&index, &query(Some("a"), Some("b"))).0, 400);
+    }
+}
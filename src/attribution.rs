@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Token attributions for the neural half of a verdict
+//!
+//! The detector model can export per-token attributions, either integrated
+//! gradients or attention-based saliency. The strongest tokens are
+//! published with the features and named in the explanation, so an audit
+//! can see what drove the scores the rules acted on.
+
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+use crate::onnx_wrapper;
+
+/// How per-token attributions are computed by the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributionMethod {
+    /// Integrated gradients of the fakeness score over the token embeddings
+    IntegratedGradients,
+    /// Attention saliency from the final encoder layer
+    Attention,
+}
+
+impl FromStr for AttributionMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "integrated-gradients" | "ig" => Ok(Self::IntegratedGradients),
+            "attention" => Ok(Self::Attention),
+            other => bail!("unknown attribution method: {}", other),
+        }
+    }
+}
+
+/// Attribution settings
+#[derive(Debug, Clone)]
+pub struct AttributionConfig {
+    pub method: AttributionMethod,
+    /// Number of top-contributing tokens kept per message
+    pub top_k: usize,
+}
+
+/// Contribution of one input token to the fakeness score
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenAttribution {
+    pub token: String,
+    /// Signed contribution; positive pushes towards fake
+    pub score: f32,
+}
+
+/// The `top_k` tokens contributing most to a message's score
+///
+/// # Arguments
+/// * `config` - Attribution method and number of tokens kept
+/// * `content_hash` - Hash of the content, as passed to inference
+/// * `text` - Content text the tokens are taken from
+pub async fn attribute(
+    config: &AttributionConfig,
+    content_hash: &str,
+    text: &str,
+) -> Result<Vec<TokenAttribution>> {
+    let tokens = tokenize(text);
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+    let scores = onnx_wrapper::run_attribution(content_hash, &tokens, config.method).await?;
+    if scores.len() != tokens.len() {
+        bail!(
+            "model returned {} attributions for {} tokens",
+            scores.len(),
+            tokens.len()
+        );
+    }
+    Ok(top_k(
+        tokens
+            .into_iter()
+            .zip(scores)
+            .map(|(token, score)| TokenAttribution { token, score })
+            .collect(),
+        config.top_k,
+    ))
+}
+
+/// Split text into the word tokens attributions are reported for
+fn tokenize(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Keep the `k` strongest tokens by absolute score, one entry per token
+fn top_k(attributions: Vec<TokenAttribution>, k: usize) -> Vec<TokenAttribution> {
+    let mut strongest: Vec<TokenAttribution> = Vec::new();
+    for attribution in attributions {
+        match strongest.iter_mut().find(|a| a.token == attribution.token) {
+            Some(existing) if existing.score.abs() < attribution.score.abs() => {
+                *existing = attribution
+            }
+            Some(_) => {}
+            None => strongest.push(attribution),
+        }
+    }
+    strongest.sort_by(|a, b| b.score.abs().total_cmp(&a.score.abs()));
+    strongest.truncate(k);
+    strongest
+}
+
+/// Explanation suffix naming the top tokens, e.g. `top tokens: "cure" (+0.42)`
+pub fn describe(attributions: &[TokenAttribution]) -> String {
+    let tokens: Vec<String> = attributions
+        .iter()
+        .map(|a| format!("{:?} ({:+.2})", a.token, a.score))
+        .collect();
+    format!("top tokens: {}", tokens.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribution(token: &str, score: f32) -> TokenAttribution {
+        TokenAttribution {
+            token: token.to_string(),
+            score,
+        }
+    }
+
+    #[test]
+    fn test_top_k_by_magnitude() {
+        let top = top_k(
+            vec![
+                attribution("the", 0.01),
+                attribution("miracle", 0.6),
+                attribution("cure", 0.4),
+                attribution("study", -0.5),
+                attribution("miracle", 0.2),
+            ],
+            3,
+        );
+        assert_eq!(
+            top,
+            vec![
+                attribution("miracle", 0.6),
+                attribution("study", -0.5),
+                attribution("cure", 0.4),
+            ]
+        );
+        assert_eq!(
+            describe(&top[..2]),
+            "top tokens: \"miracle\" (+0.60), \"study\" (-0.50)"
+        );
+    }
+
+    #[test]
+    fn test_tokenize_strips_punctuation() {
+        assert_eq!(
+            tokenize("BREAKING: vaccine \"causes\" ... harm!"),
+            vec!["BREAKING", "vaccine", "causes", "harm"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attribute_keeps_top_k() {
+        let config = AttributionConfig {
+            method: AttributionMethod::IntegratedGradients,
+            top_k: 2,
+        };
+        let top = attribute(&config, "abc123", "one two three four")
+            .await
+            .unwrap();
+        assert_eq!(top.len(), 2);
+        assert!(top[0].score.abs() >= top[1].score.abs());
+        assert!(attribute(&config, "abc123", "  ").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Budget of messages in flight
+//!
+//! A message pulled from the stream holds its payload, and everything
+//! derived from it, until it is processed. When a dependency slows down,
+//! like the knowledge graph, messages would keep being pulled while fewer
+//! complete, and memory grows with the backlog. Pulling stops while either
+//! the number of messages or their payload bytes in flight is at its
+//! budget, leaving the backlog in the stream where it costs nothing.
+
+/// Messages and payload bytes currently in flight
+#[derive(Debug, Default)]
+pub struct InFlight {
+    /// Payload bytes allowed in flight; unlimited when `None`
+    max_bytes: Option<usize>,
+    messages: usize,
+    bytes: usize,
+}
+
+impl InFlight {
+    pub fn new(max_bytes: Option<usize>) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// Whether another message may be pulled with `max_messages` allowed
+    ///
+    /// Only bytes already in flight count, so a single payload larger
+    /// than the budget is still processed, alone.
+    pub fn has_room(&self, max_messages: usize) -> bool {
+        self.messages < max_messages && self.max_bytes.is_none_or(|max| self.bytes < max)
+    }
+
+    pub fn start(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes;
+    }
+
+    pub fn finish(&mut self, bytes: usize) {
+        self.messages = self.messages.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(bytes);
+    }
+
+    pub fn messages(&self) -> usize {
+        self.messages
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Use of the tighter of the two budgets, 1 when pulling stops
+    pub fn saturation(&self, max_messages: usize) -> f64 {
+        let messages = self.messages as f64 / max_messages.max(1) as f64;
+        let bytes = self
+            .max_bytes
+            .map_or(0.0, |max| self.bytes as f64 / max.max(1) as f64);
+        messages.max(bytes).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulling_stops_at_either_budget() {
+        let mut in_flight = InFlight::new(Some(1000));
+        assert!(in_flight.has_room(2));
+        in_flight.start(400);
+        assert!(in_flight.has_room(2));
+        assert_eq!(in_flight.saturation(2), 0.5);
+
+        in_flight.start(700);
+        assert!(!in_flight.has_room(10));
+        assert_eq!(in_flight.saturation(10), 1.0);
+
+        in_flight.finish(700);
+        assert!(in_flight.has_room(10));
+        assert!(!in_flight.has_room(1));
+        assert_eq!(in_flight.bytes(), 400);
+    }
+
+    #[test]
+    fn test_oversized_payload_runs_alone() {
+        let mut in_flight = InFlight::new(Some(100));
+        assert!(in_flight.has_room(4));
+        in_flight.start(5000);
+        assert!(!in_flight.has_room(4));
+        in_flight.finish(5000);
+        assert_eq!(in_flight.messages(), 0);
+        assert!(in_flight.has_room(4));
+    }
+}
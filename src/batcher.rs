@@ -0,0 +1,257 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Burst-aware batching of inference requests
+//!
+//! Concurrent messages submit their content hash to a single batching
+//! task, which runs the ensemble once per batch. Batch size and the time
+//! spent waiting for a batch to fill scale with the JetStream backlog:
+//! with an empty queue every request runs on its own for low latency,
+//! under backlog batches grow towards the maximum for throughput. Batches
+//! run concurrently up to the ensemble's session pool size, so a batch of
+//! one does not hold up the next while a session is free.
+
+use anyhow::{Context, Result};
+use async_nats::jetstream::consumer::PullConsumer;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, Semaphore},
+    time::{timeout_at, Instant},
+};
+use tracing::warn;
+
+use crate::metrics::Metrics;
+use crate::onnx_wrapper::{Ensemble, NeuralFeatures};
+
+/// Bounds of the batch autoscaler
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// Largest batch; 1 disables batching
+    pub max_size: usize,
+    /// Longest time the first request of a batch waits for others
+    pub max_wait: Duration,
+    /// Queue depth at which the maximum size and wait are reached
+    pub full_backlog: u64,
+    /// How often the queue depth is sampled
+    pub poll_interval: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 1,
+            max_wait: Duration::from_millis(20),
+            full_backlog: 1000,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl BatchConfig {
+    pub fn enabled(&self) -> bool {
+        self.max_size > 1
+    }
+
+    /// Batch size and wait for the given number of pending messages,
+    /// scaled linearly between `(1, 0)` and `(max_size, max_wait)`
+    pub fn params_for(&self, queue_depth: u64) -> (usize, Duration) {
+        let load = (queue_depth as f64 / self.full_backlog.max(1) as f64).min(1.0);
+        let size = 1 + ((self.max_size.max(1) - 1) as f64 * load).round() as usize;
+        let wait = Duration::from_nanos((self.max_wait.as_nanos() as f64 * load).round() as u64);
+        (size, wait)
+    }
+}
+
+struct Request {
+    content_hash: String,
+    reply: oneshot::Sender<Result<NeuralFeatures>>,
+}
+
+/// Current batch parameters, shared between the autoscaler and the batcher
+struct Params {
+    size: AtomicUsize,
+    wait_micros: AtomicU64,
+}
+
+/// Handle for submitting inference requests to the batching task
+pub struct InferenceBatcher {
+    requests: mpsc::Sender<Request>,
+    params: Arc<Params>,
+    config: BatchConfig,
+}
+
+impl InferenceBatcher {
+    /// Start the batching task for `ensemble`
+    pub fn spawn(ensemble: Arc<Ensemble>, config: BatchConfig) -> Self {
+        let params = Arc::new(Params {
+            size: AtomicUsize::new(1),
+            wait_micros: AtomicU64::new(0),
+        });
+        let (requests, receiver) = mpsc::channel(config.max_size.max(1) * 4);
+        tokio::spawn(run_batches(ensemble, receiver, Arc::clone(&params)));
+        Self {
+            requests,
+            params,
+            config,
+        }
+    }
+
+    /// Infer features for one content hash as part of the next batch
+    pub async fn infer(&self, content_hash: &str) -> Result<NeuralFeatures> {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send(Request {
+                content_hash: content_hash.to_string(),
+                reply,
+            })
+            .await
+            .ok()
+            .context("Inference batcher stopped")?;
+        response
+            .await
+            .context("Inference batcher dropped request")?
+    }
+
+    /// Retune the batch size and wait for the current queue depth
+    pub fn autoscale(&self, queue_depth: u64) -> (usize, Duration) {
+        let (size, wait) = self.config.params_for(queue_depth);
+        self.params.size.store(size, Ordering::Relaxed);
+        self.params
+            .wait_micros
+            .store(wait.as_micros() as u64, Ordering::Relaxed);
+        (size, wait)
+    }
+}
+
+/// A batcher that never answers, as when the models hang
+#[cfg(test)]
+impl InferenceBatcher {
+    pub fn stalled() -> Self {
+        let (requests, receiver) = mpsc::channel(16);
+        // Requests queue up and are never taken
+        std::mem::forget(receiver);
+        Self {
+            requests,
+            params: Arc::new(Params {
+                size: AtomicUsize::new(1),
+                wait_micros: AtomicU64::new(0),
+            }),
+            config: BatchConfig::default(),
+        }
+    }
+}
+
+async fn run_batches(
+    ensemble: Arc<Ensemble>,
+    mut receiver: mpsc::Receiver<Request>,
+    params: Arc<Params>,
+) {
+    let permits = Arc::new(Semaphore::new(ensemble.concurrency().max(1)));
+    while let Some(first) = receiver.recv().await {
+        let size = params.size.load(Ordering::Relaxed).max(1);
+        let wait = Duration::from_micros(params.wait_micros.load(Ordering::Relaxed));
+        let deadline = Instant::now() + wait;
+
+        let mut batch = vec![first];
+        while batch.len() < size {
+            match receiver.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => match timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(request)) => batch.push(request),
+                    _ => break,
+                },
+            }
+        }
+
+        // With every session busy, further requests queue until a batch ends
+        let Ok(permit) = Arc::clone(&permits).acquire_owned().await else {
+            return;
+        };
+        let ensemble = Arc::clone(&ensemble);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let hashes: Vec<&str> = batch.iter().map(|r| r.content_hash.as_str()).collect();
+            let results = ensemble.run_batch(&hashes).await;
+            for (request, result) in batch.into_iter().zip(results) {
+                // The caller may have given up after its deadline
+                let _ = request.reply.send(result);
+            }
+        });
+    }
+}
+
+/// Periodically sample the backlog of the consumers, the priority one
+/// included, and retune the batcher
+pub async fn run_autoscaler(
+    batcher: Arc<InferenceBatcher>,
+    mut consumers: Vec<PullConsumer>,
+    metrics: Arc<Metrics>,
+) {
+    let mut ticker = tokio::time::interval(batcher.config.poll_interval);
+    'sample: loop {
+        ticker.tick().await;
+        let mut depth = 0;
+        for consumer in &mut consumers {
+            match consumer.info().await {
+                Ok(info) => depth += info.num_pending,
+                Err(e) => {
+                    warn!("Failed to read consumer backlog: {}", e);
+                    continue 'sample;
+                }
+            }
+        }
+        let (size, wait) = batcher.autoscale(depth);
+        metrics.queue_depth.set(depth as f64);
+        metrics.batch_size.set(size as f64);
+        metrics.batch_wait.set(wait.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onnx_wrapper::FusionStrategy;
+    use crate::session_pool::SessionOptions;
+
+    fn config() -> BatchConfig {
+        BatchConfig {
+            max_size: 33,
+            max_wait: Duration::from_millis(40),
+            full_backlog: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_params_scale_with_backlog() {
+        let config = config();
+        assert_eq!(config.params_for(0), (1, Duration::ZERO));
+        assert_eq!(config.params_for(50), (17, Duration::from_millis(20)));
+        assert_eq!(config.params_for(100), (33, Duration::from_millis(40)));
+        assert_eq!(config.params_for(10_000), (33, Duration::from_millis(40)));
+        assert!(!BatchConfig::default().enabled());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_a_batch() {
+        let ensemble = Arc::new(Ensemble::new(
+            vec!["fakeness:a.onnx".parse().unwrap()],
+            FusionStrategy::Mean,
+            &SessionOptions::default(),
+        ));
+        let batcher = InferenceBatcher::spawn(ensemble, config());
+        assert_eq!(batcher.autoscale(100).0, 33);
+
+        let results = futures::future::join_all(
+            (0..5).map(|i| batcher.infer(if i % 2 == 0 { "a" } else { "b" })),
+        )
+        .await;
+        assert!(results.iter().all(|r| r.is_ok()));
+    }
+}
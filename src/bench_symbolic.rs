@@ -0,0 +1,287 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Soufflé vs embedded engine benchmark (`nsai-detector bench-symbolic`)
+//!
+//! Runs recorded fact sets through both symbolic backends, checks that
+//! they derive the same relations and compares latency and peak memory.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fmt, fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::fact_mapping::Fact;
+use crate::souffle_wrapper::{evaluate, run_souffle, Derivation};
+
+/// Latency and memory of one backend
+#[derive(Debug, Serialize)]
+pub struct BackendStats {
+    pub backend: &'static str,
+    pub runs: usize,
+    pub mean_micros: f64,
+    pub p95_micros: f64,
+    /// Peak resident set size; `None` where the platform does not report it
+    pub peak_rss_kib: Option<u64>,
+}
+
+/// A fact set on which the backends disagree
+#[derive(Debug, Serialize)]
+pub struct Mismatch {
+    pub fact_set: usize,
+    pub embedded: String,
+    pub souffle: String,
+}
+
+/// Outcome of a benchmark run
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub fact_sets: usize,
+    pub embedded: BackendStats,
+    pub souffle: BackendStats,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Report {
+    /// Backend a deployment should pick based on this run
+    pub fn recommendation(&self) -> &'static str {
+        if !self.mismatches.is_empty() {
+            "none: backends disagree, fix the rules before choosing"
+        } else if self.embedded.mean_micros <= self.souffle.mean_micros {
+            "embedded"
+        } else {
+            "souffle"
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "fact sets: {}", self.fact_sets)?;
+        writeln!(
+            f,
+            "{:<10} {:>8} {:>12} {:>12} {:>14}",
+            "backend", "runs", "mean (us)", "p95 (us)", "peak RSS (KiB)"
+        )?;
+        for stats in [&self.embedded, &self.souffle] {
+            let rss = stats
+                .peak_rss_kib
+                .map(|kib| kib.to_string())
+                .unwrap_or_else(|| "n/a".to_string());
+            writeln!(
+                f,
+                "{:<10} {:>8} {:>12.1} {:>12.1} {:>14}",
+                stats.backend, stats.runs, stats.mean_micros, stats.p95_micros, rss
+            )?;
+        }
+
+        if self.mismatches.is_empty() {
+            writeln!(f, "correctness: all derivations identical")?;
+        } else {
+            writeln!(f, "correctness: {} mismatches", self.mismatches.len())?;
+            for mismatch in &self.mismatches {
+                writeln!(
+                    f,
+                    "  set {}: embedded [{}] vs souffle [{}]",
+                    mismatch.fact_set, mismatch.embedded, mismatch.souffle
+                )?;
+            }
+        }
+        write!(f, "recommendation: {}", self.recommendation())
+    }
+}
+
+/// Benchmark both backends over the fact sets recorded in `facts`
+///
+/// # Arguments
+/// * `souffle` - Path to the Soufflé interpreter
+/// * `program` - Datalog program evaluated by Soufflé
+/// * `facts` - Recorded fact sets, one blank-line separated block per message
+/// * `iterations` - Runs per fact set and backend
+pub fn run(souffle: &Path, program: &Path, facts: &Path, iterations: usize) -> Result<Report> {
+    let text =
+        fs::read_to_string(facts).with_context(|| format!("Failed to read {}", facts.display()))?;
+    let fact_sets = parse_fact_sets(&text)?;
+
+    let (embedded, embedded_latencies) =
+        measure(&fact_sets, iterations, |facts| Ok(evaluate(facts)))?;
+    let embedded_rss = self_peak_rss_kib();
+
+    let (souffle_results, souffle_latencies) = measure(&fact_sets, iterations, |facts| {
+        run_souffle(souffle, program, facts)
+    })?;
+
+    Ok(Report {
+        fact_sets: fact_sets.len(),
+        embedded: summarize("embedded", &embedded_latencies, embedded_rss),
+        souffle: summarize("souffle", &souffle_latencies, children_peak_rss_kib()),
+        mismatches: compare(&embedded, &souffle_results),
+    })
+}
+
+/// Parse blank-line separated blocks of facts, skipping `//` comments
+fn parse_fact_sets(text: &str) -> Result<Vec<Vec<Fact>>> {
+    let mut sets = Vec::new();
+    let mut current = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with("//") {
+            continue;
+        }
+        if line.is_empty() {
+            if !current.is_empty() {
+                sets.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        current.push(
+            line.parse()
+                .with_context(|| format!("line {}", number + 1))?,
+        );
+    }
+    if !current.is_empty() {
+        sets.push(current);
+    }
+    Ok(sets)
+}
+
+/// Run every fact set `iterations` times, keeping the last derivation of each
+fn measure<F>(
+    fact_sets: &[Vec<Fact>],
+    iterations: usize,
+    mut run: F,
+) -> Result<(Vec<Derivation>, Vec<Duration>)>
+where
+    F: FnMut(&[Fact]) -> Result<Derivation>,
+{
+    let mut derivations = Vec::with_capacity(fact_sets.len());
+    let mut latencies = Vec::with_capacity(fact_sets.len() * iterations);
+    for facts in fact_sets {
+        let mut last = None;
+        for _ in 0..iterations.max(1) {
+            let start = Instant::now();
+            last = Some(run(facts)?);
+            latencies.push(start.elapsed());
+        }
+        derivations.extend(last);
+    }
+    Ok((derivations, latencies))
+}
+
+fn summarize(
+    backend: &'static str,
+    latencies: &[Duration],
+    peak_rss_kib: Option<u64>,
+) -> BackendStats {
+    let mut micros: Vec<f64> = latencies.iter().map(|d| d.as_secs_f64() * 1e6).collect();
+    micros.sort_by(f64::total_cmp);
+
+    let mean = if micros.is_empty() {
+        0.0
+    } else {
+        micros.iter().sum::<f64>() / micros.len() as f64
+    };
+    let p95 = match micros.len() {
+        0 => 0.0,
+        len => micros[((len as f64 * 0.95).ceil() as usize).clamp(1, len) - 1],
+    };
+
+    BackendStats {
+        backend,
+        runs: micros.len(),
+        mean_micros: mean,
+        p95_micros: p95,
+        peak_rss_kib,
+    }
+}
+
+/// Fact sets whose verdict or derived relations differ between backends
+fn compare(embedded: &[Derivation], souffle: &[Derivation]) -> Vec<Mismatch> {
+    embedded
+        .iter()
+        .zip(souffle)
+        .enumerate()
+        .filter(|(_, (a, b))| {
+            a.verdict != b.verdict || a.fired != b.fired || sorted(&a.derived) != sorted(&b.derived)
+        })
+        .map(|(index, (a, b))| Mismatch {
+            fact_set: index,
+            embedded: describe(a),
+            souffle: describe(b),
+        })
+        .collect()
+}
+
+fn sorted(facts: &[Fact]) -> Vec<String> {
+    let mut facts: Vec<String> = facts.iter().map(Fact::to_string).collect();
+    facts.sort();
+    facts
+}
+
+fn describe(derivation: &Derivation) -> String {
+    sorted(&derivation.derived).join(" ")
+}
+
+/// Peak RSS of this process, which hosts the embedded engine
+fn self_peak_rss_kib() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+/// Largest peak RSS of any reaped child, i.e. the Soufflé processes
+fn children_peak_rss_kib() -> Option<u64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage only writes into the provided struct
+    let rc = unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, usage.as_mut_ptr()) };
+    if rc != 0 {
+        return None;
+    }
+    // SAFETY: initialised by the successful call above
+    let usage = unsafe { usage.assume_init() };
+    // ru_maxrss is reported in KiB on Linux
+    u64::try_from(usage.ru_maxrss).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fact_sets() {
+        let text = "// recorded\nfakeness(\"high\").\nsource_trusted(\"false\").\n\n\nfakeness(\"low\").\n";
+        let sets = parse_fact_sets(text).unwrap();
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].len(), 2);
+        assert_eq!(
+            sets[1],
+            vec![Fact::new("fakeness", vec!["low".to_string()])]
+        );
+        assert!(parse_fact_sets("fakeness(\"high\"\n").is_err());
+    }
+
+    #[test]
+    fn test_summarize_percentiles() {
+        let latencies: Vec<Duration> = (1..=20).map(Duration::from_micros).collect();
+        let stats = summarize("embedded", &latencies, None);
+        assert_eq!(stats.runs, 20);
+        assert!((stats.mean_micros - 10.5).abs() < 1e-6);
+        assert!((stats.p95_micros - 19.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_compare_flags_disagreement() {
+        let facts = |fakeness: &str| vec![Fact::new("fakeness", vec![fakeness.to_string()])];
+        let embedded = vec![evaluate(&facts("high")), evaluate(&facts("low"))];
+        let souffle = vec![evaluate(&facts("high")), evaluate(&facts("medium"))];
+
+        let mismatches = compare(&embedded, &souffle);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].fact_set, 1);
+    }
+}
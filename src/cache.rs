@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Bounded least-recently-used cache
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+/// Fixed-capacity LRU map
+///
+/// Recency is tracked with a monotonically increasing tick; the oldest
+/// tick is evicted once the capacity is exceeded.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Look up a value and mark it as recently used
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let tick = self.next_tick();
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        *last_used = tick;
+        self.order.insert(tick, key.clone());
+        Some(value.clone())
+    }
+
+    /// Insert a value, evicting the least recently used entry if full
+    pub fn put(&mut self, key: K, value: V) {
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (value, tick)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(tick, key);
+
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Drop the entries `keep` rejects
+    ///
+    /// # Returns
+    /// Number of entries dropped
+    pub fn retain(&mut self, mut keep: impl FnMut(&V) -> bool) -> usize {
+        let before = self.entries.len();
+        let order = &mut self.order;
+        self.entries.retain(|_, (value, last_used)| {
+            let kept = keep(value);
+            if !kept {
+                order.remove(last_used);
+            }
+            kept
+        });
+        before - self.entries.len()
+    }
+
+    /// Drop every entry, releasing the table's memory
+    pub fn clear(&mut self) {
+        self.entries = HashMap::new();
+        self.order = BTreeMap::new();
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.put("a", 1);
+        cache.put("b", 2);
+        assert_eq!(cache.get(&"a"), Some(1));
+
+        cache.put("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_overwrite_keeps_single_entry() {
+        let mut cache = LruCache::new(2);
+        assert_eq!(cache.len(), 0);
+        cache.put("a", 1);
+        cache.put("a", 2);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&"a"), Some(2));
+
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.get(&"a"), None);
+    }
+
+    #[test]
+    fn test_retain_drops_rejected_entries() {
+        let mut cache = LruCache::new(3);
+        cache.put("a", 1);
+        cache.put("b", 2);
+        cache.put("c", 3);
+        assert_eq!(cache.retain(|value| value % 2 == 1), 1);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"b"), None);
+
+        // The dropped entry no longer takes a place in the eviction order
+        cache.put("d", 4);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+    }
+}
@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Calibration of raw model scores
+//!
+//! Models emit uncalibrated logits; the rule thresholds (e.g. fakeness
+//! above 0.8) assume probabilities. Each score can be mapped through Platt
+//! scaling, `sigmoid(a * x + b)`, or temperature scaling, `sigmoid(x / T)`,
+//! with parameters fitted offline on a held-out set.
+
+use anyhow::{bail, Result};
+use std::{fmt, str::FromStr};
+
+use crate::onnx_wrapper::NeuralFeatures;
+
+/// Mapping from a raw logit to a probability
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scaling {
+    Platt { a: f32, b: f32 },
+    Temperature(f32),
+}
+
+impl Scaling {
+    pub fn apply(self, logit: f32) -> f32 {
+        let z = match self {
+            Self::Platt { a, b } => a * logit + b,
+            Self::Temperature(t) => logit / t,
+        };
+        1.0 / (1.0 + (-z).exp())
+    }
+}
+
+/// Scaling of one feature, parsed from `feature=platt:a:b` or
+/// `feature=temperature:T`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationSpec {
+    /// `fakeness`, `emotion` or `ai_generated`
+    pub feature: String,
+    pub scaling: Scaling,
+}
+
+impl FromStr for CalibrationSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((feature, scaling)) = s.split_once('=') else {
+            bail!(
+                "invalid calibration (expected feature=method:params): {}",
+                s
+            );
+        };
+        if !matches!(feature, "fakeness" | "emotion" | "ai_generated") {
+            bail!("unknown calibrated feature: {}", feature);
+        }
+
+        let parts: Vec<&str> = scaling.split(':').collect();
+        let scaling = match parts.as_slice() {
+            ["platt", a, b] => Scaling::Platt {
+                a: a.parse()?,
+                b: b.parse()?,
+            },
+            ["temperature", t] => {
+                let t: f32 = t.parse()?;
+                if t <= 0.0 {
+                    bail!("calibration temperature must be positive: {}", s);
+                }
+                Scaling::Temperature(t)
+            }
+            _ => bail!("invalid calibration method: {}", s),
+        };
+
+        Ok(Self {
+            feature: feature.to_string(),
+            scaling,
+        })
+    }
+}
+
+impl fmt::Display for CalibrationSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scaling {
+            Scaling::Platt { a, b } => write!(f, "{}=platt:{}:{}", self.feature, a, b),
+            Scaling::Temperature(t) => write!(f, "{}=temperature:{}", self.feature, t),
+        }
+    }
+}
+
+/// Per-feature calibration applied before the rules run
+///
+/// Features without a configured scaling pass through unchanged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Calibration {
+    fakeness: Option<Scaling>,
+    emotion: Option<Scaling>,
+    ai_generated: Option<Scaling>,
+}
+
+impl Calibration {
+    pub fn new(specs: &[CalibrationSpec]) -> Self {
+        let mut calibration = Self::default();
+        for spec in specs {
+            match spec.feature.as_str() {
+                "fakeness" => calibration.fakeness = Some(spec.scaling),
+                "emotion" => calibration.emotion = Some(spec.scaling),
+                "ai_generated" => calibration.ai_generated = Some(spec.scaling),
+                _ => {}
+            }
+        }
+        calibration
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.fakeness.is_none() && self.emotion.is_none() && self.ai_generated.is_none()
+    }
+
+    pub fn apply(&self, features: &mut NeuralFeatures) {
+        if let Some(scaling) = self.fakeness {
+            features.fakeness = scaling.apply(features.fakeness);
+        }
+        if let Some(scaling) = self.emotion {
+            features.emotion = scaling.apply(features.emotion);
+        }
+        if let Some(scaling) = self.ai_generated {
+            features.ai_generated = scaling.apply(features.ai_generated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scalings() {
+        let platt = Scaling::Platt { a: 2.0, b: -1.0 };
+        assert!((platt.apply(0.5) - 0.5).abs() < 1e-6);
+        assert!(platt.apply(3.0) > 0.99);
+
+        let temperature = Scaling::Temperature(2.0);
+        assert!((temperature.apply(0.0) - 0.5).abs() < 1e-6);
+        // A higher temperature pulls confident scores towards 0.5
+        assert!(temperature.apply(4.0) < Scaling::Temperature(1.0).apply(4.0));
+    }
+
+    #[test]
+    fn test_spec_parse() {
+        let spec: CalibrationSpec = "fakeness=platt:1.5:-0.2".parse().unwrap();
+        assert_eq!(spec.scaling, Scaling::Platt { a: 1.5, b: -0.2 });
+        let spec: CalibrationSpec = "emotion=temperature:1.8".parse().unwrap();
+        assert_eq!(spec.scaling, Scaling::Temperature(1.8));
+        assert_eq!(spec.to_string(), "emotion=temperature:1.8");
+
+        assert!("clickbait=temperature:1.0"
+            .parse::<CalibrationSpec>()
+            .is_err());
+        assert!("fakeness=temperature:0".parse::<CalibrationSpec>().is_err());
+        assert!("fakeness=isotonic".parse::<CalibrationSpec>().is_err());
+    }
+
+    #[test]
+    fn test_apply_only_configured_features() {
+        let calibration = Calibration::new(&["fakeness=temperature:1.0".parse().unwrap()]);
+        let mut features = NeuralFeatures {
+            fakeness: 0.0,
+            emotion: 0.3,
+            ..Default::default()
+        };
+        calibration.apply(&mut features);
+        assert!((features.fakeness - 0.5).abs() < 1e-6);
+        assert_eq!(features.emotion, 0.3);
+        assert!(Calibration::default().is_identity());
+    }
+}
@@ -0,0 +1,375 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Cross-referencing of content with known disinformation campaigns
+//!
+//! Researchers publish indicators of the campaigns they track: hashtags
+//! the campaign pushes, domains it links to, its images and phrases its
+//! accounts repeat word for word. Each YAML file of the campaigns
+//! directory holds the indicators of one campaign, named by the file stem,
+//! e.g. `doppelganger.yaml`:
+//!
+//! ```yaml
+//! hashtags: ["#StandWithX"]
+//! domains: ["example-news.ltd"]
+//! image_hashes: ["phash:c3a1f0e0d8c89c9c"]
+//! phrases: ["the truth they do not want you to see"]
+//! ```
+//!
+//! Content matching any indicator of a campaign gets a
+//! `matches_campaign(id)` fact. The directory is polled for changes and a
+//! changed one is loaded without a restart; one that fails to load keeps
+//! the current indicators.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::{error, info};
+
+use crate::fact_mapping::Fact;
+use crate::image_hash::HashKind;
+use crate::links;
+use crate::metrics::Metrics;
+
+/// Campaign indicator settings; disabled unless a directory is set
+#[derive(Debug, Clone)]
+pub struct CampaignConfig {
+    pub dir: PathBuf,
+    /// How often the directory is checked for changes
+    pub reload: Duration,
+    /// Largest Hamming distance of an image hash still counted as a match
+    pub max_distance: u32,
+}
+
+/// An indicator file as written
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IndicatorFile {
+    #[serde(default)]
+    hashtags: Vec<String>,
+    #[serde(default)]
+    domains: Vec<String>,
+    /// `phash:<hex>` or `dhash:<hex>`, as in the known fake images database
+    #[serde(default)]
+    image_hashes: Vec<String>,
+    #[serde(default)]
+    phrases: Vec<String>,
+}
+
+/// Indicators of one campaign, normalized for matching
+#[derive(Debug)]
+struct Campaign {
+    id: String,
+    /// Lowercase, without the `#`
+    hashtags: HashSet<String>,
+    /// Lowercase, without `www.`
+    domains: Vec<String>,
+    images: Vec<(HashKind, u64)>,
+    /// Lowercase words
+    phrases: Vec<Vec<String>>,
+}
+
+impl Campaign {
+    fn parse(id: &str, file: IndicatorFile) -> Result<Self> {
+        let images = file
+            .image_hashes
+            .iter()
+            .map(|hash| {
+                let Some((kind, hex)) = hash.split_once(':') else {
+                    bail!("image hash must be kind:hex: {}", hash);
+                };
+                let hash = u64::from_str_radix(hex, 16)
+                    .with_context(|| format!("invalid 64-bit hex hash: {}", hex))?;
+                Ok((kind.parse()?, hash))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            id: id.to_string(),
+            hashtags: file
+                .hashtags
+                .iter()
+                .map(|tag| tag.trim_start_matches('#').to_lowercase())
+                .collect(),
+            domains: file
+                .domains
+                .iter()
+                .map(|domain| domain.trim_start_matches("www.").to_lowercase())
+                .collect(),
+            images,
+            phrases: file
+                .phrases
+                .iter()
+                .map(|phrase| words(phrase))
+                .filter(|phrase| !phrase.is_empty())
+                .collect(),
+        })
+    }
+}
+
+/// Every campaign's indicators at one point in time
+#[derive(Debug)]
+pub struct Indicators {
+    campaigns: Vec<Campaign>,
+    max_distance: u32,
+    /// SHA-256 of the indicator files, to tell a change
+    sha256: String,
+}
+
+/// What of a message is matched against the indicators
+pub struct Observed<'a> {
+    pub text: &'a str,
+    /// pHash and dHash of the attached image
+    pub image: Option<(u64, u64)>,
+}
+
+impl Indicators {
+    /// Load every `*.yaml` or `*.yml` file of `dir`
+    pub fn load(dir: &Path, max_distance: u32) -> Result<Self> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read campaigns {}", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<_>>()?;
+        paths.retain(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "yaml" || ext == "yml")
+        });
+        paths.sort();
+        let mut hasher = Sha256::new();
+        let mut campaigns = Vec::new();
+        for path in paths {
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .with_context(|| format!("Invalid campaign file name {}", path.display()))?;
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            hasher.update(id.as_bytes());
+            hasher.update(contents.as_bytes());
+            let file: IndicatorFile = serde_yaml::from_str::<Option<IndicatorFile>>(&contents)
+                .with_context(|| format!("Invalid indicators {}", path.display()))?
+                .unwrap_or_default();
+            campaigns.push(
+                Campaign::parse(id, file)
+                    .with_context(|| format!("Invalid indicators {}", path.display()))?,
+            );
+        }
+        Ok(Self {
+            campaigns,
+            max_distance,
+            sha256: hex::encode(hasher.finalize()),
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.campaigns.len()
+    }
+
+    /// Whether any campaign has image indicators, worth hashing for
+    pub fn wants_images(&self) -> bool {
+        self.campaigns.iter().any(|c| !c.images.is_empty())
+    }
+
+    /// Identifiers of the campaigns the message matches
+    pub fn matches(&self, observed: &Observed) -> Vec<String> {
+        let hashtags = hashtags(observed.text);
+        let domains: HashSet<String> = links::urls(observed.text)
+            .iter()
+            .filter_map(|url| links::host(url))
+            .collect();
+        let words = words(observed.text);
+        self.campaigns
+            .iter()
+            .filter(|campaign| {
+                hashtags.iter().any(|tag| campaign.hashtags.contains(tag))
+                    || domains.iter().any(|domain| {
+                        campaign
+                            .domains
+                            .iter()
+                            .any(|known| links::within(domain, known))
+                    })
+                    || observed.image.is_some_and(|(phash, dhash)| {
+                        campaign.images.iter().any(|(kind, known)| {
+                            let hash = match kind {
+                                HashKind::Phash => phash,
+                                HashKind::Dhash => dhash,
+                            };
+                            (known ^ hash).count_ones() <= self.max_distance
+                        })
+                    })
+                    || campaign
+                        .phrases
+                        .iter()
+                        .any(|phrase| words.windows(phrase.len()).any(|w| w == phrase.as_slice()))
+            })
+            .map(|campaign| campaign.id.clone())
+            .collect()
+    }
+}
+
+/// Lowercase words of a text, punctuation dropped
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Lowercase hashtags of a text, without the `#`
+fn hashtags(text: &str) -> HashSet<String> {
+    text.split_whitespace()
+        .filter_map(|token| token.strip_prefix('#'))
+        .map(|tag| {
+            tag.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .next()
+                .unwrap_or_default()
+                .to_lowercase()
+        })
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// One `matches_campaign(id)` fact per matched campaign
+pub fn campaign_facts(campaigns: &[String]) -> Vec<Fact> {
+    campaigns
+        .iter()
+        .map(|id| Fact::new("matches_campaign", vec![id.clone()]))
+        .collect()
+}
+
+/// Campaign indicators that can be replaced while the consumer runs
+pub struct LiveCampaigns {
+    config: CampaignConfig,
+    active: RwLock<Arc<Indicators>>,
+}
+
+impl LiveCampaigns {
+    pub fn load(config: &CampaignConfig) -> Result<Self> {
+        let indicators = Indicators::load(&config.dir, config.max_distance)?;
+        Ok(Self {
+            config: config.clone(),
+            active: RwLock::new(Arc::new(indicators)),
+        })
+    }
+
+    /// The indicators new messages are matched against
+    pub fn current(&self) -> Arc<Indicators> {
+        Arc::clone(&self.active.read().unwrap())
+    }
+
+    /// Load the indicators again and swap them in if they changed
+    ///
+    /// # Returns
+    /// Whether they changed
+    pub fn reload(&self) -> Result<bool> {
+        let loaded = Indicators::load(&self.config.dir, self.config.max_distance)?;
+        let mut active = self.active.write().unwrap();
+        if loaded.sha256 == active.sha256 {
+            return Ok(false);
+        }
+        *active = Arc::new(loaded);
+        Ok(true)
+    }
+}
+
+/// Reload the campaign indicators whenever their files change
+pub async fn run_reloader(campaigns: Arc<LiveCampaigns>, metrics: Arc<Metrics>) {
+    let mut ticker = tokio::time::interval(campaigns.config.reload);
+    // The first tick completes immediately; the indicators were just loaded
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let reloading = Arc::clone(&campaigns);
+        let outcome = match tokio::task::spawn_blocking(move || reloading.reload()).await {
+            Ok(Ok(true)) => {
+                info!(
+                    "Campaign indicators reloaded, {} campaigns",
+                    campaigns.current().count()
+                );
+                "reloaded"
+            }
+            Ok(Ok(false)) => continue,
+            Ok(Err(e)) => {
+                error!(
+                    "Campaign reload failed, keeping the current indicators: {:#}",
+                    e
+                );
+                "failed"
+            }
+            Err(e) => {
+                error!("Campaign reload panicked: {}", e);
+                "failed"
+            }
+        };
+        metrics.campaign_reloads.with_label_values(&[outcome]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn campaigns_dir() -> TempDir {
+        let dir = TempDir::new("campaigns");
+        fs::write(
+            dir.join("doppelganger.yaml"),
+            "hashtags: [\"#StandWithX\"]\n\
+             domains: [example-news.ltd]\n\
+             image_hashes: [\"phash:00000000000000ff\"]\n\
+             phrases: [\"the truth they do not want you to see\"]\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_each_indicator_matches() {
+        let dir = campaigns_dir();
+        let indicators = Indicators::load(&dir, 4).unwrap();
+        assert!(indicators.wants_images());
+
+        let matches = |text, image| indicators.matches(&Observed { text, image });
+        let doppelganger = vec!["doppelganger".to_string()];
+        assert_eq!(matches("Wake up #standwithx!", None), doppelganger);
+        assert_eq!(
+            matches("Read https://www.news.example-news.ltd/a?b=1", None),
+            doppelganger
+        );
+        assert_eq!(matches("", Some((0xf0, 0))), doppelganger);
+        assert_eq!(
+            matches("This is THE truth they do not want you to see.", None),
+            doppelganger
+        );
+
+        assert!(matches("#StandWithY at https://example-news.ltd.evil.com", None).is_empty());
+        assert!(matches("", Some((0xff00, 0))).is_empty());
+        assert!(matches("the truth they want you to see", None).is_empty());
+    }
+
+    #[test]
+    fn test_changed_indicators_are_reloaded() {
+        let dir = campaigns_dir();
+        let campaigns = LiveCampaigns::load(&CampaignConfig {
+            dir: dir.to_path_buf(),
+            reload: Duration::from_secs(60),
+            max_distance: 4,
+        })
+        .unwrap();
+        assert!(!campaigns.reload().unwrap());
+
+        fs::write(dir.join("secondary.yml"), "hashtags: [\"#other\"]\n").unwrap();
+        assert!(campaigns.reload().unwrap());
+        assert_eq!(campaigns.current().count(), 2);
+
+        fs::write(dir.join("broken.yaml"), "hashtags: 3\n").unwrap();
+        assert!(campaigns.reload().is_err());
+        assert_eq!(campaigns.current().count(), 2);
+    }
+}
@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Canary items for end-to-end pipeline integrity checks
+//!
+//! A control process (`nsai-detector canary`) publishes inputs at a known
+//! rate, each carrying a signed `Nsai-Canary` header with the verdict it
+//! must receive and the time it was sent. The detector recognizes them,
+//! checks the verdict and the end-to-end latency against the SLO, and
+//! records the outcome instead of publishing a result. A stale
+//! `nsai_canary_last_pass_timestamp_seconds` means canaries are missing;
+//! `nsai_canary_failures_total` means they come out wrong or late.
+
+use anyhow::{bail, Context, Result};
+use async_nats::HeaderMap;
+use hmac::{Hmac, Mac};
+use prost::Message;
+use sha2::Sha256;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+use crate::clock::unix_millis;
+use crate::input;
+use crate::metrics::Metrics;
+use crate::model_pb::AnalysisInput;
+use crate::telemetry;
+
+/// Message header carrying the canary tag
+pub const CANARY_HEADER: &str = "Nsai-Canary";
+
+/// Source id of injected canaries
+pub const CANARY_SOURCE: &str = "canary:injector";
+
+/// Canary verification settings
+#[derive(Debug, Clone)]
+pub struct CanaryConfig {
+    /// Shared key the control process signs canaries with
+    pub key: String,
+    /// Longest acceptable time from injection to verdict
+    pub latency_slo: Duration,
+}
+
+/// Verified contents of a canary header
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanaryTag {
+    pub expected_verdict: String,
+    pub sent_at_ms: u64,
+}
+
+impl CanaryTag {
+    /// Header value `<verdict>;<sent_at_ms>;<hmac>` for a canary input
+    pub fn sign(&self, key: &str, content_hash: &str) -> String {
+        let signature = telemetry::sign(key.as_bytes(), &self.signed_bytes(content_hash));
+        format!(
+            "{};{};{}",
+            self.expected_verdict, self.sent_at_ms, signature
+        )
+    }
+
+    /// Parse a header value and check its signature against the input
+    ///
+    /// The content hash is part of the signed bytes, so a tag cannot be
+    /// replayed onto real content.
+    pub fn verify(key: &str, content_hash: &str, header: &str) -> Result<Self> {
+        let parts: Vec<&str> = header.split(';').collect();
+        let [expected_verdict, sent_at_ms, signature] = parts.as_slice() else {
+            bail!("malformed canary header: {}", header);
+        };
+        let tag = Self {
+            expected_verdict: expected_verdict.to_string(),
+            sent_at_ms: sent_at_ms.parse().context("invalid canary timestamp")?,
+        };
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(&tag.signed_bytes(content_hash));
+        mac.verify_slice(&hex::decode(signature).context("invalid canary signature")?)
+            .ok()
+            .context("canary signature does not match")?;
+        Ok(tag)
+    }
+
+    fn signed_bytes(&self, content_hash: &str) -> Vec<u8> {
+        format!(
+            "{};{};{}",
+            content_hash, self.expected_verdict, self.sent_at_ms
+        )
+        .into_bytes()
+    }
+}
+
+/// Result of checking one canary
+#[derive(Debug, Clone, PartialEq)]
+pub enum CanaryOutcome {
+    Passed,
+    WrongVerdict { expected: String, actual: String },
+    TooSlow(Duration),
+}
+
+/// Recognizes canaries in the detector and records their outcome
+pub struct CanaryVerifier {
+    config: CanaryConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl CanaryVerifier {
+    pub fn new(config: CanaryConfig, metrics: Arc<Metrics>) -> Self {
+        Self { config, metrics }
+    }
+
+    /// The canary tag of a message, if it carries a validly signed one
+    ///
+    /// A tag with a bad signature is ignored and the message is treated
+    /// as real content.
+    pub fn tag(&self, headers: Option<&HeaderMap>, content_hash: &str) -> Option<CanaryTag> {
+        let header = headers?.get(CANARY_HEADER)?;
+        match CanaryTag::verify(&self.config.key, content_hash, header.as_str()) {
+            Ok(tag) => Some(tag),
+            Err(e) => {
+                warn!("Ignoring canary header on {}: {}", content_hash, e);
+                None
+            }
+        }
+    }
+
+    /// Compare a canary's verdict and latency with what was expected
+    pub fn check(&self, tag: &CanaryTag, verdict: &str) -> CanaryOutcome {
+        let latency =
+            Duration::from_millis(unix_millis(SystemTime::now()).saturating_sub(tag.sent_at_ms));
+        let outcome = evaluate(tag, verdict, latency, self.config.latency_slo);
+
+        if outcome == CanaryOutcome::Passed {
+            self.metrics.canary_passed.inc();
+            self.metrics
+                .canary_last_pass
+                .set(tag.sent_at_ms as f64 / 1000.0);
+        } else {
+            warn!("Canary failed: {:?}", outcome);
+            self.metrics.canary_failures.inc();
+        }
+        outcome
+    }
+}
+
+fn evaluate(tag: &CanaryTag, verdict: &str, latency: Duration, slo: Duration) -> CanaryOutcome {
+    if verdict != tag.expected_verdict {
+        CanaryOutcome::WrongVerdict {
+            expected: tag.expected_verdict.clone(),
+            actual: verdict.to_string(),
+        }
+    } else if latency > slo {
+        CanaryOutcome::TooSlow(latency)
+    } else {
+        CanaryOutcome::Passed
+    }
+}
+
+/// Control loop publishing one canary per interval
+///
+/// Each canary gets a unique suffix so the feature cache cannot answer it
+/// without running inference.
+pub async fn run_injector(
+    client: async_nats::Client,
+    subject: &str,
+    key: &str,
+    text: &str,
+    expected_verdict: &str,
+    interval: Duration,
+) -> Result<()> {
+    let jetstream = async_nats::jetstream::new(client);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let tag = CanaryTag {
+            expected_verdict: expected_verdict.to_string(),
+            sent_at_ms: unix_millis(SystemTime::now()),
+        };
+        let text = format!("{} [canary {}]", text, tag.sent_at_ms);
+        let input = AnalysisInput::builder()
+            .content_hash(input::content_hash_of(&text))
+            .content_text(text)
+            .source_id(CANARY_SOURCE)
+            .build()?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CANARY_HEADER, tag.sign(key, &input.content_hash).as_str());
+        match jetstream
+            .publish_with_headers(subject.to_string(), headers, input.encode_to_vec().into())
+            .await
+        {
+            Ok(ack) => match ack.await {
+                Ok(_) => info!("Injected canary {}", input.content_hash),
+                Err(e) => warn!("Canary was not acknowledged: {}", e),
+            },
+            Err(e) => warn!("Failed to inject canary: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag() -> CanaryTag {
+        CanaryTag {
+            expected_verdict: "SAFE".to_string(),
+            sent_at_ms: 1_700_000_000_000,
+        }
+    }
+
+    #[test]
+    fn test_sign_verify_roundtrip() {
+        let header = tag().sign("key", "abc123");
+        assert_eq!(CanaryTag::verify("key", "abc123", &header).unwrap(), tag());
+
+        assert!(CanaryTag::verify("other-key", "abc123", &header).is_err());
+        // A valid tag cannot be moved onto other content
+        assert!(CanaryTag::verify("key", "def456", &header).is_err());
+        let forged = header.replacen("SAFE", "DISINFO", 1);
+        assert!(CanaryTag::verify("key", "abc123", &forged).is_err());
+        assert!(CanaryTag::verify("key", "abc123", "SAFE;1").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_outcomes() {
+        let slo = Duration::from_secs(2);
+        let fast = Duration::from_millis(300);
+        assert_eq!(evaluate(&tag(), "SAFE", fast, slo), CanaryOutcome::Passed);
+        assert_eq!(
+            evaluate(&tag(), "SUSPICIOUS", fast, slo),
+            CanaryOutcome::WrongVerdict {
+                expected: "SAFE".to_string(),
+                actual: "SUSPICIOUS".to_string(),
+            }
+        );
+        let slow = Duration::from_secs(5);
+        assert_eq!(
+            evaluate(&tag(), "SAFE", slow, slo),
+            CanaryOutcome::TooSlow(slow)
+        );
+    }
+}
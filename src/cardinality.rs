@@ -0,0 +1,326 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Metric label cardinality guards
+//!
+//! Every distinct label value is a Prometheus series held in memory forever.
+//! Labels derived from message content, such as tenants parsed from source
+//! ids, are attacker-controlled: churning source ids would otherwise grow
+//! the registry without bound. A guard gives each label a fixed budget of
+//! values, the most frequent ones; values beyond it share the `other`
+//! series.
+//!
+//! Frequencies are estimated with the space-saving algorithm over a table a
+//! few times the budget, so a flood of one-off values only ever competes
+//! for the table's least counted slots and cannot displace a busy value.
+//! Each message is counted once, by [`LabelGuard::observe`]; the series of
+//! a value ranked out are removed, so at most the budget plus `other` are
+//! exported at any time.
+
+use prometheus::{
+    core::{Collector, MetricVec, MetricVecBuilder},
+    CounterVec,
+};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+/// Label value shared by everything past the budget
+pub const OTHER_LABEL: &str = "other";
+
+/// Values counted per value of the budget
+const TRACKED_PER_SLOT: usize = 4;
+
+/// Observations between two rankings of the counted values
+const RERANK_EVERY: u64 = 1024;
+
+/// Label cardinality settings
+#[derive(Debug, Clone)]
+pub struct CardinalityConfig {
+    /// Distinct values a guarded label may take besides `other`
+    pub label_limit: usize,
+    /// Tenants exported under their own name; all others are `other`.
+    /// When unset, the `label_limit` most frequent tenants are exported.
+    pub tenant_allowlist: Option<Vec<String>>,
+}
+
+impl Default for CardinalityConfig {
+    fn default() -> Self {
+        Self {
+            label_limit: 100,
+            tenant_allowlist: None,
+        }
+    }
+}
+
+/// A metric with a guarded label
+pub trait GuardedSeries: Send + Sync {
+    /// Remove every series whose `label` is `value`
+    fn remove_value(&self, label: &str, value: &str);
+}
+
+impl<T: MetricVecBuilder> GuardedSeries for MetricVec<T> {
+    fn remove_value(&self, label: &str, value: &str) {
+        for family in self.collect() {
+            for metric in family.get_metric() {
+                let labels: HashMap<&str, &str> = metric
+                    .get_label()
+                    .iter()
+                    .map(|pair| (pair.name(), pair.value()))
+                    .collect();
+                if labels.get(label) == Some(&value) {
+                    let _ = self.remove(&labels);
+                }
+            }
+        }
+    }
+}
+
+/// Bounds the values of one metric label
+pub struct LabelGuard {
+    label: &'static str,
+    limit: usize,
+    allowlist: Option<HashSet<String>>,
+    top: Mutex<TopK>,
+    /// Metrics labeled by this guard, cleared of values ranked out
+    series: Vec<Box<dyn GuardedSeries>>,
+    overflowed: CounterVec,
+}
+
+/// Approximate most frequent values, by the space-saving algorithm
+#[derive(Default)]
+struct TopK {
+    /// Estimated counts of the tracked values; an untracked value takes the
+    /// slot of the least counted one and inherits its count
+    counts: HashMap<String, u64>,
+    /// Values currently exported under their own name
+    ranked: HashSet<String>,
+    /// Observations since `ranked` was last recomputed
+    since_rank: u64,
+}
+
+impl TopK {
+    /// Count one observation of `value`
+    ///
+    /// # Returns
+    /// The values ranked out, if the observation triggered a ranking
+    fn observe(&mut self, value: &str, limit: usize) -> Vec<String> {
+        let full = self.counts.len() >= limit * TRACKED_PER_SLOT;
+        match self.counts.get_mut(value) {
+            Some(count) => *count += 1,
+            None if !full => {
+                self.counts.insert(value.to_string(), 1);
+            }
+            None => {
+                let (evicted, min) = self
+                    .counts
+                    .iter()
+                    .min_by_key(|(_, count)| **count)
+                    .map(|(value, count)| (value.clone(), *count))
+                    .expect("a full table is not empty");
+                self.counts.remove(&evicted);
+                self.counts.insert(value.to_string(), min + 1);
+            }
+        }
+
+        self.since_rank += 1;
+        if self.since_rank >= RERANK_EVERY {
+            return self.rank(limit);
+        }
+        if self.ranked.len() < limit {
+            // Until the budget is spent there is nothing to displace
+            self.ranked.insert(value.to_string());
+        }
+        Vec::new()
+    }
+
+    fn rank(&mut self, limit: usize) -> Vec<String> {
+        let mut counted: Vec<(&String, &u64)> = self.counts.iter().collect();
+        counted.sort_by_key(|(value, count)| (Reverse(**count), *value));
+        let ranked: HashSet<String> = counted
+            .into_iter()
+            .take(limit)
+            .map(|(value, _)| value.clone())
+            .collect();
+        self.since_rank = 0;
+        let previous = std::mem::replace(&mut self.ranked, ranked);
+        previous
+            .into_iter()
+            .filter(|value| !self.ranked.contains(value))
+            .collect()
+    }
+}
+
+impl LabelGuard {
+    pub fn new(
+        label: &'static str,
+        limit: usize,
+        allowlist: Option<Vec<String>>,
+        overflowed: CounterVec,
+    ) -> Self {
+        Self {
+            label,
+            limit,
+            allowlist: allowlist.map(|values| values.into_iter().collect()),
+            top: Mutex::new(TopK::default()),
+            series: Vec::new(),
+            overflowed,
+        }
+    }
+
+    /// Remove the series of values ranked out from these metrics
+    pub fn with_series(mut self, series: Vec<Box<dyn GuardedSeries>>) -> Self {
+        self.series = series;
+        self
+    }
+
+    /// Count one message of `value`; call once per message, before
+    /// [`admit`](Self::admit) or [`bucket`](Self::bucket)
+    pub fn observe(&self, value: &str) {
+        if self.allowlist.is_none() && self.limit > 0 && value != OTHER_LABEL {
+            let ranked_out = self.top.lock().unwrap().observe(value, self.limit);
+            for value in &ranked_out {
+                for series in &self.series {
+                    series.remove_value(self.label, value);
+                }
+            }
+        }
+        if self.admit(value).is_none() {
+            self.overflowed.with_label_values(&[self.label]).inc();
+        }
+    }
+
+    /// The value itself if it has its own series, otherwise `None`
+    ///
+    /// Allowlisted values always have one. Without an allowlist, the
+    /// `limit` most frequent values do, re-ranked every `RERANK_EVERY`
+    /// observations; the series of a value ranked out are removed. A value
+    /// that is literally `other` never has its own series, which would be
+    /// indistinguishable from the shared one.
+    pub fn admit<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let admitted = value != OTHER_LABEL
+            && match &self.allowlist {
+                Some(allowlist) => allowlist.contains(value),
+                None => self.top.lock().unwrap().ranked.contains(value),
+            };
+        admitted.then_some(value)
+    }
+
+    /// Label value to record under: the value itself or `other`
+    ///
+    /// Use for counters and histograms, which aggregate correctly in the
+    /// shared series; gauges should only be set for [`admit`]ted values.
+    ///
+    /// [`admit`]: LabelGuard::admit
+    pub fn bucket<'a>(&self, value: &'a str) -> &'a str {
+        self.admit(value).unwrap_or(OTHER_LABEL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::Opts;
+
+    fn overflowed() -> CounterVec {
+        CounterVec::new(Opts::new("overflowed", "test"), &["label"]).unwrap()
+    }
+
+    /// Count a message of `value` and return the label it is recorded under
+    fn record<'a>(guard: &LabelGuard, value: &'a str) -> &'a str {
+        guard.observe(value);
+        guard.bucket(value)
+    }
+
+    #[test]
+    fn test_first_values_until_ranked() {
+        let guard = LabelGuard::new("tenant", 2, None, overflowed());
+        assert_eq!(record(&guard, "a"), "a");
+        assert_eq!(record(&guard, "b"), "b");
+        assert_eq!(record(&guard, "c"), OTHER_LABEL);
+        // Admitted values keep their series
+        assert_eq!(record(&guard, "a"), "a");
+        // Looking a value up does not count it
+        assert_eq!(guard.admit("d"), None);
+        assert_eq!(guard.overflowed.with_label_values(&["tenant"]).get(), 1.0);
+        assert_eq!(guard.top.lock().unwrap().counts.get("a"), Some(&2));
+    }
+
+    #[test]
+    fn test_most_frequent_values_are_ranked_in() {
+        let guard = LabelGuard::new("tenant", 1, None, overflowed());
+        assert_eq!(record(&guard, "early"), "early");
+        for _ in 0..RERANK_EVERY {
+            record(&guard, "busy");
+        }
+        assert_eq!(record(&guard, "busy"), "busy");
+        assert_eq!(record(&guard, "early"), OTHER_LABEL);
+    }
+
+    #[test]
+    fn test_churn_does_not_displace_busy_values() {
+        let guard = LabelGuard::new("tenant", 2, None, overflowed());
+        for i in 0..4 * RERANK_EVERY {
+            record(&guard, "newsroom");
+            record(&guard, &format!("spam-{}", i));
+        }
+        assert_eq!(record(&guard, "newsroom"), "newsroom");
+        let top = guard.top.lock().unwrap();
+        assert!(top.counts.len() <= 2 * TRACKED_PER_SLOT);
+        assert!(top.ranked.contains("newsroom"));
+    }
+
+    #[test]
+    fn test_ranked_out_series_are_removed() {
+        let messages =
+            CounterVec::new(Opts::new("messages", "test"), &["tenant", "verdict"]).unwrap();
+        let guard = LabelGuard::new("tenant", 2, None, overflowed())
+            .with_series(vec![Box::new(messages.clone())]);
+        // Every round, two new tenants outnumber all earlier ones
+        for round in 0..5u64 {
+            let tenants = [format!("a{}", round), format!("b{}", round)];
+            for _ in 0..(round + 1) * RERANK_EVERY {
+                for tenant in &tenants {
+                    let label = record(&guard, tenant);
+                    messages.with_label_values(&[label, "SAFE"]).inc();
+                }
+            }
+        }
+
+        assert_eq!(guard.admit("a4"), Some("a4"));
+        assert_eq!(guard.admit("a0"), None);
+        let series = messages.collect()[0].get_metric().len();
+        // The two ranked tenants and `other`
+        assert!(series <= 3, "{} series exported", series);
+    }
+
+    #[test]
+    fn test_other_is_never_its_own_series() {
+        let guard = LabelGuard::new(
+            "tenant",
+            2,
+            Some(vec![OTHER_LABEL.to_string()]),
+            overflowed(),
+        );
+        assert_eq!(record(&guard, OTHER_LABEL), OTHER_LABEL);
+        assert_eq!(guard.admit(OTHER_LABEL), None);
+        let guard = LabelGuard::new("tenant", 2, None, overflowed());
+        guard.observe(OTHER_LABEL);
+        assert_eq!(guard.admit(OTHER_LABEL), None);
+    }
+
+    #[test]
+    fn test_allowlist_pins_values() {
+        let guard = LabelGuard::new(
+            "tenant",
+            1,
+            Some(vec!["newsroom".to_string(), "partner".to_string()]),
+            overflowed(),
+        );
+        assert_eq!(record(&guard, "newsroom"), "newsroom");
+        assert_eq!(record(&guard, "partner"), "partner");
+        assert_eq!(record(&guard, "spam-123"), OTHER_LABEL);
+    }
+}
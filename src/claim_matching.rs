@@ -0,0 +1,438 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Matching of claims in the content against published fact-checks
+//!
+//! Much disinformation repeats a claim fact-checkers have already rated.
+//! The sentences of the content that read like claims are looked up in a
+//! fact-check corpus, a file of schema.org `ClaimReview` objects or the
+//! Google Fact Check Tools API, and every fact-check whose reviewed claim
+//! shares enough words with one of them becomes a
+//! `claim_debunked(url, rating)` fact, with the fact-checker's rating in
+//! lowercase, e.g. `claim_debunked("https://example.org/check", "false")`.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::info;
+
+use crate::fact_mapping::Fact;
+
+/// Sentences with fewer words are not taken for claims
+const MIN_CLAIM_WORDS: usize = 5;
+/// Shorter words are left out of the overlap, mostly stop words
+const MIN_WORD_CHARS: usize = 3;
+
+/// Where fact-checks are looked up
+#[derive(Debug, Clone, PartialEq)]
+pub enum FactCheckBackend {
+    /// `ClaimReview` objects, one JSON object per line
+    File { path: PathBuf },
+    /// Google Fact Check Tools `claims:search` endpoint
+    Google { url: String, api_key: String },
+}
+
+/// Fact-check backend names accepted by `NSAI_FACT_CHECKS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FactCheckKind {
+    File,
+    Google,
+}
+
+impl FromStr for FactCheckKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "file" => Ok(Self::File),
+            "google" => Ok(Self::Google),
+            other => bail!("unknown fact-check corpus: {}", other),
+        }
+    }
+}
+
+/// Claim matching settings; disabled unless `NSAI_FACT_CHECKS` is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClaimMatchConfig {
+    pub backend: FactCheckBackend,
+    /// Word overlap (Jaccard, 0-1) a reviewed claim needs to match
+    pub min_similarity: f32,
+    /// Longest sentences of a message looked up
+    pub max_claims: usize,
+    /// Deadline of one API lookup
+    pub timeout: Duration,
+}
+
+/// A published rating of a claim
+#[derive(Debug, Clone, PartialEq)]
+pub struct FactCheck {
+    /// The claim as the fact-checker reviewed it
+    pub claim: String,
+    pub url: String,
+    pub rating: String,
+}
+
+/// A fact-check matching a claim of the content
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClaimMatch {
+    /// Sentence of the content
+    pub claim: String,
+    pub url: String,
+    /// Lowercase rating, e.g. `false` or `misleading`
+    pub rating: String,
+    pub similarity: f32,
+}
+
+/// A searchable collection of fact-checks
+pub trait FactCheckCorpus: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Fact-checks possibly about `claim`, to be scored by the caller
+    fn search<'a>(&'a self, claim: &'a str) -> BoxFuture<'a, Result<Vec<FactCheck>>>;
+}
+
+/// schema.org `ClaimReview`, the fields a match needs
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClaimReview {
+    claim_reviewed: String,
+    url: String,
+    review_rating: ReviewRating,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReviewRating {
+    alternate_name: String,
+}
+
+/// Fact-checks read from a file at startup, indexed by word
+pub struct FileCorpus {
+    checks: Vec<FactCheck>,
+    by_word: HashMap<String, Vec<usize>>,
+}
+
+impl FileCorpus {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read fact-checks {}", path.display()))?;
+        let mut checks = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let review: ClaimReview = serde_json::from_str(line).with_context(|| {
+                format!(
+                    "Invalid ClaimReview on line {} of {}",
+                    number + 1,
+                    path.display()
+                )
+            })?;
+            checks.push(FactCheck {
+                claim: review.claim_reviewed,
+                url: review.url,
+                rating: review.review_rating.alternate_name,
+            });
+        }
+        Ok(Self::new(checks))
+    }
+
+    pub fn new(checks: Vec<FactCheck>) -> Self {
+        let mut by_word: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, check) in checks.iter().enumerate() {
+            for word in words(&check.claim) {
+                by_word.entry(word).or_default().push(i);
+            }
+        }
+        Self { checks, by_word }
+    }
+
+    pub fn count(&self) -> usize {
+        self.checks.len()
+    }
+}
+
+impl FactCheckCorpus for FileCorpus {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn search<'a>(&'a self, claim: &'a str) -> BoxFuture<'a, Result<Vec<FactCheck>>> {
+        let found: HashSet<usize> = words(claim)
+            .iter()
+            .filter_map(|word| self.by_word.get(word))
+            .flatten()
+            .copied()
+            .collect();
+        Box::pin(async move { Ok(found.into_iter().map(|i| self.checks[i].clone()).collect()) })
+    }
+}
+
+/// Fact-checks searched in the Google Fact Check Tools API
+pub struct GoogleCorpus {
+    client: reqwest::Client,
+    url: String,
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct GoogleResponse {
+    #[serde(default)]
+    claims: Vec<GoogleClaim>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleClaim {
+    text: String,
+    #[serde(default)]
+    claim_review: Vec<GoogleReview>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleReview {
+    url: String,
+    textual_rating: String,
+}
+
+impl FactCheckCorpus for GoogleCorpus {
+    fn name(&self) -> &'static str {
+        "google"
+    }
+
+    fn search<'a>(&'a self, claim: &'a str) -> BoxFuture<'a, Result<Vec<FactCheck>>> {
+        Box::pin(async move {
+            let body = self
+                .client
+                .get(&self.url)
+                .query(&[
+                    ("query", claim),
+                    ("key", self.api_key.as_str()),
+                    ("pageSize", "10"),
+                ])
+                .send()
+                .await
+                .context("Fact-check search failed")?
+                .error_for_status()
+                .context("Fact-check search returned an error")?
+                .bytes()
+                .await?;
+            let response: GoogleResponse =
+                serde_json::from_slice(&body).context("Fact-check search returned invalid JSON")?;
+            Ok(response
+                .claims
+                .into_iter()
+                .flat_map(|found| {
+                    found.claim_review.into_iter().map(move |review| FactCheck {
+                        claim: found.text.clone(),
+                        url: review.url,
+                        rating: review.textual_rating,
+                    })
+                })
+                .collect())
+        })
+    }
+}
+
+/// Build the configured corpus
+pub fn from_config(config: &ClaimMatchConfig) -> Result<Box<dyn FactCheckCorpus>> {
+    Ok(match &config.backend {
+        FactCheckBackend::File { path } => {
+            let corpus = FileCorpus::load(path)?;
+            info!(
+                "Loaded {} fact-checks from {}",
+                corpus.count(),
+                path.display()
+            );
+            Box::new(corpus)
+        }
+        FactCheckBackend::Google { url, api_key } => Box::new(GoogleCorpus {
+            client: reqwest::Client::builder()
+                .timeout(config.timeout)
+                .build()
+                .context("Failed to build the fact-check client")?,
+            url: url.clone(),
+            api_key: api_key.clone(),
+        }),
+    })
+}
+
+/// Matches the claims of a text against a fact-check corpus
+pub struct ClaimMatcher {
+    corpus: Box<dyn FactCheckCorpus>,
+    min_similarity: f32,
+    max_claims: usize,
+}
+
+impl ClaimMatcher {
+    pub fn new(corpus: Box<dyn FactCheckCorpus>, config: &ClaimMatchConfig) -> Self {
+        Self {
+            corpus,
+            min_similarity: config.min_similarity,
+            max_claims: config.max_claims,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.corpus.name()
+    }
+
+    /// Fact-checks matching the claims of `text`, best match per URL
+    pub async fn matches(&self, text: &str) -> Result<Vec<ClaimMatch>> {
+        let mut best: HashMap<String, ClaimMatch> = HashMap::new();
+        for claim in claims(text, self.max_claims) {
+            for check in self.corpus.search(claim).await? {
+                let similarity = similarity(claim, &check.claim);
+                if similarity < self.min_similarity
+                    || best
+                        .get(&check.url)
+                        .is_some_and(|m| m.similarity >= similarity)
+                {
+                    continue;
+                }
+                best.insert(
+                    check.url.clone(),
+                    ClaimMatch {
+                        claim: claim.to_string(),
+                        url: check.url,
+                        rating: check.rating.trim().to_lowercase(),
+                        similarity,
+                    },
+                );
+            }
+        }
+        let mut matches: Vec<ClaimMatch> = best.into_values().collect();
+        matches.sort_by(|a, b| a.url.cmp(&b.url));
+        Ok(matches)
+    }
+}
+
+/// The `max` longest sentences of `text` long enough to state a claim
+fn claims(text: &str, max: usize) -> Vec<&str> {
+    let mut sentences: Vec<&str> = text
+        .split(['.', '!', '?', '\n'])
+        .map(str::trim)
+        .filter(|sentence| sentence.split_whitespace().count() >= MIN_CLAIM_WORDS)
+        .collect();
+    sentences.sort_by_key(|sentence| std::cmp::Reverse(sentence.len()));
+    sentences.dedup();
+    sentences.truncate(max);
+    sentences
+}
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_WORD_CHARS)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Jaccard overlap of the words of two texts
+fn similarity(a: &str, b: &str) -> f32 {
+    let (a, b) = (words(a), words(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f32 / union as f32
+}
+
+/// One `claim_debunked(url, rating)` fact per matched fact-check
+pub fn claim_facts(matches: &[ClaimMatch]) -> Vec<Fact> {
+    matches
+        .iter()
+        .map(|m| Fact::new("claim_debunked", vec![m.url.clone(), m.rating.clone()]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn matcher() -> ClaimMatcher {
+        let corpus = FileCorpus::new(vec![
+            FactCheck {
+                claim: "The vaccine contains microchips to track people".to_string(),
+                url: "https://example.org/checks/microchips".to_string(),
+                rating: "False".to_string(),
+            },
+            FactCheck {
+                claim: "Drinking bleach cures the flu".to_string(),
+                url: "https://example.org/checks/bleach".to_string(),
+                rating: "Pants on Fire".to_string(),
+            },
+        ]);
+        ClaimMatcher::new(
+            Box::new(corpus),
+            &ClaimMatchConfig {
+                backend: FactCheckBackend::File {
+                    path: PathBuf::new(),
+                },
+                min_similarity: 0.5,
+                max_claims: 3,
+                timeout: Duration::from_secs(1),
+            },
+        )
+    }
+
+    #[test]
+    fn test_claims_are_long_sentences() {
+        let text = "Wake up! They put microchips in the vaccine to track people. Share this \
+                    before it gets deleted by the censors.";
+        assert_eq!(
+            claims(text, 1),
+            ["They put microchips in the vaccine to track people"]
+        );
+        assert_eq!(claims(text, 3).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_claims_match_fact_checks() {
+        let matches = matcher()
+            .matches("Wake up! The vaccine contains microchips to track people.")
+            .await
+            .unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].rating, "false");
+        assert_eq!(
+            claim_facts(&matches),
+            [Fact::new(
+                "claim_debunked",
+                vec![
+                    "https://example.org/checks/microchips".to_string(),
+                    "false".to_string()
+                ]
+            )]
+        );
+
+        // Sharing a word is not enough
+        let matches = matcher()
+            .matches("The flu season started early in the north this year.")
+            .await
+            .unwrap();
+        assert!(matches.is_empty());
+    }
+
+    #[test]
+    fn test_claim_reviews_are_loaded() {
+        let dir = TempDir::new("fact-checks");
+        let path = dir.join("fact-checks.jsonl");
+        fs::write(
+            &path,
+            r#"{"@type": "ClaimReview", "claimReviewed": "Drinking bleach cures the flu", "url": "https://example.org/checks/bleach", "reviewRating": {"@type": "Rating", "alternateName": "False"}}"#,
+        )
+        .unwrap();
+        let corpus = FileCorpus::load(&path).unwrap();
+        assert_eq!(corpus.count(), 1);
+        assert_eq!(corpus.checks[0].rating, "False");
+    }
+}
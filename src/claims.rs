@@ -0,0 +1,508 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Scheduled snapshots of the claim/debunk database
+//!
+//! Stale or corrupted claim data degrades detection silently, so the
+//! claims are periodically dumped to `claims-<unix time>.jsonl`, with a
+//! `-<n>` suffix for later snapshots of the same second, and a
+//! `sha256sum`-compatible sidecar, and verified before the snapshot is
+//! accepted:
+//!
+//! * the dump holds exactly as many claims as the database reports, and
+//!   has not shrunk below half of the previous snapshot
+//! * every claim carries text and a verdict
+//! * the file on disk hashes to the checksum computed while writing
+//! * a sample of claims re-queried one by one matches the dump
+//!
+//! The time of the last verified snapshot is exported as a freshness metric.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{error, info};
+
+use crate::clock::unix_now;
+use crate::metrics::Metrics;
+
+const SNAPSHOT_PREFIX: &str = "claims-";
+const SNAPSHOT_SUFFIX: &str = ".jsonl";
+
+/// Claim snapshot settings; disabled unless a snapshot directory is set
+#[derive(Debug, Clone)]
+pub struct ClaimSnapshotConfig {
+    pub dir: PathBuf,
+    /// Dgraph alpha HTTP endpoint
+    pub dgraph_url: String,
+    pub interval: Duration,
+    /// Claims re-queried individually during verification
+    pub samples: usize,
+    /// Verified snapshots kept on disk
+    pub keep: usize,
+}
+
+/// A claim node with its fact-check verdict
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claim {
+    pub uid: String,
+    #[serde(rename = "claim.text", default)]
+    pub text: String,
+    #[serde(rename = "claim.verdict", default)]
+    pub verdict: String,
+}
+
+/// Read access to the claim database
+pub trait ClaimSource: Send + Sync {
+    /// Number of claims the database reports
+    fn count(&self) -> BoxFuture<'_, Result<usize>>;
+
+    /// Every claim, ordered by uid
+    fn dump(&self) -> BoxFuture<'_, Result<Vec<Claim>>>;
+
+    /// A single claim looked up by uid
+    fn fetch<'a>(&'a self, uid: &'a str) -> BoxFuture<'a, Result<Option<Claim>>>;
+}
+
+/// Claims stored as `Claim` nodes in Dgraph, queried over DQL
+pub struct DgraphClaimSource {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl DgraphClaimSource {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    async fn query(&self, dql: String) -> Result<serde_json::Value> {
+        let body = self
+            .client
+            .post(format!("{}/query", self.url.trim_end_matches('/')))
+            .header("Content-Type", "application/dql")
+            .body(dql)
+            .send()
+            .await
+            .context("Dgraph query failed")?
+            .error_for_status()
+            .context("Dgraph returned an error")?
+            .bytes()
+            .await?;
+        let response: serde_json::Value =
+            serde_json::from_slice(&body).context("Dgraph returned invalid JSON")?;
+        if let Some(errors) = response.get("errors") {
+            bail!("Dgraph query errors: {}", errors);
+        }
+        Ok(response["data"].clone())
+    }
+}
+
+impl ClaimSource for DgraphClaimSource {
+    fn count(&self) -> BoxFuture<'_, Result<usize>> {
+        Box::pin(async move {
+            let data = self
+                .query("{ total(func: type(Claim)) { count(uid) } }".to_string())
+                .await?;
+            data["total"][0]["count"]
+                .as_u64()
+                .map(|count| count as usize)
+                .context("Dgraph count response missing")
+        })
+    }
+
+    fn dump(&self) -> BoxFuture<'_, Result<Vec<Claim>>> {
+        Box::pin(async move {
+            let data = self
+                .query(
+                    "{ claims(func: type(Claim), orderasc: uid) { uid claim.text claim.verdict } }"
+                        .to_string(),
+                )
+                .await?;
+            Ok(serde_json::from_value(data["claims"].clone())?)
+        })
+    }
+
+    fn fetch<'a>(&'a self, uid: &'a str) -> BoxFuture<'a, Result<Option<Claim>>> {
+        Box::pin(async move {
+            // uids are interpolated into DQL, so only accept the hex form
+            let valid = uid
+                .strip_prefix("0x")
+                .is_some_and(|hex| !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()));
+            if !valid {
+                bail!("Invalid claim uid {:?}", uid);
+            }
+            let data = self
+                .query(format!(
+                    "{{ claim(func: uid({})) @filter(type(Claim)) {{ uid claim.text claim.verdict }} }}",
+                    uid
+                ))
+                .await?;
+            let mut claims: Vec<Claim> = serde_json::from_value(data["claim"].clone())?;
+            Ok(claims.pop())
+        })
+    }
+}
+
+/// A snapshot that passed verification
+#[derive(Debug)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub count: usize,
+    pub sha256: String,
+    pub created: u64,
+}
+
+/// Dump, write and verify one snapshot, then prune old ones
+pub async fn snapshot(source: &dyn ClaimSource, config: &ClaimSnapshotConfig) -> Result<Snapshot> {
+    let dir = config.dir.clone();
+    let previous = blocking(move || {
+        fs::create_dir_all(&dir)?;
+        previous_count(&dir)
+    })
+    .await?;
+
+    let claims = source.dump().await?;
+    let expected = source.count().await?;
+    if claims.len() != expected {
+        bail!(
+            "Dumped {} claims but the database reports {}",
+            claims.len(),
+            expected
+        );
+    }
+    if claims.is_empty() {
+        bail!("Claim database is empty");
+    }
+    if let Some(previous) = previous {
+        if claims.len() * 2 < previous {
+            bail!("Claim count dropped from {} to {}", previous, claims.len());
+        }
+    }
+    if let Some(claim) = claims
+        .iter()
+        .find(|c| c.text.trim().is_empty() || c.verdict.trim().is_empty())
+    {
+        bail!("Claim {} is missing text or verdict", claim.uid);
+    }
+
+    let mut body = Vec::new();
+    for claim in &claims {
+        serde_json::to_writer(&mut body, claim)?;
+        body.push(b'\n');
+    }
+    let sha256 = hex::encode(Sha256::digest(&body));
+
+    let created = unix_now();
+    let tmp = config.dir.join(format!(
+        "{}{}.{}.tmp",
+        SNAPSHOT_PREFIX,
+        created,
+        std::process::id()
+    ));
+    let partial = tmp.clone();
+    blocking(move || {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial)?
+            .write_all(&body)?;
+        Ok(())
+    })
+    .await?;
+
+    let verified = verify(source, &tmp, &sha256, &claims, config.samples).await;
+    if let Err(e) = verified {
+        let partial = tmp.clone();
+        let _ = blocking(move || Ok(fs::remove_file(partial)?)).await;
+        return Err(e);
+    }
+
+    let dir = config.dir.clone();
+    let keep = config.keep;
+    let checksum = sha256.clone();
+    let path = blocking(move || {
+        let path = publish(&dir, &tmp, created, &checksum)?;
+        prune(&dir, keep)?;
+        Ok(path)
+    })
+    .await?;
+
+    Ok(Snapshot {
+        path,
+        count: claims.len(),
+        sha256,
+        created,
+    })
+}
+
+/// Run file I/O on the blocking pool rather than an async worker
+async fn blocking<T: Send + 'static>(io: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(io)
+        .await
+        .context("Claim snapshot I/O task failed")?
+}
+
+/// Link the verified `partial` file under a snapshot name not yet taken
+/// and write its checksum sidecar
+fn publish(dir: &Path, partial: &Path, created: u64, sha256: &str) -> Result<PathBuf> {
+    let mut seq = 0;
+    let (name, path) = loop {
+        let name = match seq {
+            0 => format!("{}{}{}", SNAPSHOT_PREFIX, created, SNAPSHOT_SUFFIX),
+            seq => format!("{}{}-{}{}", SNAPSHOT_PREFIX, created, seq, SNAPSHOT_SUFFIX),
+        };
+        let path = dir.join(&name);
+        // Unlike a rename, a link never replaces an existing snapshot
+        match fs::hard_link(partial, &path) {
+            Ok(()) => break (name, path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => seq += 1,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    fs::remove_file(partial)?;
+    fs::write(
+        dir.join(format!("{}.sha256", name)),
+        format!("{}  {}\n", sha256, name),
+    )?;
+    Ok(path)
+}
+
+/// Check the written file and re-query a sample of claims
+async fn verify(
+    source: &dyn ClaimSource,
+    path: &Path,
+    sha256: &str,
+    claims: &[Claim],
+    samples: usize,
+) -> Result<()> {
+    let path = path.to_path_buf();
+    let on_disk = blocking(move || Ok(hex::encode(Sha256::digest(fs::read(path)?)))).await?;
+    if on_disk != sha256 {
+        bail!(
+            "Snapshot checksum mismatch: wrote {}, read {}",
+            sha256,
+            on_disk
+        );
+    }
+
+    for index in sample_indices(claims.len(), samples) {
+        let expected = &claims[index];
+        match source.fetch(&expected.uid).await? {
+            Some(actual) if actual == *expected => {}
+            Some(_) => bail!("Claim {} changed or differs from the dump", expected.uid),
+            None => bail!("Claim {} missing on re-query", expected.uid),
+        }
+    }
+    Ok(())
+}
+
+/// Evenly spread indices, always including the first and last claim
+fn sample_indices(len: usize, samples: usize) -> Vec<usize> {
+    match (len, samples) {
+        (0, _) | (_, 0) => Vec::new(),
+        (len, 1) => vec![len / 2],
+        (len, samples) => {
+            let mut indices: Vec<usize> = (0..samples)
+                .map(|i| i * (len - 1) / (samples - 1))
+                .collect();
+            indices.dedup();
+            indices
+        }
+    }
+}
+
+/// Verified snapshot files, oldest first
+fn snapshots(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<((u64, u64), PathBuf)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let order = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(SNAPSHOT_PREFIX))
+            .and_then(|rest| rest.strip_suffix(SNAPSHOT_SUFFIX))
+            .and_then(|stem| {
+                let (created, seq) = stem.split_once('-').unwrap_or((stem, "0"));
+                Some((created.parse().ok()?, seq.parse().ok()?))
+            });
+        if let Some(order) = order {
+            paths.push((order, path));
+        }
+    }
+    paths.sort();
+    Ok(paths.into_iter().map(|(_, path)| path).collect())
+}
+
+fn previous_count(dir: &Path) -> Result<Option<usize>> {
+    match snapshots(dir)?.last() {
+        Some(path) => Ok(Some(fs::read_to_string(path)?.lines().count())),
+        None => Ok(None),
+    }
+}
+
+fn prune(dir: &Path, keep: usize) -> Result<()> {
+    let paths = snapshots(dir)?;
+    for path in &paths[..paths.len().saturating_sub(keep.max(1))] {
+        fs::remove_file(path)?;
+        let mut sidecar = path.clone().into_os_string();
+        sidecar.push(".sha256");
+        let _ = fs::remove_file(sidecar);
+    }
+    Ok(())
+}
+
+/// Periodically snapshot the claim database and publish freshness metrics
+pub async fn run_snapshots(
+    source: Box<dyn ClaimSource>,
+    config: ClaimSnapshotConfig,
+    metrics: Arc<Metrics>,
+) {
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+        match snapshot(source.as_ref(), &config).await {
+            Ok(snapshot) => {
+                info!(
+                    "Claim snapshot {} verified ({} claims, sha256 {})",
+                    snapshot.path.display(),
+                    snapshot.count,
+                    snapshot.sha256
+                );
+                metrics.claims.set(snapshot.count as f64);
+                metrics
+                    .claim_snapshot_timestamp
+                    .set(snapshot.created as f64);
+            }
+            Err(e) => {
+                error!("Claim snapshot failed: {}", e);
+                metrics.claim_snapshot_failures.inc();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::sync::Mutex;
+
+    struct MemorySource {
+        claims: Vec<Claim>,
+        reported: usize,
+        tampered: Mutex<Option<String>>,
+    }
+
+    impl MemorySource {
+        fn new(count: usize) -> Self {
+            let claims: Vec<Claim> = (0..count)
+                .map(|i| Claim {
+                    uid: format!("0x{:x}", i + 1),
+                    text: format!("claim {}", i),
+                    verdict: "FALSE".to_string(),
+                })
+                .collect();
+            Self {
+                reported: claims.len(),
+                claims,
+                tampered: Mutex::new(None),
+            }
+        }
+    }
+
+    impl ClaimSource for MemorySource {
+        fn count(&self) -> BoxFuture<'_, Result<usize>> {
+            Box::pin(async move { Ok(self.reported) })
+        }
+
+        fn dump(&self) -> BoxFuture<'_, Result<Vec<Claim>>> {
+            Box::pin(async move { Ok(self.claims.clone()) })
+        }
+
+        fn fetch<'a>(&'a self, uid: &'a str) -> BoxFuture<'a, Result<Option<Claim>>> {
+            Box::pin(async move {
+                let mut claim = self.claims.iter().find(|c| c.uid == uid).cloned();
+                if self.tampered.lock().unwrap().as_deref() == Some(uid) {
+                    if let Some(claim) = &mut claim {
+                        claim.verdict = "TRUE".to_string();
+                    }
+                }
+                Ok(claim)
+            })
+        }
+    }
+
+    fn config(dir: &TempDir) -> ClaimSnapshotConfig {
+        ClaimSnapshotConfig {
+            dir: dir.to_path_buf(),
+            dgraph_url: String::new(),
+            interval: Duration::from_secs(60),
+            samples: 5,
+            keep: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_writes_verified_file() {
+        let dir = TempDir::new("claims");
+        let config = config(&dir);
+        let snapshot = snapshot(&MemorySource::new(10), &config).await.unwrap();
+        assert_eq!(snapshot.count, 10);
+
+        let body = fs::read(&snapshot.path).unwrap();
+        assert_eq!(hex::encode(Sha256::digest(&body)), snapshot.sha256);
+        assert_eq!(previous_count(&config.dir).unwrap(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_rejects_inconsistent_data() {
+        let dir = TempDir::new("claims");
+        let config = config(&dir);
+
+        let mut short = MemorySource::new(10);
+        short.reported = 12;
+        assert!(snapshot(&short, &config).await.is_err());
+
+        let tampered = MemorySource::new(10);
+        *tampered.tampered.lock().unwrap() = Some("0x1".to_string());
+        assert!(snapshot(&tampered, &config).await.is_err());
+
+        assert!(snapshots(&config.dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_snapshots_of_one_second_get_distinct_names() {
+        let dir = TempDir::new("claims");
+        let config = config(&dir);
+        let mut published = Vec::new();
+        for body in ["first\n", "second\n", "third\n"] {
+            let partial = config.dir.join("partial.tmp");
+            fs::write(&partial, body).unwrap();
+            published.push(publish(&config.dir, &partial, 1_700_000_000, "00").unwrap());
+            assert!(!partial.exists());
+        }
+        assert_eq!(snapshots(&config.dir).unwrap(), published);
+        assert_eq!(fs::read_to_string(&published[0]).unwrap(), "first\n");
+        assert!(published[2].ends_with("claims-1700000000-2.jsonl"));
+
+        prune(&config.dir, 1).unwrap();
+        assert_eq!(snapshots(&config.dir).unwrap(), &published[2..]);
+    }
+
+    #[test]
+    fn test_sample_indices() {
+        assert_eq!(sample_indices(10, 3), vec![0, 4, 9]);
+        assert_eq!(sample_indices(2, 5), vec![0, 1]);
+        assert!(sample_indices(0, 5).is_empty());
+    }
+}
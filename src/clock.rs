@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Source of the current time
+//!
+//! Code that reasons about how old something is asks a [`Clock`] instead
+//! of calling [`SystemTime::now`], so tests can pin and advance time.
+//! Timestamps written to files and payloads are converted to Unix time by
+//! the helpers here.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Current wall-clock time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Whole seconds from the Unix epoch to `at`; 0 before it
+pub fn unix_secs(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Milliseconds from the Unix epoch to `at`; 0 before it
+pub fn unix_millis(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Seconds since the Unix epoch by the system clock
+pub fn unix_now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+/// Days since the Unix epoch by the system clock, in UTC
+pub fn today() -> u64 {
+    unix_now() / SECS_PER_DAY
+}
+
+/// A clock that only moves when told to
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ManualClock(std::sync::Mutex<SystemTime>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self(std::sync::Mutex::new(now))
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_manual_clock_moves_when_advanced() {
+        let clock = ManualClock::new(UNIX_EPOCH);
+        assert_eq!(clock.now(), UNIX_EPOCH);
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(90));
+        assert!(SystemClock.now() > UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_unix_time_of_instants() {
+        let at = UNIX_EPOCH + Duration::from_millis(90_500);
+        assert_eq!(unix_secs(at), 90);
+        assert_eq!(unix_millis(at), 90_500);
+        assert_eq!(unix_secs(UNIX_EPOCH - Duration::from_secs(1)), 0);
+        assert_eq!(today(), unix_now() / SECS_PER_DAY);
+    }
+}
@@ -0,0 +1,1391 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Runtime configuration
+//!
+//! Settings are read from `NSAI_*` environment variables, falling back to
+//! defaults that match the container deployment.
+
+use anyhow::{Context, Result};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::attribution::AttributionConfig;
+use crate::batcher::BatchConfig;
+use crate::calibration::CalibrationSpec;
+use crate::campaigns::CampaignConfig;
+use crate::canary::CanaryConfig;
+use crate::cardinality::CardinalityConfig;
+use crate::claim_matching::{ClaimMatchConfig, FactCheckBackend, FactCheckKind};
+use crate::claims::ClaimSnapshotConfig;
+use crate::content_object::ObjectContentConfig;
+use crate::content_store::{ContentStoreBackend, ContentStoreConfig, ContentStoreKind};
+use crate::dedup::DedupConfig;
+use crate::enrichment::{EnrichmentConfig, EnrichmentKind, EnrichmentProvider};
+use crate::explanations::ExplanationConfig;
+use crate::fact_dump::FactDumpConfig;
+use crate::fallback::FallbackConfig;
+use crate::feature_cache::FeatureCacheBackend;
+use crate::graph_batcher::GraphBatchConfig;
+use crate::graph_breaker::GraphBreakerConfig;
+use crate::graph_cache::GraphCacheConfig;
+use crate::history::HistoryConfig;
+use crate::image_hash::ImageHashConfig;
+use crate::knowledge_graph::{KnowledgeGraphBackend, KnowledgeGraphConfig, KnowledgeGraphKind};
+use crate::language::LanguageModel;
+use crate::links::{self, LinkReputationConfig, ReputationApi};
+use crate::memory_guard::MemoryGuardConfig;
+use crate::model_download::ModelDownloadConfig;
+use crate::nats::{NatsConfig, RouteModel};
+use crate::ner::NerConfig;
+use crate::ocr::{OcrBackend, OcrKind};
+use crate::onnx_wrapper::{EmbeddingConfig, FusionStrategy, ModelSpec};
+use crate::postprocess::PostProcessorSpec;
+use crate::quota::{OverflowAction, QuotaConfig, QuotaLimits};
+use crate::reasoning_cache::ReasoningCacheConfig;
+use crate::reasoning_pool::ReasoningPoolConfig;
+use crate::reevaluation::ReevaluationConfig;
+use crate::request_reply::RequestReplyConfig;
+use crate::retry::RetryConfig;
+use crate::session_pool::SessionOptions;
+use crate::shadow::ShadowConfig;
+use crate::similarity::SimilarityConfig;
+use crate::souffle_sandbox::SouffleSandbox;
+use crate::souffle_wrapper::{Aggregation, ConflictPolicy, SymbolicBackend, SymbolicKind};
+use crate::stance::{self, StanceConfig};
+use crate::telemetry::{TelemetryConfig, TelemetryField};
+use crate::thresholds::BinEdges;
+use crate::topic::TopicModel;
+use crate::transport::KafkaConfig;
+use crate::validation::ValidationConfig;
+use crate::verdict_store::VerdictStoreConfig;
+use crate::webhook::WebhookConfig;
+
+/// What the consumer does when the JetStream message stream ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEndAction {
+    /// Re-open the message stream and keep consuming
+    Resubscribe,
+    /// Shut the service down
+    Exit,
+}
+
+impl FromStr for StreamEndAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "resubscribe" => Ok(Self::Resubscribe),
+            "exit" => Ok(Self::Exit),
+            other => anyhow::bail!("unknown stream end action: {}", other),
+        }
+    }
+}
+
+/// Idle and end-of-stream behavior of the consumer loop
+#[derive(Debug, Clone)]
+pub struct IdleConfig {
+    /// How long the consumer must be idle before a heartbeat is emitted
+    pub idle_after: Duration,
+    /// Run maintenance tasks when the idle threshold is reached
+    pub run_maintenance: bool,
+    /// Behavior when the message stream returns `None`
+    pub on_stream_end: StreamEndAction,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            idle_after: Duration::from_secs(5 * 60),
+            run_maintenance: true,
+            on_stream_end: StreamEndAction::Resubscribe,
+        }
+    }
+}
+
+/// How the consumer loop works through the stream
+#[derive(Debug, Clone)]
+pub struct ConsumerConfig {
+    /// Messages processed at once; derived from the inference settings
+    /// when `None`
+    pub workers: Option<usize>,
+    /// Messages requested per pull; the worker count when `None`
+    pub fetch_size: Option<usize>,
+    /// Payload bytes requested per pull; unlimited when `None`
+    pub fetch_max_bytes: Option<usize>,
+    /// Payload bytes of the messages in flight before pulling stops;
+    /// unlimited when `None`
+    pub max_in_flight_bytes: Option<usize>,
+    /// How long a pull waits on an empty stream before it is renewed
+    pub fetch_expiry: Duration,
+    /// How long the server waits for an acknowledgement before it
+    /// redelivers a message
+    pub ack_wait: Duration,
+    /// Acknowledge progress while a message is processed, so a long
+    /// analysis is not redelivered
+    pub ack_progress: bool,
+    /// How often the server confirms an open pull is alive; half the
+    /// fetch expiry when `None`
+    pub heartbeat: Option<Duration>,
+    /// Messages handed out and not yet acknowledged, across all replicas;
+    /// the server default when `None`
+    pub max_ack_pending: Option<i64>,
+    /// Deliveries after which the server stops redelivering a message;
+    /// unlimited when `None`
+    pub max_deliver: Option<i64>,
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        Self {
+            workers: None,
+            fetch_size: None,
+            fetch_max_bytes: None,
+            max_in_flight_bytes: None,
+            fetch_expiry: Duration::from_secs(30),
+            ack_wait: Duration::from_secs(30),
+            ack_progress: true,
+            heartbeat: None,
+            max_ack_pending: None,
+            max_deliver: None,
+        }
+    }
+}
+
+impl ConsumerConfig {
+    /// Messages processed at once: `workers`, or by default the session
+    /// pool size times the batch size so batches can fill
+    pub fn workers(&self, inference: &InferenceConfig) -> usize {
+        self.workers
+            .unwrap_or(inference.sessions.pool_size.max(1) * inference.batch.max_size.max(1))
+    }
+}
+
+/// Neural inference settings
+#[derive(Debug, Clone)]
+pub struct InferenceConfig {
+    /// Deadline for a single inference before it is cancelled
+    pub timeout: Duration,
+    /// Models run concurrently on every input; the fallback for languages
+    /// without dedicated models
+    pub models: Vec<ModelSpec>,
+    /// Dedicated models per detected language
+    pub language_models: Vec<LanguageModel>,
+    /// Specialized models per classified topic; languages with dedicated
+    /// models take precedence
+    pub topic_models: Vec<TopicModel>,
+    /// Models serving the routes of a wildcard NATS subject
+    pub route_models: Vec<RouteModel>,
+    /// Fraction of topic-routed messages (0-1) also run through the
+    /// default models to compare verdicts
+    pub topic_compare_rate: f64,
+    /// How per-model scores are combined
+    pub fusion: FusionStrategy,
+    /// Root of the versioned model registry; model paths are resolved
+    /// through it when set
+    pub registry: Option<String>,
+    /// Key registry versions must be approved with by `validate-model`;
+    /// approval is not checked when unset
+    pub approval_key: Option<String>,
+    /// Session pool size and runtime threads; the pool size also bounds
+    /// how many messages are processed at once
+    pub sessions: SessionOptions,
+    /// Queue-depth driven batching of inference requests
+    pub batch: BatchConfig,
+    /// Sentence embedding published with results; off when unset
+    pub embedding: Option<EmbeddingConfig>,
+    /// Token attributions published with results; off when unset
+    pub attribution: Option<AttributionConfig>,
+    /// Engine reading text from images into the input; off when unset
+    pub ocr: Option<OcrBackend>,
+    /// Known manipulated images matched by perceptual hash, fed to the
+    /// rules; off when unset
+    pub known_images: Option<ImageHashConfig>,
+    /// Stance towards configured claims, fed to the rules; off when unset
+    pub stance: Option<StanceConfig>,
+    /// Named entities fed to the rules and the graph; off when unset
+    pub ner: Option<NerConfig>,
+    /// Scorer used when the primary models fail; messages are dropped or
+    /// redelivered instead when unset
+    pub fallback: Option<FallbackConfig>,
+    /// Dummy inference rounds run before consuming messages
+    pub warmup_rounds: usize,
+    /// Mapping of raw scores to probabilities before the rules run
+    pub calibration: Vec<CalibrationSpec>,
+    /// Cache and credentials for `https://` and `s3://` model paths
+    pub download: ModelDownloadConfig,
+}
+
+impl Default for InferenceConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(5000),
+            models: vec![ModelSpec {
+                name: "detector".to_string(),
+                path: "models/detector.onnx".to_string(),
+                weight: 1.0,
+                version: None,
+            }],
+            language_models: Vec::new(),
+            topic_models: Vec::new(),
+            route_models: Vec::new(),
+            topic_compare_rate: 0.1,
+            fusion: FusionStrategy::Mean,
+            registry: None,
+            approval_key: None,
+            sessions: SessionOptions::default(),
+            batch: BatchConfig::default(),
+            embedding: None,
+            attribution: None,
+            ocr: None,
+            known_images: None,
+            stance: None,
+            ner: None,
+            fallback: None,
+            warmup_rounds: 10,
+            calibration: Vec::new(),
+            download: ModelDownloadConfig {
+                cache_dir: PathBuf::from("models/cache"),
+                timeout: Duration::from_secs(600),
+                s3_endpoint: "https://s3.us-east-1.amazonaws.com".to_string(),
+                s3_region: "us-east-1".to_string(),
+                access_key: None,
+                secret_key: None,
+            },
+        }
+    }
+}
+
+/// Feature cache settings
+#[derive(Debug, Clone)]
+pub struct FeatureCacheConfig {
+    pub backend: FeatureCacheBackend,
+    /// Maximum entries held by the in-memory backend
+    pub capacity: usize,
+    /// Redis connection URL for the shared backend
+    pub redis_url: String,
+    /// Expiry of Redis entries
+    pub ttl: Duration,
+}
+
+impl Default for FeatureCacheConfig {
+    fn default() -> Self {
+        Self {
+            backend: FeatureCacheBackend::Memory,
+            capacity: 100_000,
+            redis_url: "redis://redis:6379".to_string(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Result encodings published during the schema migration window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultFormats {
+    /// Only the rich `AnalysisResult`
+    Rich,
+    /// Only the minimal `LegacyVerdict`
+    Legacy,
+    /// Both, each on its own subject
+    Both,
+}
+
+impl ResultFormats {
+    pub fn rich(self) -> bool {
+        matches!(self, Self::Rich | Self::Both)
+    }
+
+    pub fn legacy(self) -> bool {
+        matches!(self, Self::Legacy | Self::Both)
+    }
+}
+
+impl FromStr for ResultFormats {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rich" => Ok(Self::Rich),
+            "legacy" => Ok(Self::Legacy),
+            "both" => Ok(Self::Both),
+            other => anyhow::bail!("unknown result formats: {}", other),
+        }
+    }
+}
+
+/// Where and how analysis results are published
+#[derive(Debug, Clone)]
+pub struct PublishConfig {
+    /// Subject for the rich `AnalysisResult`
+    pub result_subject: String,
+    /// Subject for the minimal `LegacyVerdict`
+    pub legacy_subject: String,
+    /// Which encodings to publish
+    pub formats: ResultFormats,
+    /// Directory decision contexts are written to; results reference no
+    /// context when unset
+    pub context_dir: Option<PathBuf>,
+    /// Post-processors applied to every result, in order
+    pub post_processors: Vec<PostProcessorSpec>,
+    /// Batched webhook delivery of selected verdicts, off when unset
+    pub webhooks: Option<WebhookConfig>,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        Self {
+            result_subject: "disinfo.verdicts".to_string(),
+            legacy_subject: "disinfo.verdicts.legacy".to_string(),
+            formats: ResultFormats::Both,
+            context_dir: None,
+            post_processors: Vec::new(),
+            webhooks: None,
+        }
+    }
+}
+
+/// Top-level service configuration
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// NATS servers and the stream inputs are consumed from
+    pub nats: NatsConfig,
+    /// Kafka topics inputs are consumed from and results produced to,
+    /// instead of NATS; `None` unless `NSAI_TRANSPORT=kafka`
+    pub kafka: Option<KafkaConfig>,
+    /// Identity of this replica in logs, metrics and results
+    pub instance: String,
+    pub idle: IdleConfig,
+    pub consumer: ConsumerConfig,
+    /// Redelivery of messages that failed transiently
+    pub retry: RetryConfig,
+    pub inference: InferenceConfig,
+    pub feature_cache: FeatureCacheConfig,
+    pub publish: PublishConfig,
+    /// Research telemetry, `None` unless opted in
+    pub telemetry: Option<TelemetryConfig>,
+    /// Similarity API, `None` unless a listen address is configured
+    pub similarity: Option<SimilarityConfig>,
+    /// Candidate model evaluated alongside the primary, `None` if unset
+    pub shadow: Option<ShadowConfig>,
+    /// Claim database snapshots, `None` unless a snapshot directory is set
+    pub claims: Option<ClaimSnapshotConfig>,
+    /// Fact-checks matched against the claims of the content, `None`
+    /// unless a corpus is set
+    pub claim_matching: Option<ClaimMatchConfig>,
+    /// Indicators of known campaigns, `None` unless a directory is set
+    pub campaigns: Option<CampaignConfig>,
+    /// Reputation of linked domains, `None` unless a domain list or a
+    /// reputation API is set
+    pub link_reputation: Option<LinkReputationConfig>,
+    /// Verdict history aggregates for the rules, `None` unless a history
+    /// file is set
+    pub history: Option<HistoryConfig>,
+    /// Knowledge graph lookups of message sources, `None` unless a graph
+    /// is set
+    pub knowledge_graph: Option<KnowledgeGraphConfig>,
+    /// Source metadata from external providers, `None` unless a provider
+    /// is set
+    pub enrichment: Option<EnrichmentConfig>,
+    /// Localized explanation templates, `None` unless a template file is
+    /// set
+    pub explanations: Option<ExplanationConfig>,
+    pub validation: ValidationConfig,
+    /// Retrieval of content text by hash, `None` unless a store is set
+    pub content_store: Option<ContentStoreConfig>,
+    /// Text sent as object references, `None` unless buckets are set
+    pub content_objects: Option<ObjectContentConfig>,
+    /// Canary verification, `None` unless a canary key is set
+    pub canary: Option<CanaryConfig>,
+    /// Memory guardrails, `None` unless a memory limit is set
+    pub memory: Option<MemoryGuardConfig>,
+    /// Daily quotas, `None` unless some limit is set
+    pub quota: Option<QuotaConfig>,
+    /// Label budgets of tenant-labeled metrics
+    pub metrics: CardinalityConfig,
+    /// Engine evaluating the rules
+    pub symbolic: SymbolicBackend,
+    /// Limits of the Soufflé subprocess
+    pub souffle_sandbox: SouffleSandbox,
+    /// Directory of rule packs run instead of `NSAI_RULES`, `None` if unset
+    pub rules_dir: Option<PathBuf>,
+    /// How often rule files are checked for changes, `None` to reload on
+    /// `SIGHUP` only
+    pub rules_reload: Option<Duration>,
+    /// Bin edges replacing the defaults for every message
+    pub bins: Vec<BinEdges>,
+    /// Per-language and per-tenant bin edges, `None` if unset
+    pub bins_file: Option<PathBuf>,
+    /// Bounds of concurrent rule evaluations
+    pub reasoning_pool: ReasoningPoolConfig,
+    /// Cache of derivations, `None` unless a TTL is set
+    pub reasoning_cache: Option<ReasoningCacheConfig>,
+    /// Answering repeated inputs with their published result, `None`
+    /// unless a TTL is set
+    pub dedup: Option<DedupConfig>,
+    /// Latest verdict per content in a key-value bucket, `None` unless a
+    /// bucket is set
+    pub verdict_store: Option<VerdictStoreConfig>,
+    /// Synchronous analysis of core NATS requests, `None` unless enabled
+    pub request_reply: Option<RequestReplyConfig>,
+    /// Re-evaluation of recent verdicts on fact updates, `None` unless a
+    /// subject is set
+    pub reevaluation: Option<ReevaluationConfig>,
+    /// Dumps of rule facts for debugging, `None` unless a sink is set
+    pub fact_dump: Option<FactDumpConfig>,
+    /// Combines the weights of weighted rules into the confidence
+    pub aggregation: Aggregation,
+    /// Resolves contradictory verdicts of the rules
+    pub conflicts: ConflictPolicy,
+}
+
+/// Load the NATS settings from the environment
+///
+/// The tools that only publish to NATS read these without the rest of the
+/// service configuration.
+pub fn nats_from_env() -> Result<NatsConfig> {
+    let defaults = NatsConfig::default();
+    Ok(NatsConfig {
+        servers: env_list("NSAI_NATS_URL")?.unwrap_or(defaults.servers),
+        name: env_parse("NSAI_NATS_NAME")?.unwrap_or(defaults.name),
+        credentials: env_parse("NSAI_NATS_CREDENTIALS")?,
+        stream: env_parse("NSAI_NATS_STREAM")?.unwrap_or(defaults.stream),
+        subject: env_parse("NSAI_NATS_SUBJECT")?.unwrap_or(defaults.subject),
+        consumer: env_parse("NSAI_NATS_CONSUMER")?.unwrap_or(defaults.consumer),
+        priority_subject: env_parse("NSAI_NATS_PRIORITY_SUBJECT")?,
+        priority_consumer: env_parse("NSAI_NATS_PRIORITY_CONSUMER")?
+            .unwrap_or(defaults.priority_consumer),
+        reconnect_buffer: env_parse("NSAI_NATS_RECONNECT_BUFFER")?
+            .unwrap_or(defaults.reconnect_buffer),
+        max_reconnects: env_parse("NSAI_NATS_MAX_RECONNECTS")?.or(defaults.max_reconnects),
+        connect_timeout: env_parse::<u64>("NSAI_NATS_CONNECT_TIMEOUT_MS")?
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.connect_timeout),
+    })
+}
+
+/// Identity of this replica: `NSAI_INSTANCE_ID`, else the host name, which
+/// is the pod name on Kubernetes
+fn instance_from_env() -> Result<String> {
+    Ok(match env_parse("NSAI_INSTANCE_ID")? {
+        Some(instance) => instance,
+        None => std::env::var("HOSTNAME")
+            .ok()
+            .filter(|host| !host.is_empty())
+            .unwrap_or_else(|| format!("pid-{}", std::process::id())),
+    })
+}
+
+impl Config {
+    /// Load configuration from the environment
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let idle = IdleConfig {
+            idle_after: env_parse::<u64>("NSAI_IDLE_AFTER_MINUTES")?
+                .map(|m| Duration::from_secs(m * 60))
+                .unwrap_or(defaults.idle.idle_after),
+            run_maintenance: env_parse("NSAI_IDLE_MAINTENANCE")?
+                .unwrap_or(defaults.idle.run_maintenance),
+            on_stream_end: env_parse("NSAI_ON_STREAM_END")?.unwrap_or(defaults.idle.on_stream_end),
+        };
+
+        let consumer = ConsumerConfig {
+            workers: env_parse("NSAI_WORKERS")?,
+            fetch_size: env_parse("NSAI_FETCH_BATCH_SIZE")?,
+            fetch_max_bytes: env_parse("NSAI_FETCH_MAX_BYTES")?,
+            max_in_flight_bytes: env_parse("NSAI_MAX_IN_FLIGHT_BYTES")?,
+            fetch_expiry: env_parse::<u64>("NSAI_FETCH_EXPIRY_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.consumer.fetch_expiry),
+            ack_wait: env_parse::<u64>("NSAI_ACK_WAIT_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.consumer.ack_wait),
+            ack_progress: env_parse("NSAI_ACK_PROGRESS")?.unwrap_or(defaults.consumer.ack_progress),
+            heartbeat: env_parse::<u64>("NSAI_FETCH_HEARTBEAT_MS")?.map(Duration::from_millis),
+            max_ack_pending: env_parse("NSAI_MAX_ACK_PENDING")?,
+            max_deliver: env_parse("NSAI_MAX_DELIVER")?,
+        };
+        if consumer.workers == Some(0) {
+            anyhow::bail!("NSAI_WORKERS must be at least 1");
+        }
+        if consumer.fetch_size == Some(0) {
+            anyhow::bail!("NSAI_FETCH_BATCH_SIZE must be at least 1");
+        }
+        if consumer.ack_wait < Duration::from_secs(1) {
+            anyhow::bail!("NSAI_ACK_WAIT_SECS must be at least 1");
+        }
+        if consumer.fetch_expiry < Duration::from_secs(1) {
+            anyhow::bail!("NSAI_FETCH_EXPIRY_MS must be at least 1000");
+        }
+        if consumer
+            .heartbeat
+            .is_some_and(|heartbeat| heartbeat >= consumer.fetch_expiry)
+        {
+            anyhow::bail!("NSAI_FETCH_HEARTBEAT_MS must be below NSAI_FETCH_EXPIRY_MS");
+        }
+        if consumer.max_in_flight_bytes == Some(0) {
+            anyhow::bail!("NSAI_MAX_IN_FLIGHT_BYTES must be at least 1");
+        }
+        if consumer.max_ack_pending.is_some_and(|max| max < 1) {
+            anyhow::bail!("NSAI_MAX_ACK_PENDING must be at least 1");
+        }
+
+        let retry = RetryConfig {
+            initial: env_parse::<u64>("NSAI_RETRY_BACKOFF_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.retry.initial),
+            max: env_parse::<u64>("NSAI_RETRY_BACKOFF_MAX_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.retry.max),
+            max_deliveries: env_parse("NSAI_RETRY_MAX_DELIVERIES")?
+                .unwrap_or(defaults.retry.max_deliveries),
+            graph: env_parse("NSAI_RETRY_GRAPH_FAILURES")?.unwrap_or(defaults.retry.graph),
+        };
+        if retry.max_deliveries == 0 {
+            anyhow::bail!("NSAI_RETRY_MAX_DELIVERIES must be at least 1");
+        }
+        // The server must not give up before the last delivery, which is
+        // decided without the graph instead of being retried
+        if consumer
+            .max_deliver
+            .is_some_and(|max| max < retry.max_deliveries as i64)
+        {
+            anyhow::bail!("NSAI_MAX_DELIVER must be at least NSAI_RETRY_MAX_DELIVERIES");
+        }
+
+        let s3_region: String = env_parse("NSAI_S3_REGION")?
+            .unwrap_or_else(|| defaults.inference.download.s3_region.clone());
+        let inference = InferenceConfig {
+            timeout: env_parse::<u64>("NSAI_INFERENCE_TIMEOUT_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.inference.timeout),
+            models: env_list("NSAI_MODELS")?.unwrap_or(defaults.inference.models),
+            language_models: env_list("NSAI_LANGUAGE_MODELS")?
+                .unwrap_or(defaults.inference.language_models),
+            topic_models: env_list("NSAI_TOPIC_MODELS")?.unwrap_or(defaults.inference.topic_models),
+            route_models: env_list("NSAI_ROUTE_MODELS")?.unwrap_or(defaults.inference.route_models),
+            topic_compare_rate: env_parse("NSAI_TOPIC_COMPARE_RATE")?
+                .unwrap_or(defaults.inference.topic_compare_rate),
+            fusion: env_parse("NSAI_FUSION")?.unwrap_or(defaults.inference.fusion),
+            registry: env_parse("NSAI_MODEL_REGISTRY")?,
+            approval_key: env_parse("NSAI_APPROVAL_KEY")?,
+            sessions: SessionOptions {
+                pool_size: env_parse("NSAI_SESSION_POOL_SIZE")?
+                    .unwrap_or(defaults.inference.sessions.pool_size),
+                intra_op_threads: env_parse("NSAI_INTRA_OP_THREADS")?
+                    .unwrap_or(defaults.inference.sessions.intra_op_threads),
+                inter_op_threads: env_parse("NSAI_INTER_OP_THREADS")?
+                    .unwrap_or(defaults.inference.sessions.inter_op_threads),
+            },
+            batch: BatchConfig {
+                max_size: env_parse("NSAI_BATCH_MAX_SIZE")?
+                    .unwrap_or(defaults.inference.batch.max_size),
+                max_wait: env_parse::<u64>("NSAI_BATCH_MAX_WAIT_MS")?
+                    .map(Duration::from_millis)
+                    .unwrap_or(defaults.inference.batch.max_wait),
+                full_backlog: env_parse("NSAI_BATCH_FULL_BACKLOG")?
+                    .unwrap_or(defaults.inference.batch.full_backlog),
+                ..defaults.inference.batch
+            },
+            embedding: match env_parse::<String>("NSAI_EMBEDDING_MODEL")? {
+                Some(model) => Some(EmbeddingConfig {
+                    model,
+                    dim: env_parse("NSAI_EMBEDDING_DIM")?.unwrap_or(384),
+                }),
+                None => None,
+            },
+            attribution: match env_parse("NSAI_ATTRIBUTION")? {
+                Some(method) => Some(AttributionConfig {
+                    method,
+                    top_k: env_parse("NSAI_ATTRIBUTION_TOP_K")?.unwrap_or(5),
+                }),
+                None => None,
+            },
+            ocr: match env_parse::<OcrKind>("NSAI_OCR")? {
+                Some(OcrKind::Onnx) => Some(OcrBackend::Onnx {
+                    model: env_parse("NSAI_OCR_MODEL")?
+                        .unwrap_or_else(|| "models/ocr.onnx".to_string()),
+                }),
+                Some(OcrKind::Tesseract) => Some(OcrBackend::Tesseract {
+                    binary: env_parse("NSAI_TESSERACT")?
+                        .unwrap_or_else(|| PathBuf::from("tesseract")),
+                    languages: env_parse("NSAI_OCR_LANGUAGES")?
+                        .unwrap_or_else(|| "eng".to_string()),
+                }),
+                None => None,
+            },
+            known_images: match env_parse("NSAI_KNOWN_FAKE_IMAGES")? {
+                Some(path) => Some(ImageHashConfig {
+                    path,
+                    max_distance: env_parse("NSAI_IMAGE_HASH_MAX_DISTANCE")?.unwrap_or(6),
+                }),
+                None => None,
+            },
+            stance: match env_parse::<String>("NSAI_STANCE_MODEL")? {
+                Some(model) => Some(StanceConfig {
+                    model,
+                    targets: stance::load_targets(Path::new(&env_required(
+                        "NSAI_STANCE_TARGETS",
+                    )?))?,
+                }),
+                None => None,
+            },
+            ner: match env_parse::<String>("NSAI_NER_MODEL")? {
+                Some(model) => Some(NerConfig {
+                    model,
+                    min_confidence: env_parse("NSAI_NER_MIN_CONFIDENCE")?.unwrap_or(0.5),
+                    dgraph_url: if env_parse("NSAI_NER_UPSERT")?.unwrap_or(false) {
+                        Some(
+                            env_parse("NSAI_DGRAPH_URL")?
+                                .unwrap_or_else(|| "http://dgraph-alpha:8080".to_string()),
+                        )
+                    } else {
+                        None
+                    },
+                }),
+                None => None,
+            },
+            fallback: env_parse("NSAI_FALLBACK")?,
+            warmup_rounds: env_parse("NSAI_WARMUP_ROUNDS")?
+                .unwrap_or(defaults.inference.warmup_rounds),
+            calibration: env_list("NSAI_CALIBRATION")?.unwrap_or(defaults.inference.calibration),
+            download: ModelDownloadConfig {
+                cache_dir: env_parse("NSAI_MODEL_CACHE_DIR")?
+                    .unwrap_or(defaults.inference.download.cache_dir),
+                timeout: env_parse::<u64>("NSAI_MODEL_DOWNLOAD_TIMEOUT_SECS")?
+                    .map(Duration::from_secs)
+                    .unwrap_or(defaults.inference.download.timeout),
+                s3_endpoint: env_parse("NSAI_S3_ENDPOINT")?
+                    .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", s3_region)),
+                s3_region,
+                access_key: env_parse("AWS_ACCESS_KEY_ID")?,
+                secret_key: env_parse("AWS_SECRET_ACCESS_KEY")?,
+            },
+        };
+
+        let feature_cache = FeatureCacheConfig {
+            backend: env_parse("NSAI_FEATURE_CACHE")?.unwrap_or(defaults.feature_cache.backend),
+            capacity: env_parse("NSAI_FEATURE_CACHE_CAPACITY")?
+                .unwrap_or(defaults.feature_cache.capacity),
+            redis_url: env_parse("NSAI_REDIS_URL")?.unwrap_or(defaults.feature_cache.redis_url),
+            ttl: env_parse::<u64>("NSAI_FEATURE_CACHE_TTL_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.feature_cache.ttl),
+        };
+
+        let publish = PublishConfig {
+            result_subject: env_parse("NSAI_RESULT_SUBJECT")?
+                .unwrap_or(defaults.publish.result_subject),
+            legacy_subject: env_parse("NSAI_LEGACY_RESULT_SUBJECT")?
+                .unwrap_or(defaults.publish.legacy_subject),
+            formats: env_parse("NSAI_RESULT_FORMATS")?.unwrap_or(defaults.publish.formats),
+            context_dir: env_parse("NSAI_DECISION_CONTEXT_DIR")?,
+            post_processors: env_list("NSAI_POST_PROCESSORS")?.unwrap_or_default(),
+            webhooks: match env_list::<String>("NSAI_WEBHOOKS")? {
+                Some(urls) => Some(WebhookConfig {
+                    urls,
+                    verdicts: env_list("NSAI_WEBHOOK_VERDICTS")?
+                        .unwrap_or_else(|| vec!["DISINFO".to_string()])
+                        .into_iter()
+                        .collect(),
+                    max_batch: env_parse("NSAI_WEBHOOK_BATCH_SIZE")?.unwrap_or(100),
+                    max_delay: Duration::from_millis(
+                        env_parse("NSAI_WEBHOOK_BATCH_DELAY_MS")?.unwrap_or(1000),
+                    ),
+                    concurrency: env_parse("NSAI_WEBHOOK_CONCURRENCY")?.unwrap_or(2),
+                    queue: env_parse("NSAI_WEBHOOK_QUEUE")?.unwrap_or(10_000),
+                    outbox: env_parse("NSAI_WEBHOOK_OUTBOX")?,
+                    outbox_max_bytes: env_parse::<u64>("NSAI_WEBHOOK_OUTBOX_MAX_MB")?
+                        .unwrap_or(256)
+                        .saturating_mul(1024 * 1024),
+                    min_severity: env_parse("NSAI_WEBHOOK_MIN_SEVERITY")?,
+                    secret: env_parse("NSAI_WEBHOOK_SECRET")?,
+                    retries: env_parse("NSAI_WEBHOOK_RETRIES")?.unwrap_or(3),
+                    retry_backoff: Duration::from_millis(
+                        env_parse("NSAI_WEBHOOK_RETRY_BACKOFF_MS")?.unwrap_or(500),
+                    ),
+                }),
+                None => None,
+            },
+        };
+
+        let telemetry = match env_parse::<String>("NSAI_TELEMETRY_ENDPOINT")? {
+            Some(endpoint) => Some(TelemetryConfig {
+                endpoint,
+                signing_key: env_required("NSAI_TELEMETRY_KEY")?,
+                source_salt: env_required("NSAI_TELEMETRY_SALT")?,
+                interval: Duration::from_secs(
+                    env_parse("NSAI_TELEMETRY_INTERVAL_SECS")?
+                        .unwrap_or(3600)
+                        .max(1),
+                ),
+                allowed_fields: env_list::<TelemetryField>("NSAI_TELEMETRY_FIELDS")?
+                    .unwrap_or_else(|| {
+                        vec![
+                            TelemetryField::Window,
+                            TelemetryField::MessageCount,
+                            TelemetryField::VerdictCounts,
+                        ]
+                    })
+                    .into_iter()
+                    .collect(),
+            }),
+            None => None,
+        };
+
+        let similarity = match env_parse("NSAI_SIMILARITY_ADDR")? {
+            Some(listen) => Some(SimilarityConfig {
+                listen,
+                capacity: env_parse("NSAI_SIMILARITY_CAPACITY")?.unwrap_or(50_000),
+                min_similarity: env_parse("NSAI_SIMILARITY_MIN")?.unwrap_or(0.5),
+            }),
+            None => None,
+        };
+
+        let shadow = match env_parse("NSAI_SHADOW_MODEL")? {
+            Some(model) => Some(ShadowConfig {
+                model,
+                sample_rate: env_parse("NSAI_SHADOW_SAMPLE_RATE")?.unwrap_or(0.1),
+                subject: env_parse("NSAI_SHADOW_SUBJECT")?
+                    .unwrap_or_else(|| "disinfo.shadow".to_string()),
+            }),
+            None => None,
+        };
+
+        let claims = match env_parse("NSAI_CLAIM_SNAPSHOT_DIR")? {
+            Some(dir) => Some(ClaimSnapshotConfig {
+                dir,
+                dgraph_url: env_parse("NSAI_DGRAPH_URL")?
+                    .unwrap_or_else(|| "http://dgraph-alpha:8080".to_string()),
+                interval: Duration::from_secs(
+                    env_parse("NSAI_CLAIM_SNAPSHOT_INTERVAL_SECS")?
+                        .unwrap_or(6 * 60 * 60)
+                        .max(1),
+                ),
+                samples: env_parse("NSAI_CLAIM_SNAPSHOT_SAMPLES")?.unwrap_or(20),
+                keep: env_parse("NSAI_CLAIM_SNAPSHOT_KEEP")?.unwrap_or(7),
+            }),
+            None => None,
+        };
+
+        let claim_matching = match env_parse("NSAI_FACT_CHECKS")? {
+            Some(kind) => Some(ClaimMatchConfig {
+                backend: match kind {
+                    FactCheckKind::File => FactCheckBackend::File {
+                        path: env_required("NSAI_FACT_CHECK_FILE")?.into(),
+                    },
+                    FactCheckKind::Google => FactCheckBackend::Google {
+                        url: env_parse("NSAI_FACT_CHECK_API_URL")?.unwrap_or_else(|| {
+                            "https://factchecktools.googleapis.com/v1alpha1/claims:search"
+                                .to_string()
+                        }),
+                        api_key: env_required("NSAI_FACT_CHECK_API_KEY")?,
+                    },
+                },
+                min_similarity: env_parse("NSAI_CLAIM_MIN_SIMILARITY")?.unwrap_or(0.6),
+                max_claims: env_parse("NSAI_CLAIM_MAX_PER_MESSAGE")?.unwrap_or(3),
+                timeout: Duration::from_millis(
+                    env_parse("NSAI_FACT_CHECK_TIMEOUT_MS")?.unwrap_or(1000),
+                ),
+            }),
+            None => None,
+        };
+
+        let enrichment = match env_parse::<String>("NSAI_ENRICHMENT_PROVIDERS")? {
+            Some(names) => Some(EnrichmentConfig {
+                providers: names
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(|name| {
+                        Ok(match name.parse()? {
+                            EnrichmentKind::Wikidata => EnrichmentProvider::Wikidata {
+                                url: env_parse("NSAI_WIKIDATA_SPARQL_URL")?.unwrap_or_else(|| {
+                                    "https://query.wikidata.org/sparql".to_string()
+                                }),
+                            },
+                            EnrichmentKind::GeoIp => EnrichmentProvider::GeoIp {
+                                url: env_required("NSAI_GEOIP_URL")?,
+                            },
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+                timeout: Duration::from_millis(
+                    env_parse("NSAI_ENRICHMENT_TIMEOUT_MS")?.unwrap_or(2000),
+                ),
+                cache_ttl: Duration::from_secs(
+                    env_parse("NSAI_ENRICHMENT_CACHE_TTL_SECS")?.unwrap_or(86_400),
+                ),
+            }),
+            None => None,
+        };
+
+        let campaigns = match env_parse("NSAI_CAMPAIGNS_DIR")? {
+            Some(dir) => Some(CampaignConfig {
+                dir,
+                reload: Duration::from_secs(
+                    env_parse("NSAI_CAMPAIGNS_RELOAD_SECS")?
+                        .unwrap_or(60)
+                        .max(1),
+                ),
+                max_distance: env_parse("NSAI_CAMPAIGN_IMAGE_MAX_DISTANCE")?.unwrap_or(6),
+            }),
+            None => None,
+        };
+
+        let link_api = match env_parse::<String>("NSAI_LINK_REPUTATION_URL")? {
+            Some(url) => Some(ReputationApi {
+                url,
+                api_key: env_parse("NSAI_LINK_REPUTATION_API_KEY")?,
+                min_score: env_parse("NSAI_LINK_REPUTATION_MIN_SCORE")?.unwrap_or(60.0),
+            }),
+            None => None,
+        };
+        let link_allow: Option<PathBuf> = env_parse("NSAI_LINK_ALLOW_LIST")?;
+        let link_deny: Option<PathBuf> = env_parse("NSAI_LINK_DENY_LIST")?;
+        let link_reputation = if link_allow.is_some() || link_deny.is_some() || link_api.is_some() {
+            Some(LinkReputationConfig {
+                allow: link_allow,
+                deny: link_deny,
+                api: link_api,
+                shorteners: match env_parse::<String>("NSAI_LINK_SHORTENERS")? {
+                    Some(hosts) => hosts
+                        .split(',')
+                        .map(|host| host.trim().to_lowercase())
+                        .filter(|host| !host.is_empty())
+                        .collect(),
+                    None => links::DEFAULT_SHORTENERS
+                        .iter()
+                        .map(|host| host.to_string())
+                        .collect(),
+                },
+                max_links: env_parse("NSAI_LINK_MAX_PER_MESSAGE")?.unwrap_or(10),
+                timeout: Duration::from_millis(env_parse("NSAI_LINK_TIMEOUT_MS")?.unwrap_or(1000)),
+            })
+        } else {
+            None
+        };
+
+        let history = match env_parse("NSAI_HISTORY_FILE")? {
+            Some(path) => Some(HistoryConfig {
+                path,
+                interval: Duration::from_secs(
+                    env_parse::<u64>("NSAI_HISTORY_INTERVAL_SECS")?
+                        .unwrap_or(300)
+                        .max(1),
+                ),
+            }),
+            None => None,
+        };
+
+        let explanations = match env_parse("NSAI_EXPLANATION_TEMPLATES")? {
+            Some(templates) => Some(ExplanationConfig {
+                templates,
+                locale: env_parse("NSAI_EXPLANATION_LOCALE")?.unwrap_or_else(|| "en".to_string()),
+            }),
+            None => None,
+        };
+
+        let content_store = match env_parse("NSAI_CONTENT_STORE")? {
+            Some(kind) => Some(ContentStoreConfig {
+                backend: match kind {
+                    ContentStoreKind::Http => ContentStoreBackend::Http {
+                        base_url: env_required("NSAI_CONTENT_URL")?,
+                    },
+                    ContentStoreKind::S3 => {
+                        let region =
+                            env_parse("NSAI_S3_REGION")?.unwrap_or_else(|| "us-east-1".to_string());
+                        ContentStoreBackend::S3 {
+                            endpoint: env_parse("NSAI_S3_ENDPOINT")?
+                                .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region)),
+                            bucket: env_required("NSAI_S3_BUCKET")?,
+                            region,
+                            access_key: env_required("AWS_ACCESS_KEY_ID")?,
+                            secret_key: env_required("AWS_SECRET_ACCESS_KEY")?,
+                        }
+                    }
+                    ContentStoreKind::Nats => ContentStoreBackend::Nats {
+                        bucket: env_parse("NSAI_CONTENT_BUCKET")?
+                            .unwrap_or_else(|| "content".to_string()),
+                    },
+                },
+                retries: env_parse("NSAI_CONTENT_FETCH_RETRIES")?.unwrap_or(3),
+                backoff: Duration::from_millis(
+                    env_parse("NSAI_CONTENT_FETCH_BACKOFF_MS")?.unwrap_or(200),
+                ),
+            }),
+            None => None,
+        };
+
+        let content_objects = match env_list::<String>("NSAI_CONTENT_OBJECT_BUCKETS")? {
+            Some(buckets) if !buckets.is_empty() => Some(ObjectContentConfig {
+                buckets,
+                max_bytes: env_parse("NSAI_CONTENT_OBJECT_MAX_BYTES")?.unwrap_or(64 * 1024 * 1024),
+            }),
+            _ => None,
+        };
+
+        let validation = ValidationConfig {
+            tenants: env_list("NSAI_TENANTS")?,
+            fetch_content: content_store.is_some(),
+            object_buckets: content_objects
+                .as_ref()
+                .map(|c| c.buckets.clone())
+                .unwrap_or_default(),
+        };
+
+        let canary = match env_parse("NSAI_CANARY_KEY")? {
+            Some(key) => Some(CanaryConfig {
+                key,
+                latency_slo: Duration::from_millis(
+                    env_parse("NSAI_CANARY_SLO_MS")?.unwrap_or(2000),
+                ),
+            }),
+            None => None,
+        };
+
+        let memory = match env_parse::<u64>("NSAI_MEMORY_LIMIT_MB")? {
+            Some(limit_mb) => {
+                let shed_ratio = env_parse("NSAI_MEMORY_SHED_RATIO")?.unwrap_or(0.8);
+                let throttle_ratio = env_parse("NSAI_MEMORY_THROTTLE_RATIO")?.unwrap_or(0.9);
+                if !(0.0 < shed_ratio && shed_ratio <= throttle_ratio && throttle_ratio <= 1.0) {
+                    anyhow::bail!(
+                        "memory ratios must satisfy 0 < NSAI_MEMORY_SHED_RATIO <= NSAI_MEMORY_THROTTLE_RATIO <= 1"
+                    );
+                }
+                Some(MemoryGuardConfig {
+                    limit_bytes: limit_mb * 1024 * 1024,
+                    shed_ratio,
+                    throttle_ratio,
+                    interval: Duration::from_secs(
+                        env_parse::<u64>("NSAI_MEMORY_CHECK_INTERVAL_SECS")?
+                            .unwrap_or(5)
+                            .max(1),
+                    ),
+                })
+            }
+            None => None,
+        };
+
+        let per_tenant = QuotaLimits {
+            messages: env_parse("NSAI_QUOTA_MESSAGES_PER_DAY")?,
+            gpu_seconds: env_parse("NSAI_QUOTA_GPU_SECONDS_PER_DAY")?,
+        };
+        let tenants = env_list("NSAI_QUOTA_TENANTS")?.unwrap_or_default();
+        let replica = QuotaLimits {
+            messages: env_parse("NSAI_QUOTA_REPLICA_MESSAGES_PER_DAY")?,
+            gpu_seconds: env_parse("NSAI_QUOTA_REPLICA_GPU_SECONDS_PER_DAY")?,
+        };
+        let quota = if per_tenant != QuotaLimits::default()
+            || !tenants.is_empty()
+            || replica != QuotaLimits::default()
+        {
+            Some(QuotaConfig {
+                per_tenant,
+                tenants,
+                replica,
+                overflow: env_parse("NSAI_QUOTA_OVERFLOW")?.unwrap_or(OverflowAction::Reject),
+                backfill_subject: env_parse("NSAI_QUOTA_BACKFILL_SUBJECT")?
+                    .unwrap_or_else(|| "disinfo.backfill".to_string()),
+            })
+        } else {
+            None
+        };
+
+        let metrics = CardinalityConfig {
+            label_limit: env_parse("NSAI_METRIC_LABEL_LIMIT")?
+                .unwrap_or(defaults.metrics.label_limit),
+            tenant_allowlist: env_list("NSAI_METRIC_TENANTS")?
+                .or_else(|| validation.tenants.clone()),
+        };
+
+        let program =
+            env_parse("NSAI_RULES")?.unwrap_or_else(|| PathBuf::from("rules/detector.dl"));
+        let symbolic = match env_parse("NSAI_SYMBOLIC_BACKEND")? {
+            None | Some(SymbolicKind::Embedded) => SymbolicBackend::Embedded,
+            Some(SymbolicKind::Ascent) => SymbolicBackend::Ascent,
+            Some(SymbolicKind::Souffle) => SymbolicBackend::Souffle {
+                souffle: env_parse("NSAI_SOUFFLE")?.unwrap_or_else(|| PathBuf::from("souffle")),
+                program,
+            },
+            Some(SymbolicKind::Compiled) => match env_parse("NSAI_SOUFFLE_COMPILED")? {
+                Some(binary) => SymbolicBackend::Compiled { binary, program },
+                None => SymbolicBackend::CompileOnLoad {
+                    souffle: env_parse("NSAI_SOUFFLE")?.unwrap_or_else(|| PathBuf::from("souffle")),
+                    program,
+                },
+            },
+        };
+
+        let souffle_sandbox = SouffleSandbox {
+            memory_mb: env_parse("NSAI_SOUFFLE_MEMORY_MB")?
+                .unwrap_or(defaults.souffle_sandbox.memory_mb),
+            cpu_secs: env_parse("NSAI_SOUFFLE_CPU_SECS")?
+                .unwrap_or(defaults.souffle_sandbox.cpu_secs),
+            output_mb: env_parse("NSAI_SOUFFLE_OUTPUT_MB")?
+                .unwrap_or(defaults.souffle_sandbox.output_mb),
+            isolate_network: env_parse("NSAI_SOUFFLE_ISOLATE_NETWORK")?
+                .unwrap_or(defaults.souffle_sandbox.isolate_network),
+            scratch_dir: env_parse("NSAI_SOUFFLE_SCRATCH_DIR")?
+                .unwrap_or(defaults.souffle_sandbox.scratch_dir),
+        };
+
+        let config = Self {
+            nats: nats_from_env()?,
+            kafka: kafka_from_env()?,
+            instance: instance_from_env()?,
+            idle,
+            consumer,
+            retry,
+            inference,
+            feature_cache,
+            publish,
+            telemetry,
+            similarity,
+            shadow,
+            claims,
+            claim_matching,
+            campaigns,
+            link_reputation,
+            history,
+            enrichment,
+            knowledge_graph: match env_parse("NSAI_KNOWLEDGE_GRAPH")? {
+                Some(kind) => Some(KnowledgeGraphConfig {
+                    backend: match kind {
+                        KnowledgeGraphKind::Dgraph => KnowledgeGraphBackend::Dgraph {
+                            url: env_parse("NSAI_DGRAPH_URL")?
+                                .unwrap_or_else(|| "http://dgraph-alpha:8080".to_string()),
+                            api_key: env_parse("NSAI_DGRAPH_API_KEY")?,
+                        },
+                        KnowledgeGraphKind::Neo4j => KnowledgeGraphBackend::Neo4j {
+                            url: env_parse("NSAI_NEO4J_URL")?
+                                .unwrap_or_else(|| "http://neo4j:7474".to_string()),
+                            database: env_parse("NSAI_NEO4J_DATABASE")?
+                                .unwrap_or_else(|| "neo4j".to_string()),
+                            user: env_parse("NSAI_NEO4J_USER")?
+                                .unwrap_or_else(|| "neo4j".to_string()),
+                            password: env_required("NSAI_NEO4J_PASSWORD")?,
+                        },
+                        KnowledgeGraphKind::Memory => KnowledgeGraphBackend::Memory {
+                            path: env_required("NSAI_KNOWLEDGE_GRAPH_FILE")?.into(),
+                        },
+                        KnowledgeGraphKind::Snapshot => KnowledgeGraphBackend::Snapshot {
+                            path: env_required("NSAI_KNOWLEDGE_GRAPH_FILE")?.into(),
+                            reload: match env_parse("NSAI_KNOWLEDGE_GRAPH_SNAPSHOT_RELOAD_SECS")?
+                                .unwrap_or(300)
+                            {
+                                0 => None,
+                                secs => Some(Duration::from_secs(secs)),
+                            },
+                        },
+                    },
+                    timeout: Duration::from_millis(
+                        env_parse("NSAI_KNOWLEDGE_GRAPH_TIMEOUT_MS")?.unwrap_or(500),
+                    ),
+                    write_back: env_parse("NSAI_KNOWLEDGE_GRAPH_WRITE_BACK")?.unwrap_or(false),
+                    breaker: GraphBreakerConfig {
+                        retries: env_parse("NSAI_KNOWLEDGE_GRAPH_RETRIES")?.unwrap_or(2),
+                        backoff: Duration::from_millis(
+                            env_parse("NSAI_KNOWLEDGE_GRAPH_BACKOFF_MS")?.unwrap_or(50),
+                        ),
+                        failures: env_parse("NSAI_KNOWLEDGE_GRAPH_BREAKER_FAILURES")?.unwrap_or(5),
+                        open: Duration::from_secs(
+                            env_parse("NSAI_KNOWLEDGE_GRAPH_BREAKER_OPEN_SECS")?.unwrap_or(30),
+                        ),
+                    },
+                    cache: match env_parse::<u64>("NSAI_KNOWLEDGE_GRAPH_CACHE_TTL_SECS")? {
+                        Some(ttl) if ttl > 0 => Some(GraphCacheConfig {
+                            capacity: env_parse("NSAI_KNOWLEDGE_GRAPH_CACHE_CAPACITY")?
+                                .unwrap_or(10_000),
+                            ttl: Duration::from_secs(ttl),
+                            stale: Duration::from_secs(
+                                env_parse("NSAI_KNOWLEDGE_GRAPH_CACHE_STALE_SECS")?.unwrap_or(0),
+                            ),
+                        }),
+                        _ => None,
+                    },
+                    batch: match env_parse::<usize>("NSAI_KNOWLEDGE_GRAPH_BATCH_SIZE")? {
+                        Some(max_size) if max_size > 1 => Some(GraphBatchConfig {
+                            max_size,
+                            max_wait: Duration::from_millis(
+                                env_parse("NSAI_KNOWLEDGE_GRAPH_BATCH_WAIT_MS")?.unwrap_or(5),
+                            ),
+                            max_in_flight: env_parse("NSAI_KNOWLEDGE_GRAPH_BATCH_IN_FLIGHT")?
+                                .unwrap_or(4),
+                        }),
+                        _ => None,
+                    },
+                    coordination: match env_parse("NSAI_KNOWLEDGE_GRAPH_COORDINATION")? {
+                        Some(true) => Some(Duration::from_secs(
+                            env_parse::<u64>("NSAI_COORDINATION_NEW_ACCOUNT_DAYS")?.unwrap_or(30)
+                                * 24
+                                * 60
+                                * 60,
+                        )),
+                        _ => None,
+                    },
+                }),
+                None => None,
+            },
+            explanations,
+            validation,
+            content_store,
+            content_objects,
+            canary,
+            memory,
+            quota,
+            metrics,
+            symbolic,
+            souffle_sandbox,
+            rules_dir: env_parse("NSAI_RULES_DIR")?,
+            rules_reload: env_parse::<u64>("NSAI_RULES_RELOAD_SECS")?
+                .map(|secs| Duration::from_secs(secs.max(1))),
+            bins: env_list("NSAI_BINS")?.unwrap_or_default(),
+            bins_file: env_parse("NSAI_BINS_FILE")?,
+            reasoning_pool: ReasoningPoolConfig {
+                workers: env_parse("NSAI_REASONING_WORKERS")?
+                    .unwrap_or(defaults.reasoning_pool.workers),
+                queue: env_parse("NSAI_REASONING_QUEUE")?.unwrap_or(defaults.reasoning_pool.queue),
+            },
+            reasoning_cache: match env_parse::<u64>("NSAI_REASONING_CACHE_TTL_SECS")? {
+                Some(ttl) if ttl > 0 => Some(ReasoningCacheConfig {
+                    capacity: env_parse("NSAI_REASONING_CACHE_CAPACITY")?.unwrap_or(10_000),
+                    ttl: Duration::from_secs(ttl),
+                }),
+                _ => None,
+            },
+            dedup: match env_parse::<u64>("NSAI_DEDUP_TTL_SECS")? {
+                Some(ttl) if ttl > 0 => Some(DedupConfig {
+                    capacity: env_parse("NSAI_DEDUP_CAPACITY")?.unwrap_or(10_000),
+                    ttl: Duration::from_secs(ttl),
+                    kv_bucket: env_parse("NSAI_DEDUP_KV_BUCKET")?,
+                }),
+                _ => None,
+            },
+            verdict_store: match env_parse("NSAI_VERDICT_KV_BUCKET")? {
+                Some(bucket) => Some(VerdictStoreConfig {
+                    bucket,
+                    ttl: env_parse::<u64>("NSAI_VERDICT_KV_TTL_SECS")?
+                        .filter(|&ttl| ttl > 0)
+                        .map(Duration::from_secs),
+                    history: match env_parse("NSAI_VERDICT_KV_HISTORY")?.unwrap_or(1) {
+                        history @ 1..=64 => history,
+                        _ => anyhow::bail!("NSAI_VERDICT_KV_HISTORY must be between 1 and 64"),
+                    },
+                }),
+                None => None,
+            },
+            request_reply: match env_parse("NSAI_REQUEST_REPLY")?.unwrap_or(false) {
+                true => Some(RequestReplyConfig {
+                    subject: env_parse("NSAI_REQUEST_SUBJECT")?
+                        .unwrap_or_else(|| "disinfo.analyze".to_string()),
+                    queue_group: env_parse("NSAI_REQUEST_QUEUE")?
+                        .unwrap_or_else(|| "nsai-detector".to_string()),
+                    timeout: Duration::from_millis(
+                        env_parse("NSAI_REQUEST_TIMEOUT_MS")?.unwrap_or(10_000),
+                    ),
+                    workers: match env_parse("NSAI_REQUEST_WORKERS")? {
+                        Some(0) => anyhow::bail!("NSAI_REQUEST_WORKERS must be at least 1"),
+                        workers => workers,
+                    },
+                }),
+                false => None,
+            },
+            reevaluation: match env_parse("NSAI_FACT_UPDATES_SUBJECT")? {
+                Some(subject) => Some(ReevaluationConfig {
+                    subject,
+                    revision_subject: env_parse("NSAI_REVISION_SUBJECT")?
+                        .unwrap_or_else(|| "disinfo.revised".to_string()),
+                    capacity: env_parse("NSAI_REEVALUATION_CAPACITY")?.unwrap_or(10_000),
+                }),
+                None => None,
+            },
+            fact_dump: match env_parse("NSAI_FACT_DUMP")? {
+                Some(sink) => Some(FactDumpConfig {
+                    sink,
+                    all: env_parse("NSAI_FACT_DUMP_ALL")?.unwrap_or(false),
+                }),
+                None => None,
+            },
+            aggregation: env_parse("NSAI_CONFIDENCE_AGGREGATION")?.unwrap_or_default(),
+            conflicts: env_parse("NSAI_VERDICT_CONFLICTS")?.unwrap_or_default(),
+        };
+        config.check_transport()?;
+        Ok(config)
+    }
+
+    /// Fail when a feature built on JetStream or core NATS is configured
+    /// for another transport
+    fn check_transport(&self) -> Result<()> {
+        if self.kafka.is_none() {
+            return Ok(());
+        }
+        let nats_only = [
+            ("NSAI_REQUEST_REPLY", self.request_reply.is_some()),
+            ("NSAI_FACT_UPDATES_SUBJECT", self.reevaluation.is_some()),
+            (
+                "NSAI_DEDUP_KV_BUCKET",
+                self.dedup.as_ref().is_some_and(|d| d.kv_bucket.is_some()),
+            ),
+            ("NSAI_VERDICT_KV_BUCKET", self.verdict_store.is_some()),
+            (
+                "NSAI_CONTENT_OBJECT_BUCKETS",
+                self.content_objects.is_some(),
+            ),
+            (
+                "NSAI_CONTENT_STORE=nats",
+                self.content_store
+                    .as_ref()
+                    .is_some_and(|c| matches!(c.backend, ContentStoreBackend::Nats { .. })),
+            ),
+            (
+                "NSAI_QUOTA_OVERFLOW=defer",
+                self.quota
+                    .as_ref()
+                    .is_some_and(|q| q.overflow == OverflowAction::Defer),
+            ),
+            (
+                "NSAI_NATS_PRIORITY_SUBJECT",
+                self.nats.priority_subject.is_some(),
+            ),
+        ];
+        for (setting, set) in nats_only {
+            if set {
+                anyhow::bail!("{} needs NSAI_TRANSPORT=nats", setting);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Load the Kafka settings when `NSAI_TRANSPORT` selects Kafka
+fn kafka_from_env() -> Result<Option<KafkaConfig>> {
+    match env_parse::<String>("NSAI_TRANSPORT")?.as_deref() {
+        None | Some("nats") => Ok(None),
+        Some("kafka") => Ok(Some(KafkaConfig {
+            brokers: env_required("NSAI_KAFKA_BROKERS")?,
+            group: env_parse("NSAI_KAFKA_GROUP")?.unwrap_or_else(|| "nsai-detector".to_string()),
+            input_topic: env_parse("NSAI_KAFKA_INPUT_TOPIC")?
+                .unwrap_or_else(|| "disinfo.raw".to_string()),
+            properties: env_list("NSAI_KAFKA_PROPERTIES")?.unwrap_or_default(),
+            send_timeout: Duration::from_millis(
+                env_parse("NSAI_KAFKA_SEND_TIMEOUT_MS")?.unwrap_or(5_000),
+            ),
+        })),
+        Some(other) => anyhow::bail!(
+            "Unknown NSAI_TRANSPORT {:?} (expected nats or kafka)",
+            other
+        ),
+    }
+}
+
+/// Parse an optional environment variable
+fn env_parse<T>(key: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("{}", e))
+            .with_context(|| format!("Invalid value for {}", key)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Read an environment variable that must be set
+fn env_required(key: &str) -> Result<String> {
+    std::env::var(key).with_context(|| format!("{} must be set", key))
+}
+
+/// Parse an optional comma-separated environment variable
+fn env_list<T>(key: &str) -> Result<Option<Vec<T>>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(key) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                item.parse()
+                    .map_err(|e| anyhow::anyhow!("{}", e))
+                    .with_context(|| format!("Invalid entry {:?} in {}", item, key))
+            })
+            .collect::<Result<Vec<T>>>()
+            .map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_end_action_parse() {
+        assert_eq!(
+            "exit".parse::<StreamEndAction>().unwrap(),
+            StreamEndAction::Exit
+        );
+        assert!("restart".parse::<StreamEndAction>().is_err());
+    }
+
+    #[test]
+    fn test_defaults_keep_consuming() {
+        let config = Config::default();
+        assert_eq!(config.idle.on_stream_end, StreamEndAction::Resubscribe);
+        assert!(config.idle.run_maintenance);
+    }
+
+    #[test]
+    fn test_workers_default_to_filling_batches() {
+        let mut config = Config::default();
+        config.inference.sessions.pool_size = 2;
+        config.inference.batch.max_size = 8;
+        assert_eq!(config.consumer.workers(&config.inference), 16);
+
+        config.consumer.workers = Some(4);
+        assert_eq!(config.consumer.workers(&config.inference), 4);
+    }
+
+    #[test]
+    fn test_result_formats() {
+        let both = "both".parse::<ResultFormats>().unwrap();
+        assert!(both.rich() && both.legacy());
+        let rich = "rich".parse::<ResultFormats>().unwrap();
+        assert!(rich.rich() && !rich.legacy());
+    }
+
+    #[test]
+    fn test_kafka_rejects_nats_only_features() {
+        let mut config = Config {
+            kafka: Some(KafkaConfig {
+                brokers: "localhost:9092".to_string(),
+                group: "nsai-detector".to_string(),
+                input_topic: "disinfo.raw".to_string(),
+                properties: Vec::new(),
+                send_timeout: Duration::from_secs(5),
+            }),
+            ..Default::default()
+        };
+        assert!(config.check_transport().is_ok());
+
+        config.verdict_store = Some(VerdictStoreConfig {
+            bucket: "verdicts".to_string(),
+            ttl: None,
+            history: 1,
+        });
+        assert!(config.check_transport().is_err());
+
+        config.kafka = None;
+        assert!(config.check_transport().is_ok());
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Content too large for a NATS message
+//!
+//! A message is limited by the server's max payload, 1 MiB by default, so
+//! a long article or transcript cannot travel inline. Its producer puts the
+//! text in a JetStream object store bucket and publishes an input naming
+//! the object in `content_object`; the text is fetched before analysis and
+//! the rest of the pipeline sees an ordinary input.
+//!
+//! Only buckets listed in `NSAI_CONTENT_OBJECT_BUCKETS` are read, so a
+//! producer cannot make the detector read arbitrary buckets of the account.
+
+use anyhow::{Context, Result};
+use async_nats::jetstream::{
+    self,
+    object_store::{GetErrorKind, ObjectStore},
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tokio::io::AsyncReadExt;
+
+use crate::metrics::Metrics;
+use crate::model_pb::ObjectRef;
+
+/// Object content settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectContentConfig {
+    /// Buckets inputs may name
+    pub buckets: Vec<String>,
+    /// Largest object read
+    pub max_bytes: usize,
+}
+
+/// The object is larger than the configured limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "object is {} bytes, limit is {}", self.size, self.limit)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Reads the content inputs name by object reference
+pub struct ObjectContent {
+    jetstream: jetstream::Context,
+    /// Buckets opened so far
+    stores: Mutex<HashMap<String, ObjectStore>>,
+    max_bytes: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl ObjectContent {
+    pub fn new(
+        config: &ObjectContentConfig,
+        jetstream: jetstream::Context,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            jetstream,
+            stores: Mutex::new(HashMap::new()),
+            max_bytes: config.max_bytes,
+            metrics,
+        }
+    }
+
+    /// Content text of `object`, `None` if there is no such object
+    ///
+    /// Errors when the bucket cannot be read, or with [`TooLarge`] when the
+    /// object is larger than the limit; the failure is counted in
+    /// `nsai_content_fetch_failures_total`.
+    pub async fn fetch_text(&self, object: &ObjectRef) -> Result<Option<String>> {
+        let result = self.fetch(object).await;
+        if result.is_err() {
+            self.metrics.content_fetch_failures.inc();
+        }
+        result
+    }
+
+    async fn fetch(&self, object: &ObjectRef) -> Result<Option<String>> {
+        let store = self.store(&object.bucket).await?;
+        let mut reader = match store.get(&object.name).await {
+            Ok(reader) => reader,
+            Err(e) if e.kind() == GetErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Object store get of {} failed", describe(object)))
+            }
+        };
+        if reader.info().size > self.max_bytes {
+            return Err(TooLarge {
+                size: reader.info().size,
+                limit: self.max_bytes,
+            }
+            .into());
+        }
+        let mut bytes = Vec::with_capacity(reader.info().size);
+        reader
+            .read_to_end(&mut bytes)
+            .await
+            .with_context(|| format!("Object store read of {} failed", describe(object)))?;
+        Ok(Some(String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    async fn store(&self, bucket: &str) -> Result<ObjectStore> {
+        if let Some(store) = self.stores.lock().unwrap().get(bucket) {
+            return Ok(store.clone());
+        }
+        let store = self
+            .jetstream
+            .get_object_store(bucket)
+            .await
+            .with_context(|| format!("Failed to open object store {}", bucket))?;
+        self.stores
+            .lock()
+            .unwrap()
+            .insert(bucket.to_string(), store.clone());
+        Ok(store)
+    }
+}
+
+/// `bucket/name` of an object, for logs and explanations
+pub fn describe(object: &ObjectRef) -> String {
+    format!("{}/{}", object.bucket, object.name)
+}
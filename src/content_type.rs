@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Content types the rules are chosen by
+//!
+//! A text post, an image meme and a shared link call for different rules.
+//! Each input is classified during preprocessing and handed to the rules as
+//! the base fact `content_type("<type>")`; with `NSAI_RULES_DIR` set, a
+//! subdirectory named after a type holds the rule set for that type, see
+//! [`crate::live_rules`].
+
+use anyhow::{bail, Result};
+use std::{fmt, str::FromStr};
+
+use crate::model_pb::AnalysisInput;
+
+/// Words besides the links up to which a post counts as a shared link
+pub const LINK_MAX_WORDS: usize = 20;
+
+/// Kind of content of one input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ContentType {
+    /// Text without an image
+    Text,
+    /// An image, with or without caption
+    Image,
+    /// A link with at most [`LINK_MAX_WORDS`] words of comment
+    Link,
+}
+
+impl ContentType {
+    pub const ALL: [Self; 3] = [Self::Text, Self::Image, Self::Link];
+
+    /// Argument of the `content_type` fact and name of the rule set
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Image => "image",
+            Self::Link => "link",
+        }
+    }
+
+    /// Classify an input by its image and the links in its text
+    pub fn of(input: &AnalysisInput) -> Self {
+        if !input.image_url.is_empty() {
+            return Self::Image;
+        }
+        let (links, words): (Vec<&str>, Vec<&str>) = input
+            .content_text
+            .split_whitespace()
+            .partition(|word| word.starts_with("https://") || word.starts_with("http://"));
+        match !links.is_empty() && words.len() <= LINK_MAX_WORDS {
+            true => Self::Link,
+            false => Self::Text,
+        }
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "image" => Ok(Self::Image),
+            "link" => Ok(Self::Link),
+            other => bail!("unknown content type: {}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(text: &str, image_url: &str) -> AnalysisInput {
+        AnalysisInput {
+            content_text: text.to_string(),
+            image_url: image_url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_inputs_are_classified() {
+        assert_eq!(
+            ContentType::of(&input("Drinking hot water kills the virus", "")),
+            ContentType::Text
+        );
+        assert_eq!(
+            ContentType::of(&input("look at this", "https://example.com/meme.png")),
+            ContentType::Image
+        );
+        assert_eq!(
+            ContentType::of(&input(
+                "They don't want you to see this https://example.com/a",
+                ""
+            )),
+            ContentType::Link
+        );
+        let essay = format!(
+            "{} https://example.com/a",
+            "word ".repeat(LINK_MAX_WORDS + 1)
+        );
+        assert_eq!(ContentType::of(&input(&essay, "")), ContentType::Text);
+        for content_type in ContentType::ALL {
+            assert_eq!(
+                content_type.as_str().parse::<ContentType>().unwrap(),
+                content_type
+            );
+        }
+        assert!("video".parse::<ContentType>().is_err());
+    }
+}
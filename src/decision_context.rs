@@ -0,0 +1,222 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Time-of-decision context
+//!
+//! A verdict depends on more than its input: which optional stages were
+//! on, the rule thresholds and calibration, the rule program, the exact
+//! model files, and whether this message hit the feature cache or lost a
+//! stage to a failure. All of it is captured as a JSON blob and each
+//! result carries the blob's SHA-256 in `decision_context`. Blobs are
+//! written once as `<sha256>.json` under `NSAI_DECISION_CONTEXT_DIR`;
+//! identical contexts share a file, so the directory stays small while any
+//! past decision can be reconstructed.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+use crate::model_validation::file_sha256;
+use crate::onnx_wrapper::ModelSpec;
+use crate::souffle_wrapper::FiredRule;
+
+/// Rule program the embedded engine implements
+const RULES: &[u8] = include_bytes!("../rules/detector.dl");
+
+/// A served model and the hash of its file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelHash {
+    pub name: String,
+    pub version: Option<String>,
+    pub path: String,
+    /// `None` when the file could not be read
+    pub sha256: Option<String>,
+}
+
+impl ModelHash {
+    pub fn of(spec: &ModelSpec) -> Self {
+        let sha256 = match file_sha256(Path::new(&spec.path)) {
+            Ok(sha256) => Some(sha256),
+            Err(e) => {
+                warn!("Decision context without hash of {}: {:#}", spec.name, e);
+                None
+            }
+        };
+        Self {
+            name: spec.name.clone(),
+            version: spec.version.clone(),
+            path: spec.path.clone(),
+            sha256,
+        }
+    }
+}
+
+/// Settings shared by every decision until the service restarts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceContext {
+    pub service_version: String,
+    pub rules_sha256: String,
+    /// Version of the rules from their manifest
+    #[serde(default)]
+    pub rules_semver: String,
+    /// Optional stages that are enabled, e.g. `ocr` or `feature_cache`
+    pub flags: Vec<String>,
+    /// Bin edges per fact relation
+    pub bins: BTreeMap<String, Vec<f32>>,
+    /// Calibrations as configured, e.g. `fakeness=platt:1.5:-0.2`
+    pub calibration: Vec<String>,
+    pub fusion: String,
+    pub inference_timeout_ms: u64,
+    /// Every model of every ensemble, sorted by name
+    pub models: Vec<ModelHash>,
+}
+
+impl ServiceContext {
+    pub fn rules_sha256() -> String {
+        hex::encode(Sha256::digest(RULES))
+    }
+}
+
+/// What happened while deciding one message
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DecisionTrace {
+    /// `language:<code>`, `topic:<topic>` or `default`
+    pub route: String,
+    /// Ensemble that produced the features
+    pub model_version: String,
+    pub feature_cache_hit: bool,
+    /// `text`, `image` or `link`, which picks the rule set
+    #[serde(default)]
+    pub content_type: String,
+    /// Best-effort stages that failed, in pipeline order
+    pub degraded: Vec<String>,
+    /// Bin edges of the message's language or tenant that differ from the
+    /// service's
+    pub bins: BTreeMap<String, Vec<f32>>,
+    /// Rules that fired and their premises
+    pub fired_rules: Vec<FiredRule>,
+}
+
+impl DecisionTrace {
+    pub fn degrade(&mut self, stage: &str) {
+        self.degraded.push(stage.to_string());
+    }
+}
+
+#[derive(Serialize)]
+struct DecisionContext<'a> {
+    service: &'a ServiceContext,
+    decision: &'a DecisionTrace,
+}
+
+/// Writes decision contexts and returns the id results reference
+pub struct ContextRecorder {
+    dir: PathBuf,
+    service: ServiceContext,
+    /// Ids already on disk, so repeated contexts skip the filesystem
+    written: Mutex<HashSet<String>>,
+}
+
+impl ContextRecorder {
+    pub fn new(dir: PathBuf, service: ServiceContext) -> Result<Self> {
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        info!(
+            "Recording decision contexts in {} (rules {})",
+            dir.display(),
+            &service.rules_sha256[..12]
+        );
+        Ok(Self {
+            dir,
+            service,
+            written: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Persist the context of one decision
+    ///
+    /// # Returns
+    /// The context id: lowercase hex SHA-256 of the blob
+    pub async fn record(&self, trace: &DecisionTrace) -> Result<String> {
+        let blob = serde_json::to_vec(&DecisionContext {
+            service: &self.service,
+            decision: trace,
+        })?;
+        let id = hex::encode(Sha256::digest(&blob));
+        if self.written.lock().unwrap().contains(&id) {
+            return Ok(id);
+        }
+
+        let path = self.dir.join(format!("{}.json", id));
+        if !tokio::fs::try_exists(&path).await? {
+            // Write-then-rename so readers never see a partial blob
+            let tmp = path.with_extension("json.tmp");
+            tokio::fs::write(&tmp, &blob).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+        }
+        self.written.lock().unwrap().insert(id.clone());
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn service() -> ServiceContext {
+        ServiceContext {
+            service_version: "0.1.0".to_string(),
+            rules_sha256: ServiceContext::rules_sha256(),
+            rules_semver: "1.0.0".to_string(),
+            flags: vec!["feature_cache".to_string()],
+            bins: BTreeMap::from([("fakeness".to_string(), vec![0.0, 0.6, 0.8, 1.0])]),
+            calibration: Vec::new(),
+            fusion: "mean".to_string(),
+            inference_timeout_ms: 5000,
+            models: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_model_hash_of_missing_file() {
+        let spec: ModelSpec = "detector:/nonexistent/detector.onnx".parse().unwrap();
+        let hash = ModelHash::of(&spec);
+        assert_eq!(hash.name, "detector");
+        assert_eq!(hash.sha256, None);
+        assert_eq!(ServiceContext::rules_sha256().len(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_identical_contexts_share_a_blob() {
+        let dir = TempDir::new("contexts");
+        let recorder = ContextRecorder::new(dir.to_path_buf(), service()).unwrap();
+
+        let trace = DecisionTrace {
+            route: "default".to_string(),
+            model_version: "detector".to_string(),
+            ..Default::default()
+        };
+        let id = recorder.record(&trace).await.unwrap();
+        assert_eq!(recorder.record(&trace).await.unwrap(), id);
+
+        let mut degraded = trace.clone();
+        degraded.degrade("ocr");
+        let other = recorder.record(&degraded).await.unwrap();
+        assert_ne!(other, id);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // The blob hashes to its id and reconstructs the decision
+        let blob = fs::read(dir.join(format!("{}.json", other))).unwrap();
+        assert_eq!(hex::encode(Sha256::digest(&blob)), other);
+        let value: serde_json::Value = serde_json::from_slice(&blob).unwrap();
+        assert_eq!(value["decision"]["degraded"][0], "ocr");
+        assert_eq!(value["service"]["fusion"], "mean");
+    }
+}
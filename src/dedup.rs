@@ -0,0 +1,230 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Deduplication of inputs that were already analysed
+//!
+//! A redelivered message, or the same post submitted twice, would otherwise
+//! run the whole pipeline again. Published results are remembered by content
+//! hash and source, and a repeat is answered with the remembered result. The
+//! source is part of the key because its facts feed the verdict: the same
+//! content from another source is analysed afresh.
+//!
+//! Results are held in a local LRU, and optionally in a JetStream key-value
+//! bucket shared by the replicas, so a redelivery to another replica is
+//! deduplicated too. The bucket is best-effort: when it cannot be reached
+//! the input is analysed again.
+
+use anyhow::{Context, Result};
+use async_nats::jetstream::{self, kv};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::cache::LruCache;
+use crate::maintenance::Compact;
+use crate::metrics::Metrics;
+use crate::model_pb::AnalysisResult;
+
+/// Deduplication settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct DedupConfig {
+    /// Results held locally
+    pub capacity: usize,
+    /// How long a result answers repeats
+    pub ttl: Duration,
+    /// JetStream key-value bucket shared by the replicas; local only when
+    /// `None`
+    pub kv_bucket: Option<String>,
+}
+
+/// Remembers published results to answer repeated inputs with
+pub struct Deduplicator {
+    local: Mutex<LruCache<String, (Instant, AnalysisResult)>>,
+    shared: Option<kv::Store>,
+    ttl: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl Deduplicator {
+    /// Open the shared bucket, creating it when missing
+    pub async fn new(
+        config: &DedupConfig,
+        jetstream: Option<&jetstream::Context>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let shared = match &config.kv_bucket {
+            Some(bucket) => {
+                let jetstream =
+                    jetstream.context("A shared deduplication bucket needs the NATS transport")?;
+                Some(match jetstream.get_key_value(bucket).await {
+                    Ok(store) => store,
+                    Err(_) => {
+                        info!("Creating deduplication bucket {}", bucket);
+                        jetstream
+                            .create_key_value(kv::Config {
+                                bucket: bucket.clone(),
+                                description: "Published results by input".to_string(),
+                                max_age: config.ttl,
+                                history: 1,
+                                ..Default::default()
+                            })
+                            .await
+                            .with_context(|| {
+                                format!("Failed to open key-value bucket {}", bucket)
+                            })?
+                    }
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            local: Mutex::new(LruCache::new(config.capacity)),
+            shared,
+            ttl: config.ttl,
+            metrics,
+        })
+    }
+
+    /// Where results are held, for the pipeline graph
+    pub fn describe(&self) -> String {
+        match &self.shared {
+            Some(store) => format!("memory, then bucket {}", store.name),
+            None => "memory".to_string(),
+        }
+    }
+
+    /// The result already published for `content_hash` from `source_id`
+    pub async fn get(&self, content_hash: &str, source_id: &str) -> Option<AnalysisResult> {
+        let key = key(content_hash, source_id);
+        let local = self.local.lock().unwrap().get(&key);
+        if let Some((_, result)) = local.filter(|(at, _)| at.elapsed() < self.ttl) {
+            self.record("memory");
+            return Some(result);
+        }
+
+        if let Some(store) = &self.shared {
+            match store.get(&key).await {
+                Ok(Some(bytes)) => match AnalysisResult::decode(bytes.as_ref()) {
+                    Ok(result) => {
+                        self.record("kv");
+                        self.local
+                            .lock()
+                            .unwrap()
+                            .put(key, (Instant::now(), result.clone()));
+                        return Some(result);
+                    }
+                    Err(e) => warn!("Undecodable deduplication entry {}: {}", key, e),
+                },
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Deduplication lookup failed: {}", e);
+                    self.metrics.errors.inc();
+                }
+            }
+        }
+        self.record("miss");
+        None
+    }
+
+    /// Remember `result` as the answer to its input
+    pub async fn put(&self, result: &AnalysisResult) {
+        let key = key(&result.content_hash, &result.source_id);
+        self.local
+            .lock()
+            .unwrap()
+            .put(key.clone(), (Instant::now(), result.clone()));
+        if let Some(store) = &self.shared {
+            if let Err(e) = store.put(&key, result.encode_to_vec().into()).await {
+                warn!("Deduplication entry {} not shared: {}", key, e);
+                self.metrics.errors.inc();
+            }
+        }
+    }
+
+    /// Drop locally held results to free memory
+    pub fn shed(&self) -> usize {
+        let mut local = self.local.lock().unwrap();
+        let entries = local.len();
+        local.clear();
+        entries
+    }
+
+    fn record(&self, outcome: &str) {
+        self.metrics
+            .dedup_lookups
+            .with_label_values(&[outcome])
+            .inc();
+    }
+}
+
+/// Key of an input, valid as a key-value bucket key
+///
+/// Source ids hold characters bucket keys may not, so they are hashed.
+fn key(content_hash: &str, source_id: &str) -> String {
+    let source = hex::encode(Sha256::digest(source_id.as_bytes()));
+    format!("{}.{}", content_hash, &source[..16])
+}
+
+impl Compact for Deduplicator {
+    fn compact(&self) -> usize {
+        self.local
+            .lock()
+            .unwrap()
+            .retain(|(at, _)| at.elapsed() < self.ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(content_hash: &str, source_id: &str, verdict: &str) -> AnalysisResult {
+        AnalysisResult {
+            content_hash: content_hash.to_string(),
+            source_id: source_id.to_string(),
+            verdict: verdict.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn deduplicator(ttl: Duration) -> Deduplicator {
+        Deduplicator {
+            local: Mutex::new(LruCache::new(16)),
+            shared: None,
+            ttl,
+            metrics: Arc::new(Metrics::new().unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repeats_are_answered_per_source() {
+        let dedup = deduplicator(Duration::from_secs(60));
+        assert!(dedup.get("ab12", "twitter:@a").await.is_none());
+
+        dedup.put(&result("ab12", "twitter:@a", "DISINFO")).await;
+        let repeat = dedup.get("ab12", "twitter:@a").await.unwrap();
+        assert_eq!(repeat.verdict, "DISINFO");
+        // Another source's facts may decide otherwise
+        assert!(dedup.get("ab12", "twitter:@b").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_results_expire() {
+        let dedup = deduplicator(Duration::ZERO);
+        dedup.put(&result("ab12", "twitter:@a", "SAFE")).await;
+        assert!(dedup.get("ab12", "twitter:@a").await.is_none());
+    }
+
+    #[test]
+    fn test_keys_are_valid_bucket_keys() {
+        let key = key("ab12", "twitter:@some one/else");
+        assert!(key.starts_with("ab12."));
+        assert!(key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_=./".contains(c)));
+    }
+}
@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Where an input came from, and how its outcome is settled
+//!
+//! Most inputs are JetStream messages: they are acknowledged once
+//! processed and handed back for redelivery after a transient failure.
+//! Inputs sent as core NATS requests have no stream behind them: their
+//! result is the reply, and a transient failure is answered with a service
+//! error the caller may retry, since there is nothing to redeliver.
+//! Inputs received over another transport, like Kafka, are settled by
+//! that transport and handed back through it.
+
+use async_nats::{jetstream::message::Message as JetStreamMessage, HeaderMap};
+use tracing::warn;
+
+use crate::metrics::Metrics;
+use crate::retry::{self, RetryConfig};
+use crate::transport::Received;
+
+/// Header of a reply carrying an error instead of a result
+pub const SERVICE_ERROR: &str = "Nats-Service-Error";
+/// Status code of an error reply, HTTP style
+pub const SERVICE_ERROR_CODE: &str = "Nats-Service-Error-Code";
+
+/// An input being processed
+#[derive(Clone, Copy)]
+pub enum Delivery<'a> {
+    /// Message of the input stream, acknowledged once processed
+    Stream(&'a JetStreamMessage),
+    /// Core NATS request, answered with the result
+    Request {
+        message: &'a async_nats::Message,
+        client: &'a async_nats::Client,
+    },
+    /// Input of another transport, settled by it once processed; only the
+    /// Kafka consumer delivers these
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    Received(&'a dyn Received),
+}
+
+impl Delivery<'_> {
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Self::Stream(msg) => &msg.payload,
+            Self::Request { message, .. } => &message.payload,
+            Self::Received(input) => input.payload(),
+        }
+    }
+
+    pub fn headers(&self) -> Option<&HeaderMap> {
+        match self {
+            Self::Stream(msg) => msg.headers.as_ref(),
+            Self::Request { message, .. } => message.headers.as_ref(),
+            Self::Received(input) => input.headers(),
+        }
+    }
+
+    pub fn subject(&self) -> &str {
+        match self {
+            Self::Stream(msg) => msg.subject.as_str(),
+            Self::Request { message, .. } => message.subject.as_str(),
+            Self::Received(input) => input.origin(),
+        }
+    }
+
+    /// Names the delivery among the spans of a trace
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Stream(msg) => msg.reply.as_deref().unwrap_or_default(),
+            Self::Request { message, .. } => message.reply.as_deref().unwrap_or_default(),
+            Self::Received(input) => input.id(),
+        }
+    }
+
+    /// Whether no further attempt at this input will be made after this one
+    pub fn last_attempt(&self, config: &RetryConfig) -> bool {
+        match self {
+            Self::Stream(msg) => config.last_delivery(retry::deliveries(msg)),
+            Self::Request { .. } => true,
+            Self::Received(input) => config.last_delivery(input.deliveries()),
+        }
+    }
+
+    /// Settle an input whose outcome was answered
+    pub async fn ack(&self) {
+        if let Self::Stream(msg) = self {
+            let _ = msg.ack().await;
+        }
+    }
+
+    /// Settle an input that failed transiently
+    pub async fn retry(&self, config: &RetryConfig, reason: &str, metrics: &Metrics) {
+        match self {
+            Self::Stream(msg) => retry::retry(config, msg, reason, metrics).await,
+            Self::Received(input) => retry::retry_received(config, *input, reason, metrics).await,
+            Self::Request { .. } => {
+                metrics
+                    .transient_failures
+                    .with_label_values(&[reason, "answered"])
+                    .inc();
+                self.fail(503, reason).await;
+            }
+        }
+    }
+
+    /// Answer a request with an error; stream messages are settled by
+    /// [`ack`](Self::ack) and [`retry`](Self::retry) only
+    pub async fn fail(&self, code: u16, description: &str) {
+        if let Self::Request { message, client } = self {
+            let Some(reply) = message.reply.clone() else {
+                return;
+            };
+            let mut headers = HeaderMap::new();
+            headers.insert(SERVICE_ERROR, description);
+            headers.insert(SERVICE_ERROR_CODE, code.to_string().as_str());
+            if let Err(e) = client
+                .publish_with_headers(reply, headers, Vec::new().into())
+                .await
+            {
+                warn!("Failed to answer request: {}", e);
+            }
+        }
+    }
+}
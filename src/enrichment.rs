@@ -0,0 +1,517 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Enrichment of sources with metadata from external providers
+//!
+//! Rules about where a source is registered or how old an account is need
+//! metadata the message does not carry. The source is resolved with the
+//! configured providers, in order, and the first provider to know a field
+//! supplies it:
+//!
+//! * `wikidata` looks the handle up on Wikidata by the platform's
+//!   username property (e.g. `P2002` for `twitter:`), taking the country
+//!   (`P17`) and inception (`P571`) of the item;
+//! * `geoip` resolves a source that is a domain, e.g. `web:example.com`,
+//!   and looks its address up in a GeoIP service.
+//!
+//! The metadata reaches the rules as base facts:
+//!
+//! ```text
+//! source_platform("twitter").          prefix of the source id
+//! source_country("RU").                ISO 3166-1 alpha-2
+//! source_account_age(412).             days since the account was created
+//! ```
+//!
+//! Resolved sources are cached, so a provider is asked about a source at
+//! most once per TTL. A provider that fails is skipped; a source for which
+//! every provider failed is left without metadata.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::warn;
+
+use crate::cache::LruCache;
+use crate::clock;
+use crate::links;
+use crate::metrics::Metrics;
+use crate::souffle_wrapper::DgraphFacts;
+
+/// Sources whose metadata is remembered
+const CACHE_CAPACITY: usize = 10_000;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Wikidata properties holding the account name on each platform
+const WIKIDATA_HANDLES: &[(&str, &str)] = &[
+    ("twitter", "P2002"),
+    ("instagram", "P2003"),
+    ("facebook", "P2013"),
+    ("telegram", "P3789"),
+    ("tiktok", "P7085"),
+    ("youtube", "P11245"),
+];
+
+/// Where source metadata is resolved
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnrichmentProvider {
+    /// Wikidata items queried over SPARQL
+    Wikidata { url: String },
+    /// `GET <url>/<ip>` answering `{"country_code": "RU"}`
+    GeoIp { url: String },
+}
+
+/// Provider names accepted by `NSAI_ENRICHMENT_PROVIDERS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnrichmentKind {
+    Wikidata,
+    GeoIp,
+}
+
+impl FromStr for EnrichmentKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wikidata" => Ok(Self::Wikidata),
+            "geoip" => Ok(Self::GeoIp),
+            other => bail!("unknown enrichment provider: {}", other),
+        }
+    }
+}
+
+/// Source enrichment settings; disabled unless a provider is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnrichmentConfig {
+    /// Asked in order; earlier providers win
+    pub providers: Vec<EnrichmentProvider>,
+    /// Deadline of one provider request
+    pub timeout: Duration,
+    /// How long a resolved source is remembered
+    pub cache_ttl: Duration,
+}
+
+/// What the providers know about a source
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMetadata {
+    /// ISO 3166-1 alpha-2, uppercase
+    pub country: Option<String>,
+    /// Unix seconds the account or outlet was created
+    pub created_at: Option<u64>,
+}
+
+impl SourceMetadata {
+    /// Fill the fields still unknown from `other`
+    fn merge(&mut self, other: SourceMetadata) {
+        self.country = self.country.take().or(other.country);
+        self.created_at = self.created_at.or(other.created_at);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.country.is_none() && self.created_at.is_none()
+    }
+
+    /// Base facts for the rules about `source_id` as of `now`
+    pub fn facts(&self, source_id: &str, now: SystemTime) -> DgraphFacts {
+        let mut facts = HashMap::new();
+        if let Some((platform, _)) = split(source_id) {
+            facts.insert("source_platform".to_string(), platform.to_string());
+        }
+        if let Some(country) = &self.country {
+            facts.insert("source_country".to_string(), country.clone());
+        }
+        if let Some(created_at) = self.created_at {
+            let now = clock::unix_secs(now);
+            let days = now.saturating_sub(created_at) / DAY;
+            facts.insert("source_account_age".to_string(), days.to_string());
+        }
+        facts
+    }
+}
+
+/// Platform and handle of a source id, e.g. `("twitter", "example")` for
+/// `twitter:@example`
+fn split(source_id: &str) -> Option<(&str, &str)> {
+    let (platform, handle) = source_id.split_once(':')?;
+    let handle = handle.trim_start_matches('@');
+    (!platform.is_empty() && !handle.is_empty()).then_some((platform, handle))
+}
+
+/// Unix seconds of the day a `YYYY-MM-DD...` date starts
+fn unix_date(value: &str) -> Option<u64> {
+    let mut parts = value.get(..10)?.split('-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days from the civil calendar date, after Howard Hinnant
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    u64::try_from(days).ok().map(|days| days * DAY)
+}
+
+/// A source of metadata
+pub trait MetadataProvider: Send + Sync {
+    /// Provider name for logs and metrics, e.g. `wikidata`
+    fn name(&self) -> &'static str;
+
+    /// What the provider knows about the handle on the platform
+    fn lookup<'a>(
+        &'a self,
+        platform: &'a str,
+        handle: &'a str,
+    ) -> BoxFuture<'a, Result<SourceMetadata>>;
+}
+
+/// Items of Wikidata with the account on their platform
+pub struct WikidataProvider {
+    client: reqwest::Client,
+    url: String,
+}
+
+/// The first item with the handle, its country code and inception
+fn wikidata_query(property: &str, handle: &str) -> String {
+    let handle = handle.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(
+        "SELECT ?country ?inception WHERE {{
+  ?item wdt:{} ?handle .
+  FILTER(LCASE(STR(?handle)) = \"{}\")
+  OPTIONAL {{ ?item wdt:P17/wdt:P297 ?country }}
+  OPTIONAL {{ ?item wdt:P571 ?inception }}
+}} LIMIT 1",
+        property, handle
+    )
+}
+
+fn parse_wikidata(response: &serde_json::Value) -> SourceMetadata {
+    let binding = &response["results"]["bindings"][0];
+    SourceMetadata {
+        country: binding["country"]["value"].as_str().map(str::to_uppercase),
+        created_at: binding["inception"]["value"].as_str().and_then(unix_date),
+    }
+}
+
+impl MetadataProvider for WikidataProvider {
+    fn name(&self) -> &'static str {
+        "wikidata"
+    }
+
+    fn lookup<'a>(
+        &'a self,
+        platform: &'a str,
+        handle: &'a str,
+    ) -> BoxFuture<'a, Result<SourceMetadata>> {
+        Box::pin(async move {
+            let Some((_, property)) = WIKIDATA_HANDLES.iter().find(|(p, _)| *p == platform) else {
+                return Ok(SourceMetadata::default());
+            };
+            let body = self
+                .client
+                .get(&self.url)
+                .query(&[
+                    ("query", wikidata_query(property, handle).as_str()),
+                    ("format", "json"),
+                ])
+                .header(reqwest::header::ACCEPT, "application/sparql-results+json")
+                .send()
+                .await
+                .context("Wikidata request failed")?
+                .error_for_status()
+                .context("Wikidata returned an error")?
+                .bytes()
+                .await?;
+            let response: serde_json::Value =
+                serde_json::from_slice(&body).context("Wikidata returned invalid JSON")?;
+            Ok(parse_wikidata(&response))
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct GeoIpCountry {
+    #[serde(alias = "country")]
+    country_code: Option<String>,
+}
+
+/// Countries of the addresses domain sources resolve to
+pub struct GeoIpProvider {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl MetadataProvider for GeoIpProvider {
+    fn name(&self) -> &'static str {
+        "geoip"
+    }
+
+    fn lookup<'a>(&'a self, _: &'a str, handle: &'a str) -> BoxFuture<'a, Result<SourceMetadata>> {
+        Box::pin(async move {
+            // Only sources that are domains have an address
+            let Some(domain) = links::host(handle).filter(|host| host.contains('.')) else {
+                return Ok(SourceMetadata::default());
+            };
+            let Some(address) = tokio::net::lookup_host((domain.as_str(), 443))
+                .await
+                .with_context(|| format!("Failed to resolve {}", domain))?
+                .next()
+            else {
+                return Ok(SourceMetadata::default());
+            };
+            let response = self
+                .client
+                .get(format!(
+                    "{}/{}",
+                    self.url.trim_end_matches('/'),
+                    address.ip()
+                ))
+                .send()
+                .await
+                .context("GeoIP request failed")?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(SourceMetadata::default());
+            }
+            let body = response
+                .error_for_status()
+                .context("GeoIP service returned an error")?
+                .bytes()
+                .await?;
+            let country: GeoIpCountry =
+                serde_json::from_slice(&body).context("GeoIP service returned invalid JSON")?;
+            Ok(SourceMetadata {
+                country: country.country_code.map(|code| code.to_uppercase()),
+                created_at: None,
+            })
+        })
+    }
+}
+
+pub fn from_config(config: &EnrichmentConfig) -> Result<Vec<Box<dyn MetadataProvider>>> {
+    let client = || {
+        reqwest::Client::builder()
+            .timeout(config.timeout)
+            .user_agent(concat!("nsai-detector/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .context("Failed to build the enrichment client")
+    };
+    config
+        .providers
+        .iter()
+        .map(|provider| -> Result<Box<dyn MetadataProvider>> {
+            Ok(match provider {
+                EnrichmentProvider::Wikidata { url } => Box::new(WikidataProvider {
+                    client: client()?,
+                    url: url.clone(),
+                }),
+                EnrichmentProvider::GeoIp { url } => Box::new(GeoIpProvider {
+                    client: client()?,
+                    url: url.clone(),
+                }),
+            })
+        })
+        .collect()
+}
+
+/// Resolves the metadata of sources with the providers, cached
+pub struct Enricher {
+    providers: Vec<Box<dyn MetadataProvider>>,
+    cache_ttl: Duration,
+    resolved: Mutex<LruCache<String, (Instant, SourceMetadata)>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Enricher {
+    pub fn new(
+        providers: Vec<Box<dyn MetadataProvider>>,
+        config: &EnrichmentConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            providers,
+            cache_ttl: config.cache_ttl,
+            resolved: Mutex::new(LruCache::new(CACHE_CAPACITY)),
+            metrics,
+        }
+    }
+
+    /// Provider names, in order
+    pub fn names(&self) -> Vec<&'static str> {
+        self.providers.iter().map(|p| p.name()).collect()
+    }
+
+    /// Base facts about the source as of `now`
+    pub async fn facts(&self, source_id: &str, now: SystemTime) -> Result<DgraphFacts> {
+        Ok(self.metadata(source_id).await?.facts(source_id, now))
+    }
+
+    async fn metadata(&self, source_id: &str) -> Result<SourceMetadata> {
+        let Some((platform, handle)) = split(source_id) else {
+            return Ok(SourceMetadata::default());
+        };
+        let cached = self
+            .resolved
+            .lock()
+            .unwrap()
+            .get(&source_id.to_string())
+            .filter(|(at, _)| at.elapsed() < self.cache_ttl);
+        if let Some((_, metadata)) = cached {
+            return Ok(metadata);
+        }
+        let mut metadata = SourceMetadata::default();
+        let mut failed = 0;
+        let mut last_error = None;
+        for provider in &self.providers {
+            let outcome = match provider.lookup(platform, handle).await {
+                Ok(found) if found.is_empty() => "unknown",
+                Ok(found) => {
+                    metadata.merge(found);
+                    "found"
+                }
+                Err(e) => {
+                    warn!(
+                        "{} lookup of {} failed: {:#}",
+                        provider.name(),
+                        source_id,
+                        e
+                    );
+                    failed += 1;
+                    last_error = Some(e);
+                    "failed"
+                }
+            };
+            self.metrics
+                .enrichment_lookups
+                .with_label_values(&[provider.name(), outcome])
+                .inc();
+        }
+        if let Some(e) = last_error {
+            if failed == self.providers.len() {
+                return Err(e.context("Every enrichment provider failed"));
+            }
+        } else {
+            // A partial answer is asked for again on the next message
+            self.resolved
+                .lock()
+                .unwrap()
+                .put(source_id.to_string(), (Instant::now(), metadata.clone()));
+        }
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::UNIX_EPOCH;
+
+    /// Answers with fixed metadata, or fails when it has none
+    struct Fixed(Option<SourceMetadata>);
+
+    impl MetadataProvider for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn lookup<'a>(&'a self, _: &'a str, _: &'a str) -> BoxFuture<'a, Result<SourceMetadata>> {
+            Box::pin(async move {
+                match &self.0 {
+                    Some(metadata) => Ok(metadata.clone()),
+                    None => bail!("service unavailable"),
+                }
+            })
+        }
+    }
+
+    fn enricher(providers: Vec<Box<dyn MetadataProvider>>) -> Enricher {
+        Enricher::new(
+            providers,
+            &EnrichmentConfig {
+                providers: Vec::new(),
+                timeout: Duration::from_secs(1),
+                cache_ttl: Duration::from_secs(60),
+            },
+            Arc::new(Metrics::new().unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_wikidata_answers_are_parsed() {
+        assert!(wikidata_query("P2002", "example").contains("wdt:P2002 ?handle"));
+        assert!(wikidata_query("P2002", "a\"b").contains("\"a\\\"b\""));
+        let response = json!({"results": {"bindings": [{
+            "country": {"type": "literal", "value": "ru"},
+            "inception": {"type": "literal", "value": "2009-03-01T00:00:00Z"},
+        }]}});
+        assert_eq!(
+            parse_wikidata(&response),
+            SourceMetadata {
+                country: Some("RU".to_string()),
+                created_at: Some(1_235_865_600),
+            }
+        );
+        assert_eq!(
+            parse_wikidata(&json!({"results": {"bindings": []}})),
+            SourceMetadata::default()
+        );
+        assert_eq!(unix_date("1970-01-01"), Some(0));
+        assert_eq!(unix_date("2024-02-29T00:00:00Z"), Some(1_709_164_800));
+        assert_eq!(unix_date("-0500-01-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn test_metadata_maps_to_facts() {
+        let metadata = SourceMetadata {
+            country: Some("RU".to_string()),
+            created_at: Some(1_700_000_000),
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000 + 12 * DAY + 60);
+        let facts = metadata.facts("twitter:@example", now);
+        assert_eq!(facts["source_platform"], "twitter");
+        assert_eq!(facts["source_country"], "RU");
+        assert_eq!(facts["source_account_age"], "12");
+        assert!(SourceMetadata::default().facts("example", now).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_providers_fill_in_for_each_other() {
+        let country = SourceMetadata {
+            country: Some("RU".to_string()),
+            created_at: None,
+        };
+        let created = SourceMetadata {
+            country: Some("US".to_string()),
+            created_at: Some(1_700_000_000),
+        };
+        let resolved = enricher(vec![
+            Box::new(Fixed(None)),
+            Box::new(Fixed(Some(country))),
+            Box::new(Fixed(Some(created))),
+        ]);
+        let metadata = resolved.metadata("twitter:@example").await.unwrap();
+        assert_eq!(metadata.country.as_deref(), Some("RU"));
+        assert_eq!(metadata.created_at, Some(1_700_000_000));
+        assert_eq!(
+            resolved
+                .metrics
+                .enrichment_lookups
+                .with_label_values(&["fixed", "failed"])
+                .get(),
+            1.0
+        );
+
+        let failing = enricher(vec![Box::new(Fixed(None))]);
+        assert!(failing.metadata("twitter:@example").await.is_err());
+    }
+}
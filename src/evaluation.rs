@@ -0,0 +1,447 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Scoring of recorded verdicts (`nsai-detector evaluate`)
+//!
+//! Joins a labelled dataset with results recorded from a deployment, e.g.
+//! by a webhook consumer, on the content hash and reports precision, recall
+//! and F1 overall and per topic, plus a calibration curve of the fakeness
+//! score. The report is the artifact behind model and rule releases; the
+//! `eval/` crate benchmarks models on public datasets instead.
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    path::Path,
+};
+
+use crate::validation::REJECTED;
+
+/// One labelled sample, a line of the labels file
+#[derive(Debug, Clone, Deserialize)]
+pub struct LabeledSample {
+    pub content_hash: String,
+    /// Whether the content is known disinformation
+    pub fake: bool,
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+/// The fields of a recorded result the evaluation needs
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordedResult {
+    pub content_hash: String,
+    pub verdict: String,
+    #[serde(default)]
+    pub fakeness_score: f32,
+}
+
+/// A line of the results file: one webhook event or a delivered batch
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RecordedLine {
+    Batch { results: Vec<RecordedResult> },
+    Single(RecordedResult),
+}
+
+/// Counts of predicted against actual labels
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Confusion {
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub false_negatives: usize,
+    pub true_negatives: usize,
+}
+
+impl Confusion {
+    fn add(&mut self, predicted: bool, actual: bool) {
+        match (predicted, actual) {
+            (true, true) => self.true_positives += 1,
+            (true, false) => self.false_positives += 1,
+            (false, true) => self.false_negatives += 1,
+            (false, false) => self.true_negatives += 1,
+        }
+    }
+}
+
+/// Classification quality over a set of samples
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClassMetrics {
+    pub samples: usize,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+    pub accuracy: f64,
+    pub confusion: Confusion,
+}
+
+impl From<&Confusion> for ClassMetrics {
+    fn from(c: &Confusion) -> Self {
+        let ratio = |num: usize, den: usize| {
+            if den > 0 {
+                num as f64 / den as f64
+            } else {
+                0.0
+            }
+        };
+        let samples = c.true_positives + c.false_positives + c.false_negatives + c.true_negatives;
+        let precision = ratio(c.true_positives, c.true_positives + c.false_positives);
+        let recall = ratio(c.true_positives, c.true_positives + c.false_negatives);
+        let f1 = if precision + recall > 0.0 {
+            2.0 * precision * recall / (precision + recall)
+        } else {
+            0.0
+        };
+        Self {
+            samples,
+            precision,
+            recall,
+            f1,
+            accuracy: ratio(c.true_positives + c.true_negatives, samples),
+            confusion: c.clone(),
+        }
+    }
+}
+
+/// Samples whose fakeness score fell into `[lower, upper)`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CalibrationBin {
+    pub lower: f64,
+    pub upper: f64,
+    pub samples: usize,
+    /// Mean fakeness score; `None` for an empty bin
+    pub mean_score: Option<f64>,
+    /// Fraction labelled fake; `None` for an empty bin
+    pub fake_rate: Option<f64>,
+}
+
+/// Outcome of an evaluation
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub labels_sha256: String,
+    pub results_sha256: String,
+    /// Verdicts counted as predicting disinformation
+    pub positive_verdicts: Vec<String>,
+    /// Labelled samples without a recorded result
+    pub missing: usize,
+    /// Labelled samples the detector rejected
+    pub rejected: usize,
+    pub overall: ClassMetrics,
+    /// By the `topic` of the labels; samples without one are left out
+    pub topics: BTreeMap<String, ClassMetrics>,
+    pub calibration: Vec<CalibrationBin>,
+    pub expected_calibration_error: f64,
+    pub brier_score: f64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "samples: {} scored, {} without a result, {} rejected",
+            self.overall.samples, self.missing, self.rejected
+        )?;
+        writeln!(
+            f,
+            "positive verdicts: {}",
+            self.positive_verdicts.join(", ")
+        )?;
+        writeln!(
+            f,
+            "{:<20} {:>8} {:>10} {:>8} {:>8} {:>9}",
+            "", "samples", "precision", "recall", "f1", "accuracy"
+        )?;
+        let topics = self
+            .topics
+            .iter()
+            .map(|(topic, metrics)| (format!("topic {}", topic), metrics));
+        for (name, m) in std::iter::once(("overall".to_string(), &self.overall)).chain(topics) {
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>10.3} {:>8.3} {:>8.3} {:>9.3}",
+                name, m.samples, m.precision, m.recall, m.f1, m.accuracy
+            )?;
+        }
+        writeln!(
+            f,
+            "calibration of fakeness_score: ECE {:.3}, Brier {:.3}",
+            self.expected_calibration_error, self.brier_score
+        )?;
+        for bin in self.calibration.iter().filter(|b| b.samples > 0) {
+            writeln!(
+                f,
+                "  [{:.2}, {:.2}) {:>6} samples, mean score {:.3}, fake {:.3}",
+                bin.lower,
+                bin.upper,
+                bin.samples,
+                bin.mean_score.unwrap_or_default(),
+                bin.fake_rate.unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Evaluate the results recorded in `results` against the labels in
+/// `labels`
+///
+/// # Arguments
+/// * `labels` - Labelled samples, one JSON object per line
+/// * `results` - Recorded results, one webhook event or batch per line
+/// * `positive` - Verdicts counted as predicting disinformation
+/// * `bins` - Number of calibration bins
+pub fn run(labels: &Path, results: &Path, positive: &[String], bins: usize) -> Result<Report> {
+    if bins == 0 {
+        bail!("at least one calibration bin is required");
+    }
+    let read =
+        |path: &Path| fs::read(path).with_context(|| format!("Failed to read {}", path.display()));
+    let (label_bytes, result_bytes) = (read(labels)?, read(results)?);
+
+    let samples = parse_lines::<LabeledSample>(&label_bytes)
+        .with_context(|| format!("Invalid labels in {}", labels.display()))?;
+    let recorded = parse_lines::<RecordedLine>(&result_bytes)
+        .with_context(|| format!("Invalid results in {}", results.display()))?
+        .into_iter()
+        .flat_map(|line| match line {
+            RecordedLine::Batch { results } => results,
+            RecordedLine::Single(result) => vec![result],
+        })
+        .collect::<Vec<_>>();
+
+    let mut report = evaluate(&samples, &recorded, positive, bins);
+    report.labels_sha256 = hex::encode(Sha256::digest(&label_bytes));
+    report.results_sha256 = hex::encode(Sha256::digest(&result_bytes));
+    Ok(report)
+}
+
+/// Score recorded results against labelled samples
+///
+/// A content hash recorded more than once, e.g. after a redelivery, is
+/// scored by its last result.
+pub fn evaluate(
+    samples: &[LabeledSample],
+    results: &[RecordedResult],
+    positive: &[String],
+    bins: usize,
+) -> Report {
+    let by_hash: HashMap<&str, &RecordedResult> = results
+        .iter()
+        .map(|r| (r.content_hash.as_str(), r))
+        .collect();
+
+    let mut overall = Confusion::default();
+    let mut topics: BTreeMap<String, Confusion> = BTreeMap::new();
+    let mut scored = Vec::new();
+    let (mut missing, mut rejected) = (0, 0);
+    for sample in samples {
+        let Some(result) = by_hash.get(sample.content_hash.as_str()) else {
+            missing += 1;
+            continue;
+        };
+        if result.verdict == REJECTED {
+            rejected += 1;
+            continue;
+        }
+        let predicted = positive.contains(&result.verdict);
+        overall.add(predicted, sample.fake);
+        if let Some(topic) = &sample.topic {
+            topics
+                .entry(topic.clone())
+                .or_default()
+                .add(predicted, sample.fake);
+        }
+        scored.push((f64::from(result.fakeness_score), sample.fake));
+    }
+
+    let (calibration, expected_calibration_error) = calibrate(&scored, bins);
+    let brier_score = if scored.is_empty() {
+        0.0
+    } else {
+        scored
+            .iter()
+            .map(|(score, fake)| (score - if *fake { 1.0 } else { 0.0 }).powi(2))
+            .sum::<f64>()
+            / scored.len() as f64
+    };
+
+    Report {
+        labels_sha256: String::new(),
+        results_sha256: String::new(),
+        positive_verdicts: positive.to_vec(),
+        missing,
+        rejected,
+        overall: ClassMetrics::from(&overall),
+        topics: topics
+            .iter()
+            .map(|(topic, confusion)| (topic.clone(), ClassMetrics::from(confusion)))
+            .collect(),
+        calibration,
+        expected_calibration_error,
+        brier_score,
+    }
+}
+
+/// Equal-width bins of `(score, fake)` pairs and the expected calibration
+/// error over them
+fn calibrate(scored: &[(f64, bool)], bins: usize) -> (Vec<CalibrationBin>, f64) {
+    let mut sums = vec![(0usize, 0.0f64, 0usize); bins];
+    for &(score, fake) in scored {
+        let index = ((score.clamp(0.0, 1.0) * bins as f64) as usize).min(bins - 1);
+        let (count, score_sum, fakes) = &mut sums[index];
+        *count += 1;
+        *score_sum += score;
+        *fakes += usize::from(fake);
+    }
+
+    let mut error = 0.0;
+    let curve = sums
+        .iter()
+        .enumerate()
+        .map(|(index, &(count, score_sum, fakes))| {
+            let (mean_score, fake_rate) = if count > 0 {
+                let mean_score = score_sum / count as f64;
+                let fake_rate = fakes as f64 / count as f64;
+                error += count as f64 / scored.len() as f64 * (mean_score - fake_rate).abs();
+                (Some(mean_score), Some(fake_rate))
+            } else {
+                (None, None)
+            };
+            CalibrationBin {
+                lower: index as f64 / bins as f64,
+                upper: (index + 1) as f64 / bins as f64,
+                samples: count,
+                mean_score,
+                fake_rate,
+            }
+        })
+        .collect();
+    (curve, error)
+}
+
+/// Parse one JSON value per non-empty line
+fn parse_lines<T: DeserializeOwned>(bytes: &[u8]) -> Result<Vec<T>> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            serde_json::from_str(line).with_context(|| format!("line {}", number + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn sample(hash: &str, fake: bool, topic: Option<&str>) -> LabeledSample {
+        LabeledSample {
+            content_hash: hash.to_string(),
+            fake,
+            topic: topic.map(str::to_string),
+        }
+    }
+
+    fn result(hash: &str, verdict: &str, fakeness_score: f32) -> RecordedResult {
+        RecordedResult {
+            content_hash: hash.to_string(),
+            verdict: verdict.to_string(),
+            fakeness_score,
+        }
+    }
+
+    #[test]
+    fn test_metrics_and_topic_breakdown() {
+        let samples = vec![
+            sample("a", true, Some("vaccines")),
+            sample("b", true, Some("vaccines")),
+            sample("c", false, Some("elections")),
+            sample("d", false, None),
+            sample("e", true, None),
+            sample("f", false, None),
+        ];
+        let results = vec![
+            result("a", "DISINFO", 0.9),
+            result("b", "SAFE", 0.2),
+            result("c", "SAFE", 0.9),
+            result("c", "DISINFO", 0.8),
+            result("d", "SAFE", 0.1),
+            result("f", REJECTED, 0.0),
+        ];
+        let report = evaluate(&samples, &results, &["DISINFO".to_string()], 10);
+
+        assert_eq!((report.missing, report.rejected), (1, 1));
+        assert_eq!(report.overall.samples, 4);
+        assert_eq!(report.overall.precision, 0.5);
+        assert_eq!(report.overall.recall, 0.5);
+        assert_eq!(report.overall.f1, 0.5);
+        assert_eq!(report.overall.accuracy, 0.5);
+        assert_eq!(report.topics["vaccines"].recall, 0.5);
+        assert_eq!(report.topics["elections"].confusion.false_positives, 1);
+        assert!(!report.topics.contains_key("none"));
+
+        let suspicious = evaluate(
+            &samples,
+            &[result("b", "SUSPICIOUS", 0.6)],
+            &["DISINFO".to_string(), "SUSPICIOUS".to_string()],
+            10,
+        );
+        assert_eq!(suspicious.overall.recall, 1.0);
+    }
+
+    #[test]
+    fn test_calibration_curve() {
+        let scored = vec![
+            (0.05, false),
+            (0.15, false),
+            (0.95, true),
+            (1.0, true),
+            (0.9, false),
+        ];
+        let (curve, error) = calibrate(&scored, 2);
+
+        assert_eq!(curve[0].samples, 2);
+        assert_eq!(curve[0].fake_rate, Some(0.0));
+        assert_eq!(curve[1].samples, 3);
+        assert!((curve[1].mean_score.unwrap() - 0.95).abs() < 1e-9);
+        // (2 * 0.1 + 3 * |0.95 - 2/3|) / 5
+        assert!((error - 0.21).abs() < 1e-9);
+
+        let (empty, _) = calibrate(&[], 4);
+        assert_eq!(empty.len(), 4);
+        assert_eq!(empty[3].mean_score, None);
+    }
+
+    #[test]
+    fn test_results_file_formats() {
+        let dir = TempDir::new("evaluate");
+        let labels = dir.join("labels.jsonl");
+        let results = dir.join("results.jsonl");
+        fs::write(
+            &labels,
+            "{\"content_hash\": \"a\", \"fake\": true}\n\n{\"content_hash\": \"b\", \"fake\": false}\n",
+        )
+        .unwrap();
+        fs::write(
+            &results,
+            "{\"content_hash\": \"a\", \"verdict\": \"DISINFO\", \"fakeness_score\": 0.9}\n\
+             {\"results\": [{\"content_hash\": \"b\", \"verdict\": \"SAFE\", \"source_id\": \"x\"}]}\n",
+        )
+        .unwrap();
+
+        let report = run(&labels, &results, &["DISINFO".to_string()], 10).unwrap();
+        assert_eq!(report.overall.accuracy, 1.0);
+        assert_eq!(report.labels_sha256.len(), 64);
+        assert!(report.to_string().contains("overall"));
+        assert!(run(&labels, &results, &[], 0).is_err());
+
+        fs::write(&results, "not json\n").unwrap();
+        let error = run(&labels, &results, &[], 10).unwrap_err();
+        assert!(format!("{:#}", error).contains("line 1"));
+    }
+}
@@ -0,0 +1,300 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Explanation templates
+//!
+//! The rules explain a verdict by the rules that fired. With
+//! `NSAI_EXPLANATION_TEMPLATES` set, the explanation is instead rendered
+//! from a template per fired rule, filled in with the scores and facts the
+//! rule saw, and localized by the message's language. The file maps
+//! locales to templates by rule identifier:
+//!
+//! ```yaml
+//! en:
+//!   disinfo: Flagged because fakeness={score:fakeness} from an untrusted source
+//!   sanctioned: source was sanctioned on {fact:source_sanctioned_on}
+//! de:
+//!   disinfo: Markiert, da Fakeness={score:fakeness} aus nicht vertrauenswürdiger Quelle
+//! ```
+//!
+//! `{score:<feature>}` is a model score with two decimals, `{fact:<relation>}`
+//! the first argument of a base fact and `{premises}` the premises that
+//! held; `{{` and `}}` are literal braces. The templates of the message's
+//! language are used when the file has that locale, those of
+//! `NSAI_EXPLANATION_LOCALE` otherwise. A rule whose facts are missing is
+//! left out, and when no template applies the rules' explanation stands.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+};
+
+use crate::fact_mapping::{AsScore, Fact};
+use crate::onnx_wrapper::NeuralFeatures;
+use crate::souffle_wrapper::FiredRule;
+
+/// Explanation template settings
+#[derive(Debug, Clone)]
+pub struct ExplanationConfig {
+    /// YAML file of templates by locale and rule
+    pub templates: PathBuf,
+    /// Locale used for languages without templates
+    pub locale: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Score(String),
+    Fact(String),
+    Premises,
+}
+
+/// Localized templates by rule identifier
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplanationTemplates {
+    locales: HashMap<String, BTreeMap<String, Vec<Part>>>,
+    locale: String,
+}
+
+impl ExplanationTemplates {
+    pub fn load(config: &ExplanationConfig) -> Result<Self> {
+        let path = &config.templates;
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&contents, &config.locale)
+            .with_context(|| format!("Invalid explanation templates in {}", path.display()))
+    }
+
+    fn parse(contents: &str, locale: &str) -> Result<Self> {
+        let file: HashMap<String, BTreeMap<String, String>> = serde_yaml::from_str(contents)?;
+        let mut locales = HashMap::new();
+        for (code, templates) in file {
+            let mut parsed = BTreeMap::new();
+            for (rule, template) in templates {
+                let parts = parse_template(&template)
+                    .with_context(|| format!("template {} of locale {}", rule, code))?;
+                parsed.insert(rule, parts);
+            }
+            locales.insert(code.to_lowercase(), parsed);
+        }
+        let locale = locale.to_lowercase();
+        if !locales.contains_key(&locale) {
+            bail!("no templates for the default locale {}", locale);
+        }
+        Ok(Self { locales, locale })
+    }
+
+    /// Locales with templates
+    pub fn locales(&self) -> usize {
+        self.locales.len()
+    }
+
+    /// Render the templates of the `fired` rules, joined by `; `
+    ///
+    /// # Returns
+    /// The explanation, or `None` when no fired rule has a template whose
+    /// facts are all present
+    pub fn render(
+        &self,
+        language: &str,
+        fired: &[FiredRule],
+        facts: &[Fact],
+        features: &NeuralFeatures,
+    ) -> Option<String> {
+        let templates = self
+            .locales
+            .get(&language.to_lowercase())
+            .unwrap_or(&self.locales[&self.locale]);
+        let rendered: Vec<String> = fired
+            .iter()
+            .filter_map(|rule| {
+                let parts = templates.get(&rule.rule)?;
+                let mut out = String::new();
+                for part in parts {
+                    match part {
+                        Part::Text(text) => out.push_str(text),
+                        Part::Score(feature) => {
+                            out.push_str(&format!("{:.2}", score(features, feature)?))
+                        }
+                        Part::Fact(relation) => out.push_str(
+                            facts
+                                .iter()
+                                .find(|f| &f.relation == relation)?
+                                .args
+                                .first()?,
+                        ),
+                        Part::Premises => out.push_str(&rule.premises.join(", ")),
+                    }
+                }
+                Some(out)
+            })
+            .collect();
+        (!rendered.is_empty()).then(|| rendered.join("; "))
+    }
+}
+
+/// Split a template into text and placeholders
+fn parse_template(template: &str) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    name.push(c);
+                }
+                if !closed {
+                    bail!("unterminated placeholder {{{}", name);
+                }
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                parts.push(match name.split_once(':') {
+                    None if name == "premises" => Part::Premises,
+                    Some(("score", feature))
+                        if score(&NeuralFeatures::default(), feature).is_some() =>
+                    {
+                        Part::Score(feature.to_string())
+                    }
+                    Some(("fact", relation))
+                        if !relation.is_empty()
+                            && relation.chars().all(|c| c.is_alphanumeric() || c == '_') =>
+                    {
+                        Part::Fact(relation.to_string())
+                    }
+                    _ => bail!(
+                        "unknown placeholder {{{}}}, expected {{score:<feature>}}, \
+                         {{fact:<relation>}} or {{premises}}",
+                        name
+                    ),
+                });
+            }
+            '}' => bail!("unmatched }} in {:?}", template),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(parts)
+}
+
+/// Score of the model output named like its fact relation
+fn score(features: &NeuralFeatures, feature: &str) -> Option<f32> {
+    match feature {
+        "fakeness" => Some(features.fakeness),
+        "emotion" => Some(features.emotion),
+        "ai_generated" => Some(features.ai_generated),
+        "visual_artifact" => Some(features.visual_artifact.as_score()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATES: &str = "\
+en:
+  disinfo: Flagged because fakeness={score:fakeness} from an untrusted source
+  sanctioned: source was sanctioned on {fact:source_sanctioned_on}
+de:
+  disinfo: 'Markiert, da Fakeness={score:fakeness} ({premises})'
+";
+
+    fn fired(rule: &str, premises: &[&str]) -> FiredRule {
+        FiredRule {
+            rule: rule.to_string(),
+            premises: premises.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_templates_render_in_the_message_language() {
+        let templates = ExplanationTemplates::parse(TEMPLATES, "en").unwrap();
+        assert_eq!(templates.locales(), 2);
+        let features = NeuralFeatures {
+            fakeness: 0.912,
+            ..Default::default()
+        };
+        let facts = vec![Fact::new(
+            "source_sanctioned_on",
+            vec!["2024-05-01".to_string()],
+        )];
+        let rules = [
+            fired("disinfo", &["fakeness(\"high\")", "untrusted_source()"]),
+            fired("sanctioned", &[]),
+            fired("untrusted_source", &[]),
+        ];
+        assert_eq!(
+            templates.render("fr", &rules, &facts, &features).unwrap(),
+            "Flagged because fakeness=0.91 from an untrusted source; \
+             source was sanctioned on 2024-05-01"
+        );
+        assert_eq!(
+            templates.render("DE", &rules, &facts, &features).unwrap(),
+            "Markiert, da Fakeness=0.91 (fakeness(\"high\"), untrusted_source())"
+        );
+    }
+
+    #[test]
+    fn test_rules_without_facts_or_templates_are_left_out() {
+        let templates = ExplanationTemplates::parse(TEMPLATES, "en").unwrap();
+        let features = NeuralFeatures::default();
+        assert_eq!(
+            templates.render("en", &[fired("sanctioned", &[])], &[], &features),
+            None
+        );
+        assert_eq!(
+            templates.render("en", &[fired("verdict:safe", &[])], &[], &features),
+            None
+        );
+    }
+
+    #[test]
+    fn test_invalid_templates_are_rejected() {
+        assert!(ExplanationTemplates::parse(TEMPLATES, "it").is_err());
+        for template in [
+            "{score:sarcasm}",
+            "{fact:}",
+            "{source}",
+            "fakeness={score:fakeness",
+            "50}",
+        ] {
+            let contents = format!("en:\n  disinfo: '{}'\n", template);
+            assert!(
+                ExplanationTemplates::parse(&contents, "en").is_err(),
+                "{}",
+                template
+            );
+        }
+        let escaped = ExplanationTemplates::parse("en:\n  disinfo: '{{x}}'\n", "en").unwrap();
+        assert_eq!(
+            escaped.render(
+                "en",
+                &[fired("disinfo", &[])],
+                &[],
+                &NeuralFeatures::default()
+            ),
+            Some("{x}".to_string())
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Dumps of the facts behind a verdict
+//!
+//! A puzzling verdict is reproduced from the facts the rules saw, which the
+//! published result does not carry. With a sink configured, the base facts
+//! and the relations the rules derived are dumped for every message with a
+//! [`DUMP_HEADER`] header, or with `NSAI_FACT_DUMP_ALL` for every message,
+//! as JSON to a directory or a NATS subject. Facts are written in the
+//! syntax of [`crate::rule_tests`] cases, so a dump pastes into a case that
+//! `validate-rules` runs offline.
+
+use anyhow::{Context, Result};
+use async_nats::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+use tracing::{info, warn};
+
+use crate::fact_mapping::Fact;
+use crate::metrics::Metrics;
+use crate::souffle_wrapper::ReasoningResult;
+use crate::transport::Transport;
+
+/// Message header requesting a dump, whatever its value
+pub const DUMP_HEADER: &str = "Nsai-Dump-Facts";
+
+/// Where dumps go
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpSink {
+    /// `<content_hash>.json` files in a directory
+    Dir(PathBuf),
+    /// JSON messages on a NATS subject
+    Subject(String),
+}
+
+impl FromStr for DumpSink {
+    type Err = anyhow::Error;
+
+    /// Parse `nats:<subject>` or a directory path
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("nats:") {
+            Some("") => anyhow::bail!("empty dump subject"),
+            Some(subject) => Ok(Self::Subject(subject.to_string())),
+            None => Ok(Self::Dir(PathBuf::from(s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FactDumpConfig {
+    pub sink: DumpSink,
+    /// Dump every message, not only those with [`DUMP_HEADER`]
+    pub all: bool,
+}
+
+/// Facts in and out of the rules for one message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FactDump {
+    pub content_hash: String,
+    pub source_id: String,
+    pub rules_version: String,
+    pub verdict: String,
+    /// Base facts, sorted, e.g. `fakeness("high")`
+    pub facts: Vec<String>,
+    /// Relations the rules derived, sorted
+    pub derived: Vec<String>,
+    /// Identifiers of the rules that fired
+    pub fired: Vec<String>,
+}
+
+impl FactDump {
+    pub fn new(
+        content_hash: &str,
+        source_id: &str,
+        facts: &[Fact],
+        result: &ReasoningResult,
+    ) -> Self {
+        Self {
+            content_hash: content_hash.to_string(),
+            source_id: source_id.to_string(),
+            rules_version: result.rules_version.clone(),
+            verdict: result.verdict.to_string(),
+            facts: sorted(facts),
+            derived: sorted(&result.derived),
+            fired: result.fired_rules.iter().map(|f| f.rule.clone()).collect(),
+        }
+    }
+}
+
+/// Facts as case syntax, without the closing `.`
+fn sorted(facts: &[Fact]) -> Vec<String> {
+    let mut facts: Vec<String> = facts
+        .iter()
+        .map(|fact| fact.to_string().trim_end_matches('.').to_string())
+        .collect();
+    facts.sort();
+    facts.dedup();
+    facts
+}
+
+/// Writes dumps of the requested messages to the sink
+pub struct FactDumper {
+    config: FactDumpConfig,
+    transport: Arc<dyn Transport>,
+    metrics: Arc<Metrics>,
+}
+
+impl FactDumper {
+    pub fn new(
+        config: FactDumpConfig,
+        transport: Arc<dyn Transport>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        match &config.sink {
+            DumpSink::Dir(dir) => {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+                info!("Dumping rule facts to {}", dir.display());
+            }
+            DumpSink::Subject(subject) => info!("Dumping rule facts to {}", subject),
+        }
+        Ok(Self {
+            config,
+            transport,
+            metrics,
+        })
+    }
+
+    /// Whether the message with `headers` is dumped
+    pub fn wanted(&self, headers: Option<&HeaderMap>) -> bool {
+        self.config.all || headers.is_some_and(|headers| headers.get(DUMP_HEADER).is_some())
+    }
+
+    /// Write `dump`; a failure is logged and counted, never failing the
+    /// message
+    pub async fn dump(&self, dump: &FactDump) {
+        let outcome = match self.write(dump).await {
+            Ok(()) => "written",
+            Err(e) => {
+                warn!("Fact dump of {} failed: {:#}", dump.content_hash, e);
+                "failed"
+            }
+        };
+        self.metrics.fact_dumps.with_label_values(&[outcome]).inc();
+    }
+
+    async fn write(&self, dump: &FactDump) -> Result<()> {
+        let payload = serde_json::to_vec_pretty(dump)?;
+        match &self.config.sink {
+            DumpSink::Dir(dir) => {
+                // Content hashes are validated hex, safe as file names
+                let path = dir.join(format!("{}.json", dump.content_hash));
+                tokio::fs::write(&path, payload)
+                    .await
+                    .with_context(|| format!("Failed to write {}", path.display()))
+            }
+            DumpSink::Subject(subject) => self
+                .transport
+                .send(subject, HeaderMap::new(), payload)
+                .await
+                .with_context(|| format!("Failed to publish to {}", subject)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::souffle_wrapper::{FiredRule, Verdict};
+
+    #[test]
+    fn test_sinks_are_parsed() {
+        assert_eq!(
+            "nats:disinfo.debug".parse::<DumpSink>().unwrap(),
+            DumpSink::Subject("disinfo.debug".to_string())
+        );
+        assert_eq!(
+            "/var/lib/nsai/dumps".parse::<DumpSink>().unwrap(),
+            DumpSink::Dir(PathBuf::from("/var/lib/nsai/dumps"))
+        );
+        assert!("nats:".parse::<DumpSink>().is_err());
+    }
+
+    #[test]
+    fn test_dump_facts_parse_as_case_facts() {
+        let facts = vec![
+            Fact::new("source_trusted", vec!["false".to_string()]),
+            Fact::new("fakeness", vec!["high".to_string()]),
+        ];
+        let result = ReasoningResult {
+            verdict: Verdict::Disinfo,
+            explanation: String::new(),
+            confidence: 0.9,
+            fired_rules: vec![FiredRule {
+                rule: "disinfo".to_string(),
+                premises: Vec::new(),
+            }],
+            rules_version: "abc".to_string(),
+            rules_semver: String::new(),
+            labels: Vec::new(),
+            derived: vec![Fact::new("disinfo", vec![])],
+        };
+        let dump = FactDump::new("ab12", "source", &facts, &result);
+        assert_eq!(
+            dump.facts,
+            ["fakeness(\"high\")", "source_trusted(\"false\")"]
+        );
+        assert_eq!(dump.derived, ["disinfo()"]);
+        assert_eq!(dump.fired, ["disinfo"]);
+        let parsed: Vec<Fact> = dump.facts.iter().map(|f| f.parse().unwrap()).collect();
+        assert_eq!(parsed, [facts[1].clone(), facts[0].clone()]);
+    }
+}
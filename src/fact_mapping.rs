@@ -0,0 +1,348 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Declarative conversion of neural outputs into Datalog facts
+//!
+//! Each model output is described once with the [`feature_facts!`] macro:
+//!
+//! ```ignore
+//! feature_facts! {
+//!     pub fn neural_facts;
+//!     pub const NEURAL_BINS;
+//!     feature fakeness -> fact fakeness(level)
+//!         bins [0.0, 0.6, 0.8, 1.0] levels ["low", "medium", "high"];
+//! }
+//! ```
+//!
+//! which generates the glue that discretizes the score and emits
+//! `fakeness("high").`-style facts for the symbolic layer.
+
+use anyhow::{bail, Result};
+use std::{collections::HashMap, fmt};
+
+/// A single ground fact handed to the symbolic layer
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Fact {
+    pub relation: String,
+    pub args: Vec<String>,
+}
+
+impl Fact {
+    pub fn new(relation: impl Into<String>, args: Vec<String>) -> Self {
+        Self {
+            relation: relation.into(),
+            args,
+        }
+    }
+}
+
+impl fmt::Display for Fact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args: Vec<String> = self.args.iter().map(|a| format!("{:?}", a)).collect();
+        write!(f, "{}({}).", self.relation, args.join(", "))
+    }
+}
+
+impl std::str::FromStr for Fact {
+    type Err = anyhow::Error;
+
+    /// Parse `relation(arg, "arg", ...)`, with an optional trailing `.`
+    ///
+    /// Quoted arguments may contain commas and parentheses, and take the
+    /// escapes [`Fact`]'s `Display` writes.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().trim_end_matches('.');
+        let Some((relation, rest)) = s.split_once('(') else {
+            bail!("expected relation(args): {}", s);
+        };
+        let Some(args) = rest.strip_suffix(')') else {
+            bail!("missing closing parenthesis: {}", s);
+        };
+        let relation = relation.trim();
+        if relation.is_empty() || !relation.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            bail!("invalid relation name: {:?}", relation);
+        }
+        Ok(Self::new(relation, split_args(args)?))
+    }
+}
+
+/// Split fact arguments on the commas outside quotes, unquoting quoted ones
+fn split_args(args: &str) -> Result<Vec<String>> {
+    let mut out = Vec::new();
+    let mut chars = args.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(out);
+        };
+        let mut arg = String::new();
+        if first == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => arg.push('\n'),
+                        Some('r') => arg.push('\r'),
+                        Some('t') => arg.push('\t'),
+                        Some(escaped) => arg.push(escaped),
+                        None => bail!("unterminated string in ({})", args),
+                    },
+                    Some(c) => arg.push(c),
+                    None => bail!("unterminated string in ({})", args),
+                }
+            }
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                arg.push(c);
+            }
+            arg.truncate(arg.trim_end().len());
+            if arg.is_empty() {
+                bail!("empty argument in ({})", args);
+            }
+        }
+        out.push(arg);
+        match chars.next() {
+            None | Some(',') => {}
+            Some(c) => bail!("unexpected {:?} after an argument in ({})", c, args),
+        }
+    }
+}
+
+/// Runtime replacements for default bin edges, keyed by relation name
+pub type BinOverrides = HashMap<String, Vec<f32>>;
+
+/// Default discretization of one feature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BinSpec {
+    pub relation: &'static str,
+    pub edges: &'static [f32],
+    pub levels: &'static [&'static str],
+}
+
+impl BinSpec {
+    /// Edges to use, honoring a well-formed override
+    pub fn edges<'a>(&'a self, overrides: &'a BinOverrides) -> &'a [f32] {
+        overrides
+            .get(self.relation)
+            .filter(|edges| edges.len() == self.levels.len() + 1)
+            .map(Vec::as_slice)
+            .unwrap_or(self.edges)
+    }
+}
+
+/// Check that overrides name known relations and have the right arity
+pub fn validate_overrides(specs: &[BinSpec], overrides: &BinOverrides) -> Result<()> {
+    for (relation, edges) in overrides {
+        let Some(spec) = specs.iter().find(|s| s.relation == relation.as_str()) else {
+            bail!("no feature maps to relation {}", relation);
+        };
+        if edges.len() != spec.levels.len() + 1 {
+            bail!(
+                "{} needs {} bin edges, got {}",
+                relation,
+                spec.levels.len() + 1,
+                edges.len()
+            );
+        }
+        if edges.windows(2).any(|w| w[0] > w[1]) {
+            bail!("{} bin edges must be ascending", relation);
+        }
+    }
+    Ok(())
+}
+
+/// Numeric view of a feature field for discretization
+pub trait AsScore {
+    fn as_score(&self) -> f32;
+}
+
+impl AsScore for f32 {
+    fn as_score(&self) -> f32 {
+        *self
+    }
+}
+
+impl AsScore for bool {
+    fn as_score(&self) -> f32 {
+        if *self {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Map a score onto a level using `(lo, hi]` bins
+///
+/// Values below the first edge fall into the first level and values above
+/// the last edge into the last level, so out-of-range model outputs still
+/// produce a fact. NaN has no level; the feature then has no fact.
+pub fn discretize<'a>(value: f32, edges: &[f32], levels: &[&'a str]) -> Option<&'a str> {
+    if value.is_nan() {
+        return None;
+    }
+    for (level, upper) in levels.iter().zip(&edges[1..]) {
+        if value <= *upper {
+            return Some(level);
+        }
+    }
+    Some(levels[levels.len() - 1])
+}
+
+/// Generate a feature → fact conversion function and its bin table
+///
+/// Each `feature` names a field of `NeuralFeatures`; boolean fields are
+/// discretized as `0.0`/`1.0`, and NaN scores are left out. The generated
+/// function takes
+/// [`BinOverrides`] so thresholds can be changed without recompiling.
+#[macro_export]
+macro_rules! feature_facts {
+    (
+        $(#[$meta:meta])*
+        $vis:vis fn $name:ident;
+        $table_vis:vis const $table:ident;
+        $(
+            feature $field:ident -> fact $relation:ident(level)
+                bins [$($edge:expr),+ $(,)?] levels [$($level:literal),+ $(,)?];
+        )*
+    ) => {
+        /// Default bins for every mapped feature
+        $table_vis const $table: &[$crate::fact_mapping::BinSpec] = &[
+            $(
+                $crate::fact_mapping::BinSpec {
+                    relation: stringify!($relation),
+                    edges: &[$($edge),+],
+                    levels: &[$($level),+],
+                },
+            )*
+        ];
+
+        $(#[$meta])*
+        $vis fn $name(
+            features: &$crate::onnx_wrapper::NeuralFeatures,
+            overrides: &$crate::fact_mapping::BinOverrides,
+        ) -> Vec<$crate::fact_mapping::Fact> {
+            let mut facts = Vec::new();
+            let mut specs = $table.iter();
+            $(
+                const _: () = assert!(
+                    [$($edge),+].len() == [$($level),+].len() + 1,
+                    "bins must have exactly one more edge than levels"
+                );
+                let spec = specs.next().expect("one bin spec per feature");
+                if let Some(level) = $crate::fact_mapping::discretize(
+                    $crate::fact_mapping::AsScore::as_score(&features.$field),
+                    spec.edges(overrides),
+                    spec.levels,
+                ) {
+                    facts.push($crate::fact_mapping::Fact::new(
+                        stringify!($relation),
+                        vec![level.to_string()],
+                    ));
+                }
+            )*
+            facts
+        }
+    };
+}
+
+feature_facts! {
+    /// Discretized neural outputs consumed by the Datalog rules
+    pub fn neural_facts;
+    pub const NEURAL_BINS;
+    feature fakeness -> fact fakeness(level)
+        bins [0.0, 0.6, 0.8, 1.0] levels ["low", "medium", "high"];
+    feature emotion -> fact emotion(level)
+        bins [0.0, 0.5, 0.8, 1.0] levels ["low", "medium", "high"];
+    feature ai_generated -> fact ai_generated(level)
+        bins [0.0, 0.5, 0.8, 1.0] levels ["low", "medium", "high"];
+    feature visual_artifact -> fact visual_artifact(level)
+        bins [0.0, 0.5, 1.0] levels ["absent", "present"];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onnx_wrapper::NeuralFeatures;
+
+    #[test]
+    fn test_discretize_bin_edges() {
+        let edges = [0.0, 0.6, 0.8, 1.0];
+        let levels = ["low", "medium", "high"];
+        assert_eq!(discretize(0.6, &edges, &levels), Some("low"));
+        assert_eq!(discretize(0.61, &edges, &levels), Some("medium"));
+        assert_eq!(discretize(0.8, &edges, &levels), Some("medium"));
+        assert_eq!(discretize(0.81, &edges, &levels), Some("high"));
+        assert_eq!(discretize(1.5, &edges, &levels), Some("high"));
+        assert_eq!(discretize(-0.2, &edges, &levels), Some("low"));
+        assert_eq!(discretize(f32::NAN, &edges, &levels), None);
+    }
+
+    #[test]
+    fn test_nan_score_has_no_fact() {
+        let features = NeuralFeatures {
+            fakeness: f32::NAN,
+            emotion: 0.9,
+            ..Default::default()
+        };
+
+        let facts = neural_facts(&features, &BinOverrides::new());
+        assert!(facts.iter().all(|fact| fact.relation != "fakeness"));
+        assert!(facts.contains(&Fact::new("emotion", vec!["high".to_string()])));
+    }
+
+    #[test]
+    fn test_fact_parse_respects_quotes() {
+        let fact: Fact = r#"claim("a, b", plain, "say \"hi\" (twice)")."#.parse().unwrap();
+        assert_eq!(fact.relation, "claim");
+        assert_eq!(fact.args, ["a, b", "plain", "say \"hi\" (twice)"]);
+        assert_eq!(fact.to_string().parse::<Fact>().unwrap(), fact);
+
+        let empty: Fact = "tick()".parse().unwrap();
+        assert!(empty.args.is_empty());
+        assert!(r#"claim("open)"#.parse::<Fact>().is_err());
+        assert!(r#"claim("a" b)"#.parse::<Fact>().is_err());
+        assert!("claim(a,,b)".parse::<Fact>().is_err());
+    }
+
+    #[test]
+    fn test_neural_facts() {
+        let features = NeuralFeatures {
+            fakeness: 0.9,
+            visual_artifact: true,
+            ..Default::default()
+        };
+
+        let facts = neural_facts(&features, &BinOverrides::new());
+        assert_eq!(facts[0], Fact::new("fakeness", vec!["high".to_string()]));
+        assert_eq!(facts[0].to_string(), "fakeness(\"high\").");
+        assert!(facts.contains(&Fact::new("visual_artifact", vec!["present".to_string()])));
+    }
+
+    #[test]
+    fn test_bin_overrides() {
+        let features = NeuralFeatures {
+            fakeness: 0.7,
+            ..Default::default()
+        };
+        let mut overrides = BinOverrides::new();
+        overrides.insert("fakeness".to_string(), vec![0.0, 0.4, 0.6, 1.0]);
+        validate_overrides(NEURAL_BINS, &overrides).unwrap();
+
+        let facts = neural_facts(&features, &overrides);
+        assert_eq!(facts[0], Fact::new("fakeness", vec!["high".to_string()]));
+
+        overrides.insert("fakeness".to_string(), vec![0.0, 1.0]);
+        assert!(validate_overrides(NEURAL_BINS, &overrides).is_err());
+    }
+
+    #[test]
+    fn test_fact_parse() {
+        let fact: Fact = "source_trusted(\"false\").".parse().unwrap();
+        assert_eq!(fact, Fact::new("source_trusted", vec!["false".to_string()]));
+        assert_eq!("flagged()".parse::<Fact>().unwrap().args.len(), 0);
+        assert!("not a fact".parse::<Fact>().is_err());
+    }
+}
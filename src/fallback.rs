@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Fallback scoring when primary inference fails
+//!
+//! Without a fallback, a message whose inference errors is dropped and one
+//! whose inference times out is redelivered, possibly into the same
+//! outage. A configured fallback, either a lightweight model or a text
+//! heuristic, scores the message instead so it still gets a best-effort
+//! verdict. Fallback scores bypass calibration, and the heuristic never
+//! reaches the `high` fakeness bin, so it cannot produce `DISINFO` alone.
+
+use anyhow::Result;
+use std::{str::FromStr, time::Duration};
+use tokio::time::timeout;
+
+use crate::onnx_wrapper::{Ensemble, ModelSpec, NeuralFeatures};
+
+/// Model version reported for heuristic scores
+pub const HEURISTIC_VERSION: &str = "heuristic";
+
+/// Highest fakeness the heuristic reports, just below the `high` bin
+const HEURISTIC_MAX_FAKENESS: f32 = 0.79;
+
+/// Phrases typical of sensationalist disinformation
+const SENSATIONAL: &[&str] = &[
+    "shocking",
+    "miracle",
+    "they don't want you to know",
+    "mainstream media won't",
+    "wake up",
+    "share before",
+    "banned",
+    "exposed",
+    "cover-up",
+    "100% proof",
+];
+
+/// Configured fallback, parsed from `heuristic` or a `name:path[:weight]`
+/// model spec
+#[derive(Debug, Clone, PartialEq)]
+pub enum FallbackConfig {
+    Model(ModelSpec),
+    Heuristic,
+}
+
+impl FromStr for FallbackConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "heuristic" => Ok(Self::Heuristic),
+            spec => Ok(Self::Model(spec.parse()?)),
+        }
+    }
+}
+
+/// Scores messages the primary models could not
+pub enum Fallback {
+    Model(Ensemble),
+    Heuristic,
+}
+
+impl Fallback {
+    pub fn version(&self) -> String {
+        match self {
+            Self::Model(ensemble) => ensemble.version(),
+            Self::Heuristic => HEURISTIC_VERSION.to_string(),
+        }
+    }
+
+    /// Score a message, the model bounded by `deadline`
+    pub async fn score(
+        &self,
+        content_hash: &str,
+        text: &str,
+        deadline: Duration,
+    ) -> Result<NeuralFeatures> {
+        match self {
+            Self::Model(ensemble) => timeout(deadline, ensemble.run(content_hash))
+                .await
+                .map_err(|_| anyhow::anyhow!("fallback model exceeded {:?}", deadline))?,
+            Self::Heuristic => Ok(heuristic_features(text)),
+        }
+    }
+}
+
+/// Score shouting, exclamations and sensationalist phrasing
+pub fn heuristic_features(text: &str) -> NeuralFeatures {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut features = NeuralFeatures {
+        model_version: HEURISTIC_VERSION.to_string(),
+        ..Default::default()
+    };
+    if words.is_empty() {
+        return features;
+    }
+
+    let shouting = words
+        .iter()
+        .filter(|w| {
+            w.chars().filter(|c| c.is_alphabetic()).count() > 2
+                && !w.chars().any(char::is_lowercase)
+        })
+        .count() as f32
+        / words.len() as f32;
+    let exclamations = text.matches('!').count() as f32 / words.len() as f32;
+    features.emotion = (shouting * 2.0 + exclamations * 2.0).min(1.0);
+
+    let lower = text.to_lowercase();
+    let sensational = SENSATIONAL.iter().filter(|p| lower.contains(*p)).count() as f32;
+    features.fakeness = (sensational * 0.2 + features.emotion * 0.3).min(HEURISTIC_MAX_FAKENESS);
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heuristic_scores() {
+        let calm = heuristic_features("The council approved the budget on Tuesday.");
+        assert_eq!(calm.fakeness, 0.0);
+        assert_eq!(calm.model_version, HEURISTIC_VERSION);
+
+        let loud = heuristic_features(
+            "SHOCKING miracle cure EXPOSED!!! They don't want you to know, share before it is banned!",
+        );
+        assert!(loud.emotion > 0.5);
+        // Never high enough for DISINFO on its own
+        assert_eq!(loud.fakeness, HEURISTIC_MAX_FAKENESS);
+
+        assert_eq!(heuristic_features("").fakeness, 0.0);
+    }
+
+    #[test]
+    fn test_fallback_config_parse() {
+        assert_eq!(
+            "heuristic".parse::<FallbackConfig>().unwrap(),
+            FallbackConfig::Heuristic
+        );
+        let FallbackConfig::Model(spec) = "tiny:models/tiny.onnx".parse().unwrap() else {
+            panic!("expected a model fallback");
+        };
+        assert_eq!(spec.name, "tiny");
+        assert_eq!(spec.path, "models/tiny.onnx");
+        assert!("tiny".parse::<FallbackConfig>().is_err());
+    }
+}
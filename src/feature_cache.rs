@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Cache of neural features keyed by content hash
+//!
+//! Reposts of identical content skip ONNX inference entirely. Keys include
+//! the serving model version so a model upgrade never returns features
+//! produced by the previous model.
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::{str::FromStr, sync::Mutex};
+
+use crate::cache::LruCache;
+use crate::config::FeatureCacheConfig;
+use crate::onnx_wrapper::NeuralFeatures;
+
+/// Which feature cache backend to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureCacheBackend {
+    Off,
+    Memory,
+    /// Shared across replicas; requires the `redis-cache` feature
+    Redis,
+}
+
+impl FromStr for FeatureCacheBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "memory" => Ok(Self::Memory),
+            "redis" => Ok(Self::Redis),
+            other => anyhow::bail!("unknown feature cache backend: {}", other),
+        }
+    }
+}
+
+/// Storage for previously computed features
+pub trait FeatureCache: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<NeuralFeatures>>>;
+
+    fn put<'a>(&'a self, key: &'a str, features: &'a NeuralFeatures) -> BoxFuture<'a, Result<()>>;
+
+    /// Number of locally held entries, if known
+    fn local_entries(&self) -> Option<usize> {
+        None
+    }
+
+    /// Drop locally held entries to free memory; shared backends keep theirs
+    fn shed(&self) -> usize {
+        0
+    }
+}
+
+/// Cache key for a content hash under a given model version
+pub fn cache_key(content_hash: &str, model_version: &str) -> String {
+    format!("nsai:features:{}:{}", model_version, content_hash)
+}
+
+/// Build the configured backend, or `None` when caching is off
+pub async fn from_config(config: &FeatureCacheConfig) -> Result<Option<Box<dyn FeatureCache>>> {
+    match config.backend {
+        FeatureCacheBackend::Off => Ok(None),
+        FeatureCacheBackend::Memory => Ok(Some(Box::new(MemoryFeatureCache::new(config.capacity)))),
+        #[cfg(feature = "redis-cache")]
+        FeatureCacheBackend::Redis => Ok(Some(Box::new(
+            RedisFeatureCache::connect(&config.redis_url, config.ttl.as_secs()).await?,
+        ))),
+        #[cfg(not(feature = "redis-cache"))]
+        FeatureCacheBackend::Redis => {
+            anyhow::bail!("Redis feature cache requires building with --features redis-cache")
+        }
+    }
+}
+
+/// In-process LRU cache
+pub struct MemoryFeatureCache {
+    entries: Mutex<LruCache<String, NeuralFeatures>>,
+}
+
+impl MemoryFeatureCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl FeatureCache for MemoryFeatureCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<NeuralFeatures>>> {
+        let value = self.entries.lock().unwrap().get(&key.to_string());
+        Box::pin(async move { Ok(value) })
+    }
+
+    fn put<'a>(&'a self, key: &'a str, features: &'a NeuralFeatures) -> BoxFuture<'a, Result<()>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .put(key.to_string(), features.clone());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn local_entries(&self) -> Option<usize> {
+        Some(self.entries.lock().unwrap().len())
+    }
+
+    fn shed(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let shed = entries.len();
+        entries.clear();
+        shed
+    }
+}
+
+#[cfg(feature = "redis-cache")]
+pub use redis_backend::RedisFeatureCache;
+
+#[cfg(feature = "redis-cache")]
+mod redis_backend {
+    use anyhow::{Context, Result};
+    use futures::future::BoxFuture;
+    use prost::Message;
+    use redis::AsyncCommands;
+
+    use super::FeatureCache;
+    use crate::model_pb;
+    use crate::onnx_wrapper::NeuralFeatures;
+
+    /// Redis cache shared between replicas, values stored as protobuf
+    pub struct RedisFeatureCache {
+        connection: redis::aio::MultiplexedConnection,
+        ttl_secs: u64,
+    }
+
+    impl RedisFeatureCache {
+        pub async fn connect(url: &str, ttl_secs: u64) -> Result<Self> {
+            let client = redis::Client::open(url).context("Invalid Redis URL")?;
+            let connection = client
+                .get_multiplexed_async_connection()
+                .await
+                .context("Failed to connect to Redis")?;
+            Ok(Self {
+                connection,
+                ttl_secs,
+            })
+        }
+    }
+
+    impl FeatureCache for RedisFeatureCache {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<NeuralFeatures>>> {
+            Box::pin(async move {
+                let mut connection = self.connection.clone();
+                let bytes: Option<Vec<u8>> = connection.get(key).await?;
+                match bytes {
+                    Some(bytes) => Ok(Some(
+                        model_pb::NeuralFeatures::decode(bytes.as_slice())?.into(),
+                    )),
+                    None => Ok(None),
+                }
+            })
+        }
+
+        fn put<'a>(
+            &'a self,
+            key: &'a str,
+            features: &'a NeuralFeatures,
+        ) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                let mut connection = self.connection.clone();
+                let bytes = model_pb::NeuralFeatures::from(features).encode_to_vec();
+                let _: () = connection.set_ex(key, bytes, self.ttl_secs).await?;
+                Ok(())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_cache_roundtrip() {
+        let cache = MemoryFeatureCache::new(4);
+        let key = cache_key("abc123", "detector");
+        assert!(cache.get(&key).await.unwrap().is_none());
+
+        let features = NeuralFeatures {
+            fakeness: 0.7,
+            ..Default::default()
+        };
+        cache.put(&key, &features).await.unwrap();
+        assert_eq!(cache.get(&key).await.unwrap(), Some(features));
+        assert_eq!(cache.local_entries(), Some(1));
+    }
+
+    #[test]
+    fn test_key_includes_model_version() {
+        assert_ne!(
+            cache_key("abc", "fakeness@1"),
+            cache_key("abc", "fakeness@2")
+        );
+    }
+}
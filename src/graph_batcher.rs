@@ -0,0 +1,277 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Batching of knowledge graph lookups
+//!
+//! The consumer works on many messages at once, and each would look its
+//! source up in a round-trip of its own. Concurrent lookups are instead
+//! submitted to a single batching task, which waits briefly for the
+//! lookups of the other pending messages and fetches the distinct sources
+//! of the batch in one query. Batches are fetched concurrently, up to a
+//! limit, so a slow query does not hold up the lookups collected after it.
+//! A failed batch fails each of its lookups.
+
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{mpsc, oneshot, Semaphore},
+    time::{timeout_at, Instant},
+};
+
+use crate::knowledge_graph::{Coordination, KnowledgeGraph, SourceFacts, VerdictRecord};
+use crate::metrics::Metrics;
+
+/// Graph batching settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphBatchConfig {
+    /// Most lookups fetched in one query
+    pub max_size: usize,
+    /// Longest time the first lookup of a batch waits for others
+    pub max_wait: Duration,
+    /// Most batch queries in flight at once
+    pub max_in_flight: usize,
+}
+
+struct Request {
+    source_id: String,
+    reply: oneshot::Sender<Result<Option<SourceFacts>>>,
+}
+
+/// Looks sources of concurrent messages up in `graph` together
+pub struct GraphBatcher {
+    graph: Arc<dyn KnowledgeGraph>,
+    requests: mpsc::Sender<Request>,
+}
+
+impl GraphBatcher {
+    /// Start the batching task for `graph`
+    pub fn spawn(
+        graph: Arc<dyn KnowledgeGraph>,
+        config: &GraphBatchConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let (requests, receiver) = mpsc::channel(config.max_size.max(1) * 4);
+        tokio::spawn(run_batches(
+            Arc::clone(&graph),
+            receiver,
+            config.clone(),
+            metrics,
+        ));
+        Self { graph, requests }
+    }
+
+    async fn lookup(&self, source_id: &str) -> Result<Option<SourceFacts>> {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send(Request {
+                source_id: source_id.to_string(),
+                reply,
+            })
+            .await
+            .ok()
+            .context("Graph batcher stopped")?;
+        response.await.context("Graph batcher dropped lookup")?
+    }
+}
+
+impl KnowledgeGraph for GraphBatcher {
+    fn name(&self) -> &'static str {
+        self.graph.name()
+    }
+
+    fn source<'a>(&'a self, source_id: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+        Box::pin(self.lookup(source_id))
+    }
+
+    fn record_verdict<'a>(&'a self, record: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+        self.graph.record_verdict(record)
+    }
+
+    fn coordination<'a>(
+        &'a self,
+        content_hash: &'a str,
+        source_id: &'a str,
+        created_since: SystemTime,
+    ) -> BoxFuture<'a, Result<Coordination>> {
+        self.graph
+            .coordination(content_hash, source_id, created_since)
+    }
+}
+
+async fn run_batches(
+    graph: Arc<dyn KnowledgeGraph>,
+    mut receiver: mpsc::Receiver<Request>,
+    config: GraphBatchConfig,
+    metrics: Arc<Metrics>,
+) {
+    let permits = Arc::new(Semaphore::new(config.max_in_flight.max(1)));
+    while let Some(first) = receiver.recv().await {
+        let deadline = Instant::now() + config.max_wait;
+        let mut batch = vec![first];
+        while batch.len() < config.max_size {
+            match receiver.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => match timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(request)) => batch.push(request),
+                    _ => break,
+                },
+            }
+        }
+
+        // Messages of one source share a lookup
+        let ids: Vec<String> = batch
+            .iter()
+            .map(|request| request.source_id.clone())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        metrics.graph_batch_size.observe(ids.len() as f64);
+
+        // At the limit, further lookups queue until a query ends
+        let Ok(permit) = Arc::clone(&permits).acquire_owned().await else {
+            return;
+        };
+        let graph = Arc::clone(&graph);
+        tokio::spawn(async move {
+            let _permit = permit;
+            match graph.sources(&ids).await {
+                Ok(sources) => {
+                    for request in batch {
+                        let source = sources.get(&request.source_id).cloned();
+                        // The caller may have given up after its deadline
+                        let _ = request.reply.send(Ok(source));
+                    }
+                }
+                Err(e) => {
+                    for request in batch {
+                        let _ = request.reply.send(Err(anyhow!("{:#}", e)));
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::{collections::HashMap, sync::Mutex};
+
+    /// Records the ids of each batched lookup; `twitter:@down` fails and
+    /// `twitter:@slow` never answers
+    #[derive(Default)]
+    struct Recording(Mutex<Vec<Vec<String>>>);
+
+    impl KnowledgeGraph for Recording {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        fn source<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+            Box::pin(async move { bail!("looked up one by one") })
+        }
+
+        fn sources<'a>(
+            &'a self,
+            source_ids: &'a [String],
+        ) -> BoxFuture<'a, Result<HashMap<String, SourceFacts>>> {
+            let mut ids = source_ids.to_vec();
+            ids.sort();
+            self.0.lock().unwrap().push(ids);
+            Box::pin(async move {
+                if source_ids.iter().any(|id| id == "twitter:@down") {
+                    bail!("connection refused");
+                }
+                if source_ids.iter().any(|id| id == "twitter:@slow") {
+                    futures::future::pending::<()>().await;
+                }
+                Ok(source_ids
+                    .iter()
+                    .filter(|id| *id != "twitter:@unknown")
+                    .map(|id| {
+                        let source = SourceFacts {
+                            trusted: Some(true),
+                            ..Default::default()
+                        };
+                        (id.clone(), source)
+                    })
+                    .collect())
+            })
+        }
+
+        fn record_verdict<'a>(&'a self, _: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move { Ok(()) })
+        }
+
+        fn coordination<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a str,
+            _: SystemTime,
+        ) -> BoxFuture<'a, Result<Coordination>> {
+            Box::pin(async move { Ok(Coordination::default()) })
+        }
+    }
+
+    fn batcher(max_size: usize) -> (GraphBatcher, Arc<Recording>) {
+        let recording = Arc::new(Recording::default());
+        let batcher = GraphBatcher::spawn(
+            Arc::clone(&recording) as Arc<dyn KnowledgeGraph>,
+            &GraphBatchConfig {
+                max_size,
+                max_wait: Duration::from_secs(5),
+                max_in_flight: 4,
+            },
+            Arc::new(Metrics::new().unwrap()),
+        );
+        (batcher, recording)
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_share_a_query() {
+        let (batcher, recording) = batcher(3);
+        let (a, b, unknown) = tokio::join!(
+            batcher.source("twitter:@a"),
+            batcher.source("twitter:@a"),
+            batcher.source("twitter:@unknown"),
+        );
+        assert_eq!(a.unwrap().unwrap().trusted, Some(true));
+        assert_eq!(b.unwrap().unwrap().trusted, Some(true));
+        assert_eq!(unknown.unwrap(), None);
+        assert_eq!(
+            *recording.0.lock().unwrap(),
+            [vec![
+                "twitter:@a".to_string(),
+                "twitter:@unknown".to_string()
+            ]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_batches_fail_each_lookup() {
+        let (batcher, _) = batcher(2);
+        let (a, down) = tokio::join!(
+            batcher.source("twitter:@a"),
+            batcher.source("twitter:@down")
+        );
+        assert!(a.is_err());
+        assert!(down.unwrap_err().to_string().contains("connection refused"));
+    }
+
+    #[tokio::test]
+    async fn test_slow_batches_do_not_hold_up_others() {
+        let (batcher, _) = batcher(1);
+        tokio::select! {
+            _ = batcher.source("twitter:@slow") => panic!("slow lookup answered"),
+            fast = batcher.source("twitter:@a") => {
+                assert_eq!(fast.unwrap().unwrap().trusted, Some(true));
+            }
+        }
+    }
+}
@@ -0,0 +1,277 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Retries and a circuit breaker around the knowledge graph
+//!
+//! Every graph call gets a deadline and is retried with backoff. After
+//! `failures` calls in a row have failed, the breaker opens and calls fail
+//! at once without reaching the graph, so a graph that is down costs a
+//! message nothing instead of the deadline of every attempt. Once the open
+//! period has passed a single trial call is let through; its success closes
+//! the breaker, its failure opens it again. The pipeline decides a message
+//! whose lookup failed with `source_facts_unknown("true")`.
+
+use anyhow::{anyhow, bail, Result};
+use futures::future::BoxFuture;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{info, warn};
+
+use crate::knowledge_graph::{Coordination, KnowledgeGraph, SourceFacts, VerdictRecord};
+use crate::metrics::Metrics;
+
+/// Retry and breaker settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphBreakerConfig {
+    /// Attempts after the first failed one
+    pub retries: u32,
+    /// Delay before the first retry, doubled on each further one
+    pub backoff: Duration,
+    /// Failed calls in a row that open the breaker
+    pub failures: u32,
+    /// How long the breaker stays open before a trial call
+    pub open: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    /// Failed calls in a row
+    failures: u32,
+    /// Calls fail at once until then
+    open_until: Option<Instant>,
+}
+
+/// Calls `graph` with retries unless too many calls in a row have failed
+pub struct GraphBreaker {
+    graph: Arc<dyn KnowledgeGraph>,
+    config: GraphBreakerConfig,
+    /// Deadline of one attempt
+    timeout: Duration,
+    state: Mutex<State>,
+    metrics: Arc<Metrics>,
+}
+
+impl GraphBreaker {
+    pub fn new(
+        graph: Arc<dyn KnowledgeGraph>,
+        config: &GraphBreakerConfig,
+        timeout: Duration,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            graph,
+            config: config.clone(),
+            timeout,
+            state: Mutex::new(State::default()),
+            metrics,
+        }
+    }
+
+    /// Run `call`, retried unless it is the trial of an open breaker
+    async fn call<T, F, Fut>(&self, what: &str, call: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let trial = self.admit()?;
+        let retries = if trial { 0 } else { self.config.retries };
+        let mut delay = self.config.backoff;
+        let mut attempt = 0;
+        let result = loop {
+            let result = match tokio::time::timeout(self.timeout, call()).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("timed out after {:?}", self.timeout)),
+            };
+            match result {
+                Err(e) if attempt < retries => {
+                    warn!("{} failed (attempt {}): {:#}", what, attempt + 1, e);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        self.settle(result.is_ok(), trial);
+        result
+    }
+
+    /// Whether a call may go through, and if so whether it is a trial
+    fn admit(&self) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) if Instant::now() < until => {
+                self.metrics.graph_breaker_rejections.inc();
+                bail!("knowledge graph circuit breaker is open");
+            }
+            Some(_) => {
+                // Calls made while the trial runs are rejected; one that
+                // never settles only holds the breaker for another period
+                state.open_until = Some(Instant::now() + self.config.open);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn settle(&self, ok: bool, trial: bool) {
+        let mut state = self.state.lock().unwrap();
+        if ok {
+            if state.open_until.take().is_some() {
+                info!("Knowledge graph is back, closing the circuit breaker");
+                self.metrics.graph_breaker_open.set(0.0);
+            }
+            state.failures = 0;
+            return;
+        }
+        state.failures += 1;
+        if trial || state.failures >= self.config.failures {
+            warn!(
+                "Knowledge graph failed {} calls in a row, opening the circuit breaker for {:?}",
+                state.failures, self.config.open
+            );
+            state.open_until = Some(Instant::now() + self.config.open);
+            self.metrics.graph_breaker_open.set(1.0);
+        }
+    }
+}
+
+impl KnowledgeGraph for GraphBreaker {
+    fn name(&self) -> &'static str {
+        self.graph.name()
+    }
+
+    fn source<'a>(&'a self, source_id: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+        Box::pin(self.call("Source lookup", move || self.graph.source(source_id)))
+    }
+
+    fn sources<'a>(
+        &'a self,
+        source_ids: &'a [String],
+    ) -> BoxFuture<'a, Result<HashMap<String, SourceFacts>>> {
+        Box::pin(self.call("Batched source lookup", move || {
+            self.graph.sources(source_ids)
+        }))
+    }
+
+    fn record_verdict<'a>(&'a self, record: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+        // Writes are keyed by the content hash, so a retry cannot flag a
+        // source twice
+        Box::pin(self.call("Verdict write", move || self.graph.record_verdict(record)))
+    }
+
+    fn coordination<'a>(
+        &'a self,
+        content_hash: &'a str,
+        source_id: &'a str,
+        created_since: SystemTime,
+    ) -> BoxFuture<'a, Result<Coordination>> {
+        Box::pin(self.call("Coordination lookup", move || {
+            self.graph
+                .coordination(content_hash, source_id, created_since)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Fails its first `failing` lookups
+    struct Flaky {
+        failing: AtomicUsize,
+        calls: AtomicUsize,
+    }
+
+    impl KnowledgeGraph for Flaky {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn source<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let failed = self
+                .failing
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            Box::pin(async move {
+                if failed {
+                    bail!("connection refused");
+                }
+                Ok(None)
+            })
+        }
+
+        fn record_verdict<'a>(&'a self, _: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move { Ok(()) })
+        }
+
+        fn coordination<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a str,
+            _: SystemTime,
+        ) -> BoxFuture<'a, Result<Coordination>> {
+            Box::pin(async move { Ok(Coordination::default()) })
+        }
+    }
+
+    fn breaker(failing: usize, open: Duration) -> (GraphBreaker, Arc<Flaky>, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let flaky = Arc::new(Flaky {
+            failing: AtomicUsize::new(failing),
+            calls: AtomicUsize::new(0),
+        });
+        let breaker = GraphBreaker::new(
+            Arc::clone(&flaky) as Arc<dyn KnowledgeGraph>,
+            &GraphBreakerConfig {
+                retries: 1,
+                backoff: Duration::from_millis(1),
+                failures: 2,
+                open,
+            },
+            Duration::from_millis(500),
+            Arc::clone(&metrics),
+        );
+        (breaker, flaky, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_transient_failures_are_retried() {
+        let (breaker, flaky, metrics) = breaker(1, Duration::from_secs(60));
+        assert_eq!(breaker.source("twitter:@example").await.unwrap(), None);
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(metrics.graph_breaker_open.get(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_breaker_opens_after_failed_calls() {
+        let (breaker, flaky, metrics) = breaker(usize::MAX, Duration::from_secs(60));
+        assert!(breaker.source("twitter:@example").await.is_err());
+        assert!(breaker.source("twitter:@example").await.is_err());
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 4);
+        assert_eq!(metrics.graph_breaker_open.get(), 1.0);
+
+        // Open: the graph is not called
+        assert!(breaker.source("twitter:@example").await.is_err());
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 4);
+        assert_eq!(metrics.graph_breaker_rejections.get(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_trial_call_closes_the_breaker() {
+        let (breaker, flaky, metrics) = breaker(4, Duration::ZERO);
+        assert!(breaker.source("twitter:@example").await.is_err());
+        assert!(breaker.source("twitter:@example").await.is_err());
+        assert_eq!(metrics.graph_breaker_open.get(), 1.0);
+
+        assert_eq!(breaker.source("twitter:@example").await.unwrap(), None);
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 5);
+        assert_eq!(metrics.graph_breaker_open.get(), 0.0);
+    }
+}
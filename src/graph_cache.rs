@@ -0,0 +1,331 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Cache of knowledge graph lookups
+//!
+//! High-volume sources publish thousands of messages a minute, and each
+//! would look its source up in the graph. [`GraphCache`] keeps the facts of
+//! each source, known or not, for the TTL. For a further grace period an
+//! expired entry is still served while a background task fetches it again
+//! (stale-while-revalidate), so a busy source never waits on the graph; only
+//! a source not looked up for longer than both is fetched on the hot path.
+//! A verdict written back through the cache expires its source's entry.
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::warn;
+
+use crate::cache::LruCache;
+use crate::knowledge_graph::{Coordination, KnowledgeGraph, SourceFacts, VerdictRecord};
+use crate::maintenance::Compact;
+use crate::metrics::Metrics;
+
+/// Graph cache settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphCacheConfig {
+    /// Maximum sources held; least recently used are evicted
+    pub capacity: usize,
+    /// How long a lookup is served as is
+    pub ttl: Duration,
+    /// How long after the TTL a lookup is still served while it is
+    /// refreshed in the background
+    pub stale: Duration,
+}
+
+#[derive(Clone)]
+struct Entry {
+    fetched: Instant,
+    /// Set by a write-back, which makes the entry stale at once
+    expired: bool,
+    source: Option<SourceFacts>,
+}
+
+struct Shared {
+    graph: Arc<dyn KnowledgeGraph>,
+    entries: Mutex<LruCache<String, Entry>>,
+    /// Sources with a refresh in flight
+    refreshing: Mutex<HashSet<String>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Shared {
+    async fn fetch(&self, source_id: &str) -> Result<Option<SourceFacts>> {
+        let source = self.graph.source(source_id).await?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.put(
+            source_id.to_string(),
+            Entry {
+                fetched: Instant::now(),
+                expired: false,
+                source: source.clone(),
+            },
+        );
+        self.metrics.graph_cache_entries.set(entries.len() as f64);
+        Ok(source)
+    }
+}
+
+/// Serves lookups of `graph` from memory within the TTL and grace period
+pub struct GraphCache {
+    shared: Arc<Shared>,
+    ttl: Duration,
+    stale: Duration,
+}
+
+impl GraphCache {
+    pub fn new(
+        graph: Arc<dyn KnowledgeGraph>,
+        config: &GraphCacheConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                graph,
+                entries: Mutex::new(LruCache::new(config.capacity)),
+                refreshing: Mutex::new(HashSet::new()),
+                metrics,
+            }),
+            ttl: config.ttl,
+            stale: config.stale,
+        }
+    }
+
+    /// Drop every entry
+    ///
+    /// # Returns
+    /// Number of entries dropped
+    pub fn shed(&self) -> usize {
+        let mut entries = self.shared.entries.lock().unwrap();
+        let shed = entries.len();
+        entries.clear();
+        self.shared.metrics.graph_cache_entries.set(0.0);
+        shed
+    }
+
+    async fn lookup(&self, source_id: &str) -> Result<Option<SourceFacts>> {
+        let cached = self
+            .shared
+            .entries
+            .lock()
+            .unwrap()
+            .get(&source_id.to_string());
+        if let Some(entry) = cached {
+            let age = entry.fetched.elapsed();
+            if age < self.ttl && !entry.expired {
+                self.count("hit");
+                return Ok(entry.source);
+            }
+            if age < self.ttl + self.stale {
+                self.count("stale");
+                self.refresh(source_id);
+                return Ok(entry.source);
+            }
+        }
+        self.count("miss");
+        self.shared.fetch(source_id).await
+    }
+
+    /// Fetch the source again on a background task, unless one already is
+    fn refresh(&self, source_id: &str) {
+        if !self
+            .shared
+            .refreshing
+            .lock()
+            .unwrap()
+            .insert(source_id.to_string())
+        {
+            return;
+        }
+        let shared = Arc::clone(&self.shared);
+        let source_id = source_id.to_string();
+        tokio::spawn(async move {
+            // A failed refresh keeps serving the stale entry until the
+            // grace period ends
+            if let Err(e) = shared.fetch(&source_id).await {
+                warn!("Refresh of source {} failed: {:#}", source_id, e);
+            }
+            shared.refreshing.lock().unwrap().remove(&source_id);
+        });
+    }
+
+    fn count(&self, result: &str) {
+        self.shared
+            .metrics
+            .graph_cache_lookups
+            .with_label_values(&[result])
+            .inc();
+    }
+}
+
+/// Entries past their grace period are never served again
+impl Compact for GraphCache {
+    fn compact(&self) -> usize {
+        let served = self.ttl + self.stale;
+        let mut entries = self.shared.entries.lock().unwrap();
+        let dropped = entries.retain(|entry| entry.fetched.elapsed() < served);
+        self.shared
+            .metrics
+            .graph_cache_entries
+            .set(entries.len() as f64);
+        dropped
+    }
+}
+
+impl KnowledgeGraph for GraphCache {
+    fn name(&self) -> &'static str {
+        self.shared.graph.name()
+    }
+
+    fn source<'a>(&'a self, source_id: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+        Box::pin(self.lookup(source_id))
+    }
+
+    fn record_verdict<'a>(&'a self, record: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.shared.graph.record_verdict(record).await?;
+            let mut entries = self.shared.entries.lock().unwrap();
+            if let Some(entry) = entries.get(&record.source_id) {
+                entries.put(
+                    record.source_id.clone(),
+                    Entry {
+                        expired: true,
+                        ..entry
+                    },
+                );
+            }
+            Ok(())
+        })
+    }
+
+    fn coordination<'a>(
+        &'a self,
+        content_hash: &'a str,
+        source_id: &'a str,
+        created_since: SystemTime,
+    ) -> BoxFuture<'a, Result<Coordination>> {
+        // Each message is new content, so there is nothing to reuse
+        self.shared
+            .graph
+            .coordination(content_hash, source_id, created_since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::souffle_wrapper::Verdict;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts lookups of a graph that knows every source as trusted
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    impl KnowledgeGraph for Counting {
+        fn name(&self) -> &'static str {
+            "counting"
+        }
+
+        fn source<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                Ok(Some(SourceFacts {
+                    trusted: Some(true),
+                    ..Default::default()
+                }))
+            })
+        }
+
+        fn record_verdict<'a>(&'a self, _: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move { Ok(()) })
+        }
+
+        fn coordination<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a str,
+            _: SystemTime,
+        ) -> BoxFuture<'a, Result<Coordination>> {
+            Box::pin(async move { Ok(Coordination::default()) })
+        }
+    }
+
+    fn cache(ttl: Duration, stale: Duration) -> (GraphCache, Arc<Counting>, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let counting = Arc::new(Counting::default());
+        let cache = GraphCache::new(
+            Arc::clone(&counting) as Arc<dyn KnowledgeGraph>,
+            &GraphCacheConfig {
+                capacity: 8,
+                ttl,
+                stale,
+            },
+            Arc::clone(&metrics),
+        );
+        (cache, counting, metrics)
+    }
+
+    fn lookups(metrics: &Metrics, result: &str) -> f64 {
+        metrics
+            .graph_cache_lookups
+            .with_label_values(&[result])
+            .get()
+    }
+
+    #[tokio::test]
+    async fn test_fresh_lookups_skip_the_graph() {
+        let (cache, counting, metrics) = cache(Duration::from_secs(60), Duration::ZERO);
+        let first = cache.source("twitter:@example").await.unwrap();
+        let second = cache.source("twitter:@example").await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(counting.0.load(Ordering::SeqCst), 1);
+        assert_eq!(lookups(&metrics, "hit"), 1.0);
+        assert_eq!(lookups(&metrics, "miss"), 1.0);
+        assert_eq!(cache.shed(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_lookups_are_served_and_refreshed() {
+        let (stale_cache, counting, metrics) = cache(Duration::ZERO, Duration::from_secs(60));
+        stale_cache.source("twitter:@example").await.unwrap();
+        let stale = stale_cache.source("twitter:@example").await.unwrap();
+        assert_eq!(stale.unwrap().trusted, Some(true));
+        assert_eq!(lookups(&metrics, "stale"), 1.0);
+        // The refresh runs in the background
+        for _ in 0..100 {
+            if counting.0.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(counting.0.load(Ordering::SeqCst), 2);
+
+        // Past the grace period the graph is on the hot path again
+        let (cache, counting, metrics) = cache(Duration::ZERO, Duration::ZERO);
+        cache.source("twitter:@example").await.unwrap();
+        cache.source("twitter:@example").await.unwrap();
+        assert_eq!(counting.0.load(Ordering::SeqCst), 2);
+        assert_eq!(lookups(&metrics, "miss"), 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_write_backs_expire_the_source() {
+        let (cache, _, metrics) = cache(Duration::from_secs(60), Duration::from_secs(60));
+        cache.source("twitter:@example").await.unwrap();
+        let record = VerdictRecord {
+            content_hash: "ab12".to_string(),
+            source_id: "twitter:@example".to_string(),
+            verdict: Verdict::Disinfo,
+            confidence: 0.9,
+            fired: Vec::new(),
+            at: SystemTime::now(),
+        };
+        cache.record_verdict(&record).await.unwrap();
+        cache.source("twitter:@example").await.unwrap();
+        assert_eq!(lookups(&metrics, "stale"), 1.0);
+    }
+}
@@ -0,0 +1,267 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Knowledge graph facts served from a local snapshot file
+//!
+//! Air-gapped deployments cannot reach a graph database, and replaying
+//! historical traffic needs the facts as they were, not as the live graph
+//! has them today. A snapshot is a JSON lines export of the graph, one
+//! source per line with its id, its facts as in the `memory` graph and the
+//! hashes of the content it posted:
+//!
+//! ```json
+//! {"id": "twitter:@example", "source.trusted": false, "source.flags": [], "posted": ["ab12"]}
+//! ```
+//!
+//! The file is polled for changes and a changed one is loaded without a
+//! restart, so a periodic export keeps the facts current; one that fails
+//! to load keeps the current snapshot. Without polling, the facts stay
+//! those loaded at startup, which makes a replay deterministic. Nothing is
+//! ever written: verdicts written back are dropped.
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
+
+use crate::knowledge_graph::{
+    Coordination, KnowledgeGraph, MemoryGraph, SourceFacts, VerdictRecord,
+};
+use crate::metrics::Metrics;
+
+/// One line of a snapshot
+#[derive(Deserialize)]
+struct SnapshotLine {
+    id: String,
+    #[serde(flatten)]
+    facts: SourceFacts,
+    /// Hashes of the content the source posted
+    #[serde(default)]
+    posted: Vec<String>,
+}
+
+/// The graph as of one snapshot file
+struct Snapshot {
+    graph: MemoryGraph,
+    sources: usize,
+    /// SHA-256 of the file, to tell a change
+    sha256: String,
+}
+
+impl Snapshot {
+    fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read snapshot {}", path.display()))?;
+        let mut sources = HashMap::new();
+        let mut posts: HashMap<String, HashSet<String>> = HashMap::new();
+        for (n, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line: SnapshotLine = serde_json::from_str(line).with_context(|| {
+                format!("Invalid snapshot line {} of {}", n + 1, path.display())
+            })?;
+            for hash in line.posted {
+                posts.entry(hash).or_default().insert(line.id.clone());
+            }
+            sources.insert(line.id, line.facts);
+        }
+        Ok(Self {
+            sources: sources.len(),
+            graph: MemoryGraph::with_posts(sources, posts),
+            sha256: hex::encode(Sha256::digest(contents.as_bytes())),
+        })
+    }
+}
+
+/// Sources served from a snapshot that can be replaced while the consumer
+/// runs
+pub struct SnapshotGraph {
+    path: PathBuf,
+    /// How often the file is checked for changes; never when `None`
+    reload: Option<Duration>,
+    active: RwLock<Arc<Snapshot>>,
+}
+
+impl SnapshotGraph {
+    pub fn load(path: &Path, reload: Option<Duration>) -> Result<Self> {
+        let snapshot = Snapshot::load(path)?;
+        info!(
+            "Loaded snapshot of {} sources from {}",
+            snapshot.sources,
+            path.display()
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            reload,
+            active: RwLock::new(Arc::new(snapshot)),
+        })
+    }
+
+    /// Number of sources in the current snapshot
+    pub fn sources(&self) -> usize {
+        self.current().sources
+    }
+
+    fn current(&self) -> Arc<Snapshot> {
+        Arc::clone(&self.active.read().unwrap())
+    }
+
+    /// Load the file again and swap it in if it changed
+    ///
+    /// # Returns
+    /// Whether it changed
+    pub fn reload(&self) -> Result<bool> {
+        let loaded = Snapshot::load(&self.path)?;
+        let mut active = self.active.write().unwrap();
+        if loaded.sha256 == active.sha256 {
+            return Ok(false);
+        }
+        *active = Arc::new(loaded);
+        Ok(true)
+    }
+}
+
+impl KnowledgeGraph for SnapshotGraph {
+    fn name(&self) -> &'static str {
+        "snapshot"
+    }
+
+    fn source<'a>(&'a self, source_id: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+        Box::pin(async move { self.current().graph.source(source_id).await })
+    }
+
+    fn record_verdict<'a>(&'a self, _: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+        // A snapshot is read-only; the next export carries the verdicts
+        // the live graph recorded
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn coordination<'a>(
+        &'a self,
+        content_hash: &'a str,
+        source_id: &'a str,
+        created_since: SystemTime,
+    ) -> BoxFuture<'a, Result<Coordination>> {
+        Box::pin(async move {
+            self.current()
+                .graph
+                .coordination(content_hash, source_id, created_since)
+                .await
+        })
+    }
+}
+
+/// Reload the snapshot whenever its file changes
+pub async fn run_reloader(graph: Arc<SnapshotGraph>, metrics: Arc<Metrics>) {
+    let Some(reload) = graph.reload else {
+        return;
+    };
+    let mut ticker = tokio::time::interval(reload);
+    // The first tick completes immediately; the snapshot was just loaded
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let reloading = Arc::clone(&graph);
+        let outcome = match tokio::task::spawn_blocking(move || reloading.reload()).await {
+            Ok(Ok(true)) => {
+                info!("Graph snapshot reloaded, {} sources", graph.sources());
+                "reloaded"
+            }
+            Ok(Ok(false)) => continue,
+            Ok(Err(e)) => {
+                error!(
+                    "Graph snapshot reload failed, keeping the current one: {:#}",
+                    e
+                );
+                "failed"
+            }
+            Err(e) => {
+                error!("Graph snapshot reload panicked: {}", e);
+                "failed"
+            }
+        };
+        metrics
+            .graph_snapshot_reloads
+            .with_label_values(&[outcome])
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::knowledge_graph::source_facts;
+    use crate::test_support::TempDir;
+    use std::time::UNIX_EPOCH;
+
+    fn snapshot_file(contents: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new("snapshot");
+        let path = dir.join("snapshot.jsonl");
+        fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_serves_sources_and_posts() {
+        let (_dir, path) = snapshot_file(
+            "{\"id\": \"twitter:@example\", \"source.trusted\": false, \"posted\": [\"ab12\"]}\n\
+             \n\
+             {\"id\": \"twitter:@a\", \"created_at\": 1700000000, \"posted\": [\"ab12\"]}\n",
+        );
+        let graph = SnapshotGraph::load(&path, None).unwrap();
+        assert_eq!(graph.sources(), 2);
+
+        let (facts, _) = source_facts(&graph, "twitter:@example", SystemTime::now())
+            .await
+            .unwrap();
+        assert_eq!(facts["source_trusted"], "false");
+        let coordination = graph
+            .coordination("ab12", "twitter:@example", UNIX_EPOCH)
+            .await
+            .unwrap();
+        assert_eq!(coordination.accounts, 1);
+        assert_eq!(coordination.new_accounts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_changed_snapshots_are_reloaded() {
+        let (_dir, path) = snapshot_file("{\"id\": \"twitter:@example\"}\n");
+        let graph = SnapshotGraph::load(&path, Some(Duration::from_secs(60))).unwrap();
+        assert!(!graph.reload().unwrap());
+
+        fs::write(
+            &path,
+            "{\"id\": \"twitter:@example\"}\n{\"id\": \"twitter:@a\"}\n",
+        )
+        .unwrap();
+        assert!(graph.reload().unwrap());
+        assert_eq!(graph.sources(), 2);
+
+        // A broken export keeps the current snapshot
+        fs::write(&path, "{\"source.trusted\": true}\n").unwrap();
+        assert!(graph.reload().is_err());
+        assert_eq!(graph.sources(), 2);
+
+        // Written verdicts are dropped
+        let record = VerdictRecord {
+            content_hash: "ab12".to_string(),
+            source_id: "twitter:@a".to_string(),
+            verdict: crate::souffle_wrapper::Verdict::Disinfo,
+            confidence: 0.9,
+            fired: Vec::new(),
+            at: SystemTime::now(),
+        };
+        graph.record_verdict(&record).await.unwrap();
+        let source = graph.source("twitter:@a").await.unwrap().unwrap();
+        assert!(source.flags.is_empty());
+    }
+}
@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Historical aggregates for the rules
+//!
+//! Rules such as "a source with repeated disinformation verdicts this week"
+//! need history, but the symbolic engine must not query storage while it
+//! evaluates a message. Verdicts are therefore recorded into per-day
+//! buckets, and a background aggregator periodically turns the last 7 and
+//! 30 days into counts that are handed to the rules as base facts:
+//!
+//! * `source_<verdict>_<window>(count)`: verdicts issued for the source,
+//!   e.g. `source_disinfo_7d(3)`
+//! * `content_spread_<window>(count)`: distinct sources that published the
+//!   same content
+//!
+//! Counts lag by at most one aggregation interval. The buckets are
+//! persisted so history survives restarts.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use tracing::{error, info};
+
+use crate::clock::today;
+use crate::metrics::Metrics;
+use crate::souffle_wrapper::DgraphFacts;
+
+/// Aggregation windows, in days
+const WINDOWS: [(&str, u64); 2] = [("7d", 7), ("30d", 30)];
+
+/// Verdicts counted per source
+const VERDICTS: [&str; 3] = ["DISINFO", "SUSPICIOUS", "SAFE"];
+
+/// History settings; disabled unless a history file is set
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    /// File the daily buckets are persisted to
+    pub path: PathBuf,
+    /// How often aggregates are recomputed and the buckets persisted
+    pub interval: Duration,
+}
+
+/// Verdicts of one UTC day
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DayRecord {
+    /// Verdict counts by source id, then verdict
+    verdicts: HashMap<String, HashMap<String, u64>>,
+    /// Sources that published each content hash
+    spread: HashMap<String, HashSet<String>>,
+}
+
+/// Facts precomputed by the last aggregation
+#[derive(Debug, Default)]
+struct Aggregates {
+    by_source: HashMap<String, DgraphFacts>,
+    by_content: HashMap<String, DgraphFacts>,
+}
+
+/// Records verdicts and serves their aggregates as facts
+pub struct VerdictHistory {
+    path: PathBuf,
+    days: Mutex<BTreeMap<u64, DayRecord>>,
+    aggregates: RwLock<Arc<Aggregates>>,
+}
+
+impl VerdictHistory {
+    /// Load persisted buckets, if any, and compute the first aggregates
+    pub fn open(path: &Path) -> Result<Self> {
+        let days = if path.exists() {
+            let contents = fs::read(path)
+                .with_context(|| format!("Failed to read history {}", path.display()))?;
+            serde_json::from_slice(&contents)
+                .with_context(|| format!("Failed to parse history {}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+        let history = Self {
+            path: path.to_path_buf(),
+            days: Mutex::new(days),
+            aggregates: RwLock::new(Arc::default()),
+        };
+        history.aggregate();
+        Ok(history)
+    }
+
+    /// Count a published verdict
+    pub fn record(&self, source_id: &str, content_hash: &str, verdict: &str) {
+        self.record_on(today(), source_id, content_hash, verdict);
+    }
+
+    fn record_on(&self, day: u64, source_id: &str, content_hash: &str, verdict: &str) {
+        let mut days = self.days.lock().unwrap();
+        let record = days.entry(day).or_default();
+        *record
+            .verdicts
+            .entry(source_id.to_string())
+            .or_default()
+            .entry(verdict.to_string())
+            .or_default() += 1;
+        record
+            .spread
+            .entry(content_hash.to_string())
+            .or_default()
+            .insert(source_id.to_string());
+    }
+
+    /// History facts for a message; every relation is present, zero when
+    /// there is no history
+    pub fn facts(&self, source_id: &str, content_hash: &str) -> DgraphFacts {
+        let aggregates = Arc::clone(&self.aggregates.read().unwrap());
+        let mut facts = empty_facts();
+        if let Some(source) = aggregates.by_source.get(source_id) {
+            facts.extend(source.clone());
+        }
+        if let Some(content) = aggregates.by_content.get(content_hash) {
+            facts.extend(content.clone());
+        }
+        facts
+    }
+
+    /// Drop buckets past the longest window and recompute the aggregates
+    pub fn aggregate(&self) {
+        self.aggregate_on(today());
+    }
+
+    fn aggregate_on(&self, today: u64) {
+        let mut aggregates = Aggregates::default();
+        {
+            let mut days = self.days.lock().unwrap();
+            let longest = WINDOWS.iter().map(|(_, days)| *days).max().unwrap_or(0);
+            let oldest = (today + 1).saturating_sub(longest);
+            days.retain(|day, _| *day >= oldest);
+
+            for (window, length) in WINDOWS {
+                let first = (today + 1).saturating_sub(length);
+                let mut verdicts: HashMap<&str, HashMap<&str, u64>> = HashMap::new();
+                let mut spread: HashMap<&str, HashSet<&str>> = HashMap::new();
+                for record in days.range(first..).map(|(_, record)| record) {
+                    for (source, counts) in &record.verdicts {
+                        let totals = verdicts.entry(source.as_str()).or_default();
+                        for (verdict, count) in counts {
+                            *totals.entry(verdict.as_str()).or_default() += count;
+                        }
+                    }
+                    for (content, sources) in &record.spread {
+                        spread
+                            .entry(content.as_str())
+                            .or_default()
+                            .extend(sources.iter().map(String::as_str));
+                    }
+                }
+
+                for (source, totals) in verdicts {
+                    let facts = aggregates.by_source.entry(source.to_string()).or_default();
+                    for (verdict, count) in totals {
+                        if VERDICTS.contains(&verdict) {
+                            facts.insert(source_relation(verdict, window), count.to_string());
+                        }
+                    }
+                }
+                for (content, sources) in spread {
+                    aggregates
+                        .by_content
+                        .entry(content.to_string())
+                        .or_default()
+                        .insert(
+                            format!("content_spread_{}", window),
+                            sources.len().to_string(),
+                        );
+                }
+            }
+        }
+        *self.aggregates.write().unwrap() = Arc::new(aggregates);
+    }
+
+    /// Write the buckets to the history file
+    pub fn persist(&self) -> Result<()> {
+        let contents = serde_json::to_vec(&*self.days.lock().unwrap())?;
+        // Write-then-rename so a crash never leaves a truncated file
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, contents)
+            .with_context(|| format!("Failed to write history {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn source_relation(verdict: &str, window: &str) -> String {
+    format!("source_{}_{}", verdict.to_lowercase(), window)
+}
+
+fn empty_facts() -> DgraphFacts {
+    let mut facts = DgraphFacts::new();
+    for (window, _) in WINDOWS {
+        for verdict in VERDICTS {
+            facts.insert(source_relation(verdict, window), "0".to_string());
+        }
+        facts.insert(format!("content_spread_{}", window), "0".to_string());
+    }
+    facts
+}
+
+/// Recompute aggregates and persist the buckets on every interval
+pub async fn run_aggregator(
+    history: Arc<VerdictHistory>,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately; `open` already aggregated
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let history = Arc::clone(&history);
+        let persisted = tokio::task::spawn_blocking(move || {
+            history.aggregate();
+            history.persist()
+        })
+        .await;
+        match persisted {
+            Ok(Ok(())) => info!("Verdict history aggregated"),
+            Ok(Err(e)) => {
+                error!("Persisting verdict history failed: {:#}", e);
+                metrics.errors.inc();
+            }
+            Err(e) => {
+                error!("Verdict history aggregation panicked: {}", e);
+                metrics.errors.inc();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_windows_and_spread() {
+        let dir = TempDir::new("history");
+        let history = VerdictHistory::open(&dir.join("history.json")).unwrap();
+        history.record_on(100, "blog:1", "aa", "DISINFO");
+        history.record_on(95, "blog:1", "bb", "DISINFO");
+        history.record_on(80, "blog:1", "cc", "DISINFO");
+        history.record_on(100, "blog:2", "aa", "SAFE");
+
+        // Nothing is visible before the next aggregation
+        assert_eq!(history.facts("blog:1", "aa")["source_disinfo_7d"], "0");
+
+        history.aggregate_on(100);
+        let facts = history.facts("blog:1", "aa");
+        assert_eq!(facts["source_disinfo_7d"], "2");
+        assert_eq!(facts["source_disinfo_30d"], "3");
+        assert_eq!(facts["source_safe_7d"], "0");
+        assert_eq!(facts["content_spread_7d"], "2");
+        assert_eq!(history.facts("blog:3", "zz")["content_spread_30d"], "0");
+
+        // Buckets past the longest window are dropped
+        history.aggregate_on(125);
+        assert_eq!(history.facts("blog:1", "cc")["source_disinfo_30d"], "1");
+    }
+
+    #[test]
+    fn test_persisted_history_is_reloaded() {
+        let dir = TempDir::new("history");
+        let path = dir.join("history.json");
+        let history = VerdictHistory::open(&path).unwrap();
+        history.record("news:1", "aa", "SUSPICIOUS");
+        history.persist().unwrap();
+
+        let reloaded = VerdictHistory::open(&path).unwrap();
+        assert_eq!(reloaded.facts("news:1", "aa")["source_suspicious_7d"], "1");
+    }
+}
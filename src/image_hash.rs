@@ -0,0 +1,257 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Perceptual hashing of images against known fakes
+//!
+//! Manipulated images are reposted long after they were debunked, usually
+//! recompressed, resized or lightly cropped, so a byte hash never matches
+//! again. Perceptual hashes do: pHash keeps the signs of the lowest DCT
+//! frequencies, dHash the direction of horizontal gradients, and near
+//! copies land within a few bits of the original. Downloaded images are
+//! hashed and looked up in a database of known manipulated images; the
+//! outcome reaches the rules as `known_fake_image("true" | "false")`.
+
+use anyhow::{bail, Context, Result};
+use image::{imageops::FilterType, DynamicImage};
+use std::{fs, path::PathBuf, str::FromStr};
+
+/// Side length images are reduced to before the DCT
+const PHASH_SIZE: usize = 32;
+
+/// Side length of the low-frequency block kept from the DCT
+const PHASH_BLOCK: usize = 8;
+
+/// Known-fake image lookup settings
+#[derive(Debug, Clone)]
+pub struct ImageHashConfig {
+    /// Database of known manipulated images, one `<kind> <hex> [label]`
+    /// per line
+    pub path: PathBuf,
+    /// Largest Hamming distance still counted as the same image
+    pub max_distance: u32,
+}
+
+/// Perceptual hash algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashKind {
+    /// DCT-based hash, robust to rescaling and recompression
+    Phash,
+    /// Gradient-based hash, cheap and robust to brightness changes
+    Dhash,
+}
+
+impl FromStr for HashKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "phash" => Ok(Self::Phash),
+            "dhash" => Ok(Self::Dhash),
+            other => bail!("unknown image hash: {}", other),
+        }
+    }
+}
+
+/// A known manipulated image close to the analyzed one
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownMatch {
+    pub kind: HashKind,
+    /// Label from the database, e.g. the fact-check it was debunked in
+    pub label: String,
+    pub distance: u32,
+}
+
+struct KnownImage {
+    kind: HashKind,
+    hash: u64,
+    label: String,
+}
+
+/// Database of perceptual hashes of known manipulated images
+pub struct KnownFakeImages {
+    images: Vec<KnownImage>,
+    max_distance: u32,
+}
+
+impl KnownFakeImages {
+    /// Load the database a config points at
+    pub fn load(config: &ImageHashConfig) -> Result<Self> {
+        let contents = fs::read_to_string(&config.path).with_context(|| {
+            format!("Failed to read known fake images {}", config.path.display())
+        })?;
+        Self::parse(&contents, config.max_distance)
+            .with_context(|| format!("Invalid known fake images {}", config.path.display()))
+    }
+
+    /// Parse `<kind> <hex> [label]` lines; blank lines and `#` comments are
+    /// skipped
+    fn parse(contents: &str, max_distance: u32) -> Result<Self> {
+        let images = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let mut parts = line.splitn(3, char::is_whitespace);
+                let kind = parts.next().unwrap_or_default().parse()?;
+                let hex = parts
+                    .next()
+                    .with_context(|| format!("missing hash (expected kind hex): {}", line))?;
+                let hash = u64::from_str_radix(hex, 16)
+                    .with_context(|| format!("invalid 64-bit hex hash: {}", hex))?;
+                Ok(KnownImage {
+                    kind,
+                    hash,
+                    label: parts.next().unwrap_or_default().trim().to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            images,
+            max_distance,
+        })
+    }
+
+    /// Number of known images
+    pub fn count(&self) -> usize {
+        self.images.len()
+    }
+
+    /// Hash an encoded image and return the closest known fake, if any is
+    /// within the distance limit
+    pub fn lookup(&self, bytes: &[u8]) -> Result<Option<KnownMatch>> {
+        let (phash, dhash) = hash_image(bytes)?;
+        Ok(self.closest(phash, dhash))
+    }
+
+    fn closest(&self, phash: u64, dhash: u64) -> Option<KnownMatch> {
+        self.images
+            .iter()
+            .map(|known| {
+                let hash = match known.kind {
+                    HashKind::Phash => phash,
+                    HashKind::Dhash => dhash,
+                };
+                (known, (known.hash ^ hash).count_ones())
+            })
+            .filter(|(_, distance)| *distance <= self.max_distance)
+            .min_by_key(|(_, distance)| *distance)
+            .map(|(known, distance)| KnownMatch {
+                kind: known.kind,
+                label: known.label.clone(),
+                distance,
+            })
+    }
+}
+
+/// pHash and dHash of an encoded image
+pub fn hash_image(bytes: &[u8]) -> Result<(u64, u64)> {
+    let img = image::load_from_memory(bytes).context("Failed to decode image")?;
+    Ok((phash(&img), dhash(&img)))
+}
+
+/// 64-bit dHash: whether each pixel of a 9x8 grayscale thumbnail is
+/// brighter than its right neighbour
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let gray = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let bit = gray.get_pixel(x, y).0[0] > gray.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | u64::from(bit);
+        }
+    }
+    hash
+}
+
+/// 64-bit pHash: whether each of the 8x8 lowest DCT frequencies of a 32x32
+/// grayscale thumbnail is above their median
+pub fn phash(img: &DynamicImage) -> u64 {
+    let gray = img
+        .resize_exact(PHASH_SIZE as u32, PHASH_SIZE as u32, FilterType::Triangle)
+        .to_luma8();
+    let pixels: Vec<f64> = gray.pixels().map(|p| f64::from(p.0[0])).collect();
+
+    // Separable 2D DCT-II, only the low-frequency block is needed
+    let n = PHASH_SIZE as f64;
+    let cosines: Vec<Vec<f64>> = (0..PHASH_BLOCK)
+        .map(|u| {
+            (0..PHASH_SIZE)
+                .map(|x| ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2.0 * n)).cos())
+                .collect()
+        })
+        .collect();
+    let rows: Vec<Vec<f64>> = pixels
+        .chunks(PHASH_SIZE)
+        .map(|row| {
+            cosines
+                .iter()
+                .map(|cos| row.iter().zip(cos).map(|(p, c)| p * c).sum())
+                .collect()
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(PHASH_BLOCK * PHASH_BLOCK);
+    for cos in &cosines {
+        for u in 0..PHASH_BLOCK {
+            coefficients.push(rows.iter().zip(cos).map(|(row, c)| row[u] * c).sum::<f64>());
+        }
+    }
+
+    // The DC term only reflects overall brightness
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    coefficients
+        .iter()
+        .fold(0u64, |hash, c| (hash << 1) | u64::from(*c > median))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Overlapping diagonal waves, spread over many DCT frequencies
+    fn pattern(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(image::GrayImage::from_fn(width, height, |x, y| {
+            let (x, y) = (x as f64 / width as f64, y as f64 / height as f64);
+            let wave = |fx: f64, fy: f64| (std::f64::consts::TAU * (fx * x + fy * y)).cos();
+            let v = 128.0 + 40.0 * wave(1.3, 0.7) + 40.0 * wave(0.4, -2.1) + 30.0 * wave(2.6, 1.9);
+            image::Luma([v as u8])
+        }))
+    }
+
+    #[test]
+    fn test_hashes_survive_rescaling() {
+        let original = pattern(256, 192);
+        let rescaled = original.resize_exact(173, 130, FilterType::Lanczos3);
+        assert!((phash(&original) ^ phash(&rescaled)).count_ones() <= 4);
+        assert!((dhash(&original) ^ dhash(&rescaled)).count_ones() <= 4);
+
+        let other = original.rotate90();
+        assert!((phash(&original) ^ phash(&other)).count_ones() > 10);
+    }
+
+    #[test]
+    fn test_lookup_known_fake() {
+        let original = pattern(256, 192);
+        let db = format!(
+            "# kind hash label\nphash {:016x} flood photo, debunked 2023-04\ndhash 0000000000000000\n",
+            phash(&original)
+        );
+        let known = KnownFakeImages::parse(&db, 6).unwrap();
+        assert_eq!(known.count(), 2);
+
+        let mut bytes = Cursor::new(Vec::new());
+        original
+            .resize_exact(200, 150, FilterType::Triangle)
+            .write_to(&mut bytes, image::ImageFormat::Png)
+            .unwrap();
+        let found = known.lookup(bytes.get_ref()).unwrap().unwrap();
+        assert_eq!(found.kind, HashKind::Phash);
+        assert_eq!(found.label, "flood photo, debunked 2023-04");
+
+        assert_eq!(known.closest(!phash(&original), u64::MAX), None);
+        assert!(KnownFakeImages::parse("ahash ffff\n", 6).is_err());
+        assert!(KnownFakeImages::parse("phash not-hex\n", 6).is_err());
+    }
+}
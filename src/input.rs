@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Validated construction of [`AnalysisInput`] messages
+//!
+//! Tools and tests build inputs through [`AnalysisInputBuilder`] so a
+//! missing hash, a malformed image URL or an unnormalized source id is
+//! rejected before the message is published rather than deep inside the
+//! pipeline.
+
+use anyhow::{bail, Context, Result};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+use crate::model_pb::{AnalysisInput, ObjectRef};
+use crate::validation;
+
+impl AnalysisInput {
+    pub fn builder() -> AnalysisInputBuilder {
+        AnalysisInputBuilder::default()
+    }
+}
+
+/// Builder for [`AnalysisInput`]; all checks run in [`build`](Self::build)
+#[derive(Debug, Clone, Default)]
+pub struct AnalysisInputBuilder {
+    content_hash: Option<String>,
+    content_text: String,
+    source_id: String,
+    image_url: Option<String>,
+    content_object: Option<ObjectRef>,
+}
+
+impl AnalysisInputBuilder {
+    pub fn content_hash(mut self, content_hash: impl Into<String>) -> Self {
+        self.content_hash = Some(content_hash.into());
+        self
+    }
+
+    pub fn content_text(mut self, content_text: impl Into<String>) -> Self {
+        self.content_text = content_text.into();
+        self
+    }
+
+    /// Source the content came from; normalized on build
+    pub fn source_id(mut self, source_id: impl Into<String>) -> Self {
+        self.source_id = source_id.into();
+        self
+    }
+
+    /// Image to analyze; must be an absolute HTTPS URL
+    pub fn image_url(mut self, image_url: impl Into<String>) -> Self {
+        self.image_url = Some(image_url.into());
+        self
+    }
+
+    /// Object holding text too large to send inline
+    pub fn content_object(mut self, bucket: impl Into<String>, name: impl Into<String>) -> Self {
+        self.content_object = Some(ObjectRef {
+            bucket: bucket.into(),
+            name: name.into(),
+        });
+        self
+    }
+
+    /// Validate the fields and build the message
+    pub fn build(self) -> Result<AnalysisInput> {
+        let content_hash = self.content_hash.unwrap_or_default().trim().to_string();
+        if content_hash.is_empty() {
+            bail!("content_hash must not be empty");
+        }
+        if !validation::is_valid_hash(&content_hash) {
+            bail!("content_hash must be a hex digest: {}", content_hash);
+        }
+
+        let image_url = match self.image_url {
+            Some(url) => {
+                let parsed = Url::parse(url.trim())
+                    .with_context(|| format!("Invalid image URL: {}", url))?;
+                if parsed.scheme() != "https" || parsed.host_str().is_none() {
+                    bail!("image URL must be an absolute https:// URL: {}", url);
+                }
+                parsed.to_string()
+            }
+            None => String::new(),
+        };
+
+        if let Some(object) = &self.content_object {
+            if object.bucket.trim().is_empty() || object.name.trim().is_empty() {
+                bail!("content object needs a bucket and a name");
+            }
+        }
+
+        Ok(AnalysisInput {
+            content_hash,
+            content_text: self.content_text,
+            source_id: normalize_source_id(&self.source_id)?,
+            image_url,
+            content_object: self.content_object,
+        })
+    }
+}
+
+/// Hex SHA-256 of the content text, the hash producers are expected to use
+pub fn content_hash_of(text: &str) -> String {
+    hex::encode(Sha256::digest(text.as_bytes()))
+}
+
+/// Lowercase, trimmed source id with inner whitespace collapsed to `-`
+///
+/// Only `a-z`, `0-9` and `-_.:@/` are allowed after normalization. An
+/// empty id means the source is unknown.
+fn normalize_source_id(source_id: &str) -> Result<String> {
+    let normalized = source_id
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_lowercase();
+    if let Some(c) = normalized
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !"-_.:@/".contains(*c))
+    {
+        bail!(
+            "source_id {:?} contains invalid character {:?}",
+            source_id,
+            c
+        );
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_normalizes_fields() {
+        let input = AnalysisInput::builder()
+            .content_hash(" abc123 ")
+            .content_text("Test content")
+            .source_id("  Twitter:@Example  Feed ")
+            .image_url("https://example.com/img.png")
+            .build()
+            .unwrap();
+        assert_eq!(input.content_hash, "abc123");
+        assert_eq!(input.source_id, "twitter:@example-feed");
+        assert_eq!(input.image_url, "https://example.com/img.png");
+    }
+
+    #[test]
+    fn test_build_rejects_invalid_inputs() {
+        let valid = || AnalysisInput::builder().content_hash("abc123");
+        assert!(valid().build().is_ok());
+        assert!(AnalysisInput::builder().build().is_err());
+        assert!(valid().content_hash("  ").build().is_err());
+        assert!(valid().content_hash("not-hex").build().is_err());
+        assert!(valid().image_url("not a url").build().is_err());
+        assert!(valid()
+            .image_url("http://example.com/a.png")
+            .build()
+            .is_err());
+        assert!(valid().image_url("/images/a.png").build().is_err());
+        assert!(valid().source_id("feed;drop").build().is_err());
+        assert!(valid().content_object("articles", "abc123").build().is_ok());
+        assert!(valid().content_object("articles", " ").build().is_err());
+    }
+
+    #[test]
+    fn test_content_hash_of_text() {
+        assert_eq!(
+            content_hash_of("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,360 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Kafka transport, for estates without NATS
+//!
+//! With `NSAI_TRANSPORT=kafka`, inputs are consumed from a Kafka topic by
+//! the replicas as one consumer group, and results are produced to the
+//! topics named by the result subjects. Headers travel as Kafka record
+//! headers, so trace context and fact dump requests work as over NATS.
+//!
+//! A record's offset is stored for commit once it and every record before
+//! it on its partition are processed, so a restart redelivers what was in
+//! flight. A transient failure produces the record to the input topic
+//! again after the retry delay, with its delivery count in
+//! `Nsai-Delivery`, and is given up on after the last delivery.
+//!
+//! Requires building with `--features kafka`.
+
+use anyhow::Result;
+#[cfg(any(feature = "kafka", test))]
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use crate::pipeline::Pipeline;
+use crate::transport::{KafkaConfig, Transport};
+
+/// Header carrying how many times a record has been delivered
+#[cfg(feature = "kafka")]
+pub const DELIVERY_HEADER: &str = "Nsai-Delivery";
+
+/// Connect the producer results are sent with
+pub fn transport(config: &KafkaConfig) -> Result<Arc<dyn Transport>> {
+    #[cfg(feature = "kafka")]
+    {
+        Ok(Arc::new(backend::KafkaTransport::new(config)?))
+    }
+    #[cfg(not(feature = "kafka"))]
+    {
+        let _ = config;
+        anyhow::bail!("Kafka transport requires building with --features kafka")
+    }
+}
+
+/// Consume the input topic until shutdown
+pub async fn run(
+    pipeline: &Pipeline,
+    transport: Arc<dyn Transport>,
+    config: &KafkaConfig,
+) -> Result<()> {
+    #[cfg(feature = "kafka")]
+    {
+        backend::run(pipeline, transport, config).await
+    }
+    #[cfg(not(feature = "kafka"))]
+    {
+        let _ = (pipeline, transport, config);
+        anyhow::bail!("Kafka transport requires building with --features kafka")
+    }
+}
+
+/// Offsets of records being processed, by partition
+///
+/// Records finish out of order; the committed position of a partition may
+/// only pass a record once every record before it has finished.
+#[cfg(any(feature = "kafka", test))]
+#[derive(Debug, Default)]
+struct Offsets {
+    partitions: HashMap<i32, Partition>,
+}
+
+#[cfg(any(feature = "kafka", test))]
+#[derive(Debug, Default)]
+struct Partition {
+    in_flight: BTreeSet<i64>,
+    /// Highest offset started
+    highest: i64,
+}
+
+#[cfg(any(feature = "kafka", test))]
+impl Offsets {
+    fn start(&mut self, partition: i32, offset: i64) {
+        let partition = self.partitions.entry(partition).or_default();
+        partition.in_flight.insert(offset);
+        partition.highest = partition.highest.max(offset);
+    }
+
+    /// Finish `offset`, returning the position to commit when it moved
+    fn finish(&mut self, partition: i32, offset: i64) -> Option<i64> {
+        let partition = self.partitions.get_mut(&partition)?;
+        let lowest = partition.in_flight.first().copied();
+        partition.in_flight.remove(&offset);
+        if lowest != Some(offset) {
+            return None;
+        }
+        Some(
+            partition
+                .in_flight
+                .first()
+                .copied()
+                .unwrap_or(partition.highest + 1),
+        )
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod backend {
+    use anyhow::{Context, Result};
+    use async_nats::{header::HeaderName, HeaderMap};
+    use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+    use rdkafka::{
+        config::ClientConfig,
+        consumer::{CommitMode, Consumer, StreamConsumer},
+        message::{Header, Headers, Message, OwnedHeaders, OwnedMessage},
+        producer::{FutureProducer, FutureRecord},
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::signal;
+    use tracing::{info, warn, Instrument};
+
+    use super::{Offsets, DELIVERY_HEADER};
+    use crate::pipeline::Pipeline;
+    use crate::propagation::MessageContext;
+    use crate::transport::{KafkaConfig, Received, Transport};
+
+    /// Produces to Kafka topics
+    pub struct KafkaTransport {
+        producer: FutureProducer,
+        timeout: Duration,
+    }
+
+    impl KafkaTransport {
+        pub fn new(config: &KafkaConfig) -> Result<Self> {
+            let producer = client_config(config)
+                .create()
+                .context("Failed to create Kafka producer")?;
+            info!("Producing results to Kafka at {}", config.brokers);
+            Ok(Self {
+                producer,
+                timeout: config.send_timeout,
+            })
+        }
+    }
+
+    impl Transport for KafkaTransport {
+        fn name(&self) -> &'static str {
+            "kafka"
+        }
+
+        fn send<'a>(
+            &'a self,
+            destination: &'a str,
+            headers: HeaderMap,
+            payload: Vec<u8>,
+        ) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                let mut record_headers = OwnedHeaders::new();
+                for (name, values) in headers.iter() {
+                    for value in values {
+                        record_headers = record_headers.insert(Header {
+                            key: name.as_ref(),
+                            value: Some(value.as_str()),
+                        });
+                    }
+                }
+                let record: FutureRecord<'_, (), _> = FutureRecord::to(destination)
+                    .payload(&payload)
+                    .headers(record_headers);
+                self.producer
+                    .send(record, self.timeout)
+                    .await
+                    .map(|_| ())
+                    .map_err(|(e, _)| e)
+                    .with_context(|| format!("Failed to produce to {}", destination))
+            })
+        }
+    }
+
+    /// Settings shared by the producer and the consumer
+    fn client_config(config: &KafkaConfig) -> ClientConfig {
+        let mut client = ClientConfig::new();
+        client.set("bootstrap.servers", &config.brokers);
+        for property in &config.properties {
+            client.set(&property.key, &property.value);
+        }
+        client
+    }
+
+    /// A record of the input topic
+    struct Record {
+        message: OwnedMessage,
+        headers: Option<HeaderMap>,
+        id: String,
+        deliveries: u64,
+        transport: Arc<dyn Transport>,
+    }
+
+    impl Record {
+        fn new(message: OwnedMessage, transport: Arc<dyn Transport>) -> Self {
+            let headers = message.headers().map(|record_headers| {
+                let mut headers = HeaderMap::new();
+                for header in record_headers.iter() {
+                    let (Ok(name), Some(value)) = (header.key.parse::<HeaderName>(), header.value)
+                    else {
+                        continue;
+                    };
+                    headers.append(name, String::from_utf8_lossy(value).as_ref());
+                }
+                headers
+            });
+            let deliveries = headers
+                .as_ref()
+                .and_then(|headers| headers.get(DELIVERY_HEADER))
+                .and_then(|value| value.as_str().parse().ok())
+                .unwrap_or(1);
+            let id = format!(
+                "{}/{}/{}",
+                message.topic(),
+                message.partition(),
+                message.offset()
+            );
+            Self {
+                message,
+                headers,
+                id,
+                deliveries,
+                transport,
+            }
+        }
+    }
+
+    impl Received for Record {
+        fn payload(&self) -> &[u8] {
+            self.message.payload().unwrap_or_default()
+        }
+
+        fn headers(&self) -> Option<&HeaderMap> {
+            self.headers.as_ref()
+        }
+
+        fn origin(&self) -> &str {
+            self.message.topic()
+        }
+
+        fn id(&self) -> &str {
+            &self.id
+        }
+
+        fn deliveries(&self) -> u64 {
+            self.deliveries
+        }
+
+        /// Produce the record to its topic again; its worker waits out the
+        /// delay, so a struggling dependency also slows consumption
+        fn redeliver(&self, delay: Duration) -> BoxFuture<'_, Result<()>> {
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                let mut headers = self.headers.clone().unwrap_or_default();
+                headers.insert(DELIVERY_HEADER, (self.deliveries + 1).to_string().as_str());
+                self.transport
+                    .send(self.origin(), headers, self.payload().to_vec())
+                    .await
+            })
+        }
+    }
+
+    pub async fn run(
+        pipeline: &Pipeline,
+        transport: Arc<dyn Transport>,
+        config: &KafkaConfig,
+    ) -> Result<()> {
+        let consumer: StreamConsumer = client_config(config)
+            .set("group.id", &config.group)
+            .set("enable.auto.offset.store", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .context("Failed to create Kafka consumer")?;
+        consumer
+            .subscribe(&[&config.input_topic])
+            .with_context(|| format!("Failed to subscribe to {}", config.input_topic))?;
+        let workers = pipeline.config.consumer.workers(&pipeline.config.inference);
+        info!(
+            "Consuming {} as group {}, up to {} at once",
+            config.input_topic, config.group, workers
+        );
+
+        let store = |offsets: &mut Offsets, (partition, offset): (i32, i64)| {
+            if let Some(position) = offsets.finish(partition, offset) {
+                if let Err(e) = consumer.store_offset(&config.input_topic, partition, position) {
+                    warn!(
+                        "Failed to store offset {} of {}/{}: {}",
+                        position, config.input_topic, partition, e
+                    );
+                }
+            }
+        };
+
+        let mut offsets = Offsets::default();
+        let mut in_flight = FuturesUnordered::new();
+        let mut records = consumer.stream();
+        loop {
+            tokio::select! {
+                _ = signal::ctrl_c() => break,
+                Some(done) = in_flight.next(), if !in_flight.is_empty() => store(&mut offsets, done),
+                record = records.next(), if in_flight.len() < workers => match record {
+                    Some(Ok(message)) => {
+                        let message = message.detach();
+                        offsets.start(message.partition(), message.offset());
+                        in_flight.push(process(pipeline, Record::new(message, Arc::clone(&transport))));
+                    }
+                    Some(Err(e)) => {
+                        warn!("Kafka consumer error: {}", e);
+                        pipeline.metrics.errors.inc();
+                    }
+                    None => {
+                        warn!("Kafka consumer of {} ended", config.input_topic);
+                        break;
+                    }
+                },
+            }
+        }
+
+        while let Some(done) = in_flight.next().await {
+            store(&mut offsets, done);
+        }
+        drop(records);
+        // Nothing to commit when no record finished since the last commit
+        if let Err(e) = consumer.commit_consumer_state(CommitMode::Sync) {
+            warn!("Failed to commit Kafka offsets: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Process `record` under the trace context of its headers, resolving
+    /// to its partition and offset
+    async fn process(pipeline: &Pipeline, record: Record) -> (i32, i64) {
+        let span = MessageContext::from_headers(record.headers.as_ref()).span();
+        pipeline.process_received(&record).instrument(span).await;
+        (record.message.partition(), record.message.offset())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offsets_wait_for_earlier_records() {
+        let mut offsets = Offsets::default();
+        for offset in 1..=3 {
+            offsets.start(0, offset);
+        }
+        offsets.start(1, 7);
+
+        assert_eq!(offsets.finish(0, 2), None);
+        assert_eq!(offsets.finish(0, 1), Some(3));
+        assert_eq!(offsets.finish(1, 7), Some(8));
+        assert_eq!(offsets.finish(0, 3), Some(4));
+        assert_eq!(offsets.finish(2, 0), None);
+    }
+}
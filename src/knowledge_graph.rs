@@ -0,0 +1,1199 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Knowledge graph facts about the source of a message
+//!
+//! Before the rules run, the message's source is looked up in the
+//! knowledge graph with its reputation, the flags raised against it and the
+//! sources it is linked to, and handed to the rules as base facts:
+//!
+//! ```text
+//! source_trusted("true").          trusted
+//! source_reputation(72).           reputation, 0-1 as a percentage
+//! source_flagged("true").          any flags
+//! source_flags(3).                 number of flags
+//! source_flags_7d(2).              flags of the last 7 days
+//! source_flagged_age_days(12).     age of the newest flag, see crate::recency
+//! source_linked_flagged(2).        linked sources with flags
+//! ```
+//!
+//! With coordination enabled, the graph is also asked who else posted the
+//! same content and who shares infrastructure with the source, the signals
+//! of coordinated inauthentic behavior:
+//!
+//! ```text
+//! coordinated_accounts(14).        other sources that posted the content
+//! coordinated_new_accounts(9).     of those, created within the window
+//! shared_infrastructure(3).        other sources on the same infrastructure
+//! ```
+//!
+//! Who posted what comes from the content nodes the write back records, or
+//! from whatever ingests posts into the graph. These three are always
+//! present once the graph answers, zero included.
+//!
+//! A source the graph does not know has none of the source facts. With write
+//! back enabled, every verdict is recorded on its content node, and a
+//! `DISINFO` or `SUSPICIOUS` verdict flags the source, so later messages
+//! of the source see its history. Graphs are pluggable behind
+//! [`KnowledgeGraph`]: Dgraph, Neo4j, or a JSON file held in memory for
+//! deployments without a graph database and for tests.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::clock::unix_secs;
+use crate::graph_batcher::GraphBatchConfig;
+use crate::graph_breaker::GraphBreakerConfig;
+use crate::graph_cache::GraphCacheConfig;
+use crate::graph_snapshot::SnapshotGraph;
+use crate::recency::FactTimes;
+use crate::souffle_wrapper::{DgraphFacts, Verdict};
+
+const WEEK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Where source facts are looked up
+#[derive(Debug, Clone, PartialEq)]
+pub enum KnowledgeGraphBackend {
+    /// `Source` nodes queried over DQL at a Dgraph alpha's HTTP endpoint
+    Dgraph {
+        url: String,
+        /// Sent as `Dg-Auth`, e.g. a Dgraph Cloud API key
+        api_key: Option<String>,
+    },
+    /// `:Source` nodes queried over Cypher at Neo4j's HTTP endpoint
+    Neo4j {
+        url: String,
+        database: String,
+        user: String,
+        password: String,
+    },
+    /// Sources by id in a JSON file, read once at startup
+    Memory { path: PathBuf },
+    /// Sources one per line in a JSON lines export of the graph, see
+    /// [`crate::graph_snapshot`]
+    Snapshot {
+        path: PathBuf,
+        /// How often the file is checked for changes; never when `None`
+        reload: Option<Duration>,
+    },
+}
+
+/// Value of `NSAI_KNOWLEDGE_GRAPH`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnowledgeGraphKind {
+    Dgraph,
+    Neo4j,
+    Memory,
+    Snapshot,
+}
+
+impl FromStr for KnowledgeGraphKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dgraph" => Ok(Self::Dgraph),
+            "neo4j" => Ok(Self::Neo4j),
+            "memory" => Ok(Self::Memory),
+            "snapshot" => Ok(Self::Snapshot),
+            other => bail!("unknown knowledge graph: {}", other),
+        }
+    }
+}
+
+/// Source lookups; disabled unless `NSAI_KNOWLEDGE_GRAPH` is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnowledgeGraphConfig {
+    pub backend: KnowledgeGraphBackend,
+    /// Deadline of one lookup or write
+    pub timeout: Duration,
+    /// Record verdicts in the graph
+    pub write_back: bool,
+    /// Retries of failed calls and when to stop making them
+    pub breaker: GraphBreakerConfig,
+    /// Lookups cached per source; off when unset
+    pub cache: Option<GraphCacheConfig>,
+    /// Lookups of concurrent messages fetched together; off when unset
+    pub batch: Option<GraphBatchConfig>,
+    /// Age under which an account counts as new for the coordination
+    /// facts; not looked up when unset
+    pub coordination: Option<Duration>,
+}
+
+/// A flag raised against a source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Flag {
+    /// Hash of the flagged content, for flags written back; one flag per
+    /// content however often it is analyzed
+    #[serde(rename = "flag.key", alias = "key", default)]
+    pub key: String,
+    #[serde(rename = "flag.reason", alias = "reason", default)]
+    pub reason: String,
+    /// Unix seconds
+    #[serde(rename = "flag.at", alias = "at")]
+    pub at: Option<u64>,
+}
+
+/// A source linked to the message's source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkedSource {
+    #[serde(rename = "source.id", alias = "id", default)]
+    pub id: String,
+    /// Flags raised against the linked source
+    #[serde(default)]
+    pub flags: u64,
+}
+
+/// What the graph knows about one source
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceFacts {
+    #[serde(rename = "source.trusted", alias = "trusted")]
+    pub trusted: Option<bool>,
+    /// 0-1
+    #[serde(rename = "source.reputation", alias = "reputation")]
+    pub reputation: Option<f32>,
+    #[serde(rename = "source.flags", alias = "flags", default)]
+    pub flags: Vec<Flag>,
+    #[serde(rename = "source.linked", alias = "linked", default)]
+    pub linked: Vec<LinkedSource>,
+    /// Unix seconds the account was created; read by the memory graph's
+    /// coordination lookup, the databases query it themselves
+    #[serde(rename = "source.created_at", alias = "created_at", default)]
+    pub created_at: Option<u64>,
+    /// Keys of the infrastructure the source uses, e.g. `ip:203.0.113.7`
+    /// or `analytics:UA-1234`; read like `created_at`
+    #[serde(rename = "source.infra", alias = "infra", default)]
+    pub infra: Vec<String>,
+}
+
+impl SourceFacts {
+    /// Base facts for the rules as of `now` and the times of the
+    /// timestamped ones
+    pub fn facts(&self, now: SystemTime) -> (DgraphFacts, FactTimes) {
+        let mut facts = HashMap::new();
+        let mut observed = FactTimes::new();
+        if let Some(trusted) = self.trusted {
+            facts.insert("source_trusted".to_string(), trusted.to_string());
+        }
+        if let Some(reputation) = self.reputation {
+            let percent = (reputation.clamp(0.0, 1.0) * 100.0).round() as u32;
+            facts.insert("source_reputation".to_string(), percent.to_string());
+        }
+        if !self.flags.is_empty() {
+            facts.insert("source_flagged".to_string(), "true".to_string());
+            facts.insert("source_flags".to_string(), self.flags.len().to_string());
+            let since = now.checked_sub(WEEK).unwrap_or(UNIX_EPOCH);
+            let recent = self
+                .flags
+                .iter()
+                .filter_map(|flag| flag.at)
+                .filter(|at| UNIX_EPOCH + Duration::from_secs(*at) >= since)
+                .count();
+            facts.insert("source_flags_7d".to_string(), recent.to_string());
+            if let Some(at) = self.flags.iter().filter_map(|flag| flag.at).max() {
+                observed.insert(
+                    "source_flagged".to_string(),
+                    UNIX_EPOCH + Duration::from_secs(at),
+                );
+            }
+        }
+        if !self.linked.is_empty() {
+            let flagged = self.linked.iter().filter(|linked| linked.flags > 0).count();
+            facts.insert("source_linked_flagged".to_string(), flagged.to_string());
+        }
+        (facts, observed)
+    }
+}
+
+/// A verdict to record in the graph
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerdictRecord {
+    pub content_hash: String,
+    pub source_id: String,
+    pub verdict: Verdict,
+    pub confidence: f32,
+    /// Identifiers of the rules that fired
+    pub fired: Vec<String>,
+    pub at: SystemTime,
+}
+
+impl VerdictRecord {
+    /// Whether the verdict flags the source
+    pub fn flags_source(&self) -> bool {
+        matches!(self.verdict, Verdict::Disinfo | Verdict::Suspicious)
+    }
+
+    fn unix_secs(&self) -> u64 {
+        unix_secs(self.at)
+    }
+
+    /// Reason of the flag the verdict raises, e.g. `verdict:DISINFO`
+    fn reason(&self) -> String {
+        format!("verdict:{}", self.verdict)
+    }
+}
+
+/// Signals of accounts acting together around one message
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Coordination {
+    /// Other sources that posted the same content
+    #[serde(default)]
+    pub accounts: u64,
+    /// Of those, sources created since the cutoff
+    #[serde(default)]
+    pub new_accounts: u64,
+    /// Other sources sharing infrastructure with the source
+    #[serde(default)]
+    pub shared_infrastructure: u64,
+}
+
+impl Coordination {
+    /// Base facts for the rules
+    pub fn facts(&self) -> DgraphFacts {
+        HashMap::from([
+            (
+                "coordinated_accounts".to_string(),
+                self.accounts.to_string(),
+            ),
+            (
+                "coordinated_new_accounts".to_string(),
+                self.new_accounts.to_string(),
+            ),
+            (
+                "shared_infrastructure".to_string(),
+                self.shared_infrastructure.to_string(),
+            ),
+        ])
+    }
+}
+
+/// Access to the facts about sources
+pub trait KnowledgeGraph: Send + Sync {
+    /// Backend name for logs and the pipeline graph, e.g. `dgraph`
+    fn name(&self) -> &'static str;
+
+    /// The source, `None` if the graph does not know it
+    fn source<'a>(&'a self, source_id: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>>;
+
+    /// The sources the graph knows of `source_ids`, by id; graphs that can
+    /// look several up in one round-trip override this
+    fn sources<'a>(
+        &'a self,
+        source_ids: &'a [String],
+    ) -> BoxFuture<'a, Result<HashMap<String, SourceFacts>>> {
+        Box::pin(async move {
+            let mut sources = HashMap::new();
+            for source_id in source_ids {
+                if let Some(source) = self.source(source_id).await? {
+                    sources.insert(source_id.clone(), source);
+                }
+            }
+            Ok(sources)
+        })
+    }
+
+    /// Record the verdict on the content, flagging its source for
+    /// `DISINFO` and `SUSPICIOUS`
+    fn record_verdict<'a>(&'a self, record: &'a VerdictRecord) -> BoxFuture<'a, Result<()>>;
+
+    /// Who else posted the content and shares infrastructure with the
+    /// source, counting accounts created since `created_since` as new
+    fn coordination<'a>(
+        &'a self,
+        content_hash: &'a str,
+        source_id: &'a str,
+        created_since: SystemTime,
+    ) -> BoxFuture<'a, Result<Coordination>>;
+}
+
+/// Facts of the source for the rules as of `now`, none for an unknown
+/// source
+pub async fn source_facts(
+    graph: &dyn KnowledgeGraph,
+    source_id: &str,
+    now: SystemTime,
+) -> Result<(DgraphFacts, FactTimes)> {
+    Ok(graph
+        .source(source_id)
+        .await?
+        .map(|source| source.facts(now))
+        .unwrap_or_default())
+}
+
+pub fn from_config(config: &KnowledgeGraphConfig) -> Result<Box<dyn KnowledgeGraph>> {
+    let client = || {
+        reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("Failed to build the knowledge graph client")
+    };
+    Ok(match &config.backend {
+        KnowledgeGraphBackend::Dgraph { url, api_key } => Box::new(DgraphGraph {
+            client: client()?,
+            url: url.clone(),
+            api_key: api_key.clone(),
+        }),
+        KnowledgeGraphBackend::Neo4j {
+            url,
+            database,
+            user,
+            password,
+        } => Box::new(Neo4jGraph {
+            client: client()?,
+            url: url.clone(),
+            database: database.clone(),
+            user: user.clone(),
+            password: password.clone(),
+        }),
+        KnowledgeGraphBackend::Memory { path } => Box::new(MemoryGraph::load(path)?),
+        KnowledgeGraphBackend::Snapshot { path, reload } => {
+            Box::new(SnapshotGraph::load(path, *reload)?)
+        }
+    })
+}
+
+/// `Source` node with the sources it links to, parameterized by `$id`
+const DGRAPH_QUERY: &str = "query source($id: string) {
+  source(func: eq(source.id, $id), first: 1) @filter(type(Source)) {
+    source.trusted
+    source.reputation
+    source.flags { flag.key flag.reason flag.at }
+    source.linked { source.id flags: count(source.flags) }
+  }
+}";
+
+/// Other posters of the content `$hash` and the sources sharing the
+/// infrastructure of `$id`; `source.infra` needs `@reverse` in the schema
+const DGRAPH_COORDINATION_QUERY: &str = "query coordination($hash: string, $id: string) {
+  posters(func: eq(content.hash, $hash)) @filter(type(Content)) {
+    content.source @filter(NOT eq(source.id, $id)) { source.id source.created_at }
+  }
+  infra(func: eq(source.id, $id), first: 1) @filter(type(Source)) {
+    source.infra { ~source.infra @filter(NOT eq(source.id, $id)) { source.id } }
+  }
+}";
+
+/// [`DGRAPH_QUERY`] for several sources, bound as `$id0`, `$id1`, ...
+fn dgraph_batch_query(count: usize) -> String {
+    let ids: Vec<String> = (0..count).map(|n| format!("$id{}", n)).collect();
+    let params: Vec<String> = ids.iter().map(|id| format!("{}: string", id)).collect();
+    format!(
+        "query sources({}) {{
+  sources(func: eq(source.id, [{}])) @filter(type(Source)) {{
+    source.id
+    source.trusted
+    source.reputation
+    source.flags {{ flag.key flag.reason flag.at }}
+    source.linked {{ source.id flags: count(source.flags) }}
+  }}
+}}",
+        params.join(", "),
+        ids.join(", ")
+    )
+}
+
+/// Sources stored as `Source` nodes in Dgraph, queried over DQL
+pub struct DgraphGraph {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+impl DgraphGraph {
+    async fn post(&self, endpoint: &str, body: &serde_json::Value) -> Result<serde_json::Value> {
+        let mut request = self
+            .client
+            .post(format!("{}/{}", self.url.trim_end_matches('/'), endpoint))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(body)?);
+        if let Some(key) = &self.api_key {
+            request = request.header("Dg-Auth", key);
+        }
+        let body = request
+            .send()
+            .await
+            .context("Dgraph request failed")?
+            .error_for_status()
+            .context("Dgraph returned an error")?
+            .bytes()
+            .await?;
+        let response: serde_json::Value =
+            serde_json::from_slice(&body).context("Dgraph returned invalid JSON")?;
+        if let Some(errors) = response.get("errors") {
+            bail!("Dgraph errors: {}", errors);
+        }
+        Ok(response)
+    }
+}
+
+impl KnowledgeGraph for DgraphGraph {
+    fn name(&self) -> &'static str {
+        "dgraph"
+    }
+
+    fn source<'a>(&'a self, source_id: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+        Box::pin(async move {
+            // The id is bound as a query variable, never interpolated into DQL
+            let request = json!({
+                "query": DGRAPH_QUERY,
+                "variables": { "$id": source_id },
+            });
+            parse_dgraph(self.post("query", &request).await?)
+        })
+    }
+
+    fn sources<'a>(
+        &'a self,
+        source_ids: &'a [String],
+    ) -> BoxFuture<'a, Result<HashMap<String, SourceFacts>>> {
+        Box::pin(async move {
+            if source_ids.is_empty() {
+                return Ok(HashMap::new());
+            }
+            let variables: serde_json::Map<String, serde_json::Value> = source_ids
+                .iter()
+                .enumerate()
+                .map(|(n, id)| (format!("$id{}", n), json!(id)))
+                .collect();
+            let request = json!({
+                "query": dgraph_batch_query(source_ids.len()),
+                "variables": variables,
+            });
+            parse_dgraph_batch(self.post("query", &request).await?)
+        })
+    }
+
+    fn record_verdict<'a>(&'a self, record: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.post("mutate?commitNow=true", &dgraph_upsert(record))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn coordination<'a>(
+        &'a self,
+        content_hash: &'a str,
+        source_id: &'a str,
+        created_since: SystemTime,
+    ) -> BoxFuture<'a, Result<Coordination>> {
+        Box::pin(async move {
+            let request = json!({
+                "query": DGRAPH_COORDINATION_QUERY,
+                "variables": { "$hash": content_hash, "$id": source_id },
+            });
+            Ok(parse_dgraph_coordination(
+                &self.post("query", &request).await?,
+                unix_secs(created_since),
+            ))
+        })
+    }
+}
+
+/// Dgraph upsert block setting the verdict on the content node, keyed by
+/// `content.hash` like the entity upserts of [`crate::ner`]
+fn dgraph_upsert(record: &VerdictRecord) -> serde_json::Value {
+    let at = record.unix_secs();
+    let mut source = json!({
+        "uid": "uid(source)",
+        "dgraph.type": "Source",
+        "source.id": record.source_id,
+    });
+    if record.flags_source() {
+        source["source.flags"] = json!([{
+            "uid": "uid(flag)",
+            "dgraph.type": "Flag",
+            "flag.key": record.content_hash,
+            "flag.reason": record.reason(),
+            "flag.at": at,
+        }]);
+    }
+    json!({
+        "query": format!(
+            "{{ content as var(func: eq(content.hash, {:?})) \
+             source as var(func: eq(source.id, {:?})) \
+             flag as var(func: eq(flag.key, {:?})) }}",
+            record.content_hash, record.source_id, record.content_hash
+        ),
+        "set": [{
+            "uid": "uid(content)",
+            "dgraph.type": "Content",
+            "content.hash": record.content_hash,
+            "content.verdict": record.verdict.as_str(),
+            "content.confidence": record.confidence,
+            "content.fired": record.fired.join(","),
+            "content.decided_at": at,
+            "content.source": source,
+        }],
+    })
+}
+
+fn parse_dgraph(response: serde_json::Value) -> Result<Option<SourceFacts>> {
+    let mut sources: Vec<SourceFacts> = serde_json::from_value(response["data"]["source"].clone())
+        .context("Unexpected Dgraph source response")?;
+    Ok(sources.pop())
+}
+
+/// Sources by id from the response to [`dgraph_batch_query`]
+fn parse_dgraph_batch(response: serde_json::Value) -> Result<HashMap<String, SourceFacts>> {
+    let nodes: Vec<serde_json::Value> = serde_json::from_value(response["data"]["sources"].clone())
+        .context("Unexpected Dgraph sources response")?;
+    nodes
+        .into_iter()
+        .map(|node| {
+            let id = node["source.id"]
+                .as_str()
+                .context("Dgraph source without source.id")?
+                .to_string();
+            let source =
+                serde_json::from_value(node).context("Unexpected Dgraph source response")?;
+            Ok((id, source))
+        })
+        .collect()
+}
+
+/// Coordination counts from the response to [`DGRAPH_COORDINATION_QUERY`]
+fn parse_dgraph_coordination(response: &serde_json::Value, created_since: u64) -> Coordination {
+    let nodes = |value: &serde_json::Value| value.as_array().cloned().unwrap_or_default();
+    let mut posters = HashMap::new();
+    for content in nodes(&response["data"]["posters"]) {
+        for source in nodes(&content["content.source"]) {
+            if let Some(id) = source["source.id"].as_str() {
+                posters.insert(id.to_string(), source["source.created_at"].as_u64());
+            }
+        }
+    }
+    let mut sharing = HashSet::new();
+    for source in nodes(&response["data"]["infra"]) {
+        for infra in nodes(&source["source.infra"]) {
+            for other in nodes(&infra["~source.infra"]) {
+                if let Some(id) = other["source.id"].as_str() {
+                    sharing.insert(id.to_string());
+                }
+            }
+        }
+    }
+    Coordination {
+        accounts: posters.len() as u64,
+        new_accounts: posters
+            .values()
+            .filter(|created_at| created_at.is_some_and(|at| at >= created_since))
+            .count() as u64,
+        shared_infrastructure: sharing.len() as u64,
+    }
+}
+
+/// `:Source` node with its `:FLAGGED` flags and `:LINKED` sources
+const NEO4J_QUERY: &str = "MATCH (s:Source {id: $id})
+OPTIONAL MATCH (s)-[:FLAGGED]->(f:Flag)
+WITH s, collect(f {.key, .reason, .at}) AS flags
+OPTIONAL MATCH (s)-[:LINKED]->(l:Source)
+OPTIONAL MATCH (l)-[:FLAGGED]->(lf:Flag)
+WITH s, flags, l, count(lf) AS linked_flags
+RETURN {
+  trusted: s.trusted,
+  reputation: s.reputation,
+  flags: flags,
+  linked: collect(CASE WHEN l IS NULL THEN NULL ELSE {id: l.id, flags: linked_flags} END)
+}
+LIMIT 1";
+
+/// Other `:Source` nodes the `:Content` of `$hash` is `:FROM`, and those
+/// that `:USES` an `:Infra` node the source of `$id` uses
+const NEO4J_COORDINATION_QUERY: &str = "OPTIONAL MATCH (:Content {hash: $hash})-[:FROM]->(p:Source)
+WHERE p.id <> $id
+WITH collect(DISTINCT p) AS posters
+OPTIONAL MATCH (:Source {id: $id})-[:USES]->(:Infra)<-[:USES]-(o:Source)
+WHERE o.id <> $id
+RETURN {
+  accounts: size(posters),
+  new_accounts: size([p IN posters WHERE p.created_at >= $since]),
+  shared_infrastructure: count(DISTINCT o)
+}";
+
+/// [`NEO4J_QUERY`] for each id of `$ids`, returning the id alongside
+const NEO4J_BATCH_QUERY: &str = "UNWIND $ids AS id
+MATCH (s:Source {id: id})
+OPTIONAL MATCH (s)-[:FLAGGED]->(f:Flag)
+WITH id, s, collect(f {.key, .reason, .at}) AS flags
+OPTIONAL MATCH (s)-[:LINKED]->(l:Source)
+OPTIONAL MATCH (l)-[:FLAGGED]->(lf:Flag)
+WITH id, s, flags, l, count(lf) AS linked_flags
+RETURN id, {
+  trusted: s.trusted,
+  reputation: s.reputation,
+  flags: flags,
+  linked: collect(CASE WHEN l IS NULL THEN NULL ELSE {id: l.id, flags: linked_flags} END)
+}";
+
+/// Sources stored as `:Source` nodes in Neo4j, queried over Cypher
+pub struct Neo4jGraph {
+    client: reqwest::Client,
+    url: String,
+    database: String,
+    user: String,
+    password: String,
+}
+
+/// The verdict on the `:Content` node, `:FROM` its source, and for a
+/// flagging verdict one `:Flag` per content
+const NEO4J_RECORD: &str = "MERGE (c:Content {hash: $hash})
+SET c.verdict = $verdict, c.confidence = $confidence, c.fired = $fired, c.decided_at = $at
+MERGE (s:Source {id: $source})
+MERGE (c)-[:FROM]->(s)
+FOREACH (_ IN CASE WHEN $flagged THEN [1] ELSE [] END |
+  MERGE (f:Flag {key: $hash})
+  SET f.reason = $reason, f.at = $at
+  MERGE (s)-[:FLAGGED]->(f))";
+
+impl Neo4jGraph {
+    async fn run(
+        &self,
+        statement: &str,
+        parameters: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        let request = json!({
+            "statements": [{ "statement": statement, "parameters": parameters }]
+        });
+        let body = self
+            .client
+            .post(format!(
+                "{}/db/{}/tx/commit",
+                self.url.trim_end_matches('/'),
+                self.database
+            ))
+            .basic_auth(&self.user, Some(&self.password))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&request)?)
+            .send()
+            .await
+            .context("Neo4j request failed")?
+            .error_for_status()
+            .context("Neo4j returned an error")?
+            .bytes()
+            .await?;
+        let response: serde_json::Value =
+            serde_json::from_slice(&body).context("Neo4j returned invalid JSON")?;
+        if let Some(errors) = response["errors"].as_array().filter(|e| !e.is_empty()) {
+            bail!("Neo4j errors: {:?}", errors);
+        }
+        Ok(response)
+    }
+}
+
+impl KnowledgeGraph for Neo4jGraph {
+    fn name(&self) -> &'static str {
+        "neo4j"
+    }
+
+    fn source<'a>(&'a self, source_id: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+        Box::pin(
+            async move { parse_neo4j(self.run(NEO4J_QUERY, json!({ "id": source_id })).await?) },
+        )
+    }
+
+    fn sources<'a>(
+        &'a self,
+        source_ids: &'a [String],
+    ) -> BoxFuture<'a, Result<HashMap<String, SourceFacts>>> {
+        Box::pin(async move {
+            if source_ids.is_empty() {
+                return Ok(HashMap::new());
+            }
+            let response = self
+                .run(NEO4J_BATCH_QUERY, json!({ "ids": source_ids }))
+                .await?;
+            parse_neo4j_batch(response)
+        })
+    }
+
+    fn record_verdict<'a>(&'a self, record: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let parameters = json!({
+                "hash": record.content_hash,
+                "source": record.source_id,
+                "verdict": record.verdict.as_str(),
+                "confidence": record.confidence,
+                "fired": record.fired,
+                "at": record.unix_secs(),
+                "flagged": record.flags_source(),
+                "reason": record.reason(),
+            });
+            self.run(NEO4J_RECORD, parameters).await?;
+            Ok(())
+        })
+    }
+
+    fn coordination<'a>(
+        &'a self,
+        content_hash: &'a str,
+        source_id: &'a str,
+        created_since: SystemTime,
+    ) -> BoxFuture<'a, Result<Coordination>> {
+        Box::pin(async move {
+            let parameters = json!({
+                "hash": content_hash,
+                "id": source_id,
+                "since": unix_secs(created_since),
+            });
+            let response = self.run(NEO4J_COORDINATION_QUERY, parameters).await?;
+            serde_json::from_value(response["results"][0]["data"][0]["row"][0].clone())
+                .context("Unexpected Neo4j coordination response")
+        })
+    }
+}
+
+fn parse_neo4j(response: serde_json::Value) -> Result<Option<SourceFacts>> {
+    if let Some(errors) = response["errors"].as_array().filter(|e| !e.is_empty()) {
+        bail!("Neo4j query errors: {:?}", errors);
+    }
+    match response["results"][0]["data"].get(0) {
+        Some(record) => Ok(Some(
+            serde_json::from_value(record["row"][0].clone())
+                .context("Unexpected Neo4j source response")?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Sources by id from the response to [`NEO4J_BATCH_QUERY`]
+fn parse_neo4j_batch(response: serde_json::Value) -> Result<HashMap<String, SourceFacts>> {
+    if let Some(errors) = response["errors"].as_array().filter(|e| !e.is_empty()) {
+        bail!("Neo4j query errors: {:?}", errors);
+    }
+    let records = response["results"][0]["data"]
+        .as_array()
+        .context("Unexpected Neo4j sources response")?;
+    records
+        .iter()
+        .map(|record| {
+            let id = record["row"][0]
+                .as_str()
+                .context("Neo4j source without id")?
+                .to_string();
+            let source = serde_json::from_value(record["row"][1].clone())
+                .context("Unexpected Neo4j source response")?;
+            Ok((id, source))
+        })
+        .collect()
+}
+
+/// Sources by id, e.g. test fixtures or a graph exported to a JSON file
+///
+/// Verdicts written back flag the sources in memory only, and record who
+/// posted the content for the coordination lookup.
+pub struct MemoryGraph {
+    sources: RwLock<HashMap<String, SourceFacts>>,
+    /// Sources by the hash of the content they posted
+    posts: RwLock<HashMap<String, HashSet<String>>>,
+}
+
+impl MemoryGraph {
+    pub fn new(sources: HashMap<String, SourceFacts>) -> Self {
+        Self::with_posts(sources, HashMap::new())
+    }
+
+    /// Sources that already posted content, by content hash
+    pub fn with_posts(
+        sources: HashMap<String, SourceFacts>,
+        posts: HashMap<String, HashSet<String>>,
+    ) -> Self {
+        Self {
+            sources: RwLock::new(sources),
+            posts: RwLock::new(posts),
+        }
+    }
+
+    /// Read a JSON object of sources by id
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let sources = serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid knowledge graph file {}", path.display()))?;
+        Ok(Self::new(sources))
+    }
+}
+
+impl KnowledgeGraph for MemoryGraph {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn source<'a>(&'a self, source_id: &'a str) -> BoxFuture<'a, Result<Option<SourceFacts>>> {
+        Box::pin(async move { Ok(self.sources.read().unwrap().get(source_id).cloned()) })
+    }
+
+    fn record_verdict<'a>(&'a self, record: &'a VerdictRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.posts
+                .write()
+                .unwrap()
+                .entry(record.content_hash.clone())
+                .or_default()
+                .insert(record.source_id.clone());
+            if record.flags_source() {
+                let mut sources = self.sources.write().unwrap();
+                let source = sources.entry(record.source_id.clone()).or_default();
+                source.flags.retain(|flag| flag.key != record.content_hash);
+                source.flags.push(Flag {
+                    key: record.content_hash.clone(),
+                    reason: record.reason(),
+                    at: Some(record.unix_secs()),
+                });
+            }
+            Ok(())
+        })
+    }
+
+    fn coordination<'a>(
+        &'a self,
+        content_hash: &'a str,
+        source_id: &'a str,
+        created_since: SystemTime,
+    ) -> BoxFuture<'a, Result<Coordination>> {
+        Box::pin(async move {
+            let sources = self.sources.read().unwrap();
+            let posts = self.posts.read().unwrap();
+            let since = unix_secs(created_since);
+            let posters: Vec<&String> = posts
+                .get(content_hash)
+                .into_iter()
+                .flatten()
+                .filter(|id| *id != source_id)
+                .collect();
+            let new_accounts = posters
+                .iter()
+                .filter(|id| {
+                    sources
+                        .get(id.as_str())
+                        .and_then(|source| source.created_at)
+                        .is_some_and(|at| at >= since)
+                })
+                .count();
+            let infra = sources
+                .get(source_id)
+                .map(|source| source.infra.as_slice())
+                .unwrap_or_default();
+            let shared_infrastructure = sources
+                .iter()
+                .filter(|(id, source)| {
+                    id.as_str() != source_id && source.infra.iter().any(|key| infra.contains(key))
+                })
+                .count();
+            Ok(Coordination {
+                accounts: posters.len() as u64,
+                new_accounts: new_accounts as u64,
+                shared_infrastructure: shared_infrastructure as u64,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_responses_are_deserialized() {
+        let response = json!({
+            "data": {
+                "source": [{
+                    "source.trusted": false,
+                    "source.reputation": 0.724,
+                    "source.flags": [
+                        {"flag.reason": "fabricated quote", "flag.at": 1_700_000_000},
+                        {"flag.reason": "doctored image", "flag.at": 1_600_000_000},
+                    ],
+                    "source.linked": [
+                        {"source.id": "twitter:@a", "flags": 2},
+                        {"source.id": "twitter:@b", "flags": 0},
+                    ],
+                }]
+            }
+        });
+        let source = parse_dgraph(response).unwrap().unwrap();
+        assert_eq!(source.trusted, Some(false));
+        assert_eq!(source.flags[0].reason, "fabricated quote");
+        assert_eq!(source.linked[0].id, "twitter:@a");
+        assert!(parse_dgraph(json!({"data": {"source": []}}))
+            .unwrap()
+            .is_none());
+        assert!(parse_dgraph(json!({"errors": [{"message": "no predicate"}]})).is_err());
+
+        let response = json!({
+            "results": [{
+                "columns": ["source"],
+                "data": [{"row": [{
+                    "trusted": false,
+                    "reputation": 0.724,
+                    "flags": [
+                        {"reason": "fabricated quote", "at": 1_700_000_000},
+                        {"reason": "doctored image", "at": 1_600_000_000},
+                    ],
+                    "linked": [
+                        {"id": "twitter:@a", "flags": 2},
+                        {"id": "twitter:@b", "flags": 0},
+                    ],
+                }]}],
+            }],
+            "errors": [],
+        });
+        assert_eq!(parse_neo4j(response).unwrap().unwrap(), source);
+        assert!(
+            parse_neo4j(json!({"results": [{"data": []}], "errors": []}))
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            parse_neo4j(json!({"results": [], "errors": [{"code": "Neo.ClientError"}]})).is_err()
+        );
+    }
+
+    #[test]
+    fn test_batched_responses_are_deserialized() {
+        assert!(dgraph_batch_query(2).starts_with("query sources($id0: string, $id1: string)"));
+        assert!(dgraph_batch_query(2).contains("eq(source.id, [$id0, $id1])"));
+        let sources = parse_dgraph_batch(json!({
+            "data": {"sources": [
+                {"source.id": "twitter:@a", "source.trusted": true},
+                {"source.id": "twitter:@b", "source.flags": [{"flag.at": 1_700_000_000}]},
+            ]}
+        }))
+        .unwrap();
+        assert_eq!(sources["twitter:@a"].trusted, Some(true));
+        assert_eq!(sources["twitter:@b"].flags.len(), 1);
+        assert!(
+            parse_dgraph_batch(json!({"data": {"sources": [{"source.trusted": true}]}})).is_err()
+        );
+
+        let sources = parse_neo4j_batch(json!({
+            "results": [{
+                "columns": ["id", "source"],
+                "data": [{"row": ["twitter:@a", {"trusted": false, "flags": [], "linked": []}]}],
+            }],
+            "errors": [],
+        }))
+        .unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources["twitter:@a"].trusted, Some(false));
+        assert!(parse_neo4j_batch(json!({
+            "results": [{"data": []}],
+            "errors": [{"code": "Neo.ClientError"}],
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_source_maps_to_facts() {
+        let source = SourceFacts {
+            trusted: Some(false),
+            reputation: Some(0.724),
+            flags: vec![
+                Flag {
+                    key: String::new(),
+                    reason: "fabricated quote".to_string(),
+                    at: Some(1_600_000_000),
+                },
+                Flag {
+                    key: String::new(),
+                    reason: "doctored image".to_string(),
+                    at: Some(1_700_000_000),
+                },
+            ],
+            linked: vec![
+                LinkedSource {
+                    id: "twitter:@a".to_string(),
+                    flags: 2,
+                },
+                LinkedSource {
+                    id: "twitter:@b".to_string(),
+                    flags: 0,
+                },
+            ],
+            created_at: None,
+            infra: Vec::new(),
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_secs(86_400);
+        let (facts, observed) = source.facts(now);
+        assert_eq!(facts["source_trusted"], "false");
+        assert_eq!(facts["source_reputation"], "72");
+        assert_eq!(facts["source_flagged"], "true");
+        assert_eq!(facts["source_flags"], "2");
+        assert_eq!(facts["source_flags_7d"], "1");
+        assert_eq!(facts["source_linked_flagged"], "1");
+        assert_eq!(
+            observed["source_flagged"],
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+        );
+
+        let (facts, observed) = SourceFacts::default().facts(now);
+        assert!(facts.is_empty() && observed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_verdicts_flag_sources() {
+        let record = |content_hash: &str, verdict| VerdictRecord {
+            content_hash: content_hash.to_string(),
+            source_id: "twitter:@example".to_string(),
+            verdict,
+            confidence: 0.9,
+            fired: vec!["disinfo".to_string(), "untrusted_source".to_string()],
+            at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        let upsert = dgraph_upsert(&record("ab12", Verdict::Disinfo));
+        let content = &upsert["set"][0];
+        assert_eq!(content["content.verdict"], "DISINFO");
+        assert_eq!(content["content.fired"], "disinfo,untrusted_source");
+        let flag = &content["content.source"]["source.flags"][0];
+        assert_eq!(flag["flag.key"], "ab12");
+        assert_eq!(flag["flag.at"], 1_700_000_000);
+        let upsert = dgraph_upsert(&record("ab12", Verdict::Safe));
+        assert!(upsert["set"][0]["content.source"]
+            .get("source.flags")
+            .is_none());
+
+        let graph = MemoryGraph::new(HashMap::new());
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for (hash, verdict) in [
+            ("ab12", Verdict::Disinfo),
+            ("ab12", Verdict::Disinfo),
+            ("cd34", Verdict::Suspicious),
+            ("ef56", Verdict::Safe),
+        ] {
+            graph.record_verdict(&record(hash, verdict)).await.unwrap();
+        }
+        let (facts, _) = source_facts(&graph, "twitter:@example", now).await.unwrap();
+        assert_eq!(facts["source_flags_7d"], "2");
+        assert!(!facts.contains_key("source_trusted"));
+    }
+
+    #[tokio::test]
+    async fn test_coordination_is_counted() {
+        let response = json!({
+            "data": {
+                "posters": [{"content.source": [
+                    {"source.id": "twitter:@a", "source.created_at": 1_700_000_000},
+                    {"source.id": "twitter:@b", "source.created_at": 1_500_000_000},
+                    {"source.id": "twitter:@c"},
+                ]}],
+                "infra": [{"source.infra": [
+                    {"~source.infra": [{"source.id": "twitter:@a"}, {"source.id": "twitter:@d"}]},
+                    {"~source.infra": [{"source.id": "twitter:@a"}]},
+                ]}],
+            }
+        });
+        let expected = Coordination {
+            accounts: 3,
+            new_accounts: 1,
+            shared_infrastructure: 2,
+        };
+        assert_eq!(
+            parse_dgraph_coordination(&response, 1_600_000_000),
+            expected
+        );
+        assert_eq!(
+            parse_dgraph_coordination(&json!({"data": {}}), 0),
+            Coordination::default()
+        );
+
+        let source = |created_at, infra: &[&str]| SourceFacts {
+            created_at: Some(created_at),
+            infra: infra.iter().map(|key| key.to_string()).collect(),
+            ..Default::default()
+        };
+        let graph = MemoryGraph::new(HashMap::from([
+            (
+                "twitter:@example".to_string(),
+                source(1_400_000_000, &["ip:203.0.113.7"]),
+            ),
+            (
+                "twitter:@a".to_string(),
+                source(1_700_000_000, &["ip:203.0.113.7"]),
+            ),
+            (
+                "twitter:@b".to_string(),
+                source(1_500_000_000, &["ip:198.51.100.1"]),
+            ),
+            ("twitter:@c".to_string(), source(1_500_000_000, &[])),
+            (
+                "twitter:@d".to_string(),
+                source(1_700_000_000, &["ip:203.0.113.7"]),
+            ),
+        ]));
+        for source_id in ["twitter:@example", "twitter:@a", "twitter:@b", "twitter:@c"] {
+            let record = VerdictRecord {
+                content_hash: "ab12".to_string(),
+                source_id: source_id.to_string(),
+                verdict: Verdict::Safe,
+                confidence: 0.9,
+                fired: Vec::new(),
+                at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            };
+            graph.record_verdict(&record).await.unwrap();
+        }
+        let since = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let coordination = graph
+            .coordination("ab12", "twitter:@example", since)
+            .await
+            .unwrap();
+        assert_eq!(coordination, expected);
+        let facts = coordination.facts();
+        assert_eq!(facts["coordinated_accounts"], "3");
+        assert_eq!(facts["coordinated_new_accounts"], "1");
+        assert_eq!(facts["shared_infrastructure"], "2");
+        assert_eq!(
+            graph
+                .coordination("cd34", "twitter:@b", since)
+                .await
+                .unwrap(),
+            Coordination::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_graph_serves_fixtures() {
+        let dir = TempDir::new("graph");
+        let path = dir.join("graph.json");
+        fs::write(
+            &path,
+            r#"{"twitter:@example": {"source.trusted": true, "source.flags": []}}"#,
+        )
+        .unwrap();
+        let graph = from_config(&KnowledgeGraphConfig {
+            backend: KnowledgeGraphBackend::Memory { path: path.clone() },
+            timeout: Duration::from_millis(500),
+            write_back: false,
+            breaker: GraphBreakerConfig {
+                retries: 0,
+                backoff: Duration::ZERO,
+                failures: 1,
+                open: Duration::ZERO,
+            },
+            cache: None,
+            batch: None,
+            coordination: None,
+        })
+        .unwrap();
+        assert_eq!(graph.name(), "memory");
+
+        let (facts, _) = source_facts(graph.as_ref(), "twitter:@example", SystemTime::now())
+            .await
+            .unwrap();
+        assert_eq!(facts["source_trusted"], "true");
+        let (facts, _) = source_facts(graph.as_ref(), "twitter:@unknown", SystemTime::now())
+            .await
+            .unwrap();
+        assert!(facts.is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Language detection and per-language model routing
+//!
+//! Each message's language is detected before inference. Languages with
+//! dedicated models (e.g. `detector_uk.onnx`) are routed to them; all
+//! others fall back to the default, multilingual ensemble. The language is
+//! also handed to the rules as a `language(code)` fact.
+
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+use crate::onnx_wrapper::ModelSpec;
+
+/// ISO 639 code for text whose language could not be determined
+pub const UNDETERMINED: &str = "und";
+
+/// Stopwords voting for each Latin-script language
+const STOPWORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "is", "of", "to", "in", "that", "it", "with", "are",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "und", "ist", "nicht", "das", "mit", "ein", "auf", "sich",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "et", "est", "des", "une", "pas", "que", "dans",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "es", "una", "que", "por", "con", "para",
+        ],
+    ),
+];
+
+/// Fewest stopword hits needed to call a Latin-script language
+const MIN_STOPWORDS: usize = 2;
+
+/// Detect the language of a text as an ISO 639-1 code
+///
+/// Returns [`UNDETERMINED`] for short, mixed or unsupported text.
+pub fn detect(text: &str) -> &'static str {
+    // Placeholder implementation
+    // In production, this would run a compact character n-gram classifier
+    // (e.g. fastText lid.176) and apply a confidence threshold.
+    let letters = text.chars().filter(|c| c.is_alphabetic()).count();
+    let cyrillic = text
+        .chars()
+        .filter(|c| ('\u{0400}'..='\u{04FF}').contains(c))
+        .count();
+    if letters > 0 && cyrillic * 2 > letters {
+        return detect_cyrillic(text);
+    }
+
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let mut best = (UNDETERMINED, 0);
+    for (language, stopwords) in STOPWORDS {
+        let hits = words
+            .iter()
+            .filter(|w| stopwords.contains(&w.as_str()))
+            .count();
+        if hits > best.1 {
+            best = (language, hits);
+        } else if hits == best.1 {
+            best.0 = UNDETERMINED;
+        }
+    }
+    if best.1 >= MIN_STOPWORDS {
+        best.0
+    } else {
+        UNDETERMINED
+    }
+}
+
+/// Tell Ukrainian from Russian by the letters only one alphabet has
+fn detect_cyrillic(text: &str) -> &'static str {
+    let lower = text.to_lowercase();
+    let ukrainian = lower.chars().filter(|c| "іїєґ".contains(*c)).count();
+    let russian = lower.chars().filter(|c| "ыэёъ".contains(*c)).count();
+    match ukrainian.cmp(&russian) {
+        std::cmp::Ordering::Greater => "uk",
+        std::cmp::Ordering::Less => "ru",
+        std::cmp::Ordering::Equal => UNDETERMINED,
+    }
+}
+
+/// A model serving one language, parsed from `lang=name:path[:weight]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanguageModel {
+    pub language: String,
+    pub model: ModelSpec,
+}
+
+impl FromStr for LanguageModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((language, model)) = s.split_once('=') else {
+            bail!(
+                "invalid language model (expected lang=name:path[:weight]): {}",
+                s
+            );
+        };
+        let language = language.trim().to_lowercase();
+        if language.is_empty() || language == UNDETERMINED {
+            bail!("invalid language for model: {}", s);
+        }
+        Ok(Self {
+            language,
+            model: model.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            detect("The minister said that the vaccine is safe and it works"),
+            "en"
+        );
+        assert_eq!(
+            detect("Die Regierung sagt, dass das nicht stimmt und sich nichts ändert"),
+            "de"
+        );
+        assert_eq!(
+            detect("Україна отримала нову партію допомоги, це її успіх"),
+            "uk"
+        );
+        assert_eq!(
+            detect("Это было объявлено вчера, подробности ещё неизвестны"),
+            "ru"
+        );
+        assert_eq!(detect("OK 123"), UNDETERMINED);
+        assert_eq!(detect(""), UNDETERMINED);
+    }
+
+    #[test]
+    fn test_language_model_parse() {
+        let routed: LanguageModel = "UK=detector:models/detector_uk.onnx".parse().unwrap();
+        assert_eq!(routed.language, "uk");
+        assert_eq!(routed.model.path, "models/detector_uk.onnx");
+
+        assert!("detector:models/detector_uk.onnx"
+            .parse::<LanguageModel>()
+            .is_err());
+        assert!("und=detector:a.onnx".parse::<LanguageModel>().is_err());
+    }
+}
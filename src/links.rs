@@ -0,0 +1,344 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Reputation of the domains content links to
+//!
+//! Disinformation often lives on a handful of low-credibility sites and is
+//! spread as links to them, frequently behind a URL shortener. The links
+//! of the content are extracted, links on known shortener hosts are
+//! expanded by reading their redirects, and the domains are judged by a
+//! local allow list, a local deny list and, for domains on neither, an
+//! optional reputation API. Every low-credibility domain becomes a
+//! `links_to_lowcred_domain(domain)` fact.
+//!
+//! Only shortener hosts are ever requested, and only with `HEAD`: the
+//! destination of a link is never fetched.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::cache::LruCache;
+use crate::fact_mapping::Fact;
+
+/// Redirects followed from a shortened link
+const MAX_REDIRECTS: usize = 5;
+
+/// Domains whose API reputation is remembered
+const REPUTATION_CACHE_CAPACITY: usize = 10_000;
+
+/// How long an API reputation is remembered
+const REPUTATION_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Shortener hosts expanded when no list is configured
+pub const DEFAULT_SHORTENERS: &[&str] = &[
+    "bit.ly",
+    "buff.ly",
+    "cutt.ly",
+    "goo.gl",
+    "is.gd",
+    "ow.ly",
+    "rebrand.ly",
+    "t.co",
+    "tinyurl.com",
+];
+
+/// Domain reputation API, queried as `GET <url>?domain=<domain>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReputationApi {
+    pub url: String,
+    pub api_key: Option<String>,
+    /// Scores (0-100) below this are low-credibility
+    pub min_score: f32,
+}
+
+/// Link reputation settings; disabled unless a list or an API is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkReputationConfig {
+    /// Domains always credible, one per line
+    pub allow: Option<PathBuf>,
+    /// Domains always low-credibility, one per line
+    pub deny: Option<PathBuf>,
+    pub api: Option<ReputationApi>,
+    /// Hosts whose links are expanded
+    pub shorteners: Vec<String>,
+    /// Links of a message looked at
+    pub max_links: usize,
+    /// Deadline of one expansion or API request
+    pub timeout: Duration,
+}
+
+/// Links of a text, `http(s)://` or starting with `www.`
+pub fn urls(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        // Brackets and sentence punctuation around a link are not part of it
+        .map(|token| {
+            token
+                .trim_start_matches(|c: char| !c.is_alphanumeric())
+                .trim_end_matches(|c: char| !(c.is_alphanumeric() || c == '/'))
+        })
+        .filter(|token| {
+            let lower = token.to_lowercase();
+            lower.starts_with("https://")
+                || lower.starts_with("http://")
+                || lower.starts_with("www.")
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Lowercase host of a link, without `www.`
+pub fn host(url: &str) -> Option<String> {
+    let lower = url.to_lowercase();
+    let rest = lower
+        .strip_prefix("https://")
+        .or_else(|| lower.strip_prefix("http://"))
+        .unwrap_or(&lower);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    // Credentials before the host are a classic disguise
+    let authority = authority.rsplit('@').next().unwrap_or_default();
+    let host = authority
+        .split(':')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('.');
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    (!host.is_empty()).then(|| host.to_string())
+}
+
+/// Whether `domain` is `listed` or one of its subdomains
+pub fn within(domain: &str, listed: &str) -> bool {
+    domain == listed
+        || domain
+            .strip_suffix(listed)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// A list of domains, one per line; blank lines and `#` comments skipped
+#[derive(Debug, Default)]
+struct DomainList(Vec<String>);
+
+impl DomainList {
+    fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read domain list {}", path.display()))?;
+        Ok(Self::parse(&contents))
+    }
+
+    fn parse(contents: &str) -> Self {
+        Self(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter_map(host)
+                .collect(),
+        )
+    }
+
+    fn contains(&self, domain: &str) -> bool {
+        self.0.iter().any(|listed| within(domain, listed))
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiReputation {
+    score: f32,
+}
+
+/// Judges the domains content links to
+pub struct LinkChecker {
+    allow: DomainList,
+    deny: DomainList,
+    api: Option<ReputationApi>,
+    shorteners: Vec<String>,
+    max_links: usize,
+    client: reqwest::Client,
+    /// API verdicts by domain, `true` for low credibility
+    reputations: Mutex<LruCache<String, (Instant, bool)>>,
+}
+
+impl LinkChecker {
+    pub fn new(config: &LinkReputationConfig) -> Result<Self> {
+        let list = |path: &Option<PathBuf>| match path {
+            Some(path) => DomainList::load(path),
+            None => Ok(DomainList::default()),
+        };
+        Ok(Self {
+            allow: list(&config.allow)?,
+            deny: list(&config.deny)?,
+            api: config.api.clone(),
+            shorteners: config.shorteners.clone(),
+            max_links: config.max_links,
+            client: reqwest::Client::builder()
+                .timeout(config.timeout)
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .context("Failed to build the link reputation client")?,
+            reputations: Mutex::new(LruCache::new(REPUTATION_CACHE_CAPACITY)),
+        })
+    }
+
+    /// Number of listed domains, allowed and denied
+    pub fn listed(&self) -> (usize, usize) {
+        (self.allow.0.len(), self.deny.0.len())
+    }
+
+    /// Low-credibility domains `text` links to, sorted
+    pub async fn lowcred_domains(&self, text: &str) -> Result<Vec<String>> {
+        let mut domains = HashSet::new();
+        for url in urls(text).into_iter().take(self.max_links) {
+            if let Some(domain) = host(&self.expand(&url).await?) {
+                domains.insert(domain);
+            }
+        }
+        let mut lowcred = Vec::new();
+        for domain in domains {
+            if self.lowcred(&domain).await? {
+                lowcred.push(domain);
+            }
+        }
+        lowcred.sort();
+        Ok(lowcred)
+    }
+
+    fn shortened(&self, url: &str) -> bool {
+        host(url).is_some_and(|host| self.shorteners.iter().any(|s| within(&host, s)))
+    }
+
+    /// Where a link ends up, following redirects only between shorteners
+    async fn expand(&self, url: &str) -> Result<String> {
+        let mut url = url.to_string();
+        for _ in 0..MAX_REDIRECTS {
+            if !self.shortened(&url) {
+                break;
+            }
+            let request = if url.contains("://") {
+                url.clone()
+            } else {
+                format!("https://{}", url)
+            };
+            let response = self
+                .client
+                .head(&request)
+                .send()
+                .await
+                .with_context(|| format!("Failed to expand {}", url))?;
+            let Some(location) = response.headers().get(reqwest::header::LOCATION) else {
+                break;
+            };
+            url = location
+                .to_str()
+                .with_context(|| format!("Invalid redirect from {}", url))?
+                .to_string();
+        }
+        Ok(url)
+    }
+
+    async fn lowcred(&self, domain: &str) -> Result<bool> {
+        if self.allow.contains(domain) {
+            return Ok(false);
+        }
+        if self.deny.contains(domain) {
+            return Ok(true);
+        }
+        let Some(api) = &self.api else {
+            return Ok(false);
+        };
+        let cached = self
+            .reputations
+            .lock()
+            .unwrap()
+            .get(&domain.to_string())
+            .filter(|(at, _)| at.elapsed() < REPUTATION_TTL);
+        if let Some((_, lowcred)) = cached {
+            return Ok(lowcred);
+        }
+        let mut request = self.client.get(&api.url).query(&[("domain", domain)]);
+        if let Some(key) = &api.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await.context("Reputation lookup failed")?;
+        // An unrated domain is not held against the content
+        let lowcred = match response.status() {
+            reqwest::StatusCode::NOT_FOUND => false,
+            status if !status.is_success() => {
+                bail!("Reputation lookup of {} returned {}", domain, status)
+            }
+            _ => {
+                let body = response.bytes().await?;
+                let reputation: ApiReputation = serde_json::from_slice(&body)
+                    .context("Reputation lookup returned invalid JSON")?;
+                reputation.score < api.min_score
+            }
+        };
+        self.reputations
+            .lock()
+            .unwrap()
+            .put(domain.to_string(), (Instant::now(), lowcred));
+        Ok(lowcred)
+    }
+}
+
+/// One `links_to_lowcred_domain(domain)` fact per low-credibility domain
+pub fn link_facts(domains: &[String]) -> Vec<Fact> {
+    domains
+        .iter()
+        .map(|domain| Fact::new("links_to_lowcred_domain", vec![domain.clone()]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_links_and_hosts_are_extracted() {
+        let text = "See https://User@WWW.Example-News.ltd:443/a?b=1, (www.other.org/x). \
+                    Not a link: example.com";
+        let urls = urls(text);
+        assert_eq!(
+            urls,
+            [
+                "https://User@WWW.Example-News.ltd:443/a?b=1",
+                "www.other.org/x"
+            ]
+        );
+        let hosts: Vec<_> = urls.iter().filter_map(|url| host(url)).collect();
+        assert_eq!(hosts, ["example-news.ltd", "other.org"]);
+    }
+
+    #[tokio::test]
+    async fn test_lists_decide_credibility() {
+        let checker = LinkChecker {
+            allow: DomainList::parse("# fact-checkers\nfacts.example-news.ltd\n"),
+            deny: DomainList::parse("example-news.ltd\n"),
+            api: None,
+            shorteners: Vec::new(),
+            max_links: 10,
+            client: reqwest::Client::new(),
+            reputations: Mutex::new(LruCache::new(1)),
+        };
+        let lowcred = checker
+            .lowcred_domains(
+                "https://news.example-news.ltd/a https://facts.example-news.ltd/b \
+                 https://notexample-news.ltd/c",
+            )
+            .await
+            .unwrap();
+        assert_eq!(lowcred, ["news.example-news.ltd"]);
+        assert_eq!(
+            link_facts(&lowcred),
+            [Fact::new(
+                "links_to_lowcred_domain",
+                vec!["news.example-news.ltd".to_string()]
+            )]
+        );
+    }
+}
@@ -0,0 +1,752 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Rules that can be replaced while the consumer runs
+//!
+//! [`LiveRules`] holds the engine and version of the rules in force. A
+//! message takes a snapshot when it reaches the rules, so a reload never
+//! changes the rules under a message, and its result carries the version
+//! that decided it. Reloads are triggered by `SIGHUP` or, with
+//! `NSAI_RULES_RELOAD_SECS` set, by polling the rule files for changes; a
+//! reload that fails to load or validate keeps the current rules.
+//!
+//! Shadow packs of the rules directory are evaluated after the verdict, on
+//! spawned tasks, with at most [`SHADOW_CONCURRENCY`] at once; their
+//! would-be verdicts are logged and counted but never published.
+//!
+//! A subdirectory of the rules directory named after a [`ContentType`],
+//! e.g. `image/`, holds the rule set for that type: messages whose
+//! `content_type` fact names it are evaluated with its packs alone instead
+//! of the packs of the rules directory, the default set.
+//!
+//! Likewise, `tenants/<tenant>/`, e.g. `tenants/newsroom/`, holds the rule
+//! set of a tenant, used for messages whose `tenant` fact names it. A
+//! tenant's set takes precedence over the set of the content type.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use prometheus::CounterVec;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+
+use crate::content_type::ContentType;
+use crate::fact_mapping::Fact;
+use crate::metrics::Metrics;
+use crate::reasoning::{self, ReasoningEngine};
+use crate::rule_packs::{self, RulePacks};
+use crate::rules_manifest::{RuleFile, RulesManifest};
+use crate::souffle_sandbox::{Runaway, SouffleSandbox};
+use crate::souffle_wrapper::{Derivation, SymbolicBackend, Verdict};
+
+/// Shadow pack evaluations that run at once; messages arriving while all
+/// run are not shadowed
+pub const SHADOW_CONCURRENCY: usize = 4;
+
+/// Subdirectory of the rules directory holding the tenants' rule sets
+const TENANTS_DIR: &str = "tenants";
+
+/// A shadow pack, evaluated as the program plus the pack
+pub struct ShadowRules {
+    pub name: String,
+    engine: Arc<dyn ReasoningEngine>,
+}
+
+/// Budget and counters of shadow evaluations, shared across reloads
+#[derive(Clone)]
+struct ShadowObserver {
+    permits: Arc<Semaphore>,
+    runs: CounterVec,
+    verdicts: CounterVec,
+}
+
+impl ShadowObserver {
+    fn new(metrics: &Metrics) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(SHADOW_CONCURRENCY)),
+            runs: metrics.shadow_rule_runs.clone(),
+            verdicts: metrics.shadow_rule_verdicts.clone(),
+        }
+    }
+
+    /// Evaluate `shadow` in the background and compare with `verdict`
+    fn observe(&self, shadow: &ShadowRules, facts: &[Fact], verdict: Verdict) {
+        let Ok(permit) = Arc::clone(&self.permits).try_acquire_owned() else {
+            self.runs
+                .with_label_values(&[shadow.name.as_str(), "skipped"])
+                .inc();
+            return;
+        };
+        let observer = self.clone();
+        let name = shadow.name.clone();
+        let engine = Arc::clone(&shadow.engine);
+        let facts = facts.to_vec();
+        tokio::spawn(async move {
+            let outcome = match engine.evaluate(&facts).await {
+                Ok(derivation) => {
+                    observer
+                        .verdicts
+                        .with_label_values(&[name.as_str(), derivation.verdict.as_str()])
+                        .inc();
+                    if derivation.verdict == verdict {
+                        "agree"
+                    } else {
+                        info!(
+                            "Shadow rule pack {} would publish {} instead of {} ({})",
+                            name, derivation.verdict, verdict, derivation.explanation
+                        );
+                        "disagree"
+                    }
+                }
+                Err(e) => {
+                    warn!("Shadow rule pack {} failed: {:#}", name, e);
+                    "failed"
+                }
+            };
+            observer
+                .runs
+                .with_label_values(&[name.as_str(), outcome])
+                .inc();
+            drop(permit);
+        });
+    }
+}
+
+/// The rule set of one content type
+pub struct ContentTypeRules {
+    pub content_type: ContentType,
+    pub packs: RulePacks,
+    engine: Arc<dyn ReasoningEngine>,
+}
+
+/// The rule set of one tenant
+pub struct TenantRules {
+    pub tenant: String,
+    pub packs: RulePacks,
+    engine: Arc<dyn ReasoningEngine>,
+}
+
+/// One loaded version of the rules
+pub struct ActiveRules {
+    pub engine: Arc<dyn ReasoningEngine>,
+    /// Version and content hashes of the program
+    pub manifest: RulesManifest,
+    /// The configured backend, pointed at the program's snapshot and its
+    /// build when compiled on load
+    pub backend: SymbolicBackend,
+    /// Packs from `NSAI_RULES_DIR`; `None` when unset
+    pub packs: Option<RulePacks>,
+    /// Shadow packs from `NSAI_RULES_DIR`
+    pub shadow: Vec<ShadowRules>,
+    /// Rule sets of content types from subdirectories of `NSAI_RULES_DIR`
+    pub content_types: Vec<ContentTypeRules>,
+    /// Rule sets of tenants from `tenants/` of `NSAI_RULES_DIR`
+    pub tenants: Vec<TenantRules>,
+    observer: ShadowObserver,
+    /// Counts Soufflé runs killed by the sandbox
+    runaways: CounterVec,
+}
+
+impl ActiveRules {
+    fn load(
+        backend: &SymbolicBackend,
+        rules_dir: Option<&Path>,
+        sandbox: &SouffleSandbox,
+        observer: &ShadowObserver,
+        runaways: &CounterVec,
+    ) -> Result<Self> {
+        let mut shadow = Vec::new();
+        let mut content_types = Vec::new();
+        let mut tenants = Vec::new();
+        let (backend, manifest, packs) = match (rules_dir, backend) {
+            (Some(dir), _) => {
+                let packs = RulePacks::load(dir)?;
+                let files = |prefix: &str, packs: &[rule_packs::RulePack]| {
+                    packs
+                        .iter()
+                        .map(|pack| RuleFile {
+                            name: format!("{}{}", prefix, pack.file_name()),
+                            sha256: pack.sha256.clone(),
+                        })
+                        .collect::<Vec<_>>()
+                };
+                let mut sha256 = packs.sha256();
+                let mut all = files("", packs.packs());
+                for content_type in ContentType::ALL {
+                    let subdir = dir.join(content_type.as_str());
+                    if !subdir.is_dir() {
+                        continue;
+                    }
+                    let set = RulePacks::load(&subdir)?;
+                    if !set.shadow().is_empty() {
+                        bail!(
+                            "shadow packs are only run with the default rule set, not in {}",
+                            subdir.display()
+                        );
+                    }
+                    all.extend(files(&format!("{}/", content_type), set.packs()));
+                    content_types.push(ContentTypeRules {
+                        content_type,
+                        engine: reasoning::from_config(
+                            &set.apply(backend, sandbox)?.compile()?,
+                            sandbox,
+                        )?,
+                        packs: set,
+                    });
+                }
+                for (tenant, subdir) in tenant_dirs(dir)? {
+                    let set = RulePacks::load(&subdir)?;
+                    if !set.shadow().is_empty() {
+                        bail!(
+                            "shadow packs are only run with the default rule set, not in {}",
+                            subdir.display()
+                        );
+                    }
+                    all.extend(files(&format!("{}/{}/", TENANTS_DIR, tenant), set.packs()));
+                    tenants.push(TenantRules {
+                        tenant,
+                        engine: reasoning::from_config(
+                            &set.apply(backend, sandbox)?.compile()?,
+                            sandbox,
+                        )?,
+                        packs: set,
+                    });
+                }
+                if !content_types.is_empty() || !tenants.is_empty() {
+                    // One version for all sets, so a change to any is a reload
+                    let mut hasher = Sha256::new();
+                    hasher.update(&sha256);
+                    for set in &content_types {
+                        hasher.update(format!("\n{}:{}", set.content_type, set.packs.sha256()));
+                    }
+                    for set in &tenants {
+                        hasher.update(format!(
+                            "\n{}/{}:{}",
+                            TENANTS_DIR,
+                            set.tenant,
+                            set.packs.sha256()
+                        ));
+                    }
+                    sha256 = hex::encode(hasher.finalize());
+                }
+                let manifest =
+                    RulesManifest::load(dir, sha256, all)?.with_shadow(files("", packs.shadow()));
+                for (name, backend) in packs.apply_shadow(backend, sandbox)? {
+                    shadow.push(ShadowRules {
+                        name,
+                        engine: reasoning::from_config(&backend.compile()?, sandbox)?,
+                    });
+                }
+                (packs.apply(backend, sandbox)?, manifest, Some(packs))
+            }
+            (None, SymbolicBackend::Souffle { program, .. })
+            | (None, SymbolicBackend::Compiled { program, .. })
+            | (None, SymbolicBackend::CompileOnLoad { program, .. }) => {
+                let source = fs::read_to_string(program)
+                    .with_context(|| format!("Failed to read {}", program.display()))?;
+                let sha256 = hex::encode(Sha256::digest(&source));
+                let file = RuleFile {
+                    name: program
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    sha256: sha256.clone(),
+                };
+                let dir = program.parent().unwrap_or(Path::new("."));
+                let manifest = RulesManifest::load(dir, sha256, vec![file])?;
+                (
+                    rule_packs::snapshot(backend, &source, sandbox)?,
+                    manifest,
+                    None,
+                )
+            }
+            (None, _) => (backend.clone(), RulesManifest::builtin(), None),
+        };
+        // Compiles the snapshot, so reloads build the new rules
+        let backend = backend.compile()?;
+        Ok(Self {
+            engine: reasoning::from_config(&backend, sandbox)?,
+            manifest,
+            backend,
+            packs,
+            shadow,
+            content_types,
+            tenants,
+            observer: observer.clone(),
+            runaways: runaways.clone(),
+        })
+    }
+
+    /// The rule set of the content type named by the `content_type` fact,
+    /// `None` for the default set
+    pub fn content_type_rules(&self, facts: &[Fact]) -> Option<&ContentTypeRules> {
+        let content_type: ContentType = facts
+            .iter()
+            .find(|fact| fact.relation == "content_type")?
+            .args
+            .first()?
+            .parse()
+            .ok()?;
+        self.content_types
+            .iter()
+            .find(|set| set.content_type == content_type)
+    }
+
+    /// The rule set of the tenant named by the `tenant` fact, `None` for
+    /// tenants without one
+    pub fn tenant_rules(&self, facts: &[Fact]) -> Option<&TenantRules> {
+        let tenant = facts
+            .iter()
+            .find(|fact| fact.relation == "tenant")?
+            .args
+            .first()?;
+        self.tenants.iter().find(|set| set.tenant == *tenant)
+    }
+
+    /// Log the rules and export their packs
+    fn announce(&self, metrics: &Metrics) {
+        info!(
+            "Rules version {} ({})",
+            self.manifest.version,
+            &self.manifest.sha256[..12]
+        );
+        metrics.rule_packs.reset();
+        for pack in self.packs.iter().flat_map(RulePacks::packs) {
+            info!("Rule pack {} ({})", pack.name, &pack.sha256[..12]);
+            metrics
+                .rule_packs
+                .with_label_values(&[pack.name.as_str(), &pack.sha256[..12]])
+                .set(1.0);
+        }
+        for pack in self.packs.iter().flat_map(RulePacks::shadow) {
+            info!("Shadow rule pack {} ({})", pack.name, &pack.sha256[..12]);
+        }
+        let sets = self
+            .content_types
+            .iter()
+            .map(|set| (set.content_type.to_string(), &set.packs))
+            .chain(
+                self.tenants
+                    .iter()
+                    .map(|set| (format!("{}/{}", TENANTS_DIR, set.tenant), &set.packs)),
+            );
+        for (set, packs) in sets {
+            for pack in packs.packs() {
+                let name = format!("{}/{}", set, pack.name);
+                info!("Rule pack {} ({})", name, &pack.sha256[..12]);
+                metrics
+                    .rule_packs
+                    .with_label_values(&[name.as_str(), &pack.sha256[..12]])
+                    .set(1.0);
+            }
+        }
+    }
+}
+
+/// Tenants with a rule set under `tenants/` of `dir`, in name order
+fn tenant_dirs(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let dir = dir.join(TENANTS_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut tenants = Vec::new();
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if let (true, Some(tenant)) = (path.is_dir(), path.file_name()) {
+            tenants.push((tenant.to_string_lossy().into_owned(), path));
+        }
+    }
+    tenants.sort();
+    Ok(tenants)
+}
+
+/// The rules in force, replaceable at runtime
+pub struct LiveRules {
+    backend: SymbolicBackend,
+    rules_dir: Option<PathBuf>,
+    sandbox: SouffleSandbox,
+    active: RwLock<Arc<ActiveRules>>,
+    observer: ShadowObserver,
+    runaways: CounterVec,
+}
+
+impl LiveRules {
+    /// Load the configured rules, failing on invalid ones; the Soufflé
+    /// backends run in `sandbox`
+    pub fn load(
+        backend: &SymbolicBackend,
+        rules_dir: Option<&Path>,
+        sandbox: &SouffleSandbox,
+        metrics: &Metrics,
+    ) -> Result<Self> {
+        let observer = ShadowObserver::new(metrics);
+        let runaways = metrics.souffle_runaways.clone();
+        let active = ActiveRules::load(backend, rules_dir, sandbox, &observer, &runaways)?;
+        active.announce(metrics);
+        Ok(Self {
+            backend: backend.clone(),
+            rules_dir: rules_dir.map(Path::to_path_buf),
+            sandbox: sandbox.clone(),
+            active: RwLock::new(Arc::new(active)),
+            observer,
+            runaways,
+        })
+    }
+
+    /// The rules new messages are evaluated with
+    pub fn current(&self) -> Arc<ActiveRules> {
+        Arc::clone(&self.active.read().unwrap())
+    }
+
+    /// Whether the rules are read from files; the in-process backends'
+    /// rules are compiled in
+    pub fn reloadable(&self) -> bool {
+        self.rules_dir.is_some()
+            || matches!(
+                self.backend,
+                SymbolicBackend::Souffle { .. }
+                    | SymbolicBackend::Compiled { .. }
+                    | SymbolicBackend::CompileOnLoad { .. }
+            )
+    }
+
+    /// Load the rules again and swap them in if they changed
+    ///
+    /// # Returns
+    /// The new rules, or `None` when the files are unchanged
+    pub fn reload(&self) -> Result<Option<Arc<ActiveRules>>> {
+        let loaded = ActiveRules::load(
+            &self.backend,
+            self.rules_dir.as_deref(),
+            &self.sandbox,
+            &self.observer,
+            &self.runaways,
+        )?;
+        let mut active = self.active.write().unwrap();
+        if loaded.manifest == active.manifest {
+            return Ok(None);
+        }
+        *active = Arc::new(loaded);
+        Ok(Some(Arc::clone(&active)))
+    }
+}
+
+impl ReasoningEngine for ActiveRules {
+    fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+        Box::pin(async move {
+            let set = match self.tenant_rules(facts) {
+                Some(set) => Some(&set.engine),
+                None => self.content_type_rules(facts).map(|set| &set.engine),
+            };
+            let engine = set.unwrap_or(&self.engine);
+            let mut derivation = match engine.evaluate(facts).await {
+                Ok(derivation) => derivation,
+                Err(e) => {
+                    if let Some(runaway) = e.downcast_ref::<Runaway>() {
+                        warn!("Rules {}: {}", &self.manifest.sha256[..12], runaway);
+                        self.runaways.with_label_values(&[runaway.as_str()]).inc();
+                    }
+                    return Err(e);
+                }
+            };
+            derivation.rules_version = self.manifest.sha256.clone();
+            derivation.rules_semver = self.manifest.version.to_string();
+            // Shadow packs extend the default set
+            if set.is_none() {
+                for shadow in &self.shadow {
+                    self.observer.observe(shadow, facts, derivation.verdict);
+                }
+            }
+            Ok(derivation)
+        })
+    }
+}
+
+/// Evaluates with the rules in force when the message arrives
+impl ReasoningEngine for LiveRules {
+    fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+        let active = self.current();
+        Box::pin(async move { active.evaluate(facts).await })
+    }
+}
+
+/// Reload the rules on `SIGHUP` and, if `poll` is set, whenever the rule
+/// files change
+pub async fn run_reloader(rules: Arc<LiveRules>, poll: Option<Duration>, metrics: Arc<Metrics>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Cannot listen for SIGHUP, rules reload disabled: {}", e);
+            return;
+        }
+    };
+    let mut ticker = tokio::time::interval(poll.unwrap_or(Duration::MAX));
+    // The first tick completes immediately; the rules were just loaded
+    ticker.tick().await;
+    loop {
+        let requested = tokio::select! {
+            _ = hangups.recv() => true,
+            _ = ticker.tick() => false,
+        };
+        let reloading = Arc::clone(&rules);
+        let outcome = match tokio::task::spawn_blocking(move || reloading.reload()).await {
+            Ok(Ok(Some(active))) => {
+                info!("Rules reloaded");
+                active.announce(&metrics);
+                "reloaded"
+            }
+            Ok(Ok(None)) if requested => {
+                info!("Rules unchanged");
+                "unchanged"
+            }
+            Ok(Ok(None)) => continue,
+            Ok(Err(e)) => {
+                error!("Rules reload failed, keeping the current rules: {:#}", e);
+                "failed"
+            }
+            Err(e) => {
+                error!("Rules reload panicked: {}", e);
+                "failed"
+            }
+        };
+        metrics.rules_reloads.with_label_values(&[outcome]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reasoning::EmbeddedEngine;
+    use crate::test_support::TempDir;
+
+    fn rules_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("live-{}", name));
+        fs::write(
+            dir.join("00-detector.dl"),
+            include_str!("../rules/detector.dl"),
+        )
+        .unwrap();
+        dir
+    }
+
+    fn souffle() -> SymbolicBackend {
+        SymbolicBackend::Souffle {
+            souffle: PathBuf::from("souffle"),
+            program: PathBuf::from("rules/detector.dl"),
+        }
+    }
+
+    #[test]
+    fn test_reload_swaps_changed_rules() {
+        let dir = rules_dir("swap");
+        let metrics = Metrics::new().unwrap();
+        let rules = LiveRules::load(
+            &souffle(),
+            Some(&*dir),
+            &SouffleSandbox::default(),
+            &metrics,
+        )
+        .unwrap();
+        let before = rules.current();
+        assert!(rules.reloadable());
+        assert!(rules.reload().unwrap().is_none());
+
+        fs::write(
+            dir.join("50-vaccines.dl"),
+            ".decl stance_vaccines(level: symbol)\n.input stance_vaccines\n",
+        )
+        .unwrap();
+        let after = rules.reload().unwrap().expect("rules changed");
+        assert_ne!(after.manifest.sha256, before.manifest.sha256);
+        assert_eq!(rules.current().manifest, after.manifest);
+        assert_eq!(after.manifest.files[1].name, "50-vaccines.dl");
+        assert_eq!(after.packs.as_ref().unwrap().packs().len(), 2);
+        // A message that took the old rules keeps them
+        assert_eq!(before.packs.as_ref().unwrap().packs().len(), 1);
+    }
+
+    #[test]
+    fn test_content_type_sets_are_loaded() {
+        let dir = rules_dir("content-type");
+        let metrics = Metrics::new().unwrap();
+        let sandbox = SouffleSandbox::default();
+        let default = ActiveRules::load(
+            &souffle(),
+            Some(&*dir),
+            &sandbox,
+            &ShadowObserver::new(&metrics),
+            &metrics.souffle_runaways,
+        )
+        .unwrap();
+        assert!(default.content_types.is_empty());
+
+        fs::create_dir(dir.join("image")).unwrap();
+        fs::write(
+            dir.join("image").join("00-detector.dl"),
+            include_str!("../rules/detector.dl"),
+        )
+        .unwrap();
+        let rules = LiveRules::load(&souffle(), Some(&*dir), &sandbox, &metrics).unwrap();
+        let active = rules.current();
+        assert_ne!(active.manifest.sha256, default.manifest.sha256);
+        let names: Vec<_> = active
+            .manifest
+            .files
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(names, ["00-detector.dl", "image/00-detector.dl"]);
+
+        let facts =
+            |content_type: &str| vec![Fact::new("content_type", vec![content_type.to_string()])];
+        let image = active.content_type_rules(&facts("image")).unwrap();
+        assert_eq!(image.content_type, ContentType::Image);
+        assert!(active.content_type_rules(&facts("text")).is_none());
+        assert!(active.content_type_rules(&[]).is_none());
+
+        fs::write(
+            dir.join("image").join("90-vaccines.shadow.dl"),
+            ".decl stance_vaccines(level: symbol)\n.input stance_vaccines\n",
+        )
+        .unwrap();
+        assert!(rules.reload().is_err());
+    }
+
+    #[test]
+    fn test_tenant_sets_are_loaded() {
+        let dir = rules_dir("tenant");
+        let tenant_dir = dir.join(TENANTS_DIR).join("newsroom");
+        fs::create_dir_all(&tenant_dir).unwrap();
+        fs::write(
+            tenant_dir.join("00-detector.dl"),
+            include_str!("../rules/detector.dl"),
+        )
+        .unwrap();
+        let metrics = Metrics::new().unwrap();
+        let rules = LiveRules::load(
+            &souffle(),
+            Some(&*dir),
+            &SouffleSandbox::default(),
+            &metrics,
+        )
+        .unwrap();
+        let active = rules.current();
+        assert_eq!(
+            active.manifest.files[1].name,
+            "tenants/newsroom/00-detector.dl"
+        );
+
+        let facts = |tenant: &str| vec![Fact::new("tenant", vec![tenant.to_string()])];
+        let newsroom = active.tenant_rules(&facts("newsroom")).unwrap();
+        assert_eq!(newsroom.tenant, "newsroom");
+        assert!(active.tenant_rules(&facts("blog")).is_none());
+        assert!(active.tenant_rules(&[]).is_none());
+    }
+
+    #[test]
+    fn test_invalid_reload_keeps_current_rules() {
+        let dir = rules_dir("invalid");
+        let metrics = Metrics::new().unwrap();
+        let rules = LiveRules::load(
+            &souffle(),
+            Some(&*dir),
+            &SouffleSandbox::default(),
+            &metrics,
+        )
+        .unwrap();
+        let manifest = rules.current().manifest.clone();
+
+        fs::write(
+            dir.join("50-broken.dl"),
+            "disinfo() :- undeclared(\"x\").\n",
+        )
+        .unwrap();
+        assert!(rules.reload().is_err());
+        assert_eq!(rules.current().manifest, manifest);
+
+        let embedded = LiveRules::load(
+            &SymbolicBackend::Embedded,
+            None,
+            &SouffleSandbox::default(),
+            &metrics,
+        )
+        .unwrap();
+        assert!(!embedded.reloadable());
+        assert_eq!(embedded.current().manifest, RulesManifest::builtin());
+    }
+
+    #[tokio::test]
+    async fn test_shadow_packs_are_observed_only() {
+        let dir = rules_dir("shadow");
+        let metrics = Metrics::new().unwrap();
+        let rules = LiveRules::load(
+            &souffle(),
+            Some(&*dir),
+            &SouffleSandbox::default(),
+            &metrics,
+        )
+        .unwrap();
+        let before = rules.current().manifest.clone();
+
+        fs::write(
+            dir.join("60-strict.shadow.dl"),
+            "disinfo() :- fakeness(\"medium\").\n",
+        )
+        .unwrap();
+        let after = rules.reload().unwrap().expect("shadow pack added");
+        assert_eq!(after.manifest.sha256, before.sha256);
+        assert_eq!(after.manifest.shadow[0].name, "60-strict.shadow.dl");
+        assert_eq!(after.shadow[0].name, "60-strict");
+
+        let observer = ShadowObserver::new(&metrics);
+        let shadow = ShadowRules {
+            name: "60-strict".to_string(),
+            engine: Arc::new(EmbeddedEngine),
+        };
+        let facts = vec![Fact::new("fakeness", vec!["high".to_string()])];
+        observer.observe(&shadow, &facts, Verdict::Safe);
+        let runs = |outcome: &str| {
+            metrics
+                .shadow_rule_runs
+                .with_label_values(&["60-strict", outcome])
+                .get()
+        };
+        while runs("disagree") < 1.0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let verdicts = metrics
+            .shadow_rule_verdicts
+            .with_label_values(&["60-strict", "DISINFO"]);
+        assert_eq!(verdicts.get(), 1.0);
+
+        let _busy = Arc::clone(&observer.permits)
+            .acquire_many_owned(SHADOW_CONCURRENCY as u32)
+            .await
+            .unwrap();
+        observer.observe(&shadow, &facts, Verdict::Safe);
+        assert_eq!(runs("skipped"), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_derivations_carry_the_rules_version() {
+        let metrics = Metrics::new().unwrap();
+        let rules = LiveRules::load(
+            &SymbolicBackend::Embedded,
+            None,
+            &SouffleSandbox::default(),
+            &metrics,
+        )
+        .unwrap();
+        let facts = vec![Fact::new("fakeness", vec!["high".to_string()])];
+        let derivation = rules.evaluate(&facts).await.unwrap();
+        assert_eq!(derivation.rules_version, rules.current().manifest.sha256);
+        assert_eq!(derivation.rules_semver, "1.0.0");
+    }
+}
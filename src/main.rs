@@ -3,68 +3,442 @@
 
 //! Neuro-Symbolic AI Disinformation Detector Service
 
+mod ack_progress;
+mod api;
+mod attribution;
+mod backpressure;
+mod batcher;
+mod bench_symbolic;
+mod cache;
+mod calibration;
+mod campaigns;
+mod canary;
+mod cardinality;
+mod claim_matching;
+mod claims;
+mod clock;
+mod config;
+mod content_object;
+mod content_store;
+mod content_type;
+mod decision_context;
+mod dedup;
+mod delivery;
+mod enrichment;
+mod evaluation;
+mod explanations;
+mod fact_dump;
+mod fact_mapping;
+mod fallback;
+mod feature_cache;
+mod graph_batcher;
+mod graph_breaker;
+mod graph_cache;
+mod graph_snapshot;
+mod history;
+mod input;
+mod kafka;
+mod knowledge_graph;
+mod language;
+mod links;
+mod live_rules;
+mod maintenance;
+mod memory_guard;
+mod metrics;
 mod onnx_wrapper;
+mod pipeline;
+mod postprocess;
+mod propagation;
+mod publisher;
+mod quota;
+mod reasoning;
+mod reasoning_cache;
+mod reasoning_pool;
+mod recency;
+mod reevaluation;
+mod repl;
+mod replay;
+mod request_reply;
+mod retry;
+mod rule_dsl;
+mod rule_packs;
+mod rule_tests;
+mod rules_manifest;
+mod session_pool;
+mod shadow;
+mod similarity;
+mod souffle_sandbox;
 mod souffle_wrapper;
+mod stance;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod thresholds;
+mod topic;
+mod transport;
+mod validation;
+mod verdict_store;
+mod vision_wrapper;
+mod webhook;
 
 use anyhow::{Context, Result};
-use async_nats::jetstream::{self, consumer::PullConsumer, stream::Stream};
+use async_nats::jetstream::{
+    self,
+    consumer::{pull, PullConsumer},
+    stream::Stream,
+};
+use clap::{Args, Parser, Subcommand};
 use http_body_util::Full;
 use hyper::{body::Bytes, server::conn::http1, service::service_fn, Request, Response};
 use hyper_util::rt::TokioIo;
-use prometheus::{Counter, Encoder, Histogram, HistogramOpts, Opts, Registry, TextEncoder};
-use prost::Message;
-use std::{net::SocketAddr, sync::Arc, time::Instant};
-use tokio::{net::TcpListener, signal};
-use tracing::{error, info, warn};
+use prometheus::{Encoder, TextEncoder};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    signal,
+    time::{interval, sleep_until, Instant},
+};
+use tracing::{error, info, info_span, warn, Instrument};
 
+mod image_hash;
+mod model_download;
 mod model_pb;
+mod model_registry;
+mod model_validation;
+mod nats;
+mod ner;
+mod ocr;
+mod pipeline_graph;
 
-use model_pb::AnalysisInput;
+use backpressure::InFlight;
+use config::{Config, ConsumerConfig, StreamEndAction};
+use maintenance::Maintenance;
+use memory_guard::{GuardAction, MemoryGuard};
+use metrics::Metrics;
+use nats::NatsConfig;
+use pipeline::Pipeline;
+use pipeline_graph::PipelineGraph;
+use replay::{ReplayConfig, ReplayStart};
+use transport::NatsTransport;
 
-const NATS_URL: &str = "nats://nats:4222";
-const STREAM_NAME: &str = "INFERENCE_JOBS";
-const SUBJECT_INPUT: &str = "disinfo.raw";
-const CONSUMER_NAME: &str = "detector_worker";
 const METRICS_PORT: u16 = 9090;
 
-struct Metrics {
-    messages_processed: Counter,
-    errors: Counter,
-    latency: Histogram,
-    registry: Registry,
+/// Longest wait between attempts to reopen an ended message stream
+const MAX_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Parser, Debug)]
+#[command(name = "nsai-detector")]
+#[command(about = "Neuro-Symbolic AI Disinformation Detector")]
+#[command(version)]
+struct Cli {
+    /// Tool to run instead of the detector service
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    nats: NatsArgs,
 }
 
-impl Metrics {
-    fn new() -> Result<Self> {
-        let registry = Registry::new();
+/// Overrides of the `NSAI_NATS_*` settings, for every command
+#[derive(Args, Debug)]
+struct NatsArgs {
+    /// NATS servers, comma-separated
+    #[arg(long, global = true, value_delimiter = ',')]
+    nats_url: Vec<String>,
 
-        let messages_processed = Counter::with_opts(Opts::new(
-            "nsai_messages_processed_total",
-            "Total number of messages processed",
-        ))?;
+    /// Connection name shown in the server's monitoring
+    #[arg(long, global = true)]
+    nats_name: Option<String>,
 
-        let errors = Counter::with_opts(Opts::new("nsai_errors_total", "Total number of errors"))?;
+    /// JetStream stream holding the inputs
+    #[arg(long, global = true)]
+    nats_stream: Option<String>,
 
-        let latency = Histogram::with_opts(HistogramOpts::new(
-            "nsai_processing_latency_seconds",
-            "Latency of message processing",
-        ))?;
+    /// Subject inputs are published on
+    #[arg(long, global = true)]
+    nats_subject: Option<String>,
 
-        registry.register(Box::new(messages_processed.clone()))?;
-        registry.register(Box::new(errors.clone()))?;
-        registry.register(Box::new(latency.clone()))?;
+    /// Durable consumer of the detectors
+    #[arg(long, global = true)]
+    nats_consumer: Option<String>,
+}
 
-        Ok(Self {
-            messages_processed,
-            errors,
-            latency,
-            registry,
+impl NatsArgs {
+    /// The NATS settings of the environment with the flags given applied
+    fn config(self) -> Result<NatsConfig> {
+        let mut config = config::nats_from_env()?;
+        if !self.nats_url.is_empty() {
+            config.servers = self.nats_url;
+        }
+        config.name = self.nats_name.unwrap_or(config.name);
+        config.stream = self.nats_stream.unwrap_or(config.stream);
+        config.subject = self.nats_subject.unwrap_or(config.subject);
+        config.consumer = self.nats_consumer.unwrap_or(config.consumer);
+        Ok(config)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rule development tools
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommand,
+    },
+    /// Model registry administration
+    Models {
+        /// Root directory of the model registry
+        #[arg(long, env = "NSAI_MODEL_REGISTRY")]
+        registry: String,
+
+        /// Key approval manifests are checked against on activation
+        #[arg(long, env = "NSAI_APPROVAL_KEY")]
+        approval_key: Option<String>,
+
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+    /// Validate a candidate model in a sandbox and install it if approved
+    ValidateModel(ValidateModelArgs),
+    /// Compare the Soufflé and embedded rule engines on recorded fact sets
+    BenchSymbolic {
+        /// Recorded fact sets, one blank-line separated block per message
+        #[arg(long, default_value = "rules/bench_facts.dl")]
+        facts: PathBuf,
+
+        /// Datalog program evaluated by Soufflé
+        #[arg(long, default_value = "rules/detector.dl")]
+        program: PathBuf,
+
+        /// Soufflé interpreter
+        #[arg(long, default_value = "souffle")]
+        souffle: PathBuf,
+
+        /// Runs per fact set and backend
+        #[arg(long, default_value_t = 20)]
+        iterations: usize,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run rule test cases through the configured rules
+    ValidateRules {
+        /// Directory of `*.yaml` test cases
+        #[arg(long, default_value = "rules/tests")]
+        cases: PathBuf,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Score recorded verdicts against a labelled dataset
+    Evaluate {
+        /// Labelled samples, one `{"content_hash": ..., "fake": ..., "topic": ...}` per line
+        #[arg(long)]
+        labels: PathBuf,
+
+        /// Recorded results, one webhook event or `{"results": [...]}` batch per line
+        #[arg(long)]
+        results: PathBuf,
+
+        /// Verdicts counted as predicting disinformation
+        #[arg(long, value_delimiter = ',', default_value = "DISINFO")]
+        positive: Vec<String>,
+
+        /// Equal-width bins of the fakeness score calibration curve
+        #[arg(long, default_value_t = 10)]
+        bins: usize,
+
+        /// Also write the report as JSON to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Publish one analysis input to the detector's input subject
+    Submit {
+        /// Content text to analyze
+        #[arg(required_unless_present = "content_object")]
+        text: Option<String>,
+
+        /// Content hash; defaults to the SHA-256 of the text
+        #[arg(long, required_unless_present = "text")]
+        content_hash: Option<String>,
+
+        /// Object store reference of the text instead, as `BUCKET/NAME`
+        #[arg(long, conflicts_with = "text")]
+        content_object: Option<String>,
+
+        /// Source the content came from
+        #[arg(long, default_value = "")]
+        source_id: String,
+
+        /// HTTPS URL of an attached image
+        #[arg(long)]
+        image_url: Option<String>,
+
+        /// Route to publish on when the NATS subject is a wildcard, e.g.
+        /// `telegram.image`
+        #[arg(long)]
+        route: Option<String>,
+    },
+    /// Inject signed canary inputs at a fixed rate to check the pipeline
+    Canary {
+        /// Key shared with the detectors' NSAI_CANARY_KEY
+        #[arg(long, env = "NSAI_CANARY_KEY")]
+        key: String,
+
+        /// Verdict every canary must receive
+        #[arg(long)]
+        expected_verdict: String,
+
+        /// Canary content text
+        #[arg(long, default_value = "Canary item checking pipeline integrity")]
+        text: String,
+
+        /// Seconds between canaries
+        #[arg(long, default_value_t = 60)]
+        interval_secs: u64,
+
+        /// Route to inject on when the NATS subject is a wildcard
+        #[arg(long)]
+        route: Option<String>,
+    },
+    /// Re-process messages already in the stream, e.g. after a model or
+    /// rules upgrade
+    Replay(ReplayArgs),
+}
+
+#[derive(Args, Debug)]
+struct ValidateModelArgs {
+    /// Registry model name the candidate is installed under
+    #[arg(long, required_unless_present = "sandbox_report")]
+    name: Option<String>,
+
+    /// Registry version the candidate is installed as
+    #[arg(long, required_unless_present = "sandbox_report")]
+    version: Option<String>,
+
+    /// Candidate ONNX file
+    #[arg(long)]
+    model: PathBuf,
+
+    /// Labelled samples, one `{"content_hash": ..., "fake": ...}` per line
+    #[arg(long)]
+    validation_set: PathBuf,
+
+    /// Minimum accuracy on the validation set
+    #[arg(long, default_value_t = 0.8)]
+    min_accuracy: f64,
+
+    /// Minimum precision on the validation set
+    #[arg(long, default_value_t = 0.0)]
+    min_precision: f64,
+
+    /// Minimum recall on the validation set
+    #[arg(long, default_value_t = 0.0)]
+    min_recall: f64,
+
+    /// Address space limit of the sandbox
+    #[arg(long, default_value_t = 4096)]
+    memory_limit_mb: u64,
+
+    /// CPU time limit of the sandbox
+    #[arg(long, default_value_t = 300)]
+    cpu_limit_secs: u64,
+
+    /// Wall-clock limit of the sandbox
+    #[arg(long, default_value_t = 600)]
+    timeout_secs: u64,
+
+    /// Root directory of the model registry
+    #[arg(
+        long,
+        env = "NSAI_MODEL_REGISTRY",
+        required_unless_present = "sandbox_report"
+    )]
+    registry: Option<String>,
+
+    /// Key the approval manifest is signed with
+    #[arg(
+        long,
+        env = "NSAI_APPROVAL_KEY",
+        required_unless_present = "sandbox_report"
+    )]
+    key: Option<String>,
+
+    /// Run as the sandboxed child, writing the report to this file
+    #[arg(long, hide = true)]
+    sandbox_report: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ReplayArgs {
+    /// Stream sequence to start at
+    #[arg(
+        long,
+        required_unless_present = "from_time",
+        conflicts_with = "from_time"
+    )]
+    from_sequence: Option<u64>,
+
+    /// Time to start at, RFC 3339, e.g. `2024-05-01T00:00:00Z`
+    #[arg(long)]
+    from_time: Option<String>,
+
+    /// Publish results on the shadow subject only, and record nothing
+    /// about their verdicts
+    #[arg(long)]
+    shadow: bool,
+
+    /// Subject of the results of a shadow replay
+    #[arg(long, default_value = "disinfo.verdicts.replay")]
+    shadow_subject: String,
+}
+
+impl ReplayArgs {
+    fn config(self) -> Result<ReplayConfig> {
+        let start = match (self.from_sequence, self.from_time) {
+            (Some(sequence), _) => ReplayStart::Sequence(sequence),
+            (None, Some(time)) => ReplayStart::at_time(&time)?,
+            (None, None) => anyhow::bail!("A replay needs --from-sequence or --from-time"),
+        };
+        Ok(ReplayConfig {
+            start,
+            shadow_subject: self.shadow.then_some(self.shadow_subject),
         })
     }
 }
 
+#[derive(Subcommand, Debug)]
+enum RulesCommand {
+    /// Interactive rule simulation against the embedded engine
+    Repl,
+    /// Print the version and content hashes of the configured rules as JSON
+    Manifest,
+    /// Print the Datalog a `*.rules.yaml` rule pack compiles to
+    Compile { path: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+enum ModelsCommand {
+    /// List available versions and the active one
+    List { name: String },
+    /// Serve a specific version (takes effect on restart)
+    Activate { name: String, version: String },
+    /// Return to the previously active version (takes effect on restart)
+    Rollback { name: String },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     // Initialize tracing
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -73,155 +447,873 @@ async fn main() -> Result<()> {
         )
         .init();
 
+    match cli.command {
+        Some(Command::Rules {
+            command: RulesCommand::Repl,
+        }) => repl::run(),
+        Some(Command::Rules {
+            command: RulesCommand::Manifest,
+        }) => rules_manifest(),
+        Some(Command::Rules {
+            command: RulesCommand::Compile { path },
+        }) => compile_rules(&path),
+        Some(Command::Models {
+            registry,
+            approval_key,
+            command,
+        }) => run_models_command(&registry, approval_key, command),
+        Some(Command::ValidateModel(args)) => validate_model(args).await,
+        Some(Command::BenchSymbolic {
+            facts,
+            program,
+            souffle,
+            iterations,
+            json,
+        }) => {
+            let report = bench_symbolic::run(&souffle, &program, &facts, iterations)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{}", report);
+            }
+            Ok(())
+        }
+        Some(Command::ValidateRules { cases, json }) => validate_rules(&cases, json).await,
+        Some(Command::Evaluate {
+            labels,
+            results,
+            positive,
+            bins,
+            report,
+        }) => {
+            let evaluation = evaluation::run(&labels, &results, &positive, bins)?;
+            if let Some(path) = report {
+                std::fs::write(&path, serde_json::to_vec_pretty(&evaluation)?)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+            }
+            print!("{}", evaluation);
+            Ok(())
+        }
+        Some(Command::Submit {
+            text,
+            content_hash,
+            content_object,
+            source_id,
+            image_url,
+            route,
+        }) => {
+            let text = text.unwrap_or_default();
+            let mut builder = model_pb::AnalysisInput::builder()
+                .content_hash(content_hash.unwrap_or_else(|| input::content_hash_of(&text)))
+                .content_text(text)
+                .source_id(source_id);
+            if let Some(object) = content_object {
+                let Some((bucket, name)) = object.split_once('/') else {
+                    anyhow::bail!("content object must be BUCKET/NAME: {}", object);
+                };
+                builder = builder.content_object(bucket, name);
+            }
+            if let Some(image_url) = image_url {
+                builder = builder.image_url(image_url);
+            }
+            submit(&cli.nats.config()?, route.as_deref(), builder.build()?).await
+        }
+        Some(Command::Canary {
+            key,
+            expected_verdict,
+            text,
+            interval_secs,
+            route,
+        }) => {
+            let nats = cli.nats.config()?;
+            let tenant = validation::tenant_of(canary::CANARY_SOURCE);
+            let subject = nats.input_subject(tenant, route.as_deref())?;
+            let client = nats.connect().await?;
+            canary::run_injector(
+                client,
+                &subject,
+                &key,
+                &text,
+                &expected_verdict,
+                Duration::from_secs(interval_secs),
+            )
+            .await
+        }
+        Some(Command::Replay(args)) => run_replay_command(cli.nats, args).await,
+        None => run_service(cli.nats).await,
+    }
+}
+
+async fn submit(
+    nats: &NatsConfig,
+    route: Option<&str>,
+    input: model_pb::AnalysisInput,
+) -> Result<()> {
+    use prost::Message;
+
+    let subject = nats.input_subject(validation::tenant_of(&input.source_id), route)?;
+    let client = nats.connect().await?;
+    jetstream::new(client)
+        .publish(subject, input.encode_to_vec().into())
+        .await
+        .context("Failed to publish input")?
+        .await
+        .context("Input was not acknowledged by JetStream")?;
+    println!("{}", input.content_hash);
+    Ok(())
+}
+
+/// Print the manifest of the rules the service would load
+fn rules_manifest() -> Result<()> {
+    let config = Config::from_env()?;
+    let metrics = Metrics::new()?;
+    let rules = live_rules::LiveRules::load(
+        &config.symbolic,
+        config.rules_dir.as_deref(),
+        &config.souffle_sandbox,
+        &metrics,
+    )?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&rules.current().manifest)?
+    );
+    Ok(())
+}
+
+fn compile_rules(path: &std::path::Path) -> Result<()> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    print!("{}", rule_dsl::compile(&source)?);
+    Ok(())
+}
+
+/// Run the rule test cases in `cases` through the rules and thresholds
+/// the service would use, failing if any case fails
+async fn validate_rules(cases: &std::path::Path, json: bool) -> Result<()> {
+    let config = Config::from_env()?;
+    let metrics = Metrics::new()?;
+    let rules = live_rules::LiveRules::load(
+        &config.symbolic,
+        config.rules_dir.as_deref(),
+        &config.souffle_sandbox,
+        &metrics,
+    )?;
+    let thresholds = thresholds::Thresholds::load(&config.bins, config.bins_file.as_deref())?;
+    let report = rule_tests::run(cases, &rules, &thresholds).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+    match report.failed() {
+        0 => Ok(()),
+        failed => anyhow::bail!("{} of {} rule tests failed", failed, report.cases.len()),
+    }
+}
+
+async fn validate_model(args: ValidateModelArgs) -> Result<()> {
+    if let Some(report) = args.sandbox_report {
+        let result = model_validation::evaluate(&args.model, &args.validation_set).await?;
+        std::fs::write(report, serde_json::to_vec(&result)?)?;
+        return Ok(());
+    }
+
+    // Enforced by clap outside the sandbox
+    let (Some(name), Some(version), Some(root), Some(key)) =
+        (args.name, args.version, args.registry, args.key)
+    else {
+        anyhow::bail!("--name, --version, --registry and --key are required");
+    };
+    let registry = model_registry::ModelRegistry::open(root)?;
+    let manifest = model_validation::approve(
+        &name,
+        &version,
+        &args.model,
+        &args.validation_set,
+        model_validation::Thresholds {
+            min_accuracy: args.min_accuracy,
+            min_precision: args.min_precision,
+            min_recall: args.min_recall,
+        },
+        &model_validation::SandboxLimits {
+            memory_mb: args.memory_limit_mb,
+            cpu_secs: args.cpu_limit_secs,
+            wall: Duration::from_secs(args.timeout_secs),
+        },
+        &key,
+    )
+    .await?;
+    registry.install(&args.model, &manifest)?;
+    println!("{}", serde_json::to_string_pretty(&manifest)?);
+    Ok(())
+}
+
+fn run_models_command(
+    root: &str,
+    approval_key: Option<String>,
+    command: ModelsCommand,
+) -> Result<()> {
+    let registry = model_registry::ModelRegistry::open(root)?.with_approval_key(approval_key);
+    match command {
+        ModelsCommand::List { name } => {
+            let active = registry.active(&name).ok();
+            for version in registry.versions(&name)? {
+                let marker = if active.as_deref() == Some(version.as_str()) {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{} {}", marker, version);
+            }
+        }
+        ModelsCommand::Activate { name, version } => {
+            registry.activate(&name, &version)?;
+            println!("{} -> {}", name, version);
+        }
+        ModelsCommand::Rollback { name } => {
+            let version = registry.rollback(&name)?;
+            println!("{} rolled back to {}", name, version);
+        }
+    }
+    Ok(())
+}
+
+async fn run_service(nats: NatsArgs) -> Result<()> {
     info!("Starting NSAI Detector Service (Rust Edition)");
 
+    let mut config = Config::from_env()?;
+    config.nats = nats.config()?;
+
+    // Fetch and verify remote models before anything depends on them
+    model_download::download_models(&mut config).await?;
+
     // Initialize ONNX runtime
     onnx_wrapper::init_runtime()?;
 
     // Initialize metrics
-    let metrics = Arc::new(Metrics::new()?);
+    let metrics = Arc::new(Metrics::new()?.with_cardinality(&config.metrics));
 
-    // Start metrics server
+    // Start metrics server; the pipeline graph is served once it is built
     let metrics_clone = Arc::clone(&metrics);
+    let graph = Arc::new(OnceLock::new());
+    let graph_clone = Arc::clone(&graph);
     tokio::spawn(async move {
-        if let Err(e) = run_metrics_server(metrics_clone).await {
+        if let Err(e) = run_metrics_server(metrics_clone, graph_clone).await {
             error!("Metrics server failed: {}", e);
         }
     });
 
+    if let Some(kafka) = config.kafka.clone() {
+        return run_kafka_service(config, metrics, graph, kafka).await;
+    }
+
     // Connect to NATS
-    let client = async_nats::connect(NATS_URL)
-        .await
-        .context("Failed to connect to NATS")?;
+    let nats = config.nats.clone();
+    let client = nats.connect_observed(Arc::clone(&metrics)).await?;
 
-    info!("Connected to NATS at {}", NATS_URL);
+    info!(
+        "Connected to NATS at {} as {}",
+        nats.servers.join(","),
+        nats.name
+    );
 
     // Get JetStream context
-    let jetstream = jetstream::new(client);
+    let jetstream = jetstream::new(client.clone());
 
     // Create or get the stream
     let stream = jetstream
         .get_or_create_stream(jetstream::stream::Config {
-            name: STREAM_NAME.to_string(),
-            subjects: vec![SUBJECT_INPUT.to_string()],
+            name: nats.stream.clone(),
+            subjects: nats.stream_subjects()?,
             ..Default::default()
         })
         .await
         .context("Failed to create stream")?;
 
-    // Create a pull consumer
-    let consumer: PullConsumer = stream
+    // Create a pull consumer, and one for urgent inputs; each takes only
+    // its own subject once the stream has both
+    let filter = |subject: &str| match &nats.priority_subject {
+        Some(_) => subject.to_string(),
+        None => String::new(),
+    };
+    let consumer = durable_consumer(
+        &stream,
+        &nats.consumer,
+        filter(&nats.subject),
+        &config.consumer,
+    )
+    .await
+    .context("Failed to create consumer")?;
+    let priority = match &nats.priority_subject {
+        Some(subject) => Some(
+            durable_consumer(
+                &stream,
+                &nats.priority_consumer,
+                filter(subject),
+                &config.consumer,
+            )
+            .await
+            .context("Failed to create priority consumer")?,
+        ),
+        None => None,
+    };
+
+    info!("Listening for messages on {}...", nats.subject);
+    if let Some(subject) = &nats.priority_subject {
+        info!("Taking urgent messages on {} first", subject);
+    }
+
+    let transport = Arc::new(NatsTransport::new(client.clone()));
+    let pipeline = Pipeline::new(config, metrics, transport).await?;
+    let _ = graph.set(pipeline.graph());
+
+    // Retune batch sizes from the consumer backlog
+    if let Some(batcher) = &pipeline.batcher {
+        tokio::spawn(batcher::run_autoscaler(
+            Arc::clone(batcher),
+            std::iter::once(consumer.clone())
+                .chain(priority.clone())
+                .collect(),
+            Arc::clone(&pipeline.metrics),
+        ));
+    }
+
+    // Revise recent verdicts when the facts of their sources change
+    if let (Some(reevaluator), Some(config)) =
+        (&pipeline.reevaluation, &pipeline.config.reevaluation)
+    {
+        let reevaluator = Arc::clone(reevaluator);
+        let client = client.clone();
+        let config = config.clone();
+        let subject = config.subject.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reevaluation::run_updates(reevaluator, client, config).await {
+                error!("Fact updates failed: {}", e);
+            }
+        });
+        info!("Fact updates enabled <- {}", subject);
+    }
+
+    start_pipeline(&pipeline, &nats.consumer).await?;
+
+    // Process messages until shutdown signal; their logs name the replica
+    let span = info_span!("replica", instance = %pipeline.config.instance);
+    let consumer = run_consumer(
+        consumer,
+        priority,
+        &pipeline,
+        Maintenance::new(pipeline.idle_tasks()),
+    )
+    .instrument(span.clone());
+    match &pipeline.config.request_reply {
+        Some(requests) => {
+            let requests = request_reply::serve(&pipeline, client, requests).instrument(span);
+            tokio::try_join!(consumer, requests).map(|_| ())
+        }
+        None => consumer.await,
+    }
+}
+
+/// Run the service on Kafka topics instead of NATS
+async fn run_kafka_service(
+    config: Config,
+    metrics: Arc<Metrics>,
+    graph: Arc<OnceLock<PipelineGraph>>,
+    kafka: transport::KafkaConfig,
+) -> Result<()> {
+    let transport = kafka::transport(&kafka)?;
+    let pipeline = Pipeline::new(config, metrics, Arc::clone(&transport)).await?;
+    let _ = graph.set(pipeline.graph());
+
+    start_pipeline(&pipeline, &kafka.group).await?;
+
+    let span = info_span!("replica", instance = %pipeline.config.instance);
+    kafka::run(&pipeline, transport, &kafka)
+        .instrument(span)
+        .await
+}
+
+/// Start the background tasks of `pipeline` and warm it up, then mark the
+/// replica ready as a member of `consumer`
+async fn start_pipeline(pipeline: &Pipeline, consumer: &str) -> Result<()> {
+    // Start research telemetry export (opt-in)
+    if let (Some(aggregator), Some(telemetry)) = (&pipeline.telemetry, &pipeline.config.telemetry) {
+        let exporter = telemetry::SignedHttpExporter::new(
+            telemetry.endpoint.clone(),
+            telemetry.signing_key.clone(),
+        )?;
+        tokio::spawn(telemetry::run_exporter(
+            Arc::clone(aggregator),
+            Box::new(exporter),
+            telemetry.interval,
+        ));
+        info!("Research telemetry enabled -> {}", telemetry.endpoint);
+    }
+
+    // Snapshot and verify the claim database on a schedule
+    if let Some(snapshots) = &pipeline.config.claims {
+        let source = claims::DgraphClaimSource::new(snapshots.dgraph_url.clone());
+        tokio::spawn(claims::run_snapshots(
+            Box::new(source),
+            snapshots.clone(),
+            Arc::clone(&pipeline.metrics),
+        ));
+        info!("Claim snapshots enabled -> {}", snapshots.dir.display());
+    }
+
+    // Keep the historical aggregates the rules see up to date
+    if let (Some(history), Some(config)) = (&pipeline.history, &pipeline.config.history) {
+        tokio::spawn(history::run_aggregator(
+            Arc::clone(history),
+            config.interval,
+            Arc::clone(&pipeline.metrics),
+        ));
+        info!("Verdict history enabled -> {}", config.path.display());
+    }
+
+    // Swap in changed rules without a restart
+    if pipeline.rules.reloadable() {
+        tokio::spawn(live_rules::run_reloader(
+            Arc::clone(&pipeline.rules),
+            pipeline.config.rules_reload,
+            Arc::clone(&pipeline.metrics),
+        ));
+    }
+
+    // Pick up new campaign indicators without a restart
+    if let Some(campaigns) = &pipeline.campaigns {
+        tokio::spawn(campaigns::run_reloader(
+            Arc::clone(campaigns),
+            Arc::clone(&pipeline.metrics),
+        ));
+    }
+
+    // Pick up new graph snapshots without a restart
+    if let Some(snapshot) = &pipeline.graph_snapshot {
+        tokio::spawn(graph_snapshot::run_reloader(
+            Arc::clone(snapshot),
+            Arc::clone(&pipeline.metrics),
+        ));
+    }
+
+    // Serve the similarity API for external tools
+    if let (Some(index), Some(similarity)) = (&pipeline.similarity, &pipeline.config.similarity) {
+        let index = Arc::clone(index);
+        let listen = similarity.listen;
+        tokio::spawn(async move {
+            if let Err(e) = api::run_api_server(listen, index).await {
+                error!("Similarity API failed: {}", e);
+            }
+        });
+    }
+
+    // Take the cold-start cost before real messages arrive
+    pipeline.warm_up().await?;
+    pipeline.metrics.ready.set(1.0);
+
+    pipeline
+        .metrics
+        .instance_info
+        .with_label_values(&[&pipeline.config.instance, consumer])
+        .set(1.0);
+    info!("Running as instance {}", pipeline.config.instance);
+    Ok(())
+}
+
+/// Get or create the durable pull consumer `name`, taking the messages of
+/// `filter_subject`, or of the whole stream when empty
+async fn durable_consumer(
+    stream: &Stream,
+    name: &str,
+    filter_subject: String,
+    config: &ConsumerConfig,
+) -> Result<PullConsumer> {
+    Ok(stream
         .get_or_create_consumer(
-            CONSUMER_NAME,
+            name,
             jetstream::consumer::pull::Config {
-                durable_name: Some(CONSUMER_NAME.to_string()),
+                durable_name: Some(name.to_string()),
+                filter_subject,
                 ack_policy: jetstream::consumer::AckPolicy::Explicit,
+                ack_wait: config.ack_wait,
+                max_ack_pending: config.max_ack_pending.unwrap_or_default(),
+                max_deliver: config.max_deliver.unwrap_or_default(),
                 deliver_policy: jetstream::consumer::DeliverPolicy::All,
                 ..Default::default()
             },
         )
-        .await
-        .context("Failed to create consumer")?;
-
-    info!("Listening for messages on {}...", SUBJECT_INPUT);
-
-    // Process messages until shutdown signal
-    run_consumer(consumer, stream, metrics).await
+        .await?)
 }
 
+/// Process the messages of `consumer` until shutdown
+///
+/// Messages of the `priority` consumer, if any, are taken first whenever
+/// there is room for one, so urgent inputs only wait for a free worker.
 async fn run_consumer(
     consumer: PullConsumer,
-    _stream: Stream,
-    metrics: Arc<Metrics>,
+    priority: Option<PullConsumer>,
+    pipeline: &Pipeline,
+    maintenance: Maintenance,
 ) -> Result<()> {
-    let mut messages = consumer
-        .messages()
-        .await
-        .context("Failed to get message stream")?;
+    let metrics = &pipeline.metrics;
+    let idle = &pipeline.config.idle;
+
+    // Messages are processed concurrently up to the worker count
+    let max_in_flight = pipeline.config.consumer.workers(&pipeline.config.inference);
+    metrics.in_flight_limit.set(max_in_flight as f64);
+    info!("Processing up to {} messages at once", max_in_flight);
+
+    let mut messages = pull_messages(&consumer, &pipeline.config.consumer, max_in_flight).await?;
+    let mut urgent = match &priority {
+        Some(priority) => {
+            Some(pull_messages(priority, &pipeline.config.consumer, max_in_flight).await?)
+        }
+        None => None,
+    };
+    let mut in_flight = FuturesUnordered::new();
+    // Pulling also stops at the bytes budget, so a slow dependency leaves
+    // the backlog in the stream rather than in memory
+    let mut budget = InFlight::new(pipeline.config.consumer.max_in_flight_bytes);
+
+    // Under memory pressure the guard sheds caches and lowers the limit
+    let mut guard = pipeline
+        .config
+        .memory
+        .clone()
+        .map(|c| MemoryGuard::new(c, max_in_flight, Arc::clone(metrics)));
+    let mut guard_checks = interval(guard.as_ref().map_or(Duration::MAX, MemoryGuard::interval));
+
+    let mut idle_deadline = Instant::now() + idle.idle_after;
+    let mut maintenance_done = false;
 
     loop {
+        let in_flight_limit = guard.as_ref().map_or(max_in_flight, MemoryGuard::in_flight);
+        // Biased, so a free worker goes to an urgent message first
         tokio::select! {
+            biased;
             _ = signal::ctrl_c() => {
                 info!("Shutting down gracefully...");
                 break;
             }
-            msg = messages.next() => {
+            _ = sleep_until(idle_deadline) => {
+                metrics.idle_heartbeats.inc();
+                info!("Consumer idle for {:?}", idle.idle_after);
+                // Maintenance runs once per idle period, heartbeats keep going
+                if idle.run_maintenance && !maintenance_done {
+                    let failures = maintenance.run_all().await;
+                    metrics.maintenance_failures.inc_by(failures as f64);
+                    maintenance_done = true;
+                }
+                idle_deadline = Instant::now() + idle.idle_after;
+            }
+            Some(bytes) = in_flight.next(), if !in_flight.is_empty() => {
+                budget.finish(bytes);
+                record_in_flight(metrics, &budget, in_flight_limit);
+                idle_deadline = Instant::now() + idle.idle_after;
+            }
+            _ = guard_checks.tick(), if guard.is_some() => {
+                if let Some(guard) = guard.as_mut() {
+                    match memory_guard::rss_bytes() {
+                        Ok(rss) => {
+                            if matches!(guard.check(rss), GuardAction::Shed | GuardAction::Throttle) {
+                                info!("Shed {} cache entries", pipeline.shed_caches());
+                            }
+                        }
+                        Err(e) => warn!("Memory check failed: {:#}", e),
+                    }
+                    record_in_flight(metrics, &budget, guard.in_flight());
+                }
+            }
+            Some(msg) = OptionFuture::from(urgent.as_mut().map(StreamExt::next)),
+                if budget.has_room(in_flight_limit) =>
+            {
+                idle_deadline = Instant::now() + idle.idle_after;
+                maintenance_done = false;
                 match msg {
                     Some(Ok(message)) => {
-                        info!("Pre-processing message: {}", message.subject);
-                        process_message(&message, &metrics).await;
-                        info!("Post-processing message: {}", message.subject);
+                        metrics.priority_messages.inc();
+                        budget.start(message.payload.len());
+                        in_flight.push(process(pipeline, message));
+                        record_in_flight(metrics, &budget, in_flight_limit);
                     }
                     Some(Err(e)) => {
-                        warn!("Message error: {}", e);
+                        warn!("Priority message error: {}", e);
                         metrics.errors.inc();
                     }
-                    None => {
-                        info!("Message stream ended");
-                        break;
+                    None => match idle.on_stream_end {
+                        StreamEndAction::Exit => {
+                            info!("Priority message stream ended");
+                            break;
+                        }
+                        StreamEndAction::Resubscribe => {
+                            warn!("Priority message stream ended, resubscribing");
+                            if let Some(priority) = &priority {
+                                match reopen_messages(priority, pipeline, max_in_flight).await {
+                                    Some(reopened) => urgent = Some(reopened),
+                                    None => break,
+                                }
+                            }
+                        }
+                    },
+                }
+            }
+            msg = messages.next(), if budget.has_room(in_flight_limit) => {
+                idle_deadline = Instant::now() + idle.idle_after;
+                maintenance_done = false;
+                match msg {
+                    Some(Ok(message)) => {
+                        budget.start(message.payload.len());
+                        in_flight.push(process(pipeline, message));
+                        record_in_flight(metrics, &budget, in_flight_limit);
+                    }
+                    Some(Err(e)) => {
+                        warn!("Message error: {}", e);
+                        metrics.errors.inc();
                     }
+                    None => match idle.on_stream_end {
+                        StreamEndAction::Exit => {
+                            info!("Message stream ended");
+                            break;
+                        }
+                        StreamEndAction::Resubscribe => {
+                            warn!("Message stream ended, resubscribing");
+                            match reopen_messages(&consumer, pipeline, max_in_flight).await {
+                                Some(reopened) => messages = reopened,
+                                None => break,
+                            }
+                        }
+                    },
                 }
             }
         }
     }
 
+    if !in_flight.is_empty() {
+        info!("Waiting for {} in-flight messages", in_flight.len());
+        while in_flight.next().await.is_some() {}
+    }
+
     Ok(())
 }
 
-async fn process_message(msg: &async_nats::jetstream::message::Message, metrics: &Metrics) {
-    let start = Instant::now();
-
-    // Parse protobuf message
-    let input = match AnalysisInput::decode(msg.payload.as_ref()) {
-        Ok(input) => input,
-        Err(e) => {
-            error!("Unmarshal error: {}", e);
-            metrics.errors.inc();
-            let _ = msg.ack().await;
-            return;
-        }
-    };
+/// Re-score the stream's messages from the given sequence or time up to
+/// its end as of start-up
+async fn run_replay_command(nats: NatsArgs, args: ReplayArgs) -> Result<()> {
+    let replay = args.config()?;
+    let mut config = Config::from_env()?;
+    config.nats = nats.config()?;
+    replay.apply(&mut config);
 
-    metrics.messages_processed.inc();
+    model_download::download_models(&mut config).await?;
+    onnx_wrapper::init_runtime()?;
+    let metrics = Arc::new(Metrics::new()?.with_cardinality(&config.metrics));
 
-    // Neuro-Symbolic Pipeline
-    let neural_features = match onnx_wrapper::run_inference(&input.content_hash).await {
-        Ok(features) => features,
-        Err(e) => {
-            error!("ONNX inference error: {}", e);
-            metrics.errors.inc();
-            let _ = msg.ack().await;
-            return;
+    let nats = config.nats.clone();
+    let client = nats.connect_observed(Arc::clone(&metrics)).await?;
+    let jetstream = jetstream::new(client.clone());
+    let mut stream = jetstream
+        .get_stream(&nats.stream)
+        .await
+        .with_context(|| format!("Failed to get stream {}", nats.stream))?;
+
+    let consumer_config = config.consumer.clone();
+    let transport = Arc::new(NatsTransport::new(client));
+    let pipeline = Pipeline::new(config, metrics, transport).await?;
+    pipeline.warm_up().await?;
+
+    // Messages arriving during the replay are left to the service
+    let end = stream
+        .info()
+        .await
+        .context("Failed to get stream info")?
+        .state
+        .last_sequence;
+
+    // Ephemeral: the server removes it should the replay die
+    let mut consumer: PullConsumer = stream
+        .create_consumer(jetstream::consumer::pull::Config {
+            ack_policy: jetstream::consumer::AckPolicy::Explicit,
+            ack_wait: consumer_config.ack_wait,
+            max_ack_pending: consumer_config.max_ack_pending.unwrap_or_default(),
+            max_deliver: consumer_config.max_deliver.unwrap_or_default(),
+            deliver_policy: replay.start.deliver_policy(),
+            inactive_threshold: Duration::from_secs(300),
+            ..Default::default()
+        })
+        .await
+        .context("Failed to create replay consumer")?;
+    let name = consumer.cached_info().name.clone();
+    info!(
+        "Replaying {} up to sequence {} as consumer {}{}",
+        nats.stream,
+        end,
+        name,
+        match &replay.shadow_subject {
+            Some(subject) => format!(", results to {}", subject),
+            None => String::new(),
         }
-    };
+    );
+
+    let outcome = run_replay(&mut consumer, &pipeline, end).await;
+    if let Err(e) = stream.delete_consumer(&name).await {
+        warn!("Replay consumer {} not deleted: {}", name, e);
+    }
+    outcome
+}
 
-    let dgraph_facts = fetch_dgraph_facts(&input.source_id).await;
+/// Process the messages of `consumer` up to stream sequence `end`
+async fn run_replay(consumer: &mut PullConsumer, pipeline: &Pipeline, end: u64) -> Result<()> {
+    let workers = pipeline.config.consumer.workers(&pipeline.config.inference);
+    let mut messages = pull_messages(consumer, &pipeline.config.consumer, workers).await?;
+    let mut in_flight = FuturesUnordered::new();
+    let mut budget = InFlight::new(pipeline.config.consumer.max_in_flight_bytes);
+    let mut checks = interval(Duration::from_secs(1));
+    let mut replayed = 0u64;
 
-    match souffle_wrapper::run_datalog(&neural_features, &dgraph_facts).await {
-        Ok((verdict, explanation)) => {
-            info!(
-                "Verdict for {}: {} | {}",
-                input.content_hash, verdict, explanation
-            );
+    loop {
+        tokio::select! {
+            _ = signal::ctrl_c() => {
+                info!("Replay interrupted");
+                break;
+            }
+            Some(bytes) = in_flight.next(), if !in_flight.is_empty() => budget.finish(bytes),
+            _ = checks.tick() => {
+                let info = consumer
+                    .info()
+                    .await
+                    .context("Failed to get replay consumer info")?;
+                if replay::caught_up(
+                    info.ack_floor.stream_sequence,
+                    info.num_pending,
+                    info.num_ack_pending,
+                    end,
+                ) {
+                    break;
+                }
+            }
+            msg = messages.next(), if budget.has_room(workers) => match msg {
+                Some(Ok(message)) => {
+                    let sequence = message.info().map_or(0, |info| info.stream_sequence);
+                    if sequence > end {
+                        continue;
+                    }
+                    replayed += 1;
+                    budget.start(message.payload.len());
+                    in_flight.push(process(pipeline, message));
+                }
+                Some(Err(e)) => {
+                    warn!("Message error: {}", e);
+                    pipeline.metrics.errors.inc();
+                }
+                None => {
+                    messages = pull_messages(consumer, &pipeline.config.consumer, workers).await?;
+                }
+            },
         }
-        Err(e) => {
-            error!("Souffle error: {}", e);
-            metrics.errors.inc();
+    }
+
+    while in_flight.next().await.is_some() {}
+    info!("Replayed {} deliveries", replayed);
+    Ok(())
+}
+
+/// Publish the in-flight gauges under the current message limit
+fn record_in_flight(metrics: &Metrics, in_flight: &InFlight, limit: usize) {
+    metrics.messages_in_flight.set(in_flight.messages() as f64);
+    metrics.in_flight_bytes.set(in_flight.bytes() as f64);
+    metrics
+        .in_flight_saturation
+        .set(in_flight.saturation(limit));
+}
+
+/// Process `message` under the trace context of its headers, resolving to
+/// its payload size
+fn process(pipeline: &Pipeline, message: jetstream::Message) -> impl Future<Output = usize> + '_ {
+    let bytes = message.payload.len();
+    let span = propagation::MessageContext::from_headers(message.headers.as_ref()).span();
+    info!(parent: &span, "Pre-processing message: {}", message.subject);
+    async move {
+        let consumer = &pipeline.config.consumer;
+        let work = pipeline.process_message(&message);
+        if consumer.ack_progress {
+            ack_progress::with_progress(&message, consumer.ack_wait, &pipeline.metrics, work).await;
+        } else {
+            work.await;
         }
+        info!("Post-processing message: {}", message.subject);
+        bytes
     }
+    .instrument(span)
+}
 
-    metrics.latency.observe(start.elapsed().as_secs_f64());
-    let _ = msg.ack().await;
+/// Open the message stream of `consumer` again after it ended
+///
+/// While the server is unreachable, e.g. restarting, opening fails; it is
+/// retried with a backoff of up to [`MAX_RESUBSCRIBE_DELAY`] until it
+/// succeeds, or `None` is returned on a shutdown signal.
+async fn reopen_messages(
+    consumer: &PullConsumer,
+    pipeline: &Pipeline,
+    workers: usize,
+) -> Option<pull::Stream> {
+    let metrics = &pipeline.metrics;
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match pull_messages(consumer, &pipeline.config.consumer, workers).await {
+            Ok(messages) => {
+                metrics
+                    .consumer_resubscribes
+                    .with_label_values(&["resubscribed"])
+                    .inc();
+                return Some(messages);
+            }
+            Err(e) => {
+                warn!("Resubscribing failed, retrying in {:?}: {:#}", delay, e);
+                metrics
+                    .consumer_resubscribes
+                    .with_label_values(&["failed"])
+                    .inc();
+            }
+        }
+        tokio::select! {
+            _ = signal::ctrl_c() => {
+                info!("Shutting down gracefully...");
+                return None;
+            }
+            _ = tokio::time::sleep(delay) => {}
+        }
+        delay = (delay * 2).min(MAX_RESUBSCRIBE_DELAY);
+    }
 }
 
-async fn fetch_dgraph_facts(_source_id: &str) -> std::collections::HashMap<String, String> {
-    // Placeholder: would query Dgraph for source reputation facts
-    let mut facts = std::collections::HashMap::new();
-    facts.insert("source_trusted".to_string(), "true".to_string());
-    facts
+/// Open the message stream of `consumer`
+///
+/// Messages are pulled in batches, by default as many as there are
+/// workers so no more wait unprocessed. A pull that finds the stream empty
+/// waits server-side until its expiry instead of polling.
+async fn pull_messages(
+    consumer: &PullConsumer,
+    config: &ConsumerConfig,
+    workers: usize,
+) -> Result<pull::Stream> {
+    let mut builder = consumer
+        .stream()
+        .max_messages_per_batch(config.fetch_size.unwrap_or(workers))
+        .expires(config.fetch_expiry)
+        .heartbeat(config.heartbeat.unwrap_or(config.fetch_expiry / 2));
+    if let Some(max_bytes) = config.fetch_max_bytes {
+        builder = builder.max_bytes_per_batch(max_bytes);
+    }
+    builder
+        .messages()
+        .await
+        .context("Failed to get message stream")
 }
 
-async fn run_metrics_server(metrics: Arc<Metrics>) -> Result<()> {
+async fn run_metrics_server(
+    metrics: Arc<Metrics>,
+    graph: Arc<OnceLock<PipelineGraph>>,
+) -> Result<()> {
     let addr = SocketAddr::from(([0, 0, 0, 0], METRICS_PORT));
     let listener = TcpListener::bind(addr).await?;
 
@@ -231,11 +1323,13 @@ async fn run_metrics_server(metrics: Arc<Metrics>) -> Result<()> {
         let (stream, _) = listener.accept().await?;
         let io = TokioIo::new(stream);
         let metrics = Arc::clone(&metrics);
+        let graph = Arc::clone(&graph);
 
         tokio::spawn(async move {
             let service = service_fn(move |req: Request<hyper::body::Incoming>| {
                 let metrics = Arc::clone(&metrics);
-                async move { handle_metrics_request(req, metrics) }
+                let graph = Arc::clone(&graph);
+                async move { handle_metrics_request(req, metrics, graph) }
             });
 
             if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
@@ -248,6 +1342,7 @@ async fn run_metrics_server(metrics: Arc<Metrics>) -> Result<()> {
 fn handle_metrics_request(
     req: Request<hyper::body::Incoming>,
     metrics: Arc<Metrics>,
+    graph: Arc<OnceLock<PipelineGraph>>,
 ) -> Result<Response<Full<Bytes>>, hyper::Error> {
     if req.uri().path() == "/metrics" {
         let encoder = TextEncoder::new();
@@ -259,6 +1354,39 @@ fn handle_metrics_request(
             .header("Content-Type", encoder.format_type())
             .body(Full::new(Bytes::from(buffer)))
             .unwrap())
+    } else if req.uri().path() == "/ready" {
+        // Not ready until models are warmed up and messages are consumed
+        let (status, body) = if metrics.ready.get() > 0.0 {
+            (200, "ready")
+        } else {
+            (503, "warming up")
+        };
+        Ok(Response::builder()
+            .status(status)
+            .body(Full::new(Bytes::from(body)))
+            .unwrap())
+    } else if let Some(format) = req
+        .uri()
+        .path()
+        .strip_prefix("/pipeline")
+        .filter(|f| f.is_empty() || *f == ".dot")
+    {
+        // Stages, flags, timeouts and model versions of this deployment
+        let Some(graph) = graph.get() else {
+            return Ok(Response::builder()
+                .status(503)
+                .body(Full::new(Bytes::from("pipeline not built yet")))
+                .unwrap());
+        };
+        let (content_type, body) = if format == ".dot" {
+            ("text/vnd.graphviz", graph.to_dot())
+        } else {
+            ("application/json", graph.to_json())
+        };
+        Ok(Response::builder()
+            .header("Content-Type", content_type)
+            .body(Full::new(Bytes::from(body)))
+            .unwrap())
     } else {
         Ok(Response::builder()
             .status(404)
@@ -267,4 +1395,4 @@ fn handle_metrics_request(
     }
 }
 
-use futures::StreamExt;
+use futures::{future::OptionFuture, stream::FuturesUnordered, StreamExt};
@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Maintenance tasks run while the consumer is idle
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::history::VerdictHistory;
+use crate::onnx_wrapper;
+
+/// A unit of housekeeping work executed when no messages are arriving
+pub trait IdleTask: Send + Sync {
+    /// Short name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Run the task once
+    fn run(&self) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Runs a probe inference and checks that the expected outputs come back
+pub struct ModelSelfTest;
+
+impl IdleTask for ModelSelfTest {
+    fn name(&self) -> &'static str {
+        "model-self-test"
+    }
+
+    fn run(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async {
+            let features = onnx_wrapper::run_inference("self-test").await?;
+            if !features.fakeness.is_finite() {
+                bail!("self-test inference returned a non-finite fakeness score");
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A cache holding entries past their TTL until they are looked up again
+pub trait Compact: Send + Sync {
+    /// Drop the expired entries
+    ///
+    /// # Returns
+    /// Number of entries dropped
+    fn compact(&self) -> usize;
+}
+
+/// Drops the expired entries of the in-memory caches, returning their
+/// memory before the next burst of traffic needs it
+pub struct CacheCompaction {
+    caches: Vec<Arc<dyn Compact>>,
+}
+
+impl CacheCompaction {
+    pub fn new(caches: Vec<Arc<dyn Compact>>) -> Self {
+        Self { caches }
+    }
+}
+
+impl IdleTask for CacheCompaction {
+    fn name(&self) -> &'static str {
+        "cache-compaction"
+    }
+
+    fn run(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async {
+            let dropped: usize = self.caches.iter().map(|cache| cache.compact()).sum();
+            info!("Dropped {} expired cache entries", dropped);
+            Ok(())
+        })
+    }
+}
+
+/// Writes the verdict history to its file, so a restart after a quiet
+/// period loses nothing recorded since the last aggregation
+pub struct HistorySnapshot(pub Arc<VerdictHistory>);
+
+impl IdleTask for HistorySnapshot {
+    fn name(&self) -> &'static str {
+        "history-snapshot"
+    }
+
+    fn run(&self) -> BoxFuture<'_, Result<()>> {
+        let history = Arc::clone(&self.0);
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || history.persist())
+                .await
+                .context("History snapshot panicked")?
+        })
+    }
+}
+
+/// Ordered set of idle tasks
+pub struct Maintenance {
+    tasks: Vec<Box<dyn IdleTask>>,
+}
+
+impl Maintenance {
+    pub fn new(tasks: Vec<Box<dyn IdleTask>>) -> Self {
+        Self { tasks }
+    }
+
+    /// Run every task, logging failures without aborting the rest
+    ///
+    /// # Returns
+    /// Number of tasks that failed
+    pub async fn run_all(&self) -> usize {
+        let mut failures = 0;
+        for task in &self.tasks {
+            match task.run().await {
+                Ok(()) => info!("Idle task {} completed", task.name()),
+                Err(e) => {
+                    warn!("Idle task {} failed: {}", task.name(), e);
+                    failures += 1;
+                }
+            }
+        }
+        failures
+    }
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self::new(vec![Box::new(ModelSelfTest)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Failing;
+
+    impl IdleTask for Failing {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn run(&self) -> BoxFuture<'_, Result<()>> {
+            Box::pin(async { bail!("boom") })
+        }
+    }
+
+    struct Expiring(std::sync::Mutex<usize>);
+
+    impl Compact for Expiring {
+        fn compact(&self) -> usize {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_compaction_compacts_every_cache() {
+        let caches: Vec<Arc<Expiring>> = vec![
+            Arc::new(Expiring(std::sync::Mutex::new(2))),
+            Arc::new(Expiring(std::sync::Mutex::new(3))),
+        ];
+        let task = CacheCompaction::new(
+            caches
+                .iter()
+                .map(|cache| Arc::clone(cache) as Arc<dyn Compact>)
+                .collect(),
+        );
+        task.run().await.unwrap();
+        assert!(caches.iter().all(|cache| cache.compact() == 0));
+    }
+
+    #[tokio::test]
+    async fn test_run_all_counts_failures() {
+        let maintenance = Maintenance::new(vec![Box::new(ModelSelfTest), Box::new(Failing)]);
+        assert_eq!(maintenance.run_all().await, 1);
+    }
+}
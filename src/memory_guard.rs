@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Memory guardrails
+//!
+//! Losing a pod to the OOM killer also loses its in-memory telemetry
+//! windows and cache warmth. The consumer checks resident memory on an
+//! interval: past the shed threshold the feature cache and similarity
+//! index are emptied; past the throttle threshold the number of messages
+//! in flight is also halved on every check. Once memory is back under the
+//! shed threshold, concurrency doubles back towards its configured maximum.
+
+use anyhow::{Context, Result};
+use std::{fs, sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+use crate::metrics::Metrics;
+
+/// Memory guard settings
+#[derive(Debug, Clone)]
+pub struct MemoryGuardConfig {
+    /// Memory available to the process, e.g. the container limit
+    pub limit_bytes: u64,
+    /// Fraction of the limit above which caches are shed
+    pub shed_ratio: f64,
+    /// Fraction of the limit above which concurrency is reduced
+    pub throttle_ratio: f64,
+    /// How often resident memory is checked
+    pub interval: Duration,
+}
+
+/// What a check asks the consumer to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardAction {
+    None,
+    /// Empty the in-memory caches
+    Shed,
+    /// Empty the caches and lower the in-flight limit
+    Throttle,
+    /// Raise the in-flight limit again
+    Restore,
+}
+
+/// Tracks memory pressure and the current in-flight limit
+pub struct MemoryGuard {
+    config: MemoryGuardConfig,
+    max_in_flight: usize,
+    in_flight: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl MemoryGuard {
+    pub fn new(config: MemoryGuardConfig, max_in_flight: usize, metrics: Arc<Metrics>) -> Self {
+        metrics.in_flight_limit.set(max_in_flight as f64);
+        Self {
+            config,
+            max_in_flight,
+            in_flight: max_in_flight,
+            metrics,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Messages that may currently be processed at once
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Evaluate one resident memory sample
+    pub fn check(&mut self, rss_bytes: u64) -> GuardAction {
+        self.metrics.memory_rss_bytes.set(rss_bytes as f64);
+        let usage = rss_bytes as f64 / self.config.limit_bytes as f64;
+        let action = if usage >= self.config.throttle_ratio {
+            self.in_flight = (self.in_flight / 2).max(1);
+            warn!(
+                "Memory at {:.0}% of limit: shedding caches, in-flight limit {}",
+                usage * 100.0,
+                self.in_flight
+            );
+            GuardAction::Throttle
+        } else if usage >= self.config.shed_ratio {
+            warn!("Memory at {:.0}% of limit: shedding caches", usage * 100.0);
+            GuardAction::Shed
+        } else if self.in_flight < self.max_in_flight {
+            self.in_flight = (self.in_flight * 2).min(self.max_in_flight);
+            info!(
+                "Memory at {:.0}% of limit: in-flight limit back to {}",
+                usage * 100.0,
+                self.in_flight
+            );
+            GuardAction::Restore
+        } else {
+            GuardAction::None
+        };
+
+        let label = match action {
+            GuardAction::Shed => "shed",
+            GuardAction::Throttle => "throttle",
+            GuardAction::Restore | GuardAction::None => "",
+        };
+        if !label.is_empty() {
+            self.metrics
+                .memory_guard_activations
+                .with_label_values(&[label])
+                .inc();
+        }
+        self.metrics.in_flight_limit.set(self.in_flight as f64);
+        action
+    }
+}
+
+/// Resident set size of this process
+pub fn rss_bytes() -> Result<u64> {
+    let statm =
+        fs::read_to_string("/proc/self/statm").context("Failed to read /proc/self/statm")?;
+    let pages: u64 = statm
+        .split_whitespace()
+        .nth(1)
+        .context("Malformed /proc/self/statm")?
+        .parse()?;
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(pages * page_size.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(max_in_flight: usize) -> MemoryGuard {
+        MemoryGuard::new(
+            MemoryGuardConfig {
+                limit_bytes: 1000,
+                shed_ratio: 0.8,
+                throttle_ratio: 0.9,
+                interval: Duration::from_secs(5),
+            },
+            max_in_flight,
+            Arc::new(Metrics::new().unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let mut guard = guard(8);
+        assert_eq!(guard.check(500), GuardAction::None);
+        assert_eq!(guard.check(850), GuardAction::Shed);
+        assert_eq!(guard.in_flight(), 8);
+        assert_eq!(guard.check(950), GuardAction::Throttle);
+        assert_eq!(guard.in_flight(), 4);
+        assert_eq!(
+            guard
+                .metrics
+                .memory_guard_activations
+                .with_label_values(&["shed"])
+                .get(),
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_throttle_and_restore() {
+        let mut guard = guard(8);
+        for _ in 0..5 {
+            guard.check(990);
+        }
+        assert_eq!(guard.in_flight(), 1);
+
+        // Shedding alone does not raise the limit again
+        assert_eq!(guard.check(850), GuardAction::Shed);
+        assert_eq!(guard.in_flight(), 1);
+
+        assert_eq!(guard.check(100), GuardAction::Restore);
+        assert_eq!(guard.in_flight(), 2);
+        guard.check(100);
+        guard.check(100);
+        assert_eq!(guard.in_flight(), 8);
+        assert_eq!(guard.check(100), GuardAction::None);
+    }
+
+    #[test]
+    fn test_rss_is_readable() {
+        assert!(rss_bytes().unwrap() > 0);
+    }
+}
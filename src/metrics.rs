@@ -0,0 +1,899 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Prometheus metrics for the detector service
+
+use anyhow::Result;
+use prometheus::{
+    Counter, CounterVec, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, Opts, Registry,
+};
+
+use crate::cardinality::{CardinalityConfig, GuardedSeries, LabelGuard};
+
+/// Per-model inference metrics, labeled by model name and version
+#[derive(Clone)]
+pub struct ModelMetrics {
+    pub latency: HistogramVec,
+    /// Distribution of each named output
+    pub scores: HistogramVec,
+    pub errors: CounterVec,
+}
+
+pub struct Metrics {
+    pub messages_processed: Counter,
+    pub priority_messages: Counter,
+    pub errors: Counter,
+    pub rejected: Counter,
+    pub content_fetch_failures: Counter,
+    pub latency: Histogram,
+    pub idle_heartbeats: Counter,
+    pub maintenance_failures: Counter,
+    pub inference_timeouts: Counter,
+    pub fallback_inferences: CounterVec,
+    pub transient_failures: CounterVec,
+    pub inference_queue_wait: Histogram,
+    pub inference_sessions: Gauge,
+    pub models: ModelMetrics,
+    pub ready: Gauge,
+    pub queue_depth: Gauge,
+    pub batch_size: Gauge,
+    pub batch_wait: Gauge,
+    pub feature_cache_hits: Counter,
+    pub feature_cache_misses: Counter,
+    pub feature_cache_entries: Gauge,
+    pub shadow_runs: Counter,
+    pub shadow_disagreements: Counter,
+    pub shadow_failures: Counter,
+    pub shadow_skipped: Counter,
+    pub topic_messages: CounterVec,
+    pub tenant_messages: CounterVec,
+    pub topic_comparisons: CounterVec,
+    pub topic_disagreements: CounterVec,
+    pub ocr_texts: Counter,
+    pub known_fake_images: Counter,
+    pub claim_matches: Counter,
+    pub campaign_matches: CounterVec,
+    pub campaign_reloads: CounterVec,
+    pub graph_snapshot_reloads: CounterVec,
+    pub lowcred_links: Counter,
+    pub enrichment_lookups: CounterVec,
+    pub canary_passed: Counter,
+    pub canary_failures: Counter,
+    pub canary_last_pass: Gauge,
+    pub claims: Gauge,
+    pub claim_snapshot_timestamp: Gauge,
+    pub claim_snapshot_failures: Counter,
+    pub memory_rss_bytes: Gauge,
+    pub memory_guard_activations: CounterVec,
+    pub in_flight_limit: Gauge,
+    pub messages_in_flight: Gauge,
+    pub in_flight_bytes: Gauge,
+    pub in_flight_saturation: Gauge,
+    pub ack_progress: Counter,
+    pub instance_info: GaugeVec,
+    pub nats_connected: Gauge,
+    pub nats_events: CounterVec,
+    pub nats_reconnects: Counter,
+    pub consumer_resubscribes: CounterVec,
+    pub quota_messages: GaugeVec,
+    pub quota_gpu_seconds: GaugeVec,
+    pub quota_exceeded: CounterVec,
+    pub label_overflows: CounterVec,
+    pub webhook_results: CounterVec,
+    pub webhook_retries: CounterVec,
+    pub rule_packs: GaugeVec,
+    pub rules_reloads: CounterVec,
+    pub shadow_rule_runs: CounterVec,
+    pub shadow_rule_verdicts: CounterVec,
+    pub reasoning_workers: Gauge,
+    pub reasoning_busy: Gauge,
+    pub reasoning_queued: Gauge,
+    pub reasoning_queue_wait: Histogram,
+    pub reasoning_rejected: Counter,
+    pub reasoning_cache_hits: Counter,
+    pub reasoning_cache_misses: Counter,
+    pub dedup_lookups: CounterVec,
+    pub verdict_store_writes: CounterVec,
+    pub requests: CounterVec,
+    pub reasoning_cache_entries: Gauge,
+    pub reevaluations: CounterVec,
+    pub reevaluation_tracked: Gauge,
+    pub souffle_runaways: CounterVec,
+    pub fact_dumps: CounterVec,
+    pub verdict_write_backs: CounterVec,
+    pub graph_cache_lookups: CounterVec,
+    pub graph_cache_entries: Gauge,
+    pub graph_batch_size: Histogram,
+    pub graph_breaker_open: Gauge,
+    pub graph_breaker_rejections: Counter,
+    /// Bounds the `tenant` label of the metrics above
+    pub tenants: LabelGuard,
+    pub registry: Registry,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+
+        let priority_messages = Counter::with_opts(Opts::new(
+            "nsai_priority_messages_total",
+            "Messages taken from the priority subject",
+        ))?;
+
+        let messages_processed = Counter::with_opts(Opts::new(
+            "nsai_messages_processed_total",
+            "Total number of messages processed",
+        ))?;
+
+        let errors = Counter::with_opts(Opts::new("nsai_errors_total", "Total number of errors"))?;
+
+        let rejected = Counter::with_opts(Opts::new(
+            "nsai_inputs_rejected_total",
+            "Number of inputs rejected by validation with a REJECTED result",
+        ))?;
+
+        let content_fetch_failures = Counter::with_opts(Opts::new(
+            "nsai_content_fetch_failures_total",
+            "Number of content retrievals by hash that failed after all retries",
+        ))?;
+
+        let latency = Histogram::with_opts(HistogramOpts::new(
+            "nsai_processing_latency_seconds",
+            "Latency of message processing",
+        ))?;
+
+        let idle_heartbeats = Counter::with_opts(Opts::new(
+            "nsai_idle_heartbeats_total",
+            "Number of idle intervals with no incoming messages",
+        ))?;
+
+        let maintenance_failures = Counter::with_opts(Opts::new(
+            "nsai_maintenance_failures_total",
+            "Number of failed idle maintenance tasks",
+        ))?;
+
+        let inference_timeouts = Counter::with_opts(Opts::new(
+            "nsai_inference_timeouts_total",
+            "Number of inferences cancelled after exceeding the deadline",
+        ))?;
+
+        let fallback_inferences = CounterVec::new(
+            Opts::new(
+                "nsai_fallback_inferences_total",
+                "Number of messages scored by the fallback after the primary failed",
+            ),
+            &["reason"],
+        )?;
+
+        let transient_failures = CounterVec::new(
+            Opts::new(
+                "nsai_transient_failures_total",
+                "Number of messages handed back or given up on after a transient failure",
+            ),
+            &["reason", "outcome"],
+        )?;
+
+        let inference_queue_wait = Histogram::with_opts(
+            HistogramOpts::new(
+                "nsai_inference_queue_wait_seconds",
+                "Time spent waiting for a free ONNX session",
+            )
+            .buckets(vec![0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+        )?;
+
+        let inference_sessions = Gauge::with_opts(Opts::new(
+            "nsai_inference_sessions",
+            "Number of ONNX sessions across all pooled models",
+        ))?;
+
+        let model_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "nsai_model_inference_seconds",
+                "Inference time of a single model, excluding the wait for a session",
+            )
+            .buckets(vec![
+                0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ]),
+            &["model", "version"],
+        )?;
+
+        let model_scores = HistogramVec::new(
+            HistogramOpts::new(
+                "nsai_model_output_score",
+                "Distribution of the scores a model outputs",
+            )
+            .buckets(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0]),
+            &["model", "version", "output"],
+        )?;
+
+        let model_errors = CounterVec::new(
+            Opts::new(
+                "nsai_model_errors_total",
+                "Number of failed inferences of a single model",
+            ),
+            &["model", "version"],
+        )?;
+
+        let ready = Gauge::with_opts(Opts::new(
+            "nsai_ready",
+            "1 once models are warmed up and messages are consumed, else 0",
+        ))?;
+
+        let queue_depth = Gauge::with_opts(Opts::new(
+            "nsai_queue_depth",
+            "Messages pending on the JetStream consumers",
+        ))?;
+
+        let batch_size = Gauge::with_opts(Opts::new(
+            "nsai_batch_size",
+            "Current maximum inference batch size chosen by the autoscaler",
+        ))?;
+
+        let batch_wait = Gauge::with_opts(Opts::new(
+            "nsai_batch_wait_seconds",
+            "Current time a batch waits to fill, chosen by the autoscaler",
+        ))?;
+
+        let feature_cache_hits = Counter::with_opts(Opts::new(
+            "nsai_feature_cache_hits_total",
+            "Number of inferences served from the feature cache",
+        ))?;
+
+        let feature_cache_misses = Counter::with_opts(Opts::new(
+            "nsai_feature_cache_misses_total",
+            "Number of feature cache lookups that required inference",
+        ))?;
+
+        let feature_cache_entries = Gauge::with_opts(Opts::new(
+            "nsai_feature_cache_entries",
+            "Number of entries held by the in-memory feature cache",
+        ))?;
+
+        let shadow_runs = Counter::with_opts(Opts::new(
+            "nsai_shadow_runs_total",
+            "Number of messages also evaluated by the shadow model",
+        ))?;
+
+        let shadow_disagreements = Counter::with_opts(Opts::new(
+            "nsai_shadow_disagreements_total",
+            "Number of shadow runs whose verdict differs from the primary",
+        ))?;
+
+        let shadow_failures = Counter::with_opts(Opts::new(
+            "nsai_shadow_failures_total",
+            "Number of failed or timed out shadow runs",
+        ))?;
+
+        let shadow_skipped = Counter::with_opts(Opts::new(
+            "nsai_shadow_skipped_total",
+            "Number of sampled messages not shadowed because all shadow runs were busy",
+        ))?;
+
+        let topic_messages = CounterVec::new(
+            Opts::new(
+                "nsai_topic_messages_total",
+                "Number of messages by classified topic and verdict",
+            ),
+            &["topic", "verdict"],
+        )?;
+
+        let tenant_messages = CounterVec::new(
+            Opts::new(
+                "nsai_tenant_messages_total",
+                "Number of messages by tenant and verdict",
+            ),
+            &["tenant", "verdict"],
+        )?;
+
+        let topic_comparisons = CounterVec::new(
+            Opts::new(
+                "nsai_topic_comparisons_total",
+                "Number of topic-routed messages also evaluated by the general models",
+            ),
+            &["topic"],
+        )?;
+
+        let topic_disagreements = CounterVec::new(
+            Opts::new(
+                "nsai_topic_disagreements_total",
+                "Number of topic comparisons whose general verdict differs",
+            ),
+            &["topic"],
+        )?;
+
+        let ocr_texts = Counter::with_opts(Opts::new(
+            "nsai_ocr_texts_total",
+            "Number of images whose extracted text was added to the analysis input",
+        ))?;
+
+        let known_fake_images = Counter::with_opts(Opts::new(
+            "nsai_known_fake_images_total",
+            "Number of images matching a known manipulated image",
+        ))?;
+
+        let claim_matches = Counter::with_opts(Opts::new(
+            "nsai_claim_matches_total",
+            "Number of fact-checks matched against claims of the content",
+        ))?;
+
+        let campaign_matches = CounterVec::new(
+            Opts::new(
+                "nsai_campaign_matches_total",
+                "Number of messages matching a known campaign by campaign",
+            ),
+            &["campaign"],
+        )?;
+
+        let lowcred_links = Counter::with_opts(Opts::new(
+            "nsai_lowcred_links_total",
+            "Number of low-credibility domains linked to by the content",
+        ))?;
+
+        let campaign_reloads = CounterVec::new(
+            Opts::new(
+                "nsai_campaign_reloads_total",
+                "Number of campaign indicator reloads by outcome",
+            ),
+            &["outcome"],
+        )?;
+
+        let enrichment_lookups = CounterVec::new(
+            Opts::new(
+                "nsai_enrichment_lookups_total",
+                "Number of source metadata lookups by provider and outcome",
+            ),
+            &["provider", "outcome"],
+        )?;
+
+        let graph_snapshot_reloads = CounterVec::new(
+            Opts::new(
+                "nsai_graph_snapshot_reloads_total",
+                "Number of knowledge graph snapshot reloads by outcome",
+            ),
+            &["outcome"],
+        )?;
+
+        let canary_passed = Counter::with_opts(Opts::new(
+            "nsai_canary_passed_total",
+            "Number of canaries with the expected verdict within the latency SLO",
+        ))?;
+
+        let canary_failures = Counter::with_opts(Opts::new(
+            "nsai_canary_failures_total",
+            "Number of canaries with a wrong verdict or over the latency SLO",
+        ))?;
+
+        let canary_last_pass = Gauge::with_opts(Opts::new(
+            "nsai_canary_last_pass_timestamp_seconds",
+            "Injection time of the most recent canary that passed",
+        ))?;
+
+        let claims = Gauge::with_opts(Opts::new(
+            "nsai_claims",
+            "Number of claims in the last verified snapshot",
+        ))?;
+
+        let claim_snapshot_timestamp = Gauge::with_opts(Opts::new(
+            "nsai_claim_snapshot_timestamp_seconds",
+            "Unix time of the last verified claim database snapshot",
+        ))?;
+
+        let claim_snapshot_failures = Counter::with_opts(Opts::new(
+            "nsai_claim_snapshot_failures_total",
+            "Number of claim snapshots that failed or did not verify",
+        ))?;
+
+        let memory_rss_bytes = Gauge::with_opts(Opts::new(
+            "nsai_memory_rss_bytes",
+            "Resident memory at the last memory guard check",
+        ))?;
+
+        let memory_guard_activations = CounterVec::new(
+            Opts::new(
+                "nsai_memory_guard_activations_total",
+                "Number of memory guard checks that shed caches or throttled",
+            ),
+            &["action"],
+        )?;
+
+        let in_flight_limit = Gauge::with_opts(Opts::new(
+            "nsai_in_flight_limit",
+            "Messages currently allowed in flight",
+        ))?;
+
+        let messages_in_flight = Gauge::with_opts(Opts::new(
+            "nsai_messages_in_flight",
+            "Messages currently being processed",
+        ))?;
+
+        let in_flight_bytes = Gauge::with_opts(Opts::new(
+            "nsai_in_flight_bytes",
+            "Payload bytes of the messages currently being processed",
+        ))?;
+
+        let in_flight_saturation = Gauge::with_opts(Opts::new(
+            "nsai_in_flight_saturation",
+            "Use of the tighter in-flight budget, 1 while pulling is stopped",
+        ))?;
+
+        let ack_progress = Counter::with_opts(Opts::new(
+            "nsai_ack_progress_total",
+            "Number of in-progress acknowledgements sent for long analyses",
+        ))?;
+
+        let nats_connected = Gauge::with_opts(Opts::new(
+            "nsai_nats_connected",
+            "Whether the NATS client is connected (1) or not (0)",
+        ))?;
+
+        let nats_events = CounterVec::new(
+            Opts::new("nsai_nats_events_total", "Number of NATS connection events"),
+            &["event"],
+        )?;
+
+        let nats_reconnects = Counter::with_opts(Opts::new(
+            "nsai_nats_reconnects_total",
+            "Number of reconnections to NATS after a disconnect",
+        ))?;
+
+        let consumer_resubscribes = CounterVec::new(
+            Opts::new(
+                "nsai_consumer_resubscribes_total",
+                "Number of attempts to reopen an ended message stream",
+            ),
+            &["outcome"],
+        )?;
+
+        let instance_info = GaugeVec::new(
+            Opts::new(
+                "nsai_instance_info",
+                "Identity of this replica and the durable consumer it shares; always 1",
+            ),
+            &["instance", "consumer"],
+        )?;
+
+        let quota_messages = GaugeVec::new(
+            Opts::new(
+                "nsai_quota_messages",
+                "Messages counted against each tenant's quota today",
+            ),
+            &["tenant"],
+        )?;
+
+        let quota_gpu_seconds = GaugeVec::new(
+            Opts::new(
+                "nsai_quota_gpu_seconds",
+                "Inference seconds counted against each tenant's quota today",
+            ),
+            &["tenant"],
+        )?;
+
+        let quota_exceeded = CounterVec::new(
+            Opts::new(
+                "nsai_quota_exceeded_total",
+                "Number of messages over quota, by overflow action",
+            ),
+            &["tenant", "action"],
+        )?;
+
+        let webhook_results = CounterVec::new(
+            Opts::new(
+                "nsai_webhook_results_total",
+                "Number of results sent to webhooks, by destination and outcome",
+            ),
+            &["destination", "outcome"],
+        )?;
+
+        let webhook_retries = CounterVec::new(
+            Opts::new(
+                "nsai_webhook_retries_total",
+                "Number of webhook batch deliveries retried, by destination",
+            ),
+            &["destination"],
+        )?;
+
+        let rule_packs = GaugeVec::new(
+            Opts::new(
+                "nsai_rule_packs",
+                "Active rule packs, 1 per pack and content hash",
+            ),
+            &["pack", "sha256"],
+        )?;
+
+        let rules_reloads = CounterVec::new(
+            Opts::new(
+                "nsai_rules_reloads_total",
+                "Number of rules reloads, by outcome",
+            ),
+            &["outcome"],
+        )?;
+
+        let shadow_rule_runs = CounterVec::new(
+            Opts::new(
+                "nsai_shadow_rule_runs_total",
+                "Number of shadow rule pack evaluations, by pack and outcome",
+            ),
+            &["pack", "outcome"],
+        )?;
+
+        let shadow_rule_verdicts = CounterVec::new(
+            Opts::new(
+                "nsai_shadow_rule_verdicts_total",
+                "Would-be verdicts of shadow rule packs, by pack and verdict",
+            ),
+            &["pack", "verdict"],
+        )?;
+
+        let reasoning_workers = Gauge::with_opts(Opts::new(
+            "nsai_reasoning_workers",
+            "Number of rule evaluations that may run at once",
+        ))?;
+
+        let reasoning_busy = Gauge::with_opts(Opts::new(
+            "nsai_reasoning_busy",
+            "Number of rule evaluations running",
+        ))?;
+
+        let reasoning_queued = Gauge::with_opts(Opts::new(
+            "nsai_reasoning_queued",
+            "Number of rule evaluations waiting for a worker",
+        ))?;
+
+        let reasoning_queue_wait = Histogram::with_opts(
+            HistogramOpts::new(
+                "nsai_reasoning_queue_wait_seconds",
+                "Time spent waiting for a free reasoning worker",
+            )
+            .buckets(vec![0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+        )?;
+
+        let reasoning_rejected = Counter::with_opts(Opts::new(
+            "nsai_reasoning_rejected_total",
+            "Number of rule evaluations rejected because the reasoning queue was full",
+        ))?;
+
+        let reasoning_cache_hits = Counter::with_opts(Opts::new(
+            "nsai_reasoning_cache_hits_total",
+            "Number of derivations served from the reasoning cache",
+        ))?;
+
+        let reasoning_cache_misses = Counter::with_opts(Opts::new(
+            "nsai_reasoning_cache_misses_total",
+            "Number of rule evaluations not found in the reasoning cache",
+        ))?;
+
+        let dedup_lookups = CounterVec::new(
+            Opts::new(
+                "nsai_dedup_lookups_total",
+                "Number of inputs checked against the results already published",
+            ),
+            &["outcome"],
+        )?;
+
+        let requests = CounterVec::new(
+            Opts::new(
+                "nsai_requests_total",
+                "Number of analysis requests answered",
+            ),
+            &["outcome"],
+        )?;
+
+        let verdict_store_writes = CounterVec::new(
+            Opts::new(
+                "nsai_verdict_store_writes_total",
+                "Number of verdicts written to the verdict bucket",
+            ),
+            &["outcome"],
+        )?;
+
+        let reasoning_cache_entries = Gauge::with_opts(Opts::new(
+            "nsai_reasoning_cache_entries",
+            "Number of derivations held by the reasoning cache",
+        ))?;
+
+        let reevaluations = CounterVec::new(
+            Opts::new(
+                "nsai_reevaluations_total",
+                "Number of recent verdicts re-evaluated after a fact update, by outcome",
+            ),
+            &["outcome"],
+        )?;
+
+        let souffle_runaways = CounterVec::new(
+            Opts::new(
+                "nsai_souffle_runaways_total",
+                "Number of Soufflé runs killed for exceeding a sandbox limit, by reason",
+            ),
+            &["reason"],
+        )?;
+
+        let reevaluation_tracked = Gauge::with_opts(Opts::new(
+            "nsai_reevaluation_tracked",
+            "Number of recent verdicts kept for re-evaluation",
+        ))?;
+
+        let fact_dumps = CounterVec::new(
+            Opts::new(
+                "nsai_fact_dumps_total",
+                "Number of fact dumps for debugging by outcome",
+            ),
+            &["outcome"],
+        )?;
+
+        let verdict_write_backs = CounterVec::new(
+            Opts::new(
+                "nsai_verdict_write_backs_total",
+                "Number of verdicts written back to the knowledge graph by outcome",
+            ),
+            &["outcome"],
+        )?;
+
+        let graph_cache_lookups = CounterVec::new(
+            Opts::new(
+                "nsai_graph_cache_lookups_total",
+                "Number of source lookups by graph cache result (hit, stale or miss)",
+            ),
+            &["result"],
+        )?;
+
+        let graph_cache_entries = Gauge::with_opts(Opts::new(
+            "nsai_graph_cache_entries",
+            "Number of sources held by the graph cache",
+        ))?;
+
+        let graph_batch_size = Histogram::with_opts(
+            HistogramOpts::new(
+                "nsai_graph_batch_size",
+                "Distinct sources fetched per batched knowledge graph lookup",
+            )
+            .buckets(vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0]),
+        )?;
+
+        let graph_breaker_open = Gauge::with_opts(Opts::new(
+            "nsai_graph_breaker_open",
+            "Whether the knowledge graph circuit breaker is open (1) or closed (0)",
+        ))?;
+
+        let graph_breaker_rejections = Counter::with_opts(Opts::new(
+            "nsai_graph_breaker_rejections_total",
+            "Number of knowledge graph calls failed at once by the open circuit breaker",
+        ))?;
+
+        let label_overflows = CounterVec::new(
+            Opts::new(
+                "nsai_metric_label_overflows_total",
+                "Number of observations recorded under the `other` label value",
+            ),
+            &["label"],
+        )?;
+        let defaults = CardinalityConfig::default();
+        let tenants = LabelGuard::new(
+            "tenant",
+            defaults.label_limit,
+            defaults.tenant_allowlist,
+            label_overflows.clone(),
+        )
+        .with_series(tenant_series(
+            &tenant_messages,
+            &quota_messages,
+            &quota_gpu_seconds,
+            &quota_exceeded,
+        ));
+
+        registry.register(Box::new(messages_processed.clone()))?;
+        registry.register(Box::new(priority_messages.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(rejected.clone()))?;
+        registry.register(Box::new(content_fetch_failures.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        registry.register(Box::new(idle_heartbeats.clone()))?;
+        registry.register(Box::new(maintenance_failures.clone()))?;
+        registry.register(Box::new(inference_timeouts.clone()))?;
+        registry.register(Box::new(fallback_inferences.clone()))?;
+        registry.register(Box::new(transient_failures.clone()))?;
+        registry.register(Box::new(inference_queue_wait.clone()))?;
+        registry.register(Box::new(inference_sessions.clone()))?;
+        registry.register(Box::new(model_latency.clone()))?;
+        registry.register(Box::new(model_scores.clone()))?;
+        registry.register(Box::new(model_errors.clone()))?;
+        registry.register(Box::new(ready.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(batch_size.clone()))?;
+        registry.register(Box::new(batch_wait.clone()))?;
+        registry.register(Box::new(feature_cache_hits.clone()))?;
+        registry.register(Box::new(feature_cache_misses.clone()))?;
+        registry.register(Box::new(feature_cache_entries.clone()))?;
+        registry.register(Box::new(shadow_runs.clone()))?;
+        registry.register(Box::new(shadow_disagreements.clone()))?;
+        registry.register(Box::new(shadow_failures.clone()))?;
+        registry.register(Box::new(shadow_skipped.clone()))?;
+        registry.register(Box::new(topic_messages.clone()))?;
+        registry.register(Box::new(tenant_messages.clone()))?;
+        registry.register(Box::new(topic_comparisons.clone()))?;
+        registry.register(Box::new(topic_disagreements.clone()))?;
+        registry.register(Box::new(ocr_texts.clone()))?;
+        registry.register(Box::new(known_fake_images.clone()))?;
+        registry.register(Box::new(claim_matches.clone()))?;
+        registry.register(Box::new(campaign_matches.clone()))?;
+        registry.register(Box::new(campaign_reloads.clone()))?;
+        registry.register(Box::new(graph_snapshot_reloads.clone()))?;
+        registry.register(Box::new(lowcred_links.clone()))?;
+        registry.register(Box::new(enrichment_lookups.clone()))?;
+        registry.register(Box::new(canary_passed.clone()))?;
+        registry.register(Box::new(canary_failures.clone()))?;
+        registry.register(Box::new(canary_last_pass.clone()))?;
+        registry.register(Box::new(claims.clone()))?;
+        registry.register(Box::new(claim_snapshot_timestamp.clone()))?;
+        registry.register(Box::new(claim_snapshot_failures.clone()))?;
+        registry.register(Box::new(memory_rss_bytes.clone()))?;
+        registry.register(Box::new(memory_guard_activations.clone()))?;
+        registry.register(Box::new(in_flight_limit.clone()))?;
+        registry.register(Box::new(messages_in_flight.clone()))?;
+        registry.register(Box::new(in_flight_bytes.clone()))?;
+        registry.register(Box::new(in_flight_saturation.clone()))?;
+        registry.register(Box::new(ack_progress.clone()))?;
+        registry.register(Box::new(instance_info.clone()))?;
+        registry.register(Box::new(nats_connected.clone()))?;
+        registry.register(Box::new(nats_events.clone()))?;
+        registry.register(Box::new(nats_reconnects.clone()))?;
+        registry.register(Box::new(consumer_resubscribes.clone()))?;
+        registry.register(Box::new(quota_messages.clone()))?;
+        registry.register(Box::new(quota_gpu_seconds.clone()))?;
+        registry.register(Box::new(quota_exceeded.clone()))?;
+        registry.register(Box::new(label_overflows.clone()))?;
+        registry.register(Box::new(webhook_results.clone()))?;
+        registry.register(Box::new(webhook_retries.clone()))?;
+        registry.register(Box::new(rule_packs.clone()))?;
+        registry.register(Box::new(rules_reloads.clone()))?;
+        registry.register(Box::new(shadow_rule_runs.clone()))?;
+        registry.register(Box::new(shadow_rule_verdicts.clone()))?;
+        registry.register(Box::new(reasoning_workers.clone()))?;
+        registry.register(Box::new(reasoning_busy.clone()))?;
+        registry.register(Box::new(reasoning_queued.clone()))?;
+        registry.register(Box::new(reasoning_queue_wait.clone()))?;
+        registry.register(Box::new(reasoning_rejected.clone()))?;
+        registry.register(Box::new(reasoning_cache_hits.clone()))?;
+        registry.register(Box::new(reasoning_cache_misses.clone()))?;
+        registry.register(Box::new(dedup_lookups.clone()))?;
+        registry.register(Box::new(verdict_store_writes.clone()))?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(reasoning_cache_entries.clone()))?;
+        registry.register(Box::new(reevaluations.clone()))?;
+        registry.register(Box::new(souffle_runaways.clone()))?;
+        registry.register(Box::new(reevaluation_tracked.clone()))?;
+        registry.register(Box::new(fact_dumps.clone()))?;
+        registry.register(Box::new(verdict_write_backs.clone()))?;
+        registry.register(Box::new(graph_cache_lookups.clone()))?;
+        registry.register(Box::new(graph_cache_entries.clone()))?;
+        registry.register(Box::new(graph_batch_size.clone()))?;
+        registry.register(Box::new(graph_breaker_open.clone()))?;
+        registry.register(Box::new(graph_breaker_rejections.clone()))?;
+
+        Ok(Self {
+            messages_processed,
+            priority_messages,
+            errors,
+            rejected,
+            content_fetch_failures,
+            latency,
+            idle_heartbeats,
+            maintenance_failures,
+            inference_timeouts,
+            fallback_inferences,
+            transient_failures,
+            inference_queue_wait,
+            inference_sessions,
+            models: ModelMetrics {
+                latency: model_latency,
+                scores: model_scores,
+                errors: model_errors,
+            },
+            ready,
+            queue_depth,
+            batch_size,
+            batch_wait,
+            feature_cache_hits,
+            feature_cache_misses,
+            feature_cache_entries,
+            shadow_runs,
+            shadow_disagreements,
+            shadow_failures,
+            shadow_skipped,
+            topic_messages,
+            tenant_messages,
+            topic_comparisons,
+            topic_disagreements,
+            ocr_texts,
+            known_fake_images,
+            claim_matches,
+            campaign_matches,
+            campaign_reloads,
+            graph_snapshot_reloads,
+            lowcred_links,
+            enrichment_lookups,
+            canary_passed,
+            canary_failures,
+            canary_last_pass,
+            claims,
+            claim_snapshot_timestamp,
+            claim_snapshot_failures,
+            memory_rss_bytes,
+            memory_guard_activations,
+            in_flight_limit,
+            messages_in_flight,
+            in_flight_bytes,
+            in_flight_saturation,
+            ack_progress,
+            instance_info,
+            nats_connected,
+            nats_events,
+            nats_reconnects,
+            consumer_resubscribes,
+            quota_messages,
+            quota_gpu_seconds,
+            quota_exceeded,
+            label_overflows,
+            webhook_results,
+            webhook_retries,
+            rule_packs,
+            rules_reloads,
+            shadow_rule_runs,
+            shadow_rule_verdicts,
+            reasoning_workers,
+            reasoning_busy,
+            reasoning_queued,
+            reasoning_queue_wait,
+            reasoning_rejected,
+            reasoning_cache_hits,
+            reasoning_cache_misses,
+            dedup_lookups,
+            verdict_store_writes,
+            requests,
+            reasoning_cache_entries,
+            reevaluations,
+            reevaluation_tracked,
+            souffle_runaways,
+            fact_dumps,
+            verdict_write_backs,
+            graph_cache_lookups,
+            graph_cache_entries,
+            graph_batch_size,
+            graph_breaker_open,
+            graph_breaker_rejections,
+            tenants,
+            registry,
+        })
+    }
+
+    /// Apply the configured label budgets
+    pub fn with_cardinality(mut self, config: &CardinalityConfig) -> Self {
+        self.tenants = LabelGuard::new(
+            "tenant",
+            config.label_limit,
+            config.tenant_allowlist.clone(),
+            self.label_overflows.clone(),
+        )
+        .with_series(tenant_series(
+            &self.tenant_messages,
+            &self.quota_messages,
+            &self.quota_gpu_seconds,
+            &self.quota_exceeded,
+        ));
+        self
+    }
+}
+
+/// Metrics labeled by tenant, cleared of the tenants ranked out
+fn tenant_series(
+    messages: &CounterVec,
+    quota_messages: &GaugeVec,
+    quota_gpu_seconds: &GaugeVec,
+    quota_exceeded: &CounterVec,
+) -> Vec<Box<dyn GuardedSeries>> {
+    vec![
+        Box::new(messages.clone()),
+        Box::new(quota_messages.clone()),
+        Box::new(quota_gpu_seconds.clone()),
+        Box::new(quota_exceeded.clone()),
+    ]
+}
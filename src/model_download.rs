@@ -0,0 +1,306 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Download of remote models at startup
+//!
+//! A model path may be an `https://` or `s3://<bucket>/<key>` URI instead
+//! of a local file, followed by a `#sha256=<hex>` checksum, e.g.
+//! `detector:s3://models/detector.onnx#sha256=9f86...`. Before the service
+//! starts, every remote model is downloaded into a local cache keyed by its
+//! checksum and verified; a mismatch refuses startup. Verified cache
+//! entries are reused, so a restart only downloads models that changed.
+
+use anyhow::{bail, Context, Result};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::{fmt, fs, path::PathBuf, time::Duration};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::content_store::{ContentStore, S3ContentStore};
+use crate::onnx_wrapper::ModelSpec;
+
+/// Remote model download settings
+#[derive(Debug, Clone)]
+pub struct ModelDownloadConfig {
+    /// Directory verified downloads are cached in
+    pub cache_dir: PathBuf,
+    /// Deadline for downloading a single model
+    pub timeout: Duration,
+    /// S3 endpoint and credentials for `s3://` models
+    pub s3_endpoint: String,
+    pub s3_region: String,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+}
+
+/// Where a remote model is downloaded from
+#[derive(Debug, Clone, PartialEq)]
+enum ModelSource {
+    Https(Url),
+    S3 { bucket: String, key: String },
+}
+
+impl fmt::Display for ModelSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Https(url) => write!(f, "{}", url),
+            Self::S3 { bucket, key } => write!(f, "s3://{}/{}", bucket, key),
+        }
+    }
+}
+
+/// A model path naming a remote file and its expected checksum
+#[derive(Debug, Clone, PartialEq)]
+struct RemoteModel {
+    source: ModelSource,
+    /// Lowercase hex SHA-256
+    sha256: String,
+}
+
+impl RemoteModel {
+    /// Parse a model path, `None` for local files
+    fn parse(path: &str) -> Result<Option<Self>> {
+        if !path.contains("://") {
+            return Ok(None);
+        }
+        let sha256 = path
+            .split_once('#')
+            .and_then(|(_, fragment)| fragment.strip_prefix("sha256="))
+            .map(str::to_lowercase)
+            .with_context(|| format!("Remote model {} needs a #sha256=<hex> checksum", path))?;
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Invalid SHA-256 checksum for remote model {}", path);
+        }
+
+        let mut url = Url::parse(path).with_context(|| format!("Invalid model URI: {}", path))?;
+        url.set_fragment(None);
+        let source = match url.scheme() {
+            "https" => ModelSource::Https(url),
+            "s3" => {
+                let bucket = url
+                    .host_str()
+                    .with_context(|| format!("S3 model URI has no bucket: {}", path))?;
+                let key = url.path().trim_start_matches('/');
+                if key.is_empty() {
+                    bail!("S3 model URI has no key: {}", path);
+                }
+                ModelSource::S3 {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                }
+            }
+            other => bail!("Unsupported model URI scheme {}: {}", other, path),
+        };
+        Ok(Some(Self { source, sha256 }))
+    }
+}
+
+/// Downloads remote models into the local cache
+pub struct ModelDownloader {
+    config: ModelDownloadConfig,
+    client: reqwest::Client,
+}
+
+impl ModelDownloader {
+    pub fn new(config: ModelDownloadConfig) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(config.timeout).build()?,
+            config,
+        })
+    }
+
+    /// A spec whose path is a verified local file
+    ///
+    /// Local specs are returned unchanged.
+    pub async fn localize(&self, spec: &ModelSpec) -> Result<ModelSpec> {
+        let Some(remote) = RemoteModel::parse(&spec.path)? else {
+            return Ok(spec.clone());
+        };
+        let cached = self
+            .config
+            .cache_dir
+            .join(format!("{}.onnx", remote.sha256));
+        let localized = ModelSpec {
+            path: cached.to_string_lossy().to_string(),
+            ..spec.clone()
+        };
+
+        if let Ok(bytes) = fs::read(&cached) {
+            if verify(&bytes, &remote.sha256).is_ok() {
+                info!("Using cached model {} from {}", spec.name, cached.display());
+                return Ok(localized);
+            }
+            warn!(
+                "Cached model {} is corrupt, downloading again",
+                cached.display()
+            );
+        }
+
+        info!("Downloading model {} from {}", spec.name, remote.source);
+        let bytes = self
+            .download(&remote.source)
+            .await
+            .with_context(|| format!("Failed to download model {}", spec.name))?;
+        verify(&bytes, &remote.sha256)
+            .with_context(|| format!("Refusing model {} from {}", spec.name, remote.source))?;
+
+        // Write-then-rename so a crash never leaves a partial cache entry
+        fs::create_dir_all(&self.config.cache_dir).with_context(|| {
+            format!(
+                "Failed to create model cache {}",
+                self.config.cache_dir.display()
+            )
+        })?;
+        let tmp = cached.with_extension("onnx.tmp");
+        fs::write(&tmp, &bytes)?;
+        fs::rename(&tmp, &cached)?;
+        info!(
+            "Cached model {} ({} bytes) at {}",
+            spec.name,
+            bytes.len(),
+            cached.display()
+        );
+        Ok(localized)
+    }
+
+    async fn download(&self, source: &ModelSource) -> Result<Vec<u8>> {
+        match source {
+            ModelSource::Https(url) => {
+                let response = self
+                    .client
+                    .get(url.clone())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(response.bytes().await?.to_vec())
+            }
+            ModelSource::S3 { bucket, key } => {
+                let (Some(access_key), Some(secret_key)) =
+                    (&self.config.access_key, &self.config.secret_key)
+                else {
+                    bail!("S3 models need AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY");
+                };
+                S3ContentStore::new(
+                    &self.config.s3_endpoint,
+                    bucket,
+                    &self.config.s3_region,
+                    access_key,
+                    secret_key,
+                )?
+                .with_client(self.client.clone())
+                .fetch(key)
+                .await?
+                .with_context(|| format!("No object {} in bucket {}", key, bucket))
+            }
+        }
+    }
+}
+
+/// Check downloaded bytes against the expected lowercase hex SHA-256
+fn verify(bytes: &[u8], sha256: &str) -> Result<()> {
+    let actual = hex::encode(Sha256::digest(bytes));
+    if actual != sha256 {
+        bail!("checksum mismatch: expected {}, got {}", sha256, actual);
+    }
+    Ok(())
+}
+
+/// Replace every remote model path in the config with its verified local copy
+pub async fn download_models(config: &mut Config) -> Result<()> {
+    let inference = &mut config.inference;
+    let specs: Vec<&mut ModelSpec> = inference
+        .models
+        .iter_mut()
+        .chain(inference.language_models.iter_mut().map(|m| &mut m.model))
+        .chain(inference.topic_models.iter_mut().map(|m| &mut m.model))
+        .chain(config.shadow.as_mut().map(|s| &mut s.model))
+        .filter(|spec| spec.path.contains("://"))
+        .collect();
+    if specs.is_empty() {
+        return Ok(());
+    }
+
+    let downloader = ModelDownloader::new(inference.download.clone())?;
+    for spec in specs {
+        *spec = downloader.localize(spec).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_parse_remote_model() {
+        assert_eq!(RemoteModel::parse("models/detector.onnx").unwrap(), None);
+
+        let remote = RemoteModel::parse(&format!(
+            "s3://models/detector/v2.onnx#sha256={}",
+            HELLO_SHA256.to_uppercase()
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            remote.source,
+            ModelSource::S3 {
+                bucket: "models".to_string(),
+                key: "detector/v2.onnx".to_string(),
+            }
+        );
+        assert_eq!(remote.sha256, HELLO_SHA256);
+
+        let remote = RemoteModel::parse(&format!(
+            "https://models.example.org/detector.onnx#sha256={}",
+            HELLO_SHA256
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            remote.source,
+            ModelSource::Https(Url::parse("https://models.example.org/detector.onnx").unwrap())
+        );
+
+        // Remote models must be pinned
+        assert!(RemoteModel::parse("https://models.example.org/detector.onnx").is_err());
+        assert!(RemoteModel::parse("https://models.example.org/d.onnx#sha256=abc").is_err());
+        assert!(RemoteModel::parse(&format!("ftp://host/d.onnx#sha256={}", HELLO_SHA256)).is_err());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        assert!(verify(b"hello", HELLO_SHA256).is_ok());
+        assert!(verify(b"hello!", HELLO_SHA256).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_localize_uses_verified_cache() {
+        let cache_dir = TempDir::new("model-cache");
+        let cached = cache_dir.join(format!("{}.onnx", HELLO_SHA256));
+        fs::write(&cached, b"hello").unwrap();
+
+        let downloader = ModelDownloader::new(ModelDownloadConfig {
+            cache_dir: cache_dir.to_path_buf(),
+            timeout: Duration::from_secs(1),
+            s3_endpoint: "https://s3.us-east-1.amazonaws.com".to_string(),
+            s3_region: "us-east-1".to_string(),
+            access_key: None,
+            secret_key: None,
+        })
+        .unwrap();
+        // The cache hit needs no network or credentials
+        let spec: ModelSpec = format!("detector:s3://models/detector.onnx#sha256={}", HELLO_SHA256)
+            .parse()
+            .unwrap();
+        let localized = downloader.localize(&spec).await.unwrap();
+        assert_eq!(localized.path, cached.to_string_lossy());
+        assert_eq!(localized.name, "detector");
+
+        let local: ModelSpec = "detector:models/detector.onnx".parse().unwrap();
+        assert_eq!(downloader.localize(&local).await.unwrap(), local);
+    }
+}
@@ -7,6 +7,7 @@
 //! Generated via prost derive macros (no protoc required at build time).
 
 use prost::Message;
+use std::collections::BTreeMap;
 
 /// Input message for content analysis
 #[derive(Clone, PartialEq, Message)]
@@ -22,6 +23,20 @@ pub struct AnalysisInput {
 
     #[prost(string, tag = "4")]
     pub image_url: String,
+
+    /// Text too large for a message, fetched before analysis
+    #[prost(message, optional, tag = "5")]
+    pub content_object: Option<ObjectRef>,
+}
+
+/// Object in a JetStream object store bucket
+#[derive(Clone, PartialEq, Message)]
+pub struct ObjectRef {
+    #[prost(string, tag = "1")]
+    pub bucket: String,
+
+    #[prost(string, tag = "2")]
+    pub name: String,
 }
 
 /// Neural feature outputs from ONNX inference
@@ -35,6 +50,193 @@ pub struct NeuralFeatures {
 
     #[prost(bool, tag = "3")]
     pub visual_artifact: bool,
+
+    #[prost(float, repeated, tag = "4")]
+    pub embedding: Vec<f32>,
+
+    #[prost(string, tag = "5")]
+    pub model_version: String,
+
+    #[prost(message, repeated, tag = "6")]
+    pub attributions: Vec<TokenAttribution>,
+
+    #[prost(float, tag = "7")]
+    pub ai_generated_score: f32,
+
+    #[prost(message, repeated, tag = "8")]
+    pub stances: Vec<StanceScore>,
+
+    #[prost(message, repeated, tag = "9")]
+    pub entities: Vec<Entity>,
+}
+
+/// Contribution of one input token to the fakeness score
+#[derive(Clone, PartialEq, Message)]
+pub struct TokenAttribution {
+    #[prost(string, tag = "1")]
+    pub token: String,
+
+    #[prost(float, tag = "2")]
+    pub score: f32,
+}
+
+/// Stance of the content towards one configured claim
+#[derive(Clone, PartialEq, Message)]
+pub struct StanceScore {
+    #[prost(string, tag = "1")]
+    pub topic: String,
+
+    #[prost(float, tag = "2")]
+    pub support: f32,
+
+    #[prost(float, tag = "3")]
+    pub deny: f32,
+}
+
+/// Named entity mentioned in the content
+#[derive(Clone, PartialEq, Message)]
+pub struct Entity {
+    #[prost(string, tag = "1")]
+    pub text: String,
+
+    #[prost(string, tag = "2")]
+    pub kind: String,
+
+    #[prost(float, tag = "3")]
+    pub confidence: f32,
+}
+
+/// A rule that fired on the way to the verdict
+#[derive(Clone, PartialEq, Message)]
+pub struct FiredRule {
+    #[prost(string, tag = "1")]
+    pub rule: String,
+
+    /// Body atoms that held, e.g. `fakeness("high")`
+    #[prost(string, repeated, tag = "2")]
+    pub premises: Vec<String>,
+}
+
+/// A label of the content, e.g. `DISINFO` or `AI_GENERATED`
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+
+    /// Support of the model scores for the label, 0-1
+    #[prost(float, tag = "2")]
+    pub confidence: f32,
+}
+
+/// Current schema version of [`AnalysisResult`]
+pub const ANALYSIS_RESULT_SCHEMA_VERSION: u32 = 5;
+
+/// Rich analysis result published after processing
+#[derive(Clone, PartialEq, Message)]
+pub struct AnalysisResult {
+    #[prost(uint32, tag = "1")]
+    pub schema_version: u32,
+
+    #[prost(string, tag = "2")]
+    pub content_hash: String,
+
+    #[prost(string, tag = "3")]
+    pub source_id: String,
+
+    #[prost(string, tag = "4")]
+    pub verdict: String,
+
+    #[prost(string, tag = "5")]
+    pub explanation: String,
+
+    #[prost(message, optional, tag = "7")]
+    pub neural_features: Option<NeuralFeatures>,
+
+    /// Reason code when `verdict` is `REJECTED`, otherwise empty
+    #[prost(string, tag = "8")]
+    pub rejection_reason: String,
+
+    /// SHA-256 of the decision context blob, empty when not recorded
+    #[prost(string, tag = "9")]
+    pub decision_context: String,
+
+    /// Added by result post-processors, e.g. `policy_code`
+    #[prost(btree_map = "string, string", tag = "10")]
+    pub annotations: BTreeMap<String, String>,
+
+    /// SHA-256 of the rules that decided the verdict, empty when rejected
+    #[prost(string, tag = "11")]
+    pub rules_version: String,
+
+    /// Rules that fired, sorted by identifier; empty when rejected
+    #[prost(message, repeated, tag = "12")]
+    pub fired_rules: Vec<FiredRule>,
+
+    /// The verdict unless `SAFE`, then the labels the rules derived by
+    /// name; empty when rejected
+    #[prost(message, repeated, tag = "13")]
+    pub labels: Vec<Label>,
+
+    /// Version of the rules from their manifest, empty when rejected
+    #[prost(string, tag = "14")]
+    pub rules_semver: String,
+
+    /// Version of the models that produced the features, empty when
+    /// rejected
+    #[prost(string, tag = "15")]
+    pub model_version: String,
+
+    /// Confidence of the verdict, 0-1; 0 when rejected
+    #[prost(float, tag = "16")]
+    pub confidence: f32,
+
+    /// Where the processing time went, unset when rejected
+    #[prost(message, optional, tag = "17")]
+    pub timing: Option<Timing>,
+
+    /// Replica that decided the verdict, see `NSAI_INSTANCE_ID`
+    #[prost(string, tag = "18")]
+    pub instance: String,
+}
+
+/// When an input was received and where its processing time went
+#[derive(Clone, PartialEq, Message)]
+pub struct Timing {
+    /// Unix time in milliseconds the detector received the input
+    #[prost(uint64, tag = "1")]
+    pub received_at_ms: u64,
+
+    /// Text inference, or the feature cache lookup when cached
+    #[prost(uint32, tag = "2")]
+    pub inference_ms: u32,
+
+    /// Rule evaluation
+    #[prost(uint32, tag = "3")]
+    pub reasoning_ms: u32,
+
+    /// Receipt to publishing
+    #[prost(uint32, tag = "4")]
+    pub total_ms: u32,
+}
+
+/// Minimal verdict format kept for consumers that have not migrated
+/// to [`AnalysisResult`]
+#[derive(Clone, PartialEq, Message)]
+pub struct LegacyVerdict {
+    #[prost(string, tag = "1")]
+    pub content_hash: String,
+
+    #[prost(string, tag = "2")]
+    pub verdict: String,
+}
+
+impl From<&AnalysisResult> for LegacyVerdict {
+    fn from(result: &AnalysisResult) -> Self {
+        Self {
+            content_hash: result.content_hash.clone(),
+            verdict: result.verdict.clone(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -48,6 +250,10 @@ fn test_analysis_input_roundtrip() {
             content_text: "Test content".to_string(),
             source_id: "source-1".to_string(),
             image_url: "https://example.com/img.png".to_string(),
+            content_object: Some(ObjectRef {
+                bucket: "articles".to_string(),
+                name: "abc123".to_string(),
+            }),
         };
 
         // Encode
@@ -65,7 +271,24 @@ fn test_neural_features_roundtrip() {
         let features = NeuralFeatures {
             fakeness_score: 0.75,
             emotion_score: 0.42,
+            ai_generated_score: 0.9,
             visual_artifact: true,
+            embedding: vec![0.1, 0.2],
+            model_version: "v1".to_string(),
+            attributions: vec![TokenAttribution {
+                token: "cure".to_string(),
+                score: -0.3,
+            }],
+            stances: vec![StanceScore {
+                topic: "vaccines".to_string(),
+                support: 0.05,
+                deny: 0.9,
+            }],
+            entities: vec![Entity {
+                text: "World Health Organization".to_string(),
+                kind: "organization".to_string(),
+                confidence: 0.97,
+            }],
         };
 
         let mut buf = Vec::new();
@@ -75,4 +298,59 @@ fn test_neural_features_roundtrip() {
 
         assert_eq!(features, decoded);
     }
+
+    #[test]
+    fn test_legacy_verdict_from_result() {
+        let result = AnalysisResult {
+            schema_version: ANALYSIS_RESULT_SCHEMA_VERSION,
+            content_hash: "abc123".to_string(),
+            source_id: "source-1".to_string(),
+            verdict: "SAFE".to_string(),
+            explanation: "No rules fired".to_string(),
+            neural_features: None,
+            rejection_reason: String::new(),
+            decision_context: String::new(),
+            annotations: BTreeMap::new(),
+            rules_version: String::new(),
+            fired_rules: Vec::new(),
+            labels: Vec::new(),
+            rules_semver: String::new(),
+            model_version: String::new(),
+            confidence: 0.0,
+            timing: None,
+            instance: String::new(),
+        };
+
+        let legacy = LegacyVerdict::from(&result);
+        let mut buf = Vec::new();
+        legacy.encode(&mut buf).unwrap();
+
+        let decoded = LegacyVerdict::decode(&buf[..]).unwrap();
+        assert_eq!(decoded.content_hash, "abc123");
+        assert_eq!(decoded.verdict, "SAFE");
+    }
+
+    #[test]
+    fn test_analysis_result_roundtrip() {
+        let result = AnalysisResult {
+            schema_version: ANALYSIS_RESULT_SCHEMA_VERSION,
+            content_hash: "abc123".to_string(),
+            verdict: "DISINFO".to_string(),
+            confidence: 0.87,
+            fired_rules: vec![FiredRule {
+                rule: "disinfo".to_string(),
+                premises: vec!["fakeness(\"high\")".to_string()],
+            }],
+            timing: Some(Timing {
+                received_at_ms: 1_700_000_000_000,
+                inference_ms: 42,
+                reasoning_ms: 3,
+                total_ms: 51,
+            }),
+            ..Default::default()
+        };
+
+        let decoded = AnalysisResult::decode(result.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, result);
+    }
 }
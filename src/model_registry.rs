@@ -0,0 +1,324 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Versioned model registry
+//!
+//! Models live on disk as `<root>/<name>/<version>/model.onnx`. Each model
+//! directory holds an `ACTIVE` file naming the serving version and a
+//! `HISTORY` log of activations. Rollbacks are logged too, marked as such,
+//! so the log replays as a stack: each rollback undoes one activation, and
+//! repeated rollbacks walk further back instead of alternating between
+//! the last two versions.
+//!
+//! With an approval key, a version is only activated or served if its
+//! directory holds an `APPROVAL.json` manifest, signed with that key by
+//! `nsai-detector validate-model`, for the exact model file.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::clock::unix_now;
+use crate::model_validation::{self, ApprovalManifest};
+use crate::onnx_wrapper::ModelSpec;
+
+const MODEL_FILE: &str = "model.onnx";
+const ACTIVE_FILE: &str = "ACTIVE";
+const HISTORY_FILE: &str = "HISTORY";
+const APPROVAL_FILE: &str = "APPROVAL.json";
+/// Marks a `HISTORY` entry written by a rollback
+const ROLLBACK_MARK: &str = "rollback";
+
+/// Registry of named, versioned models rooted at a directory
+pub struct ModelRegistry {
+    root: PathBuf,
+    /// Key approval manifests must be signed with; unchecked when unset
+    approval_key: Option<String>,
+}
+
+impl ModelRegistry {
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        if !root.is_dir() {
+            bail!("Model registry {} is not a directory", root.display());
+        }
+        Ok(Self {
+            root,
+            approval_key: None,
+        })
+    }
+
+    /// Require a valid approval manifest for every activated or served version
+    pub fn with_approval_key(mut self, key: Option<String>) -> Self {
+        self.approval_key = key;
+        self
+    }
+
+    /// Add an approved model file as a new version
+    pub fn install(&self, model: &Path, manifest: &ApprovalManifest) -> Result<()> {
+        validate_name(&manifest.version)?;
+        let dir = self.model_dir(&manifest.name)?.join(&manifest.version);
+        if dir.join(MODEL_FILE).exists() {
+            bail!(
+                "Model {} already has a version {}",
+                manifest.name,
+                manifest.version
+            );
+        }
+        fs::create_dir_all(&dir)?;
+
+        let tmp = dir.join(format!("{}.tmp", MODEL_FILE));
+        fs::copy(model, &tmp).with_context(|| format!("Failed to copy {}", model.display()))?;
+        fs::write(
+            dir.join(APPROVAL_FILE),
+            serde_json::to_vec_pretty(manifest)?,
+        )?;
+        fs::rename(&tmp, dir.join(MODEL_FILE))?;
+        Ok(())
+    }
+
+    /// Check the version's approval manifest when a key is configured
+    fn check_approval(&self, name: &str, version: &str) -> Result<()> {
+        let Some(key) = &self.approval_key else {
+            return Ok(());
+        };
+        let dir = self.model_dir(name)?.join(version);
+        let manifest: ApprovalManifest = serde_json::from_slice(
+            &fs::read(dir.join(APPROVAL_FILE))
+                .with_context(|| format!("Model {}@{} has no approval manifest", name, version))?,
+        )
+        .with_context(|| format!("Invalid approval manifest for {}@{}", name, version))?;
+        let sha256 = model_validation::file_sha256(&dir.join(MODEL_FILE))?;
+        manifest
+            .verify(key, name, version, &sha256)
+            .with_context(|| format!("Model {}@{} is not approved", name, version))
+    }
+
+    /// All versions available for a model, sorted
+    pub fn versions(&self, name: &str) -> Result<Vec<String>> {
+        let dir = self.model_dir(name)?;
+        let mut versions = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("Unknown model {}", name))? {
+            let entry = entry?;
+            if entry.path().join(MODEL_FILE).is_file() {
+                versions.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    /// Currently serving version of a model
+    pub fn active(&self, name: &str) -> Result<String> {
+        let path = self.model_dir(name)?.join(ACTIVE_FILE);
+        let version = fs::read_to_string(&path)
+            .with_context(|| format!("No active version recorded for {}", name))?;
+        Ok(version.trim().to_string())
+    }
+
+    /// Make `version` the serving version and record it in the history
+    pub fn activate(&self, name: &str, version: &str) -> Result<()> {
+        self.switch(name, version, false)
+    }
+
+    fn switch(&self, name: &str, version: &str, rollback: bool) -> Result<()> {
+        validate_name(version)?;
+        let dir = self.model_dir(name)?;
+        if !dir.join(version).join(MODEL_FILE).is_file() {
+            bail!("Model {} has no version {}", name, version);
+        }
+        self.check_approval(name, version)?;
+
+        // Write-then-rename so readers never observe a partial ACTIVE file
+        let tmp = dir.join(format!("{}.tmp", ACTIVE_FILE));
+        fs::write(&tmp, version)?;
+        fs::rename(&tmp, dir.join(ACTIVE_FILE))?;
+
+        let mut history = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(HISTORY_FILE))?;
+        if rollback {
+            writeln!(history, "{} {} {}", unix_now(), version, ROLLBACK_MARK)?;
+        } else {
+            writeln!(history, "{} {}", unix_now(), version)?;
+        }
+        Ok(())
+    }
+
+    /// Re-activate the version that was serving before the current one was
+    /// activated; each further rollback goes one activation further back
+    ///
+    /// # Returns
+    /// The version rolled back to
+    pub fn rollback(&self, name: &str) -> Result<String> {
+        let current = self.active(name)?;
+        let history = fs::read_to_string(self.model_dir(name)?.join(HISTORY_FILE))
+            .with_context(|| format!("No activation history for {}", name))?;
+
+        let mut stack = activation_stack(&history);
+        if stack.last() == Some(&current.as_str()) {
+            stack.pop();
+        }
+        let previous = stack
+            .last()
+            .map(|version| version.to_string())
+            .with_context(|| format!("No earlier version of {} to roll back to", name))?;
+
+        self.switch(name, &previous, true)?;
+        Ok(previous)
+    }
+
+    /// Resolve a configured model to its active on-disk version
+    pub fn resolve(&self, spec: &ModelSpec) -> Result<ModelSpec> {
+        let version = self.active(&spec.name)?;
+        self.check_approval(&spec.name, &version)?;
+        Ok(ModelSpec {
+            path: self
+                .model_dir(&spec.name)?
+                .join(&version)
+                .join(MODEL_FILE)
+                .to_string_lossy()
+                .to_string(),
+            version: Some(version),
+            ..spec.clone()
+        })
+    }
+
+    fn model_dir(&self, name: &str) -> Result<PathBuf> {
+        validate_name(name)?;
+        Ok(self.root.join(name))
+    }
+}
+
+/// Versions activated and not rolled back, oldest first
+fn activation_stack(history: &str) -> Vec<&str> {
+    let mut stack: Vec<&str> = Vec::new();
+    for line in history.lines() {
+        let mut fields = line.split_whitespace().skip(1);
+        match (fields.next(), fields.next()) {
+            (Some(_), Some(ROLLBACK_MARK)) => {
+                stack.pop();
+            }
+            (Some(version), None) if stack.last() != Some(&version) => stack.push(version),
+            _ => {}
+        }
+    }
+    stack
+}
+
+/// Check that a registry-relative name cannot escape the root
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || Path::new(name).components().count() != 1 || name.starts_with('.') {
+        bail!("Invalid model name or version: {:?}", name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn registry_with_versions(versions: &[&str]) -> (ModelRegistry, TempDir) {
+        let root = TempDir::new("registry");
+        for version in versions {
+            let dir = root.join("fakeness").join(version);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(MODEL_FILE), b"onnx").unwrap();
+        }
+        (ModelRegistry::open(root.to_path_buf()).unwrap(), root)
+    }
+
+    #[test]
+    fn test_activate_and_rollback() {
+        let (registry, _root) = registry_with_versions(&["1.0.0", "1.1.0"]);
+        assert_eq!(
+            registry.versions("fakeness").unwrap(),
+            vec!["1.0.0", "1.1.0"]
+        );
+
+        registry.activate("fakeness", "1.0.0").unwrap();
+        registry.activate("fakeness", "1.1.0").unwrap();
+        assert_eq!(registry.active("fakeness").unwrap(), "1.1.0");
+
+        assert_eq!(registry.rollback("fakeness").unwrap(), "1.0.0");
+        assert_eq!(registry.active("fakeness").unwrap(), "1.0.0");
+    }
+
+    #[test]
+    fn test_consecutive_rollbacks_walk_back() {
+        let (registry, _root) = registry_with_versions(&["1.0.0", "1.1.0", "1.2.0"]);
+        registry.activate("fakeness", "1.0.0").unwrap();
+        registry.activate("fakeness", "1.1.0").unwrap();
+        registry.activate("fakeness", "1.2.0").unwrap();
+
+        assert_eq!(registry.rollback("fakeness").unwrap(), "1.1.0");
+        assert_eq!(registry.rollback("fakeness").unwrap(), "1.0.0");
+        assert_eq!(registry.active("fakeness").unwrap(), "1.0.0");
+        assert!(registry.rollback("fakeness").is_err());
+
+        // A new activation is undone by the next rollback
+        registry.activate("fakeness", "1.2.0").unwrap();
+        assert_eq!(registry.rollback("fakeness").unwrap(), "1.0.0");
+    }
+
+    #[test]
+    fn test_resolve_stamps_version() {
+        let (registry, _root) = registry_with_versions(&["2.0.0"]);
+        registry.activate("fakeness", "2.0.0").unwrap();
+
+        let spec: ModelSpec = "fakeness:unused.onnx".parse().unwrap();
+        let resolved = registry.resolve(&spec).unwrap();
+        assert_eq!(resolved.version.as_deref(), Some("2.0.0"));
+        assert!(resolved.path.ends_with("2.0.0/model.onnx"));
+        assert!(registry.activate("fakeness", "9.9.9").is_err());
+    }
+
+    #[test]
+    fn test_approval_required_with_key() {
+        let (registry, root) = registry_with_versions(&["3.0.0"]);
+        let registry = registry.with_approval_key(Some("key".to_string()));
+        assert!(registry.activate("fakeness", "3.0.0").is_err());
+
+        let candidate = root.join("candidate.onnx");
+        fs::write(&candidate, b"candidate").unwrap();
+        let manifest = ApprovalManifest {
+            name: "fakeness".to_string(),
+            version: "3.1.0".to_string(),
+            sha256: model_validation::file_sha256(&candidate).unwrap(),
+            metrics: model_validation::ValidationMetrics {
+                samples: 1,
+                accuracy: 1.0,
+                precision: 1.0,
+                recall: 1.0,
+            },
+            thresholds: model_validation::Thresholds {
+                min_accuracy: 0.9,
+                min_precision: 0.0,
+                min_recall: 0.0,
+            },
+            approved_at: 1_700_000_000,
+            signature: String::new(),
+        }
+        .sign("key");
+        registry.install(&candidate, &manifest).unwrap();
+        registry.activate("fakeness", "3.1.0").unwrap();
+        assert!(registry.install(&candidate, &manifest).is_err());
+
+        // A swapped model file no longer matches its approval
+        fs::write(root.join("fakeness/3.1.0").join(MODEL_FILE), b"swapped").unwrap();
+        let spec: ModelSpec = "fakeness:unused.onnx".parse().unwrap();
+        assert!(registry.resolve(&spec).is_err());
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("fakeness").is_ok());
+        assert!(validate_name("../etc").is_err());
+        assert!(validate_name("a/b").is_err());
+    }
+}
@@ -0,0 +1,436 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Sandboxed validation of candidate models (`nsai-detector validate-model`)
+//!
+//! Bring-your-own models are evaluated in a child process with an empty
+//! environment and capped memory and CPU time, so a malformed or hostile
+//! file can neither read credentials nor exhaust the host. The child checks
+//! the model's input and output schema and scores a labelled validation
+//! set; the parent compares the metrics with the required thresholds and
+//! signs an [`ApprovalManifest`]. A registry holding the approval key only
+//! serves versions whose manifest verifies.
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{process::Command, time::timeout};
+
+use crate::clock::unix_now;
+use crate::onnx_wrapper::{self, Ensemble, FusionStrategy, ModelSchema, ModelSpec};
+use crate::session_pool::SessionOptions;
+use crate::telemetry;
+
+/// Inputs every detector model must accept
+const REQUIRED_INPUTS: &[&str] = &["input_ids", "attention_mask"];
+
+/// Outputs the symbolic layer cannot do without
+const REQUIRED_OUTPUTS: &[&str] = &["fakeness_score", "emotion_score"];
+
+/// Fakeness above which a validation sample counts as predicted fake
+const DECISION_THRESHOLD: f32 = 0.5;
+
+/// One labelled validation sample, a line of the JSONL validation set
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidationSample {
+    pub content_hash: String,
+    /// Whether the content is known disinformation
+    pub fake: bool,
+}
+
+/// Classification quality of a candidate on the validation set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidationMetrics {
+    pub samples: usize,
+    pub accuracy: f64,
+    pub precision: f64,
+    pub recall: f64,
+}
+
+impl ValidationMetrics {
+    /// Score `(predicted_fake, fake)` pairs
+    pub fn score(predictions: &[(bool, bool)]) -> Self {
+        let count = |predicted: bool, actual: bool| {
+            predictions
+                .iter()
+                .filter(|p| **p == (predicted, actual))
+                .count() as f64
+        };
+        let (tp, fp, fn_, tn) = (
+            count(true, true),
+            count(true, false),
+            count(false, true),
+            count(false, false),
+        );
+        let ratio = |num: f64, den: f64| if den > 0.0 { num / den } else { 0.0 };
+        Self {
+            samples: predictions.len(),
+            accuracy: ratio(tp + tn, predictions.len() as f64),
+            precision: ratio(tp, tp + fp),
+            recall: ratio(tp, tp + fn_),
+        }
+    }
+}
+
+/// Minimum metrics a candidate must reach to be approved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Thresholds {
+    pub min_accuracy: f64,
+    pub min_precision: f64,
+    pub min_recall: f64,
+}
+
+impl Thresholds {
+    /// Every threshold the metrics miss, empty when all are met
+    pub fn violations(&self, metrics: &ValidationMetrics) -> Vec<String> {
+        [
+            ("accuracy", metrics.accuracy, self.min_accuracy),
+            ("precision", metrics.precision, self.min_precision),
+            ("recall", metrics.recall, self.min_recall),
+        ]
+        .into_iter()
+        .filter(|(_, value, min)| value < min)
+        .map(|(name, value, min)| format!("{} {:.3} is below {:.3}", name, value, min))
+        .collect()
+    }
+}
+
+/// Resource limits of the sandboxed evaluation
+#[derive(Debug, Clone)]
+pub struct SandboxLimits {
+    /// Address space cap
+    pub memory_mb: u64,
+    /// CPU time cap; the kernel kills the child past it
+    pub cpu_secs: u64,
+    /// Wall-clock deadline
+    pub wall: Duration,
+}
+
+/// What the sandboxed child reports back to the parent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SandboxReport {
+    pub schema: ModelSchema,
+    pub schema_errors: Vec<String>,
+    /// `None` when the schema check already failed
+    pub metrics: Option<ValidationMetrics>,
+}
+
+/// Signed record that a model file passed validation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApprovalManifest {
+    pub name: String,
+    pub version: String,
+    /// Hex SHA-256 of the approved model file
+    pub sha256: String,
+    pub metrics: ValidationMetrics,
+    pub thresholds: Thresholds,
+    pub approved_at: u64,
+    /// HMAC-SHA256 over the other fields
+    pub signature: String,
+}
+
+impl ApprovalManifest {
+    fn signed_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&Self {
+            signature: String::new(),
+            ..self.clone()
+        })
+        .expect("manifest serializes")
+    }
+
+    pub fn sign(mut self, key: &str) -> Self {
+        self.signature = telemetry::sign(key.as_bytes(), &self.signed_bytes());
+        self
+    }
+
+    /// Check the signature and that the manifest approves this exact file
+    pub fn verify(&self, key: &str, name: &str, version: &str, sha256: &str) -> Result<()> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(&self.signed_bytes());
+        mac.verify_slice(&hex::decode(&self.signature).context("invalid manifest signature")?)
+            .ok()
+            .context("approval manifest signature does not match")?;
+        if self.name != name || self.version != version {
+            bail!(
+                "approval manifest is for {}@{}, not {}@{}",
+                self.name,
+                self.version,
+                name,
+                version
+            );
+        }
+        if self.sha256 != sha256 {
+            bail!("model file does not match the approved checksum");
+        }
+        Ok(())
+    }
+}
+
+/// Hex SHA-256 of a file
+pub fn file_sha256(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hex::encode(Sha256::digest(&bytes)))
+}
+
+/// Problems with a model's schema, empty when it can be served
+fn check_schema(schema: &ModelSchema) -> Vec<String> {
+    let missing = |required: &[&str], present: &[String], kind: &str| {
+        required
+            .iter()
+            .filter(|name| !present.iter().any(|p| p == *name))
+            .map(|name| format!("missing {} {}", kind, name))
+            .collect::<Vec<_>>()
+    };
+    let mut errors = missing(REQUIRED_INPUTS, &schema.inputs, "input");
+    errors.extend(missing(REQUIRED_OUTPUTS, &schema.outputs, "output"));
+    errors
+}
+
+fn load_validation_set(path: &Path) -> Result<Vec<ValidationSample>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let samples = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("{}:{}: invalid sample", path.display(), i + 1))
+        })
+        .collect::<Result<Vec<ValidationSample>>>()?;
+    if samples.is_empty() {
+        bail!("Validation set {} is empty", path.display());
+    }
+    Ok(samples)
+}
+
+/// Evaluate the candidate in this process; runs inside the sandbox
+pub async fn evaluate(model: &Path, validation_set: &Path) -> Result<SandboxReport> {
+    let schema = onnx_wrapper::inspect_model(model)?;
+    let mut schema_errors = check_schema(&schema);
+    if !schema_errors.is_empty() {
+        return Ok(SandboxReport {
+            schema,
+            schema_errors,
+            metrics: None,
+        });
+    }
+
+    let samples = load_validation_set(validation_set)?;
+    let ensemble = Ensemble::new(
+        vec![ModelSpec {
+            name: "candidate".to_string(),
+            path: model.to_string_lossy().to_string(),
+            weight: 1.0,
+            version: None,
+        }],
+        FusionStrategy::Mean,
+        &SessionOptions::default(),
+    );
+    let mut predictions = Vec::with_capacity(samples.len());
+    for sample in &samples {
+        let features = ensemble.run(&sample.content_hash).await?;
+        if !(0.0..=1.0).contains(&features.fakeness) {
+            schema_errors.push(format!(
+                "fakeness_score {} outside [0, 1] for {}",
+                features.fakeness, sample.content_hash
+            ));
+            break;
+        }
+        predictions.push((features.fakeness > DECISION_THRESHOLD, sample.fake));
+    }
+
+    let metrics = schema_errors
+        .is_empty()
+        .then(|| ValidationMetrics::score(&predictions));
+    Ok(SandboxReport {
+        schema,
+        schema_errors,
+        metrics,
+    })
+}
+
+/// Run [`evaluate`] in a resource-limited child of this binary
+///
+/// The child is `nsai-detector validate-model --sandbox-report <file>`
+/// with an empty environment; it writes its [`SandboxReport`] as JSON.
+pub async fn evaluate_sandboxed(
+    model: &Path,
+    validation_set: &Path,
+    limits: &SandboxLimits,
+) -> Result<SandboxReport> {
+    let report_path: PathBuf = std::env::temp_dir().join(format!(
+        "nsai-validate-{}-{}.json",
+        std::process::id(),
+        unix_now()
+    ));
+    let memory = limits.memory_mb * 1024 * 1024;
+    let cpu_secs = limits.cpu_secs;
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .arg("validate-model")
+        .arg("--model")
+        .arg(model)
+        .arg("--validation-set")
+        .arg(validation_set)
+        .arg("--sandbox-report")
+        .arg(&report_path)
+        .env_clear()
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    // SAFETY: only async-signal-safe setrlimit calls run between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, value) in [(libc::RLIMIT_AS, memory), (libc::RLIMIT_CPU, cpu_secs)] {
+                let limit = libc::rlimit {
+                    rlim_cur: value as libc::rlim_t,
+                    rlim_max: value as libc::rlim_t,
+                };
+                if libc::setrlimit(resource, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn().context("Failed to start sandbox")?;
+    let status = match timeout(limits.wall, child.wait()).await {
+        Ok(status) => status?,
+        Err(_) => {
+            let _ = child.kill().await;
+            let _ = fs::remove_file(&report_path);
+            bail!("Sandboxed validation exceeded {:?}", limits.wall);
+        }
+    };
+    let report = fs::read_to_string(&report_path);
+    let _ = fs::remove_file(&report_path);
+    if !status.success() {
+        bail!("Sandboxed validation failed: {}", status);
+    }
+    serde_json::from_str(&report?).context("Invalid sandbox report")
+}
+
+/// Validate a candidate in the sandbox and sign its approval
+///
+/// Fails, listing every reason, if the schema check fails or a threshold
+/// is missed.
+pub async fn approve(
+    name: &str,
+    version: &str,
+    model: &Path,
+    validation_set: &Path,
+    thresholds: Thresholds,
+    limits: &SandboxLimits,
+    key: &str,
+) -> Result<ApprovalManifest> {
+    let sha256 = file_sha256(model)?;
+    let report = evaluate_sandboxed(model, validation_set, limits).await?;
+    if !report.schema_errors.is_empty() {
+        bail!("Model schema rejected: {}", report.schema_errors.join("; "));
+    }
+    let metrics = report
+        .metrics
+        .context("Sandbox reported no metrics for a valid schema")?;
+    let violations = thresholds.violations(&metrics);
+    if !violations.is_empty() {
+        bail!("Model rejected: {}", violations.join("; "));
+    }
+
+    Ok(ApprovalManifest {
+        name: name.to_string(),
+        version: version.to_string(),
+        sha256,
+        metrics,
+        thresholds,
+        approved_at: unix_now(),
+        signature: String::new(),
+    }
+    .sign(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> ApprovalManifest {
+        ApprovalManifest {
+            name: "fakeness".to_string(),
+            version: "2.0.0".to_string(),
+            sha256: "ab".repeat(32),
+            metrics: ValidationMetrics {
+                samples: 4,
+                accuracy: 0.75,
+                precision: 1.0,
+                recall: 0.5,
+            },
+            thresholds: Thresholds {
+                min_accuracy: 0.7,
+                min_precision: 0.0,
+                min_recall: 0.0,
+            },
+            approved_at: 1_700_000_000,
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_metrics_and_thresholds() {
+        let metrics = ValidationMetrics::score(&[
+            (true, true),
+            (false, true),
+            (false, false),
+            (false, false),
+        ]);
+        assert_eq!(metrics, manifest().metrics);
+
+        let thresholds = Thresholds {
+            min_accuracy: 0.8,
+            min_precision: 0.9,
+            min_recall: 0.6,
+        };
+        assert_eq!(
+            thresholds.violations(&metrics),
+            vec![
+                "accuracy 0.750 is below 0.800",
+                "recall 0.500 is below 0.600"
+            ]
+        );
+        assert!(manifest().thresholds.violations(&metrics).is_empty());
+    }
+
+    #[test]
+    fn test_manifest_sign_verify() {
+        let signed = manifest().sign("key");
+        let sha = "ab".repeat(32);
+        signed.verify("key", "fakeness", "2.0.0", &sha).unwrap();
+
+        assert!(signed.verify("other", "fakeness", "2.0.0", &sha).is_err());
+        assert!(signed.verify("key", "fakeness", "2.1.0", &sha).is_err());
+        assert!(signed
+            .verify("key", "fakeness", "2.0.0", &"cd".repeat(32))
+            .is_err());
+
+        let mut tampered = signed.clone();
+        tampered.metrics.accuracy = 0.99;
+        assert!(tampered.verify("key", "fakeness", "2.0.0", &sha).is_err());
+    }
+
+    #[test]
+    fn test_check_schema() {
+        let schema = ModelSchema {
+            inputs: vec!["input_ids".to_string()],
+            outputs: vec!["fakeness_score".to_string(), "emotion_score".to_string()],
+        };
+        assert_eq!(check_schema(&schema), vec!["missing input attention_mask"]);
+    }
+}
@@ -0,0 +1,458 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Connection to the NATS cluster inputs are consumed from
+//!
+//! The servers, the stream, subject and durable consumer, and how the
+//! client rides out a disconnect are configured through `NSAI_NATS_*`,
+//! and the `--nats-*` flags of every command override them, so the same
+//! binary runs against production, a staging cluster or a local server.
+//!
+//! The subject may end in a wildcard, e.g. `disinfo.raw.>`. The part of a
+//! message's subject the wildcard matched is its route, such as `telegram`
+//! or `telegram.image`, and routes can have models of their own and pick
+//! the rule set of a content type.
+//!
+//! Urgent inputs, like moderation requests about breaking news, can be
+//! published on a priority subject of the same stream instead. It has a
+//! durable consumer of its own whose messages are taken before those of the
+//! input subject, so they do not wait behind a backlog or a backfill.
+//!
+//! The service connects with [`NatsConfig::connect_observed`], which logs
+//! and counts connection events, so a disconnect, a server going into lame
+//! duck mode or a slow consumer shows in the metrics rather than only as a
+//! gap in the processed messages.
+
+use anyhow::{bail, Context, Result};
+use async_nats::{ConnectOptions, Event, ServerAddr};
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+use crate::content_type::ContentType;
+use crate::metrics::Metrics;
+use crate::onnx_wrapper::ModelSpec;
+
+/// NATS connection and consumer settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatsConfig {
+    /// Server URLs; the client connects to one and fails over to the rest
+    pub servers: Vec<String>,
+    /// Connection name shown in the server's monitoring
+    pub name: String,
+    /// `.creds` file of the NATS user, e.g. issued by `nsc`
+    pub credentials: Option<PathBuf>,
+    /// JetStream stream holding the inputs
+    pub stream: String,
+    /// Subject inputs are published on
+    pub subject: String,
+    /// Durable pull consumer of the detectors
+    pub consumer: String,
+    /// Subject of urgent inputs, taken before the others; none when `None`
+    pub priority_subject: Option<String>,
+    /// Durable pull consumer of the priority subject
+    pub priority_consumer: String,
+    /// Outgoing messages buffered while the client reconnects
+    pub reconnect_buffer: usize,
+    /// Reconnect attempts before the client gives up; unlimited when `None`
+    pub max_reconnects: Option<usize>,
+    /// Deadline of connecting to one server
+    pub connect_timeout: Duration,
+}
+
+impl Default for NatsConfig {
+    fn default() -> Self {
+        Self {
+            servers: vec!["nats://nats:4222".to_string()],
+            name: "nsai-detector".to_string(),
+            credentials: None,
+            stream: "INFERENCE_JOBS".to_string(),
+            subject: "disinfo.raw".to_string(),
+            consumer: "detector_worker".to_string(),
+            priority_subject: None,
+            priority_consumer: "detector_worker_priority".to_string(),
+            reconnect_buffer: 2048,
+            max_reconnects: None,
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl NatsConfig {
+    /// The servers, checked to be NATS URLs
+    fn server_addrs(&self) -> Result<Vec<ServerAddr>> {
+        if self.servers.is_empty() {
+            bail!("no NATS server configured");
+        }
+        self.servers
+            .iter()
+            .map(|server| {
+                server
+                    .parse()
+                    .with_context(|| format!("Invalid NATS server URL {}", server))
+            })
+            .collect()
+    }
+
+    /// Position of the token a route starts at, e.g. 2 of `disinfo.raw.>`;
+    /// `None` for a subject not ending in a wildcard
+    fn route_token(&self) -> Option<usize> {
+        let last = self.subject.split('.').count() - 1;
+        let wildcard = matches!(self.subject.rsplit('.').next(), Some("*" | ">"));
+        (last > 0 && wildcard).then_some(last)
+    }
+
+    /// Position of the token naming the tenant, a `*` before the last
+    /// token, e.g. 1 of `disinfo.*.raw`; `None` without one
+    fn tenant_token(&self) -> Option<usize> {
+        let tokens: Vec<&str> = self.subject.split('.').collect();
+        tokens[..tokens.len() - 1]
+            .iter()
+            .position(|token| *token == "*")
+    }
+
+    /// Route of a message received on `subject`: the part the wildcard
+    /// matched, `None` on a plain subject
+    pub fn route_of<'a>(&self, subject: &'a str) -> Option<&'a str> {
+        let position = self.route_token()?;
+        if !subject_matches(&self.subject, subject) {
+            return None;
+        }
+        let (start, _) = subject.match_indices('.').nth(position - 1)?;
+        Some(&subject[start + 1..]).filter(|route| !route.is_empty())
+    }
+
+    /// Tenant of a message received on `subject`: the token the tenant
+    /// wildcard matched, `None` on a subject without one
+    pub fn tenant_of<'a>(&self, subject: &'a str) -> Option<&'a str> {
+        let position = self.tenant_token()?;
+        if !subject_matches(&self.subject, subject) {
+            return None;
+        }
+        subject.split('.').nth(position)
+    }
+
+    /// Subject to publish an input of `tenant` on; a wildcard subject
+    /// needs a `route`
+    pub fn input_subject(&self, tenant: &str, route: Option<&str>) -> Result<String> {
+        let mut tokens: Vec<&str> = self.subject.split('.').collect();
+        if let Some(position) = self.tenant_token() {
+            if tenant.is_empty() || tenant.contains(['.', '*', '>', ' ']) {
+                bail!("tenant {:?} cannot be a subject token", tenant);
+            }
+            tokens[position] = tenant;
+        }
+        match (self.route_token(), route) {
+            (Some(position), Some(route)) => tokens[position] = route,
+            (Some(_), None) => bail!("{} is a wildcard; give a route", self.subject),
+            (None, None) => {}
+            (None, Some(_)) => bail!("{} is not a wildcard; routes need one", self.subject),
+        }
+        Ok(tokens.join("."))
+    }
+
+    /// Subjects of the input stream: the input subject, and the priority
+    /// subject if any, which the input subject must not match
+    pub fn stream_subjects(&self) -> Result<Vec<String>> {
+        let Some(priority) = &self.priority_subject else {
+            return Ok(vec![self.subject.clone()]);
+        };
+        if priority.is_empty() || subject_matches(&self.subject, priority) {
+            bail!(
+                "priority subject {:?} must be apart from {}",
+                priority,
+                self.subject
+            );
+        }
+        Ok(vec![self.subject.clone(), priority.clone()])
+    }
+
+    /// Connect to the first server that answers
+    pub async fn connect(&self) -> Result<async_nats::Client> {
+        self.connect_with(ConnectOptions::new()).await
+    }
+
+    /// Connect like [`connect`](Self::connect), recording the connection
+    /// state and events in `metrics`
+    pub async fn connect_observed(&self, metrics: Arc<Metrics>) -> Result<async_nats::Client> {
+        let observer = Arc::clone(&metrics);
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let options = ConnectOptions::new().event_callback(move |event| {
+            let metrics = Arc::clone(&observer);
+            let disconnected = Arc::clone(&disconnected);
+            async move { record_event(&metrics, &disconnected, &event) }
+        });
+        let client = self.connect_with(options).await?;
+        metrics.nats_connected.set(1.0);
+        Ok(client)
+    }
+
+    async fn connect_with(&self, options: ConnectOptions) -> Result<async_nats::Client> {
+        let servers = self.server_addrs()?;
+        let mut options = options
+            .name(&self.name)
+            .client_capacity(self.reconnect_buffer)
+            .max_reconnects(self.max_reconnects)
+            .connection_timeout(self.connect_timeout);
+        if let Some(path) = &self.credentials {
+            options = options
+                .credentials_file(path)
+                .await
+                .with_context(|| format!("Failed to read NATS credentials {}", path.display()))?;
+        }
+        options
+            .connect(servers.as_slice())
+            .await
+            .with_context(|| format!("Failed to connect to NATS at {}", self.servers.join(",")))
+    }
+}
+
+/// Log and count a connection event; `disconnected` is set between a
+/// disconnect and the reconnection that follows
+fn record_event(metrics: &Metrics, disconnected: &AtomicBool, event: &Event) {
+    let name = match event {
+        Event::Connected => {
+            metrics.nats_connected.set(1.0);
+            if disconnected.swap(false, Ordering::Relaxed) {
+                info!("Reconnected to NATS");
+                metrics.nats_reconnects.inc();
+            }
+            "connected"
+        }
+        Event::Disconnected => {
+            metrics.nats_connected.set(0.0);
+            disconnected.store(true, Ordering::Relaxed);
+            warn!("Disconnected from NATS, reconnecting");
+            "disconnected"
+        }
+        Event::LameDuckMode => {
+            warn!("NATS server entered lame duck mode and will shut down");
+            "lame_duck"
+        }
+        Event::Draining => "draining",
+        Event::Closed => {
+            metrics.nats_connected.set(0.0);
+            error!("NATS connection closed");
+            "closed"
+        }
+        Event::SlowConsumer(sid) => {
+            warn!(
+                "NATS subscription {} is a slow consumer, messages dropped",
+                sid
+            );
+            "slow_consumer"
+        }
+        Event::ServerError(e) => {
+            warn!("NATS server error: {}", e);
+            "server_error"
+        }
+        Event::ClientError(e) => {
+            warn!("NATS client error: {}", e);
+            "client_error"
+        }
+    };
+    metrics.nats_events.with_label_values(&[name]).inc();
+}
+
+/// Whether `subject` is one of the subjects `pattern` matches
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for wanted in pattern.split('.') {
+        match (wanted, tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (wanted, Some(token)) if wanted == token => {}
+            _ => return false,
+        }
+    }
+    tokens.next().is_none()
+}
+
+/// A model serving one route, parsed from `route=name:path[:weight]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteModel {
+    pub route: String,
+    pub model: ModelSpec,
+}
+
+impl FromStr for RouteModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((route, model)) = s.split_once('=') else {
+            bail!(
+                "invalid route model (expected route=name:path[:weight]): {}",
+                s
+            );
+        };
+        let route = route.trim();
+        if route.is_empty() || route.contains(['*', '>', ' ']) {
+            bail!("invalid route for model: {}", s);
+        }
+        Ok(Self {
+            route: route.to_string(),
+            model: model.parse()?,
+        })
+    }
+}
+
+/// Content type a route names with its last token, e.g. `image` of
+/// `telegram.image`
+pub fn route_content_type(route: &str) -> Option<ContentType> {
+    route.rsplit('.').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconnects_follow_disconnects() {
+        let metrics = Metrics::new().unwrap();
+        let disconnected = AtomicBool::new(false);
+        record_event(&metrics, &disconnected, &Event::Connected);
+        assert_eq!(metrics.nats_reconnects.get(), 0.0);
+        assert_eq!(metrics.nats_connected.get(), 1.0);
+
+        record_event(&metrics, &disconnected, &Event::Disconnected);
+        assert_eq!(metrics.nats_connected.get(), 0.0);
+        record_event(&metrics, &disconnected, &Event::Connected);
+        assert_eq!(metrics.nats_reconnects.get(), 1.0);
+        assert_eq!(
+            metrics.nats_events.with_label_values(&["connected"]).get(),
+            2.0
+        );
+    }
+
+    #[test]
+    fn test_servers_must_be_urls() {
+        let config = NatsConfig {
+            servers: vec![
+                "nats://nats-1:4222".to_string(),
+                "tls://nats-2.staging.example:4222".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(config.server_addrs().unwrap().len(), 2);
+
+        let config = NatsConfig {
+            servers: vec!["nats://nats-1:not-a-port".to_string()],
+            ..Default::default()
+        };
+        assert!(config.server_addrs().is_err());
+        let config = NatsConfig {
+            servers: Vec::new(),
+            ..Default::default()
+        };
+        assert!(config.server_addrs().is_err());
+    }
+
+    #[test]
+    fn test_routes_of_wildcard_subjects() {
+        let config = NatsConfig {
+            subject: "disinfo.raw.>".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.route_of("disinfo.raw.telegram.image"),
+            Some("telegram.image")
+        );
+        assert_eq!(config.route_of("disinfo.rawer"), None);
+        assert_eq!(
+            config.input_subject("newsroom", Some("telegram")).unwrap(),
+            "disinfo.raw.telegram"
+        );
+        assert!(config.input_subject("newsroom", None).is_err());
+        assert_eq!(
+            route_content_type("telegram.image"),
+            Some(ContentType::Image)
+        );
+        assert_eq!(route_content_type("telegram"), None);
+
+        // A plain subject has no routes
+        let plain = NatsConfig::default();
+        assert_eq!(plain.route_of("disinfo.raw"), None);
+        assert_eq!(
+            plain.input_subject("newsroom", None).unwrap(),
+            "disinfo.raw"
+        );
+        assert!(plain.input_subject("newsroom", Some("telegram")).is_err());
+        assert_eq!(plain.tenant_of("disinfo.raw"), None);
+    }
+
+    #[test]
+    fn test_tenants_of_tenant_subjects() {
+        let config = NatsConfig {
+            subject: "disinfo.*.raw".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(config.tenant_of("disinfo.newsroom.raw"), Some("newsroom"));
+        assert_eq!(config.tenant_of("disinfo.newsroom.cooked"), None);
+        assert_eq!(config.route_of("disinfo.newsroom.raw"), None);
+        assert_eq!(
+            config.input_subject("newsroom", None).unwrap(),
+            "disinfo.newsroom.raw"
+        );
+        assert!(config.input_subject("news.room", None).is_err());
+
+        // Tenants and routes combine
+        let routed = NatsConfig {
+            subject: "disinfo.*.raw.>".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            routed.tenant_of("disinfo.newsroom.raw.telegram.image"),
+            Some("newsroom")
+        );
+        assert_eq!(
+            routed.route_of("disinfo.newsroom.raw.telegram.image"),
+            Some("telegram.image")
+        );
+        assert_eq!(
+            routed.input_subject("newsroom", Some("telegram")).unwrap(),
+            "disinfo.newsroom.raw.telegram"
+        );
+
+        // A priority subject of one tenant would be taken as its input
+        let priority = NatsConfig {
+            priority_subject: Some("disinfo.urgent.raw".to_string()),
+            ..config
+        };
+        assert!(priority.stream_subjects().is_err());
+    }
+
+    #[test]
+    fn test_priority_subject_is_apart() {
+        let config = NatsConfig {
+            priority_subject: Some("disinfo.raw.urgent".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.stream_subjects().unwrap(),
+            vec!["disinfo.raw", "disinfo.raw.urgent"]
+        );
+        assert_eq!(
+            NatsConfig::default().stream_subjects().unwrap(),
+            vec!["disinfo.raw"]
+        );
+
+        // The input subject would take urgent inputs too
+        let wildcard = NatsConfig {
+            subject: "disinfo.raw.>".to_string(),
+            ..config.clone()
+        };
+        assert!(wildcard.stream_subjects().is_err());
+        let same = NatsConfig {
+            subject: "disinfo.raw.urgent".to_string(),
+            ..config
+        };
+        assert!(same.stream_subjects().is_err());
+    }
+}
@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Named entity recognition
+//!
+//! Rules such as "mentions a sanctioned organisation and comes from an
+//! untrusted source" need to know who and what a text is about. The NER
+//! model tags entity spans in the content text; each distinct entity
+//! becomes a `mentions(entity, type)` fact and, when enabled, an `Entity`
+//! node in Dgraph linked to the content that mentions it, so the knowledge
+//! graph grows with the traffic it describes.
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+
+use crate::fact_mapping::Fact;
+use crate::onnx_wrapper;
+
+/// NER model settings
+#[derive(Debug, Clone)]
+pub struct NerConfig {
+    /// Token classification model with BIO-tagged PER/ORG/LOC/MISC labels
+    pub model: String,
+    /// Entities below this confidence are dropped
+    pub min_confidence: f32,
+    /// Dgraph alpha HTTP endpoint entities are upserted into; `None`
+    /// keeps them out of the graph
+    pub dgraph_url: Option<String>,
+}
+
+/// An entity mentioned in the content
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entity {
+    /// Surface form with whitespace collapsed
+    pub text: String,
+    /// `person`, `organization`, `location` or `misc`
+    pub kind: String,
+    pub confidence: f32,
+}
+
+/// Entity type named by a model label, e.g. `B-ORG` or `I-PER`
+fn kind_of(label: &str) -> &'static str {
+    let label = label
+        .split_once('-')
+        .map_or(label, |(_, kind)| kind)
+        .to_ascii_uppercase();
+    match label.as_str() {
+        "PER" | "PERSON" => "person",
+        "ORG" | "ORGANIZATION" => "organization",
+        "LOC" | "LOCATION" | "GPE" => "location",
+        _ => "misc",
+    }
+}
+
+/// Extract the distinct entities of a text
+///
+/// An entity mentioned several times is kept once, with its highest
+/// confidence.
+pub async fn extract(config: &NerConfig, text: &str) -> Result<Vec<Entity>> {
+    let spans = onnx_wrapper::run_ner(&config.model, text).await?;
+    let mut entities: Vec<Entity> = Vec::new();
+    for (span, label, confidence) in spans {
+        let text = span.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.is_empty() || confidence < config.min_confidence {
+            continue;
+        }
+        let kind = kind_of(&label);
+        match entities
+            .iter_mut()
+            .find(|e| e.kind == kind && e.text.eq_ignore_ascii_case(&text))
+        {
+            Some(entity) => entity.confidence = entity.confidence.max(confidence),
+            None => entities.push(Entity {
+                text,
+                kind: kind.to_string(),
+                confidence,
+            }),
+        }
+    }
+    Ok(entities)
+}
+
+/// One `mentions(entity, type)` fact per entity
+pub fn mention_facts(entities: &[Entity]) -> Vec<Fact> {
+    entities
+        .iter()
+        .map(|e| Fact::new("mentions", vec![e.text.clone(), e.kind.clone()]))
+        .collect()
+}
+
+/// Writes mentioned entities into the knowledge graph
+pub struct EntityGraph {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl EntityGraph {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    /// Upsert the entities and link them from the content node
+    ///
+    /// Entities and content are matched on `entity.key` and
+    /// `content.hash`, so repeated mentions reuse the existing nodes.
+    pub async fn upsert(&self, content_hash: &str, entities: &[Entity]) -> Result<()> {
+        if entities.is_empty() {
+            return Ok(());
+        }
+        let body = self
+            .client
+            .post(format!(
+                "{}/mutate?commitNow=true",
+                self.url.trim_end_matches('/')
+            ))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&upsert_request(content_hash, entities))?)
+            .send()
+            .await
+            .context("Dgraph upsert failed")?
+            .error_for_status()
+            .context("Dgraph returned an error")?
+            .bytes()
+            .await?;
+        let response: serde_json::Value =
+            serde_json::from_slice(&body).context("Dgraph returned invalid JSON")?;
+        if let Some(errors) = response.get("errors") {
+            bail!("Dgraph upsert errors: {}", errors);
+        }
+        Ok(())
+    }
+}
+
+/// Dgraph upsert block for one content node and its entities
+fn upsert_request(content_hash: &str, entities: &[Entity]) -> serde_json::Value {
+    let mut query = format!("content as var(func: eq(content.hash, {:?}))", content_hash);
+    let mut mentions = Vec::with_capacity(entities.len());
+    for (i, entity) in entities.iter().enumerate() {
+        let key = format!("{}:{}", entity.kind, entity.text.to_lowercase());
+        query.push_str(&format!(" e{} as var(func: eq(entity.key, {:?}))", i, key));
+        mentions.push(json!({
+            "uid": format!("uid(e{})", i),
+            "dgraph.type": "Entity",
+            "entity.key": key,
+            "entity.name": entity.text,
+            "entity.type": entity.kind,
+        }));
+    }
+    json!({
+        "query": format!("{{ {} }}", query),
+        "set": [{
+            "uid": "uid(content)",
+            "dgraph.type": "Content",
+            "content.hash": content_hash,
+            "content.mentions": mentions,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(text: &str, kind: &str) -> Entity {
+        Entity {
+            text: text.to_string(),
+            kind: kind.to_string(),
+            confidence: 0.9,
+        }
+    }
+
+    #[test]
+    fn test_labels_and_facts() {
+        assert_eq!(kind_of("B-ORG"), "organization");
+        assert_eq!(kind_of("I-PER"), "person");
+        assert_eq!(kind_of("GPE"), "location");
+        assert_eq!(kind_of("B-EVENT"), "misc");
+        assert_eq!(
+            mention_facts(&[entity("World Health Organization", "organization")]),
+            vec![Fact::new(
+                "mentions",
+                vec![
+                    "World Health Organization".to_string(),
+                    "organization".to_string()
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_upsert_request_reuses_nodes() {
+        let request = upsert_request("abc123", &[entity("Acme Corp", "organization")]);
+        assert_eq!(
+            request["query"],
+            r#"{ content as var(func: eq(content.hash, "abc123")) e0 as var(func: eq(entity.key, "organization:acme corp")) }"#
+        );
+        let mention = &request["set"][0]["content.mentions"][0];
+        assert_eq!(mention["uid"], "uid(e0)");
+        assert_eq!(mention["entity.type"], "organization");
+    }
+}
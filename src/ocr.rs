@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Text extraction from images
+//!
+//! Much disinformation is text baked into memes. When an input carries an
+//! image, an [`OcrEngine`] reads the text in it and the result is appended
+//! to `content_text` before language detection and text inference. Engines
+//! are an ONNX text-recognition model or the `tesseract` binary.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use std::{path::PathBuf, process::Stdio, str::FromStr, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command, time::timeout};
+use tracing::info;
+
+/// Deadline for reading the text of one image
+pub const OCR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which engine reads text from images
+#[derive(Debug, Clone, PartialEq)]
+pub enum OcrBackend {
+    /// Text detection and recognition ONNX model
+    Onnx { model: String },
+    /// The `tesseract` command line tool
+    Tesseract { binary: PathBuf, languages: String },
+}
+
+/// Engine name as configured in `NSAI_OCR`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OcrKind {
+    Onnx,
+    Tesseract,
+}
+
+impl FromStr for OcrKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "onnx" => Ok(Self::Onnx),
+            "tesseract" => Ok(Self::Tesseract),
+            other => bail!("unknown OCR engine: {}", other),
+        }
+    }
+}
+
+/// Reads the text in an encoded image
+pub trait OcrEngine: Send + Sync {
+    fn extract<'a>(&'a self, image: &'a [u8]) -> BoxFuture<'a, Result<String>>;
+}
+
+/// Build the configured engine
+pub fn from_config(backend: &OcrBackend) -> Box<dyn OcrEngine> {
+    match backend {
+        OcrBackend::Onnx { model } => Box::new(OnnxOcr {
+            model: model.clone(),
+        }),
+        OcrBackend::Tesseract { binary, languages } => Box::new(TesseractOcr {
+            binary: binary.clone(),
+            languages: languages.clone(),
+        }),
+    }
+}
+
+/// ONNX text detection and recognition
+pub struct OnnxOcr {
+    model: String,
+}
+
+impl OcrEngine for OnnxOcr {
+    fn extract<'a>(&'a self, image: &'a [u8]) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            // Placeholder implementation
+            // In production, this would run a text detector (e.g. DBNet)
+            // over the decoded image, crop each detected line and decode it
+            // with the recognition model (e.g. CRNN with CTC decoding).
+            let decoded = image::load_from_memory(image).context("Failed to decode image")?;
+            info!(
+                "OCR with {} on {}x{} image (placeholder)",
+                self.model,
+                decoded.width(),
+                decoded.height()
+            );
+            Ok(String::new())
+        })
+    }
+}
+
+/// Text extraction with `tesseract stdin stdout`
+pub struct TesseractOcr {
+    binary: PathBuf,
+    /// Tesseract language packs, e.g. `eng+ukr`
+    languages: String,
+}
+
+impl OcrEngine for TesseractOcr {
+    fn extract<'a>(&'a self, image: &'a [u8]) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let mut child = Command::new(&self.binary)
+                .args(["stdin", "stdout", "-l", &self.languages])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .with_context(|| format!("Failed to run {}", self.binary.display()))?;
+
+            let mut stdin = child.stdin.take().context("tesseract stdin unavailable")?;
+            let run = async {
+                stdin.write_all(image).await?;
+                drop(stdin);
+                child.wait_with_output().await
+            };
+            let output = timeout(OCR_TIMEOUT, run)
+                .await
+                .context("tesseract timed out")??;
+            if !output.status.success() {
+                bail!(
+                    "tesseract exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        })
+    }
+}
+
+/// Append text read from an image to the input text
+///
+/// OCR output is broken into the image's lines and often padded with
+/// blank lines; it is collapsed to single spaces first.
+pub fn merge_text(text: &str, ocr: &str) -> String {
+    let ocr = ocr.split_whitespace().collect::<Vec<_>>().join(" ");
+    match (text.trim().is_empty(), ocr.is_empty()) {
+        (_, true) => text.to_string(),
+        (true, false) => ocr,
+        (false, false) => format!("{}\n{}", text, ocr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_text() {
+        assert_eq!(
+            merge_text("Look at this", "VACCINES\n\n  CONTAIN\nMICROCHIPS\n"),
+            "Look at this\nVACCINES CONTAIN MICROCHIPS"
+        );
+        assert_eq!(merge_text("", "TEXT ONLY"), "TEXT ONLY");
+        assert_eq!(merge_text("Caption", " \n "), "Caption");
+    }
+
+    #[test]
+    fn test_ocr_kind_parse() {
+        assert_eq!("Tesseract".parse::<OcrKind>().unwrap(), OcrKind::Tesseract);
+        assert_eq!("onnx".parse::<OcrKind>().unwrap(), OcrKind::Onnx);
+        assert!("easyocr".parse::<OcrKind>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_tesseract_is_an_error() {
+        let engine = from_config(&OcrBackend::Tesseract {
+            binary: PathBuf::from("/nonexistent/tesseract"),
+            languages: "eng".to_string(),
+        });
+        assert!(engine.extract(b"not an image").await.is_err());
+    }
+}
@@ -3,12 +3,172 @@
 
 //! ONNX Runtime wrapper for neural inference
 
-use anyhow::Result;
-use std::collections::HashMap;
+use anyhow::{bail, Context, Result};
+use futures::future::join_all;
+use prometheus::Histogram;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::Path,
+    str::FromStr,
+};
 use tracing::info;
 
-/// Neural feature output from ONNX inference
-pub type NeuralFeatures = HashMap<String, f32>;
+use crate::attribution::{AttributionMethod, TokenAttribution};
+use crate::claim_matching::ClaimMatch;
+use crate::metrics::ModelMetrics;
+use crate::model_pb;
+use crate::ner::Entity;
+use crate::session_pool::{SessionOptions, SessionPool};
+use crate::stance::StanceScore;
+
+/// Raw named outputs of a single model, keyed by output tensor name
+pub type ModelOutputs = HashMap<String, f32>;
+
+/// Neural feature output handed to the symbolic layer
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NeuralFeatures {
+    /// 0.0 (authentic) to 1.0 (fake)
+    pub fakeness: f32,
+    /// Emotional manipulation index
+    pub emotion: f32,
+    /// 0.0 (human-written) to 1.0 (LLM-generated text)
+    pub ai_generated: f32,
+    /// AI-generated image indicators
+    pub visual_artifact: bool,
+    /// Dense content embedding, empty when not produced
+    pub embedding: Vec<f32>,
+    /// Strongest token contributions, empty when not computed
+    pub attributions: Vec<TokenAttribution>,
+    /// Stance towards each configured claim, empty when not computed
+    pub stances: Vec<StanceScore>,
+    /// Named entities in the content, empty when not extracted
+    pub entities: Vec<Entity>,
+    /// Fact-checks of claims in the content, empty when not matched; not
+    /// published
+    pub claim_matches: Vec<ClaimMatch>,
+    /// Known campaigns the content matches, empty when not cross-referenced;
+    /// not published
+    pub campaigns: Vec<String>,
+    /// Low-credibility domains the content links to, empty when not
+    /// checked; not published
+    pub lowcred_domains: Vec<String>,
+    /// Version of the model (or ensemble) that produced the features
+    pub model_version: String,
+}
+
+impl NeuralFeatures {
+    /// Build typed features from raw model outputs
+    ///
+    /// Missing required outputs are an error rather than a silent `0.0`.
+    pub fn from_outputs(outputs: &ModelOutputs, model_version: &str) -> Result<Self> {
+        let required = |name: &str| {
+            outputs
+                .get(name)
+                .copied()
+                .with_context(|| format!("model output {} missing", name))
+        };
+
+        Ok(Self {
+            fakeness: required("fakeness_score")?,
+            emotion: required("emotion_score")?,
+            // Only models with the AI-text head emit it
+            ai_generated: outputs.get("ai_generated_score").copied().unwrap_or(0.0),
+            visual_artifact: outputs.get("visual_artifact").is_some_and(|v| *v > 0.5),
+            embedding: Vec::new(),
+            attributions: Vec::new(),
+            stances: Vec::new(),
+            entities: Vec::new(),
+            claim_matches: Vec::new(),
+            campaigns: Vec::new(),
+            lowcred_domains: Vec::new(),
+            model_version: model_version.to_string(),
+        })
+    }
+}
+
+impl From<&NeuralFeatures> for model_pb::NeuralFeatures {
+    fn from(features: &NeuralFeatures) -> Self {
+        Self {
+            fakeness_score: features.fakeness,
+            emotion_score: features.emotion,
+            ai_generated_score: features.ai_generated,
+            visual_artifact: features.visual_artifact,
+            embedding: features.embedding.clone(),
+            model_version: features.model_version.clone(),
+            attributions: features
+                .attributions
+                .iter()
+                .map(|a| model_pb::TokenAttribution {
+                    token: a.token.clone(),
+                    score: a.score,
+                })
+                .collect(),
+            stances: features
+                .stances
+                .iter()
+                .map(|s| model_pb::StanceScore {
+                    topic: s.topic.clone(),
+                    support: s.support,
+                    deny: s.deny,
+                })
+                .collect(),
+            entities: features
+                .entities
+                .iter()
+                .map(|e| model_pb::Entity {
+                    text: e.text.clone(),
+                    kind: e.kind.clone(),
+                    confidence: e.confidence,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<model_pb::NeuralFeatures> for NeuralFeatures {
+    fn from(features: model_pb::NeuralFeatures) -> Self {
+        Self {
+            fakeness: features.fakeness_score,
+            emotion: features.emotion_score,
+            ai_generated: features.ai_generated_score,
+            visual_artifact: features.visual_artifact,
+            embedding: features.embedding,
+            attributions: features
+                .attributions
+                .into_iter()
+                .map(|a| TokenAttribution {
+                    token: a.token,
+                    score: a.score,
+                })
+                .collect(),
+            stances: features
+                .stances
+                .into_iter()
+                .map(|s| StanceScore {
+                    topic: s.topic,
+                    support: s.support,
+                    deny: s.deny,
+                })
+                .collect(),
+            entities: features
+                .entities
+                .into_iter()
+                .map(|e| Entity {
+                    text: e.text,
+                    kind: e.kind,
+                    confidence: e.confidence,
+                })
+                .collect(),
+            claim_matches: Vec::new(),
+            campaigns: Vec::new(),
+            lowcred_domains: Vec::new(),
+            model_version: features.model_version,
+        }
+    }
+}
 
 /// Initialize the ONNX runtime
 ///
@@ -23,14 +183,47 @@ pub fn init_runtime() -> Result<()> {
     Ok(())
 }
 
+/// Input and output tensor names of an ONNX model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelSchema {
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+/// Load a model file and read its input and output schema
+pub fn inspect_model(path: &Path) -> Result<ModelSchema> {
+    // Placeholder implementation
+    // In production, this would load the graph with ort and list
+    // session.inputs and session.outputs.
+    let metadata =
+        fs::metadata(path).with_context(|| format!("Failed to read model {}", path.display()))?;
+    if metadata.len() == 0 {
+        bail!("Model file {} is empty", path.display());
+    }
+    Ok(ModelSchema {
+        inputs: vec!["input_ids".to_string(), "attention_mask".to_string()],
+        outputs: vec![
+            "fakeness_score".to_string(),
+            "emotion_score".to_string(),
+            "ai_generated_score".to_string(),
+        ],
+    })
+}
+
 /// Run neural inference on content
 ///
 /// # Arguments
 /// * `content_hash` - Hash of the content to analyze
 ///
 /// # Returns
-/// Map of feature names to scores
+/// Typed features from the default model
 pub async fn run_inference(content_hash: &str) -> Result<NeuralFeatures> {
+    let outputs = run_model_outputs(content_hash).await?;
+    NeuralFeatures::from_outputs(&outputs, "detector")
+}
+
+/// Run the ONNX session and return its named outputs
+pub async fn run_model_outputs(content_hash: &str) -> Result<ModelOutputs> {
     // Placeholder implementation
     // In production, this would:
     // 1. Fetch content by hash
@@ -40,11 +233,422 @@ pub async fn run_inference(content_hash: &str) -> Result<NeuralFeatures> {
 
     let _ = content_hash; // Suppress unused warning
 
-    let mut features = HashMap::new();
-    features.insert("fakeness_score".to_string(), 0.5);
-    features.insert("emotion_score".to_string(), 0.3);
+    let mut outputs = HashMap::new();
+    outputs.insert("fakeness_score".to_string(), 0.5);
+    outputs.insert("emotion_score".to_string(), 0.3);
+    // AI-text head: a classifier over the encoder output, combined with
+    // the perplexity of the text under a reference language model
+    outputs.insert("ai_generated_score".to_string(), 0.2);
+
+    Ok(outputs)
+}
+
+/// Run the text encoder and return its pooled sentence embedding
+///
+/// # Arguments
+/// * `content_hash` - Hash of the content to analyze
+/// * `dim` - Expected embedding dimension
+pub async fn run_embedding(content_hash: &str, dim: usize) -> Result<Vec<f32>> {
+    // Placeholder implementation
+    // In production, this would mean-pool the encoder's last hidden state
+    // (e.g. a 384-dim MiniLM output) over the non-padding tokens.
+    let raw: Vec<f32> = (0..dim)
+        .map(|i| {
+            let mut hasher = DefaultHasher::new();
+            (content_hash, i).hash(&mut hasher);
+            (hasher.finish() as f64 / u64::MAX as f64 * 2.0 - 1.0) as f32
+        })
+        .collect();
+
+    if raw.len() != dim {
+        bail!("embedding has {} dimensions, expected {}", raw.len(), dim);
+    }
+    Ok(normalize(raw))
+}
+
+/// Run the stance model on a (text, claim) pair
+///
+/// # Returns
+/// Probabilities that the text supports and denies the claim
+pub async fn run_stance(model: &str, text: &str, claim: &str) -> Result<(f32, f32)> {
+    // Placeholder implementation
+    // In production, this would tokenize the pair as
+    // `[CLS] text [SEP] claim [SEP]`, run the NLI session and softmax its
+    // entailment/contradiction/neutral logits.
+    let mut hasher = DefaultHasher::new();
+    (model, text, claim).hash(&mut hasher);
+    let h = hasher.finish();
+    let support = (h & 0xffff) as f32 / 0xffff as f32 * 0.3;
+    let deny = ((h >> 16) & 0xffff) as f32 / 0xffff as f32 * 0.3;
+    Ok((support, deny))
+}
 
-    Ok(features)
+/// Run the NER model over a text
+///
+/// # Returns
+/// Entity spans with their label (e.g. `B-ORG`) and confidence
+pub async fn run_ner(model: &str, text: &str) -> Result<Vec<(String, String, f32)>> {
+    // Placeholder implementation
+    // In production, this would tokenize the text, run the token
+    // classification session and merge consecutive B-/I- tags into spans
+    // scored by their mean token probability.
+    let _ = model;
+    let mut spans = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    for word in text.split_whitespace().chain(std::iter::once("")) {
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric());
+        if word.chars().next().is_some_and(char::is_uppercase) {
+            current.push(word);
+        } else if !current.is_empty() {
+            spans.push((current.join(" "), "B-MISC".to_string(), 0.6));
+            current.clear();
+        }
+    }
+    Ok(spans)
+}
+
+/// Run the model's attribution head over the input tokens
+///
+/// # Arguments
+/// * `content_hash` - Hash of the content to analyze
+/// * `tokens` - Input tokens, in order
+/// * `method` - Attribution method exported by the model
+///
+/// # Returns
+/// One signed score per token
+pub async fn run_attribution(
+    content_hash: &str,
+    tokens: &[String],
+    method: AttributionMethod,
+) -> Result<Vec<f32>> {
+    // Placeholder implementation
+    // In production, this would run the exported attribution graph:
+    // integrated gradients over 32 interpolation steps from a padding
+    // baseline, or the final layer's [CLS] attention, summed over the
+    // subword pieces of each token.
+    let scores = tokens
+        .iter()
+        .enumerate()
+        .map(|(i, token)| {
+            let mut hasher = DefaultHasher::new();
+            (
+                content_hash,
+                i,
+                token,
+                method == AttributionMethod::Attention,
+            )
+                .hash(&mut hasher);
+            let score = (hasher.finish() as f64 / u64::MAX as f64 * 2.0 - 1.0) as f32;
+            match method {
+                // Attention weights are not signed
+                AttributionMethod::Attention => score.abs(),
+                AttributionMethod::IntegratedGradients => score,
+            }
+        })
+        .collect();
+    Ok(scores)
+}
+
+/// Scale a vector to unit length, leaving the zero vector unchanged
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+/// Sentence embedding output settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbeddingConfig {
+    /// Ensemble member whose encoder produces the embedding
+    pub model: String,
+    /// Embedding dimension, e.g. 384
+    pub dim: usize,
+}
+
+/// A single ONNX model participating in the ensemble
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelSpec {
+    /// Model name, e.g. `fakeness`, `emotion`, `clickbait`
+    pub name: String,
+    /// Path to the ONNX file
+    pub path: String,
+    /// Weight used by [`FusionStrategy::Weighted`]
+    pub weight: f32,
+    /// Registry version, when resolved through the model registry
+    pub version: Option<String>,
+}
+
+impl FromStr for ModelSpec {
+    type Err = anyhow::Error;
+
+    /// Parse `name:path[:weight]`, where the path may be a URI
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((name, rest)) = s.split_once(':') else {
+            bail!("invalid model spec (expected name:path[:weight]): {}", s);
+        };
+        let (path, weight) = if rest.contains("://") {
+            // Only a number after the URI's path is a weight, not a port
+            match rest.rsplit_once(':') {
+                Some((uri, weight))
+                    if uri
+                        .split_once("://")
+                        .is_some_and(|(_, after)| after.contains('/')) =>
+                {
+                    (uri, weight.parse()?)
+                }
+                _ => (rest, 1.0),
+            }
+        } else {
+            match rest.split_once(':') {
+                Some((path, weight)) => (path, weight.parse()?),
+                None => (rest, 1.0),
+            }
+        };
+        Ok(Self {
+            name: name.to_string(),
+            path: path.to_string(),
+            weight,
+            version: None,
+        })
+    }
+}
+
+/// How per-model scores for the same feature are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FusionStrategy {
+    Mean,
+    Max,
+    Weighted,
+}
+
+impl FusionStrategy {
+    /// Fuse `(score, weight)` pairs into a single score
+    pub fn fuse(self, scores: &[(f32, f32)]) -> f32 {
+        if scores.is_empty() {
+            return 0.0;
+        }
+        match self {
+            Self::Mean => scores.iter().map(|(s, _)| s).sum::<f32>() / scores.len() as f32,
+            Self::Max => scores
+                .iter()
+                .map(|(s, _)| *s)
+                .fold(f32::NEG_INFINITY, f32::max),
+            Self::Weighted => {
+                let total: f32 = scores.iter().map(|(_, w)| w).sum();
+                if total <= 0.0 {
+                    return Self::Mean.fuse(scores);
+                }
+                scores.iter().map(|(s, w)| s * w).sum::<f32>() / total
+            }
+        }
+    }
+}
+
+impl FromStr for FusionStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mean" => Ok(Self::Mean),
+            "max" => Ok(Self::Max),
+            "weighted" => Ok(Self::Weighted),
+            other => bail!("unknown fusion strategy: {}", other),
+        }
+    }
+}
+
+/// Set of models run concurrently on the same input
+pub struct Ensemble {
+    members: Vec<(ModelSpec, SessionPool)>,
+    fusion: FusionStrategy,
+    queue_wait: Option<Histogram>,
+    model_metrics: Option<ModelMetrics>,
+    /// Index of the member producing embeddings, and their dimension
+    embedding: Option<(usize, usize)>,
+}
+
+impl Ensemble {
+    pub fn new(models: Vec<ModelSpec>, fusion: FusionStrategy, sessions: &SessionOptions) -> Self {
+        let members = models
+            .into_iter()
+            .map(|model| {
+                let pool = SessionPool::new(&model, sessions);
+                (model, pool)
+            })
+            .collect();
+        Self {
+            members,
+            fusion,
+            queue_wait: None,
+            model_metrics: None,
+            embedding: None,
+        }
+    }
+
+    /// Also output a sentence embedding from the configured member
+    pub fn with_embedding(mut self, config: &EmbeddingConfig) -> Result<Self> {
+        let member = self
+            .members
+            .iter()
+            .position(|(model, _)| model.name == config.model)
+            .with_context(|| format!("embedding model {} is not in the ensemble", config.model))?;
+        self.embedding = Some((member, config.dim));
+        Ok(self)
+    }
+
+    /// Record time spent waiting for a free session
+    pub fn with_queue_wait(mut self, histogram: Histogram) -> Self {
+        self.queue_wait = Some(histogram);
+        self
+    }
+
+    /// Record latency, output scores and errors of every member
+    pub fn with_model_metrics(mut self, metrics: ModelMetrics) -> Self {
+        self.model_metrics = Some(metrics);
+        self
+    }
+
+    /// Models in the ensemble
+    pub fn models(&self) -> impl Iterator<Item = &ModelSpec> {
+        self.members.iter().map(|(model, _)| model)
+    }
+
+    /// Total number of sessions across all members
+    pub fn sessions(&self) -> usize {
+        self.members.iter().map(|(_, pool)| pool.size()).sum()
+    }
+
+    /// Runs that can use a session at once: the smallest member pool
+    pub fn concurrency(&self) -> usize {
+        self.members
+            .iter()
+            .map(|(_, pool)| pool.size())
+            .min()
+            .unwrap_or(1)
+    }
+
+    /// Run every model concurrently and fuse their outputs
+    ///
+    /// A feature is fused only over the models that produced it, so a
+    /// model that does not emit e.g. `emotion_score` does not drag the
+    /// fused score towards zero.
+    pub async fn run(&self, content_hash: &str) -> Result<NeuralFeatures> {
+        let embed = async {
+            match self.embedding {
+                Some((member, dim)) => {
+                    self.members[member]
+                        .1
+                        .embed(content_hash, dim, self.queue_wait.as_ref())
+                        .await
+                }
+                None => Ok(Vec::new()),
+            }
+        };
+        let (outputs, embedding) = futures::join!(
+            join_all(self.members.iter().map(|(model, pool)| self.run_member(
+                model,
+                pool,
+                content_hash
+            )),),
+            embed
+        );
+
+        let mut per_output: HashMap<String, Vec<(f32, f32)>> = HashMap::new();
+        for ((model, _), output) in self.members.iter().zip(outputs) {
+            for (name, score) in output? {
+                per_output
+                    .entry(name)
+                    .or_default()
+                    .push((score, model.weight));
+            }
+        }
+
+        let fused: ModelOutputs = per_output
+            .into_iter()
+            .map(|(name, scores)| (name, self.fusion.fuse(&scores)))
+            .collect();
+
+        let mut features = NeuralFeatures::from_outputs(&fused, &self.version())?;
+        features.embedding = embedding?;
+        Ok(features)
+    }
+
+    /// Run one member, recording its metrics
+    async fn run_member(
+        &self,
+        model: &ModelSpec,
+        pool: &SessionPool,
+        content_hash: &str,
+    ) -> Result<ModelOutputs> {
+        let Some(metrics) = &self.model_metrics else {
+            return pool.run(content_hash, self.queue_wait.as_ref(), None).await;
+        };
+        let version = model.version.as_deref().unwrap_or("");
+        let latency = metrics
+            .latency
+            .with_label_values(&[model.name.as_str(), version]);
+        let outputs = pool
+            .run(content_hash, self.queue_wait.as_ref(), Some(&latency))
+            .await;
+        match &outputs {
+            Ok(outputs) => {
+                for (output, score) in outputs {
+                    metrics
+                        .scores
+                        .with_label_values(&[model.name.as_str(), version, output.as_str()])
+                        .observe(f64::from(*score));
+                }
+            }
+            Err(_) => metrics
+                .errors
+                .with_label_values(&[model.name.as_str(), version])
+                .inc(),
+        }
+        outputs
+    }
+
+    /// Run dummy inferences so sessions are initialized before real traffic
+    ///
+    /// Each round runs one inference per pooled session at once, so every
+    /// session is warmed rather than only the first one checked out.
+    pub async fn warm_up(&self, rounds: usize) -> Result<()> {
+        let width = self
+            .members
+            .iter()
+            .map(|(_, pool)| pool.size())
+            .max()
+            .unwrap_or(0);
+        for round in 0..rounds {
+            let content_hash = format!("warmup-{}", round);
+            for result in join_all((0..width).map(|_| self.run(&content_hash))).await {
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a batch of inputs; each result matches the input at its position
+    pub async fn run_batch(&self, content_hashes: &[&str]) -> Vec<Result<NeuralFeatures>> {
+        // Placeholder implementation
+        // In production, the inputs would be stacked into one tensor per
+        // model and run through a single session call.
+        join_all(content_hashes.iter().map(|hash| self.run(hash))).await
+    }
+
+    /// Version string identifying the ensemble members, e.g.
+    /// `fakeness@1.2.0+emotion@0.3.1`
+    pub fn version(&self) -> String {
+        self.members
+            .iter()
+            .map(|(m, _)| match &m.version {
+                Some(version) => format!("{}@{}", m.name, version),
+                None => m.name.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("+")
+    }
 }
 
 #[cfg(test)]
@@ -54,7 +658,175 @@ mod tests {
     #[tokio::test]
     async fn test_run_inference() {
         let features = run_inference("test_hash").await.unwrap();
-        assert!(features.contains_key("fakeness_score"));
-        assert!(features.contains_key("emotion_score"));
+        assert!((0.0..=1.0).contains(&features.fakeness));
+        assert!((0.0..=1.0).contains(&features.emotion));
+        assert_eq!(features.model_version, "detector");
+    }
+
+    #[test]
+    fn test_fusion_strategies() {
+        let scores = [(0.2, 1.0), (0.8, 3.0)];
+        assert!((FusionStrategy::Mean.fuse(&scores) - 0.5).abs() < 1e-6);
+        assert!((FusionStrategy::Max.fuse(&scores) - 0.8).abs() < 1e-6);
+        assert!((FusionStrategy::Weighted.fuse(&scores) - 0.65).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_model_spec_parse() {
+        let spec: ModelSpec = "emotion:models/emotion.onnx:0.5".parse().unwrap();
+        assert_eq!(spec.name, "emotion");
+        assert_eq!(spec.weight, 0.5);
+        assert!("emotion".parse::<ModelSpec>().is_err());
+        assert!("emotion:a.onnx:heavy".parse::<ModelSpec>().is_err());
+
+        let spec: ModelSpec = "emotion:https://host:8443/emotion.onnx#sha256=ab:0.5"
+            .parse()
+            .unwrap();
+        assert_eq!(spec.path, "https://host:8443/emotion.onnx#sha256=ab");
+        assert_eq!(spec.weight, 0.5);
+        let spec: ModelSpec = "emotion:https://host:8443".parse().unwrap();
+        assert_eq!(spec.path, "https://host:8443");
+        assert_eq!(spec.weight, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_ensemble_run() {
+        let ensemble = Ensemble::new(
+            vec![
+                "fakeness:a.onnx".parse().unwrap(),
+                "clickbait:b.onnx".parse().unwrap(),
+            ],
+            FusionStrategy::Mean,
+            &SessionOptions::default(),
+        );
+        let features = ensemble.run("test_hash").await.unwrap();
+        assert_eq!(features.model_version, "fakeness+clickbait");
+    }
+
+    #[tokio::test]
+    async fn test_ensemble_embedding() {
+        let models = vec![
+            "fakeness:a.onnx".parse().unwrap(),
+            "encoder:b.onnx".parse().unwrap(),
+        ];
+        let config = EmbeddingConfig {
+            model: "encoder".to_string(),
+            dim: 384,
+        };
+        let ensemble = Ensemble::new(models, FusionStrategy::Mean, &SessionOptions::default())
+            .with_embedding(&config)
+            .unwrap();
+
+        let features = ensemble.run("test_hash").await.unwrap();
+        assert_eq!(features.embedding.len(), 384);
+        let norm: f32 = features.embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-4);
+        assert_eq!(ensemble.run("test_hash").await.unwrap(), features);
+
+        let missing = EmbeddingConfig {
+            model: "missing".to_string(),
+            dim: 384,
+        };
+        let ensemble = Ensemble::new(
+            vec!["fakeness:a.onnx".parse().unwrap()],
+            FusionStrategy::Mean,
+            &SessionOptions::default(),
+        );
+        assert!(ensemble.with_embedding(&missing).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_uses_every_session() {
+        let queue_wait = Histogram::with_opts(prometheus::HistogramOpts::new("w", "w")).unwrap();
+        let ensemble = Ensemble::new(
+            vec![
+                "fakeness:a.onnx".parse().unwrap(),
+                "clickbait:b.onnx".parse().unwrap(),
+            ],
+            FusionStrategy::Mean,
+            &SessionOptions {
+                pool_size: 3,
+                ..Default::default()
+            },
+        )
+        .with_queue_wait(queue_wait.clone());
+        assert_eq!(ensemble.concurrency(), 3);
+
+        ensemble.warm_up(2).await.unwrap();
+        // 2 rounds x 3 sessions x 2 members
+        assert_eq!(queue_wait.get_sample_count(), 12);
+    }
+
+    #[tokio::test]
+    async fn test_model_metrics_per_member() {
+        let metrics = crate::metrics::Metrics::new().unwrap();
+        let ensemble = Ensemble::new(
+            vec![
+                "fakeness:a.onnx".parse().unwrap(),
+                "clickbait:b.onnx".parse().unwrap(),
+            ],
+            FusionStrategy::Mean,
+            &SessionOptions::default(),
+        )
+        .with_model_metrics(metrics.models.clone());
+
+        ensemble.run("abc").await.unwrap();
+        let models = &metrics.models;
+        assert_eq!(
+            models
+                .latency
+                .with_label_values(&["fakeness", ""])
+                .get_sample_count(),
+            1
+        );
+        assert_eq!(
+            models
+                .scores
+                .with_label_values(&["clickbait", "", "fakeness_score"])
+                .get_sample_count(),
+            1
+        );
+        assert_eq!(
+            models.errors.with_label_values(&["fakeness", ""]).get(),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_missing_output_is_error() {
+        let mut outputs = HashMap::new();
+        outputs.insert("emotion_score".to_string(), 0.3);
+        assert!(NeuralFeatures::from_outputs(&outputs, "v1").is_err());
+    }
+
+    #[test]
+    fn test_protobuf_conversion_roundtrip() {
+        let features = NeuralFeatures {
+            fakeness: 0.9,
+            emotion: 0.1,
+            ai_generated: 0.7,
+            visual_artifact: true,
+            embedding: vec![0.5, -0.5],
+            attributions: vec![TokenAttribution {
+                token: "cure".to_string(),
+                score: 0.4,
+            }],
+            stances: vec![StanceScore {
+                topic: "vaccines".to_string(),
+                support: 0.1,
+                deny: 0.8,
+            }],
+            entities: vec![Entity {
+                text: "Acme Corp".to_string(),
+                kind: "organization".to_string(),
+                confidence: 0.8,
+            }],
+            claim_matches: Vec::new(),
+            campaigns: Vec::new(),
+            lowcred_domains: Vec::new(),
+            model_version: "v2".to_string(),
+        };
+        let pb = model_pb::NeuralFeatures::from(&features);
+        assert_eq!(NeuralFeatures::from(pb), features);
     }
 }
@@ -0,0 +1,2204 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Per-message neuro-symbolic pipeline
+
+use anyhow::{Context, Result};
+use async_nats::jetstream::message::Message as JetStreamMessage;
+use prost::Message;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+use tokio::time::timeout;
+use tracing::{error, info, warn};
+
+use crate::attribution;
+use crate::batcher::InferenceBatcher;
+use crate::calibration::Calibration;
+use crate::campaigns::{LiveCampaigns, Observed};
+use crate::canary::CanaryVerifier;
+use crate::claim_matching::{self, ClaimMatcher};
+use crate::clock::{self, Clock, SystemClock};
+use crate::config::Config;
+use crate::content_object::{self, ObjectContent, TooLarge};
+use crate::content_store::{self, ContentFetcher};
+use crate::content_type::ContentType;
+use crate::decision_context::{ContextRecorder, DecisionTrace, ModelHash, ServiceContext};
+use crate::dedup::Deduplicator;
+use crate::delivery::Delivery;
+use crate::enrichment::{self, Enricher};
+use crate::explanations::ExplanationTemplates;
+use crate::fact_dump::{FactDump, FactDumper};
+use crate::fact_mapping::NEURAL_BINS;
+use crate::fallback::{Fallback, FallbackConfig};
+use crate::feature_cache::{self, cache_key, FeatureCache};
+use crate::graph_batcher::GraphBatcher;
+use crate::graph_breaker::GraphBreaker;
+use crate::graph_cache::GraphCache;
+use crate::graph_snapshot::SnapshotGraph;
+use crate::history::VerdictHistory;
+use crate::image_hash::{self, KnownFakeImages};
+use crate::knowledge_graph::{self, KnowledgeGraph, KnowledgeGraphBackend, VerdictRecord};
+use crate::language;
+use crate::links::LinkChecker;
+use crate::live_rules::LiveRules;
+use crate::maintenance::{CacheCompaction, Compact, HistorySnapshot, IdleTask, ModelSelfTest};
+use crate::metrics::Metrics;
+use crate::model_pb::{AnalysisInput, AnalysisResult, Timing, ANALYSIS_RESULT_SCHEMA_VERSION};
+use crate::model_registry::ModelRegistry;
+use crate::nats;
+use crate::ner::{self, EntityGraph};
+use crate::ocr::{self, OcrBackend, OcrEngine};
+use crate::onnx_wrapper::{Ensemble, FusionStrategy, ModelSpec, NeuralFeatures};
+use crate::pipeline_graph::{PipelineGraph, Stage};
+use crate::postprocess;
+use crate::propagation::MessageContext;
+use crate::publisher::ResultPublisher;
+use crate::quota::{OverflowAction, QuotaTracker};
+use crate::reasoning::ReasoningEngine;
+use crate::reasoning_cache::ReasoningCache;
+use crate::reasoning_pool::{ReasoningPool, Saturated};
+use crate::recency;
+use crate::reevaluation::Reevaluator;
+use crate::retry;
+use crate::rule_packs::RulePacks;
+use crate::session_pool::SessionOptions;
+use crate::shadow::ShadowRunner;
+use crate::similarity::SimilarityIndex;
+use crate::souffle_wrapper::{self, Aggregation, ConflictPolicy, ReasoningResult, SymbolicBackend};
+use crate::stance;
+use crate::telemetry::TelemetryAggregator;
+use crate::thresholds::Thresholds;
+use crate::topic::{self, TopicMonitor};
+use crate::transport::{Received, Transport};
+use crate::validation::{self, RejectReason, REJECTED};
+use crate::verdict_store::VerdictStore;
+use crate::vision_wrapper::{self, ImageAnalyzer};
+use crate::webhook::WebhookSinks;
+
+/// Shared state for processing messages
+pub struct Pipeline {
+    pub config: Config,
+    pub metrics: Arc<Metrics>,
+    pub ensemble: Arc<Ensemble>,
+    /// Dedicated ensembles by detected language
+    routes: HashMap<String, Arc<Ensemble>>,
+    /// Specialized ensembles by classified topic
+    topic_routes: HashMap<String, Arc<Ensemble>>,
+    /// Ensembles by route of the NATS subject
+    subject_routes: HashMap<String, Arc<Ensemble>>,
+    /// Compares topic-routed verdicts with the default ensemble's
+    topics: Option<TopicMonitor>,
+    /// Scores messages the primary models failed on; `None` when unset
+    fallback: Option<Fallback>,
+    /// Batches concurrent inferences; `None` when batching is disabled
+    pub batcher: Option<Arc<InferenceBatcher>>,
+    pub feature_cache: Option<Box<dyn FeatureCache>>,
+    pub image_analyzer: ImageAnalyzer,
+    /// Rules in force, reloaded by `live_rules::run_reloader`
+    pub rules: Arc<LiveRules>,
+    /// Evaluates `rules` through the bounded reasoning pool, and the
+    /// reasoning cache when enabled
+    reasoning: Arc<dyn ReasoningEngine>,
+    /// Derivations by rules version and facts; `None` when disabled
+    reasoning_cache: Option<Arc<ReasoningCache>>,
+    /// Re-evaluates recent verdicts on fact updates; `None` when disabled
+    pub reevaluation: Option<Arc<Reevaluator>>,
+    /// Bin edges discretizing the scores for the rules
+    thresholds: Thresholds,
+    /// Combines the weights of weighted rules into the confidence
+    aggregation: Aggregation,
+    /// Renders explanations from fired rules; `None` when unset
+    explanations: Option<ExplanationTemplates>,
+    /// Resolves contradictory verdicts of the rules
+    conflicts: ConflictPolicy,
+    /// Reads text in images into the input; `None` when OCR is disabled
+    ocr: Option<Box<dyn OcrEngine>>,
+    /// Perceptual hashes of known manipulated images; `None` when unset
+    known_images: Option<KnownFakeImages>,
+    /// Matches claims of the content against fact-checks; `None` when no
+    /// corpus is set
+    claim_matcher: Option<ClaimMatcher>,
+    /// Indicators of known campaigns, reloaded by
+    /// `campaigns::run_reloader`; `None` when unset
+    pub campaigns: Option<Arc<LiveCampaigns>>,
+    /// Judges linked domains; `None` when no list or API is set
+    link_checker: Option<LinkChecker>,
+    pub publisher: ResultPublisher,
+    pub telemetry: Option<Arc<TelemetryAggregator>>,
+    pub similarity: Option<Arc<SimilarityIndex>>,
+    pub shadow: Option<ShadowRunner>,
+    calibration: Calibration,
+    canary: Option<CanaryVerifier>,
+    /// Resolves content text by hash when producers send only the hash
+    content: Option<ContentFetcher>,
+    /// Reads text sent as an object reference; `None` unless buckets are
+    /// configured
+    objects: Option<ObjectContent>,
+    /// Answers repeated inputs with their published result; `None` when
+    /// disabled
+    dedup: Option<Arc<Deduplicator>>,
+    /// Latest verdict per content for other services; `None` unless a
+    /// bucket is set
+    verdicts: Option<VerdictStore>,
+    /// Persists the context each verdict was decided in
+    contexts: Option<ContextRecorder>,
+    /// Dumps the facts of requested messages; `None` without a sink
+    fact_dump: Option<FactDumper>,
+    /// Verdict history served to the rules as aggregate facts
+    pub history: Option<Arc<VerdictHistory>>,
+    /// Time the ages of knowledge graph facts are computed against
+    clock: Arc<dyn Clock>,
+    /// Upserts extracted entities; `None` unless NER upserts are enabled
+    entity_graph: Option<EntityGraph>,
+    /// Looks up the facts about sources; `None` when no graph is set
+    knowledge_graph: Option<Arc<dyn KnowledgeGraph>>,
+    /// Resolves source metadata; `None` when no provider is set
+    enricher: Option<Enricher>,
+    /// Source lookup cache wrapped around `knowledge_graph`, kept to shed it
+    graph_cache: Option<Arc<GraphCache>>,
+    /// Snapshot behind `knowledge_graph`, reloaded by
+    /// `graph_snapshot::run_reloader`; `None` unless the graph is one
+    pub graph_snapshot: Option<Arc<SnapshotGraph>>,
+    /// Daily quota accounting; `None` when no quota is configured
+    quotas: Option<QuotaTracker>,
+    /// Republishes inputs deferred by the quota; `None` unless the
+    /// transport is NATS
+    jetstream: Option<async_nats::jetstream::Context>,
+}
+
+impl Pipeline {
+    pub async fn new(
+        config: Config,
+        metrics: Arc<Metrics>,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Self> {
+        // The features built on JetStream need the NATS transport
+        let client = transport.nats().cloned();
+        let post_processors = config
+            .publish
+            .post_processors
+            .iter()
+            .map(postprocess::from_spec)
+            .collect::<Result<Vec<_>>>()?;
+        if !post_processors.is_empty() {
+            let names: Vec<&str> = post_processors.iter().map(|p| p.name()).collect();
+            info!("Result post-processors: {}", names.join(" -> "));
+        }
+        let mut publisher = ResultPublisher::new(Arc::clone(&transport), config.publish.clone())
+            .with_post_processors(post_processors);
+        if let Some(webhooks) = &config.publish.webhooks {
+            publisher =
+                publisher.with_webhooks(WebhookSinks::spawn(webhooks, Arc::clone(&metrics))?);
+        }
+        let jetstream = client.clone().map(async_nats::jetstream::new);
+        let registry = config
+            .inference
+            .registry
+            .as_deref()
+            .map(|root| {
+                ModelRegistry::open(root)
+                    .map(|r| r.with_approval_key(config.inference.approval_key.clone()))
+            })
+            .transpose()?;
+        let resolve = |spec: &ModelSpec| match &registry {
+            Some(registry) => registry.resolve(spec),
+            None => Ok(spec.clone()),
+        };
+        let models = config
+            .inference
+            .models
+            .iter()
+            .map(&resolve)
+            .collect::<Result<Vec<_>>>()?;
+        let mut ensemble =
+            Ensemble::new(models, config.inference.fusion, &config.inference.sessions)
+                .with_queue_wait(metrics.inference_queue_wait.clone())
+                .with_model_metrics(metrics.models.clone());
+        if let Some(embedding) = &config.inference.embedding {
+            ensemble = ensemble.with_embedding(embedding)?;
+            info!(
+                "Publishing {}-dim embeddings from {}",
+                embedding.dim, embedding.model
+            );
+        }
+        let ensemble = Arc::new(ensemble);
+        info!(
+            "Serving models {} with {} sessions",
+            ensemble.version(),
+            ensemble.sessions()
+        );
+
+        let fallback = match &config.inference.fallback {
+            Some(FallbackConfig::Model(spec)) => Some(Fallback::Model(
+                Ensemble::new(
+                    vec![resolve(spec)?],
+                    FusionStrategy::Mean,
+                    &SessionOptions::default(),
+                )
+                .with_model_metrics(metrics.models.clone()),
+            )),
+            Some(FallbackConfig::Heuristic) => Some(Fallback::Heuristic),
+            None => None,
+        };
+        if let Some(fallback) = &fallback {
+            info!(
+                "Falling back to {} on inference failure",
+                fallback.version()
+            );
+        }
+
+        let route_ensembles = |routed: HashMap<String, Vec<ModelSpec>>| {
+            routed
+                .into_iter()
+                .map(|(key, models)| {
+                    let ensemble =
+                        Ensemble::new(models, config.inference.fusion, &config.inference.sessions)
+                            .with_queue_wait(metrics.inference_queue_wait.clone())
+                            .with_model_metrics(metrics.models.clone());
+                    info!("Routing {} to models {}", key, ensemble.version());
+                    (key, Arc::new(ensemble))
+                })
+                .collect::<HashMap<_, _>>()
+        };
+        let mut routed: HashMap<String, Vec<ModelSpec>> = HashMap::new();
+        for route in &config.inference.language_models {
+            routed
+                .entry(route.language.clone())
+                .or_default()
+                .push(resolve(&route.model)?);
+        }
+        let routes = route_ensembles(routed);
+        let mut routed: HashMap<String, Vec<ModelSpec>> = HashMap::new();
+        for route in &config.inference.topic_models {
+            routed
+                .entry(route.topic.clone())
+                .or_default()
+                .push(resolve(&route.model)?);
+        }
+        let topic_routes = route_ensembles(routed);
+        let mut routed: HashMap<String, Vec<ModelSpec>> = HashMap::new();
+        for route in &config.inference.route_models {
+            routed
+                .entry(route.route.clone())
+                .or_default()
+                .push(resolve(&route.model)?);
+        }
+        let subject_routes = route_ensembles(routed);
+        metrics.inference_sessions.set(
+            (ensemble.sessions()
+                + routes
+                    .values()
+                    .chain(topic_routes.values())
+                    .chain(subject_routes.values())
+                    .map(|e| e.sessions())
+                    .sum::<usize>()) as f64,
+        );
+        let batcher = config.inference.batch.enabled().then(|| {
+            Arc::new(InferenceBatcher::spawn(
+                Arc::clone(&ensemble),
+                config.inference.batch.clone(),
+            ))
+        });
+        let feature_cache = feature_cache::from_config(&config.feature_cache).await?;
+        let telemetry = config.telemetry.as_ref().map(|t| {
+            Arc::new(TelemetryAggregator::new(
+                t.source_salt.clone(),
+                t.allowed_fields.clone(),
+            ))
+        });
+        let similarity = config
+            .similarity
+            .as_ref()
+            .map(|s| Arc::new(SimilarityIndex::new(s.capacity, s.min_similarity)));
+        let calibration = Calibration::new(&config.inference.calibration);
+        if !calibration.is_identity() {
+            info!("Calibrating scores: {:?}", calibration);
+        }
+
+        let thresholds = Thresholds::load(&config.bins, config.bins_file.as_deref())?;
+        if !thresholds.global().is_empty() || thresholds.scopes() > 0 {
+            info!(
+                "Bin thresholds: {:?}, {} language and tenant overrides",
+                thresholds.global(),
+                thresholds.scopes()
+            );
+        }
+
+        let explanations = match &config.explanations {
+            Some(e) => {
+                let templates = ExplanationTemplates::load(e)?;
+                info!(
+                    "Explanation templates: {} locales, default {}",
+                    templates.locales(),
+                    e.locale
+                );
+                Some(templates)
+            }
+            None => None,
+        };
+
+        let rules = Arc::new(LiveRules::load(
+            &config.symbolic,
+            config.rules_dir.as_deref(),
+            &config.souffle_sandbox,
+            &metrics,
+        )?);
+        let in_process = matches!(
+            rules.current().backend,
+            SymbolicBackend::Embedded | SymbolicBackend::Ascent
+        );
+        if !in_process {
+            config.souffle_sandbox.check()?;
+        }
+        let mut reasoning: Arc<dyn ReasoningEngine> = Arc::new(ReasoningPool::new(
+            Arc::clone(&rules) as Arc<dyn ReasoningEngine>,
+            in_process,
+            &config.reasoning_pool,
+            Arc::clone(&metrics),
+        ));
+        info!(
+            "Reasoning pool: {} workers, queue of {}",
+            config.reasoning_pool.workers.max(1),
+            config.reasoning_pool.queue
+        );
+        let reasoning_cache = config.reasoning_cache.as_ref().map(|c| {
+            info!("Reasoning cache: {} entries for {:?}", c.capacity, c.ttl);
+            Arc::new(ReasoningCache::new(
+                Arc::clone(&reasoning),
+                Arc::clone(&rules),
+                c,
+                Arc::clone(&metrics),
+            ))
+        });
+        if let Some(cache) = &reasoning_cache {
+            reasoning = Arc::clone(cache) as Arc<dyn ReasoningEngine>;
+        }
+        let topics = (!topic_routes.is_empty()).then(|| {
+            TopicMonitor::new(
+                Arc::clone(&ensemble),
+                config.inference.topic_compare_rate,
+                config.inference.timeout,
+                calibration.clone(),
+                Arc::clone(&reasoning),
+                config.conflicts,
+                Arc::clone(&metrics),
+            )
+        });
+        let reevaluation = config.reevaluation.as_ref().map(|c| {
+            info!(
+                "Re-evaluating up to {} recent verdicts on fact updates from {}",
+                c.capacity, c.subject
+            );
+            Arc::new(Reevaluator::new(
+                c.capacity,
+                Arc::clone(&reasoning),
+                config.aggregation,
+                config.conflicts,
+                Arc::clone(&metrics),
+            ))
+        });
+
+        let content = match &config.content_store {
+            Some(store) => Some(ContentFetcher::new(
+                content_store::from_config(&store.backend, client.clone()).await?,
+                store,
+                Arc::clone(&metrics),
+            )),
+            None => None,
+        };
+
+        let objects = match &config.content_objects {
+            Some(c) => {
+                let jetstream = jetstream
+                    .clone()
+                    .context("Object content needs the NATS transport")?;
+                Some(ObjectContent::new(c, jetstream, Arc::clone(&metrics)))
+            }
+            None => None,
+        };
+
+        let dedup = match &config.dedup {
+            Some(c) => {
+                let dedup = Deduplicator::new(c, jetstream.as_ref(), Arc::clone(&metrics)).await?;
+                info!("Deduplication: {} for {:?}", dedup.describe(), c.ttl);
+                Some(Arc::new(dedup))
+            }
+            None => None,
+        };
+
+        let verdicts = match &config.verdict_store {
+            Some(c) => {
+                let jetstream = jetstream
+                    .as_ref()
+                    .context("The verdict store needs the NATS transport")?;
+                let verdicts = VerdictStore::new(c, jetstream, Arc::clone(&metrics)).await?;
+                info!("Latest verdicts -> bucket {}", verdicts.bucket());
+                Some(verdicts)
+            }
+            None => None,
+        };
+
+        let canary = config
+            .canary
+            .clone()
+            .map(|c| CanaryVerifier::new(c, Arc::clone(&metrics)));
+
+        let fact_dump = match &config.fact_dump {
+            Some(dump) => Some(FactDumper::new(
+                dump.clone(),
+                Arc::clone(&transport),
+                Arc::clone(&metrics),
+            )?),
+            None => None,
+        };
+
+        let shadow = config.shadow.as_ref().map(|s| {
+            ShadowRunner::new(
+                s,
+                config.inference.timeout,
+                Arc::clone(&transport),
+                Arc::clone(&metrics),
+            )
+            .with_calibration(calibration.clone())
+            .with_reasoning(Arc::clone(&reasoning))
+            .with_conflicts(config.conflicts)
+        });
+        if let Some(shadow) = &shadow {
+            info!(
+                "Shadowing {} on {:.1}% of traffic",
+                shadow.version(),
+                shadow.sample_rate() * 100.0
+            );
+        }
+        let ocr = config.inference.ocr.as_ref().map(ocr::from_config);
+        let known_images = match &config.inference.known_images {
+            Some(known) => {
+                let images = KnownFakeImages::load(known)?;
+                info!("Loaded {} known fake image hashes", images.count());
+                Some(images)
+            }
+            None => None,
+        };
+        let claim_matcher = match &config.claim_matching {
+            Some(matching) => {
+                let matcher = ClaimMatcher::new(claim_matching::from_config(matching)?, matching);
+                info!("Matching claims against {} fact-checks", matcher.name());
+                Some(matcher)
+            }
+            None => None,
+        };
+        let enricher = match &config.enrichment {
+            Some(enrichment) => {
+                let enricher = Enricher::new(
+                    enrichment::from_config(enrichment)?,
+                    enrichment,
+                    Arc::clone(&metrics),
+                );
+                info!("Enriching sources from {}", enricher.names().join(", "));
+                Some(enricher)
+            }
+            None => None,
+        };
+        let campaigns = match &config.campaigns {
+            Some(campaigns) => {
+                let live = LiveCampaigns::load(campaigns)?;
+                info!("Loaded {} known campaigns", live.current().count());
+                Some(Arc::new(live))
+            }
+            None => None,
+        };
+        let link_checker = match &config.link_reputation {
+            Some(reputation) => {
+                let checker = LinkChecker::new(reputation)?;
+                let (allowed, denied) = checker.listed();
+                info!(
+                    "Link reputation: {} allowed and {} denied domains{}",
+                    allowed,
+                    denied,
+                    if reputation.api.is_some() {
+                        ", API"
+                    } else {
+                        ""
+                    }
+                );
+                Some(checker)
+            }
+            None => None,
+        };
+
+        let contexts = match &config.publish.context_dir {
+            Some(dir) => {
+                let flags = [
+                    ("batching", batcher.is_some()),
+                    ("feature_cache", feature_cache.is_some()),
+                    ("embedding", config.inference.embedding.is_some()),
+                    ("attribution", config.inference.attribution.is_some()),
+                    ("ocr", ocr.is_some()),
+                    ("known_images", known_images.is_some()),
+                    ("claim_matching", claim_matcher.is_some()),
+                    ("campaigns", campaigns.is_some()),
+                    ("link_reputation", link_checker.is_some()),
+                    ("stance", config.inference.stance.is_some()),
+                    ("fallback", fallback.is_some()),
+                    ("history", config.history.is_some()),
+                    ("knowledge_graph", config.knowledge_graph.is_some()),
+                    ("source_enrichment", enricher.is_some()),
+                    (
+                        "graph_cache",
+                        config
+                            .knowledge_graph
+                            .as_ref()
+                            .is_some_and(|g| g.cache.is_some()),
+                    ),
+                    (
+                        "graph_batching",
+                        config
+                            .knowledge_graph
+                            .as_ref()
+                            .is_some_and(|g| g.batch.is_some()),
+                    ),
+                    (
+                        "coordination",
+                        config
+                            .knowledge_graph
+                            .as_ref()
+                            .is_some_and(|g| g.coordination.is_some()),
+                    ),
+                    ("ner", config.inference.ner.is_some()),
+                    ("content_store", content.is_some()),
+                    ("content_object", objects.is_some()),
+                    ("dedup", dedup.is_some()),
+                    ("verdict_store", verdicts.is_some()),
+                    ("request_reply", config.request_reply.is_some()),
+                    ("kafka", transport.name() == "kafka"),
+                    ("topic_compare", topics.is_some()),
+                    ("shadow", shadow.is_some()),
+                    ("similarity", similarity.is_some()),
+                    ("telemetry", telemetry.is_some()),
+                    ("canary", canary.is_some()),
+                    ("rule_packs", config.rules_dir.is_some()),
+                    ("reasoning_cache", reasoning_cache.is_some()),
+                    ("reevaluation", reevaluation.is_some()),
+                    ("explanations", explanations.is_some()),
+                ];
+                let fallback_model = match &fallback {
+                    Some(Fallback::Model(ensemble)) => Some(ensemble),
+                    _ => None,
+                };
+                let mut models: Vec<ModelHash> = std::iter::once(ensemble.as_ref())
+                    .chain(routes.values().map(Arc::as_ref))
+                    .chain(topic_routes.values().map(Arc::as_ref))
+                    .chain(subject_routes.values().map(Arc::as_ref))
+                    .chain(fallback_model)
+                    .flat_map(|e| e.models())
+                    .map(ModelHash::of)
+                    .collect();
+                models.sort_by(|a, b| (&a.name, &a.path).cmp(&(&b.name, &b.path)));
+                models.dedup();
+                let service = ServiceContext {
+                    service_version: env!("CARGO_PKG_VERSION").to_string(),
+                    rules_sha256: rules.current().manifest.sha256.clone(),
+                    rules_semver: rules.current().manifest.version.to_string(),
+                    flags: flags
+                        .iter()
+                        .filter(|(_, on)| *on)
+                        .map(|(flag, _)| flag.to_string())
+                        .collect(),
+                    bins: NEURAL_BINS
+                        .iter()
+                        .map(|spec| {
+                            let edges = spec.edges(thresholds.global());
+                            (spec.relation.to_string(), edges.to_vec())
+                        })
+                        .collect(),
+                    calibration: config
+                        .inference
+                        .calibration
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect(),
+                    fusion: format!("{:?}", config.inference.fusion).to_lowercase(),
+                    inference_timeout_ms: config.inference.timeout.as_millis() as u64,
+                    models,
+                };
+                Some(ContextRecorder::new(dir.clone(), service)?)
+            }
+            None => None,
+        };
+
+        let history = match &config.history {
+            Some(h) => Some(Arc::new(VerdictHistory::open(&h.path)?)),
+            None => None,
+        };
+
+        let entity_graph = config
+            .inference
+            .ner
+            .as_ref()
+            .and_then(|n| n.dgraph_url.clone())
+            .map(EntityGraph::new);
+        let mut graph_cache = None;
+        let mut graph_snapshot = None;
+        let knowledge_graph = match &config.knowledge_graph {
+            Some(graph_config) => {
+                let mut graph: Arc<dyn KnowledgeGraph> = match &graph_config.backend {
+                    KnowledgeGraphBackend::Snapshot { path, reload } => {
+                        let snapshot = Arc::new(SnapshotGraph::load(path, *reload)?);
+                        graph_snapshot = Some(Arc::clone(&snapshot));
+                        snapshot
+                    }
+                    _ => knowledge_graph::from_config(graph_config)?.into(),
+                };
+                info!("Looking up sources in {}", graph.name());
+                graph = Arc::new(GraphBreaker::new(
+                    graph,
+                    &graph_config.breaker,
+                    graph_config.timeout,
+                    Arc::clone(&metrics),
+                ));
+                // One breaker call per batch, and cache hits skip the batch
+                if let Some(b) = &graph_config.batch {
+                    info!(
+                        "Graph batching: up to {} sources per query, {:?} wait",
+                        b.max_size, b.max_wait
+                    );
+                    graph = Arc::new(GraphBatcher::spawn(graph, b, Arc::clone(&metrics)));
+                }
+                if let Some(c) = &graph_config.cache {
+                    info!(
+                        "Graph cache: {} sources for {:?}, {:?} stale",
+                        c.capacity, c.ttl, c.stale
+                    );
+                    let cache = Arc::new(GraphCache::new(graph, c, Arc::clone(&metrics)));
+                    graph = Arc::clone(&cache) as Arc<dyn KnowledgeGraph>;
+                    graph_cache = Some(cache);
+                }
+                Some(graph)
+            }
+            None => None,
+        };
+
+        let quotas = config
+            .quota
+            .clone()
+            .map(|q| QuotaTracker::new(q, Arc::clone(&metrics)));
+
+        // `config` itself moves into the pipeline
+        let aggregation = config.aggregation;
+        let conflicts = config.conflicts;
+
+        Ok(Self {
+            config,
+            metrics,
+            ensemble,
+            routes,
+            topic_routes,
+            subject_routes,
+            topics,
+            fallback,
+            batcher,
+            feature_cache,
+            image_analyzer: ImageAnalyzer::new()?,
+            rules,
+            reasoning,
+            thresholds,
+            reasoning_cache,
+            dedup,
+            verdicts,
+            reevaluation,
+            aggregation,
+            explanations,
+            conflicts,
+            ocr,
+            known_images,
+            claim_matcher,
+            campaigns,
+            link_checker,
+            publisher,
+            telemetry,
+            similarity,
+            shadow,
+            calibration,
+            canary,
+            content,
+            objects,
+            contexts,
+            fact_dump,
+            history,
+            clock: Arc::new(SystemClock),
+            entity_graph,
+            knowledge_graph,
+            enricher,
+            graph_cache,
+            graph_snapshot,
+            quotas,
+            jetstream,
+        })
+    }
+
+    /// Empty the in-memory caches under memory pressure
+    ///
+    /// # Returns
+    /// Number of entries dropped
+    pub fn shed_caches(&self) -> usize {
+        let mut shed = 0;
+        if let Some(cache) = &self.feature_cache {
+            shed += cache.shed();
+            if let Some(entries) = cache.local_entries() {
+                self.metrics.feature_cache_entries.set(entries as f64);
+            }
+        }
+        if let Some(similarity) = &self.similarity {
+            shed += similarity.clear();
+        }
+        if let Some(cache) = &self.reasoning_cache {
+            shed += cache.shed();
+        }
+        if let Some(cache) = &self.graph_cache {
+            shed += cache.shed();
+        }
+        if let Some(dedup) = &self.dedup {
+            shed += dedup.shed();
+        }
+        shed
+    }
+
+    /// Housekeeping run while the consumer is idle: the model self-test,
+    /// compaction of the caches with a TTL and a snapshot of the history
+    pub fn idle_tasks(&self) -> Vec<Box<dyn IdleTask>> {
+        let mut caches: Vec<Arc<dyn Compact>> = Vec::new();
+        if let Some(cache) = &self.reasoning_cache {
+            caches.push(Arc::clone(cache) as Arc<dyn Compact>);
+        }
+        if let Some(cache) = &self.graph_cache {
+            caches.push(Arc::clone(cache) as Arc<dyn Compact>);
+        }
+        if let Some(dedup) = &self.dedup {
+            caches.push(Arc::clone(dedup) as Arc<dyn Compact>);
+        }
+        let mut tasks: Vec<Box<dyn IdleTask>> = vec![Box::new(ModelSelfTest)];
+        if !caches.is_empty() {
+            tasks.push(Box::new(CacheCompaction::new(caches)));
+        }
+        if let Some(history) = &self.history {
+            tasks.push(Box::new(HistorySnapshot(Arc::clone(history))));
+        }
+        tasks
+    }
+
+    /// Warm the model sessions with dummy inferences
+    ///
+    /// Bypasses the batcher and feature cache so the sessions themselves
+    /// take the cold-start cost.
+    pub async fn warm_up(&self) -> Result<()> {
+        let rounds = self.config.inference.warmup_rounds;
+        if rounds == 0 {
+            return Ok(());
+        }
+        let start = Instant::now();
+        for ensemble in std::iter::once(&self.ensemble)
+            .chain(self.routes.values())
+            .chain(self.topic_routes.values())
+            .chain(self.subject_routes.values())
+        {
+            ensemble
+                .warm_up(rounds)
+                .await
+                .with_context(|| format!("Warm-up of {} failed", ensemble.version()))?;
+        }
+        info!(
+            "Warmed up models with {} rounds in {:?}",
+            rounds,
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Describe the stages messages pass through with this configuration
+    ///
+    /// Mirrors the order of [`Pipeline::process_message`]; keep both in
+    /// step when adding a stage.
+    pub fn graph(&self) -> PipelineGraph {
+        let config = &self.config;
+        let timeout = config.inference.timeout;
+        let mut models = vec![format!("default: {}", self.ensemble.version())];
+        let mut routes: Vec<String> = self
+            .routes
+            .iter()
+            .map(|(language, e)| format!("language:{}: {}", language, e.version()))
+            .chain(
+                self.topic_routes
+                    .iter()
+                    .map(|(topic, e)| format!("topic:{}: {}", topic, e.version())),
+            )
+            .chain(
+                self.subject_routes
+                    .iter()
+                    .map(|(route, e)| format!("subject:{}: {}", route, e.version())),
+            )
+            .collect();
+        routes.sort();
+        models.extend(routes);
+
+        let mut fusion = format!("fusion {:?}", config.inference.fusion).to_lowercase();
+        if self.batcher.is_some() {
+            fusion.push_str(", batched");
+        }
+        let ocr = match &config.inference.ocr {
+            Some(OcrBackend::Tesseract { languages, .. }) => Stage::new("ocr", true)
+                .timeout(ocr::OCR_TIMEOUT)
+                .detail(format!("tesseract {}", languages)),
+            Some(OcrBackend::Onnx { model }) => {
+                Stage::new("ocr", true).detail(format!("onnx {}", model))
+            }
+            None => Stage::new("ocr", false),
+        };
+        let active = self.rules.current();
+        let rules = match &active.backend {
+            SymbolicBackend::Embedded => Stage::new("rules", true).detail(format!(
+                "embedded, rules {}",
+                ServiceContext::rules_sha256()
+            )),
+            SymbolicBackend::Ascent => Stage::new("rules", true)
+                .detail(format!("ascent, rules {}", ServiceContext::rules_sha256())),
+            SymbolicBackend::Souffle { program, .. } => Stage::new("rules", true)
+                .timeout(souffle_wrapper::SOUFFLE_TIMEOUT)
+                .detail(format!("souffle {}", program.display())),
+            SymbolicBackend::Compiled { binary, .. } => Stage::new("rules", true)
+                .timeout(souffle_wrapper::SOUFFLE_TIMEOUT)
+                .detail(format!("compiled {}", binary.display())),
+            SymbolicBackend::CompileOnLoad { program, .. } => Stage::new("rules", true)
+                .timeout(souffle_wrapper::SOUFFLE_TIMEOUT)
+                .detail(format!("compiling {}", program.display())),
+        }
+        .models(
+            active
+                .packs
+                .iter()
+                .flat_map(RulePacks::packs)
+                .map(|pack| format!("{}@{}", pack.name, &pack.sha256[..12]))
+                .chain(
+                    active
+                        .packs
+                        .iter()
+                        .flat_map(RulePacks::shadow)
+                        .map(|pack| format!("{}@{} (shadow)", pack.name, &pack.sha256[..12])),
+                )
+                .chain(active.content_types.iter().flat_map(|set| {
+                    set.packs.packs().iter().map(move |pack| {
+                        format!("{}/{}@{}", set.content_type, pack.name, &pack.sha256[..12])
+                    })
+                }))
+                .collect(),
+        );
+        let post_processors = self.publisher.post_processor_names();
+
+        let stages = vec![
+            Stage::new("decode", true),
+            Stage::new("validate", true).after(&["decode"]),
+            Stage::new("dedup", self.dedup.is_some())
+                .detail(
+                    self.dedup
+                        .as_ref()
+                        .map(|dedup| dedup.describe())
+                        .unwrap_or_default(),
+                )
+                .after(&["validate"]),
+            Stage::new("quota", self.quotas.is_some())
+                .detail(match &self.quotas {
+                    Some(quotas) => format!("overflow {}", quotas.overflow().as_str()),
+                    None => String::new(),
+                })
+                .after(&["dedup"]),
+            Stage::new("content_object", self.objects.is_some())
+                .detail(
+                    self.config
+                        .content_objects
+                        .as_ref()
+                        .map(|c| c.buckets.join(", "))
+                        .unwrap_or_default(),
+                )
+                .after(&["quota"]),
+            Stage::new("content_fetch", self.content.is_some()).after(&["content_object"]),
+            Stage::new("image_download", true)
+                .best_effort()
+                .timeout(vision_wrapper::DOWNLOAD_TIMEOUT)
+                .after(&["content_fetch"]),
+            ocr.best_effort().after(&["image_download"]),
+            Stage::new("routing", true)
+                .detail("subject route, then language, then topic")
+                .after(&["ocr"]),
+            Stage::new("feature_cache", self.feature_cache.is_some()).after(&["routing"]),
+            Stage::new("inference", true)
+                .timeout(timeout)
+                .models(models)
+                .detail(fusion)
+                .after(&["feature_cache"]),
+            Stage::new("fallback", self.fallback.is_some())
+                .timeout(timeout)
+                .models(self.fallback.iter().map(Fallback::version).collect())
+                .after(&["inference"]),
+            Stage::new("calibration", !self.calibration.is_identity())
+                .detail(
+                    config
+                        .inference
+                        .calibration
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+                .after(&["inference"]),
+            Stage::new("image_analysis", true)
+                .best_effort()
+                .after(&["image_download", "inference"]),
+            Stage::new("known_images", self.known_images.is_some())
+                .best_effort()
+                .detail(match (&self.known_images, &config.inference.known_images) {
+                    (Some(images), Some(known)) => format!(
+                        "{} hashes, max distance {}",
+                        images.count(),
+                        known.max_distance
+                    ),
+                    _ => String::new(),
+                })
+                .after(&["image_download"]),
+            Stage::new("stance", config.inference.stance.is_some())
+                .best_effort()
+                .models(
+                    config
+                        .inference
+                        .stance
+                        .iter()
+                        .map(|s| s.model.clone())
+                        .collect(),
+                )
+                .after(&["inference"]),
+            Stage::new("campaigns", self.campaigns.is_some())
+                .after(&["image_download", "inference"]),
+            Stage::new("link_reputation", self.link_checker.is_some())
+                .best_effort()
+                .after(&["inference"]),
+            Stage::new("claim_matching", self.claim_matcher.is_some())
+                .best_effort()
+                .detail(
+                    self.claim_matcher
+                        .as_ref()
+                        .map_or("", |matcher| matcher.name()),
+                )
+                .after(&["inference"]),
+            Stage::new("ner", config.inference.ner.is_some())
+                .best_effort()
+                .models(
+                    config
+                        .inference
+                        .ner
+                        .iter()
+                        .map(|n| n.model.clone())
+                        .collect(),
+                )
+                .after(&["inference"]),
+            Stage::new("entity_upsert", self.entity_graph.is_some())
+                .best_effort()
+                .after(&["ner"]),
+            Stage::new("history", self.history.is_some()).after(&["validate"]),
+            Stage::new("source_facts", self.knowledge_graph.is_some())
+                .best_effort()
+                .detail(
+                    self.knowledge_graph
+                        .as_ref()
+                        .map_or("", |graph| graph.name()),
+                )
+                .after(&["validate"]),
+            Stage::new(
+                "coordination",
+                config
+                    .knowledge_graph
+                    .as_ref()
+                    .is_some_and(|graph| graph.coordination.is_some()),
+            )
+            .best_effort()
+            .after(&["source_facts"]),
+            Stage::new("source_enrichment", self.enricher.is_some())
+                .best_effort()
+                .detail(
+                    self.enricher
+                        .as_ref()
+                        .map_or(String::new(), |enricher| enricher.names().join(",")),
+                )
+                .after(&["validate"]),
+            Stage::new("facts", true).after(&[
+                "calibration",
+                "fallback",
+                "image_analysis",
+                "known_images",
+                "stance",
+                "campaigns",
+                "link_reputation",
+                "claim_matching",
+                "ner",
+                "history",
+                "source_facts",
+                "coordination",
+                "source_enrichment",
+            ]),
+            rules.after(&["facts"]),
+            Stage::new("fact_dump", self.fact_dump.is_some())
+                .best_effort()
+                .after(&["rules"]),
+            Stage::new("canary", self.canary.is_some()).after(&["rules"]),
+            Stage::new(
+                "verdict_write_back",
+                config
+                    .knowledge_graph
+                    .as_ref()
+                    .is_some_and(|graph| graph.write_back),
+            )
+            .best_effort()
+            .after(&["rules"]),
+            Stage::new("explanations", self.explanations.is_some()).after(&["rules"]),
+            Stage::new("attribution", config.inference.attribution.is_some())
+                .best_effort()
+                .after(&["rules"]),
+            Stage::new("telemetry", self.telemetry.is_some()).after(&["rules"]),
+            Stage::new("topic_compare", self.topics.is_some())
+                .timeout(timeout)
+                .models(vec![self.ensemble.version()])
+                .after(&["rules"]),
+            Stage::new("shadow", self.shadow.is_some())
+                .timeout(timeout)
+                .models(self.shadow.iter().map(ShadowRunner::version).collect())
+                .after(&["rules"]),
+            Stage::new("similarity", self.similarity.is_some()).after(&["rules"]),
+            Stage::new("decision_context", self.contexts.is_some())
+                .best_effort()
+                .after(&["attribution"]),
+            Stage::new("publish", true)
+                .detail(post_processors.join(" -> "))
+                .after(&["decision_context"]),
+            Stage::new("webhooks", config.publish.webhooks.is_some())
+                .detail(match &config.publish.webhooks {
+                    Some(webhooks) => format!(
+                        "{} destinations, batches of {} within {}ms",
+                        webhooks.urls.len(),
+                        webhooks.max_batch,
+                        webhooks.max_delay.as_millis()
+                    ),
+                    None => String::new(),
+                })
+                .after(&["publish"]),
+            Stage::new("verdict_store", self.verdicts.is_some())
+                .detail(
+                    self.verdicts
+                        .as_ref()
+                        .map(|verdicts| verdicts.bucket().to_string())
+                        .unwrap_or_default(),
+                )
+                .best_effort()
+                .after(&["publish"]),
+        ];
+        PipelineGraph {
+            service_version: env!("CARGO_PKG_VERSION").to_string(),
+            stages,
+        }
+    }
+
+    /// Process a single JetStream message and acknowledge it
+    pub async fn process_message(&self, msg: &JetStreamMessage) {
+        self.process(Delivery::Stream(msg)).await
+    }
+
+    /// Process a core NATS request and reply with its result
+    pub async fn process_request(
+        &self,
+        message: &async_nats::Message,
+        client: &async_nats::Client,
+    ) {
+        self.process(Delivery::Request { message, client }).await
+    }
+
+    /// Process an input of another transport; the transport settles it
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    pub async fn process_received(&self, input: &dyn Received) {
+        self.process(Delivery::Received(input)).await
+    }
+
+    /// Process one input and settle its delivery
+    async fn process(&self, msg: Delivery<'_>) {
+        let metrics = &self.metrics;
+        let start = Instant::now();
+        let received_at = self.clock.now();
+
+        // Parse protobuf message
+        let mut input = match AnalysisInput::decode(msg.payload()) {
+            Ok(input) => input,
+            Err(e) => {
+                error!("Unmarshal error: {}", e);
+                metrics.errors.inc();
+                self.reject(
+                    msg,
+                    &AnalysisInput::default(),
+                    RejectReason::MalformedPayload,
+                    format!("Payload is not an AnalysisInput: {}", e),
+                )
+                .await;
+                msg.ack().await;
+                return;
+            }
+        };
+
+        // On a tenant subject, the subject is who sent the input
+        let subject_tenant = self.config.nats.tenant_of(msg.subject());
+        if let Err(reason) = validation::validate(&input, &self.config.validation)
+            .and_then(|()| validation::check_subject_tenant(&input, subject_tenant))
+        {
+            warn!("Rejected input {:?}: {}", input.content_hash, reason);
+            self.reject(
+                msg,
+                &input,
+                reason,
+                format!("Input failed validation: {}", reason),
+            )
+            .await;
+            msg.ack().await;
+            return;
+        }
+
+        // Tenant metrics rank tenants by their messages, each counted once
+        metrics
+            .tenants
+            .observe(validation::tenant_of(&input.source_id));
+
+        // A repeat is answered with the result already published for it,
+        // without spending quota
+        if let Some(dedup) = &self.dedup {
+            if let Some(result) = dedup.get(&input.content_hash, &input.source_id).await {
+                info!(
+                    "Repeat of {} answered with its published {}",
+                    input.content_hash, result.verdict
+                );
+                metrics.latency.observe(start.elapsed().as_secs_f64());
+                match self.deliver(msg, &result).await {
+                    Ok(()) => msg.ack().await,
+                    Err(e) => {
+                        error!("Publish error: {}", e);
+                        metrics.errors.inc();
+                        msg.retry(&self.config.retry, "publish", metrics).await;
+                    }
+                }
+                return;
+            }
+        }
+
+        if let Some(quotas) = &self.quotas {
+            let tenant = validation::tenant_of(&input.source_id);
+            if !quotas.admit(tenant) {
+                self.over_quota(quotas, msg, &input).await;
+                return;
+            }
+        }
+
+        metrics.messages_processed.inc();
+
+        let canary = self.canary.as_ref().and_then(|verifier| {
+            verifier
+                .tag(msg.headers(), &input.content_hash)
+                .map(|tag| (verifier, tag))
+        });
+
+        // Text too large for a message is sent as an object reference
+        let object = input
+            .content_object
+            .clone()
+            .filter(|_| input.content_text.trim().is_empty());
+        if let (Some(objects), Some(object)) = (&self.objects, object) {
+            match objects.fetch_text(&object).await {
+                Ok(Some(text)) => input.content_text = text,
+                Ok(None) => {
+                    self.reject(
+                        msg,
+                        &input,
+                        RejectReason::ContentNotFound,
+                        format!("No object {}", content_object::describe(&object)),
+                    )
+                    .await;
+                    msg.ack().await;
+                    return;
+                }
+                Err(e) if e.is::<TooLarge>() => {
+                    self.reject(
+                        msg,
+                        &input,
+                        RejectReason::ContentTooLarge,
+                        format!("Object {}: {}", content_object::describe(&object), e),
+                    )
+                    .await;
+                    msg.ack().await;
+                    return;
+                }
+                Err(e) => {
+                    // The bucket may recover; hand the message back for redelivery
+                    error!("Content object fetch error: {:#}", e);
+                    metrics.errors.inc();
+                    msg.retry(&self.config.retry, "content_object", metrics)
+                        .await;
+                    return;
+                }
+            }
+        }
+
+        // Producers may send only the hash; resolve the text it names
+        let missing_text = input.content_text.trim().is_empty();
+        if let Some(content) = self.content.as_ref().filter(|_| missing_text) {
+            match content.fetch_text(&input.content_hash).await {
+                Ok(Some(text)) => input.content_text = text,
+                Ok(None) if !input.image_url.is_empty() => {}
+                Ok(None) => {
+                    self.reject(
+                        msg,
+                        &input,
+                        RejectReason::ContentNotFound,
+                        format!("No content stored for {}", input.content_hash),
+                    )
+                    .await;
+                    msg.ack().await;
+                    return;
+                }
+                Err(e) => {
+                    // The store may recover; hand the message back for redelivery
+                    error!("Content fetch error: {:#}", e);
+                    metrics.errors.inc();
+                    msg.retry(&self.config.retry, "content_store", metrics)
+                        .await;
+                    return;
+                }
+            }
+        }
+
+        // Everything besides the service settings that shaped this verdict
+        let mut trace = DecisionTrace::default();
+
+        // The image is fetched once: OCR reads it before text inference and
+        // the vision model after. Both are best-effort
+        let image = if input.image_url.is_empty() {
+            None
+        } else {
+            match self.image_analyzer.download(&input.image_url).await {
+                Ok(bytes) => Some(bytes),
+                Err(e) => {
+                    warn!("Image download failed for {}: {}", input.content_hash, e);
+                    metrics.errors.inc();
+                    trace.degrade("image_download");
+                    None
+                }
+            }
+        };
+        if let (Some(engine), Some(bytes)) = (&self.ocr, &image) {
+            match engine.extract(bytes).await {
+                Ok(text) if !text.trim().is_empty() => {
+                    input.content_text = ocr::merge_text(&input.content_text, &text);
+                    metrics.ocr_texts.inc();
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("OCR failed for {}: {:#}", input.content_hash, e);
+                    metrics.errors.inc();
+                    trace.degrade("ocr");
+                }
+            }
+        }
+
+        let language = language::detect(&input.content_text);
+        let topic = topic::classify(&input.content_text);
+        // A route the producer chose beats a dedicated language model,
+        // which beats a topic specialization, which beats the default
+        // ensemble
+        let subject_route = self.config.nats.route_of(msg.subject());
+        let subject_model =
+            subject_route.and_then(|route| Some((route, self.subject_routes.get(route)?)));
+        let (route, topic_routed) = match (subject_model, self.routes.get(language)) {
+            (Some((subject_route, route)), _) => {
+                trace.route = format!("subject:{}", subject_route);
+                (Some(route), false)
+            }
+            (None, Some(route)) => {
+                trace.route = format!("language:{}", language);
+                (Some(route), false)
+            }
+            (None, None) => {
+                let route = self.topic_routes.get(topic);
+                trace.route = match route {
+                    Some(_) => format!("topic:{}", topic),
+                    None => "default".to_string(),
+                };
+                (route, route.is_some())
+            }
+        };
+
+        // Neuro-Symbolic Pipeline
+        let inference_start = Instant::now();
+        let inferred = self.infer(&input, route, &mut trace).await;
+        let inference_time = inference_start.elapsed();
+        if let Err(e) = &inferred {
+            error!("ONNX inference error: {}", e);
+            metrics.errors.inc();
+        }
+        let mut neural_features = match (inferred, &self.fallback) {
+            (Ok(Some(mut features)), _) => {
+                // Raw scores become probabilities before the rule thresholds apply
+                self.calibration.apply(&mut features);
+                features
+            }
+            (failed, Some(fallback)) => {
+                let reason = if failed.is_ok() { "timeout" } else { "error" };
+                let deadline = self.config.inference.timeout;
+                match fallback
+                    .score(&input.content_hash, &input.content_text, deadline)
+                    .await
+                {
+                    Ok(features) => {
+                        metrics
+                            .fallback_inferences
+                            .with_label_values(&[reason])
+                            .inc();
+                        trace.model_version = fallback.version();
+                        trace.degrade("inference");
+                        features
+                    }
+                    Err(e) => {
+                        error!("Fallback inference error: {:#}", e);
+                        metrics.errors.inc();
+                        msg.retry(&self.config.retry, "inference", metrics).await;
+                        return;
+                    }
+                }
+            }
+            (Ok(None), None) => {
+                // Deadline exceeded: hand the message back for redelivery
+                msg.retry(&self.config.retry, "inference_timeout", metrics)
+                    .await;
+                return;
+            }
+            (Err(_), None) => {
+                msg.retry(&self.config.retry, "inference", metrics).await;
+                return;
+            }
+        };
+
+        // Image branch: visual features are best-effort and never drop the message
+        if let Some(bytes) = &image {
+            match self.image_analyzer.analyze(bytes).await {
+                Ok(visual) => visual.merge_into(&mut neural_features),
+                Err(e) => {
+                    warn!("Image analysis failed for {}: {}", input.content_hash, e);
+                    metrics.errors.inc();
+                    trace.degrade("image_analysis");
+                }
+            }
+        }
+        let known_fake_image = match (&self.known_images, &image) {
+            (Some(known), Some(bytes)) => match known.lookup(bytes) {
+                Ok(found) => {
+                    if let Some(found) = &found {
+                        info!(
+                            "Image of {} matches known fake \"{}\" ({:?}, distance {})",
+                            input.content_hash, found.label, found.kind, found.distance
+                        );
+                        metrics.known_fake_images.inc();
+                    }
+                    Some(found.is_some())
+                }
+                Err(e) => {
+                    warn!("Image hashing failed for {}: {:#}", input.content_hash, e);
+                    metrics.errors.inc();
+                    trace.degrade("image_hash");
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        if let Some(campaigns) = &self.campaigns {
+            let indicators = campaigns.current();
+            // An image that cannot be hashed was reported by the known
+            // images lookup, if enabled; its text is still matched
+            let hashes = image
+                .as_deref()
+                .filter(|_| indicators.wants_images())
+                .and_then(|bytes| image_hash::hash_image(bytes).ok());
+            neural_features.campaigns = indicators.matches(&Observed {
+                text: &input.content_text,
+                image: hashes,
+            });
+            for campaign in &neural_features.campaigns {
+                info!("{} matches campaign {}", input.content_hash, campaign);
+                metrics
+                    .campaign_matches
+                    .with_label_values(&[campaign])
+                    .inc();
+            }
+        }
+
+        // Best-effort: without stances the stance rules simply do not fire
+        if let Some(config) = &self.config.inference.stance {
+            match stance::detect(config, &input.content_text).await {
+                Ok(stances) => neural_features.stances = stances,
+                Err(e) => {
+                    warn!("Stance detection failed for {}: {}", input.content_hash, e);
+                    metrics.errors.inc();
+                    trace.degrade("stance");
+                }
+            }
+        }
+
+        // Best-effort: without domains the link rules do not fire
+        if let Some(checker) = &self.link_checker {
+            match checker.lowcred_domains(&input.content_text).await {
+                Ok(domains) => {
+                    metrics.lowcred_links.inc_by(domains.len() as f64);
+                    neural_features.lowcred_domains = domains;
+                }
+                Err(e) => {
+                    warn!("Link reputation failed for {}: {:#}", input.content_hash, e);
+                    metrics.errors.inc();
+                    trace.degrade("link_reputation");
+                }
+            }
+        }
+
+        // Best-effort: without matches the fact-check rules do not fire
+        if let Some(matcher) = &self.claim_matcher {
+            match matcher.matches(&input.content_text).await {
+                Ok(matches) => {
+                    metrics.claim_matches.inc_by(matches.len() as f64);
+                    neural_features.claim_matches = matches;
+                }
+                Err(e) => {
+                    warn!("Claim matching failed for {}: {:#}", input.content_hash, e);
+                    metrics.errors.inc();
+                    trace.degrade("claim_matching");
+                }
+            }
+        }
+
+        if let Some(config) = &self.config.inference.ner {
+            match ner::extract(config, &input.content_text).await {
+                Ok(entities) => neural_features.entities = entities,
+                Err(e) => {
+                    warn!("Entity extraction failed for {}: {}", input.content_hash, e);
+                    metrics.errors.inc();
+                    trace.degrade("ner");
+                }
+            }
+        }
+        if let Some(graph) = &self.entity_graph {
+            // The verdict does not depend on the graph write
+            if let Err(e) = graph
+                .upsert(&input.content_hash, &neural_features.entities)
+                .await
+            {
+                warn!("Entity upsert failed for {}: {:#}", input.content_hash, e);
+                metrics.errors.inc();
+                trace.degrade("entity_upsert");
+            }
+        }
+
+        // Without its graph facts a source is unknown to the rules, hence
+        // untrusted; a failed lookup is told apart by source_facts_unknown.
+        // An unreachable graph is waited out while deliveries remain
+        let (mut dgraph_facts, observed) = match &self.knowledge_graph {
+            Some(graph) => {
+                let now = self.clock.now();
+                match knowledge_graph::source_facts(graph.as_ref(), &input.source_id, now).await {
+                    Ok(facts) => facts,
+                    Err(e) if self.config.retry.graph && !msg.last_attempt(&self.config.retry) => {
+                        warn!("Source lookup failed for {}: {:#}", input.source_id, e);
+                        metrics.errors.inc();
+                        metrics.latency.observe(start.elapsed().as_secs_f64());
+                        msg.retry(&self.config.retry, "knowledge_graph", metrics)
+                            .await;
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Source lookup failed for {}: {:#}", input.source_id, e);
+                        metrics.errors.inc();
+                        trace.degrade("source_facts");
+                        let facts = [("source_facts_unknown".to_string(), "true".to_string())];
+                        (facts.into_iter().collect(), Default::default())
+                    }
+                }
+            }
+            None => Default::default(),
+        };
+        if let (Some(graph), Some(new_account_age)) = (
+            &self.knowledge_graph,
+            self.config
+                .knowledge_graph
+                .as_ref()
+                .and_then(|graph| graph.coordination),
+        ) {
+            let created_since = self
+                .clock
+                .now()
+                .checked_sub(new_account_age)
+                .unwrap_or(UNIX_EPOCH);
+            match graph
+                .coordination(&input.content_hash, &input.source_id, created_since)
+                .await
+            {
+                Ok(coordination) => dgraph_facts.extend(coordination.facts()),
+                Err(e) => {
+                    warn!(
+                        "Coordination lookup failed for {}: {:#}",
+                        input.content_hash, e
+                    );
+                    metrics.errors.inc();
+                    trace.degrade("coordination");
+                }
+            }
+        }
+        if let Some(enricher) = &self.enricher {
+            match enricher.facts(&input.source_id, self.clock.now()).await {
+                Ok(facts) => dgraph_facts.extend(facts),
+                Err(e) => {
+                    warn!("Source enrichment failed for {}: {:#}", input.source_id, e);
+                    metrics.errors.inc();
+                    trace.degrade("source_enrichment");
+                }
+            }
+        }
+        dgraph_facts.extend(recency::age_facts(&observed, self.clock.as_ref()));
+        dgraph_facts.insert("language".to_string(), language.to_string());
+        // Tenants with rule sets of their own are evaluated with them
+        dgraph_facts.insert(
+            "tenant".to_string(),
+            validation::tenant_of(&input.source_id).to_string(),
+        );
+        // A route naming a content type picks its rule set
+        let content_type = subject_route
+            .and_then(nats::route_content_type)
+            .unwrap_or_else(|| ContentType::of(&input));
+        dgraph_facts.insert("content_type".to_string(), content_type.to_string());
+        trace.content_type = content_type.to_string();
+        if let Some(known_fake_image) = known_fake_image {
+            dgraph_facts.insert("known_fake_image".to_string(), known_fake_image.to_string());
+        }
+        if let Some(history) = &self.history {
+            dgraph_facts.extend(history.facts(&input.source_id, &input.content_hash));
+        }
+
+        let bins = self
+            .thresholds
+            .resolve(language, validation::tenant_of(&input.source_id));
+        // Scoped thresholds are part of the decision; NSAI_BINS is in the
+        // service context
+        trace.bins = bins
+            .iter()
+            .filter(|(relation, edges)| self.thresholds.global().get(*relation) != Some(edges))
+            .map(|(relation, edges)| (relation.clone(), edges.clone()))
+            .collect();
+        let reasoning_start = Instant::now();
+        let reasoning = souffle_wrapper::run_datalog(
+            self.reasoning.as_ref(),
+            &neural_features,
+            &dgraph_facts,
+            &bins,
+            self.aggregation,
+            self.conflicts,
+        )
+        .await;
+        let reasoning_time = reasoning_start.elapsed();
+        match reasoning {
+            Ok(reasoning) => {
+                if let Some(dumper) = self
+                    .fact_dump
+                    .as_ref()
+                    .filter(|dumper| dumper.wanted(msg.headers()))
+                {
+                    let facts = souffle_wrapper::base_facts(&neural_features, &dgraph_facts, &bins);
+                    dumper
+                        .dump(&FactDump::new(
+                            &input.content_hash,
+                            &input.source_id,
+                            &facts,
+                            &reasoning,
+                        ))
+                        .await;
+                }
+                let ReasoningResult {
+                    verdict,
+                    mut explanation,
+                    confidence,
+                    fired_rules,
+                    rules_version,
+                    rules_semver,
+                    labels,
+                    derived: _,
+                } = reasoning;
+                if let Some(templates) = &self.explanations {
+                    let facts = souffle_wrapper::base_facts(&neural_features, &dgraph_facts, &bins);
+                    if let Some(rendered) =
+                        templates.render(language, &fired_rules, &facts, &neural_features)
+                    {
+                        explanation = rendered;
+                    }
+                }
+                info!(
+                    "Verdict for {}: {} ({:.2}) | {}",
+                    input.content_hash, verdict, confidence, explanation
+                );
+
+                // Canaries check the pipeline; they are not real content
+                // and never reach telemetry, the index or consumers
+                if let Some((verifier, tag)) = &canary {
+                    verifier.check(tag, verdict.as_str());
+                    metrics.latency.observe(start.elapsed().as_secs_f64());
+                    msg.ack().await;
+                    return;
+                }
+
+                // Best-effort: a verdict is published without attributions
+                // rather than not at all
+                if let Some(config) = &self.config.inference.attribution {
+                    match attribution::attribute(config, &input.content_hash, &input.content_text)
+                        .await
+                    {
+                        Ok(top) => {
+                            if !top.is_empty() {
+                                explanation =
+                                    format!("{}; {}", explanation, attribution::describe(&top));
+                            }
+                            neural_features.attributions = top;
+                        }
+                        Err(e) => {
+                            warn!("Attribution failed for {}: {}", input.content_hash, e);
+                            metrics.errors.inc();
+                            trace.degrade("attribution");
+                        }
+                    }
+                }
+
+                if let Some(telemetry) = &self.telemetry {
+                    telemetry.record(&input.source_id, verdict.as_str());
+                }
+                if let Some(history) = &self.history {
+                    history.record(&input.source_id, &input.content_hash, verdict.as_str());
+                }
+                if let Some(graph) = self.knowledge_graph.as_ref().filter(|_| {
+                    self.config
+                        .knowledge_graph
+                        .as_ref()
+                        .is_some_and(|graph| graph.write_back)
+                }) {
+                    // The verdict does not wait on the graph write
+                    let graph = Arc::clone(graph);
+                    let metrics = Arc::clone(metrics);
+                    let record = VerdictRecord {
+                        content_hash: input.content_hash.clone(),
+                        source_id: input.source_id.clone(),
+                        verdict,
+                        confidence,
+                        fired: fired_rules.iter().map(|f| f.rule.clone()).collect(),
+                        at: self.clock.now(),
+                    };
+                    tokio::spawn(async move {
+                        let outcome = match graph.record_verdict(&record).await {
+                            Ok(()) => "written",
+                            Err(e) => {
+                                warn!(
+                                    "Verdict write-back failed for {}: {:#}",
+                                    record.content_hash, e
+                                );
+                                "failed"
+                            }
+                        };
+                        metrics
+                            .verdict_write_backs
+                            .with_label_values(&[outcome])
+                            .inc();
+                    });
+                }
+                if let Some(reevaluation) = &self.reevaluation {
+                    reevaluation.record(
+                        &input.source_id,
+                        &input.content_hash,
+                        &neural_features,
+                        &dgraph_facts,
+                        &bins,
+                        verdict,
+                    );
+                }
+
+                metrics
+                    .topic_messages
+                    .with_label_values(&[topic, verdict.as_str()])
+                    .inc();
+                let tenant = metrics
+                    .tenants
+                    .bucket(validation::tenant_of(&input.source_id));
+                metrics
+                    .tenant_messages
+                    .with_label_values(&[tenant, verdict.as_str()])
+                    .inc();
+                if let Some(topics) = self.topics.as_ref().filter(|_| topic_routed) {
+                    topics.observe(
+                        topic,
+                        &input.content_hash,
+                        &neural_features,
+                        verdict,
+                        &dgraph_facts,
+                        &bins,
+                    );
+                }
+
+                if let Some(shadow) = &self.shadow {
+                    shadow.observe(
+                        &input.content_hash,
+                        &neural_features,
+                        verdict,
+                        &dgraph_facts,
+                        &bins,
+                    );
+                }
+
+                if let Some(similarity) = &self.similarity {
+                    similarity.insert(
+                        &input.content_hash,
+                        &input.content_text,
+                        verdict.as_str(),
+                        &neural_features.embedding,
+                    );
+                }
+
+                trace.fired_rules = fired_rules.clone();
+                // A verdict is published even if its context cannot be saved
+                let decision_context = match &self.contexts {
+                    Some(contexts) => match contexts.record(&trace).await {
+                        Ok(id) => id,
+                        Err(e) => {
+                            warn!(
+                                "Decision context for {} not recorded: {}",
+                                input.content_hash, e
+                            );
+                            metrics.errors.inc();
+                            String::new()
+                        }
+                    },
+                    None => String::new(),
+                };
+
+                let result = AnalysisResult {
+                    schema_version: ANALYSIS_RESULT_SCHEMA_VERSION,
+                    content_hash: input.content_hash.clone(),
+                    source_id: input.source_id.clone(),
+                    verdict: verdict.to_string(),
+                    explanation,
+                    neural_features: Some((&neural_features).into()),
+                    rejection_reason: String::new(),
+                    decision_context,
+                    annotations: Default::default(),
+                    rules_version,
+                    fired_rules: fired_rules.iter().map(Into::into).collect(),
+                    labels: labels.iter().map(Into::into).collect(),
+                    rules_semver,
+                    model_version: neural_features.model_version.clone(),
+                    confidence,
+                    timing: Some(Timing {
+                        received_at_ms: clock::unix_millis(received_at),
+                        inference_ms: millis(inference_time),
+                        reasoning_ms: millis(reasoning_time),
+                        total_ms: millis(start.elapsed()),
+                    }),
+                    instance: self.config.instance.clone(),
+                };
+                match self.deliver(msg, &result).await {
+                    Ok(()) => {
+                        if let Some(dedup) = &self.dedup {
+                            dedup.put(&result).await;
+                        }
+                        if let Some(verdicts) = &self.verdicts {
+                            verdicts.put(&result).await;
+                        }
+                    }
+                    Err(e) => {
+                        // The result is lost unless the input is analysed again
+                        error!("Publish error: {}", e);
+                        metrics.errors.inc();
+                        metrics.latency.observe(start.elapsed().as_secs_f64());
+                        msg.retry(&self.config.retry, "publish", metrics).await;
+                        return;
+                    }
+                }
+            }
+            Err(e) if e.is::<Saturated>() => {
+                // Every worker is busy; hand the message back for redelivery
+                warn!("Rules not evaluated for {}: {}", input.content_hash, e);
+                metrics.latency.observe(start.elapsed().as_secs_f64());
+                msg.retry(&self.config.retry, "rules_saturated", metrics)
+                    .await;
+                return;
+            }
+            Err(e) => {
+                error!("Souffle error: {:#}", e);
+                metrics.errors.inc();
+                metrics.latency.observe(start.elapsed().as_secs_f64());
+                msg.retry(&self.config.retry, "reasoning", metrics).await;
+                return;
+            }
+        }
+
+        metrics.latency.observe(start.elapsed().as_secs_f64());
+        msg.ack().await;
+    }
+
+    /// Publish a `REJECTED` result so the producer learns why the input
+    /// was not analysed
+    async fn reject(
+        &self,
+        msg: Delivery<'_>,
+        input: &AnalysisInput,
+        reason: RejectReason,
+        explanation: String,
+    ) {
+        self.metrics.rejected.inc();
+        let result = AnalysisResult {
+            schema_version: ANALYSIS_RESULT_SCHEMA_VERSION,
+            content_hash: input.content_hash.clone(),
+            source_id: input.source_id.clone(),
+            verdict: REJECTED.to_string(),
+            explanation,
+            neural_features: None,
+            rejection_reason: reason.code().to_string(),
+            decision_context: String::new(),
+            annotations: Default::default(),
+            rules_version: String::new(),
+            fired_rules: Vec::new(),
+            labels: Vec::new(),
+            rules_semver: String::new(),
+            model_version: String::new(),
+            confidence: 0.0,
+            timing: None,
+            instance: self.config.instance.clone(),
+        };
+        if let Err(e) = self.deliver(msg, &result).await {
+            error!("Publish error: {}", e);
+            self.metrics.errors.inc();
+        }
+    }
+
+    /// Publish `result`, or reply with it to a request
+    ///
+    /// Its headers continue the trace of the input; each delivery of an
+    /// input becomes its own span.
+    async fn deliver(&self, msg: Delivery<'_>, result: &AnalysisResult) -> Result<()> {
+        let headers = MessageContext::from_headers(msg.headers()).headers(msg.id());
+        match msg {
+            Delivery::Stream(_) | Delivery::Received(_) => {
+                self.publisher.publish(result, headers).await
+            }
+            Delivery::Request { message, .. } => match &message.reply {
+                Some(reply) => self.publisher.reply(reply, result, headers).await,
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Reject or defer a message its tenant has no quota left for
+    async fn over_quota(&self, quotas: &QuotaTracker, msg: Delivery<'_>, input: &AnalysisInput) {
+        let tenant = validation::tenant_of(&input.source_id);
+        match (quotas.overflow(), msg, &self.jetstream) {
+            (OverflowAction::Defer, Delivery::Stream(msg), Some(jetstream)) => {
+                // The headers go along, so the trace continues when it is
+                // analysed from the backfill subject
+                let deferred = async {
+                    jetstream
+                        .publish_with_headers(
+                            quotas.backfill_subject().to_string(),
+                            msg.headers.clone().unwrap_or_default(),
+                            msg.payload.clone(),
+                        )
+                        .await?
+                        .await
+                };
+                match deferred.await {
+                    Ok(_) => {
+                        info!(
+                            "Tenant {:?} is over quota, deferred {} to {}",
+                            tenant,
+                            input.content_hash,
+                            quotas.backfill_subject()
+                        );
+                        let _ = msg.ack().await;
+                    }
+                    Err(e) => {
+                        // Not lost: redelivery retries the quota check
+                        error!("Deferring {} failed: {}", input.content_hash, e);
+                        self.metrics.errors.inc();
+                        retry::retry(&self.config.retry, msg, "quota_deferral", &self.metrics)
+                            .await;
+                    }
+                }
+            }
+            // A request, or an input of another transport, has no stream to
+            // wait in until quota is available
+            _ => {
+                warn!(
+                    "Tenant {:?} is over quota, rejecting {}",
+                    tenant, input.content_hash
+                );
+                self.reject(
+                    msg,
+                    input,
+                    RejectReason::QuotaExceeded,
+                    format!("Daily quota for tenant {:?} is used up", tenant),
+                )
+                .await;
+                msg.ack().await;
+            }
+        }
+    }
+
+    /// Run text inference under the configured deadline
+    ///
+    /// Routed messages run on their dedicated ensemble directly; all
+    /// others go to the default ensemble, through the batcher when enabled.
+    /// Cached features for the same content and model version are reused.
+    /// The ensemble and any cache hit or outage are noted in `trace`. Time
+    /// spent on the models, cancelled or not, counts against the tenant's
+    /// quota.
+    ///
+    /// # Returns
+    /// `None` if the deadline elapsed; the inference future is dropped,
+    /// which cancels it.
+    async fn infer(
+        &self,
+        input: &AnalysisInput,
+        route: Option<&Arc<Ensemble>>,
+        trace: &mut DecisionTrace,
+    ) -> Result<Option<NeuralFeatures>> {
+        let ensemble = route.unwrap_or(&self.ensemble);
+        trace.model_version = ensemble.version();
+        let key = cache_key(&input.content_hash, &ensemble.version());
+        if let Some(cache) = &self.feature_cache {
+            match cache.get(&key).await {
+                Ok(Some(features)) => {
+                    self.metrics.feature_cache_hits.inc();
+                    trace.feature_cache_hit = true;
+                    return Ok(Some(features));
+                }
+                Ok(None) => self.metrics.feature_cache_misses.inc(),
+                Err(e) => {
+                    // A cache outage degrades to plain inference
+                    warn!("Feature cache lookup failed: {}", e);
+                    self.metrics.feature_cache_misses.inc();
+                    trace.degrade("feature_cache");
+                }
+            }
+        }
+
+        let deadline = self.config.inference.timeout;
+        let inference = async {
+            match (route, &self.batcher) {
+                (None, Some(batcher)) => batcher.infer(&input.content_hash).await,
+                _ => ensemble.run(&input.content_hash).await,
+            }
+        };
+        let started = Instant::now();
+        let outcome =
+            within_deadline(deadline, inference, &input.content_hash, &self.metrics).await;
+        if let Some(quotas) = &self.quotas {
+            quotas.charge(
+                validation::tenant_of(&input.source_id),
+                started.elapsed().as_secs_f64(),
+            );
+        }
+        match outcome {
+            Ok(Some(features)) => {
+                if let Some(cache) = &self.feature_cache {
+                    if let Err(e) = cache.put(&key, &features).await {
+                        warn!("Feature cache store failed: {}", e);
+                    }
+                    if let Some(entries) = cache.local_entries() {
+                        self.metrics.feature_cache_entries.set(entries as f64);
+                    }
+                }
+                Ok(Some(features))
+            }
+            other => other,
+        }
+    }
+}
+
+/// Run `inference` until `deadline`
+///
+/// # Returns
+/// `None` if the deadline elapsed; the future is dropped, which cancels
+/// it, and the timeout counted
+async fn within_deadline<T>(
+    deadline: Duration,
+    inference: impl Future<Output = Result<T>>,
+    content_hash: &str,
+    metrics: &Metrics,
+) -> Result<Option<T>> {
+    match timeout(deadline, inference).await {
+        Ok(outcome) => outcome.map(Some),
+        Err(_) => {
+            warn!(
+                "Inference for {} exceeded {:?}, cancelled",
+                content_hash, deadline
+            );
+            metrics.inference_timeouts.inc();
+            Ok(None)
+        }
+    }
+}
+
+/// Whole milliseconds of `elapsed`, saturating
+fn millis(elapsed: Duration) -> u32 {
+    u32::try_from(elapsed.as_millis()).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feature_cache::MemoryFeatureCache;
+    use crate::transport::{Received, Transport};
+    use async_nats::HeaderMap;
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// An input that records whether it was handed back
+    #[derive(Default)]
+    struct Stub {
+        payload: Vec<u8>,
+        redelivered: AtomicBool,
+    }
+
+    impl Stub {
+        fn new(input: &AnalysisInput) -> Self {
+            Self {
+                payload: input.encode_to_vec(),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Received for Stub {
+        fn payload(&self) -> &[u8] {
+            &self.payload
+        }
+
+        fn headers(&self) -> Option<&HeaderMap> {
+            None
+        }
+
+        fn origin(&self) -> &str {
+            "disinfo.raw"
+        }
+
+        fn id(&self) -> &str {
+            "stub"
+        }
+
+        fn deliveries(&self) -> u64 {
+            1
+        }
+
+        fn redeliver(&self, _delay: Duration) -> BoxFuture<'_, Result<()>> {
+            self.redelivered.store(true, Ordering::SeqCst);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// A transport every send fails on, as when the broker is down
+    struct Unreachable;
+
+    impl Transport for Unreachable {
+        fn name(&self) -> &'static str {
+            "unreachable"
+        }
+
+        fn send<'a>(
+            &'a self,
+            _destination: &'a str,
+            _headers: HeaderMap,
+            _payload: Vec<u8>,
+        ) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async { anyhow::bail!("transport unreachable") })
+        }
+    }
+
+    fn input() -> AnalysisInput {
+        AnalysisInput {
+            content_hash: "ab12".to_string(),
+            source_id: "blog:1".to_string(),
+            content_text: "Breaking news".to_string(),
+            ..Default::default()
+        }
+    }
+
+    async fn pipeline(transport: Arc<dyn Transport>) -> Pipeline {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        Pipeline::new(Config::default(), metrics, transport)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unpublished_result_is_retried() {
+        let mut pipeline = pipeline(Arc::new(Unreachable)).await;
+        // Features come from the cache, so no model has to be loaded
+        let cache = MemoryFeatureCache::new(1);
+        let key = cache_key("ab12", &pipeline.ensemble.version());
+        cache.put(&key, &NeuralFeatures::default()).await.unwrap();
+        pipeline.feature_cache = Some(Box::new(cache));
+
+        let input = Stub::new(&input());
+        pipeline.process(Delivery::Received(&input)).await;
+        assert!(input.redelivered.load(Ordering::SeqCst));
+        assert_eq!(
+            pipeline
+                .metrics
+                .transient_failures
+                .with_label_values(&["publish", "redelivered"])
+                .get(),
+            1.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inference_timeout_is_counted_and_retried() {
+        let mut pipeline = pipeline(Arc::new(Unreachable)).await;
+        pipeline.batcher = Some(Arc::new(InferenceBatcher::stalled()));
+        pipeline.config.inference.timeout = Duration::from_millis(10);
+
+        let input = Stub::new(&input());
+        pipeline.process(Delivery::Received(&input)).await;
+        // Handed back for redelivery rather than acknowledged
+        assert!(input.redelivered.load(Ordering::SeqCst));
+        assert_eq!(pipeline.metrics.inference_timeouts.get(), 1.0);
+        assert_eq!(
+            pipeline
+                .metrics
+                .transient_failures
+                .with_label_values(&["inference_timeout", "redelivered"])
+                .get(),
+            1.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inference_within_deadline_is_kept() {
+        let metrics = Metrics::new().unwrap();
+        let fast = async { Ok(NeuralFeatures::default()) };
+        let outcome = within_deadline(Duration::from_secs(1), fast, "aa", &metrics)
+            .await
+            .unwrap();
+        assert!(outcome.is_some());
+        assert_eq!(metrics.inference_timeouts.get(), 0.0);
+    }
+}
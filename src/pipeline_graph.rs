@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! The configured processing pipeline as a graph
+//!
+//! What a deployment does to a message depends on a couple dozen settings.
+//! [`PipelineGraph`] describes the stages a message passes through, which
+//! of them are enabled, their deadlines and the model versions they run,
+//! and is served by the metrics server as JSON (`/pipeline`) and as a
+//! Graphviz document (`/pipeline.dot`), so operators and auditors can see
+//! what processing a given deployment performs.
+
+use serde::Serialize;
+use std::{fmt::Write, time::Duration};
+
+/// One processing stage
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stage {
+    pub name: &'static str,
+    pub enabled: bool,
+    /// A failure degrades the decision instead of dropping the message
+    pub best_effort: bool,
+    pub timeout_ms: Option<u64>,
+    /// Model versions the stage runs, e.g. `default: fake@3+emo@1`
+    pub models: Vec<String>,
+    /// Stage-specific settings, e.g. the OCR engine
+    pub detail: Option<String>,
+    /// Stages whose output this stage consumes
+    pub after: Vec<&'static str>,
+}
+
+impl Stage {
+    pub fn new(name: &'static str, enabled: bool) -> Self {
+        Self {
+            name,
+            enabled,
+            best_effort: false,
+            timeout_ms: None,
+            models: Vec::new(),
+            detail: None,
+            after: Vec::new(),
+        }
+    }
+
+    pub fn best_effort(mut self) -> Self {
+        self.best_effort = true;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout_ms = Some(timeout.as_millis() as u64);
+        self
+    }
+
+    pub fn models(mut self, models: Vec<String>) -> Self {
+        self.models = models;
+        self
+    }
+
+    /// Set the detail; an empty one is left out
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        let detail = detail.into();
+        self.detail = (!detail.is_empty()).then_some(detail);
+        self
+    }
+
+    pub fn after(mut self, stages: &[&'static str]) -> Self {
+        self.after = stages.to_vec();
+        self
+    }
+}
+
+/// Stages of the pipeline in processing order
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PipelineGraph {
+    pub service_version: String,
+    pub stages: Vec<Stage>,
+}
+
+impl PipelineGraph {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Render as a Graphviz digraph; disabled stages are drawn dashed and
+    /// best-effort ones with a dotted border
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph pipeline {\n  rankdir=TB;\n  node [shape=box];\n");
+        for stage in &self.stages {
+            let mut label = stage.name.to_string();
+            if let Some(timeout_ms) = stage.timeout_ms {
+                let _ = write!(label, "\\ntimeout {}ms", timeout_ms);
+            }
+            for model in &stage.models {
+                let _ = write!(label, "\\n{}", escape(model));
+            }
+            if let Some(detail) = &stage.detail {
+                let _ = write!(label, "\\n{}", escape(detail));
+            }
+            let style = match (stage.enabled, stage.best_effort) {
+                (false, _) => ", style=dashed, color=gray, fontcolor=gray",
+                (true, true) => ", style=dotted",
+                (true, false) => "",
+            };
+            let _ = writeln!(dot, "  \"{}\" [label=\"{}\"{}];", stage.name, label, style);
+        }
+        for stage in &self.stages {
+            for before in &stage.after {
+                let _ = writeln!(dot, "  \"{}\" -> \"{}\";", before, stage.name);
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Quote characters that would end a DOT string
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> PipelineGraph {
+        PipelineGraph {
+            service_version: "0.1.0".to_string(),
+            stages: vec![
+                Stage::new("validate", true),
+                Stage::new("ocr", false)
+                    .best_effort()
+                    .detail("tesseract \"eng\"")
+                    .after(&["validate"]),
+                Stage::new("inference", true)
+                    .timeout(Duration::from_millis(500))
+                    .models(vec!["default: fake@3".to_string()])
+                    .after(&["ocr"]),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_dot_rendering() {
+        let dot = graph().to_dot();
+        assert!(dot.starts_with("digraph pipeline {"));
+        assert!(
+            dot.contains("\"inference\" [label=\"inference\\ntimeout 500ms\\ndefault: fake@3\"];")
+        );
+        assert!(dot.contains("\"ocr\" [label=\"ocr\\ntesseract \\\"eng\\\"\", style=dashed"));
+        assert!(dot.contains("\"validate\" -> \"ocr\";"));
+        assert!(dot.contains("\"ocr\" -> \"inference\";"));
+    }
+
+    #[test]
+    fn test_json_rendering() {
+        let json: serde_json::Value = serde_json::from_str(&graph().to_json()).unwrap();
+        assert_eq!(json["stages"][2]["timeout_ms"], 500);
+        assert_eq!(json["stages"][1]["enabled"], false);
+        assert_eq!(json["stages"][1]["after"][0], "validate");
+        assert!(json["stages"][0]["detail"].is_null());
+        assert_eq!(Stage::new("publish", true).detail("").detail, None);
+    }
+}
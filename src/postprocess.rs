@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Result post-processors
+//!
+//! Partners consume results under different contracts: one wants its own
+//! policy codes next to the verdict, another may not receive explanations
+//! or embeddings. Instead of branching on tenants in the pipeline, every
+//! published result runs through a chain of post-processors, in the order
+//! they are configured, that rewrite or enrich it.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::model_pb::AnalysisResult;
+use crate::validation;
+
+/// Rewrites or enriches a result before it is published
+pub trait PostProcessor: Send + Sync {
+    /// Name used in logs and the configuration
+    fn name(&self) -> &'static str;
+
+    fn process(&self, result: &mut AnalysisResult);
+}
+
+/// A configured post-processor, parsed from `<kind>:<file>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum PostProcessorSpec {
+    /// Annotate results with tenant policy codes, see [`PolicyCodes`]
+    PolicyCodes(PathBuf),
+    /// Remove fields a tenant may not receive, see [`StripFields`]
+    StripFields(PathBuf),
+}
+
+impl FromStr for PostProcessorSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, path) = s
+            .split_once(':')
+            .with_context(|| format!("invalid post-processor (expected kind:file): {}", s))?;
+        match kind {
+            "policy_codes" => Ok(Self::PolicyCodes(path.into())),
+            "strip_fields" => Ok(Self::StripFields(path.into())),
+            other => bail!("unknown post-processor: {}", other),
+        }
+    }
+}
+
+/// Build a post-processor from its spec, loading its rule file
+pub fn from_spec(spec: &PostProcessorSpec) -> Result<Box<dyn PostProcessor>> {
+    match spec {
+        PostProcessorSpec::PolicyCodes(path) => {
+            Ok(Box::new(PolicyCodes::parse(&read_rules(path)?)?))
+        }
+        PostProcessorSpec::StripFields(path) => {
+            Ok(Box::new(StripFields::parse(&read_rules(path)?)?))
+        }
+    }
+}
+
+fn read_rules(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .with_context(|| format!("Failed to read post-processor rules {}", path.display()))
+}
+
+/// Non-blank lines that are not `#` comments, split at the first `:`
+fn rule_lines(contents: &str) -> Result<Vec<(&str, &str)>> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_once(':')
+                .map(|(tenant, rest)| (tenant.trim(), rest.trim()))
+                .with_context(|| format!("invalid post-processor rule: {}", line))
+        })
+        .collect()
+}
+
+/// Tenant of a result; `*` entries apply to tenants without their own
+fn tenant_entry<'a, T>(entries: &'a HashMap<String, T>, result: &AnalysisResult) -> Option<&'a T> {
+    entries
+        .get(validation::tenant_of(&result.source_id))
+        .or_else(|| entries.get("*"))
+}
+
+/// Sets the `policy_code` annotation from the tenant's code for the verdict
+///
+/// Rules read `tenant: VERDICT=CODE, VERDICT=CODE`.
+pub struct PolicyCodes {
+    codes: HashMap<String, HashMap<String, String>>,
+}
+
+impl PolicyCodes {
+    fn parse(contents: &str) -> Result<Self> {
+        let mut codes = HashMap::new();
+        for (tenant, rest) in rule_lines(contents)? {
+            let verdicts = rest
+                .split(',')
+                .map(str::trim)
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    pair.split_once('=')
+                        .map(|(verdict, code)| {
+                            (verdict.trim().to_string(), code.trim().to_string())
+                        })
+                        .with_context(|| {
+                            format!("invalid policy code (expected VERDICT=CODE): {}", pair)
+                        })
+                })
+                .collect::<Result<HashMap<_, _>>>()?;
+            if codes.insert(tenant.to_string(), verdicts).is_some() {
+                bail!("duplicate policy codes for tenant {}", tenant);
+            }
+        }
+        Ok(Self { codes })
+    }
+}
+
+impl PostProcessor for PolicyCodes {
+    fn name(&self) -> &'static str {
+        "policy_codes"
+    }
+
+    fn process(&self, result: &mut AnalysisResult) {
+        let code = tenant_entry(&self.codes, result).and_then(|codes| codes.get(&result.verdict));
+        if let Some(code) = code {
+            result
+                .annotations
+                .insert("policy_code".to_string(), code.clone());
+        }
+    }
+}
+
+/// Result fields a tenant contract can exclude
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultField {
+    Explanation,
+    NeuralFeatures,
+    Embedding,
+    Attributions,
+    Stances,
+    Entities,
+    DecisionContext,
+    FiredRules,
+    Labels,
+}
+
+impl FromStr for ResultField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "explanation" => Ok(Self::Explanation),
+            "neural_features" => Ok(Self::NeuralFeatures),
+            "embedding" => Ok(Self::Embedding),
+            "attributions" => Ok(Self::Attributions),
+            "stances" => Ok(Self::Stances),
+            "entities" => Ok(Self::Entities),
+            "decision_context" => Ok(Self::DecisionContext),
+            "fired_rules" => Ok(Self::FiredRules),
+            "labels" => Ok(Self::Labels),
+            other => bail!("unknown result field: {}", other),
+        }
+    }
+}
+
+/// Clears fields a tenant's contract excludes
+///
+/// Rules read `tenant: field, field`.
+pub struct StripFields {
+    fields: HashMap<String, Vec<ResultField>>,
+}
+
+impl StripFields {
+    fn parse(contents: &str) -> Result<Self> {
+        let mut fields = HashMap::new();
+        for (tenant, rest) in rule_lines(contents)? {
+            let excluded = rest
+                .split(',')
+                .map(str::trim)
+                .filter(|field| !field.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<ResultField>>>()?;
+            if fields.insert(tenant.to_string(), excluded).is_some() {
+                bail!("duplicate stripped fields for tenant {}", tenant);
+            }
+        }
+        Ok(Self { fields })
+    }
+}
+
+impl PostProcessor for StripFields {
+    fn name(&self) -> &'static str {
+        "strip_fields"
+    }
+
+    fn process(&self, result: &mut AnalysisResult) {
+        let Some(excluded) = tenant_entry(&self.fields, result) else {
+            return;
+        };
+        for field in excluded {
+            match field {
+                ResultField::Explanation => result.explanation.clear(),
+                ResultField::NeuralFeatures => result.neural_features = None,
+                ResultField::DecisionContext => result.decision_context.clear(),
+                ResultField::FiredRules => result.fired_rules.clear(),
+                ResultField::Labels => result.labels.clear(),
+                ResultField::Embedding
+                | ResultField::Attributions
+                | ResultField::Stances
+                | ResultField::Entities => {
+                    let Some(features) = result.neural_features.as_mut() else {
+                        continue;
+                    };
+                    match field {
+                        ResultField::Embedding => features.embedding.clear(),
+                        ResultField::Attributions => features.attributions.clear(),
+                        ResultField::Stances => features.stances.clear(),
+                        _ => features.entities.clear(),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_pb::NeuralFeatures;
+
+    fn result(source_id: &str, verdict: &str) -> AnalysisResult {
+        AnalysisResult {
+            source_id: source_id.to_string(),
+            verdict: verdict.to_string(),
+            explanation: "High fakeness from an untrusted source".to_string(),
+            neural_features: Some(NeuralFeatures {
+                fakeness_score: 0.9,
+                embedding: vec![0.1, 0.2],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_policy_codes() {
+        let codes = PolicyCodes::parse(
+            "# tenant: VERDICT=CODE\nnewsroom: DISINFO=NR-7, SUSPICIOUS=NR-3\n*: DISINFO=GEN-1\n",
+        )
+        .unwrap();
+
+        let mut tagged = result("newsroom:feed-1", "SUSPICIOUS");
+        codes.process(&mut tagged);
+        assert_eq!(tagged.annotations["policy_code"], "NR-3");
+
+        let mut fallback = result("blog:post-9", "DISINFO");
+        codes.process(&mut fallback);
+        assert_eq!(fallback.annotations["policy_code"], "GEN-1");
+
+        let mut safe = result("blog:post-9", "SAFE");
+        codes.process(&mut safe);
+        assert!(safe.annotations.is_empty());
+
+        assert!(PolicyCodes::parse("newsroom: DISINFO\n").is_err());
+    }
+
+    #[test]
+    fn test_strip_fields() {
+        let strip = StripFields::parse("partner: explanation, embedding\n").unwrap();
+        let mut stripped = result("partner:42", "DISINFO");
+        strip.process(&mut stripped);
+        assert!(stripped.explanation.is_empty());
+        let features = stripped.neural_features.unwrap();
+        assert!(features.embedding.is_empty());
+        assert_eq!(features.fakeness_score, 0.9);
+
+        // Tenants without rules keep the full result
+        let mut untouched = result("newsroom:1", "DISINFO");
+        strip.process(&mut untouched);
+        assert_eq!(untouched, result("newsroom:1", "DISINFO"));
+
+        assert!(StripFields::parse("partner: secrets\n").is_err());
+        assert_eq!(
+            "strip_fields:/etc/nsai/contracts"
+                .parse::<PostProcessorSpec>()
+                .unwrap(),
+            PostProcessorSpec::StripFields("/etc/nsai/contracts".into())
+        );
+    }
+}
@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Trace context and metadata carried in NATS headers
+//!
+//! Producers may send a W3C `traceparent` (and `tracestate`) with an input,
+//! along with `Nsai-Tenant` and `Nsai-Priority` metadata. The processing of
+//! the message is logged under a span carrying them, and the results it
+//! publishes carry them on: the trace id is kept and the parent id becomes
+//! the detector's own span, so a trace continues across the services
+//! consuming verdicts.
+
+use anyhow::{bail, Result};
+use async_nats::HeaderMap;
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+use tracing::{info_span, Span};
+
+/// W3C trace context header
+pub const TRACEPARENT: &str = "traceparent";
+/// Vendor trace state, passed on unchanged
+pub const TRACESTATE: &str = "tracestate";
+/// Tenant of the input as told by the producer
+pub const TENANT_HEADER: &str = "Nsai-Tenant";
+/// Priority of the input as told by the producer
+pub const PRIORITY_HEADER: &str = "Nsai-Priority";
+
+/// A parsed `traceparent` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceParent {
+    /// 32 hex digits shared by every span of the trace
+    pub trace_id: String,
+    /// 16 hex digits naming the span that sent the message
+    pub parent_id: String,
+    /// 2 hex digits, e.g. `01` when sampled
+    pub flags: String,
+}
+
+impl TraceParent {
+    /// The context of a span of the same trace, named by `seed`
+    pub fn child(&self, seed: &str) -> Self {
+        let digest = Sha256::digest(format!("{}:{}:{}", self.trace_id, self.parent_id, seed));
+        Self {
+            trace_id: self.trace_id.clone(),
+            parent_id: hex::encode(&digest[..8]),
+            flags: self.flags.clone(),
+        }
+    }
+}
+
+impl FromStr for TraceParent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.trim().split('-').collect();
+        let hex = |field: &str, len: usize| {
+            field.len() == len
+                && field
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        };
+        // Later versions may append fields; the first four keep their meaning
+        match fields.as_slice() {
+            [version, trace_id, parent_id, flags, rest @ ..]
+                if hex(version, 2)
+                    && *version != "ff"
+                    && (*version != "00" || rest.is_empty())
+                    && hex(trace_id, 32)
+                    && hex(parent_id, 16)
+                    && hex(flags, 2)
+                    && trace_id.chars().any(|c| c != '0')
+                    && parent_id.chars().any(|c| c != '0') =>
+            {
+                Ok(Self {
+                    trace_id: trace_id.to_string(),
+                    parent_id: parent_id.to_string(),
+                    flags: flags.to_string(),
+                })
+            }
+            _ => bail!("invalid traceparent: {}", s),
+        }
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{}-{}-{}", self.trace_id, self.parent_id, self.flags)
+    }
+}
+
+/// What the headers of an input tell about it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageContext {
+    /// `None` when absent or invalid; an invalid one starts no trace
+    pub trace: Option<TraceParent>,
+    pub tracestate: Option<String>,
+    pub tenant: Option<String>,
+    pub priority: Option<String>,
+}
+
+impl MessageContext {
+    pub fn from_headers(headers: Option<&HeaderMap>) -> Self {
+        let Some(headers) = headers else {
+            return Self::default();
+        };
+        let value = |name: &str| {
+            headers
+                .get(name)
+                .map(|value| value.as_str().trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        Self {
+            trace: value(TRACEPARENT).and_then(|value| value.parse().ok()),
+            tracestate: value(TRACESTATE),
+            tenant: value(TENANT_HEADER),
+            priority: value(PRIORITY_HEADER),
+        }
+    }
+
+    /// Span to process the message under
+    pub fn span(&self) -> Span {
+        info_span!(
+            "message",
+            trace_id = self.trace.as_ref().map(|trace| trace.trace_id.as_str()),
+            tenant = self.tenant.as_deref(),
+            priority = self.priority.as_deref(),
+        )
+    }
+
+    /// Headers of a message published while processing this one; `seed`
+    /// tells the detector's span apart from others of the trace
+    pub fn headers(&self, seed: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(trace) = &self.trace {
+            headers.insert(TRACEPARENT, trace.child(seed).to_string().as_str());
+            if let Some(state) = &self.tracestate {
+                headers.insert(TRACESTATE, state.as_str());
+            }
+        }
+        if let Some(tenant) = &self.tenant {
+            headers.insert(TENANT_HEADER, tenant.as_str());
+        }
+        if let Some(priority) = &self.priority {
+            headers.insert(PRIORITY_HEADER, priority.as_str());
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_traceparent_parse() {
+        let trace: TraceParent = PARENT.parse().unwrap();
+        assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace.to_string(), PARENT);
+
+        assert!("00-00000000000000000000000000000000-00f067aa0ba902b7-01"
+            .parse::<TraceParent>()
+            .is_err());
+        assert!("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"
+            .parse::<TraceParent>()
+            .is_err());
+        assert!(format!("{}-extra", PARENT).parse::<TraceParent>().is_err());
+        // A later version may carry more fields
+        assert!(format!("01{}-extra", &PARENT[2..])
+            .parse::<TraceParent>()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_context_is_propagated() {
+        let mut incoming = HeaderMap::new();
+        incoming.insert(TRACEPARENT, PARENT);
+        incoming.insert(TRACESTATE, "vendor=abc");
+        incoming.insert(TENANT_HEADER, "newsroom");
+        let context = MessageContext::from_headers(Some(&incoming));
+        assert_eq!(context.tenant.as_deref(), Some("newsroom"));
+        assert_eq!(context.priority, None);
+
+        let outgoing = context.headers("ab12");
+        let child: TraceParent = outgoing.get(TRACEPARENT).unwrap().as_str().parse().unwrap();
+        assert_eq!(child.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(child.parent_id, "00f067aa0ba902b7");
+        assert_eq!(outgoing.get(TRACESTATE).unwrap().as_str(), "vendor=abc");
+        assert_eq!(outgoing.get(TENANT_HEADER).unwrap().as_str(), "newsroom");
+
+        // Without headers nothing is made up
+        let empty = MessageContext::from_headers(None).headers("ab12");
+        assert!(empty.get(TRACEPARENT).is_none());
+    }
+}
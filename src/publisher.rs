@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Publishing of analysis results to downstream consumers
+//!
+//! During a schema migration both the rich `AnalysisResult` and the minimal
+//! `LegacyVerdict` can be published, each on its own subject, so consumers
+//! move over on their own schedule.
+//!
+//! A subject may contain `{tenant}`, e.g. `disinfo.{tenant}.verdicts`, to
+//! publish every tenant's results on subjects of its own, which NATS
+//! permissions can then restrict to that tenant.
+
+use anyhow::{Context, Result};
+use async_nats::HeaderMap;
+use prost::Message;
+use std::{borrow::Cow, sync::Arc};
+
+use crate::config::PublishConfig;
+use crate::model_pb::{AnalysisResult, LegacyVerdict};
+use crate::postprocess::PostProcessor;
+use crate::transport::Transport;
+use crate::validation;
+use crate::webhook::WebhookSinks;
+
+/// Placeholder of a result subject replaced by the result's tenant
+const TENANT_PLACEHOLDER: &str = "{tenant}";
+
+/// Publishes results over the transport
+pub struct ResultPublisher {
+    transport: Arc<dyn Transport>,
+    config: PublishConfig,
+    post_processors: Vec<Box<dyn PostProcessor>>,
+    webhooks: Option<WebhookSinks>,
+}
+
+impl ResultPublisher {
+    pub fn new(transport: Arc<dyn Transport>, config: PublishConfig) -> Self {
+        Self {
+            transport,
+            config,
+            post_processors: Vec::new(),
+            webhooks: None,
+        }
+    }
+
+    /// Run every result through `post_processors`, in order, before it
+    /// is published
+    pub fn with_post_processors(mut self, post_processors: Vec<Box<dyn PostProcessor>>) -> Self {
+        self.post_processors = post_processors;
+        self
+    }
+
+    /// Also hand every post-processed result to the webhook sinks
+    pub fn with_webhooks(mut self, webhooks: WebhookSinks) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Names of the post-processors, in the order they run
+    pub fn post_processor_names(&self) -> Vec<&'static str> {
+        self.post_processors.iter().map(|p| p.name()).collect()
+    }
+
+    /// Run `result` through the post-processors
+    fn post_process<'a>(&self, result: &'a AnalysisResult) -> Cow<'a, AnalysisResult> {
+        let mut result = Cow::Borrowed(result);
+        for post_processor in &self.post_processors {
+            post_processor.process(result.to_mut());
+        }
+        result
+    }
+
+    /// Publish a result in every configured format, with `headers`
+    /// carrying the trace context of its input
+    pub async fn publish(&self, result: &AnalysisResult, headers: HeaderMap) -> Result<()> {
+        let result = self.post_process(result);
+        let result = result.as_ref();
+
+        // Queued without waiting; a slow webhook never holds up publishing
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.send(result);
+        }
+
+        if self.config.formats.rich() {
+            self.transport
+                .send(
+                    &tenant_subject(&self.config.result_subject, result),
+                    headers.clone(),
+                    result.encode_to_vec(),
+                )
+                .await
+                .context("Failed to publish analysis result")?;
+        }
+
+        if self.config.formats.legacy() {
+            let legacy = LegacyVerdict::from(result);
+            self.transport
+                .send(
+                    &tenant_subject(&self.config.legacy_subject, result),
+                    headers,
+                    legacy.encode_to_vec(),
+                )
+                .await
+                .context("Failed to publish legacy verdict")?;
+        }
+
+        Ok(())
+    }
+
+    /// Answer a request with the rich result on its `reply` subject; the
+    /// result is not published, nor handed to the webhooks
+    pub async fn reply(
+        &self,
+        reply: &str,
+        result: &AnalysisResult,
+        headers: HeaderMap,
+    ) -> Result<()> {
+        let result = self.post_process(result);
+        self.transport
+            .send(reply, headers, result.encode_to_vec())
+            .await
+            .context("Failed to reply with analysis result")
+    }
+}
+
+/// `subject` with the tenant of `result` in place of `{tenant}`
+///
+/// Characters that would make the tenant more than one token are replaced,
+/// so no source id can steer a result onto another tenant's subjects.
+fn tenant_subject(subject: &str, result: &AnalysisResult) -> String {
+    if !subject.contains(TENANT_PLACEHOLDER) {
+        return subject.to_string();
+    }
+    let tenant: String = validation::tenant_of(&result.source_id)
+        .chars()
+        .map(|c| match c {
+            '.' | '*' | '>' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect();
+    let tenant = if tenant.is_empty() { "_" } else { &tenant };
+    subject.replace(TENANT_PLACEHOLDER, tenant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_subjects() {
+        let result = |source_id: &str| AnalysisResult {
+            source_id: source_id.to_string(),
+            ..Default::default()
+        };
+        let subject = "disinfo.{tenant}.verdicts";
+        assert_eq!(
+            tenant_subject(subject, &result("newsroom:feed-1")),
+            "disinfo.newsroom.verdicts"
+        );
+        assert_eq!(
+            tenant_subject(subject, &result("other.>:feed-1")),
+            "disinfo.other__.verdicts"
+        );
+        assert_eq!(tenant_subject(subject, &result("")), "disinfo._.verdicts");
+        assert_eq!(
+            tenant_subject("disinfo.verdicts", &result("newsroom:feed-1")),
+            "disinfo.verdicts"
+        );
+    }
+}
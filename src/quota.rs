@@ -0,0 +1,389 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Daily processing quotas
+//!
+//! Each tenant (the `<tenant>:` prefix of source ids) may be limited to a
+//! number of messages and of inference seconds per UTC day, and all
+//! tenants together to replica limits, so one partner's spike cannot
+//! consume everyone's capacity. A message over quota is either rejected
+//! with `QUOTA_EXCEEDED` or deferred: republished on a backfill subject for
+//! processing once capacity frees up.
+//!
+//! Usage is kept in memory and every limit applies to one replica: a fleet
+//! of `n` replicas admits up to `n` times each limit, and a restart starts
+//! the day's count over.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::clock::today;
+use crate::metrics::Metrics;
+
+/// Tenants whose usage is counted separately each day; tenant prefixes
+/// come from untrusted source ids, so further tenants without their own
+/// limits share one count
+const MAX_TRACKED_TENANTS: usize = 10_000;
+
+/// Limits per UTC day; `None` is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QuotaLimits {
+    pub messages: Option<u64>,
+    /// Seconds of model session time; cache hits cost nothing
+    pub gpu_seconds: Option<f64>,
+}
+
+impl QuotaLimits {
+    fn is_unlimited(&self) -> bool {
+        self.messages.is_none() && self.gpu_seconds.is_none()
+    }
+
+    fn exceeded_by(&self, usage: &Usage) -> bool {
+        self.messages.is_some_and(|limit| usage.messages >= limit)
+            || self
+                .gpu_seconds
+                .is_some_and(|limit| usage.gpu_seconds >= limit)
+    }
+}
+
+/// Limits of one tenant, parsed from `tenant=messages:gpu_seconds` where
+/// either limit may be left empty
+#[derive(Debug, Clone, PartialEq)]
+pub struct TenantQuota {
+    pub tenant: String,
+    pub limits: QuotaLimits,
+}
+
+impl FromStr for TenantQuota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (tenant, limits) = s.split_once('=').with_context(|| {
+            format!(
+                "invalid tenant quota (expected tenant=messages:gpu_seconds): {}",
+                s
+            )
+        })?;
+        let (messages, gpu_seconds) = limits.split_once(':').unwrap_or((limits, ""));
+        let limits = QuotaLimits {
+            messages: (!messages.is_empty())
+                .then(|| messages.parse())
+                .transpose()?,
+            gpu_seconds: (!gpu_seconds.is_empty())
+                .then(|| gpu_seconds.parse())
+                .transpose()?,
+        };
+        if tenant.is_empty() || limits.is_unlimited() {
+            bail!("invalid tenant quota: {}", s);
+        }
+        Ok(Self {
+            tenant: tenant.to_string(),
+            limits,
+        })
+    }
+}
+
+/// What happens to a message over quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowAction {
+    /// Publish a `REJECTED` result with reason `QUOTA_EXCEEDED`
+    Reject,
+    /// Republish the input on the backfill subject
+    Defer,
+}
+
+impl FromStr for OverflowAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "defer" => Ok(Self::Defer),
+            other => bail!("unknown quota overflow action: {}", other),
+        }
+    }
+}
+
+impl OverflowAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::Defer => "defer",
+        }
+    }
+}
+
+/// Quota settings, all enforced per replica
+#[derive(Debug, Clone)]
+pub struct QuotaConfig {
+    /// Limits of tenants without their own entry
+    pub per_tenant: QuotaLimits,
+    /// Tenant-specific limits
+    pub tenants: Vec<TenantQuota>,
+    /// Limits over all tenants of the replica together
+    pub replica: QuotaLimits,
+    pub overflow: OverflowAction,
+    /// JetStream subject deferred inputs are republished on
+    pub backfill_subject: String,
+}
+
+/// Consumption within the current day
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    day: u64,
+    messages: u64,
+    gpu_seconds: f64,
+}
+
+impl Usage {
+    /// Start over when a new UTC day begins
+    fn roll(&mut self, day: u64) {
+        if self.day != day {
+            *self = Usage {
+                day,
+                ..Default::default()
+            };
+        }
+    }
+}
+
+/// Usage of the current day; only tenants seen today are kept
+#[derive(Default)]
+struct Ledger {
+    tenants: HashMap<String, Usage>,
+    /// Shared by the tenants seen after [`MAX_TRACKED_TENANTS`]
+    untracked: Usage,
+    replica: Usage,
+}
+
+impl Ledger {
+    /// Forget every tenant when a new UTC day begins
+    fn roll(&mut self, day: u64) {
+        if self.replica.day != day {
+            self.replica.roll(day);
+            self.untracked.roll(day);
+            self.tenants.clear();
+        }
+    }
+
+    /// Today's usage of `tenant`, tracked separately if there is room or
+    /// it has limits of its own
+    fn usage(&mut self, tenant: &str, configured: bool, day: u64) -> &mut Usage {
+        let usage = if configured
+            || self.tenants.len() < MAX_TRACKED_TENANTS
+            || self.tenants.contains_key(tenant)
+        {
+            self.tenants.entry(tenant.to_string()).or_default()
+        } else {
+            &mut self.untracked
+        };
+        usage.roll(day);
+        usage
+    }
+}
+
+/// Admits messages against the daily quotas and accounts their cost
+pub struct QuotaTracker {
+    config: QuotaConfig,
+    ledger: Mutex<Ledger>,
+    metrics: Arc<Metrics>,
+}
+
+impl QuotaTracker {
+    pub fn new(config: QuotaConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config,
+            ledger: Mutex::new(Ledger::default()),
+            metrics,
+        }
+    }
+
+    pub fn overflow(&self) -> OverflowAction {
+        self.config.overflow
+    }
+
+    pub fn backfill_subject(&self) -> &str {
+        &self.config.backfill_subject
+    }
+
+    /// Limits of `tenant`, and whether they are its own
+    fn limits(&self, tenant: &str) -> (QuotaLimits, bool) {
+        self.config
+            .tenants
+            .iter()
+            .find(|t| t.tenant == tenant)
+            .map_or((self.config.per_tenant, false), |t| (t.limits, true))
+    }
+
+    /// Count a message against its tenant's quota
+    ///
+    /// # Returns
+    /// `false`, without counting it, if the tenant or the global quota for
+    /// today is used up
+    pub fn admit(&self, tenant: &str) -> bool {
+        self.admit_on(tenant, today())
+    }
+
+    fn admit_on(&self, tenant: &str, day: u64) -> bool {
+        let (limits, configured) = self.limits(tenant);
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.roll(day);
+        if self.config.replica.exceeded_by(&ledger.replica) {
+            // Not this tenant's fault; it keeps its count unspent
+            self.exceeded(tenant);
+            return false;
+        }
+        let usage = ledger.usage(tenant, configured, day);
+        if limits.exceeded_by(usage) {
+            self.exceeded(tenant);
+            return false;
+        }
+        usage.messages += 1;
+        let messages = usage.messages;
+        ledger.replica.messages += 1;
+        if let Some(tenant) = self.metrics.tenants.admit(tenant) {
+            self.metrics
+                .quota_messages
+                .with_label_values(&[tenant])
+                .set(messages as f64);
+        }
+        true
+    }
+
+    /// Add the inference time spent on an admitted message
+    pub fn charge(&self, tenant: &str, gpu_seconds: f64) {
+        self.charge_on(tenant, gpu_seconds, today());
+    }
+
+    fn charge_on(&self, tenant: &str, gpu_seconds: f64, day: u64) {
+        let (_, configured) = self.limits(tenant);
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.roll(day);
+        ledger.replica.gpu_seconds += gpu_seconds;
+        let usage = ledger.usage(tenant, configured, day);
+        usage.gpu_seconds += gpu_seconds;
+        if let Some(tenant) = self.metrics.tenants.admit(tenant) {
+            self.metrics
+                .quota_gpu_seconds
+                .with_label_values(&[tenant])
+                .set(usage.gpu_seconds);
+        }
+    }
+
+    fn exceeded(&self, tenant: &str) {
+        self.metrics
+            .quota_exceeded
+            .with_label_values(&[
+                self.metrics.tenants.bucket(tenant),
+                self.config.overflow.as_str(),
+            ])
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(replica: QuotaLimits) -> QuotaTracker {
+        QuotaTracker::new(
+            QuotaConfig {
+                per_tenant: QuotaLimits {
+                    messages: Some(2),
+                    gpu_seconds: None,
+                },
+                tenants: vec!["partner=:1.5".parse().unwrap()],
+                replica,
+                overflow: OverflowAction::Reject,
+                backfill_subject: "disinfo.backfill".to_string(),
+            },
+            Arc::new(Metrics::new().unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_tenant_quota_parse() {
+        let quota: TenantQuota = "newsroom=50000:3600".parse().unwrap();
+        assert_eq!(quota.limits.messages, Some(50000));
+        assert_eq!(quota.limits.gpu_seconds, Some(3600.0));
+        let quota: TenantQuota = "newsroom=100".parse().unwrap();
+        assert_eq!(quota.limits.gpu_seconds, None);
+
+        assert!("newsroom".parse::<TenantQuota>().is_err());
+        assert!("newsroom=:".parse::<TenantQuota>().is_err());
+        assert!("newsroom=many".parse::<TenantQuota>().is_err());
+    }
+
+    #[test]
+    fn test_daily_limits_per_tenant() {
+        let quotas = tracker(QuotaLimits::default());
+        assert!(quotas.admit_on("newsroom", 1));
+        assert!(quotas.admit_on("newsroom", 1));
+        assert!(!quotas.admit_on("newsroom", 1));
+        // Other tenants and the next day are unaffected
+        assert!(quotas.admit_on("blog", 1));
+        assert!(quotas.admit_on("newsroom", 2));
+
+        // The partner override limits inference time, not messages
+        quotas.metrics.tenants.observe("partner");
+        for _ in 0..5 {
+            assert!(quotas.admit_on("partner", 1));
+        }
+        quotas.charge_on("partner", 2.0, 1);
+        assert!(!quotas.admit_on("partner", 1));
+        assert_eq!(
+            quotas
+                .metrics
+                .quota_exceeded
+                .with_label_values(&["partner", "reject"])
+                .get(),
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_new_day_forgets_tenants() {
+        let quotas = tracker(QuotaLimits::default());
+        assert!(quotas.admit_on("newsroom", 1));
+        assert!(quotas.admit_on("blog", 1));
+        assert!(quotas.admit_on("blog", 2));
+        let ledger = quotas.ledger.lock().unwrap();
+        assert_eq!(ledger.tenants.len(), 1);
+        assert_eq!(ledger.tenants["blog"].messages, 1);
+    }
+
+    #[test]
+    fn test_tracked_tenants_are_bounded() {
+        let quotas = tracker(QuotaLimits::default());
+        for i in 0..MAX_TRACKED_TENANTS {
+            assert!(quotas.admit_on(&format!("tenant-{}", i), 1));
+        }
+        // Later tenants share one count, tenants with own limits do not
+        assert!(quotas.admit_on("late-1", 1));
+        assert!(quotas.admit_on("late-2", 1));
+        assert!(!quotas.admit_on("late-3", 1));
+        assert!(quotas.admit_on("partner", 1));
+        assert!(quotas.admit_on("tenant-0", 1));
+
+        let ledger = quotas.ledger.lock().unwrap();
+        assert_eq!(ledger.tenants.len(), MAX_TRACKED_TENANTS + 1);
+        assert_eq!(ledger.untracked.messages, 2);
+    }
+
+    #[test]
+    fn test_replica_limit_spares_tenant_count() {
+        let quotas = tracker(QuotaLimits {
+            messages: Some(1),
+            gpu_seconds: None,
+        });
+        assert!(quotas.admit_on("newsroom", 1));
+        assert!(!quotas.admit_on("blog", 1));
+        let ledger = quotas.ledger.lock().unwrap();
+        assert!(!ledger.tenants.contains_key("blog"));
+        assert_eq!(ledger.replica.messages, 1);
+    }
+}
@@ -0,0 +1,262 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Engines evaluating the symbolic rules
+//!
+//! The pipeline, the shadow runner and the topic monitor hand base facts to
+//! a [`ReasoningEngine`] built once from the configured
+//! [`SymbolicBackend`]. Besides the embedded mirror and the Soufflé
+//! backends, builds with the `ascent-engine` feature can evaluate the rules
+//! with an in-process Datalog engine, so deployments without the `souffle`
+//! binary still get stratified rule evaluation rather than hand-written
+//! Rust.
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::{path::PathBuf, sync::Arc};
+
+use crate::fact_mapping::Fact;
+use crate::souffle_sandbox::SouffleSandbox;
+use crate::souffle_wrapper::{self, Derivation, SymbolicBackend};
+
+/// Evaluates the rules over one message's base facts
+pub trait ReasoningEngine: Send + Sync {
+    fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>>;
+}
+
+/// Build the engine for the configured backend, running the Soufflé
+/// backends in `sandbox`
+pub fn from_config(
+    backend: &SymbolicBackend,
+    sandbox: &SouffleSandbox,
+) -> Result<Arc<dyn ReasoningEngine>> {
+    match backend {
+        SymbolicBackend::Embedded => Ok(Arc::new(EmbeddedEngine)),
+        SymbolicBackend::Souffle { souffle, program } => Ok(Arc::new(ExternalEngine {
+            executable: souffle.clone(),
+            program: program.clone(),
+            interpret: true,
+            sandbox: sandbox.clone(),
+        })),
+        SymbolicBackend::Compiled { binary, program } => Ok(Arc::new(ExternalEngine {
+            executable: binary.clone(),
+            program: program.clone(),
+            interpret: false,
+            sandbox: sandbox.clone(),
+        })),
+        SymbolicBackend::CompileOnLoad { .. } => from_config(&backend.compile()?, sandbox),
+        #[cfg(feature = "ascent-engine")]
+        SymbolicBackend::Ascent => Ok(Arc::new(AscentEngine)),
+        #[cfg(not(feature = "ascent-engine"))]
+        SymbolicBackend::Ascent => {
+            anyhow::bail!("Ascent rule engine requires building with --features ascent-engine")
+        }
+    }
+}
+
+/// In-process mirror of `rules/detector.dl`
+pub struct EmbeddedEngine;
+
+impl ReasoningEngine for EmbeddedEngine {
+    fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+        let derivation = souffle_wrapper::evaluate(facts);
+        Box::pin(async move { Ok(derivation) })
+    }
+}
+
+/// The Soufflé interpreter, or a program compiled with `souffle -o`
+pub struct ExternalEngine {
+    executable: PathBuf,
+    program: PathBuf,
+    interpret: bool,
+    sandbox: SouffleSandbox,
+}
+
+impl ReasoningEngine for ExternalEngine {
+    fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+        Box::pin(souffle_wrapper::run_external(
+            &self.executable,
+            &self.program,
+            self.interpret,
+            &self.sandbox,
+            facts,
+        ))
+    }
+}
+
+#[cfg(feature = "ascent-engine")]
+pub use ascent_backend::AscentEngine;
+
+#[cfg(feature = "ascent-engine")]
+mod ascent_backend {
+    use anyhow::Result;
+    use ascent::ascent;
+    use futures::future::BoxFuture;
+
+    use super::ReasoningEngine;
+    use crate::fact_mapping::Fact;
+    use crate::souffle_wrapper::{self, Derivation};
+
+    // `rules/detector.dl`, compiled into the service
+    ascent! {
+        struct DetectorProgram;
+
+        relation message();
+        relation fakeness(String);
+        relation source_trusted(String);
+        relation ai_generated(String);
+        relation emotion(String);
+
+        relation elevated_fakeness();
+        relation untrusted_source();
+        relation disinfo();
+        relation synthetic_text();
+        relation verdict(String);
+        relation label(&'static str);
+        relation fired(&'static str, &'static str);
+
+        elevated_fakeness() <-- fakeness(level), if level == "medium" || level == "high";
+        untrusted_source() <-- message(), !source_trusted("true".to_string());
+        disinfo() <-- fakeness(level), if level == "high", untrusted_source();
+        synthetic_text() <-- ai_generated(level), if level == "high";
+
+        verdict("DISINFO".to_string()) <-- disinfo();
+        verdict("SUSPICIOUS".to_string()) <-- elevated_fakeness(), !disinfo();
+        verdict("SAFE".to_string()) <-- message(), !elevated_fakeness();
+
+        label("AI_GENERATED") <-- synthetic_text();
+        label("EMOTIONALLY_MANIPULATIVE") <-- emotion(level), if level == "high";
+
+        fired("elevated_fakeness:medium", r#"fakeness("medium")"#) <--
+            fakeness(level), if level == "medium";
+        fired("elevated_fakeness:high", r#"fakeness("high")"#) <--
+            fakeness(level), if level == "high";
+        fired("untrusted_source", r#"!source_trusted("true")"#) <--
+            message(), !source_trusted("true".to_string());
+        fired("disinfo", r#"fakeness("high")"#) <--
+            fakeness(level), if level == "high", untrusted_source();
+        fired("disinfo", "untrusted_source()") <--
+            fakeness(level), if level == "high", untrusted_source();
+        fired("synthetic_text", r#"ai_generated("high")"#) <--
+            ai_generated(level), if level == "high";
+        fired("verdict:disinfo", "disinfo()") <-- disinfo();
+        fired("verdict:suspicious", "elevated_fakeness()") <-- elevated_fakeness(), !disinfo();
+        fired("verdict:suspicious", "!disinfo()") <-- elevated_fakeness(), !disinfo();
+        fired("verdict:safe", "!elevated_fakeness()") <-- message(), !elevated_fakeness();
+        fired("label:ai_generated", "synthetic_text()") <-- synthetic_text();
+        fired("label:emotionally_manipulative", r#"emotion("high")"#) <--
+            emotion(level), if level == "high";
+    }
+
+    /// Semi-naive, stratified evaluation of the detector rules in process
+    pub struct AscentEngine;
+
+    impl ReasoningEngine for AscentEngine {
+        fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+            Box::pin(async move { run(facts) })
+        }
+    }
+
+    fn run(facts: &[Fact]) -> Result<Derivation> {
+        let mut program = DetectorProgram {
+            message: vec![()],
+            ..Default::default()
+        };
+        for fact in facts {
+            let [value] = fact.args.as_slice() else {
+                continue;
+            };
+            let row = (value.clone(),);
+            match fact.relation.as_str() {
+                "fakeness" => program.fakeness.push(row),
+                "source_trusted" => program.source_trusted.push(row),
+                "ai_generated" => program.ai_generated.push(row),
+                "emotion" => program.emotion.push(row),
+                _ => {}
+            }
+        }
+        program.run();
+
+        let verdict =
+            souffle_wrapper::single_verdict(program.verdict.iter().map(|(v,)| v.as_str()))?;
+        let mut derived = Vec::new();
+        for (relation, holds) in [
+            ("elevated_fakeness", !program.elevated_fakeness.is_empty()),
+            ("untrusted_source", !program.untrusted_source.is_empty()),
+            ("synthetic_text", !program.synthetic_text.is_empty()),
+        ] {
+            if holds {
+                derived.push(Fact::new(relation, vec![]));
+            }
+        }
+        for (symbol,) in &program.verdict {
+            derived.push(Fact::new("verdict", vec![symbol.clone()]));
+        }
+        let mut labels: Vec<&str> = program.label.iter().map(|(name,)| *name).collect();
+        labels.sort();
+        for name in labels {
+            derived.push(Fact::new("label", vec![name.to_string()]));
+        }
+
+        let fired = souffle_wrapper::group_fired(program.fired.iter().copied());
+        Ok(Derivation {
+            verdict,
+            explanation: souffle_wrapper::explain(
+                verdict,
+                !program.synthetic_text.is_empty(),
+                &fired,
+            ),
+            derived,
+            fired,
+            rules_version: String::new(),
+            rules_semver: String::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fact(relation: &str, value: &str) -> Fact {
+        Fact::new(relation, vec![value.to_string()])
+    }
+
+    #[tokio::test]
+    async fn test_embedded_engine_matches_evaluate() {
+        let facts = vec![fact("fakeness", "high"), fact("source_trusted", "false")];
+        let engine = from_config(&SymbolicBackend::Embedded, &SouffleSandbox::default()).unwrap();
+        assert_eq!(
+            engine.evaluate(&facts).await.unwrap(),
+            souffle_wrapper::evaluate(&facts)
+        );
+    }
+
+    #[cfg(not(feature = "ascent-engine"))]
+    #[test]
+    fn test_ascent_requires_feature() {
+        assert!(from_config(&SymbolicBackend::Ascent, &SouffleSandbox::default()).is_err());
+    }
+
+    #[cfg(feature = "ascent-engine")]
+    #[tokio::test]
+    async fn test_ascent_engine_agrees_with_embedded() {
+        let engine = from_config(&SymbolicBackend::Ascent, &SouffleSandbox::default()).unwrap();
+        let cases = vec![
+            vec![],
+            vec![fact("fakeness", "low"), fact("source_trusted", "true")],
+            vec![fact("fakeness", "medium")],
+            vec![fact("fakeness", "high"), fact("source_trusted", "true")],
+            vec![fact("fakeness", "high"), fact("ai_generated", "high")],
+            vec![fact("ai_generated", "high"), fact("emotion", "high")],
+        ];
+        for facts in cases {
+            assert_eq!(
+                engine.evaluate(&facts).await.unwrap(),
+                souffle_wrapper::evaluate(&facts),
+                "facts: {:?}",
+                facts
+            );
+        }
+    }
+}
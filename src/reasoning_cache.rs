@@ -0,0 +1,200 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Cache of rule derivations
+//!
+//! Scores reach the rules discretized into levels, so many messages from
+//! the same source with near-identical scores hand the rules the same base
+//! facts and get the same derivation. [`ReasoningCache`] keys derivations
+//! by the rules version and the facts, and serves a repeat within the TTL
+//! without evaluating the rules. A reload changes the version, so cached
+//! derivations of the old rules are never served; cache hits skip the
+//! shadow rule packs as well.
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::cache::LruCache;
+use crate::fact_mapping::Fact;
+use crate::live_rules::LiveRules;
+use crate::maintenance::Compact;
+use crate::metrics::Metrics;
+use crate::reasoning::ReasoningEngine;
+use crate::souffle_wrapper::Derivation;
+
+/// Reasoning cache settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReasoningCacheConfig {
+    /// Maximum derivations held; least recently used are evicted
+    pub capacity: usize,
+    /// How long a derivation is served
+    pub ttl: Duration,
+}
+
+/// Serves derivations of `engine` for facts seen within the TTL
+pub struct ReasoningCache {
+    engine: Arc<dyn ReasoningEngine>,
+    rules: Arc<LiveRules>,
+    ttl: Duration,
+    entries: Mutex<LruCache<[u8; 32], (Instant, Derivation)>>,
+    metrics: Arc<Metrics>,
+}
+
+impl ReasoningCache {
+    /// Cache `engine`, which evaluates `rules`
+    pub fn new(
+        engine: Arc<dyn ReasoningEngine>,
+        rules: Arc<LiveRules>,
+        config: &ReasoningCacheConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            engine,
+            rules,
+            ttl: config.ttl,
+            entries: Mutex::new(LruCache::new(config.capacity)),
+            metrics,
+        }
+    }
+
+    /// Drop every entry
+    ///
+    /// # Returns
+    /// Number of entries dropped
+    pub fn shed(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let shed = entries.len();
+        entries.clear();
+        self.metrics.reasoning_cache_entries.set(0.0);
+        shed
+    }
+
+    async fn run(&self, facts: &[Fact]) -> Result<Derivation> {
+        let key = cache_key(&self.rules.current().manifest.sha256, facts);
+        let cached = self.entries.lock().unwrap().get(&key);
+        if let Some((at, derivation)) = cached {
+            if at.elapsed() < self.ttl {
+                self.metrics.reasoning_cache_hits.inc();
+                return Ok(derivation);
+            }
+        }
+        self.metrics.reasoning_cache_misses.inc();
+
+        let derivation = self.engine.evaluate(facts).await?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.put(key, (Instant::now(), derivation.clone()));
+        self.metrics
+            .reasoning_cache_entries
+            .set(entries.len() as f64);
+        Ok(derivation)
+    }
+}
+
+impl Compact for ReasoningCache {
+    fn compact(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let dropped = entries.retain(|(at, _)| at.elapsed() < self.ttl);
+        self.metrics
+            .reasoning_cache_entries
+            .set(entries.len() as f64);
+        dropped
+    }
+}
+
+impl ReasoningEngine for ReasoningCache {
+    fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+        Box::pin(self.run(facts))
+    }
+}
+
+/// SHA-256 of the rules version and the facts, in any order
+fn cache_key(rules_version: &str, facts: &[Fact]) -> [u8; 32] {
+    let mut facts: Vec<String> = facts.iter().map(Fact::to_string).collect();
+    facts.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(rules_version);
+    for fact in facts {
+        hasher.update([0]);
+        hasher.update(fact);
+    }
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::souffle_sandbox::SouffleSandbox;
+    use crate::souffle_wrapper::{SymbolicBackend, Verdict};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts evaluations of the embedded rules
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    impl ReasoningEngine for Counting {
+        fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(crate::souffle_wrapper::evaluate(facts)) })
+        }
+    }
+
+    fn cache(ttl: Duration) -> (ReasoningCache, Arc<Counting>, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let rules = Arc::new(
+            LiveRules::load(
+                &SymbolicBackend::Embedded,
+                None,
+                &SouffleSandbox::default(),
+                &metrics,
+            )
+            .unwrap(),
+        );
+        let counting = Arc::new(Counting::default());
+        let cache = ReasoningCache::new(
+            Arc::clone(&counting) as Arc<dyn ReasoningEngine>,
+            rules,
+            &ReasoningCacheConfig { capacity: 8, ttl },
+            Arc::clone(&metrics),
+        );
+        (cache, counting, metrics)
+    }
+
+    fn fact(relation: &str, value: &str) -> Fact {
+        Fact::new(relation, vec![value.to_string()])
+    }
+
+    #[tokio::test]
+    async fn test_identical_facts_skip_the_rules() {
+        let (cache, counting, metrics) = cache(Duration::from_secs(60));
+        let facts = vec![fact("fakeness", "high"), fact("source_trusted", "false")];
+        let first = cache.evaluate(&facts).await.unwrap();
+        // The same facts in another order
+        let reordered: Vec<Fact> = facts.iter().rev().cloned().collect();
+        let second = cache.evaluate(&reordered).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.verdict, Verdict::Disinfo);
+        assert_eq!(counting.0.load(Ordering::SeqCst), 1);
+        assert_eq!(metrics.reasoning_cache_hits.get(), 1.0);
+        assert_eq!(metrics.reasoning_cache_misses.get(), 1.0);
+
+        cache.evaluate(&[fact("fakeness", "low")]).await.unwrap();
+        assert_eq!(counting.0.load(Ordering::SeqCst), 2);
+        assert_eq!(metrics.reasoning_cache_entries.get(), 2.0);
+        assert_eq!(cache.shed(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_derivations_are_evaluated_again() {
+        let (cache, counting, metrics) = cache(Duration::ZERO);
+        let facts = vec![fact("fakeness", "medium")];
+        cache.evaluate(&facts).await.unwrap();
+        cache.evaluate(&facts).await.unwrap();
+        assert_eq!(counting.0.load(Ordering::SeqCst), 2);
+        assert_eq!(metrics.reasoning_cache_hits.get(), 0.0);
+    }
+}
@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Bounded pool of rule evaluations
+//!
+//! Every message that reaches the rules would otherwise start its own
+//! Soufflé process, so a burst starts as many processes as messages in
+//! flight. [`ReasoningPool`] runs at most `workers` evaluations at once and
+//! lets at most `queue` more wait for a worker; further evaluations fail
+//! with [`Saturated`] straight away, and the consumer hands their messages
+//! back for redelivery. The in-process engines evaluate on blocking
+//! threads, so long derivations do not stall the consumer's runtime.
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::sync::Semaphore;
+
+use crate::fact_mapping::Fact;
+use crate::metrics::Metrics;
+use crate::reasoning::ReasoningEngine;
+use crate::souffle_wrapper::Derivation;
+
+/// Worker and queue bounds
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReasoningPoolConfig {
+    /// Evaluations that run at once
+    pub workers: usize,
+    /// Evaluations that may wait for a worker
+    pub queue: usize,
+}
+
+impl Default for ReasoningPoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            queue: 64,
+        }
+    }
+}
+
+/// Every worker is busy and the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Saturated;
+
+impl fmt::Display for Saturated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("reasoning pool saturated")
+    }
+}
+
+impl std::error::Error for Saturated {}
+
+/// Routes evaluations of `engine` through a bounded set of workers
+pub struct ReasoningPool {
+    engine: Arc<dyn ReasoningEngine>,
+    permits: Semaphore,
+    queue: usize,
+    waiting: AtomicUsize,
+    /// Whether `engine` evaluates in process and so runs on blocking threads
+    in_process: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl ReasoningPool {
+    pub fn new(
+        engine: Arc<dyn ReasoningEngine>,
+        in_process: bool,
+        config: &ReasoningPoolConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let workers = config.workers.max(1);
+        metrics.reasoning_workers.set(workers as f64);
+        Self {
+            engine,
+            permits: Semaphore::new(workers),
+            queue: config.queue,
+            waiting: AtomicUsize::new(0),
+            in_process,
+            metrics,
+        }
+    }
+
+    async fn run(&self, facts: &[Fact]) -> Result<Derivation> {
+        let start = Instant::now();
+        let permit = match self.permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.queue {
+                    self.waiting.fetch_sub(1, Ordering::SeqCst);
+                    self.metrics.reasoning_rejected.inc();
+                    return Err(Saturated.into());
+                }
+                self.metrics.reasoning_queued.inc();
+                let permit = self.permits.acquire().await;
+                self.waiting.fetch_sub(1, Ordering::SeqCst);
+                self.metrics.reasoning_queued.dec();
+                permit.expect("reasoning pool semaphore is never closed")
+            }
+        };
+        self.metrics
+            .reasoning_queue_wait
+            .observe(start.elapsed().as_secs_f64());
+
+        self.metrics.reasoning_busy.inc();
+        let derivation = if self.in_process {
+            let engine = Arc::clone(&self.engine);
+            let facts = facts.to_vec();
+            let handle = tokio::runtime::Handle::current();
+            tokio::task::spawn_blocking(move || handle.block_on(engine.evaluate(&facts)))
+                .await
+                .unwrap_or_else(|e| Err(anyhow::anyhow!("rule evaluation panicked: {}", e)))
+        } else {
+            self.engine.evaluate(facts).await
+        };
+        self.metrics.reasoning_busy.dec();
+        drop(permit);
+        derivation
+    }
+}
+
+impl ReasoningEngine for ReasoningPool {
+    fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+        Box::pin(self.run(facts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reasoning::EmbeddedEngine;
+    use crate::souffle_wrapper::Verdict;
+    use std::time::Duration;
+
+    /// Holds every evaluation until released
+    struct Gate(tokio::sync::Notify);
+
+    impl ReasoningEngine for Gate {
+        fn evaluate<'a>(&'a self, facts: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+            Box::pin(async move {
+                self.0.notified().await;
+                EmbeddedEngine.evaluate(facts).await
+            })
+        }
+    }
+
+    fn facts() -> Vec<Fact> {
+        vec![Fact::new("fakeness", vec!["high".to_string()])]
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_rejects() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let gate = Arc::new(Gate(tokio::sync::Notify::new()));
+        let pool = Arc::new(ReasoningPool::new(
+            Arc::clone(&gate) as Arc<dyn ReasoningEngine>,
+            false,
+            &ReasoningPoolConfig {
+                workers: 1,
+                queue: 1,
+            },
+            Arc::clone(&metrics),
+        ));
+        let facts = facts();
+        let running = tokio::spawn({
+            let (pool, facts) = (Arc::clone(&pool), facts.clone());
+            async move { pool.evaluate(&facts).await }
+        });
+        let queued = tokio::spawn({
+            let (pool, facts) = (Arc::clone(&pool), facts.clone());
+            async move { pool.evaluate(&facts).await }
+        });
+        while metrics.reasoning_queued.get() < 1.0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(metrics.reasoning_busy.get(), 1.0);
+
+        let rejected = pool.evaluate(&facts).await.unwrap_err();
+        assert!(rejected.is::<Saturated>());
+        assert_eq!(metrics.reasoning_rejected.get(), 1.0);
+
+        gate.0.notify_one();
+        assert_eq!(running.await.unwrap().unwrap().verdict, Verdict::Disinfo);
+        gate.0.notify_one();
+        assert!(queued.await.unwrap().is_ok());
+        assert_eq!(metrics.reasoning_busy.get(), 0.0);
+        assert_eq!(metrics.reasoning_queued.get(), 0.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_in_process_engines_run_on_blocking_threads() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let pool = ReasoningPool::new(
+            Arc::new(EmbeddedEngine),
+            true,
+            &ReasoningPoolConfig::default(),
+            Arc::clone(&metrics),
+        );
+        let derivation = pool.evaluate(&facts()).await.unwrap();
+        assert_eq!(derivation.verdict, Verdict::Disinfo);
+        assert_eq!(metrics.reasoning_workers.get(), 4.0);
+        assert_eq!(metrics.reasoning_queue_wait.get_sample_count(), 1);
+    }
+}
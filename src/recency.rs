@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Recency of knowledge graph facts
+//!
+//! Soufflé has no notion of the current time, so rules cannot compare
+//! timestamps themselves. A knowledge graph fact that carries the time it
+//! was asserted is instead passed to the rules with its age, in whole days
+//! at the time of evaluation, as `<relation>_age_days(days)`:
+//!
+//! ```text
+//! source_flagged("true").
+//! source_flagged_age_days(12).
+//! recently_flagged() :- source_flagged("true"), source_flagged_age_days(d), d <= 30.
+//! ```
+
+use std::{collections::HashMap, time::SystemTime};
+
+use crate::clock::Clock;
+use crate::souffle_wrapper::DgraphFacts;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// When each knowledge graph fact was asserted, by relation
+pub type FactTimes = HashMap<String, SystemTime>;
+
+/// `<relation>_age_days` facts for the timestamped facts, as of `clock`
+///
+/// Timestamps in the future, e.g. from a skewed clock, are age 0.
+pub fn age_facts(observed: &FactTimes, clock: &dyn Clock) -> DgraphFacts {
+    let now = clock.now();
+    observed
+        .iter()
+        .map(|(relation, at)| {
+            let age = now.duration_since(*at).map_or(0, |age| age.as_secs());
+            (age_relation(relation), (age / SECS_PER_DAY).to_string())
+        })
+        .collect()
+}
+
+/// Relation holding the age of `relation`'s fact
+pub fn age_relation(relation: &str) -> String {
+    format!("{}_age_days", relation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const DAY: Duration = Duration::from_secs(SECS_PER_DAY);
+
+    #[test]
+    fn test_ages_follow_the_clock() {
+        let flagged = UNIX_EPOCH + 100 * DAY;
+        let observed = FactTimes::from([("source_flagged".to_string(), flagged)]);
+        let clock = ManualClock::new(flagged + 29 * DAY + Duration::from_secs(3600));
+        assert_eq!(
+            age_facts(&observed, &clock)["source_flagged_age_days"],
+            "29"
+        );
+
+        clock.advance(DAY);
+        assert_eq!(
+            age_facts(&observed, &clock)["source_flagged_age_days"],
+            "30"
+        );
+    }
+
+    #[test]
+    fn test_future_timestamps_are_age_zero() {
+        let clock = ManualClock::new(UNIX_EPOCH + DAY);
+        let observed = FactTimes::from([("source_flagged".to_string(), UNIX_EPOCH + 3 * DAY)]);
+        assert_eq!(age_facts(&observed, &clock)["source_flagged_age_days"], "0");
+    }
+}
@@ -0,0 +1,392 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Re-evaluation of recent verdicts when graph facts change
+//!
+//! A verdict depends on the knowledge graph facts of its source as they
+//! were when the message arrived. When a source's facts change, e.g. its
+//! reputation is revised, the verdicts of its recent content may no longer
+//! hold. With `NSAI_FACT_UPDATES_SUBJECT` set, the service keeps the model
+//! scores and facts of its most recent verdicts and listens for
+//! [`FactUpdate`]s. An update re-runs only the rules, never the models, and
+//! only for content of that source whose facts it actually changes; a
+//! verdict that changes is published as a [`Revision`].
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+
+use crate::fact_mapping::BinOverrides;
+use crate::metrics::Metrics;
+use crate::onnx_wrapper::NeuralFeatures;
+use crate::reasoning::ReasoningEngine;
+use crate::souffle_wrapper::{self, Aggregation, ConflictPolicy, DgraphFacts, Verdict};
+
+/// Re-evaluation settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReevaluationConfig {
+    /// Subject fact updates arrive on
+    pub subject: String,
+    /// Subject revised verdicts are published on
+    pub revision_subject: String,
+    /// Recent verdicts kept; the oldest are forgotten first
+    pub capacity: usize,
+}
+
+/// New values of a source's graph facts, by relation
+///
+/// A `null` value removes the fact.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FactUpdate {
+    pub source_id: String,
+    pub facts: HashMap<String, Option<String>>,
+}
+
+/// A verdict that changed with the facts of its source
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Revision {
+    pub content_hash: String,
+    pub source_id: String,
+    pub previous_verdict: Verdict,
+    pub verdict: Verdict,
+    pub confidence: f32,
+    pub explanation: String,
+    pub rules_version: String,
+    /// Relations of the update that changed the content's facts
+    pub changed: Vec<String>,
+}
+
+/// What a verdict was decided on
+#[derive(Debug, Clone)]
+struct Evaluated {
+    features: NeuralFeatures,
+    dgraph_facts: DgraphFacts,
+    bins: BinOverrides,
+    verdict: Verdict,
+}
+
+/// Recent verdicts by source and content hash, oldest first in `order`
+#[derive(Default)]
+struct Recent {
+    by_source: HashMap<String, HashMap<String, Evaluated>>,
+    order: VecDeque<(String, String)>,
+}
+
+/// Keeps recent verdicts and re-evaluates them on fact updates
+pub struct Reevaluator {
+    recent: Mutex<Recent>,
+    capacity: usize,
+    reasoning: Arc<dyn ReasoningEngine>,
+    aggregation: Aggregation,
+    conflicts: ConflictPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl Reevaluator {
+    pub fn new(
+        capacity: usize,
+        reasoning: Arc<dyn ReasoningEngine>,
+        aggregation: Aggregation,
+        conflicts: ConflictPolicy,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            recent: Mutex::new(Recent::default()),
+            capacity: capacity.max(1),
+            reasoning,
+            aggregation,
+            conflicts,
+            metrics,
+        }
+    }
+
+    /// Keep what a verdict was decided on
+    pub fn record(
+        &self,
+        source_id: &str,
+        content_hash: &str,
+        features: &NeuralFeatures,
+        dgraph_facts: &DgraphFacts,
+        bins: &BinOverrides,
+        verdict: Verdict,
+    ) {
+        // The rules see scores only
+        let features = NeuralFeatures {
+            embedding: Vec::new(),
+            attributions: Vec::new(),
+            ..features.clone()
+        };
+        let evaluated = Evaluated {
+            features,
+            dgraph_facts: dgraph_facts.clone(),
+            bins: bins.clone(),
+            verdict,
+        };
+
+        let mut recent = self.recent.lock().unwrap();
+        let previous = recent
+            .by_source
+            .entry(source_id.to_string())
+            .or_default()
+            .insert(content_hash.to_string(), evaluated);
+        if previous.is_none() {
+            recent
+                .order
+                .push_back((source_id.to_string(), content_hash.to_string()));
+        }
+        while recent.order.len() > self.capacity {
+            let Some((source_id, content_hash)) = recent.order.pop_front() else {
+                break;
+            };
+            if let Some(contents) = recent.by_source.get_mut(&source_id) {
+                contents.remove(&content_hash);
+                if contents.is_empty() {
+                    recent.by_source.remove(&source_id);
+                }
+            }
+        }
+        self.metrics
+            .reevaluation_tracked
+            .set(recent.order.len() as f64);
+    }
+
+    /// Re-run the rules for the source's content whose facts `update`
+    /// changes
+    ///
+    /// # Returns
+    /// The verdicts that changed
+    pub async fn apply(&self, update: &FactUpdate) -> Vec<Revision> {
+        // Copies of the facts are updated under the lock; the rules run
+        // without it
+        let affected: Vec<(String, Evaluated, Vec<String>)> = {
+            let recent = self.recent.lock().unwrap();
+            let Some(contents) = recent.by_source.get(&update.source_id) else {
+                return Vec::new();
+            };
+            contents
+                .iter()
+                .filter_map(|(content_hash, evaluated)| {
+                    let mut evaluated = evaluated.clone();
+                    let changed = apply_facts(&mut evaluated.dgraph_facts, &update.facts);
+                    if changed.is_empty() {
+                        self.metrics
+                            .reevaluations
+                            .with_label_values(&["skipped"])
+                            .inc();
+                        return None;
+                    }
+                    Some((content_hash.clone(), evaluated, changed))
+                })
+                .collect()
+        };
+
+        let mut revisions = Vec::new();
+        for (content_hash, mut evaluated, changed) in affected {
+            let result = match souffle_wrapper::run_datalog(
+                self.reasoning.as_ref(),
+                &evaluated.features,
+                &evaluated.dgraph_facts,
+                &evaluated.bins,
+                self.aggregation,
+                self.conflicts,
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("Re-evaluation of {} failed: {:#}", content_hash, e);
+                    self.metrics
+                        .reevaluations
+                        .with_label_values(&["failed"])
+                        .inc();
+                    continue;
+                }
+            };
+
+            let previous_verdict = evaluated.verdict;
+            let outcome = match result.verdict == previous_verdict {
+                true => "confirmed",
+                false => "revised",
+            };
+            self.metrics
+                .reevaluations
+                .with_label_values(&[outcome])
+                .inc();
+            evaluated.verdict = result.verdict;
+            // Content forgotten in the meantime stays forgotten
+            if let Some(kept) = self
+                .recent
+                .lock()
+                .unwrap()
+                .by_source
+                .get_mut(&update.source_id)
+                .and_then(|contents| contents.get_mut(&content_hash))
+            {
+                *kept = evaluated;
+            }
+
+            if result.verdict != previous_verdict {
+                info!(
+                    "Verdict for {} revised after a fact update: {} -> {}",
+                    content_hash, previous_verdict, result.verdict
+                );
+                revisions.push(Revision {
+                    content_hash,
+                    source_id: update.source_id.clone(),
+                    previous_verdict,
+                    verdict: result.verdict,
+                    confidence: result.confidence,
+                    explanation: result.explanation,
+                    rules_version: result.rules_version,
+                    changed,
+                });
+            }
+        }
+        revisions
+    }
+}
+
+/// Apply new fact values, returning the relations whose value changed
+fn apply_facts(facts: &mut DgraphFacts, update: &HashMap<String, Option<String>>) -> Vec<String> {
+    let mut changed = Vec::new();
+    for (relation, value) in update {
+        let previous = match value {
+            Some(value) => facts.insert(relation.clone(), value.clone()),
+            None => facts.remove(relation),
+        };
+        if previous.as_ref() != value.as_ref() {
+            changed.push(relation.clone());
+        }
+    }
+    changed.sort();
+    changed
+}
+
+/// Re-evaluate on every fact update and publish the revisions
+pub async fn run_updates(
+    reevaluator: Arc<Reevaluator>,
+    client: async_nats::Client,
+    config: ReevaluationConfig,
+) -> Result<()> {
+    let mut updates = client
+        .subscribe(config.subject.clone())
+        .await
+        .with_context(|| format!("Failed to subscribe to {}", config.subject))?;
+    while let Some(message) = updates.next().await {
+        let update: FactUpdate = match serde_json::from_slice(&message.payload) {
+            Ok(update) => update,
+            Err(e) => {
+                warn!("Invalid fact update on {}: {}", config.subject, e);
+                reevaluator.metrics.errors.inc();
+                continue;
+            }
+        };
+        for revision in reevaluator.apply(&update).await {
+            match serde_json::to_vec(&revision) {
+                Ok(payload) => {
+                    if let Err(e) = client
+                        .publish(config.revision_subject.clone(), payload.into())
+                        .await
+                    {
+                        warn!("Failed to publish revision: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to encode revision: {}", e),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reasoning::EmbeddedEngine;
+
+    fn reevaluator(capacity: usize) -> (Reevaluator, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let reevaluator = Reevaluator::new(
+            capacity,
+            Arc::new(EmbeddedEngine),
+            Aggregation::default(),
+            ConflictPolicy::default(),
+            Arc::clone(&metrics),
+        );
+        (reevaluator, metrics)
+    }
+
+    fn record(reevaluator: &Reevaluator, content_hash: &str, fakeness: f32, verdict: Verdict) {
+        let features = NeuralFeatures {
+            fakeness,
+            ..Default::default()
+        };
+        let facts = DgraphFacts::from([("source_trusted".to_string(), "true".to_string())]);
+        reevaluator.record(
+            "twitter:@example",
+            content_hash,
+            &features,
+            &facts,
+            &BinOverrides::new(),
+            verdict,
+        );
+    }
+
+    fn update(value: Option<&str>) -> FactUpdate {
+        FactUpdate {
+            source_id: "twitter:@example".to_string(),
+            facts: HashMap::from([("source_trusted".to_string(), value.map(String::from))]),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fact_updates_revise_affected_verdicts() {
+        let (reevaluator, metrics) = reevaluator(8);
+        record(&reevaluator, "fake", 0.95, Verdict::Suspicious);
+        record(&reevaluator, "benign", 0.05, Verdict::Safe);
+
+        // The source loses its trusted status
+        let revisions = reevaluator.apply(&update(None)).await;
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0].content_hash, "fake");
+        assert_eq!(revisions[0].previous_verdict, Verdict::Suspicious);
+        assert_eq!(revisions[0].verdict, Verdict::Disinfo);
+        assert_eq!(revisions[0].changed, vec!["source_trusted"]);
+        let count = |outcome: &str| metrics.reevaluations.with_label_values(&[outcome]).get();
+        assert_eq!(count("revised"), 1.0);
+        assert_eq!(count("confirmed"), 1.0);
+
+        // The same update again changes nothing
+        assert!(reevaluator.apply(&update(None)).await.is_empty());
+        assert_eq!(count("skipped"), 2.0);
+
+        // Other sources are unaffected
+        let other = FactUpdate {
+            source_id: "twitter:@other".to_string(),
+            ..update(Some("false"))
+        };
+        assert!(reevaluator.apply(&other).await.is_empty());
+    }
+
+    #[test]
+    fn test_oldest_verdicts_are_forgotten() {
+        let (reevaluator, metrics) = reevaluator(2);
+        record(&reevaluator, "a", 0.5, Verdict::Safe);
+        record(&reevaluator, "b", 0.5, Verdict::Safe);
+        // A repeat replaces its entry instead of adding one
+        record(&reevaluator, "b", 0.6, Verdict::Safe);
+        let tracked = |reevaluator: &Reevaluator| reevaluator.recent.lock().unwrap().order.len();
+        assert_eq!(tracked(&reevaluator), 2);
+        record(&reevaluator, "c", 0.5, Verdict::Safe);
+        assert_eq!(tracked(&reevaluator), 2);
+        assert_eq!(metrics.reevaluation_tracked.get(), 2.0);
+        let recent = reevaluator.recent.lock().unwrap();
+        let contents = &recent.by_source["twitter:@example"];
+        assert!(!contents.contains_key("a"));
+        assert!(contents.contains_key("b") && contents.contains_key("c"));
+    }
+}
@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Interactive rule simulation (`nsai-detector rules repl`)
+//!
+//! Lets an analyst set neural scores, assert graph facts, tweak bin
+//! thresholds and re-run the rules without touching NATS.
+
+use anyhow::{bail, Context, Result};
+use std::io::{self, BufRead, Write};
+
+use crate::fact_mapping::{validate_overrides, BinOverrides, Fact, NEURAL_BINS};
+use crate::onnx_wrapper::NeuralFeatures;
+use crate::souffle_wrapper::{base_facts, evaluate, DgraphFacts, FiredRule};
+
+const HELP: &str = "\
+Commands:
+  set <feature> <value>                            set a neural score
+                                                   (fakeness, emotion,
+                                                   ai_generated, visual_artifact)
+  assert <relation(args)>                          add a base fact
+  retract <relation(args)>                         remove an asserted fact
+  bins <relation> <e0,e1,...>                      override bin edges
+  bins                                             show active bins
+  facts                                            show base facts
+  run                                              evaluate the rules
+  reset                                            clear the session
+  quit                                             leave the REPL";
+
+/// Result of handling one REPL line
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Output(String),
+    Quit,
+}
+
+/// State of one simulation session
+#[derive(Default)]
+pub struct Session {
+    features: NeuralFeatures,
+    asserted: Vec<Fact>,
+    overrides: BinOverrides,
+}
+
+impl Session {
+    /// Handle a single input line
+    pub fn handle(&mut self, line: &str) -> Result<Reply> {
+        let line = line.trim();
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        let output = match command {
+            "" => String::new(),
+            "help" => HELP.to_string(),
+            "quit" | "exit" => return Ok(Reply::Quit),
+            "set" => self.set(rest)?,
+            "assert" => {
+                let fact: Fact = rest.parse()?;
+                let shown = fact.to_string();
+                if !self.asserted.contains(&fact) {
+                    self.asserted.push(fact);
+                }
+                format!("asserted {}", shown)
+            }
+            "retract" => {
+                let fact: Fact = rest.parse()?;
+                let before = self.asserted.len();
+                self.asserted.retain(|f| f != &fact);
+                if self.asserted.len() == before {
+                    bail!("{} was not asserted", fact);
+                }
+                format!("retracted {}", fact)
+            }
+            "bins" if rest.is_empty() => self.show_bins(),
+            "bins" => self.set_bins(rest)?,
+            "facts" => self
+                .facts()
+                .iter()
+                .map(Fact::to_string)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "run" => {
+                let derivation = evaluate(&self.facts());
+                let derived: Vec<String> = derivation.derived.iter().map(Fact::to_string).collect();
+                let fired: Vec<String> =
+                    derivation.fired.iter().map(FiredRule::to_string).collect();
+                format!(
+                    "verdict: {}\nexplanation: {}\nderived:\n  {}\nfired:\n  {}",
+                    derivation.verdict,
+                    derivation.explanation,
+                    derived.join("\n  "),
+                    fired.join("\n  ")
+                )
+            }
+            "reset" => {
+                *self = Self::default();
+                "session cleared".to_string()
+            }
+            other => bail!("unknown command {:?} (try `help`)", other),
+        };
+
+        Ok(Reply::Output(output))
+    }
+
+    fn set(&mut self, args: &str) -> Result<String> {
+        let (feature, value) = args
+            .split_once(' ')
+            .context("usage: set <feature> <value>")?;
+        let value = value.trim();
+        match feature {
+            "fakeness" => self.features.fakeness = value.parse()?,
+            "emotion" => self.features.emotion = value.parse()?,
+            "ai_generated" => self.features.ai_generated = value.parse()?,
+            "visual_artifact" => self.features.visual_artifact = value.parse()?,
+            other => bail!("unknown feature {:?}", other),
+        }
+        Ok(format!("{} = {}", feature, value))
+    }
+
+    fn set_bins(&mut self, args: &str) -> Result<String> {
+        let (relation, edges) = args
+            .split_once(' ')
+            .context("usage: bins <relation> <e0,e1,...>")?;
+        let edges = edges
+            .split(',')
+            .map(|e| e.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .context("bin edges must be numbers")?;
+
+        let mut candidate = self.overrides.clone();
+        candidate.insert(relation.to_string(), edges);
+        validate_overrides(NEURAL_BINS, &candidate)?;
+        self.overrides = candidate;
+        Ok(format!("{} bins updated", relation))
+    }
+
+    fn show_bins(&self) -> String {
+        NEURAL_BINS
+            .iter()
+            .map(|spec| {
+                format!(
+                    "{}: {:?} -> {:?}",
+                    spec.relation,
+                    spec.edges(&self.overrides),
+                    spec.levels
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn facts(&self) -> Vec<Fact> {
+        let mut facts = base_facts(&self.features, &DgraphFacts::new(), &self.overrides);
+        facts.extend(self.asserted.iter().cloned());
+        facts
+    }
+}
+
+/// Run the REPL on stdin/stdout until `quit` or end of input
+pub fn run() -> Result<()> {
+    let mut session = Session::default();
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    println!("NSAI rule REPL (type `help` for commands)");
+    loop {
+        print!("rules> ");
+        stdout.flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+
+        match session.handle(&line) {
+            Ok(Reply::Output(output)) if !output.is_empty() => println!("{}", output),
+            Ok(Reply::Output(_)) => {}
+            Ok(Reply::Quit) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(session: &mut Session, line: &str) -> String {
+        match session.handle(line).unwrap() {
+            Reply::Output(output) => output,
+            Reply::Quit => panic!("unexpected quit"),
+        }
+    }
+
+    #[test]
+    fn test_assert_and_run() {
+        let mut session = Session::default();
+        output(&mut session, "set fakeness 0.9");
+        assert!(output(&mut session, "run").contains("verdict: DISINFO"));
+
+        output(&mut session, "assert source_trusted(\"true\")");
+        assert!(output(&mut session, "run").contains("verdict: SUSPICIOUS"));
+    }
+
+    #[test]
+    fn test_bins_tweak_changes_verdict() {
+        let mut session = Session::default();
+        output(&mut session, "set fakeness 0.5");
+        assert!(output(&mut session, "run").contains("verdict: SAFE"));
+
+        output(&mut session, "bins fakeness 0.0,0.4,0.8,1.0");
+        assert!(output(&mut session, "run").contains("verdict: SUSPICIOUS"));
+        assert!(session.handle("bins fakeness 0.0,1.0").is_err());
+    }
+
+    #[test]
+    fn test_quit() {
+        let mut session = Session::default();
+        assert_eq!(session.handle("quit").unwrap(), Reply::Quit);
+    }
+}
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Re-processing of messages already in the stream
+//!
+//! After a model or rules upgrade, past inputs can be scored again by an
+//! ephemeral consumer reading the stream from a sequence or a point in
+//! time up to the last message present when the replay started. The
+//! durable consumer of the running service is left alone.
+//!
+//! A replay re-scores rather than repeats: deduplication would answer with
+//! the old results, so it is off, and quotas are off since the inputs were
+//! already admitted once. In shadow mode results go only to a separate
+//! subject and nothing the service remembers about verdicts is written, so
+//! an upgrade can be compared against production before it is rolled out.
+
+use anyhow::{Context, Result};
+use async_nats::jetstream::consumer::DeliverPolicy;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::config::{Config, ResultFormats};
+
+/// Where in the stream a replay starts
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayStart {
+    /// At this stream sequence
+    Sequence(u64),
+    /// At the first message stored at or after this time
+    Time(OffsetDateTime),
+}
+
+impl ReplayStart {
+    /// Parse an RFC 3339 timestamp, e.g. `2024-05-01T00:00:00Z`
+    pub fn at_time(timestamp: &str) -> Result<Self> {
+        OffsetDateTime::parse(timestamp, &Rfc3339)
+            .map(Self::Time)
+            .with_context(|| format!("Invalid replay start time: {}", timestamp))
+    }
+
+    pub fn deliver_policy(&self) -> DeliverPolicy {
+        match self {
+            Self::Sequence(sequence) => DeliverPolicy::ByStartSequence {
+                start_sequence: *sequence,
+            },
+            Self::Time(time) => DeliverPolicy::ByStartTime { start_time: *time },
+        }
+    }
+}
+
+/// Replay settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayConfig {
+    pub start: ReplayStart,
+    /// Subject results are published on instead of the result subjects;
+    /// `None` to publish replayed results like live ones
+    pub shadow_subject: Option<String>,
+}
+
+impl ReplayConfig {
+    /// Adapt the service configuration to replaying
+    pub fn apply(&self, config: &mut Config) {
+        config.dedup = None;
+        config.quota = None;
+
+        let Some(subject) = &self.shadow_subject else {
+            return;
+        };
+        config.publish.result_subject = subject.clone();
+        config.publish.formats = ResultFormats::Rich;
+        config.publish.webhooks = None;
+        config.telemetry = None;
+        config.history = None;
+        config.reevaluation = None;
+        config.similarity = None;
+        config.claims = None;
+        config.verdict_store = None;
+        if let Some(graph) = config.knowledge_graph.as_mut() {
+            graph.write_back = false;
+        }
+        if let Some(ner) = config.inference.ner.as_mut() {
+            ner.dgraph_url = None;
+        }
+    }
+}
+
+/// Whether every message up to stream sequence `end` has been dealt with,
+/// given the replay consumer's ack floor and its pending and unacknowledged
+/// message counts
+pub fn caught_up(ack_floor: u64, pending: u64, ack_pending: usize, end: u64) -> bool {
+    ack_floor >= end || (pending == 0 && ack_pending == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dedup::DedupConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn test_start_time_parse() {
+        let start = ReplayStart::at_time("2024-05-01T12:00:00Z").unwrap();
+        assert_eq!(
+            start,
+            ReplayStart::Time(OffsetDateTime::from_unix_timestamp(1_714_564_800).unwrap())
+        );
+        assert!(ReplayStart::at_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_shadow_replay_publishes_apart() {
+        let mut config = Config {
+            dedup: Some(DedupConfig {
+                capacity: 10,
+                ttl: Duration::from_secs(60),
+                kv_bucket: None,
+            }),
+            ..Default::default()
+        };
+        let replay = ReplayConfig {
+            start: ReplayStart::Sequence(1),
+            shadow_subject: Some("disinfo.verdicts.replay".to_string()),
+        };
+        replay.apply(&mut config);
+        assert!(config.dedup.is_none());
+        assert_eq!(config.publish.result_subject, "disinfo.verdicts.replay");
+        assert!(!config.publish.formats.legacy());
+        assert!(config.publish.webhooks.is_none());
+
+        // Without shadow mode results go where live ones do
+        let mut config = Config::default();
+        ReplayConfig {
+            shadow_subject: None,
+            ..replay
+        }
+        .apply(&mut config);
+        assert_eq!(config.publish.result_subject, "disinfo.verdicts");
+    }
+
+    #[test]
+    fn test_caught_up() {
+        assert!(!caught_up(10, 5, 2, 15));
+        assert!(caught_up(15, 3, 1, 15));
+        // Started past the end, or nothing left either way
+        assert!(caught_up(20, 0, 0, 15));
+        assert!(caught_up(0, 0, 0, 15));
+    }
+}
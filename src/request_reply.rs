@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Synchronous analysis over core NATS request-reply
+//!
+//! A caller that needs the verdict before it can go on, like a moderation
+//! step in a posting flow, should not have to publish to the stream and
+//! watch the verdicts subject for its content hash. Requests carrying an
+//! `AnalysisInput` on the request subject run through the same pipeline
+//! and are answered with the `AnalysisResult`. Replicas answer as one
+//! queue group, so each request is analysed once.
+//!
+//! A request is answered within the deadline or not at all: one whose
+//! analysis runs past it, or fails transiently, gets an empty reply with a
+//! `Nats-Service-Error` header, and it is up to the caller to try again.
+
+use anyhow::{Context, Result};
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::time::Duration;
+use tokio::{signal, time::timeout};
+use tracing::{info, warn, Instrument};
+
+use crate::delivery::Delivery;
+use crate::pipeline::Pipeline;
+use crate::propagation::MessageContext;
+
+/// Request-reply settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestReplyConfig {
+    /// Subject requests arrive on; must not be one of the input stream's
+    pub subject: String,
+    /// Queue group the replicas share requests in
+    pub queue_group: String,
+    /// Deadline of an analysis, from receipt to reply
+    pub timeout: Duration,
+    /// Requests analysed at once; the consumer's worker count when `None`
+    pub workers: Option<usize>,
+}
+
+/// Answer requests until shutdown
+pub async fn serve(
+    pipeline: &Pipeline,
+    client: async_nats::Client,
+    config: &RequestReplyConfig,
+) -> Result<()> {
+    let mut requests = client
+        .queue_subscribe(config.subject.clone(), config.queue_group.clone())
+        .await
+        .with_context(|| format!("Failed to subscribe to {}", config.subject))?;
+    let workers = config
+        .workers
+        .unwrap_or_else(|| pipeline.config.consumer.workers(&pipeline.config.inference));
+    info!(
+        "Answering analysis requests on {}, up to {} at once",
+        config.subject, workers
+    );
+
+    let mut in_flight = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            _ = signal::ctrl_c() => break,
+            Some(()) = in_flight.next(), if !in_flight.is_empty() => {}
+            request = requests.next(), if in_flight.len() < workers => match request {
+                Some(request) => in_flight.push(answer(pipeline, &client, request, config.timeout)),
+                None => {
+                    warn!("Request subscription to {} ended", config.subject);
+                    break;
+                }
+            },
+        }
+    }
+
+    while in_flight.next().await.is_some() {}
+    Ok(())
+}
+
+/// Analyse `request` and reply, or reply with an error at the deadline
+async fn answer(
+    pipeline: &Pipeline,
+    client: &async_nats::Client,
+    request: async_nats::Message,
+    deadline: Duration,
+) {
+    let span = MessageContext::from_headers(request.headers.as_ref()).span();
+    async {
+        let outcome = match timeout(deadline, pipeline.process_request(&request, client)).await {
+            Ok(()) => "completed",
+            Err(_) => {
+                warn!("Request on {} ran past its deadline", request.subject);
+                Delivery::Request {
+                    message: &request,
+                    client,
+                }
+                .fail(504, "deadline exceeded")
+                .await;
+                "deadline_exceeded"
+            }
+        };
+        pipeline
+            .metrics
+            .requests
+            .with_label_values(&[outcome])
+            .inc();
+    }
+    .instrument(span)
+    .await
+}
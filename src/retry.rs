@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Redelivery of messages that failed transiently
+//!
+//! A failure that may pass on its own, like an inference deadline, an
+//! unreachable knowledge graph or a saturated rule engine, hands the message
+//! back with a NAK whose delay doubles with every delivery, so a struggling
+//! dependency is not hammered by immediate redeliveries. A permanent failure,
+//! like a payload that does not decode, is answered and acknowledged instead:
+//! no redelivery would change its outcome. A message still failing after its
+//! last delivery is terminated so it stops occupying the consumer.
+//!
+//! Inputs of other transports follow the same backoff, handed back through
+//! [`Received::redeliver`].
+
+use async_nats::jetstream::{message::Message as JetStreamMessage, AckKind};
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::metrics::Metrics;
+use crate::transport::Received;
+
+/// Backoff settings of transient failures
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryConfig {
+    /// Redelivery delay after the first delivery failed
+    pub initial: Duration,
+    /// Longest redelivery delay
+    pub max: Duration,
+    /// Deliveries before a message is given up on
+    pub max_deliveries: u64,
+    /// Retry messages whose source lookup failed instead of deciding
+    /// without the knowledge graph, until their last delivery
+    pub graph: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            max_deliveries: 8,
+            graph: true,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Redelivery delay after delivery number `delivered` failed
+    pub fn delay(&self, delivered: u64) -> Duration {
+        let doublings = delivered.saturating_sub(1).min(31) as u32;
+        self.initial.saturating_mul(1 << doublings).min(self.max)
+    }
+
+    /// Whether delivery number `delivered` is the last one
+    pub fn last_delivery(&self, delivered: u64) -> bool {
+        delivered >= self.max_deliveries
+    }
+}
+
+/// How many times `msg` has been delivered, this delivery included
+pub fn deliveries(msg: &JetStreamMessage) -> u64 {
+    msg.info()
+        .map(|info| info.delivered.max(1) as u64)
+        .unwrap_or(1)
+}
+
+/// Hand `msg` back after a transient failure, or terminate it when this
+/// was its last delivery
+pub async fn retry(config: &RetryConfig, msg: &JetStreamMessage, reason: &str, metrics: &Metrics) {
+    let delivered = deliveries(msg);
+    if config.last_delivery(delivered) {
+        error!(
+            "Giving up on message after {} deliveries: {}",
+            delivered, reason
+        );
+        metrics
+            .transient_failures
+            .with_label_values(&[reason, "terminated"])
+            .inc();
+        let _ = msg.ack_with(AckKind::Term).await;
+        return;
+    }
+    let delay = config.delay(delivered);
+    warn!(
+        "Redelivering message in {:?} (delivery {}): {}",
+        delay, delivered, reason
+    );
+    metrics
+        .transient_failures
+        .with_label_values(&[reason, "redelivered"])
+        .inc();
+    let _ = msg.ack_with(AckKind::Nak(Some(delay))).await;
+}
+
+/// [`retry`] for an input of another transport; an input given up on is
+/// dropped
+pub async fn retry_received(
+    config: &RetryConfig,
+    input: &dyn Received,
+    reason: &str,
+    metrics: &Metrics,
+) {
+    let delivered = input.deliveries();
+    if config.last_delivery(delivered) {
+        error!(
+            "Giving up on {} after {} deliveries: {}",
+            input.id(),
+            delivered,
+            reason
+        );
+        metrics
+            .transient_failures
+            .with_label_values(&[reason, "terminated"])
+            .inc();
+        return;
+    }
+    let delay = config.delay(delivered);
+    warn!(
+        "Redelivering {} in {:?} (delivery {}): {}",
+        input.id(),
+        delay,
+        delivered,
+        reason
+    );
+    match input.redeliver(delay).await {
+        Ok(()) => metrics
+            .transient_failures
+            .with_label_values(&[reason, "redelivered"])
+            .inc(),
+        Err(e) => {
+            error!("Redelivering {} failed: {:#}", input.id(), e);
+            metrics
+                .transient_failures
+                .with_label_values(&[reason, "terminated"])
+                .inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_doubles_up_to_the_maximum() {
+        let config = RetryConfig {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(5),
+            ..Default::default()
+        };
+        assert_eq!(config.delay(1), Duration::from_millis(500));
+        assert_eq!(config.delay(2), Duration::from_secs(1));
+        assert_eq!(config.delay(4), Duration::from_secs(4));
+        assert_eq!(config.delay(5), Duration::from_secs(5));
+        assert_eq!(config.delay(1000), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_last_delivery() {
+        let config = RetryConfig {
+            max_deliveries: 3,
+            ..Default::default()
+        };
+        assert!(!config.last_delivery(2));
+        assert!(config.last_delivery(3));
+        assert!(config.last_delivery(4));
+    }
+}
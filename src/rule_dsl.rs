@@ -0,0 +1,335 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Rules written as YAML conditions, compiled to Datalog
+//!
+//! Analysts who do not write Soufflé can author a rule pack as a
+//! `*.rules.yaml` file in `NSAI_RULES_DIR`. Each rule lists conditions on
+//! feature levels and facts and what follows when all of them hold; the
+//! pack is compiled to Datalog when the rules are loaded and checked like
+//! any other pack:
+//!
+//! ```yaml
+//! inputs:
+//!   stance_vaccines: symbol
+//! rules:
+//!   - id: vaccine_denial
+//!     description: Denying the vaccine claim from an untrusted source
+//!     when:
+//!       stance_vaccines: denies
+//!       fakeness: [medium, high]
+//!       source_trusted: not true
+//!       source_disinfo_30d: ">= 3"
+//!     then:
+//!       disinfo: true
+//!       label: VACCINE_DENIAL
+//!       weight: 0.4
+//! ```
+//!
+//! A condition is a value, `not <value>`, a list of values any of which
+//! holds, or a comparison (`>= 3`, `< 30`, `= 0`, `!= 0`) of a number
+//! relation. `then` derives `disinfo()`, a `label` and a weighted
+//! `contribution`, in any combination; every rule also reports the
+//! premises that held in `fired`. `inputs` declares the relations no other
+//! pack declares, e.g. a stance topic.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    inputs: BTreeMap<String, InputType>,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum InputType {
+    Symbol,
+    Number,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    id: String,
+    #[serde(default)]
+    description: Option<String>,
+    when: BTreeMap<String, Value>,
+    then: Then,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Then {
+    #[serde(default)]
+    disinfo: bool,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    weight: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Is(String),
+    Not(String),
+    AnyOf(Vec<String>),
+    Compare(&'static str, i64),
+}
+
+impl Condition {
+    fn parse(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) => {
+                let s = s.trim();
+                for op in [">=", "<=", "!=", ">", "<", "="] {
+                    if let Some(number) = s.strip_prefix(op) {
+                        let number = number
+                            .trim()
+                            .parse()
+                            .with_context(|| format!("expected a whole number after {}", op))?;
+                        return Ok(Self::Compare(op, number));
+                    }
+                }
+                match s.strip_prefix("not ") {
+                    Some(negated) => Ok(Self::Not(symbol(negated.trim())?)),
+                    None => Ok(Self::Is(symbol(s)?)),
+                }
+            }
+            Value::Bool(b) => Ok(Self::Is(b.to_string())),
+            Value::Number(n) => match n.as_i64() {
+                Some(n) => Ok(Self::Compare("=", n)),
+                None => bail!("expected a whole number: {}", n),
+            },
+            Value::Sequence(values) if !values.is_empty() => values
+                .iter()
+                .map(|value| match value {
+                    Value::String(s) => symbol(s.trim()),
+                    Value::Bool(b) => Ok(b.to_string()),
+                    other => bail!("expected a value: {:?}", other),
+                })
+                .collect::<Result<_>>()
+                .map(Self::AnyOf),
+            other => bail!("expected a value, a list or a comparison: {:?}", other),
+        }
+    }
+
+    /// Body atoms for `relation`, binding `var` if needed, and the premise
+    /// expression reported in `fired`
+    fn compile(&self, relation: &str, var: &str) -> (String, String) {
+        match self {
+            Self::Is(value) => (
+                format!("{}(\"{}\")", relation, value),
+                quote(&format!("{}(\"{}\")", relation, value)),
+            ),
+            Self::Not(value) => (
+                format!("!{}(\"{}\")", relation, value),
+                quote(&format!("!{}(\"{}\")", relation, value)),
+            ),
+            Self::AnyOf(values) => {
+                let alternatives: Vec<String> = values
+                    .iter()
+                    .map(|value| format!("{} = \"{}\"", var, value))
+                    .collect();
+                (
+                    format!("{}({}), ({})", relation, var, alternatives.join(" ; ")),
+                    format!("cat(\"{}(\\\"\", {}, \"\\\")\")", relation, var),
+                )
+            }
+            Self::Compare(op, number) => (
+                format!("{}({}), {} {} {}", relation, var, var, op, number),
+                format!("cat(\"{}(\", to_string({}), \")\")", relation, var),
+            ),
+        }
+    }
+}
+
+/// Compile a `*.rules.yaml` pack to Datalog
+pub fn compile(source: &str) -> Result<String> {
+    let file: RuleFile = serde_yaml::from_str(source)?;
+    if file.rules.is_empty() {
+        bail!("no rules");
+    }
+
+    let mut program = String::new();
+    for (relation, kind) in &file.inputs {
+        identifier(relation)?;
+        let column = match kind {
+            InputType::Symbol => "value: symbol",
+            InputType::Number => "count: number",
+        };
+        program.push_str(&format!(
+            ".decl {}({})\n.input {}\n",
+            relation, column, relation
+        ));
+    }
+
+    let mut ids = HashSet::new();
+    for rule in &file.rules {
+        compile_rule(rule, &mut program).with_context(|| format!("rule {}", rule.id))?;
+        if !ids.insert(rule.id.as_str()) {
+            bail!("duplicate rule id {}", rule.id);
+        }
+    }
+    Ok(program)
+}
+
+fn compile_rule(rule: &Rule, program: &mut String) -> Result<()> {
+    if rule.id.is_empty()
+        || !rule
+            .id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    {
+        bail!("rule ids are letters, digits, _ and :");
+    }
+    if rule.when.is_empty() {
+        bail!("no conditions");
+    }
+    let mut body = Vec::new();
+    let mut premises = Vec::new();
+    for (i, (relation, value)) in rule.when.iter().enumerate() {
+        identifier(relation)?;
+        let condition =
+            Condition::parse(value).with_context(|| format!("condition {}", relation))?;
+        let (atoms, premise) = condition.compile(relation, &format!("v{}", i));
+        body.push(atoms);
+        premises.push(premise);
+    }
+    let body = body.join(", ");
+
+    let mut heads = Vec::new();
+    if rule.then.disinfo {
+        heads.push("disinfo()".to_string());
+    }
+    if let Some(label) = &rule.then.label {
+        heads.push(format!("label(\"{}\")", symbol(label)?));
+    }
+    if let Some(weight) = rule.then.weight {
+        if !(weight > 0.0 && weight <= 1.0) {
+            bail!("weight must be in (0, 1]: {}", weight);
+        }
+        heads.push(format!("contribution(\"{}\", {:?})", rule.id, weight));
+    }
+    if heads.is_empty() {
+        bail!("then derives nothing, expected disinfo, label or weight");
+    }
+
+    program.push('\n');
+    if let Some(description) = &rule.description {
+        for line in description.lines() {
+            program.push_str(&format!("// {}\n", line));
+        }
+    }
+    for head in heads {
+        program.push_str(&format!("{} :- {}.\n", head, body));
+    }
+    for premise in premises {
+        program.push_str(&format!(
+            "fired(\"{}\", {}) :- {}.\n",
+            rule.id, premise, body
+        ));
+    }
+    Ok(())
+}
+
+fn identifier(relation: &str) -> Result<()> {
+    let valid = relation
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && relation
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("invalid relation name {:?}", relation);
+    }
+    Ok(())
+}
+
+/// A symbol value, which may not break out of its string literal
+fn symbol(value: &str) -> Result<String> {
+    if value.is_empty() || value.contains(['"', '\\', '\n']) {
+        bail!("invalid value {:?}", value);
+    }
+    Ok(value.to_string())
+}
+
+/// `text` as a Soufflé string literal
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACK: &str = "\
+inputs:
+  stance_vaccines: symbol
+rules:
+  - id: vaccine_denial
+    description: Denying the vaccine claim from an untrusted source
+    when:
+      stance_vaccines: denies
+      fakeness: [medium, high]
+      source_trusted: not true
+      source_disinfo_30d: '>= 3'
+    then:
+      disinfo: true
+      weight: 0.4
+";
+
+    #[test]
+    fn test_rules_compile_to_datalog() {
+        let program = compile(PACK).unwrap();
+        let body = "fakeness(v0), (v0 = \"medium\" ; v0 = \"high\"), \
+                    source_disinfo_30d(v1), v1 >= 3, \
+                    !source_trusted(\"true\"), stance_vaccines(\"denies\")";
+        assert!(
+            program.starts_with(".decl stance_vaccines(value: symbol)\n.input stance_vaccines\n")
+        );
+        assert!(program.contains("// Denying the vaccine claim from an untrusted source\n"));
+        assert!(program.contains(&format!("disinfo() :- {}.\n", body)));
+        assert!(program.contains(&format!(
+            "contribution(\"vaccine_denial\", 0.4) :- {}.\n",
+            body
+        )));
+        assert!(program.contains(&format!(
+            "fired(\"vaccine_denial\", \"!source_trusted(\\\"true\\\")\") :- {}.\n",
+            body
+        )));
+        assert!(program.contains(
+            "fired(\"vaccine_denial\", cat(\"source_disinfo_30d(\", to_string(v1), \")\"))"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        let rule = |when: &str, then: &str| {
+            compile(&format!(
+                "rules:\n  - id: r\n    when: {{ {} }}\n    then: {{ {} }}\n",
+                when, then
+            ))
+        };
+        assert!(rule("fakeness: high", "label: HIGH").is_ok());
+        assert!(rule("", "disinfo: true").is_err());
+        assert!(rule("fakeness: high", "").is_err());
+        assert!(rule("fakeness: 'high\" ), x(\"'", "disinfo: true").is_err());
+        assert!(rule("'bad-relation': high", "disinfo: true").is_err());
+        assert!(rule("source_disinfo_7d: '>= 2.5'", "disinfo: true").is_err());
+        assert!(rule("fakeness: high", "weight: 1.5").is_err());
+        assert!(rule("fakeness: high", "verdict: SAFE").is_err());
+        assert!(compile("rules: []\n").is_err());
+        assert!(compile(
+            "rules:\n  - { id: r, when: { a: x }, then: { disinfo: true } }\n  \
+             - { id: r, when: { b: x }, then: { disinfo: true } }\n"
+        )
+        .is_err());
+    }
+}
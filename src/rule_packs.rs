@@ -0,0 +1,641 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Rule packs loaded from a rules directory
+//!
+//! With `NSAI_RULES_DIR` set, every `*.dl` file in the directory is a rule
+//! pack and the program the Soufflé backends run is the packs concatenated
+//! in file name order, e.g. `00-detector.dl` followed by `50-vaccines.dl`.
+//! Packs are checked at startup, so a pack that uses an undeclared relation
+//! or redeclares one of another pack stops the service instead of failing
+//! every message.
+//!
+//! A pack named `*.shadow.dl`, e.g. `60-strict.shadow.dl`, is a shadow pack:
+//! it is left out of the program and evaluated as the program plus that pack
+//! next to it, so its would-be verdicts can be compared before it goes live.
+//!
+//! A `*.rules.yaml` file is a pack written in the rule format of
+//! [`crate::rule_dsl`], compiled to Datalog on load and then treated like a
+//! `.dl` pack of the same name; `*.shadow.rules.yaml` is a shadow pack.
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::rule_dsl;
+use crate::souffle_sandbox::SouffleSandbox;
+use crate::souffle_wrapper::{directive_relations, SymbolicBackend};
+
+/// Tells apart the partial copies [`snapshot`] writes
+static SNAPSHOT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Soufflé functors, which look like atoms but are not relations
+const FUNCTORS: &[&str] = &[
+    "cat",
+    "contains",
+    "match",
+    "max",
+    "min",
+    "ord",
+    "range",
+    "strlen",
+    "substr",
+    "to_float",
+    "to_number",
+    "to_string",
+    "to_unsigned",
+];
+
+/// Suffix of the file stem of shadow packs
+const SHADOW_SUFFIX: &str = ".shadow";
+
+/// Suffix of the file stem of packs in the YAML rule format
+const RULES_SUFFIX: &str = ".rules";
+
+/// One `.dl` or `.rules.yaml` file of the rules directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct RulePack {
+    /// File name without the extension, and without `.shadow` for shadow
+    /// packs
+    pub name: String,
+    /// Lowercase hex SHA-256 of the file
+    pub sha256: String,
+    /// Whether the pack only runs in the shadow of the program
+    pub shadow: bool,
+    /// Whether the pack was compiled from the YAML rule format
+    pub compiled: bool,
+    /// Datalog of the pack
+    source: String,
+}
+
+impl RulePack {
+    pub fn file_name(&self) -> String {
+        format!(
+            "{}{}{}",
+            self.name,
+            if self.shadow { SHADOW_SUFFIX } else { "" },
+            if self.compiled { ".rules.yaml" } else { ".dl" }
+        )
+    }
+}
+
+/// Validated rule packs in evaluation order
+#[derive(Debug, Clone, PartialEq)]
+pub struct RulePacks {
+    packs: Vec<RulePack>,
+    shadow: Vec<RulePack>,
+}
+
+impl RulePacks {
+    /// Load and validate every `*.dl` and `*.rules.yaml` file in `dir`
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut paths = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read rules directory {}", dir.display()))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        paths.retain(|path| {
+            path.extension().is_some_and(|ext| ext == "dl")
+                || path
+                    .to_str()
+                    .is_some_and(|path| path.ends_with(&format!("{}.yaml", RULES_SUFFIX)))
+        });
+        paths.sort();
+
+        let packs = paths
+            .iter()
+            .map(|path| {
+                let source = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let name = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                Ok((name, source))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::parse(packs).with_context(|| format!("Invalid rule packs in {}", dir.display()))
+    }
+
+    /// Validate `(name, contents)` packs in evaluation order
+    ///
+    /// Packs named `*.rules` are compiled from the YAML rule format first.
+    /// Shadow packs, named `*.shadow`, are validated one by one together
+    /// with the other packs.
+    pub fn parse(files: Vec<(String, String)>) -> Result<Self> {
+        let mut packs = Vec::new();
+        let mut shadow = Vec::new();
+        for (name, contents) in files {
+            let sha256 = hex::encode(Sha256::digest(&contents));
+            let (name, compiled, source) = match name.strip_suffix(RULES_SUFFIX) {
+                Some(stem) => {
+                    let source = rule_dsl::compile(&contents)
+                        .with_context(|| format!("{} does not compile", name))?;
+                    (stem.to_string(), true, source)
+                }
+                None => (name, false, contents),
+            };
+            let (name, is_shadow) = match name.strip_suffix(SHADOW_SUFFIX) {
+                Some(stem) => (stem.to_string(), true),
+                None => (name, false),
+            };
+            let pack = RulePack {
+                name,
+                sha256,
+                shadow: is_shadow,
+                compiled,
+                source,
+            };
+            match is_shadow {
+                true => shadow.push(pack),
+                false => packs.push(pack),
+            }
+        }
+        if packs.is_empty() {
+            bail!("no .dl rule packs");
+        }
+
+        let primary: Vec<&RulePack> = packs.iter().collect();
+        validate(&primary)?;
+        for pack in &shadow {
+            let mut combined = primary.clone();
+            combined.push(pack);
+            validate(&combined)?;
+        }
+        Ok(Self { packs, shadow })
+    }
+
+    pub fn packs(&self) -> &[RulePack] {
+        &self.packs
+    }
+
+    /// Shadow packs, in file name order
+    pub fn shadow(&self) -> &[RulePack] {
+        &self.shadow
+    }
+
+    /// The packs as one Soufflé program
+    pub fn program(&self) -> String {
+        let mut program = String::new();
+        for pack in &self.packs {
+            push_pack(&mut program, pack);
+        }
+        program
+    }
+
+    /// Lowercase hex SHA-256 of [`Self::program`]
+    pub fn sha256(&self) -> String {
+        hex::encode(Sha256::digest(self.program()))
+    }
+
+    /// Point a Soufflé backend at the combined program
+    ///
+    /// The program is written to the scratch directory of `sandbox`; a
+    /// prebuilt compiled backend's binary must have been built from the
+    /// same program.
+    pub fn apply(
+        &self,
+        backend: &SymbolicBackend,
+        sandbox: &SouffleSandbox,
+    ) -> Result<SymbolicBackend> {
+        match backend {
+            SymbolicBackend::Embedded | SymbolicBackend::Ascent => {
+                bail!("rule packs need NSAI_SYMBOLIC_BACKEND=souffle or compiled")
+            }
+            _ => snapshot(backend, &self.program(), sandbox),
+        }
+    }
+
+    /// Point a Soufflé backend at the program plus each shadow pack
+    ///
+    /// A prebuilt compiled backend has no binary for these programs, so
+    /// shadow packs need the interpreter or a build on load.
+    pub fn apply_shadow(
+        &self,
+        backend: &SymbolicBackend,
+        sandbox: &SouffleSandbox,
+    ) -> Result<Vec<(String, SymbolicBackend)>> {
+        if self.shadow.is_empty() {
+            return Ok(Vec::new());
+        }
+        if !matches!(
+            backend,
+            SymbolicBackend::Souffle { .. } | SymbolicBackend::CompileOnLoad { .. }
+        ) {
+            bail!("shadow rule packs need NSAI_SYMBOLIC_BACKEND=souffle, or compiled without NSAI_SOUFFLE_COMPILED");
+        }
+        self.shadow
+            .iter()
+            .map(|pack| {
+                let mut program = self.program();
+                push_pack(&mut program, pack);
+                Ok((pack.name.clone(), snapshot(backend, &program, sandbox)?))
+            })
+            .collect()
+    }
+}
+
+fn push_pack(program: &mut String, pack: &RulePack) {
+    program.push_str(&format!("// pack {} ({})\n", pack.name, &pack.sha256[..12]));
+    program.push_str(&pack.source);
+    if !pack.source.ends_with('\n') {
+        program.push('\n');
+    }
+}
+
+/// Check packs form one valid program
+fn validate(packs: &[&RulePack]) -> Result<()> {
+    let stripped: Vec<String> = packs
+        .iter()
+        .map(|pack| strip_comments(&pack.source))
+        .collect();
+
+    let mut declared: HashMap<String, &str> = HashMap::new();
+    for (pack, source) in packs.iter().zip(&stripped) {
+        let name = pack.name.as_str();
+        for relation in declarations(source) {
+            if let Some(other) = declared.insert(relation.clone(), name) {
+                bail!(
+                    "relation {} declared in both {} and {}",
+                    relation,
+                    other,
+                    name
+                );
+            }
+        }
+    }
+
+    let mut outputs_verdict = false;
+    for (pack, source) in packs.iter().zip(&stripped) {
+        let name = &pack.name;
+        let outputs = directive_relations(source, ".output");
+        outputs_verdict |= outputs.iter().any(|relation| relation == "verdict");
+        let used = directive_relations(source, ".input")
+            .into_iter()
+            .chain(outputs)
+            .chain(atoms(source));
+        for relation in used {
+            if !declared.contains_key(&relation) {
+                bail!("{} uses undeclared relation {}", name, relation);
+            }
+        }
+    }
+    if !outputs_verdict {
+        bail!("no pack outputs the verdict relation");
+    }
+
+    Ok(())
+}
+
+/// Point a Soufflé backend at a copy of `program` in the sandbox's
+/// private scratch directory
+///
+/// Copies are named by content hash and never modified, so a run that
+/// started on one program finishes on it even if the rules change. An
+/// existing copy is reused only if it still holds `program`. The
+/// in-process backends have no program and are returned as they are.
+pub fn snapshot(
+    backend: &SymbolicBackend,
+    program: &str,
+    sandbox: &SouffleSandbox,
+) -> Result<SymbolicBackend> {
+    let copy = || -> Result<PathBuf> {
+        let sha256 = hex::encode(Sha256::digest(program));
+        let path = sandbox
+            .scratch_root()?
+            .join(format!("nsai-rules-{}.dl", &sha256[..12]));
+        if fs::read(&path).is_ok_and(|existing| existing == program.as_bytes()) {
+            return Ok(path);
+        }
+        let partial = path.with_extension(format!(
+            "{}-{}.tmp",
+            std::process::id(),
+            SNAPSHOT_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+            .and_then(|mut file| file.write_all(program.as_bytes()))
+            .and_then(|()| fs::rename(&partial, &path))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&partial);
+            })
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    };
+    match backend {
+        SymbolicBackend::Souffle { souffle, .. } => Ok(SymbolicBackend::Souffle {
+            souffle: souffle.clone(),
+            program: copy()?,
+        }),
+        SymbolicBackend::Compiled { binary, .. } => Ok(SymbolicBackend::Compiled {
+            binary: binary.clone(),
+            program: copy()?,
+        }),
+        SymbolicBackend::CompileOnLoad { souffle, .. } => Ok(SymbolicBackend::CompileOnLoad {
+            souffle: souffle.clone(),
+            program: copy()?,
+        }),
+        SymbolicBackend::Embedded | SymbolicBackend::Ascent => Ok(backend.clone()),
+    }
+}
+
+/// Remove `//` and `/* */` comments, keeping line breaks and string literals
+fn strip_comments(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            if c == '\\' {
+                out.extend(chars.next());
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) => while chars.next_if(|&next| next != '\n').is_some() {},
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for next in chars.by_ref() {
+                    if previous == '*' && next == '/' {
+                        break;
+                    }
+                    if next == '\n' {
+                        out.push('\n');
+                    }
+                    previous = next;
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Relations declared with `.decl`
+fn declarations(source: &str) -> Vec<String> {
+    source
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(".decl"))
+        .map(|rest| {
+            rest.trim_start()
+                .chars()
+                .take_while(|&c| c.is_ascii_alphanumeric() || c == '_')
+                .collect::<String>()
+        })
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Relations used as atoms in the rules, i.e. outside directives
+fn atoms(source: &str) -> Vec<String> {
+    let mut atoms = Vec::new();
+    for line in source.lines().filter(|l| !l.trim_start().starts_with('.')) {
+        let mut chars = line.chars().peekable();
+        let mut previous = ' ';
+        let mut in_string = false;
+        while let Some(c) = chars.next() {
+            if in_string {
+                if c == '\\' {
+                    chars.next();
+                } else if c == '"' {
+                    in_string = false;
+                }
+            } else if c == '"' {
+                in_string = true;
+            } else if (c.is_ascii_alphabetic() || c == '_') && !is_ident(previous) {
+                let mut ident = c.to_string();
+                while let Some(next) = chars.next_if(|&n| is_ident(n)) {
+                    ident.push(next);
+                }
+                while chars.next_if(|n| n.is_whitespace()).is_some() {}
+                let user_functor = previous == '@' || previous == '$';
+                if chars.peek() == Some(&'(')
+                    && !user_functor
+                    && !FUNCTORS.contains(&ident.as_str())
+                {
+                    atoms.push(ident);
+                }
+                previous = 'a';
+                continue;
+            }
+            previous = c;
+        }
+    }
+    atoms
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    const DETECTOR: &str = include_str!("../rules/detector.dl");
+
+    fn packs(extra: &str) -> Result<RulePacks> {
+        RulePacks::parse(vec![
+            ("00-detector".to_string(), DETECTOR.to_string()),
+            ("50-vaccines".to_string(), extra.to_string()),
+        ])
+    }
+
+    fn sandbox(dir: &TempDir) -> SouffleSandbox {
+        SouffleSandbox {
+            scratch_dir: dir.join("scratch"),
+            ..SouffleSandbox::default()
+        }
+    }
+
+    #[test]
+    fn test_packs_extend_the_detector() {
+        let packs = packs(
+            "// Denying the vaccine claim from an untrusted source\n\
+             .decl stance_vaccines(level: symbol)\n\
+             .input stance_vaccines\n\
+             disinfo() :- stance_vaccines(\"denies\"), untrusted_source(), \
+             strlen(\"x(y)\") > 0.\n",
+        )
+        .unwrap();
+        let names: Vec<&str> = packs.packs().iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["00-detector", "50-vaccines"]);
+
+        let program = packs.program();
+        assert!(
+            program.find(".decl verdict").unwrap() < program.find("// pack 50-vaccines").unwrap()
+        );
+        assert!(program.contains(&format!(
+            "// pack 50-vaccines ({})",
+            &packs.packs()[1].sha256[..12]
+        )));
+        assert_eq!(packs.sha256().len(), 64);
+    }
+
+    #[test]
+    fn test_invalid_packs_are_rejected() {
+        let undeclared = packs("disinfo() :- stance_climate(\"denies\").\n").unwrap_err();
+        assert!(undeclared
+            .to_string()
+            .contains("undeclared relation stance_climate"));
+
+        let redeclared = packs(".decl disinfo()\n").unwrap_err();
+        assert!(redeclared
+            .to_string()
+            .contains("disinfo declared in both 00-detector and 50-vaccines"));
+
+        // Commented out atoms and directives do not count
+        assert!(packs("/* .output nothing\n nothing() :- x(). */\n// y().\n").is_ok());
+
+        let no_verdict = RulePacks::parse(vec![(
+            "only".to_string(),
+            ".decl a()\n.output a\n".to_string(),
+        )]);
+        assert!(no_verdict.is_err());
+        assert!(RulePacks::parse(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_apply_needs_a_souffle_backend() {
+        let packs = packs("").unwrap();
+        let scratch = TempDir::new("rule-packs");
+        let sandbox = sandbox(&scratch);
+        assert!(packs.apply(&SymbolicBackend::Embedded, &sandbox).is_err());
+
+        let backend = SymbolicBackend::Souffle {
+            souffle: PathBuf::from("souffle"),
+            program: PathBuf::from("rules/detector.dl"),
+        };
+        let SymbolicBackend::Souffle { program, .. } = packs.apply(&backend, &sandbox).unwrap()
+        else {
+            panic!("backend changed kind");
+        };
+        assert_eq!(fs::read_to_string(&program).unwrap(), packs.program());
+        assert!(program.starts_with(&sandbox.scratch_dir));
+    }
+
+    #[test]
+    fn test_tampered_snapshot_is_rewritten() {
+        let scratch = TempDir::new("rule-packs");
+        let sandbox = sandbox(&scratch);
+        let backend = SymbolicBackend::Souffle {
+            souffle: PathBuf::from("souffle"),
+            program: PathBuf::from("rules/detector.dl"),
+        };
+        let source = "// tampered snapshot\n";
+        let SymbolicBackend::Souffle { program, .. } =
+            snapshot(&backend, source, &sandbox).unwrap()
+        else {
+            panic!("backend changed kind");
+        };
+        fs::write(&program, "verdict(\"trusted\").\n").unwrap();
+
+        let SymbolicBackend::Souffle { program: again, .. } =
+            snapshot(&backend, source, &sandbox).unwrap()
+        else {
+            panic!("backend changed kind");
+        };
+        assert_eq!(again, program);
+        assert_eq!(fs::read_to_string(&program).unwrap(), source);
+    }
+
+    #[test]
+    fn test_shadow_packs_stay_out_of_the_program() {
+        let packs = RulePacks::parse(vec![
+            ("00-detector".to_string(), DETECTOR.to_string()),
+            (
+                "60-strict.shadow".to_string(),
+                "disinfo() :- fakeness(\"medium\").\n".to_string(),
+            ),
+        ])
+        .unwrap();
+        assert_eq!(packs.packs().len(), 1);
+        assert_eq!(packs.shadow()[0].name, "60-strict");
+        assert_eq!(packs.shadow()[0].file_name(), "60-strict.shadow.dl");
+        assert!(!packs.program().contains("60-strict"));
+
+        let backend = SymbolicBackend::Souffle {
+            souffle: PathBuf::from("souffle"),
+            program: PathBuf::from("rules/detector.dl"),
+        };
+        let scratch = TempDir::new("rule-packs");
+        let shadow = packs.apply_shadow(&backend, &sandbox(&scratch)).unwrap();
+        let [(name, SymbolicBackend::Souffle { program, .. })] = shadow.as_slice() else {
+            panic!("expected one interpreted shadow backend");
+        };
+        assert_eq!(name, "60-strict");
+        let source = fs::read_to_string(program).unwrap();
+        assert!(source.starts_with(&packs.program()));
+        assert!(source.contains("// pack 60-strict"));
+
+        let prebuilt = SymbolicBackend::Compiled {
+            binary: PathBuf::from("nsai-rules"),
+            program: PathBuf::from("rules/detector.dl"),
+        };
+        assert!(packs.apply_shadow(&prebuilt, &sandbox(&scratch)).is_err());
+
+        // A shadow pack is checked against the other packs
+        assert!(RulePacks::parse(vec![
+            ("00-detector".to_string(), DETECTOR.to_string()),
+            (
+                "60-strict.shadow".to_string(),
+                ".decl disinfo()\n".to_string()
+            ),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_yaml_packs_are_compiled() {
+        let rules = "\
+inputs:
+  stance_vaccines: symbol
+rules:
+  - id: vaccine_denial
+    when: { stance_vaccines: denies, source_disinfo_30d: '>= 3' }
+    then: { disinfo: true, weight: 0.4 }
+";
+        let packs = RulePacks::parse(vec![
+            ("00-detector".to_string(), DETECTOR.to_string()),
+            ("50-vaccines.rules".to_string(), rules.to_string()),
+        ])
+        .unwrap();
+        let pack = &packs.packs()[1];
+        assert_eq!(pack.name, "50-vaccines");
+        assert_eq!(pack.file_name(), "50-vaccines.rules.yaml");
+        assert_eq!(pack.sha256, hex::encode(Sha256::digest(rules)));
+        assert!(packs.program().contains(
+            "disinfo() :- source_disinfo_30d(v0), v0 >= 3, stance_vaccines(\"denies\")."
+        ));
+
+        // Compiled packs are checked like any other
+        let undeclared = RulePacks::parse(vec![
+            ("00-detector".to_string(), DETECTOR.to_string()),
+            (
+                "50-climate.rules".to_string(),
+                "rules:\n  - { id: c, when: { stance_climate: denies }, then: { disinfo: true } }\n"
+                    .to_string(),
+            ),
+        ])
+        .unwrap_err();
+        assert!(undeclared
+            .to_string()
+            .contains("undeclared relation stance_climate"));
+    }
+}
@@ -0,0 +1,274 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Unit tests for the rules
+//!
+//! `nsai-detector validate-rules` runs every `*.yaml` file of a directory
+//! through the configured rules and reports which cases pass, so a rule
+//! change can be checked without a NATS deployment. A file holds a list of
+//! cases:
+//!
+//! ```yaml
+//! - name: high fakeness from an untrusted source
+//!   scores: { fakeness: 0.9 }
+//!   facts: ['source_trusted("false")']
+//!   expect: DISINFO
+//!   fired: [disinfo]
+//! ```
+//!
+//! `fired` and `labels` name rules that must fire and labels that must be
+//! derived, among others. Scores are discretized with the configured bins,
+//! including the overrides of the case's `language` and `tenant`; facts are
+//! passed to the rules as they are.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::fact_mapping::{discretize, Fact, NEURAL_BINS};
+use crate::reasoning::ReasoningEngine;
+use crate::souffle_wrapper::Verdict;
+use crate::thresholds::Thresholds;
+
+/// Given facts and scores, the verdict the rules must derive
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleCase {
+    pub name: String,
+    /// Model scores by fact relation, e.g. `fakeness: 0.9`
+    #[serde(default)]
+    pub scores: BTreeMap<String, f32>,
+    /// Base facts, e.g. `source_trusted("false")`
+    #[serde(default)]
+    pub facts: Vec<String>,
+    /// Detected language, also passed to the rules as `language(code)`
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Tenant whose bin overrides apply
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub expect: Verdict,
+    /// Rule identifiers that must fire, e.g. `disinfo`
+    #[serde(default)]
+    pub fired: Vec<String>,
+    /// Labels the rules must derive, e.g. `AI_GENERATED`
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl RuleCase {
+    /// The base facts the case describes
+    fn base_facts(&self, thresholds: &Thresholds) -> Result<Vec<Fact>> {
+        let language = self.language.as_deref().unwrap_or("und");
+        let bins = thresholds.resolve(language, self.tenant.as_deref().unwrap_or(""));
+        let mut facts = Vec::new();
+        for (relation, score) in &self.scores {
+            let Some(spec) = NEURAL_BINS.iter().find(|s| s.relation == relation.as_str()) else {
+                bail!("no model score maps to relation {}", relation);
+            };
+            let Some(level) = discretize(*score, spec.edges(&bins), spec.levels) else {
+                bail!("score of {} is not a number", relation);
+            };
+            facts.push(Fact::new(relation.as_str(), vec![level.to_string()]));
+        }
+        for fact in &self.facts {
+            facts.push(
+                fact.parse()
+                    .with_context(|| format!("invalid fact {:?}", fact))?,
+            );
+        }
+        if let Some(language) = &self.language {
+            facts.push(Fact::new("language", vec![language.clone()]));
+        }
+        Ok(facts)
+    }
+}
+
+/// Result of one case
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CaseOutcome {
+    pub file: String,
+    pub name: String,
+    /// Why the case failed; `None` when it passed
+    pub failure: Option<String>,
+}
+
+/// Outcomes of every case, in file and case order
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub cases: Vec<CaseOutcome>,
+}
+
+impl Report {
+    pub fn failed(&self) -> usize {
+        self.cases.iter().filter(|c| c.failure.is_some()).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for case in &self.cases {
+            match &case.failure {
+                None => writeln!(f, "PASS {}: {}", case.file, case.name)?,
+                Some(failure) => writeln!(f, "FAIL {}: {}: {}", case.file, case.name, failure)?,
+            }
+        }
+        writeln!(
+            f,
+            "{} passed, {} failed",
+            self.cases.len() - self.failed(),
+            self.failed()
+        )
+    }
+}
+
+/// Run every case in the `*.yaml` files of `dir`
+///
+/// A file that cannot be read or parsed is an error; a case whose facts
+/// are invalid or whose evaluation fails counts as failed.
+pub async fn run(
+    dir: &Path,
+    engine: &dyn ReasoningEngine,
+    thresholds: &Thresholds,
+) -> Result<Report> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read rule tests in {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    paths.retain(|path| {
+        path.extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml")
+    });
+    paths.sort();
+    if paths.is_empty() {
+        bail!("no .yaml rule tests in {}", dir.display());
+    }
+
+    let mut cases = Vec::new();
+    for path in &paths {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let file_cases: Vec<RuleCase> = serde_yaml::from_str(&contents)
+            .with_context(|| format!("Invalid rule tests in {}", path.display()))?;
+        let file = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for case in file_cases {
+            cases.push(CaseOutcome {
+                file: file.clone(),
+                failure: check(&case, engine, thresholds).await.err(),
+                name: case.name,
+            });
+        }
+    }
+    Ok(Report { cases })
+}
+
+/// Evaluate one case, describing the mismatch if it fails
+async fn check(
+    case: &RuleCase,
+    engine: &dyn ReasoningEngine,
+    thresholds: &Thresholds,
+) -> std::result::Result<(), String> {
+    let facts = case
+        .base_facts(thresholds)
+        .map_err(|e| format!("{:#}", e))?;
+    let derivation = engine
+        .evaluate(&facts)
+        .await
+        .map_err(|e| format!("rules failed: {:#}", e))?;
+    if derivation.verdict != case.expect {
+        return Err(format!(
+            "expected {}, got {} ({})",
+            case.expect, derivation.verdict, derivation.explanation
+        ));
+    }
+    let missing: Vec<&str> = case
+        .fired
+        .iter()
+        .filter(|rule| !derivation.fired.iter().any(|f| &f.rule == *rule))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        let fired: Vec<&str> = derivation.fired.iter().map(|f| f.rule.as_str()).collect();
+        return Err(format!(
+            "rules {} did not fire (fired: {})",
+            missing.join(", "),
+            fired.join(", ")
+        ));
+    }
+    let labels = derivation.labels();
+    let missing: Vec<&str> = case
+        .labels
+        .iter()
+        .map(String::as_str)
+        .filter(|label| !labels.contains(label))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "labels {} not derived (derived: {})",
+            missing.join(", "),
+            labels.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reasoning::EmbeddedEngine;
+    use crate::test_support::TempDir;
+
+    #[tokio::test]
+    async fn test_bundled_rule_tests_pass() {
+        let report = run(
+            Path::new("rules/tests"),
+            &EmbeddedEngine,
+            &Thresholds::default(),
+        )
+        .await
+        .unwrap();
+        assert!(!report.cases.is_empty());
+        assert_eq!(report.failed(), 0, "{}", report);
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_reported() {
+        let dir = TempDir::new("rule-tests");
+        fs::write(
+            dir.join("cases.yaml"),
+            "- name: wrong verdict\n  scores: { fakeness: 0.9 }\n  expect: SAFE\n\
+             - name: missing rule\n  scores: { fakeness: 0.7 }\n  expect: SUSPICIOUS\n  fired: [disinfo]\n\
+             - name: bad fact\n  facts: ['source_trusted']\n  expect: SAFE\n\
+             - name: tenant thresholds\n  scores: { fakeness: 0.7 }\n  tenant: strict\n  expect: SAFE\n",
+        )
+        .unwrap();
+        let report = run(&dir, &EmbeddedEngine, &Thresholds::default())
+            .await
+            .unwrap();
+        let failures: Vec<&str> = report
+            .cases
+            .iter()
+            .map(|c| c.failure.as_deref().unwrap_or(""))
+            .collect();
+        assert!(failures[0].starts_with("expected SAFE, got DISINFO"));
+        assert!(failures[1].starts_with("rules disinfo did not fire"));
+        assert!(failures[2].contains("invalid fact"));
+        // Without a strict tenant override 0.7 is medium
+        assert!(failures[3].starts_with("expected SAFE, got SUSPICIOUS"));
+        assert_eq!(report.failed(), 4);
+        assert!(report.to_string().ends_with("0 passed, 4 failed\n"));
+
+        let bins_file = dir.join("bins.txt");
+        fs::write(&bins_file, "tenant strict: fakeness=0:0.75:0.9:1\n").unwrap();
+        let thresholds = Thresholds::load(&[], Some(&bins_file)).unwrap();
+        let report = run(&dir, &EmbeddedEngine, &thresholds).await.unwrap();
+        assert!(report.cases[3].failure.is_none());
+    }
+}
@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Versioned rule sets
+//!
+//! A [`RulesManifest`] names the rules that decided a verdict: the semantic
+//! version declared in `manifest.yaml` next to the rules, bumped by rule
+//! authors on every release, and the SHA-256 of the program and of each
+//! `.dl` file it was built from. Results are stamped with both, so a past
+//! verdict can be traced to the exact rules and re-run against them.
+//!
+//! ```yaml
+//! version: 1.2.0
+//! ```
+
+use anyhow::{Context, Result};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path};
+use tracing::warn;
+
+use crate::decision_context::ServiceContext;
+
+/// File declaring the version, in the rules directory or next to
+/// `NSAI_RULES`
+pub const MANIFEST_FILE: &str = "manifest.yaml";
+
+/// Manifest of the rules compiled into the service
+const BUILTIN: &str = include_str!("../rules/manifest.yaml");
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    version: Version,
+}
+
+/// One `.dl` file of the rules
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RuleFile {
+    pub name: String,
+    /// Lowercase hex SHA-256 of the file
+    pub sha256: String,
+}
+
+/// Version and content hashes of a rule set
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RulesManifest {
+    pub version: Version,
+    /// Lowercase hex SHA-256 of the program evaluated, i.e. the files
+    /// concatenated, or with rule sets per content type, of the default
+    /// set's hash and those of the sets
+    pub sha256: String,
+    /// Files in evaluation order, those of content type sets prefixed with
+    /// their directory, e.g. `image/`
+    pub files: Vec<RuleFile>,
+    /// Shadow packs, which do not decide verdicts
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shadow: Vec<RuleFile>,
+}
+
+impl RulesManifest {
+    /// Manifest of rules read from files, declared in `dir`'s
+    /// `manifest.yaml`
+    ///
+    /// Rules without a manifest are version `0.0.0`.
+    pub fn load(dir: &Path, sha256: String, files: Vec<RuleFile>) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        let version = match fs::read_to_string(&path) {
+            Ok(contents) => parse(&contents)
+                .with_context(|| format!("Invalid rules manifest {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("No {}, rules are version 0.0.0", path.display());
+                Version::new(0, 0, 0)
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Ok(Self {
+            version,
+            sha256,
+            files,
+            shadow: Vec::new(),
+        })
+    }
+
+    /// Manifest of `rules/detector.dl`, which the in-process engines
+    /// implement
+    pub fn builtin() -> Self {
+        let sha256 = ServiceContext::rules_sha256();
+        Self {
+            version: parse(BUILTIN).expect("rules/manifest.yaml is valid"),
+            files: vec![RuleFile {
+                name: "detector.dl".to_string(),
+                sha256: sha256.clone(),
+            }],
+            sha256,
+            shadow: Vec::new(),
+        }
+    }
+
+    /// Record the shadow packs next to the program's files
+    pub fn with_shadow(mut self, shadow: Vec<RuleFile>) -> Self {
+        self.shadow = shadow;
+        self
+    }
+}
+
+fn parse(contents: &str) -> Result<Version> {
+    let manifest: ManifestFile = serde_yaml::from_str(contents)?;
+    Ok(manifest.version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_builtin_manifest_describes_the_detector() {
+        let manifest = RulesManifest::builtin();
+        assert!(manifest.version > Version::new(0, 0, 0));
+        assert_eq!(manifest.sha256, ServiceContext::rules_sha256());
+        assert_eq!(manifest.files.len(), 1);
+    }
+
+    #[test]
+    fn test_load_reads_the_declared_version() {
+        let dir = TempDir::new("manifest");
+        let unversioned = RulesManifest::load(&dir, "ab".repeat(32), Vec::new()).unwrap();
+        assert_eq!(unversioned.version, Version::new(0, 0, 0));
+
+        fs::write(dir.join(MANIFEST_FILE), "version: 2.1.0-rc.1\n").unwrap();
+        let manifest = RulesManifest::load(&dir, "ab".repeat(32), Vec::new()).unwrap();
+        assert_eq!(manifest.version.to_string(), "2.1.0-rc.1");
+
+        fs::write(dir.join(MANIFEST_FILE), "version: 2.1\n").unwrap();
+        assert!(RulesManifest::load(&dir, "ab".repeat(32), Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Pool of ONNX sessions for concurrent inference
+//!
+//! A single session serializes every inference through one set of
+//! runtime threads. Each model instead owns a fixed number of sessions;
+//! callers check one out, waiting on a semaphore when all are busy.
+
+use anyhow::Result;
+use prometheus::Histogram;
+use std::{sync::Mutex, time::Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::info;
+
+use crate::onnx_wrapper::{self, ModelOutputs, ModelSpec};
+
+/// Session pool and runtime threading settings
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// Sessions per model, i.e. inferences of one model that run at once
+    pub pool_size: usize,
+    /// Threads used within one operator; 0 lets the runtime decide
+    pub intra_op_threads: usize,
+    /// Threads used to run independent operators; 0 lets the runtime decide
+    pub inter_op_threads: usize,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            pool_size: 1,
+            intra_op_threads: 0,
+            inter_op_threads: 0,
+        }
+    }
+}
+
+/// One loaded model session
+struct Session {
+    model: ModelSpec,
+}
+
+impl Session {
+    fn load(model: &ModelSpec, options: &SessionOptions) -> Self {
+        // Placeholder implementation
+        // In production, this would build an ort::Session:
+        //     ort::Session::builder()?
+        //         .with_intra_threads(options.intra_op_threads)?
+        //         .with_inter_threads(options.inter_op_threads)?
+        //         .commit_from_file(&model.path)?
+        let _ = options;
+        Self {
+            model: model.clone(),
+        }
+    }
+
+    async fn run(&self, content_hash: &str) -> Result<ModelOutputs> {
+        info!("Running model {} ({})", self.model.name, self.model.path);
+        onnx_wrapper::run_model_outputs(content_hash).await
+    }
+
+    async fn embed(&self, content_hash: &str, dim: usize) -> Result<Vec<f32>> {
+        onnx_wrapper::run_embedding(content_hash, dim).await
+    }
+}
+
+/// Fixed set of sessions for one model
+pub struct SessionPool {
+    idle: Mutex<Vec<Session>>,
+    permits: Semaphore,
+    size: usize,
+}
+
+impl SessionPool {
+    pub fn new(model: &ModelSpec, options: &SessionOptions) -> Self {
+        let size = options.pool_size.max(1);
+        Self {
+            idle: Mutex::new((0..size).map(|_| Session::load(model, options)).collect()),
+            permits: Semaphore::new(size),
+            size,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Run the model on a free session, waiting if all are busy
+    ///
+    /// # Arguments
+    /// * `content_hash` - Hash of the content to analyze
+    /// * `queue_wait` - Records how long the call waited for a session
+    /// * `latency` - Records how long the session took to run
+    pub async fn run(
+        &self,
+        content_hash: &str,
+        queue_wait: Option<&Histogram>,
+        latency: Option<&Histogram>,
+    ) -> Result<ModelOutputs> {
+        let start = Instant::now();
+        let checkout = self.checkout().await;
+        if let Some(queue_wait) = queue_wait {
+            queue_wait.observe(start.elapsed().as_secs_f64());
+        }
+        let start = Instant::now();
+        let outputs = checkout.session().run(content_hash).await;
+        if let Some(latency) = latency {
+            latency.observe(start.elapsed().as_secs_f64());
+        }
+        outputs
+    }
+
+    /// Compute the model's sentence embedding on a free session
+    pub async fn embed(
+        &self,
+        content_hash: &str,
+        dim: usize,
+        queue_wait: Option<&Histogram>,
+    ) -> Result<Vec<f32>> {
+        let start = Instant::now();
+        let checkout = self.checkout().await;
+        if let Some(queue_wait) = queue_wait {
+            queue_wait.observe(start.elapsed().as_secs_f64());
+        }
+        checkout.session().embed(content_hash, dim).await
+    }
+
+    async fn checkout(&self) -> Checkout<'_> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("session pool semaphore is never closed");
+        let session = self.idle.lock().unwrap().pop();
+        Checkout {
+            pool: self,
+            session,
+            _permit: permit,
+        }
+    }
+}
+
+/// A session borrowed from the pool, returned on drop
+///
+/// Dropping also covers cancellation, e.g. when an inference deadline
+/// elapses mid-run.
+struct Checkout<'a> {
+    pool: &'a SessionPool,
+    session: Option<Session>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Checkout<'_> {
+    fn session(&self) -> &Session {
+        self.session
+            .as_ref()
+            .expect("a permit guarantees an idle session")
+    }
+}
+
+impl Drop for Checkout<'_> {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.pool.idle.lock().unwrap().push(session);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::HistogramOpts;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn pool(size: usize) -> SessionPool {
+        let model: ModelSpec = "fakeness:a.onnx".parse().unwrap();
+        SessionPool::new(
+            &model,
+            &SessionOptions {
+                pool_size: size,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_checkout_waits_for_free_session() {
+        let pool = pool(1);
+        let first = pool.checkout().await;
+        assert!(timeout(Duration::from_millis(20), pool.checkout())
+            .await
+            .is_err());
+
+        drop(first);
+        assert!(timeout(Duration::from_millis(20), pool.checkout())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_run_records_queue_wait() {
+        let pool = pool(2);
+        let queue_wait = Histogram::with_opts(HistogramOpts::new("wait", "wait")).unwrap();
+        pool.run("a", Some(&queue_wait), None).await.unwrap();
+        pool.run("b", Some(&queue_wait), None).await.unwrap();
+        assert_eq!(queue_wait.get_sample_count(), 2);
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_pool_has_at_least_one_session() {
+        assert_eq!(pool(0).size(), 1);
+    }
+}
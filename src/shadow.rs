@@ -0,0 +1,296 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Shadow model execution for A/B evaluation
+//!
+//! A candidate model runs on a sampled fraction of traffic after the
+//! primary verdict is known. Both outputs are logged, counted and
+//! published as a JSON [`ShadowRecord`]; the published verdict is never
+//! affected.
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Semaphore, time::timeout};
+use tracing::{info, warn};
+
+use crate::calibration::Calibration;
+use crate::fact_mapping::BinOverrides;
+use crate::metrics::Metrics;
+use crate::onnx_wrapper::{Ensemble, FusionStrategy, ModelSpec, NeuralFeatures};
+use crate::reasoning::{EmbeddedEngine, ReasoningEngine};
+use crate::session_pool::SessionOptions;
+use crate::souffle_wrapper::{self, Aggregation, ConflictPolicy, DgraphFacts, Verdict};
+use crate::transport::Transport;
+
+/// Shadow runs that execute at once; sampled messages arriving while all
+/// run are skipped
+pub const SHADOW_CONCURRENCY: usize = 4;
+
+/// Shadow model settings
+#[derive(Debug, Clone)]
+pub struct ShadowConfig {
+    /// Candidate model, `name:path`
+    pub model: ModelSpec,
+    /// Fraction of messages (0-1) also run through the shadow model
+    pub sample_rate: f64,
+    /// Subject the comparison records are published on
+    pub subject: String,
+}
+
+/// Side-by-side outputs of the primary and shadow models for one message
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShadowRecord {
+    pub content_hash: String,
+    pub primary_version: String,
+    pub primary_fakeness: f32,
+    pub primary_emotion: f32,
+    pub primary_verdict: Verdict,
+    pub shadow_version: String,
+    pub shadow_fakeness: f32,
+    pub shadow_emotion: f32,
+    pub shadow_verdict: Verdict,
+}
+
+impl ShadowRecord {
+    pub fn disagrees(&self) -> bool {
+        self.primary_verdict != self.shadow_verdict
+    }
+}
+
+/// Runs the shadow model in the background
+pub struct ShadowRunner {
+    ensemble: Arc<Ensemble>,
+    sample_rate: f64,
+    subject: String,
+    deadline: Duration,
+    calibration: Calibration,
+    reasoning: Arc<dyn ReasoningEngine>,
+    conflicts: ConflictPolicy,
+    transport: Arc<dyn Transport>,
+    metrics: Arc<Metrics>,
+    permits: Arc<Semaphore>,
+}
+
+impl ShadowRunner {
+    pub fn new(
+        config: &ShadowConfig,
+        deadline: Duration,
+        transport: Arc<dyn Transport>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            ensemble: Arc::new(
+                Ensemble::new(
+                    vec![config.model.clone()],
+                    FusionStrategy::Mean,
+                    &SessionOptions::default(),
+                )
+                .with_model_metrics(metrics.models.clone()),
+            ),
+            sample_rate: config.sample_rate,
+            subject: config.subject.clone(),
+            deadline,
+            calibration: Calibration::default(),
+            reasoning: Arc::new(EmbeddedEngine),
+            conflicts: ConflictPolicy::default(),
+            transport,
+            metrics,
+            permits: Arc::new(Semaphore::new(SHADOW_CONCURRENCY)),
+        }
+    }
+
+    /// Calibrate candidate scores like the primary's
+    pub fn with_calibration(mut self, calibration: Calibration) -> Self {
+        self.calibration = calibration;
+        self
+    }
+
+    /// Evaluate the candidate's rules with the primary's engine
+    pub fn with_reasoning(mut self, reasoning: Arc<dyn ReasoningEngine>) -> Self {
+        self.reasoning = reasoning;
+        self
+    }
+
+    /// Resolve contradictory verdicts like the primary
+    pub fn with_conflicts(mut self, conflicts: ConflictPolicy) -> Self {
+        self.conflicts = conflicts;
+        self
+    }
+
+    /// Model version string of the candidate
+    pub fn version(&self) -> String {
+        self.ensemble.version()
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Run the shadow model for this message if it falls in the sample
+    ///
+    /// Returns immediately; the comparison happens on a spawned task so
+    /// the primary path never waits for the candidate. At most
+    /// [`SHADOW_CONCURRENCY`] run at once, further samples are skipped.
+    pub fn observe(
+        &self,
+        content_hash: &str,
+        primary: &NeuralFeatures,
+        primary_verdict: Verdict,
+        dgraph_facts: &DgraphFacts,
+        bins: &BinOverrides,
+    ) {
+        if !in_sample(content_hash, self.sample_rate) {
+            return;
+        }
+        let Ok(permit) = Arc::clone(&self.permits).try_acquire_owned() else {
+            self.metrics.shadow_skipped.inc();
+            return;
+        };
+
+        let ensemble = Arc::clone(&self.ensemble);
+        let metrics = Arc::clone(&self.metrics);
+        let transport = Arc::clone(&self.transport);
+        let subject = self.subject.clone();
+        let deadline = self.deadline;
+        let calibration = self.calibration.clone();
+        let reasoning = Arc::clone(&self.reasoning);
+        let conflicts = self.conflicts;
+        let content_hash = content_hash.to_string();
+        let primary = primary.clone();
+        let dgraph_facts = dgraph_facts.clone();
+        let bins = bins.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let mut shadow = match timeout(deadline, ensemble.run(&content_hash)).await {
+                Ok(Ok(features)) => features,
+                Ok(Err(e)) => {
+                    warn!("Shadow inference for {} failed: {}", content_hash, e);
+                    metrics.shadow_failures.inc();
+                    return;
+                }
+                Err(_) => {
+                    warn!(
+                        "Shadow inference for {} exceeded {:?}",
+                        content_hash, deadline
+                    );
+                    metrics.shadow_failures.inc();
+                    return;
+                }
+            };
+            calibration.apply(&mut shadow);
+            // The candidate replaces the text models only
+            shadow.visual_artifact = primary.visual_artifact;
+
+            let shadow_verdict = match souffle_wrapper::run_datalog(
+                reasoning.as_ref(),
+                &shadow,
+                &dgraph_facts,
+                &bins,
+                // Only the verdict is compared
+                Aggregation::default(),
+                conflicts,
+            )
+            .await
+            {
+                Ok(result) => result.verdict,
+                Err(e) => {
+                    warn!("Shadow rules for {} failed: {}", content_hash, e);
+                    metrics.shadow_failures.inc();
+                    return;
+                }
+            };
+
+            let record = ShadowRecord {
+                content_hash,
+                primary_version: primary.model_version.clone(),
+                primary_fakeness: primary.fakeness,
+                primary_emotion: primary.emotion,
+                primary_verdict,
+                shadow_version: shadow.model_version.clone(),
+                shadow_fakeness: shadow.fakeness,
+                shadow_emotion: shadow.emotion,
+                shadow_verdict,
+            };
+
+            metrics.shadow_runs.inc();
+            if record.disagrees() {
+                metrics.shadow_disagreements.inc();
+            }
+            info!(
+                "Shadow {} for {}: {} (primary {})",
+                record.shadow_version,
+                record.content_hash,
+                record.shadow_verdict,
+                record.primary_verdict
+            );
+
+            match serde_json::to_vec(&record) {
+                Ok(payload) => {
+                    if let Err(e) = transport
+                        .send(&subject, async_nats::HeaderMap::new(), payload)
+                        .await
+                    {
+                        warn!("Failed to publish shadow record: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to encode shadow record: {}", e),
+            }
+        });
+    }
+}
+
+/// Deterministic sampling on the content hash, so redeliveries of the
+/// same message make the same decision
+pub fn in_sample(content_hash: &str, sample_rate: f64) -> bool {
+    if sample_rate >= 1.0 {
+        return true;
+    }
+    // SHA-256 rather than the std hasher, whose output may change between
+    // Rust releases and so between replicas
+    let digest = Sha256::digest(content_hash.as_bytes());
+    let prefix = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+    (prefix as f64 / u64::MAX as f64) < sample_rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling_rate() {
+        let hashes: Vec<String> = (0..10_000).map(|i| format!("hash-{}", i)).collect();
+        let sampled = hashes.iter().filter(|h| in_sample(h, 0.25)).count();
+        assert!((2_000..3_000).contains(&sampled), "sampled {}", sampled);
+
+        assert!(hashes.iter().all(|h| !in_sample(h, 0.0)));
+        assert!(hashes.iter().all(|h| in_sample(h, 1.0)));
+        assert_eq!(in_sample("abc", 0.5), in_sample("abc", 0.5));
+        // Pinned, so every replica and release samples the same messages
+        let pinned: Vec<bool> = (0..8)
+            .map(|i| in_sample(&format!("hash-{}", i), 0.5))
+            .collect();
+        assert_eq!(pinned, [false, true, true, true, false, false, false, true]);
+    }
+
+    #[test]
+    fn test_record_disagreement() {
+        let mut record = ShadowRecord {
+            content_hash: "abc".to_string(),
+            primary_version: "fakeness@1".to_string(),
+            primary_fakeness: 0.5,
+            primary_emotion: 0.3,
+            primary_verdict: Verdict::Safe,
+            shadow_version: "fakeness@2".to_string(),
+            shadow_fakeness: 0.7,
+            shadow_emotion: 0.3,
+            shadow_verdict: Verdict::Safe,
+        };
+        assert!(!record.disagrees());
+        record.shadow_verdict = Verdict::Suspicious;
+        assert!(record.disagrees());
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["shadow_verdict"], "SUSPICIOUS");
+    }
+}
@@ -0,0 +1,379 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Nearest-neighbour lookup over previously analysed content
+//!
+//! Every processed item is indexed by a MinHash signature of its text
+//! (banded for locality-sensitive hashing) and, when the models produce
+//! one, its embedding. Fact-checkers query by text or content hash to see
+//! whether a "new" post is a recycled known hoax.
+
+use serde::Serialize;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::Mutex,
+};
+
+/// LSH bands; together with `ROWS` gives a 64-value signature
+const BANDS: usize = 16;
+const ROWS: usize = 4;
+/// Words per shingle
+const SHINGLE_WORDS: usize = 3;
+
+/// Similarity API settings
+#[derive(Debug, Clone)]
+pub struct SimilarityConfig {
+    /// Address the similarity API listens on
+    pub listen: SocketAddr,
+    /// Number of recent items kept in the index
+    pub capacity: usize,
+    /// Neighbours below this similarity are not returned
+    pub min_similarity: f32,
+}
+
+/// A previously seen item close to the query
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Neighbor {
+    pub content_hash: String,
+    pub verdict: String,
+    pub similarity: f32,
+}
+
+struct Item {
+    verdict: String,
+    /// Empty for text without words, which is compared by embedding only
+    signature: Vec<u64>,
+    embedding: Vec<f32>,
+    /// Position in the insertion order
+    seq: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    items: HashMap<String, Item>,
+    /// Insertion order, oldest first, for eviction; entries of removed or
+    /// re-inserted items stay until they reach the front or are compacted
+    order: VecDeque<(u64, String)>,
+    next_seq: u64,
+    buckets: HashMap<(usize, u64), HashSet<String>>,
+}
+
+/// Bounded index of recently analysed content
+pub struct SimilarityIndex {
+    capacity: usize,
+    min_similarity: f32,
+    inner: Mutex<Inner>,
+}
+
+impl SimilarityIndex {
+    pub fn new(capacity: usize, min_similarity: f32) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            min_similarity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Index an analysed item, replacing any earlier entry for the hash
+    ///
+    /// Items with neither words nor an embedding have nothing to compare
+    /// and are not indexed.
+    pub fn insert(&self, content_hash: &str, text: &str, verdict: &str, embedding: &[f32]) {
+        let signature = minhash(text);
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.remove(content_hash);
+        if signature.is_empty() && embedding.is_empty() {
+            return;
+        }
+        for key in band_keys(&signature) {
+            inner
+                .buckets
+                .entry(key)
+                .or_default()
+                .insert(content_hash.to_string());
+        }
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.items.insert(
+            content_hash.to_string(),
+            Item {
+                verdict: verdict.to_string(),
+                signature,
+                embedding: embedding.to_vec(),
+                seq,
+            },
+        );
+        inner.order.push_back((seq, content_hash.to_string()));
+
+        while inner.items.len() > self.capacity {
+            let Some((seq, oldest)) = inner.order.pop_front() else {
+                break;
+            };
+            if inner.items.get(&oldest).is_some_and(|item| item.seq == seq) {
+                inner.remove(&oldest);
+            }
+        }
+        // Re-inserted hashes leave stale entries behind; drop them before
+        // they outnumber the live ones
+        if inner.order.len() > 2 * self.capacity {
+            let Inner { items, order, .. } = &mut *inner;
+            order.retain(|(seq, hash)| items.get(hash).is_some_and(|item| item.seq == *seq));
+        }
+    }
+
+    /// Drop every indexed item to free memory
+    ///
+    /// # Returns
+    /// Number of items dropped
+    pub fn clear(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let dropped = inner.items.len();
+        *inner = Inner::default();
+        dropped
+    }
+
+    /// Nearest items to arbitrary text; none for text without words
+    pub fn similar_to_text(&self, text: &str, limit: usize) -> Vec<Neighbor> {
+        let signature = minhash(text);
+        if signature.is_empty() {
+            return Vec::new();
+        }
+        let inner = self.inner.lock().unwrap();
+        self.nearest(&inner, &signature, &[], None, limit)
+    }
+
+    /// Nearest items to a previously analysed content hash
+    ///
+    /// # Returns
+    /// `None` if the hash is not (or no longer) in the index
+    pub fn similar_to_hash(&self, content_hash: &str, limit: usize) -> Option<Vec<Neighbor>> {
+        let inner = self.inner.lock().unwrap();
+        let item = inner.items.get(content_hash)?;
+        Some(self.nearest(
+            &inner,
+            &item.signature,
+            &item.embedding,
+            Some(content_hash),
+            limit,
+        ))
+    }
+
+    fn nearest(
+        &self,
+        inner: &Inner,
+        signature: &[u64],
+        embedding: &[f32],
+        exclude: Option<&str>,
+        limit: usize,
+    ) -> Vec<Neighbor> {
+        let mut candidates: HashSet<&String> = band_keys(signature)
+            .filter_map(|key| inner.buckets.get(&key))
+            .flatten()
+            .collect();
+        // Embeddings catch paraphrases that share no shingles; the index is
+        // bounded, so a linear scan is affordable
+        if !embedding.is_empty() {
+            candidates.extend(
+                inner
+                    .items
+                    .iter()
+                    .filter(|(_, item)| item.embedding.len() == embedding.len())
+                    .map(|(hash, _)| hash),
+            );
+        }
+
+        let mut neighbors: Vec<Neighbor> = candidates
+            .into_iter()
+            .filter(|hash| Some(hash.as_str()) != exclude)
+            .filter_map(|hash| {
+                let item = &inner.items[hash];
+                let similarity = if !embedding.is_empty() && item.embedding.len() == embedding.len()
+                {
+                    cosine(embedding, &item.embedding)
+                } else {
+                    signature_similarity(signature, &item.signature)
+                };
+                (similarity >= self.min_similarity).then(|| Neighbor {
+                    content_hash: hash.clone(),
+                    verdict: item.verdict.clone(),
+                    similarity,
+                })
+            })
+            .collect();
+
+        neighbors.sort_by(|a, b| {
+            b.similarity
+                .total_cmp(&a.similarity)
+                .then_with(|| a.content_hash.cmp(&b.content_hash))
+        });
+        neighbors.truncate(limit);
+        neighbors
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, content_hash: &str) {
+        let Some(item) = self.items.remove(content_hash) else {
+            return;
+        };
+        for key in band_keys(&item.signature) {
+            if let Some(bucket) = self.buckets.get_mut(&key) {
+                bucket.remove(content_hash);
+                if bucket.is_empty() {
+                    self.buckets.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// MinHash signature over lowercase word shingles, empty for text without
+/// words
+fn minhash(text: &str) -> Vec<u64> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.is_empty() {
+        return Vec::new();
+    }
+    let shingles: Vec<&[String]> = if words.len() <= SHINGLE_WORDS {
+        vec![words.as_slice()]
+    } else {
+        words.windows(SHINGLE_WORDS).collect()
+    };
+
+    (0..BANDS * ROWS)
+        .map(|seed| {
+            shingles
+                .iter()
+                .map(|shingle| {
+                    let mut hasher = DefaultHasher::new();
+                    seed.hash(&mut hasher);
+                    shingle.hash(&mut hasher);
+                    hasher.finish()
+                })
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect()
+}
+
+/// Bucket keys of each LSH band of a signature
+fn band_keys(signature: &[u64]) -> impl Iterator<Item = (usize, u64)> + '_ {
+    signature.chunks(ROWS).enumerate().map(|(band, rows)| {
+        let mut hasher = DefaultHasher::new();
+        rows.hash(&mut hasher);
+        (band, hasher.finish())
+    })
+}
+
+/// Estimated Jaccard similarity of two signatures, 0 if either is empty
+fn signature_similarity(a: &[u64], b: &[u64]) -> f32 {
+    let equal = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    equal as f32 / a.len().max(b.len()).max(1) as f32
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denominator = norm(a) * norm(b);
+    if denominator == 0.0 {
+        0.0
+    } else {
+        dot / denominator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOAX: &str = "Drinking hot water every fifteen minutes kills the virus in your throat";
+
+    #[test]
+    fn test_recycled_text_is_found() {
+        let index = SimilarityIndex::new(10, 0.3);
+        index.insert("hoax", HOAX, "DISINFO", &[]);
+        index.insert(
+            "other",
+            "Local council approves new cycling lanes downtown",
+            "SAFE",
+            &[],
+        );
+
+        let repost = format!("BREAKING: {} - share before they delete it", HOAX);
+        let neighbors = index.similar_to_text(&repost, 5);
+        assert_eq!(neighbors.len(), 1);
+        assert_eq!(neighbors[0].content_hash, "hoax");
+        assert_eq!(neighbors[0].verdict, "DISINFO");
+    }
+
+    #[test]
+    fn test_hash_query_uses_embeddings_and_excludes_self() {
+        let index = SimilarityIndex::new(10, 0.9);
+        index.insert("a", "first wording", "DISINFO", &[1.0, 0.0]);
+        index.insert("b", "entirely different phrasing", "DISINFO", &[0.99, 0.05]);
+        index.insert("c", "unrelated", "SAFE", &[0.0, 1.0]);
+
+        let neighbors = index.similar_to_hash("a", 5).unwrap();
+        assert_eq!(neighbors.len(), 1);
+        assert_eq!(neighbors[0].content_hash, "b");
+        assert!(index.similar_to_hash("missing", 5).is_none());
+    }
+
+    #[test]
+    fn test_capacity_evicts_oldest() {
+        let index = SimilarityIndex::new(2, 0.0);
+        index.insert("a", HOAX, "DISINFO", &[]);
+        index.insert("b", HOAX, "DISINFO", &[]);
+        index.insert("c", HOAX, "DISINFO", &[]);
+
+        assert!(index.similar_to_hash("a", 5).is_none());
+        let neighbors = index.similar_to_hash("c", 5).unwrap();
+        assert_eq!(neighbors.len(), 1);
+        assert_eq!(neighbors[0].content_hash, "b");
+
+        assert_eq!(index.clear(), 2);
+        assert!(index.similar_to_hash("c", 5).is_none());
+    }
+
+    #[test]
+    fn test_reinserted_hash_is_evicted_by_its_latest_insert() {
+        let index = SimilarityIndex::new(2, 0.0);
+        index.insert("a", HOAX, "DISINFO", &[]);
+        index.insert("b", HOAX, "DISINFO", &[]);
+        for _ in 0..10 {
+            index.insert("a", HOAX, "DISINFO", &[]);
+        }
+        index.insert("c", HOAX, "DISINFO", &[]);
+
+        assert!(index.similar_to_hash("b", 5).is_none());
+        assert!(index.similar_to_hash("a", 5).is_some());
+        assert!(index.inner.lock().unwrap().order.len() <= 4);
+    }
+
+    #[test]
+    fn test_text_without_words_matches_nothing() {
+        let index = SimilarityIndex::new(10, 0.0);
+        index.insert("image-1", "", "DISINFO", &[]);
+        index.insert("image-2", "  --  ", "SAFE", &[]);
+        index.insert("hoax", HOAX, "DISINFO", &[]);
+
+        assert!(index.similar_to_hash("image-1", 5).is_none());
+        assert!(index.similar_to_text("", 5).is_empty());
+        let neighbors = index.similar_to_hash("hoax", 5).unwrap();
+        assert!(neighbors.is_empty());
+
+        // Still compared by embedding
+        index.insert("photo-1", "", "DISINFO", &[1.0, 0.0]);
+        index.insert("photo-2", "", "DISINFO", &[1.0, 0.1]);
+        let neighbors = index.similar_to_hash("photo-1", 5).unwrap();
+        assert_eq!(neighbors.len(), 1);
+        assert_eq!(neighbors[0].content_hash, "photo-2");
+    }
+}
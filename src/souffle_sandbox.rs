@@ -0,0 +1,295 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Confinement of the Soufflé subprocess
+//!
+//! Rule packs are written by analysts, not by the service's developers, so
+//! the `souffle` and `compiled` backends run their programs as untrusted:
+//! with an empty environment, in a scratch directory only the service user
+//! can reach, under address space, CPU time and output size limits and,
+//! unless disabled, in a network namespace of its own. A run that exceeds a
+//! limit is killed and reported as a [`Runaway`], so a pathological program
+//! costs one message its verdict and never the worker.
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fmt, fs,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::Duration,
+};
+
+/// Limits of one Soufflé run
+#[derive(Debug, Clone, PartialEq)]
+pub struct SouffleSandbox {
+    /// Address space cap
+    pub memory_mb: u64,
+    /// CPU time cap, across all threads of the run
+    pub cpu_secs: u64,
+    /// Size cap of every file the run writes
+    pub output_mb: u64,
+    /// Run in a network namespace without interfaces
+    pub isolate_network: bool,
+    /// Parent of the runs' scratch directories, private to the service user
+    pub scratch_dir: PathBuf,
+}
+
+impl Default for SouffleSandbox {
+    fn default() -> Self {
+        Self {
+            memory_mb: 1024,
+            cpu_secs: 5,
+            output_mb: 64,
+            isolate_network: true,
+            scratch_dir: std::env::temp_dir().join("nsai-souffle"),
+        }
+    }
+}
+
+impl SouffleSandbox {
+    /// Create the scratch directory, readable by the service user only
+    pub fn scratch_root(&self) -> Result<&Path> {
+        let dir = &self.scratch_dir;
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+            .with_context(|| format!("Failed to restrict {}", dir.display()))?;
+        Ok(dir)
+    }
+
+    /// Check that runs can be confined as configured
+    ///
+    /// Network isolation needs `CAP_SYS_ADMIN` or unprivileged user
+    /// namespaces. Without either every run would fail, so the service
+    /// refuses to start rather than run the rules with network access.
+    pub fn check(&self) -> Result<()> {
+        if !self.isolate_network {
+            return Ok(());
+        }
+        let mut probe = std::process::Command::new("/bin/sh");
+        probe.arg("-c").arg(":");
+        // SAFETY: only the async-signal-safe unshare call runs between
+        // fork and exec
+        unsafe {
+            probe.pre_exec(unshare_network);
+        }
+        probe.status().map(drop).map_err(|e| {
+            anyhow!(
+                "Cannot isolate Soufflé from the network: {}. Allow unshare in the \
+                 seccomp profile, grant CAP_SYS_ADMIN or set \
+                 NSAI_SOUFFLE_ISOLATE_NETWORK=false",
+                e
+            )
+        })
+    }
+
+    /// Confine `command`, which runs in `scratch`
+    pub fn apply(&self, command: &mut tokio::process::Command, scratch: &Path) {
+        command
+            .env_clear()
+            .env("TMPDIR", scratch)
+            .current_dir(scratch);
+        // The interpreter runs its preprocessor from the PATH
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+
+        let mb = 1024 * 1024;
+        let limits = [
+            (libc::RLIMIT_AS, self.memory_mb * mb, self.memory_mb * mb),
+            // SIGXCPU at the soft limit, SIGKILL a second later
+            (libc::RLIMIT_CPU, self.cpu_secs, self.cpu_secs + 1),
+            (libc::RLIMIT_FSIZE, self.output_mb * mb, self.output_mb * mb),
+            (libc::RLIMIT_CORE, 0, 0),
+        ];
+        let isolate_network = self.isolate_network;
+        // SAFETY: only async-signal-safe setrlimit and unshare calls run
+        // between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                for (resource, soft, hard) in limits {
+                    let limit = libc::rlimit {
+                        rlim_cur: soft as libc::rlim_t,
+                        rlim_max: hard as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if isolate_network {
+                    unshare_network()?;
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Move the calling process into a network namespace without interfaces
+///
+/// Without CAP_SYS_ADMIN a user namespace grants the right to create the
+/// network namespace.
+fn unshare_network() -> std::io::Result<()> {
+    // SAFETY: unshare only changes the namespaces of the calling process
+    let unshared = unsafe {
+        libc::unshare(libc::CLONE_NEWNET) == 0
+            || libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) == 0
+    };
+    if unshared {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// A run killed for exceeding a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runaway {
+    /// Ran past [`crate::souffle_wrapper::SOUFFLE_TIMEOUT`]
+    Timeout,
+    /// Used up its CPU time
+    Cpu,
+    /// Failed to allocate within its address space
+    Memory,
+    /// Wrote a file past the size cap
+    Output,
+}
+
+impl Runaway {
+    /// Label of `nsai_souffle_runaways_total`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::Cpu => "cpu",
+            Self::Memory => "memory",
+            Self::Output => "output",
+        }
+    }
+
+    /// The limit a finished run was killed for, if any, given the CPU time
+    /// it used and its cap
+    pub fn classify(
+        status: &ExitStatus,
+        stderr: &[u8],
+        cpu_time: Duration,
+        cpu_secs: u64,
+    ) -> Option<Self> {
+        let stderr = String::from_utf8_lossy(stderr);
+        match status.signal() {
+            Some(libc::SIGXCPU) => Some(Self::Cpu),
+            // The hard CPU limit kills with SIGKILL, but so does the OOM
+            // killer or an operator
+            Some(libc::SIGKILL) if cpu_time >= Duration::from_secs(cpu_secs) => Some(Self::Cpu),
+            Some(libc::SIGXFSZ) => Some(Self::Output),
+            _ if stderr.contains("bad_alloc") || stderr.contains("Cannot allocate memory") => {
+                Some(Self::Memory)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// CPU time of the service's reaped children so far
+///
+/// The difference across a run bounds the run's own CPU time from above;
+/// children of other runs reaped meanwhile count too.
+pub fn children_cpu_time() -> Duration {
+    // SAFETY: getrusage only writes the struct it is given
+    let usage = unsafe {
+        let mut usage = std::mem::zeroed::<libc::rusage>();
+        libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage);
+        usage
+    };
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+impl fmt::Display for Runaway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = match self {
+            Self::Timeout => "its deadline",
+            Self::Cpu => "its CPU time limit",
+            Self::Memory => "its memory limit",
+            Self::Output => "its output size limit",
+        };
+        write!(f, "Soufflé run killed, exceeded {}", limit)
+    }
+}
+
+impl std::error::Error for Runaway {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_killed_runs_are_classified() {
+        let signalled = |signal: i32| ExitStatus::from_raw(signal);
+        let exited = |code: i32| ExitStatus::from_raw(code << 8);
+        let classify = |status: ExitStatus, stderr: &[u8], cpu_secs: u64| {
+            Runaway::classify(&status, stderr, Duration::from_secs(cpu_secs), 5)
+        };
+        assert_eq!(
+            classify(signalled(libc::SIGXCPU), b"", 5),
+            Some(Runaway::Cpu)
+        );
+        assert_eq!(
+            classify(signalled(libc::SIGKILL), b"", 6),
+            Some(Runaway::Cpu)
+        );
+        assert_eq!(classify(signalled(libc::SIGKILL), b"", 1), None);
+        assert_eq!(
+            classify(signalled(libc::SIGXFSZ), b"", 0),
+            Some(Runaway::Output)
+        );
+        assert_eq!(
+            classify(
+                signalled(libc::SIGABRT),
+                b"terminate called after throwing an instance of 'std::bad_alloc'",
+                0
+            ),
+            Some(Runaway::Memory)
+        );
+        assert_eq!(classify(exited(1), b"syntax error", 0), None);
+        assert_eq!(classify(exited(0), b"", 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_runaway_programs_are_killed() {
+        let dir = TempDir::new("sandbox");
+        let sandbox = SouffleSandbox {
+            cpu_secs: 1,
+            isolate_network: false,
+            scratch_dir: dir.join("scratch"),
+            ..Default::default()
+        };
+        let scratch = sandbox.scratch_root().unwrap().to_path_buf();
+        let mode = fs::metadata(&scratch).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let mut command = tokio::process::Command::new("/bin/sh");
+        command.arg("-c").arg("while :; do :; done");
+        sandbox.apply(&mut command, &scratch);
+        let started = children_cpu_time();
+        let output = command.output().await.unwrap();
+        assert_eq!(
+            Runaway::classify(
+                &output.status,
+                &output.stderr,
+                children_cpu_time() - started,
+                sandbox.cpu_secs
+            ),
+            Some(Runaway::Cpu)
+        );
+    }
+}
@@ -2,20 +2,298 @@
 // SPDX-FileCopyrightText: 2024 Hyperpolymath
 
 //! Soufflé Datalog wrapper for symbolic reasoning
+//!
+//! Rules are evaluated by one of four backends: the embedded engine, an
+//! in-process mirror of `rules/detector.dl`; the same rules in an
+//! in-process Datalog engine (`ascent-engine` feature); the `souffle`
+//! interpreter running the program itself; or the program compiled with
+//! `souffle -o`, ahead of time or when the rules are loaded. See
+//! [`crate::reasoning`]. The Soufflé backends write each message's base
+//! facts to `.facts` files in a scratch directory and read the `.output`
+//! relations back, so rule changes take effect without a rebuild of the
+//! service.
+//!
+//! Rules report their provenance through the `fired(rule, premise)`
+//! relation: every rule of `rules/detector.dl` has companion rules deriving
+//! one row per body atom that held when it fired, which every backend
+//! returns as [`FiredRule`]s.
 
-use anyhow::Result;
-use std::collections::HashMap;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+use tracing::info;
 
+use crate::campaigns::campaign_facts;
+use crate::claim_matching::claim_facts;
+use crate::fact_mapping::{neural_facts, BinOverrides, Fact};
+use crate::links::link_facts;
+use crate::model_pb;
+use crate::ner::mention_facts;
 use crate::onnx_wrapper::NeuralFeatures;
+use crate::reasoning::ReasoningEngine;
+use crate::souffle_sandbox::{children_cpu_time, Runaway, SouffleSandbox};
+use crate::stance::stance_facts;
 
 /// Facts from the knowledge graph (Dgraph)
 pub type DgraphFacts = HashMap<String, String>;
 
 /// Verdict from the symbolic reasoning engine
-pub type Verdict = String;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Verdict {
+    Safe,
+    Suspicious,
+    Disinfo,
+    /// The rules derived no verdict, or more than one left to a human by
+    /// [`ConflictPolicy::Escalate`]
+    Inconclusive,
+}
+
+impl Verdict {
+    /// The `verdict` relation's symbol, as published
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Safe => "SAFE",
+            Self::Suspicious => "SUSPICIOUS",
+            Self::Disinfo => "DISINFO",
+            Self::Inconclusive => "INCONCLUSIVE",
+        }
+    }
+
+    /// Rank for [`ConflictPolicy::Severity`], higher is more severe
+    pub fn severity(self) -> u8 {
+        match self {
+            Self::Inconclusive => 0,
+            Self::Safe => 1,
+            Self::Suspicious => 2,
+            Self::Disinfo => 3,
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Verdict {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "SAFE" => Ok(Self::Safe),
+            "SUSPICIOUS" => Ok(Self::Suspicious),
+            "DISINFO" => Ok(Self::Disinfo),
+            "INCONCLUSIVE" => Ok(Self::Inconclusive),
+            other => bail!("unknown verdict: {}", other),
+        }
+    }
+}
+
+/// Deadline for one external Soufflé run
+pub const SOUFFLE_TIMEOUT: Duration = Duration::from_secs(5);
 
-/// Human-readable explanation
-pub type Explanation = String;
+/// Which engine evaluates the rules
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum SymbolicBackend {
+    /// In-process mirror of `rules/detector.dl`
+    #[default]
+    Embedded,
+    /// `rules/detector.dl` compiled into the service with Ascent; requires
+    /// the `ascent-engine` feature
+    Ascent,
+    /// The Soufflé interpreter running `program`
+    Souffle { souffle: PathBuf, program: PathBuf },
+    /// `program` compiled with `souffle -o` into `binary`; the source is
+    /// still read for its `.input`/`.output` directives
+    Compiled { binary: PathBuf, program: PathBuf },
+    /// `program` compiled with `souffle` when the rules are loaded, then run
+    /// as [`SymbolicBackend::Compiled`]
+    CompileOnLoad { souffle: PathBuf, program: PathBuf },
+}
+
+impl SymbolicBackend {
+    /// Compile the program of a [`SymbolicBackend::CompileOnLoad`] backend,
+    /// see [`compile`]; other backends are returned as they are
+    pub fn compile(&self) -> Result<Self> {
+        match self {
+            Self::CompileOnLoad { souffle, program } => Ok(Self::Compiled {
+                binary: compile(souffle, program)?,
+                program: program.clone(),
+            }),
+            other => Ok(other.clone()),
+        }
+    }
+}
+
+/// Backend name as configured in `NSAI_SYMBOLIC_BACKEND`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolicKind {
+    Embedded,
+    Ascent,
+    Souffle,
+    Compiled,
+}
+
+impl FromStr for SymbolicKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "embedded" => Ok(Self::Embedded),
+            "ascent" => Ok(Self::Ascent),
+            "souffle" => Ok(Self::Souffle),
+            "compiled" => Ok(Self::Compiled),
+            other => bail!("unknown symbolic backend: {}", other),
+        }
+    }
+}
+
+/// Outcome of evaluating the rules over a set of base facts
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derivation {
+    pub verdict: Verdict,
+    pub explanation: String,
+    /// Intermediate and output relations derived by the rules
+    pub derived: Vec<Fact>,
+    /// Rules that fired, sorted by identifier
+    pub fired: Vec<FiredRule>,
+    /// SHA-256 of the rules; empty unless evaluated through
+    /// [`crate::live_rules::LiveRules`]
+    pub rules_version: String,
+    /// Version of the rules from their manifest; empty like `rules_version`
+    pub rules_semver: String,
+}
+
+impl Derivation {
+    /// Distinct verdicts of the derived `verdict` facts, most severe first
+    pub fn verdicts(&self) -> Vec<Verdict> {
+        let mut verdicts: Vec<Verdict> = self
+            .derived
+            .iter()
+            .filter(|f| f.relation == "verdict")
+            .filter_map(|f| f.args.first()?.parse().ok())
+            .collect();
+        verdicts.sort_by_key(|v| std::cmp::Reverse(v.severity()));
+        verdicts.dedup();
+        verdicts
+    }
+
+    /// Weights of the derived `contribution(rule, weight)` facts by rule
+    pub fn contributions(&self) -> Vec<(&str, f32)> {
+        self.derived
+            .iter()
+            .filter(|f| f.relation == "contribution")
+            .filter_map(|f| match f.args.as_slice() {
+                [rule, weight] => Some((rule.as_str(), weight.parse().ok()?)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Names of the derived `label` facts, sorted and deduplicated
+    pub fn labels(&self) -> Vec<&str> {
+        let mut labels: Vec<&str> = self
+            .derived
+            .iter()
+            .filter(|f| f.relation == "label")
+            .filter_map(|f| f.args.first().map(String::as_str))
+            .collect();
+        labels.sort();
+        labels.dedup();
+        labels
+    }
+}
+
+/// Outcome of the symbolic layer for one message
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReasoningResult {
+    pub verdict: Verdict,
+    /// Human-readable explanation
+    pub explanation: String,
+    /// Support of the evidence for the verdict, see [`confidence`]
+    pub confidence: f32,
+    /// Rules that fired on the way to the verdict
+    pub fired_rules: Vec<FiredRule>,
+    /// SHA-256 of the rules that decided the verdict
+    pub rules_version: String,
+    /// Version of the rules that decided the verdict, e.g. `1.2.0`
+    pub rules_semver: String,
+    /// The verdict, unless `SAFE` or `INCONCLUSIVE`, and [`NEEDS_REVIEW`]
+    /// for escalated conflicts, followed by the labels the rules derived
+    pub labels: Vec<Label>,
+    /// Intermediate and output relations, for fact dumps
+    pub derived: Vec<Fact>,
+}
+
+/// A label of the content and the support of the scores for it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Label {
+    /// e.g. `DISINFO` or `AI_GENERATED`
+    pub name: String,
+    /// See [`label_confidence`]
+    pub confidence: f32,
+}
+
+impl From<&Label> for model_pb::Label {
+    fn from(label: &Label) -> Self {
+        Self {
+            name: label.name.clone(),
+            confidence: label.confidence,
+        }
+    }
+}
+
+/// A rule that fired and the premises that held when it did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FiredRule {
+    /// Rule identifier, e.g. `disinfo` or `elevated_fakeness:high`
+    pub rule: String,
+    /// Body atoms that held, negated ones prefixed with `!`, e.g.
+    /// `fakeness("high")` or `!source_trusted("true")`
+    pub premises: Vec<String>,
+}
+
+impl fmt::Display for FiredRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} <- {}", self.rule, self.premises.join(", "))
+    }
+}
+
+impl From<&FiredRule> for model_pb::FiredRule {
+    fn from(fired: &FiredRule) -> Self {
+        Self {
+            rule: fired.rule.clone(),
+            premises: fired.premises.clone(),
+        }
+    }
+}
+
+/// Group `fired(rule, premise)` rows into rules sorted by identifier
+pub fn group_fired<'a>(rows: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<FiredRule> {
+    let mut rules: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (rule, premise) in rows {
+        rules.entry(rule).or_default().insert(premise);
+    }
+    rules
+        .into_iter()
+        .map(|(rule, premises)| FiredRule {
+            rule: rule.to_string(),
+            premises: premises.into_iter().map(str::to_string).collect(),
+        })
+        .collect()
+}
 
 /// Run Datalog rules on neural features and graph facts
 ///
@@ -24,78 +302,1077 @@
 /// facts to derive a final verdict.
 ///
 /// # Arguments
+/// * `engine` - Engine evaluating the rules
 /// * `neural_features` - Output from ONNX inference
 /// * `dgraph_facts` - Facts from the knowledge graph
+/// * `bins` - Bin edges replacing the defaults, see [`crate::thresholds`]
+/// * `aggregation` - How weighted rules combine into the confidence
+/// * `conflicts` - How contradictory verdicts resolve into one
 ///
 /// # Returns
-/// Tuple of (verdict, explanation)
+/// The verdict with its explanation, confidence and fired rules
 pub async fn run_datalog(
+    engine: &dyn ReasoningEngine,
     neural_features: &NeuralFeatures,
     dgraph_facts: &DgraphFacts,
-) -> Result<(Verdict, Explanation)> {
-    // Placeholder implementation
-    // In production, this would:
-    // 1. Convert neural features to Datalog facts
-    // 2. Load Soufflé program
-    // 3. Execute rules
-    // 4. Extract verdict from output relations
-
-    let fakeness = neural_features
-        .get("fakeness_score")
-        .copied()
-        .unwrap_or(0.0);
-
-    let source_trusted = dgraph_facts
-        .get("source_trusted")
-        .map(|v| v == "true")
-        .unwrap_or(false);
+    bins: &BinOverrides,
+    aggregation: Aggregation,
+    conflicts: ConflictPolicy,
+) -> Result<ReasoningResult> {
+    let facts = base_facts(neural_features, dgraph_facts, bins);
+    let derivation = engine.evaluate(&facts).await?;
+    let contributions = derivation.contributions();
+    let score = if contributions.is_empty() {
+        neural_features.fakeness
+    } else {
+        aggregation.combine(contributions.iter().map(|(_, weight)| *weight))
+    };
+
+    let mut verdict = derivation.verdict;
+    let mut explanation = derivation.explanation.clone();
+    let contradictory = derivation.verdicts();
+    let escalated = contradictory.len() > 1 && conflicts == ConflictPolicy::Escalate;
+    if contradictory.len() > 1 {
+        verdict = conflicts.resolve(&contradictory, score);
+        let synthetic_text = derivation
+            .derived
+            .contains(&Fact::new("synthetic_text", vec![]));
+        let names: Vec<&str> = contradictory.iter().map(|v| v.as_str()).collect();
+        explanation = format!(
+            "{}; contradictory verdicts {} {}",
+            explain(verdict, synthetic_text, &derivation.fired),
+            names.join(", "),
+            match conflicts {
+                ConflictPolicy::Escalate => "escalated for review".to_string(),
+                policy => format!("resolved by {}", policy),
+            }
+        );
+    }
+
+    let confidence = confidence(verdict, score);
+    let mut labels = Vec::new();
+    if matches!(verdict, Verdict::Disinfo | Verdict::Suspicious) {
+        labels.push(Label {
+            name: verdict.to_string(),
+            confidence,
+        });
+    }
+    if escalated {
+        labels.push(Label {
+            name: NEEDS_REVIEW.to_string(),
+            confidence: 1.0,
+        });
+    }
+    labels.extend(
+        derivation
+            .labels()
+            .into_iter()
+            .filter(|name| *name != verdict.as_str() && *name != NEEDS_REVIEW)
+            .map(|name| Label {
+                name: name.to_string(),
+                confidence: label_confidence(name, neural_features),
+            }),
+    );
+    Ok(ReasoningResult {
+        verdict,
+        explanation,
+        confidence,
+        fired_rules: derivation.fired,
+        rules_version: derivation.rules_version,
+        rules_semver: derivation.rules_semver,
+        labels,
+        derived: derivation.derived,
+    })
+}
+
+/// Support of the evidence for a verdict: the evidence `score`, i.e. the
+/// fakeness score or the combined weights of the weighted rules, for
+/// `DISINFO` and `SUSPICIOUS`, its complement for `SAFE`, and zero when the
+/// rules were inconclusive
+pub fn confidence(verdict: Verdict, score: f32) -> f32 {
+    let score = score.clamp(0.0, 1.0);
+    match verdict {
+        Verdict::Disinfo | Verdict::Suspicious => score,
+        Verdict::Safe => 1.0 - score,
+        Verdict::Inconclusive => 0.0,
+    }
+}
+
+/// Label of results whose contradictory verdicts were escalated
+pub const NEEDS_REVIEW: &str = "NEEDS_REVIEW";
+
+/// How contradictory `verdict` rows, e.g. from two rule packs, resolve
+/// into the published verdict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// The most severe, `DISINFO` over `SUSPICIOUS` over `SAFE`
+    Severity,
+    /// The one the evidence score supports most, see [`confidence`]; ties
+    /// go to the more severe
+    ConfidenceWeighted,
+    /// `INCONCLUSIVE`, labeled [`NEEDS_REVIEW`] for a human to decide
+    #[default]
+    Escalate,
+}
+
+impl ConflictPolicy {
+    /// Pick the verdict among `verdicts`, given the evidence `score`
+    pub fn resolve(self, verdicts: &[Verdict], score: f32) -> Verdict {
+        let most_severe = |key: &dyn Fn(Verdict) -> f32| {
+            verdicts
+                .iter()
+                .copied()
+                .max_by(|a, b| {
+                    key(*a)
+                        .total_cmp(&key(*b))
+                        .then(a.severity().cmp(&b.severity()))
+                })
+                .unwrap_or(Verdict::Inconclusive)
+        };
+        match self {
+            Self::Severity => most_severe(&|_| 0.0),
+            Self::ConfidenceWeighted => most_severe(&|v| confidence(v, score)),
+            Self::Escalate => Verdict::Inconclusive,
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Severity => "severity",
+            Self::ConfidenceWeighted => "confidence",
+            Self::Escalate => "escalate",
+        })
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    /// `severity`, `confidence` or `escalate`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "severity" => Ok(Self::Severity),
+            "confidence" => Ok(Self::ConfidenceWeighted),
+            "escalate" => Ok(Self::Escalate),
+            _ => bail!(
+                "unknown conflict policy {:?}, expected severity, confidence or escalate",
+                s
+            ),
+        }
+    }
+}
+
+/// How the weights of `contribution(rule, weight)` rows combine into one
+/// evidence score
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Aggregation {
+    /// `1 - (1 - w1)(1 - w2)...`, as for independent pieces of evidence
+    #[default]
+    NoisyOr,
+    /// The strongest contribution
+    Max,
+    /// The sum of the contributions, at most `cap`
+    Sum { cap: f32 },
+}
+
+impl Aggregation {
+    /// Combine weights, each clamped to 0-1, into a score in 0-1
+    pub fn combine(self, weights: impl IntoIterator<Item = f32>) -> f32 {
+        let weights = weights.into_iter().map(|w| w.clamp(0.0, 1.0));
+        match self {
+            Self::NoisyOr => 1.0 - weights.map(|w| 1.0 - w).product::<f32>(),
+            Self::Max => weights.fold(0.0, f32::max),
+            Self::Sum { cap } => weights.sum::<f32>().min(cap),
+        }
+    }
+}
+
+impl FromStr for Aggregation {
+    type Err = anyhow::Error;
+
+    /// `noisy-or`, `max`, `sum` or `sum:<cap>` with a cap in (0, 1]
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "noisy-or" | "noisy_or" => Ok(Self::NoisyOr),
+            "max" => Ok(Self::Max),
+            "sum" => Ok(Self::Sum { cap: 1.0 }),
+            other => {
+                let Some(cap) = other.strip_prefix("sum:") else {
+                    bail!(
+                        "unknown aggregation {:?}, expected noisy-or, max or sum[:cap]",
+                        s
+                    );
+                };
+                let cap: f32 = cap
+                    .parse()
+                    .with_context(|| format!("invalid sum cap {:?}", cap))?;
+                if !(cap > 0.0 && cap <= 1.0) {
+                    bail!("sum cap must be in (0, 1]: {}", cap);
+                }
+                Ok(Self::Sum { cap })
+            }
+        }
+    }
+}
+
+/// Support of the neural scores for a label the rules derived: the score of
+/// the model output behind the core labels, and 1 for labels of rule packs,
+/// which hold or do not
+pub fn label_confidence(label: &str, neural_features: &NeuralFeatures) -> f32 {
+    match label {
+        "AI_GENERATED" => neural_features.ai_generated.clamp(0.0, 1.0),
+        "EMOTIONALLY_MANIPULATIVE" => neural_features.emotion.clamp(0.0, 1.0),
+        _ => 1.0,
+    }
+}
+
+/// Build the input relations for one message
+pub fn base_facts(
+    neural_features: &NeuralFeatures,
+    dgraph_facts: &DgraphFacts,
+    overrides: &BinOverrides,
+) -> Vec<Fact> {
+    let mut facts = neural_facts(neural_features, overrides);
+    facts.extend(stance_facts(&neural_features.stances));
+    facts.extend(mention_facts(&neural_features.entities));
+    facts.extend(claim_facts(&neural_features.claim_matches));
+    facts.extend(campaign_facts(&neural_features.campaigns));
+    facts.extend(link_facts(&neural_features.lowcred_domains));
+    facts.extend(
+        dgraph_facts
+            .iter()
+            .map(|(key, value)| Fact::new(key.as_str(), vec![value.clone()])),
+    );
+    facts
+}
+
+/// Evaluate the rule program over base facts
+///
+/// Embedded equivalent of `rules/detector.dl`, with the rule identifiers
+/// reported in `fired`:
+///
+/// ```text
+/// elevated_fakeness:medium  elevated_fakeness() :- fakeness("medium").
+/// elevated_fakeness:high    elevated_fakeness() :- fakeness("high").
+/// untrusted_source          untrusted_source()  :- !source_trusted("true").
+/// disinfo                   disinfo()           :- fakeness("high"), untrusted_source().
+/// synthetic_text            synthetic_text()    :- ai_generated("high").
+/// verdict:disinfo           verdict("DISINFO")    :- disinfo().
+/// verdict:suspicious        verdict("SUSPICIOUS") :- elevated_fakeness(), !disinfo().
+/// verdict:safe              verdict("SAFE")       :- !elevated_fakeness().
+/// ```
+pub fn evaluate(facts: &[Fact]) -> Derivation {
+    let has_fact =
+        |relation: &str, arg: &str| facts.contains(&Fact::new(relation, vec![arg.to_string()]));
+
+    let mut derived = Vec::new();
+    let mut fired: Vec<(&str, &str)> = Vec::new();
+    let fakeness_medium = has_fact("fakeness", "medium");
+    let fakeness_high = has_fact("fakeness", "high");
+    if fakeness_medium {
+        fired.push(("elevated_fakeness:medium", r#"fakeness("medium")"#));
+    }
+    if fakeness_high {
+        fired.push(("elevated_fakeness:high", r#"fakeness("high")"#));
+    }
+    let elevated_fakeness = fakeness_high || fakeness_medium;
+    if elevated_fakeness {
+        derived.push(Fact::new("elevated_fakeness", vec![]));
+    }
+
+    let untrusted_source = !has_fact("source_trusted", "true");
+    if untrusted_source {
+        derived.push(Fact::new("untrusted_source", vec![]));
+        fired.push(("untrusted_source", r#"!source_trusted("true")"#));
+    }
+
+    let synthetic_text = has_fact("ai_generated", "high");
+    if synthetic_text {
+        derived.push(Fact::new("synthetic_text", vec![]));
+        fired.push(("synthetic_text", r#"ai_generated("high")"#));
+    }
 
     // Simple rule: high fakeness + untrusted source = DISINFO
-    let (verdict, explanation) = if fakeness > 0.8 && !source_trusted {
-        (
-            "DISINFO".to_string(),
-            "High fakeness score from untrusted source".to_string(),
-        )
-    } else if fakeness > 0.6 {
-        (
-            "SUSPICIOUS".to_string(),
-            "Elevated fakeness score detected".to_string(),
-        )
+    let verdict = if fakeness_high && untrusted_source {
+        fired.push(("disinfo", r#"fakeness("high")"#));
+        fired.push(("disinfo", "untrusted_source()"));
+        fired.push(("verdict:disinfo", "disinfo()"));
+        Verdict::Disinfo
+    } else if elevated_fakeness {
+        fired.push(("verdict:suspicious", "elevated_fakeness()"));
+        fired.push(("verdict:suspicious", "!disinfo()"));
+        Verdict::Suspicious
     } else {
-        (
-            "SAFE".to_string(),
-            "No rules fired (placeholder)".to_string(),
-        )
+        fired.push(("verdict:safe", "!elevated_fakeness()"));
+        Verdict::Safe
+    };
+    derived.push(Fact::new("verdict", vec![verdict.to_string()]));
+
+    if synthetic_text {
+        derived.push(Fact::new("label", vec!["AI_GENERATED".to_string()]));
+        fired.push(("label:ai_generated", "synthetic_text()"));
+    }
+    if has_fact("emotion", "high") {
+        derived.push(Fact::new(
+            "label",
+            vec!["EMOTIONALLY_MANIPULATIVE".to_string()],
+        ));
+        fired.push(("label:emotionally_manipulative", r#"emotion("high")"#));
+    }
+
+    let fired = group_fired(fired);
+    Derivation {
+        verdict,
+        explanation: explain(verdict, synthetic_text, &fired),
+        derived,
+        fired,
+        rules_version: String::new(),
+        rules_semver: String::new(),
+    }
+}
+
+/// Human-readable explanation for a verdict
+///
+/// Synthetic text is called out separately so analysts can tell LLM-driven
+/// astroturf from human-written disinformation. The fired rules follow,
+/// e.g. `(fired: disinfo <- fakeness("high"), untrusted_source(); ...)`.
+pub fn explain(verdict: Verdict, synthetic_text: bool, fired: &[FiredRule]) -> String {
+    let mut explanation = match verdict {
+        Verdict::Disinfo => "High fakeness score from untrusted source",
+        Verdict::Suspicious => "Elevated fakeness score detected",
+        Verdict::Safe => "Fakeness score not elevated",
+        Verdict::Inconclusive => "Rules derived no single verdict",
+    }
+    .to_string();
+    if synthetic_text {
+        explanation.push_str("; text is likely AI-generated");
+    }
+    if !fired.is_empty() {
+        let rules: Vec<String> = fired.iter().map(FiredRule::to_string).collect();
+        explanation.push_str(&format!(" (fired: {})", rules.join("; ")));
+    }
+    explanation
+}
+
+/// Distinguishes scratch directories of concurrent Soufflé runs
+static RUN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Evaluate a Soufflé program over base facts with the `souffle` binary
+///
+/// Facts are written as tab-separated `<relation>.facts` files, the
+/// program's `.output` relations are read back as derived facts.
+///
+/// # Arguments
+/// * `souffle` - Path to the Soufflé interpreter
+/// * `program` - Datalog program, e.g. `rules/detector.dl`
+/// * `facts` - Base facts for one message
+pub fn run_souffle(souffle: &Path, program: &Path, facts: &[Fact]) -> Result<Derivation> {
+    let source = fs::read_to_string(program)
+        .with_context(|| format!("Failed to read {}", program.display()))?;
+
+    let scratch = Scratch::new(&std::env::temp_dir())?;
+    write_inputs(&scratch.input_dir(), &source, facts)?;
+    let output = Command::new(souffle)
+        .arg("-F")
+        .arg(scratch.input_dir())
+        .arg("-D")
+        .arg(scratch.output_dir())
+        .arg(program)
+        .output()
+        .with_context(|| format!("Failed to run {}", souffle.display()))?;
+    read_outputs(&scratch.output_dir(), &source, &output)
+}
+
+/// Compile `program` into an executable with `souffle -o`
+///
+/// Binaries are named by the program's content hash in the temp directory
+/// and reused, so a restart or a reload back to rules built before skips
+/// the C++ build, which takes far longer than a message.
+pub fn compile(souffle: &Path, program: &Path) -> Result<PathBuf> {
+    let source =
+        fs::read(program).with_context(|| format!("Failed to read {}", program.display()))?;
+    let sha256 = hex::encode(Sha256::digest(&source));
+    let binary = std::env::temp_dir().join(format!("nsai-rules-{}", &sha256[..12]));
+    if binary.exists() {
+        return Ok(binary);
+    }
+
+    let partial = binary.with_extension(format!("{}.tmp", std::process::id()));
+    let started = Instant::now();
+    let output = Command::new(souffle)
+        .arg("-o")
+        .arg(&partial)
+        .arg(program)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run {}", souffle.display()))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&partial);
+        bail!(
+            "souffle -o failed on {}: {}",
+            program.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    fs::rename(&partial, &binary)
+        .with_context(|| format!("Failed to write {}", binary.display()))?;
+    info!(
+        "Compiled {} into {} in {:.1?}",
+        program.display(),
+        binary.display(),
+        started.elapsed()
+    );
+    Ok(binary)
+}
+
+/// Evaluate base facts with an external Soufflé command, bounded by
+/// [`SOUFFLE_TIMEOUT`] and confined by `sandbox`
+///
+/// `executable` is the interpreter, which is passed `program`, when
+/// `interpret` is set, and otherwise the compiled program. A run killed
+/// for exceeding a limit fails with a [`Runaway`] error.
+pub async fn run_external(
+    executable: &Path,
+    program: &Path,
+    interpret: bool,
+    sandbox: &SouffleSandbox,
+    facts: &[Fact],
+) -> Result<Derivation> {
+    let source = tokio::fs::read_to_string(program)
+        .await
+        .with_context(|| format!("Failed to read {}", program.display()))?;
+
+    let scratch = Scratch::new(sandbox.scratch_root()?)?;
+    write_inputs(&scratch.input_dir(), &source, facts)?;
+    let mut command = tokio::process::Command::new(executable);
+    command
+        .arg("-F")
+        .arg(scratch.input_dir())
+        .arg("-D")
+        .arg(scratch.output_dir())
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    if interpret {
+        command.arg(program);
+    }
+    sandbox.apply(&mut command, &scratch.0);
+    let started = children_cpu_time();
+    let output = timeout(SOUFFLE_TIMEOUT, command.output())
+        .await
+        .map_err(|_| Runaway::Timeout)?
+        .with_context(|| format!("Failed to run {}", executable.display()))?;
+    let cpu_time = children_cpu_time().saturating_sub(started);
+    if let Some(runaway) =
+        Runaway::classify(&output.status, &output.stderr, cpu_time, sandbox.cpu_secs)
+    {
+        return Err(runaway.into());
+    }
+    read_outputs(&scratch.output_dir(), &source, &output)
+}
+
+/// Fact and output directories of one run, removed when dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(root: &Path) -> Result<Self> {
+        let scratch = Self(root.join(format!(
+            "nsai-souffle-{}-{}",
+            std::process::id(),
+            RUN_COUNTER.fetch_add(1, Ordering::Relaxed)
+        )));
+        fs::create_dir_all(scratch.input_dir())?;
+        fs::create_dir_all(scratch.output_dir())?;
+        Ok(scratch)
+    }
+
+    fn input_dir(&self) -> PathBuf {
+        self.0.join("in")
+    }
+
+    fn output_dir(&self) -> PathBuf {
+        self.0.join("out")
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write a `.facts` file for every input relation of the program
+fn write_inputs(input_dir: &Path, source: &str, facts: &[Fact]) -> Result<()> {
+    // Soufflé refuses to start if a declared input has no facts file
+    for relation in directive_relations(source, ".input") {
+        let rows: Vec<String> = facts
+            .iter()
+            .filter(|f| f.relation == relation)
+            .map(|f| f.args.join("\t"))
+            .collect();
+        let mut contents = rows.join("\n");
+        if !rows.is_empty() {
+            contents.push('\n');
+        }
+        fs::write(input_dir.join(format!("{}.facts", relation)), contents)?;
+    }
+    Ok(())
+}
+
+/// Derive the verdict from a finished run's output relations
+fn read_outputs(output_dir: &Path, source: &str, output: &Output) -> Result<Derivation> {
+    if !output.status.success() {
+        bail!(
+            "Soufflé exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let mut derived = Vec::new();
+    let mut provenance = Vec::new();
+    let outputs = directive_relations(source, ".output");
+    for relation in &outputs {
+        let csv =
+            fs::read_to_string(output_dir.join(format!("{}.csv", relation))).unwrap_or_default();
+        let facts = parse_output_relation(relation, &csv);
+        if relation == "fired" {
+            provenance = facts;
+        } else {
+            derived.extend(facts);
+        }
+    }
+    let fired = if outputs.iter().any(|relation| relation == "fired") {
+        group_fired(provenance.iter().filter_map(|f| match f.args.as_slice() {
+            [rule, premise] => Some((rule.as_str(), premise.as_str())),
+            _ => None,
+        }))
+    } else {
+        // Uninstrumented programs still name the relations they derived
+        let mut relations: Vec<&str> = derived
+            .iter()
+            .filter(|f| !["verdict", "label", "contribution"].contains(&f.relation.as_str()))
+            .map(|f| f.relation.as_str())
+            .collect();
+        relations.sort();
+        relations.dedup();
+        relations
+            .into_iter()
+            .map(|rule| FiredRule {
+                rule: rule.to_string(),
+                premises: Vec::new(),
+            })
+            .collect()
     };
 
-    Ok((verdict, explanation))
+    let verdict = single_verdict(
+        derived
+            .iter()
+            .filter(|f| f.relation == "verdict")
+            .filter_map(|f| f.args.first().map(String::as_str)),
+    )?;
+    let synthetic_text = derived.contains(&Fact::new("synthetic_text", vec![]));
+    Ok(Derivation {
+        verdict,
+        explanation: explain(verdict, synthetic_text, &fired),
+        derived,
+        fired,
+        rules_version: String::new(),
+        rules_semver: String::new(),
+    })
+}
+
+/// The verdict derived by the rules, or [`Verdict::Inconclusive`] when
+/// they derived none or several; an unknown symbol is an error
+pub fn single_verdict<'a>(symbols: impl IntoIterator<Item = &'a str>) -> Result<Verdict> {
+    let mut verdicts = Vec::new();
+    for symbol in symbols {
+        let verdict: Verdict = symbol.parse()?;
+        if !verdicts.contains(&verdict) {
+            verdicts.push(verdict);
+        }
+    }
+    match verdicts.as_slice() {
+        [verdict] => Ok(*verdict),
+        _ => Ok(Verdict::Inconclusive),
+    }
+}
+
+/// Relation names listed by `.input`/`.output` directives
+pub fn directive_relations(source: &str, directive: &str) -> Vec<String> {
+    source
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(directive))
+        .flat_map(|names| names.split(','))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Parse one tab-separated Soufflé output file into facts
+///
+/// A nullary relation that holds is written as a single empty line.
+fn parse_output_relation(relation: &str, csv: &str) -> Vec<Fact> {
+    if csv.is_empty() {
+        return Vec::new();
+    }
+    csv.lines()
+        .map(|line| {
+            let args = if line.is_empty() {
+                Vec::new()
+            } else {
+                line.split('\t').map(str::to_string).collect()
+            };
+            Fact::new(relation, args)
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::reasoning::{self, EmbeddedEngine};
+    use crate::test_support::TempDir;
+    use futures::future::BoxFuture;
 
     #[tokio::test]
     async fn test_safe_verdict() {
-        let mut features = HashMap::new();
-        features.insert("fakeness_score".to_string(), 0.3);
+        let features = NeuralFeatures {
+            fakeness: 0.3,
+            ..Default::default()
+        };
 
         let mut facts = HashMap::new();
         facts.insert("source_trusted".to_string(), "true".to_string());
 
-        let (verdict, _) = run_datalog(&features, &facts).await.unwrap();
-        assert_eq!(verdict, "SAFE");
+        let result = run_datalog(
+            &EmbeddedEngine,
+            &features,
+            &facts,
+            &BinOverrides::new(),
+            Aggregation::default(),
+            ConflictPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.verdict, Verdict::Safe);
+        assert!((result.confidence - 0.7).abs() < 1e-6);
+        assert_eq!(
+            result.fired_rules,
+            vec![FiredRule {
+                rule: "verdict:safe".to_string(),
+                premises: vec!["!elevated_fakeness()".to_string()],
+            }]
+        );
+        assert!(!result.explanation.contains("placeholder"));
+
+        // Lower thresholds make the same score elevated
+        let mut bins = BinOverrides::new();
+        bins.insert("fakeness".to_string(), vec![0.0, 0.1, 0.2, 1.0]);
+        let result = run_datalog(
+            &EmbeddedEngine,
+            &features,
+            &facts,
+            &bins,
+            Aggregation::default(),
+            ConflictPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.verdict, Verdict::Suspicious);
     }
 
     #[tokio::test]
     async fn test_disinfo_verdict() {
-        let mut features = HashMap::new();
-        features.insert("fakeness_score".to_string(), 0.9);
+        let features = NeuralFeatures {
+            fakeness: 0.9,
+            ..Default::default()
+        };
 
         let mut facts = HashMap::new();
         facts.insert("source_trusted".to_string(), "false".to_string());
 
-        let (verdict, _) = run_datalog(&features, &facts).await.unwrap();
-        assert_eq!(verdict, "DISINFO");
+        let result = run_datalog(
+            &EmbeddedEngine,
+            &features,
+            &facts,
+            &BinOverrides::new(),
+            Aggregation::default(),
+            ConflictPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.verdict, Verdict::Disinfo);
+        assert!((result.confidence - 0.9).abs() < 1e-6);
+        let rules: Vec<&str> = result.fired_rules.iter().map(|r| r.rule.as_str()).collect();
+        assert_eq!(
+            rules,
+            vec![
+                "disinfo",
+                "elevated_fakeness:high",
+                "untrusted_source",
+                "verdict:disinfo"
+            ]
+        );
+        assert_eq!(
+            result.fired_rules[0].to_string(),
+            r#"disinfo <- fakeness("high"), untrusted_source()"#
+        );
+        assert!(result
+            .explanation
+            .contains(r#"(fired: disinfo <- fakeness("high"), untrusted_source();"#));
+    }
+
+    #[test]
+    fn test_evaluate_derived_relations() {
+        let facts = vec![
+            Fact::new("fakeness", vec!["high".to_string()]),
+            Fact::new("source_trusted", vec!["true".to_string()]),
+        ];
+
+        let derivation = evaluate(&facts);
+        assert_eq!(derivation.verdict, Verdict::Suspicious);
+        assert!(derivation
+            .derived
+            .contains(&Fact::new("elevated_fakeness", vec![])));
+        assert!(!derivation
+            .derived
+            .contains(&Fact::new("untrusted_source", vec![])));
+    }
+
+    #[tokio::test]
+    async fn test_synthetic_text_is_explained() {
+        let features = NeuralFeatures {
+            fakeness: 0.9,
+            ai_generated: 0.95,
+            ..Default::default()
+        };
+
+        let mut facts = HashMap::new();
+        facts.insert("source_trusted".to_string(), "false".to_string());
+
+        let result = run_datalog(
+            &EmbeddedEngine,
+            &features,
+            &facts,
+            &BinOverrides::new(),
+            Aggregation::default(),
+            ConflictPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.verdict, Verdict::Disinfo);
+        assert!(result
+            .explanation
+            .contains("; text is likely AI-generated (fired: "));
+        assert!(result
+            .fired_rules
+            .iter()
+            .any(|r| r.rule == "synthetic_text"));
+        let labels: Vec<(&str, f32)> = result
+            .labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.confidence))
+            .collect();
+        assert_eq!(labels, vec![("DISINFO", 0.9), ("AI_GENERATED", 0.95)]);
+    }
+
+    #[tokio::test]
+    async fn test_labels_hold_whatever_the_verdict() {
+        let features = NeuralFeatures {
+            fakeness: 0.2,
+            emotion: 0.85,
+            ..Default::default()
+        };
+        let result = run_datalog(
+            &EmbeddedEngine,
+            &features,
+            &HashMap::new(),
+            &BinOverrides::new(),
+            Aggregation::default(),
+            ConflictPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.verdict, Verdict::Safe);
+        assert_eq!(
+            result.labels,
+            vec![Label {
+                name: "EMOTIONALLY_MANIPULATIVE".to_string(),
+                confidence: 0.85,
+            }]
+        );
+        assert_eq!(label_confidence("SATIRE", &features), 1.0);
+    }
+
+    #[test]
+    fn test_souffle_io_helpers() {
+        let program = ".input fakeness, emotion\n.input source_trusted\n.output verdict\n";
+        assert_eq!(
+            directive_relations(program, ".input"),
+            vec!["fakeness", "emotion", "source_trusted"]
+        );
+
+        assert_eq!(
+            parse_output_relation("untrusted_source", "\n"),
+            vec![Fact::new("untrusted_source", vec![])]
+        );
+        assert!(parse_output_relation("untrusted_source", "").is_empty());
+        assert_eq!(
+            parse_output_relation("verdict", "SAFE\n"),
+            vec![Fact::new("verdict", vec!["SAFE".to_string()])]
+        );
+
+        assert_eq!(
+            group_fired([("b", "y()"), ("a", "x()"), ("b", "x()"), ("b", "y()")]),
+            vec![
+                FiredRule {
+                    rule: "a".to_string(),
+                    premises: vec!["x()".to_string()],
+                },
+                FiredRule {
+                    rule: "b".to_string(),
+                    premises: vec!["x()".to_string(), "y()".to_string()],
+                },
+            ]
+        );
+
+        assert_eq!(single_verdict(["SAFE", "SAFE"]).unwrap(), Verdict::Safe);
+        assert_eq!(
+            single_verdict(["SAFE", "DISINFO"]).unwrap(),
+            Verdict::Inconclusive
+        );
+        assert_eq!(single_verdict([]).unwrap(), Verdict::Inconclusive);
+        assert!(single_verdict(["MAYBE"]).is_err());
+        assert_eq!("disinfo".parse::<Verdict>().unwrap(), Verdict::Disinfo);
+    }
+
+    #[tokio::test]
+    async fn test_compiled_backend_round_trip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for `souffle -o`: invoked as `<binary> -F <in> -D <out>`
+        let dir = TempDir::new("compiled");
+        let program = dir.join("detector.dl");
+        fs::write(
+            &program,
+            ".input fakeness\n.output verdict, synthetic_text\n",
+        )
+        .unwrap();
+        let binary = dir.join("detector");
+        fs::write(
+            &binary,
+            "#!/bin/sh\n\
+             if grep -q high \"$2/fakeness.facts\"; then v=DISINFO; else v=SAFE; fi\n\
+             echo $v > \"$4/verdict.csv\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let backend = SymbolicBackend::Compiled {
+            binary,
+            program: program.clone(),
+        };
+        let features = NeuralFeatures {
+            fakeness: 0.9,
+            ..Default::default()
+        };
+        // Network namespaces may be unavailable where the tests run
+        let sandbox = SouffleSandbox {
+            isolate_network: false,
+            ..Default::default()
+        };
+        let engine = reasoning::from_config(&backend, &sandbox).unwrap();
+        let result = run_datalog(
+            engine.as_ref(),
+            &features,
+            &HashMap::new(),
+            &BinOverrides::new(),
+            Aggregation::default(),
+            ConflictPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.verdict, Verdict::Disinfo);
+        // The stand-in program does not report provenance
+        assert!(result.fired_rules.is_empty());
+        assert_eq!(result.explanation, explain(Verdict::Disinfo, false, &[]));
+
+        let missing = SymbolicBackend::Souffle {
+            souffle: PathBuf::from("/nonexistent/souffle"),
+            program,
+        };
+        let missing = reasoning::from_config(&missing, &sandbox).unwrap();
+        assert!(run_datalog(
+            missing.as_ref(),
+            &features,
+            &HashMap::new(),
+            &BinOverrides::new(),
+            Aggregation::default(),
+            ConflictPolicy::default(),
+        )
+        .await
+        .is_err());
+        assert_eq!(
+            "Compiled".parse::<SymbolicKind>().unwrap(),
+            SymbolicKind::Compiled
+        );
+        assert!("prolog".parse::<SymbolicKind>().is_err());
+    }
+
+    #[test]
+    fn test_compile_on_load_reuses_builds() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("compile");
+        // Stands in for `souffle -o <binary> <program>`, logging each build
+        let souffle = dir.join("souffle");
+        fs::write(
+            &souffle,
+            format!(
+                "#!/bin/sh\necho \"$3\" >> {0}/builds\n\
+                 grep -q broken \"$3\" && exit 1\nprintf '#!/bin/sh\\n' > \"$2\"\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&souffle, fs::Permissions::from_mode(0o755)).unwrap();
+        let program = dir.join("detector.dl");
+        fs::write(&program, format!("// {:?}\n.output verdict\n", &*dir)).unwrap();
+
+        let backend = SymbolicBackend::CompileOnLoad {
+            souffle: souffle.clone(),
+            program: program.clone(),
+        };
+        let SymbolicBackend::Compiled { binary, .. } = backend.compile().unwrap() else {
+            panic!("backend not compiled");
+        };
+        assert!(binary.exists());
+        assert_eq!(compile(&souffle, &program).unwrap(), binary);
+        let builds = fs::read_to_string(dir.join("builds")).unwrap();
+        assert_eq!(builds.lines().count(), 1);
+
+        fs::write(&program, format!("// {:?}\nbroken\n", &*dir)).unwrap();
+        assert!(compile(&souffle, &program).is_err());
+        assert_eq!(
+            SymbolicBackend::Embedded.compile().unwrap(),
+            SymbolicBackend::Embedded
+        );
+        fs::remove_file(binary).unwrap();
+    }
+
+    /// Derives fixed facts whatever the input
+    struct Fixed(Vec<Fact>);
+
+    impl ReasoningEngine for Fixed {
+        fn evaluate<'a>(&'a self, _: &'a [Fact]) -> BoxFuture<'a, Result<Derivation>> {
+            Box::pin(async move {
+                Ok(Derivation {
+                    verdict: Verdict::Disinfo,
+                    explanation: String::new(),
+                    derived: self.0.clone(),
+                    fired: Vec::new(),
+                    rules_version: String::new(),
+                    rules_semver: String::new(),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_weighted_rules_set_the_confidence() {
+        let contribution = |rule: &str, weight: &str| {
+            Fact::new("contribution", vec![rule.to_string(), weight.to_string()])
+        };
+        let engine = Fixed(vec![
+            contribution("stance_vaccines", "0.5"),
+            contribution("repeat_offender", "0.6"),
+        ]);
+        let features = NeuralFeatures {
+            fakeness: 0.95,
+            ..Default::default()
+        };
+        let mut confidences = Vec::new();
+        for aggregation in ["noisy-or", "max", "sum", "sum:0.9"] {
+            let result = run_datalog(
+                &engine,
+                &features,
+                &HashMap::new(),
+                &BinOverrides::new(),
+                aggregation.parse().unwrap(),
+                ConflictPolicy::default(),
+            )
+            .await
+            .unwrap();
+            confidences.push(result.confidence);
+        }
+        let expected = [0.8, 0.6, 1.0, 0.9];
+        for (confidence, expected) in confidences.iter().zip(expected) {
+            assert!((confidence - expected).abs() < 1e-6, "{:?}", confidences);
+        }
+
+        // Without weighted rules the fakeness score is the evidence
+        let result = run_datalog(
+            &Fixed(Vec::new()),
+            &features,
+            &HashMap::new(),
+            &BinOverrides::new(),
+            Aggregation::Max,
+            ConflictPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert!((result.confidence - 0.95).abs() < 1e-6);
+        assert!("sum:1.5".parse::<Aggregation>().is_err());
+        assert!("mean".parse::<Aggregation>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_contradictory_verdicts_follow_the_policy() {
+        let verdict = |symbol: &str| Fact::new("verdict", vec![symbol.to_string()]);
+        let engine = Fixed(vec![verdict("SAFE"), verdict("DISINFO")]);
+        let features = NeuralFeatures {
+            fakeness: 0.2,
+            ..Default::default()
+        };
+        let mut outcomes = Vec::new();
+        for policy in ["severity", "confidence", "escalate"] {
+            let result = run_datalog(
+                &engine,
+                &features,
+                &HashMap::new(),
+                &BinOverrides::new(),
+                Aggregation::default(),
+                policy.parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            assert!(result
+                .explanation
+                .contains("contradictory verdicts DISINFO, SAFE"));
+            outcomes.push(result);
+        }
+        assert_eq!(outcomes[0].verdict, Verdict::Disinfo);
+        assert!(outcomes[0].explanation.ends_with("resolved by severity"));
+        // A fakeness of 0.2 supports SAFE with 0.8, DISINFO with 0.2
+        assert_eq!(outcomes[1].verdict, Verdict::Safe);
+        assert!((outcomes[1].confidence - 0.8).abs() < 1e-6);
+        assert_eq!(outcomes[2].verdict, Verdict::Inconclusive);
+        assert_eq!(outcomes[2].labels[0].name, NEEDS_REVIEW);
+        assert!("vote".parse::<ConflictPolicy>().is_err());
+
+        // A single verdict is published as derived
+        let result = run_datalog(
+            &Fixed(vec![verdict("DISINFO"), verdict("DISINFO")]),
+            &features,
+            &HashMap::new(),
+            &BinOverrides::new(),
+            Aggregation::default(),
+            ConflictPolicy::Escalate,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.verdict, Verdict::Disinfo);
+        assert!(result.labels.iter().all(|l| l.name != NEEDS_REVIEW));
     }
 }
@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Stance towards configured claims
+//!
+//! A fakeness score says little about *what* a text asserts. The stance
+//! model, an NLI cross-encoder, scores the text against each configured
+//! claim as support or denial, and every topic becomes a
+//! `stance_<topic>(level)` fact with level `supports`, `denies` or
+//! `neutral`. Rules can then combine e.g. "denies the vaccine safety
+//! consensus" with source reputation.
+
+use anyhow::{bail, Context, Result};
+use std::{fs, path::Path};
+
+use crate::fact_mapping::Fact;
+use crate::onnx_wrapper;
+
+/// Probability above which a text supports or denies a claim
+const STANCE_THRESHOLD: f32 = 0.5;
+
+/// Stance model and the claims it scores content against
+#[derive(Debug, Clone)]
+pub struct StanceConfig {
+    /// NLI model scoring (text, claim) pairs
+    pub model: String,
+    pub targets: Vec<StanceTarget>,
+}
+
+/// A claim stance is measured against, parsed from `topic: claim text`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StanceTarget {
+    /// Lowercase identifier, the suffix of the `stance_<topic>` relation
+    pub topic: String,
+    pub claim: String,
+}
+
+/// Load stance targets, one `topic: claim text` per line
+///
+/// Blank lines and lines starting with `#` are skipped.
+pub fn load_targets(path: &Path) -> Result<Vec<StanceTarget>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read stance targets {}", path.display()))?;
+    let mut targets: Vec<StanceTarget> = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((topic, claim)) = line.split_once(':') else {
+            bail!("invalid stance target (expected topic: claim): {}", line);
+        };
+        let (topic, claim) = (topic.trim(), claim.trim());
+        if topic.is_empty()
+            || !topic
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            bail!("stance topic must be a lowercase identifier: {:?}", topic);
+        }
+        if claim.is_empty() {
+            bail!("stance topic {} has no claim", topic);
+        }
+        if targets.iter().any(|t| t.topic == topic) {
+            bail!("duplicate stance topic: {}", topic);
+        }
+        targets.push(StanceTarget {
+            topic: topic.to_string(),
+            claim: claim.to_string(),
+        });
+    }
+    Ok(targets)
+}
+
+/// Stance of a text towards one topic's claim
+#[derive(Debug, Clone, PartialEq)]
+pub struct StanceScore {
+    pub topic: String,
+    /// Probability the text supports the claim
+    pub support: f32,
+    /// Probability the text denies the claim
+    pub deny: f32,
+}
+
+impl StanceScore {
+    /// `supports`, `denies` or `neutral`
+    pub fn level(&self) -> &'static str {
+        if self.deny > STANCE_THRESHOLD && self.deny >= self.support {
+            "denies"
+        } else if self.support > STANCE_THRESHOLD {
+            "supports"
+        } else {
+            "neutral"
+        }
+    }
+}
+
+/// Score a text against every configured claim
+pub async fn detect(config: &StanceConfig, text: &str) -> Result<Vec<StanceScore>> {
+    let mut scores = Vec::with_capacity(config.targets.len());
+    for target in &config.targets {
+        let (support, deny) = onnx_wrapper::run_stance(&config.model, text, &target.claim).await?;
+        scores.push(StanceScore {
+            topic: target.topic.clone(),
+            support,
+            deny,
+        });
+    }
+    Ok(scores)
+}
+
+/// One `stance_<topic>(level)` fact per scored topic
+pub fn stance_facts(scores: &[StanceScore]) -> Vec<Fact> {
+    scores
+        .iter()
+        .map(|score| {
+            Fact::new(
+                format!("stance_{}", score.topic),
+                vec![score.level().to_string()],
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn score(support: f32, deny: f32) -> StanceScore {
+        StanceScore {
+            topic: "vaccines".to_string(),
+            support,
+            deny,
+        }
+    }
+
+    #[test]
+    fn test_stance_levels() {
+        assert_eq!(score(0.9, 0.05).level(), "supports");
+        assert_eq!(score(0.1, 0.8).level(), "denies");
+        assert_eq!(score(0.3, 0.3).level(), "neutral");
+        assert_eq!(
+            stance_facts(&[score(0.1, 0.8)]),
+            vec![Fact::new("stance_vaccines", vec!["denies".to_string()])]
+        );
+    }
+
+    #[test]
+    fn test_load_targets() {
+        let dir = TempDir::new("stance");
+        let path = dir.join("targets.txt");
+        fs::write(
+            &path,
+            "# Claims rules take a stance on\nvaccines: Approved vaccines are safe\n\n\
+             election_2024: The 2024 election results were accurate\n",
+        )
+        .unwrap();
+        let targets = load_targets(&path).unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[1].topic, "election_2024");
+        assert_eq!(targets[0].claim, "Approved vaccines are safe");
+
+        fs::write(&path, "Vaccines: Approved vaccines are safe\n").unwrap();
+        assert!(load_targets(&path).is_err());
+        fs::write(&path, "vaccines: a\nvaccines: b\n").unwrap();
+        assert!(load_targets(&path).is_err());
+    }
+}
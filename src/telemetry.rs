@@ -0,0 +1,421 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Opt-in anonymized telemetry for research partners
+//!
+//! Only aggregated counts leave the service: no content, no content hashes,
+//! and source ids are replaced by salted SHA-256 digests. Every exported
+//! field must appear in the configured allowlist.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::clock::unix_now;
+
+/// Export attempts a window gets; a failed window is merged into the next
+/// and dropped once this many exports of it failed
+const MAX_EXPORT_ATTEMPTS: u32 = 3;
+
+/// Fields that may be exported, each enabled via the allowlist
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TelemetryField {
+    /// Start and end of the aggregation window
+    Window,
+    /// Total messages analyzed in the window
+    MessageCount,
+    /// Message counts per verdict
+    VerdictCounts,
+    /// Verdict counts per hashed source
+    SourceCounts,
+}
+
+impl FromStr for TelemetryField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "window" => Ok(Self::Window),
+            "message_count" => Ok(Self::MessageCount),
+            "verdict_counts" => Ok(Self::VerdictCounts),
+            "source_counts" => Ok(Self::SourceCounts),
+            other => bail!("unknown telemetry field: {}", other),
+        }
+    }
+}
+
+/// Telemetry export settings; disabled unless an endpoint is configured
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    pub endpoint: String,
+    /// HMAC-SHA256 key used to sign payloads
+    pub signing_key: String,
+    /// Salt mixed into source id hashes
+    pub source_salt: String,
+    pub interval: Duration,
+    pub allowed_fields: HashSet<TelemetryField>,
+}
+
+/// Aggregated payload sent to partners
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct TelemetryPayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_start: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_end: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verdict_counts: Option<BTreeMap<String, u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_counts: Option<BTreeMap<String, BTreeMap<String, u64>>>,
+}
+
+impl TelemetryPayload {
+    /// Combine with the payload of the window that followed this one
+    fn merge(mut self, later: TelemetryPayload) -> TelemetryPayload {
+        let add = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+        self.window_start = self.window_start.or(later.window_start);
+        self.window_end = later.window_end.or(self.window_end);
+        self.message_count = add(self.message_count, later.message_count);
+        if let Some(counts) = later.verdict_counts {
+            let merged = self.verdict_counts.get_or_insert_with(BTreeMap::new);
+            for (verdict, count) in counts {
+                *merged.entry(verdict).or_default() += count;
+            }
+        }
+        if let Some(sources) = later.source_counts {
+            let merged = self.source_counts.get_or_insert_with(BTreeMap::new);
+            for (source, counts) in sources {
+                let merged = merged.entry(source).or_default();
+                for (verdict, count) in counts {
+                    *merged.entry(verdict).or_default() += count;
+                }
+            }
+        }
+        self
+    }
+}
+
+#[derive(Default)]
+struct Window {
+    start: u64,
+    message_count: u64,
+    verdict_counts: BTreeMap<String, u64>,
+    source_counts: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+/// Accumulates anonymized counts between exports
+pub struct TelemetryAggregator {
+    source_salt: String,
+    allowed_fields: HashSet<TelemetryField>,
+    window: Mutex<Window>,
+}
+
+impl TelemetryAggregator {
+    pub fn new(source_salt: String, allowed_fields: HashSet<TelemetryField>) -> Self {
+        Self {
+            source_salt,
+            allowed_fields,
+            window: Mutex::new(Window {
+                start: unix_now(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Record one verdict, counting only allowlisted fields
+    pub fn record(&self, source_id: &str, verdict: &str) {
+        let allowed = |field: TelemetryField| self.allowed_fields.contains(&field);
+        let hashed_source =
+            allowed(TelemetryField::SourceCounts).then(|| self.hash_source(source_id));
+        let mut window = self.window.lock().unwrap();
+        if allowed(TelemetryField::MessageCount) {
+            window.message_count += 1;
+        }
+        if allowed(TelemetryField::VerdictCounts) {
+            *window
+                .verdict_counts
+                .entry(verdict.to_string())
+                .or_default() += 1;
+        }
+        if let Some(hashed_source) = hashed_source {
+            *window
+                .source_counts
+                .entry(hashed_source)
+                .or_default()
+                .entry(verdict.to_string())
+                .or_default() += 1;
+        }
+    }
+
+    /// Close the current window and build a payload holding only
+    /// allowlisted fields
+    pub fn take_payload(&self) -> TelemetryPayload {
+        let window = {
+            let mut guard = self.window.lock().unwrap();
+            std::mem::replace(
+                &mut *guard,
+                Window {
+                    start: unix_now(),
+                    ..Default::default()
+                },
+            )
+        };
+
+        let allowed = |field: TelemetryField| self.allowed_fields.contains(&field);
+        let mut payload = TelemetryPayload::default();
+        if allowed(TelemetryField::Window) {
+            payload.window_start = Some(window.start);
+            payload.window_end = Some(unix_now());
+        }
+        if allowed(TelemetryField::MessageCount) {
+            payload.message_count = Some(window.message_count);
+        }
+        if allowed(TelemetryField::VerdictCounts) {
+            payload.verdict_counts = Some(window.verdict_counts);
+        }
+        if allowed(TelemetryField::SourceCounts) {
+            payload.source_counts = Some(window.source_counts);
+        }
+        payload
+    }
+
+    fn hash_source(&self, source_id: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.source_salt.as_bytes());
+        hasher.update(source_id.as_bytes());
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// Destination for telemetry payloads
+pub trait TelemetryExporter: Send + Sync {
+    fn export<'a>(&'a self, payload: &'a TelemetryPayload) -> BoxFuture<'a, Result<()>>;
+}
+
+/// POSTs JSON payloads signed with HMAC-SHA256 in `X-Signature`
+pub struct SignedHttpExporter {
+    client: reqwest::Client,
+    endpoint: String,
+    signing_key: String,
+}
+
+impl SignedHttpExporter {
+    pub fn new(endpoint: String, signing_key: String) -> Result<Self> {
+        if !endpoint.starts_with("https://") {
+            bail!("Telemetry endpoint must use HTTPS: {}", endpoint);
+        }
+        Ok(Self {
+            client: reqwest::Client::new(),
+            endpoint,
+            signing_key,
+        })
+    }
+}
+
+impl TelemetryExporter for SignedHttpExporter {
+    fn export<'a>(&'a self, payload: &'a TelemetryPayload) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let body = serde_json::to_vec(payload)?;
+            let signature = sign(self.signing_key.as_bytes(), &body);
+            self.client
+                .post(&self.endpoint)
+                .header("Content-Type", "application/json")
+                .header("X-Signature", format!("sha256={}", signature))
+                .body(body)
+                .send()
+                .await
+                .context("Telemetry request failed")?
+                .error_for_status()
+                .context("Telemetry endpoint returned an error")?;
+            Ok(())
+        })
+    }
+}
+
+/// Hex-encoded HMAC-SHA256 of a body
+pub fn sign(key: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Periodically export and reset the aggregated window
+pub async fn run_exporter(
+    aggregator: Arc<TelemetryAggregator>,
+    exporter: Box<dyn TelemetryExporter>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    let mut unsent = None;
+    loop {
+        ticker.tick().await;
+        unsent = export_window(&aggregator, exporter.as_ref(), unsent).await;
+    }
+}
+
+/// Export the current window together with `unsent`, the earlier windows
+/// whose export failed and the number of attempts they had
+///
+/// Returns what is still unsent, `None` once exported or given up on.
+async fn export_window(
+    aggregator: &TelemetryAggregator,
+    exporter: &dyn TelemetryExporter,
+    unsent: Option<(TelemetryPayload, u32)>,
+) -> Option<(TelemetryPayload, u32)> {
+    let (payload, attempts) = match unsent {
+        Some((earlier, attempts)) => (earlier.merge(aggregator.take_payload()), attempts + 1),
+        None => (aggregator.take_payload(), 1),
+    };
+    match exporter.export(&payload).await {
+        Ok(()) => {
+            info!("Exported research telemetry window");
+            None
+        }
+        Err(e) if attempts < MAX_EXPORT_ATTEMPTS => {
+            warn!(
+                "Telemetry export failed, retrying with the next window: {}",
+                e
+            );
+            Some((payload, attempts))
+        }
+        Err(e) => {
+            warn!(
+                "Telemetry export failed {} times, dropping the window: {}",
+                attempts, e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_respects_allowlist() {
+        let allowed = HashSet::from([TelemetryField::VerdictCounts]);
+        let aggregator = TelemetryAggregator::new("salt".to_string(), allowed);
+        aggregator.record("source-1", "SAFE");
+        aggregator.record("source-1", "DISINFO");
+
+        assert!(aggregator.window.lock().unwrap().source_counts.is_empty());
+
+        let payload = aggregator.take_payload();
+        assert_eq!(payload.verdict_counts.unwrap().get("SAFE"), Some(&1));
+        assert!(payload.source_counts.is_none());
+        assert!(payload.message_count.is_none());
+        assert!(payload.window_start.is_none());
+    }
+
+    #[test]
+    fn test_sources_are_hashed() {
+        let allowed = HashSet::from([TelemetryField::SourceCounts]);
+        let aggregator = TelemetryAggregator::new("salt".to_string(), allowed);
+        aggregator.record("source-1", "SAFE");
+
+        let json = serde_json::to_string(&aggregator.take_payload()).unwrap();
+        assert!(!json.contains("source-1"));
+    }
+
+    /// Fails the first `failures` exports and records every payload
+    struct Flaky {
+        failures: Mutex<u32>,
+        payloads: Mutex<Vec<Option<u64>>>,
+    }
+
+    impl TelemetryExporter for Flaky {
+        fn export<'a>(&'a self, payload: &'a TelemetryPayload) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                self.payloads.lock().unwrap().push(payload.message_count);
+                let mut failures = self.failures.lock().unwrap();
+                if *failures > 0 {
+                    *failures -= 1;
+                    bail!("endpoint unavailable");
+                }
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_window_is_merged_into_the_next() {
+        let allowed = HashSet::from([TelemetryField::MessageCount, TelemetryField::VerdictCounts]);
+        let aggregator = TelemetryAggregator::new("salt".to_string(), allowed);
+        let exporter = Flaky {
+            failures: Mutex::new(1),
+            payloads: Mutex::new(Vec::new()),
+        };
+
+        aggregator.record("source-1", "SAFE");
+        let unsent = export_window(&aggregator, &exporter, None).await;
+        assert!(unsent.is_some());
+        aggregator.record("source-1", "DISINFO");
+        assert!(export_window(&aggregator, &exporter, unsent)
+            .await
+            .is_none());
+        assert_eq!(*exporter.payloads.lock().unwrap(), [Some(1), Some(2)]);
+
+        // Given up on after the last attempt
+        *exporter.failures.lock().unwrap() = MAX_EXPORT_ATTEMPTS;
+        let mut unsent = None;
+        for attempt in 1..=MAX_EXPORT_ATTEMPTS {
+            aggregator.record("source-1", "SAFE");
+            unsent = export_window(&aggregator, &exporter, unsent).await;
+            assert_eq!(unsent.is_some(), attempt < MAX_EXPORT_ATTEMPTS);
+        }
+        aggregator.record("source-1", "SAFE");
+        assert!(export_window(&aggregator, &exporter, None).await.is_none());
+        assert_eq!(exporter.payloads.lock().unwrap().last(), Some(&Some(1)));
+    }
+
+    #[test]
+    fn test_payload_merge_adds_counts() {
+        let earlier = TelemetryPayload {
+            window_start: Some(10),
+            window_end: Some(20),
+            message_count: Some(2),
+            verdict_counts: Some(BTreeMap::from([("SAFE".to_string(), 2)])),
+            source_counts: None,
+        };
+        let later = TelemetryPayload {
+            window_start: Some(20),
+            window_end: Some(30),
+            message_count: Some(1),
+            verdict_counts: Some(BTreeMap::from([
+                ("SAFE".to_string(), 1),
+                ("DISINFO".to_string(), 1),
+            ])),
+            source_counts: None,
+        };
+        let merged = earlier.merge(later);
+        assert_eq!(
+            (merged.window_start, merged.window_end),
+            (Some(10), Some(30))
+        );
+        assert_eq!(merged.message_count, Some(3));
+        let verdicts = merged.verdict_counts.unwrap();
+        assert_eq!((verdicts["SAFE"], verdicts["DISINFO"]), (3, 1));
+    }
+
+    #[test]
+    fn test_signature_is_stable() {
+        assert_eq!(sign(b"key", b"body"), sign(b"key", b"body"));
+        assert_ne!(sign(b"key", b"body"), sign(b"other", b"body"));
+    }
+}
@@ -0,0 +1,52 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Fixtures shared by the unit tests
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Tells apart the directories of tests running in one process
+static TEMP_DIRS: AtomicU64 = AtomicU64::new(0);
+
+/// An empty directory of one test's own, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create a directory named after `name` that no other test shares
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "nsai-{}-{}-{}",
+            name,
+            std::process::id(),
+            TEMP_DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("test directory can be created");
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
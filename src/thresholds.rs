@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Discretization thresholds from configuration
+//!
+//! The bin edges compiled into [`NEURAL_BINS`] are defaults. `NSAI_BINS`
+//! replaces them for every message, e.g. `fakeness=0:0.5:0.85:1` moves the
+//! `fakeness("medium")` and `fakeness("high")` facts to 0.5 and 0.85, and
+//! `NSAI_BINS_FILE` overrides them per language and per tenant, one line
+//! per scope:
+//!
+//! ```text
+//! # scope: relation=edges, relation=edges
+//! language de: fakeness=0:0.55:0.75:1
+//! tenant newsroom: fakeness=0:0.7:0.9:1, emotion=0:0.6:0.9:1
+//! ```
+//!
+//! A tenant's edges take precedence over its language's, which take
+//! precedence over `NSAI_BINS`, relation by relation.
+
+use anyhow::{bail, Context, Result};
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use crate::fact_mapping::{validate_overrides, BinOverrides, NEURAL_BINS};
+
+/// Bin edges of one fact relation, `relation=e0:e1:...`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinEdges {
+    pub relation: String,
+    pub edges: Vec<f32>,
+}
+
+impl FromStr for BinEdges {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((relation, edges)) = s.split_once('=') else {
+            bail!("expected relation=edges: {}", s);
+        };
+        let edges = edges
+            .split(':')
+            .map(|edge| {
+                edge.trim()
+                    .parse()
+                    .with_context(|| format!("invalid bin edge {:?}", edge))
+            })
+            .collect::<Result<Vec<f32>>>()?;
+        Ok(Self {
+            relation: relation.trim().to_string(),
+            edges,
+        })
+    }
+}
+
+/// Bin overrides in force, global and per scope
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Thresholds {
+    global: BinOverrides,
+    languages: HashMap<String, BinOverrides>,
+    tenants: HashMap<String, BinOverrides>,
+}
+
+impl Thresholds {
+    /// Validate `NSAI_BINS` and read the scoped overrides in `file`
+    pub fn load(global: &[BinEdges], file: Option<&Path>) -> Result<Self> {
+        let contents = match file {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+            None => String::new(),
+        };
+        Self::parse(global, &contents).with_context(|| match file {
+            Some(path) => format!("Invalid bin thresholds in {}", path.display()),
+            None => "Invalid NSAI_BINS".to_string(),
+        })
+    }
+
+    fn parse(global: &[BinEdges], contents: &str) -> Result<Self> {
+        let mut thresholds = Self {
+            global: overrides(global.iter().cloned())?,
+            ..Default::default()
+        };
+        let lines = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        for line in lines {
+            let Some((scope, rest)) = line.split_once(':') else {
+                bail!("expected scope: relation=edges, ...: {}", line);
+            };
+            let (scoped, key) = match scope.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["language", code] => (&mut thresholds.languages, code.to_lowercase()),
+                ["tenant", tenant] => (&mut thresholds.tenants, tenant.to_string()),
+                _ => bail!(
+                    "unknown scope {:?}, expected language <code> or tenant <id>",
+                    scope
+                ),
+            };
+            let edges = rest
+                .split(',')
+                .map(str::trim)
+                .filter(|edges| !edges.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<BinEdges>>>()?;
+            if scoped.insert(key, overrides(edges)?).is_some() {
+                bail!("duplicate bin thresholds for {}", scope.trim());
+            }
+        }
+        Ok(thresholds)
+    }
+
+    /// Overrides of `NSAI_BINS` alone, as every message without a scoped
+    /// override uses them
+    pub fn global(&self) -> &BinOverrides {
+        &self.global
+    }
+
+    /// Overrides for a message in `language` from `tenant`
+    pub fn resolve(&self, language: &str, tenant: &str) -> BinOverrides {
+        let mut resolved = self.global.clone();
+        for scoped in [self.languages.get(language), self.tenants.get(tenant)] {
+            resolved.extend(
+                scoped
+                    .into_iter()
+                    .flatten()
+                    .map(|(r, e)| (r.clone(), e.clone())),
+            );
+        }
+        resolved
+    }
+
+    /// Number of scopes with their own thresholds
+    pub fn scopes(&self) -> usize {
+        self.languages.len() + self.tenants.len()
+    }
+}
+
+fn overrides(edges: impl IntoIterator<Item = BinEdges>) -> Result<BinOverrides> {
+    let mut overrides = BinOverrides::new();
+    for BinEdges { relation, edges } in edges {
+        if overrides.insert(relation.clone(), edges).is_some() {
+            bail!("{} has more than one set of bin edges", relation);
+        }
+    }
+    validate_overrides(NEURAL_BINS, &overrides)?;
+    Ok(overrides)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edges(spec: &str) -> BinEdges {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn test_scoped_thresholds_take_precedence() {
+        let thresholds = Thresholds::parse(
+            &[edges("fakeness=0:0.5:0.85:1"), edges("emotion=0:0.4:0.7:1")],
+            "# stricter for partners\n\
+             language DE: fakeness=0:0.55:0.75:1\n\
+             tenant newsroom: fakeness=0:0.7:0.9:1\n",
+        )
+        .unwrap();
+        assert_eq!(thresholds.scopes(), 2);
+
+        assert_eq!(
+            thresholds.resolve("en", "blog")["fakeness"],
+            vec![0.0, 0.5, 0.85, 1.0]
+        );
+        assert_eq!(
+            thresholds.resolve("de", "blog")["fakeness"],
+            vec![0.0, 0.55, 0.75, 1.0]
+        );
+        let newsroom = thresholds.resolve("de", "newsroom");
+        assert_eq!(newsroom["fakeness"], vec![0.0, 0.7, 0.9, 1.0]);
+        assert_eq!(newsroom["emotion"], vec![0.0, 0.4, 0.7, 1.0]);
+    }
+
+    #[test]
+    fn test_invalid_thresholds_are_rejected() {
+        assert!("fakeness".parse::<BinEdges>().is_err());
+        assert!("fakeness=0:x:1".parse::<BinEdges>().is_err());
+        // Wrong arity, unknown relation, descending edges
+        assert!(Thresholds::parse(&[edges("fakeness=0:0.5:1")], "").is_err());
+        assert!(Thresholds::parse(&[edges("sarcasm=0:0.5:1")], "").is_err());
+        assert!(Thresholds::parse(&[], "language de: fakeness=0:0.9:0.8:1").is_err());
+        assert!(Thresholds::parse(&[], "region eu: fakeness=0:0.6:0.8:1").is_err());
+        assert!(Thresholds::parse(
+            &[],
+            "tenant a: fakeness=0:0.6:0.8:1\ntenant a: emotion=0:0.5:0.8:1"
+        )
+        .is_err());
+    }
+}
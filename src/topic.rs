@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Topic classification and topic-specialized models
+//!
+//! Messages are classified into a topic (e.g. `health`, `election`) and
+//! routed to a model registered for it, falling back to the general
+//! ensemble. To justify keeping a specialization, a sample of its messages
+//! is also run through the general ensemble and the verdicts compared per
+//! topic.
+
+use anyhow::{bail, Result};
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::time::timeout;
+use tracing::warn;
+
+use crate::calibration::Calibration;
+use crate::fact_mapping::BinOverrides;
+use crate::metrics::Metrics;
+use crate::onnx_wrapper::{Ensemble, ModelSpec, NeuralFeatures};
+use crate::reasoning::ReasoningEngine;
+use crate::shadow;
+use crate::souffle_wrapper::{self, Aggregation, ConflictPolicy, DgraphFacts, Verdict};
+
+/// Topic of content no specialization covers
+pub const GENERAL: &str = "general";
+
+/// Keywords voting for each topic
+const KEYWORDS: &[(&str, &[&str])] = &[
+    (
+        "health",
+        &[
+            "vaccine", "vaccines", "virus", "covid", "cure", "cancer", "doctor", "doctors",
+            "hospital", "disease", "pandemic", "medicine", "health",
+        ],
+    ),
+    (
+        "election",
+        &[
+            "election",
+            "elections",
+            "vote",
+            "votes",
+            "voting",
+            "ballot",
+            "ballots",
+            "poll",
+            "polls",
+            "candidate",
+            "fraud",
+            "campaign",
+            "electoral",
+        ],
+    ),
+];
+
+/// Fewest keyword hits needed to assign a topic
+const MIN_KEYWORDS: usize = 2;
+
+/// Classify a text into a topic, or [`GENERAL`]
+pub fn classify(text: &str) -> &'static str {
+    // Placeholder implementation
+    // In production, this would run the topic classifier model on the
+    // text and pick the most likely topic above a confidence threshold.
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let mut best = (GENERAL, 0);
+    for (topic, keywords) in KEYWORDS {
+        let hits = words
+            .iter()
+            .filter(|w| keywords.contains(&w.as_str()))
+            .count();
+        if hits > best.1 {
+            best = (topic, hits);
+        }
+    }
+    if best.1 >= MIN_KEYWORDS {
+        best.0
+    } else {
+        GENERAL
+    }
+}
+
+/// A model specialized for one topic, parsed from `topic=name:path[:weight]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicModel {
+    pub topic: String,
+    pub model: ModelSpec,
+}
+
+impl FromStr for TopicModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((topic, model)) = s.split_once('=') else {
+            bail!(
+                "invalid topic model (expected topic=name:path[:weight]): {}",
+                s
+            );
+        };
+        let topic = topic.trim().to_lowercase();
+        if !KEYWORDS.iter().any(|(known, _)| *known == topic) {
+            bail!("unknown topic for model: {}", s);
+        }
+        Ok(Self {
+            topic,
+            model: model.parse()?,
+        })
+    }
+}
+
+/// Compares specialized verdicts with the general ensemble's on a sample
+pub struct TopicMonitor {
+    general: Arc<Ensemble>,
+    sample_rate: f64,
+    deadline: Duration,
+    calibration: Calibration,
+    reasoning: Arc<dyn ReasoningEngine>,
+    conflicts: ConflictPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl TopicMonitor {
+    pub fn new(
+        general: Arc<Ensemble>,
+        sample_rate: f64,
+        deadline: Duration,
+        calibration: Calibration,
+        reasoning: Arc<dyn ReasoningEngine>,
+        conflicts: ConflictPolicy,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            general,
+            sample_rate,
+            deadline,
+            calibration,
+            reasoning,
+            conflicts,
+            metrics,
+        }
+    }
+
+    /// Run the general ensemble on a sampled specialized message
+    ///
+    /// Returns immediately; the comparison runs on a spawned task.
+    pub fn observe(
+        &self,
+        topic: &str,
+        content_hash: &str,
+        specialized: &NeuralFeatures,
+        verdict: Verdict,
+        dgraph_facts: &DgraphFacts,
+        bins: &BinOverrides,
+    ) {
+        if !shadow::in_sample(content_hash, self.sample_rate) {
+            return;
+        }
+
+        let general = Arc::clone(&self.general);
+        let metrics = Arc::clone(&self.metrics);
+        let calibration = self.calibration.clone();
+        let reasoning = Arc::clone(&self.reasoning);
+        let conflicts = self.conflicts;
+        let deadline = self.deadline;
+        let topic = topic.to_string();
+        let content_hash = content_hash.to_string();
+        let visual_artifact = specialized.visual_artifact;
+        let dgraph_facts = dgraph_facts.clone();
+        let bins = bins.clone();
+
+        tokio::spawn(async move {
+            let mut features = match timeout(deadline, general.run(&content_hash)).await {
+                Ok(Ok(features)) => features,
+                Ok(Err(e)) => {
+                    warn!("General comparison for {} failed: {}", content_hash, e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("General comparison for {} timed out", content_hash);
+                    return;
+                }
+            };
+            calibration.apply(&mut features);
+            features.visual_artifact = visual_artifact;
+
+            let general_verdict = match souffle_wrapper::run_datalog(
+                reasoning.as_ref(),
+                &features,
+                &dgraph_facts,
+                &bins,
+                // Only the verdict is compared
+                Aggregation::default(),
+                conflicts,
+            )
+            .await
+            {
+                Ok(result) => result.verdict,
+                Err(e) => {
+                    warn!(
+                        "General comparison rules for {} failed: {}",
+                        content_hash, e
+                    );
+                    return;
+                }
+            };
+
+            metrics
+                .topic_comparisons
+                .with_label_values(&[topic.as_str()])
+                .inc();
+            if general_verdict != verdict {
+                metrics
+                    .topic_disagreements
+                    .with_label_values(&[topic.as_str()])
+                    .inc();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            classify("Doctors confirm the new vaccine alters your DNA"),
+            "health"
+        );
+        assert_eq!(
+            classify("Millions of ballots were destroyed before the election"),
+            "election"
+        );
+        assert_eq!(classify("Local bakery wins award"), GENERAL);
+        // A single keyword is not enough
+        assert_eq!(classify("The virus of boredom"), GENERAL);
+    }
+
+    #[test]
+    fn test_topic_model_parse() {
+        let model: TopicModel = "Health=fakeness:models/health.onnx".parse().unwrap();
+        assert_eq!(model.topic, "health");
+        assert_eq!(model.model.name, "fakeness");
+
+        assert!("sports=fakeness:models/sports.onnx"
+            .parse::<TopicModel>()
+            .is_err());
+        assert!("fakeness:models/health.onnx".parse::<TopicModel>().is_err());
+    }
+}
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Transports results are sent over
+//!
+//! The pipeline sends results, shadow records and fact dumps through a
+//! [`Transport`] rather than a NATS client, so the same pipeline serves a
+//! NATS deployment and, with `NSAI_TRANSPORT=kafka`, one whose estate is
+//! Kafka only. Destinations are the configured subjects either way; on
+//! Kafka they name topics, e.g. `disinfo.verdicts`.
+//!
+//! Inputs of a transport other than NATS reach the pipeline as
+//! [`Received`] inputs. The features built on JetStream, such as the
+//! key-value buckets and the object store, need the NATS transport.
+
+use anyhow::Result;
+use async_nats::HeaderMap;
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+/// Sends payloads to subjects or topics
+pub trait Transport: Send + Sync {
+    /// Name in logs and the pipeline graph, e.g. `nats`
+    fn name(&self) -> &'static str;
+
+    /// Send `payload` with `headers` to `destination`
+    fn send<'a>(
+        &'a self,
+        destination: &'a str,
+        headers: HeaderMap,
+        payload: Vec<u8>,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// The NATS client, for the features built on JetStream; `None` on
+    /// other transports
+    fn nats(&self) -> Option<&async_nats::Client> {
+        None
+    }
+}
+
+/// Core NATS, the default transport
+pub struct NatsTransport {
+    client: async_nats::Client,
+}
+
+impl NatsTransport {
+    pub fn new(client: async_nats::Client) -> Self {
+        Self { client }
+    }
+}
+
+impl Transport for NatsTransport {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    fn send<'a>(
+        &'a self,
+        destination: &'a str,
+        headers: HeaderMap,
+        payload: Vec<u8>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.client
+                .publish_with_headers(destination.to_string(), headers, payload.into())
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn nats(&self) -> Option<&async_nats::Client> {
+        Some(&self.client)
+    }
+}
+
+/// An input received over a transport other than NATS
+///
+/// Such inputs are settled by the transport once processed; a transient
+/// failure hands them back with [`redeliver`](Received::redeliver).
+pub trait Received: Send + Sync {
+    fn payload(&self) -> &[u8];
+
+    fn headers(&self) -> Option<&HeaderMap>;
+
+    /// Topic the input was received on
+    fn origin(&self) -> &str;
+
+    /// Names the delivery, e.g. by topic, partition and offset
+    fn id(&self) -> &str;
+
+    /// How many times the input has been delivered, this delivery included
+    fn deliveries(&self) -> u64;
+
+    /// Deliver the input again after `delay`
+    fn redeliver(&self, delay: Duration) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Kafka transport settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaConfig {
+    /// Bootstrap brokers, comma-separated `host:port`
+    pub brokers: String,
+    /// Consumer group the replicas share the input partitions in
+    pub group: String,
+    /// Topic inputs are consumed from
+    pub input_topic: String,
+    /// Further librdkafka properties, e.g. `security.protocol=SASL_SSL`
+    pub properties: Vec<KafkaProperty>,
+    /// Deadline of producing a record
+    pub send_timeout: Duration,
+}
+
+/// A librdkafka property, parsed from `key=value`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaProperty {
+    pub key: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for KafkaProperty {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(Self {
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            }),
+            _ => anyhow::bail!("invalid Kafka property (expected key=value): {}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kafka_properties() {
+        let property: KafkaProperty = "security.protocol = SASL_SSL".parse().unwrap();
+        assert_eq!(property.key, "security.protocol");
+        assert_eq!(property.value, "SASL_SSL");
+        let empty: KafkaProperty = "sasl.password=".parse().unwrap();
+        assert_eq!(empty.value, "");
+        assert!("security.protocol".parse::<KafkaProperty>().is_err());
+        assert!("=SASL_SSL".parse::<KafkaProperty>().is_err());
+    }
+}
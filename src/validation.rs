@@ -0,0 +1,230 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Validation of incoming analysis inputs
+//!
+//! Inputs that cannot be analysed are not dropped silently: the pipeline
+//! publishes a `REJECTED` result carrying a [`RejectReason`] code so
+//! producers can see what was wrong with their message.
+
+use std::fmt;
+
+use crate::model_pb::AnalysisInput;
+
+/// Verdict published for rejected inputs
+pub const REJECTED: &str = "REJECTED";
+
+/// Longest accepted content hash, in hex digits
+const MAX_HASH_LEN: usize = 128;
+
+/// Why an input was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// Payload is not a decodable `AnalysisInput`
+    MalformedPayload,
+    /// Neither content text nor an image to analyse
+    EmptyContent,
+    /// Content hash is empty, not hex or too long
+    InvalidHash,
+    /// Content object lacks a name or is in a bucket not configured
+    ObjectNotAllowed,
+    /// Source id belongs to a tenant that is not configured
+    UnknownTenant,
+    /// Source id belongs to another tenant than the subject it came on
+    TenantMismatch,
+    /// Text was to be fetched by hash, but the content store has none
+    ContentNotFound,
+    /// Content object is larger than the configured limit
+    ContentTooLarge,
+    /// Tenant or global daily quota is used up
+    QuotaExceeded,
+}
+
+impl RejectReason {
+    /// Stable code published in `AnalysisResult.rejection_reason`
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::MalformedPayload => "MALFORMED_PAYLOAD",
+            Self::EmptyContent => "EMPTY_CONTENT",
+            Self::InvalidHash => "INVALID_HASH",
+            Self::ObjectNotAllowed => "OBJECT_NOT_ALLOWED",
+            Self::UnknownTenant => "UNKNOWN_TENANT",
+            Self::TenantMismatch => "TENANT_MISMATCH",
+            Self::ContentNotFound => "CONTENT_NOT_FOUND",
+            Self::ContentTooLarge => "CONTENT_TOO_LARGE",
+            Self::QuotaExceeded => "QUOTA_EXCEEDED",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Input validation settings
+#[derive(Debug, Clone, Default)]
+pub struct ValidationConfig {
+    /// Tenants accepted as the `<tenant>:` prefix of source ids; any
+    /// tenant is accepted when unset
+    pub tenants: Option<Vec<String>>,
+    /// Empty text is accepted because it is fetched by content hash
+    pub fetch_content: bool,
+    /// Object store buckets inputs may name in `content_object`
+    pub object_buckets: Vec<String>,
+}
+
+/// Content hashes are hex digests
+pub fn is_valid_hash(content_hash: &str) -> bool {
+    !content_hash.is_empty()
+        && content_hash.len() <= MAX_HASH_LEN
+        && content_hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Tenant of a source id, the part before the first `:`
+pub fn tenant_of(source_id: &str) -> &str {
+    source_id
+        .split_once(':')
+        .map_or(source_id, |(tenant, _)| tenant)
+}
+
+/// Check a decoded input before any inference runs
+pub fn validate(input: &AnalysisInput, config: &ValidationConfig) -> Result<(), RejectReason> {
+    if !config.fetch_content
+        && input.content_text.trim().is_empty()
+        && input.image_url.trim().is_empty()
+        && input.content_object.is_none()
+    {
+        return Err(RejectReason::EmptyContent);
+    }
+    if !is_valid_hash(&input.content_hash) {
+        return Err(RejectReason::InvalidHash);
+    }
+    if let Some(object) = &input.content_object {
+        if object.name.is_empty() || !config.object_buckets.contains(&object.bucket) {
+            return Err(RejectReason::ObjectNotAllowed);
+        }
+    }
+    if let Some(tenants) = &config.tenants {
+        let tenant = tenant_of(&input.source_id);
+        if !tenants.iter().any(|t| t == tenant) {
+            return Err(RejectReason::UnknownTenant);
+        }
+    }
+    Ok(())
+}
+
+/// Check that an input received on the subject of `subject_tenant`, if
+/// any, has a source id of that tenant
+///
+/// Publish permissions are granted per subject, so a tenant must not be
+/// able to pass its inputs off as another's by choosing their source ids.
+pub fn check_subject_tenant(
+    input: &AnalysisInput,
+    subject_tenant: Option<&str>,
+) -> Result<(), RejectReason> {
+    match subject_tenant {
+        Some(tenant) if tenant != tenant_of(&input.source_id) => Err(RejectReason::TenantMismatch),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_pb::ObjectRef;
+
+    fn input() -> AnalysisInput {
+        AnalysisInput {
+            content_hash: "ab12cd".to_string(),
+            content_text: "Some text".to_string(),
+            source_id: "newsroom:feed-1".to_string(),
+            image_url: String::new(),
+            content_object: None,
+        }
+    }
+
+    #[test]
+    fn test_rejection_reasons() {
+        let config = ValidationConfig::default();
+        assert_eq!(validate(&input(), &config), Ok(()));
+
+        let empty = AnalysisInput {
+            content_text: "  ".to_string(),
+            ..input()
+        };
+        assert_eq!(validate(&empty, &config), Err(RejectReason::EmptyContent));
+        let image_only = AnalysisInput {
+            image_url: "https://example.com/a.png".to_string(),
+            ..empty.clone()
+        };
+        assert_eq!(validate(&image_only, &config), Ok(()));
+        let fetched = ValidationConfig {
+            fetch_content: true,
+            ..Default::default()
+        };
+        assert_eq!(validate(&empty, &fetched), Ok(()));
+
+        for hash in ["", "not-hex", &"a".repeat(MAX_HASH_LEN + 1)] {
+            let bad = AnalysisInput {
+                content_hash: hash.to_string(),
+                ..input()
+            };
+            assert_eq!(validate(&bad, &config), Err(RejectReason::InvalidHash));
+        }
+    }
+
+    #[test]
+    fn test_content_objects() {
+        let config = ValidationConfig {
+            object_buckets: vec!["articles".to_string()],
+            ..Default::default()
+        };
+        let object = |bucket: &str, name: &str| AnalysisInput {
+            content_text: String::new(),
+            content_object: Some(ObjectRef {
+                bucket: bucket.to_string(),
+                name: name.to_string(),
+            }),
+            ..input()
+        };
+        assert_eq!(validate(&object("articles", "ab12cd"), &config), Ok(()));
+        assert_eq!(
+            validate(&object("secrets", "ab12cd"), &config),
+            Err(RejectReason::ObjectNotAllowed)
+        );
+        assert_eq!(
+            validate(&object("articles", ""), &config),
+            Err(RejectReason::ObjectNotAllowed)
+        );
+        assert_eq!(
+            validate(&object("articles", "ab12cd"), &ValidationConfig::default()),
+            Err(RejectReason::ObjectNotAllowed)
+        );
+    }
+
+    #[test]
+    fn test_unknown_tenant() {
+        let config = ValidationConfig {
+            tenants: Some(vec!["newsroom".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(validate(&input(), &config), Ok(()));
+
+        let other = AnalysisInput {
+            source_id: "other:feed-1".to_string(),
+            ..input()
+        };
+        assert_eq!(validate(&other, &config), Err(RejectReason::UnknownTenant));
+        assert_eq!(tenant_of("newsroom"), "newsroom");
+        assert_eq!(RejectReason::UnknownTenant.to_string(), "UNKNOWN_TENANT");
+
+        assert_eq!(check_subject_tenant(&input(), Some("newsroom")), Ok(()));
+        assert_eq!(check_subject_tenant(&other, None), Ok(()));
+        assert_eq!(
+            check_subject_tenant(&other, Some("newsroom")),
+            Err(RejectReason::TenantMismatch)
+        );
+    }
+}
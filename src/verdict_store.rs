@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Latest verdict per content in a JetStream key-value bucket
+//!
+//! Results are published as a stream of events; a service that only needs
+//! to know whether some content has been analysed, and how, would have to
+//! keep its own table of them. With a verdict bucket configured, every
+//! published analysis also overwrites the entry of its content hash, so
+//! `nats kv get` or any JetStream client answers the question directly.
+//!
+//! Entries are `AnalysisResult` protobufs as published, without the
+//! embedding, which is large and of no use to a lookup. Writes are
+//! best-effort: a result whose entry cannot be written is still published.
+
+use anyhow::{Context, Result};
+use async_nats::jetstream::{self, kv};
+use prost::Message;
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+use crate::metrics::Metrics;
+use crate::model_pb::AnalysisResult;
+
+/// Verdict bucket settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerdictStoreConfig {
+    /// JetStream key-value bucket, created when missing
+    pub bucket: String,
+    /// How long an entry is kept after its last write; forever when `None`
+    pub ttl: Option<Duration>,
+    /// Past verdicts kept per content, the latest included
+    pub history: i64,
+}
+
+/// Writes the latest verdict of every analysed content
+pub struct VerdictStore {
+    store: kv::Store,
+    metrics: Arc<Metrics>,
+}
+
+impl VerdictStore {
+    /// Open the bucket, creating it when missing
+    pub async fn new(
+        config: &VerdictStoreConfig,
+        jetstream: &jetstream::Context,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let store = match jetstream.get_key_value(&config.bucket).await {
+            Ok(store) => store,
+            Err(_) => {
+                info!("Creating verdict bucket {}", config.bucket);
+                jetstream
+                    .create_key_value(kv::Config {
+                        bucket: config.bucket.clone(),
+                        description: "Latest verdict by content hash".to_string(),
+                        max_age: config.ttl.unwrap_or_default(),
+                        history: config.history,
+                        ..Default::default()
+                    })
+                    .await
+                    .with_context(|| format!("Failed to open key-value bucket {}", config.bucket))?
+            }
+        };
+        Ok(Self { store, metrics })
+    }
+
+    /// Name of the bucket, for the pipeline graph
+    pub fn bucket(&self) -> &str {
+        &self.store.name
+    }
+
+    /// Make `result` the latest verdict of its content
+    pub async fn put(&self, result: &AnalysisResult) {
+        let outcome = match self
+            .store
+            .put(&result.content_hash, entry(result).encode_to_vec().into())
+            .await
+        {
+            Ok(_) => "written",
+            Err(e) => {
+                warn!("Verdict of {} not stored: {}", result.content_hash, e);
+                "failed"
+            }
+        };
+        self.metrics
+            .verdict_store_writes
+            .with_label_values(&[outcome])
+            .inc();
+    }
+}
+
+/// What is stored of `result`
+fn entry(result: &AnalysisResult) -> AnalysisResult {
+    let mut entry = result.clone();
+    if let Some(features) = entry.neural_features.as_mut() {
+        features.embedding.clear();
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_pb::NeuralFeatures;
+
+    #[test]
+    fn test_entries_leave_out_the_embedding() {
+        let result = AnalysisResult {
+            content_hash: "ab12".to_string(),
+            verdict: "DISINFO".to_string(),
+            neural_features: Some(NeuralFeatures {
+                fakeness_score: 0.9,
+                embedding: vec![0.1; 768],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let stored = AnalysisResult::decode(entry(&result).encode_to_vec().as_slice()).unwrap();
+        assert_eq!(stored.verdict, "DISINFO");
+        let features = stored.neural_features.unwrap();
+        assert_eq!(features.fakeness_score, 0.9);
+        assert!(features.embedding.is_empty());
+    }
+}
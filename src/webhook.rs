@@ -0,0 +1,818 @@
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-FileCopyrightText: 2024 Hyperpolymath
+
+//! Batched webhook sinks
+//!
+//! Partners and analytics pipelines receive selected verdicts by HTTP POST.
+//! A burst of `DISINFO` verdicts during a major event must not turn into
+//! thousands of individual calls that get us rate-limited, so results are
+//! batched per destination: a batch is sent once it holds `max_batch`
+//! results or its first result is `max_delay` old, and at most
+//! `concurrency` batches per destination are in flight. A destination that
+//! falls behind stops taking batches, its queue fills, and further results
+//! spill to its outbox file instead of piling up in memory; so do batches
+//! that could not be delivered. The outbox is written on the blocking pool,
+//! is capped in size, and is drained a batch at a time once the destination
+//! has caught up.
+//!
+//! A batch is signed with HMAC-SHA256 in `X-Signature` when a secret is
+//! set, as telemetry is, so a destination can tell our calls from forged
+//! ones. The signature covers `<X-Timestamp>.<body>`, so a destination can
+//! also reject replayed calls by their age. Redirects are not followed, a
+//! signed batch goes to the configured URL or nowhere. A delivery failing
+//! on the network, with `429` or with a `5xx` is
+//! retried with a doubling backoff before its results spill. Any other
+//! rejection would not change on retry, nor on every drain of the outbox,
+//! so those results are dropped and counted as `rejected`.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    time::{timeout_at, Instant},
+};
+use tracing::{info, warn};
+
+use crate::clock::unix_now;
+use crate::metrics::Metrics;
+use crate::model_pb::AnalysisResult;
+use crate::souffle_wrapper::Verdict;
+use crate::telemetry;
+
+/// Deadline for delivering one batch
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often an idle destination retries its outbox
+const OUTBOX_DRAIN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Header carrying the HMAC-SHA256 of a batch
+const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Header carrying the unix time a batch was signed at
+const TIMESTAMP_HEADER: &str = "X-Timestamp";
+
+/// Overflowing results written to the outbox at once
+const SPILL_BATCH: usize = 1000;
+
+/// Webhook settings shared by all destinations
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// HTTPS endpoints receiving batches
+    pub urls: Vec<String>,
+    /// Verdicts, or labels, sent to the webhooks
+    pub verdicts: HashSet<String>,
+    /// Results per batch
+    pub max_batch: usize,
+    /// Longest a result waits for its batch to fill
+    pub max_delay: Duration,
+    /// Batches in flight per destination
+    pub concurrency: usize,
+    /// Results queued per destination before spilling to the outbox
+    pub queue: usize,
+    /// Directory of the outbox files; overflow is dropped when unset
+    pub outbox: Option<PathBuf>,
+    /// Size an outbox file may grow to; further overflow is dropped
+    pub outbox_max_bytes: u64,
+    /// Also send results whose verdict is at least this severe
+    pub min_severity: Option<Verdict>,
+    /// HMAC-SHA256 key batches are signed with; unsigned when unset
+    pub secret: Option<String>,
+    /// Retries of a batch failing transiently before it spills
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each further one
+    pub retry_backoff: Duration,
+}
+
+/// A result as delivered to webhooks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub content_hash: String,
+    pub source_id: String,
+    pub verdict: String,
+    pub explanation: String,
+    pub fakeness_score: f32,
+    pub decision_context: String,
+    pub annotations: BTreeMap<String, String>,
+    /// Missing from events spilled before rules were versioned
+    #[serde(default)]
+    pub rules_version: String,
+    /// Missing from events spilled before rules had a manifest
+    #[serde(default)]
+    pub rules_semver: String,
+    #[serde(default)]
+    pub model_version: String,
+    /// Confidence by label, e.g. `{"DISINFO": 0.9, "AI_GENERATED": 0.8}`
+    #[serde(default)]
+    pub labels: BTreeMap<String, f32>,
+}
+
+impl From<&AnalysisResult> for WebhookEvent {
+    fn from(result: &AnalysisResult) -> Self {
+        Self {
+            content_hash: result.content_hash.clone(),
+            source_id: result.source_id.clone(),
+            verdict: result.verdict.clone(),
+            explanation: result.explanation.clone(),
+            fakeness_score: result
+                .neural_features
+                .as_ref()
+                .map_or(0.0, |f| f.fakeness_score),
+            decision_context: result.decision_context.clone(),
+            annotations: result.annotations.clone(),
+            rules_version: result.rules_version.clone(),
+            rules_semver: result.rules_semver.clone(),
+            model_version: result.model_version.clone(),
+            labels: result
+                .labels
+                .iter()
+                .map(|label| (label.name.clone(), label.confidence))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Batch<'a> {
+    results: &'a [WebhookEvent],
+}
+
+/// Undelivered events of one destination, one JSON object per line
+struct Outbox {
+    path: PathBuf,
+    max_bytes: u64,
+    lock: Mutex<()>,
+}
+
+impl Outbox {
+    fn new(dir: &Path, url: &str, max_bytes: u64) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create outbox {}", dir.display()))?;
+        let key = hex::encode(Sha256::digest(url.as_bytes()));
+        Ok(Self {
+            path: dir.join(format!("webhook-{}.jsonl", &key[..12])),
+            max_bytes,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Append events, unless the outbox would outgrow its limit
+    fn append(&self, events: &[WebhookEvent]) -> Result<()> {
+        let mut lines = Vec::new();
+        for event in events {
+            serde_json::to_writer(&mut lines, event)?;
+            lines.push(b'\n');
+        }
+        let _guard = self.lock.lock().unwrap();
+        let size = fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+        if size + lines.len() as u64 > self.max_bytes {
+            bail!(
+                "outbox {} holds {} of {} bytes",
+                self.path.display(),
+                size,
+                self.max_bytes
+            );
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&lines)
+            .with_context(|| format!("Failed to write outbox {}", self.path.display()))
+    }
+
+    /// Move the outbox aside for draining, so events spilled meanwhile
+    /// start a new file
+    ///
+    /// # Returns
+    /// The file to drain, `None` if nothing is pending. A file left by an
+    /// interrupted drain is drained first.
+    fn take(&self) -> Result<Option<PathBuf>> {
+        let _guard = self.lock.lock().unwrap();
+        let draining = self.path.with_extension("jsonl.draining");
+        if draining.exists() {
+            return Ok(Some(draining));
+        }
+        match fs::rename(&self.path, &draining) {
+            Ok(()) => Ok(Some(draining)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to take outbox {}", self.path.display()))
+            }
+        }
+    }
+}
+
+/// Up to `max_batch` events from a taken outbox file, none at its end
+async fn read_batch(
+    reader: &mut BufReader<tokio::fs::File>,
+    max_batch: usize,
+) -> Result<Vec<WebhookEvent>> {
+    let mut batch = Vec::new();
+    let mut line = Vec::new();
+    while batch.len() < max_batch {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        // A line cut short by a crash is skipped, not fatal
+        if let Ok(event) = serde_json::from_slice(&line) {
+            batch.push(event);
+        }
+    }
+    Ok(batch)
+}
+
+struct Destination {
+    /// Host of the URL, used as the metric label
+    label: String,
+    queue: mpsc::Sender<WebhookEvent>,
+    /// Results the queue had no room for, on their way to the outbox
+    overflow: Option<mpsc::Sender<WebhookEvent>>,
+}
+
+/// Fans results out to the batching task of every destination
+pub struct WebhookSinks {
+    verdicts: HashSet<String>,
+    min_severity: Option<Verdict>,
+    destinations: Vec<Destination>,
+    metrics: Arc<Metrics>,
+}
+
+impl WebhookSinks {
+    /// Start a batching task per destination
+    pub fn spawn(config: &WebhookConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .context("Failed to build webhook HTTP client")?;
+        let mut destinations = Vec::new();
+        for url in &config.urls {
+            let label = match reqwest::Url::parse(url) {
+                Ok(parsed) if parsed.scheme() == "https" => {
+                    parsed.host_str().unwrap_or_default().to_string()
+                }
+                _ => bail!("Webhook URL must be an absolute https:// URL: {}", url),
+            };
+            let outbox = match &config.outbox {
+                Some(dir) => Some(Arc::new(Outbox::new(dir, url, config.outbox_max_bytes)?)),
+                None => None,
+            };
+            let overflow = outbox.as_ref().map(|outbox| {
+                let (overflow, events) = mpsc::channel(config.queue.max(1));
+                tokio::spawn(run_spiller(
+                    Arc::clone(outbox),
+                    events,
+                    label.clone(),
+                    Arc::clone(&metrics),
+                ));
+                overflow
+            });
+            let (queue, events) = mpsc::channel(config.queue.max(1));
+            tokio::spawn(run_destination(
+                Delivery {
+                    client: client.clone(),
+                    url: url.clone(),
+                    label: label.clone(),
+                    outbox,
+                    secret: config.secret.clone(),
+                    retries: config.retries,
+                    retry_backoff: config.retry_backoff,
+                    metrics: Arc::clone(&metrics),
+                },
+                events,
+                config.clone(),
+            ));
+            match config.min_severity {
+                Some(min) => info!(
+                    "Sending {:?} verdicts, and any at least as severe as {}, to webhook {}",
+                    config.verdicts, min, label
+                ),
+                None => info!(
+                    "Sending {:?} verdicts to webhook {}",
+                    config.verdicts, label
+                ),
+            }
+            destinations.push(Destination {
+                label,
+                queue,
+                overflow,
+            });
+        }
+        Ok(Self {
+            verdicts: config.verdicts.clone(),
+            min_severity: config.min_severity,
+            destinations,
+            metrics,
+        })
+    }
+
+    /// Whether `result` is sent: its verdict or a label is listed, or its
+    /// verdict meets the minimum severity
+    fn selected(&self, result: &AnalysisResult) -> bool {
+        self.verdicts.contains(&result.verdict)
+            || result
+                .labels
+                .iter()
+                .any(|label| self.verdicts.contains(&label.name))
+            || self.min_severity.is_some_and(|min| {
+                result
+                    .verdict
+                    .parse::<Verdict>()
+                    .is_ok_and(|verdict| verdict.severity() >= min.severity())
+            })
+    }
+
+    /// Queue a result for every destination; never waits
+    pub fn send(&self, result: &AnalysisResult) {
+        if !self.selected(result) {
+            return;
+        }
+        let event = WebhookEvent::from(result);
+        for destination in &self.destinations {
+            let overflow = match destination.queue.try_send(event.clone()) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(event) | TrySendError::Closed(event)) => event,
+            };
+            // The spiller writes the outbox; the caller never waits on disk
+            let spilling = destination
+                .overflow
+                .as_ref()
+                .is_some_and(|spiller| spiller.try_send(overflow).is_ok());
+            if !spilling {
+                self.metrics
+                    .webhook_results
+                    .with_label_values(&[destination.label.as_str(), "dropped"])
+                    .inc();
+            }
+        }
+    }
+}
+
+/// Write the results a destination's queue had no room for to its outbox
+async fn run_spiller(
+    outbox: Arc<Outbox>,
+    mut overflow: mpsc::Receiver<WebhookEvent>,
+    label: String,
+    metrics: Arc<Metrics>,
+) {
+    let mut events = Vec::new();
+    while overflow.recv_many(&mut events, SPILL_BATCH).await > 0 {
+        let count = events.len();
+        let outcome = spill(Some(Arc::clone(&outbox)), std::mem::take(&mut events)).await;
+        metrics
+            .webhook_results
+            .with_label_values(&[label.as_str(), outcome])
+            .inc_by(count as f64);
+    }
+}
+
+/// Write events to the outbox, if there is one, on the blocking pool
+///
+/// # Returns
+/// The `webhook_results` outcome, `spilled` or `dropped`
+async fn spill(outbox: Option<Arc<Outbox>>, events: Vec<WebhookEvent>) -> &'static str {
+    let Some(outbox) = outbox else {
+        return "dropped";
+    };
+    let count = events.len();
+    let appended = tokio::task::spawn_blocking(move || outbox.append(&events))
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+    match appended {
+        Ok(()) => "spilled",
+        Err(e) => {
+            warn!("Dropping {} webhook results: {:#}", count, e);
+            "dropped"
+        }
+    }
+}
+
+/// Everything needed to deliver a batch to one destination
+#[derive(Clone)]
+struct Delivery {
+    client: reqwest::Client,
+    url: String,
+    label: String,
+    outbox: Option<Arc<Outbox>>,
+    secret: Option<String>,
+    retries: u32,
+    retry_backoff: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl Delivery {
+    /// POST a batch; results the destination refuses are dropped and
+    /// other undelivered results spill to the outbox
+    async fn deliver(self, batch: Vec<WebhookEvent>, _permit: OwnedSemaphorePermit) {
+        let count = batch.len();
+        let outcome = match self.post(&batch).await {
+            Ok(_) => "delivered",
+            Err(e) if e.is::<Rejected>() => {
+                warn!(
+                    "Webhook {} refused {} results, dropping them: {:#}",
+                    self.label,
+                    batch.len(),
+                    e
+                );
+                "rejected"
+            }
+            Err(e) => {
+                warn!(
+                    "Webhook {} rejected {} results: {:#}",
+                    self.label,
+                    batch.len(),
+                    e
+                );
+                spill(self.outbox.clone(), batch).await
+            }
+        };
+        self.metrics
+            .webhook_results
+            .with_label_values(&[self.label.as_str(), outcome])
+            .inc_by(count as f64);
+    }
+
+    /// POST `batch`, retrying failures that may pass
+    async fn post(&self, batch: &[WebhookEvent]) -> Result<()> {
+        let body = serde_json::to_vec(&Batch { results: batch })?;
+        let mut retried = 0;
+        loop {
+            let mut request = self
+                .client
+                .post(&self.url)
+                .header("Content-Type", "application/json");
+            // Signed per attempt, so a retry carries a fresh timestamp
+            if let Some(secret) = &self.secret {
+                let timestamp = unix_now().to_string();
+                request = request
+                    .header(SIGNATURE_HEADER, sign(secret, &timestamp, &body))
+                    .header(TIMESTAMP_HEADER, timestamp);
+            }
+            let error = match request.body(body.clone()).send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) if !retryable(response.status()) => {
+                    return Err(Rejected(response.status()).into())
+                }
+                Ok(response) => anyhow::anyhow!("destination answered {}", response.status()),
+                Err(e) => e.into(),
+            };
+            if retried >= self.retries {
+                return Err(error);
+            }
+            let delay = self.retry_backoff.saturating_mul(1 << retried.min(16));
+            warn!(
+                "Webhook {} failed, retrying in {:?}: {:#}",
+                self.label, delay, error
+            );
+            self.metrics
+                .webhook_retries
+                .with_label_values(&[self.label.as_str()])
+                .inc();
+            tokio::time::sleep(delay).await;
+            retried += 1;
+        }
+    }
+}
+
+/// `X-Signature` value of a batch: the HMAC-SHA256 of `<timestamp>.<body>`
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut signed = Vec::with_capacity(timestamp.len() + 1 + body.len());
+    signed.extend_from_slice(timestamp.as_bytes());
+    signed.push(b'.');
+    signed.extend_from_slice(body);
+    format!("sha256={}", telemetry::sign(secret.as_bytes(), &signed))
+}
+
+/// Whether a delivery answered with `status` may succeed when retried
+fn retryable(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// A destination refused a batch with a status retrying would not change
+#[derive(Debug)]
+struct Rejected(reqwest::StatusCode);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "destination answered {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Batch a destination's queue and deliver with bounded concurrency
+async fn run_destination(
+    delivery: Delivery,
+    mut events: mpsc::Receiver<WebhookEvent>,
+    config: WebhookConfig,
+) {
+    let permits = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    let max_batch = config.max_batch.max(1);
+    let mut drain = tokio::time::interval(OUTBOX_DRAIN_INTERVAL);
+    loop {
+        let first = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => event,
+                None => return,
+            },
+            _ = drain.tick() => {
+                // Only retry the outbox once live traffic has caught up
+                if let Some(outbox) = delivery.outbox.as_ref().filter(|_| events.is_empty()) {
+                    if let Err(e) = drain_outbox(&delivery, outbox, &permits, max_batch).await {
+                        warn!("Reading webhook outbox failed: {:#}", e);
+                    }
+                }
+                continue;
+            }
+        };
+
+        let mut batch = vec![first];
+        let deadline = Instant::now() + config.max_delay;
+        while batch.len() < max_batch {
+            match timeout_at(deadline, events.recv()).await {
+                Ok(Some(event)) => batch.push(event),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        // While every permit is taken the queue backs up and new results
+        // spill to the outbox
+        let permit = acquire(&permits).await;
+        tokio::spawn(delivery.clone().deliver(batch, permit));
+    }
+}
+
+/// Deliver the outbox's events a batch at a time; results failing again
+/// spill to a new outbox file
+async fn drain_outbox(
+    delivery: &Delivery,
+    outbox: &Arc<Outbox>,
+    permits: &Arc<Semaphore>,
+    max_batch: usize,
+) -> Result<()> {
+    let outbox = Arc::clone(outbox);
+    let Some(path) = tokio::task::spawn_blocking(move || outbox.take()).await?? else {
+        return Ok(());
+    };
+    let mut reader = BufReader::new(tokio::fs::File::open(&path).await?);
+    loop {
+        let batch = read_batch(&mut reader, max_batch).await?;
+        if batch.is_empty() {
+            break;
+        }
+        let permit = acquire(permits).await;
+        tokio::spawn(delivery.clone().deliver(batch, permit));
+    }
+    tokio::fs::remove_file(&path).await?;
+    Ok(())
+}
+
+async fn acquire(permits: &Arc<Semaphore>) -> OwnedSemaphorePermit {
+    Arc::clone(permits)
+        .acquire_owned()
+        .await
+        .expect("webhook permits are never closed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_pb::NeuralFeatures;
+    use crate::test_support::TempDir;
+
+    fn result(content_hash: &str, verdict: &str) -> AnalysisResult {
+        AnalysisResult {
+            content_hash: content_hash.to_string(),
+            source_id: "blog:1".to_string(),
+            verdict: verdict.to_string(),
+            neural_features: Some(NeuralFeatures {
+                fakeness_score: 0.9,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    const OUTBOX_MAX_BYTES: u64 = 1 << 20;
+
+    /// Take the outbox and read back everything in it
+    async fn drained(outbox: &Outbox) -> Vec<WebhookEvent> {
+        let Some(path) = outbox.take().unwrap() else {
+            return Vec::new();
+        };
+        let mut reader = BufReader::new(tokio::fs::File::open(&path).await.unwrap());
+        let events = read_batch(&mut reader, usize::MAX).await.unwrap();
+        fs::remove_file(path).unwrap();
+        events
+    }
+
+    #[tokio::test]
+    async fn test_outbox_round_trip() {
+        let dir = TempDir::new("outbox");
+        let outbox = Outbox::new(&dir, "https://partner.example/hook", OUTBOX_MAX_BYTES).unwrap();
+        let events: Vec<WebhookEvent> = ["aa", "bb", "cc"]
+            .iter()
+            .map(|hash| WebhookEvent::from(&result(hash, "DISINFO")))
+            .collect();
+        outbox.append(&events[..1]).unwrap();
+        outbox.append(&events[1..]).unwrap();
+
+        // Read a batch at a time; spills meanwhile go to a new file
+        let taken = outbox.take().unwrap().unwrap();
+        outbox.append(&events[..1]).unwrap();
+        let mut reader = BufReader::new(tokio::fs::File::open(&taken).await.unwrap());
+        assert_eq!(read_batch(&mut reader, 2).await.unwrap(), events[..2]);
+        assert_eq!(read_batch(&mut reader, 2).await.unwrap(), events[2..]);
+        assert!(read_batch(&mut reader, 2).await.unwrap().is_empty());
+        // An interrupted drain is resumed before the new file
+        assert_eq!(outbox.take().unwrap(), Some(taken.clone()));
+        fs::remove_file(taken).unwrap();
+
+        assert_eq!(drained(&outbox).await, events[..1]);
+        assert!(drained(&outbox).await.is_empty());
+        assert_eq!(events[0].fakeness_score, 0.9);
+    }
+
+    #[test]
+    fn test_outbox_is_capped() {
+        let dir = TempDir::new("outbox");
+        let event = WebhookEvent::from(&result("aa", "DISINFO"));
+        let line = serde_json::to_vec(&event).unwrap().len() as u64 + 1;
+        let outbox = Outbox::new(&dir, "https://partner.example/hook", 2 * line).unwrap();
+
+        outbox.append(std::slice::from_ref(&event)).unwrap();
+        outbox.append(std::slice::from_ref(&event)).unwrap();
+        assert!(outbox.append(std::slice::from_ref(&event)).is_err());
+        assert_eq!(fs::metadata(&outbox.path).unwrap().len(), 2 * line);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_spills_to_outbox() {
+        let dir = TempDir::new("outbox");
+        let outbox =
+            Arc::new(Outbox::new(&dir, "https://partner.example/hook", OUTBOX_MAX_BYTES).unwrap());
+        // Nobody consumes the queue, as when every delivery is in flight
+        let (queue, mut events) = mpsc::channel(1);
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let (overflow, spilled) = mpsc::channel(10);
+        tokio::spawn(run_spiller(
+            Arc::clone(&outbox),
+            spilled,
+            "partner.example".to_string(),
+            Arc::clone(&metrics),
+        ));
+        let sinks = WebhookSinks {
+            verdicts: HashSet::from(["DISINFO".to_string()]),
+            min_severity: None,
+            destinations: vec![Destination {
+                label: "partner.example".to_string(),
+                queue,
+                overflow: Some(overflow),
+            }],
+            metrics: Arc::clone(&metrics),
+        };
+
+        sinks.send(&result("aa", "DISINFO"));
+        sinks.send(&result("bb", "SAFE"));
+        sinks.send(&result("cc", "DISINFO"));
+        // Labels select results as verdicts do
+        let mut labeled = result("dd", "SAFE");
+        labeled.labels.push(crate::model_pb::Label {
+            name: "DISINFO".to_string(),
+            confidence: 0.9,
+        });
+        sinks.send(&labeled);
+
+        assert_eq!(events.recv().await.unwrap().content_hash, "aa");
+        let spilled_count = || {
+            metrics
+                .webhook_results
+                .with_label_values(&["partner.example", "spilled"])
+                .get()
+        };
+        for _ in 0..500 {
+            if spilled_count() == 2.0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(spilled_count(), 2.0);
+        let spilled: Vec<String> = drained(&outbox)
+            .await
+            .into_iter()
+            .map(|e| e.content_hash)
+            .collect();
+        assert_eq!(spilled, ["cc", "dd"]);
+    }
+
+    #[test]
+    fn test_min_severity_selects_results() {
+        let (queue, _events) = mpsc::channel(1);
+        let sinks = WebhookSinks {
+            verdicts: HashSet::new(),
+            min_severity: Some(Verdict::Suspicious),
+            destinations: vec![Destination {
+                label: "partner.example".to_string(),
+                queue,
+                overflow: None,
+            }],
+            metrics: Arc::new(Metrics::new().unwrap()),
+        };
+        assert!(sinks.selected(&result("aa", "DISINFO")));
+        assert!(sinks.selected(&result("bb", "SUSPICIOUS")));
+        assert!(!sinks.selected(&result("cc", "SAFE")));
+        assert!(!sinks.selected(&result("dd", "REJECTED")));
+    }
+
+    #[tokio::test]
+    async fn test_refused_batches_are_dropped_not_spilled() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let dir = TempDir::new("outbox");
+        let outbox =
+            Arc::new(Outbox::new(&dir, "https://partner.example/hook", OUTBOX_MAX_BYTES).unwrap());
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let delivery = Delivery {
+            client: reqwest::Client::new(),
+            url: format!("http://{}/hook", addr),
+            label: "partner.example".to_string(),
+            outbox: Some(Arc::clone(&outbox)),
+            secret: None,
+            retries: 3,
+            retry_backoff: Duration::from_millis(1),
+            metrics: Arc::clone(&metrics),
+        };
+        let batch = vec![WebhookEvent::from(&result("aa", "DISINFO"))];
+        delivery
+            .deliver(batch, acquire(&Arc::new(Semaphore::new(1))).await)
+            .await;
+
+        assert!(drained(&outbox).await.is_empty());
+        let count = |outcome: &str| {
+            metrics
+                .webhook_results
+                .with_label_values(&["partner.example", outcome])
+                .get()
+        };
+        assert_eq!(count("rejected"), 1.0);
+        assert_eq!(count("spilled"), 0.0);
+        assert_eq!(
+            metrics
+                .webhook_retries
+                .with_label_values(&["partner.example"])
+                .get(),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_signature_covers_the_timestamp() {
+        let body = br#"{"results":[]}"#;
+        assert_eq!(
+            sign("key", "1700000000", body),
+            format!(
+                "sha256={}",
+                telemetry::sign(b"key", br#"1700000000.{"results":[]}"#)
+            )
+        );
+        assert_ne!(
+            sign("key", "1700000000", body),
+            sign("key", "1700000001", body)
+        );
+    }
+
+    #[test]
+    fn test_only_transient_rejections_are_retried() {
+        assert!(retryable(reqwest::StatusCode::SERVICE_UNAVAILABLE));
+        assert!(retryable(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(!retryable(reqwest::StatusCode::BAD_REQUEST));
+        assert!(!retryable(reqwest::StatusCode::UNAUTHORIZED));
+    }
+}